@@ -0,0 +1,440 @@
+//! The control API for `whisper listen --api`.
+//!
+//! Lets GUIs and bots drive a running node over HTTP without linking
+//! Rust: `POST /messages` sends a text message, `GET /conversations`
+//! lists conversations, and `GET /events` streams incoming messages as
+//! server-sent events. Like the metrics endpoint it only binds to
+//! loopback addresses, and every request must carry the token from
+//! `api.token` in the data directory as `Authorization: Bearer <token>`.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, oneshot};
+
+use super::output::ConversationOutput;
+use crate::message::{Conversation, Recipient};
+use crate::storage::DatabaseHandle;
+
+/// Where `--api` listens when no address is given.
+pub const DEFAULT_API_ADDR: &str = "127.0.0.1:9465";
+
+/// File in the data directory holding the API token.
+pub const API_TOKEN_FILE: &str = "api.token";
+
+/// Largest request head we read.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Largest request body we read.
+const MAX_REQUEST_BODY: usize = 64 * 1024;
+
+/// How often the event stream sends a comment, so dead clients are noticed.
+const EVENT_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Incoming messages buffered for slow event streams.
+const EVENT_BUFFER: usize = 256;
+
+/// Path of the API token file.
+pub fn api_token_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(API_TOKEN_FILE)
+}
+
+/// Read the API token, creating one readable only by us if there's none.
+pub fn load_api_token(data_dir: &Path) -> Result<String> {
+    let path = api_token_path(data_dir);
+    if path.exists() {
+        let token = std::fs::read_to_string(&path).context("Failed to read API token")?;
+        let token = token.trim();
+        if token.is_empty() {
+            bail!("API token file {:?} is empty; delete it to get a new token", path);
+        }
+        return Ok(token.to_string());
+    }
+
+    let token = hex::encode(rand::random::<[u8; 32]>());
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&path).context("Failed to create API token")?;
+    std::io::Write::write_all(&mut file, token.as_bytes()).context("Failed to write API token")?;
+    Ok(token)
+}
+
+/// A `POST /messages` request, answered by the listen loop with the
+/// message ID or why it couldn't be sent.
+pub struct ApiSend {
+    pub to: String,
+    pub text: String,
+    pub reply: oneshot::Sender<Result<uuid::Uuid>>,
+}
+
+/// The listen loop's end of the API: sends to carry out, and where to
+/// publish incoming messages (as JSON) for the event stream.
+pub struct ApiLink {
+    pub sends: mpsc::Receiver<ApiSend>,
+    pub incoming: broadcast::Sender<String>,
+}
+
+/// Shared state of the API server.
+pub struct ControlApi {
+    token: String,
+    db: DatabaseHandle,
+    sends: mpsc::Sender<ApiSend>,
+    incoming: broadcast::Sender<String>,
+}
+
+impl ControlApi {
+    /// An API accepting `token`, and the link for the listen loop.
+    pub fn new(token: String, db: DatabaseHandle) -> (Arc<Self>, ApiLink) {
+        let (sends, sends_rx) = mpsc::channel(16);
+        let (incoming, _) = broadcast::channel(EVENT_BUFFER);
+        let api = Arc::new(Self {
+            token,
+            db,
+            sends,
+            incoming: incoming.clone(),
+        });
+        (api, ApiLink { sends: sends_rx, incoming })
+    }
+
+    /// Whether the request carries our token. Compared in constant time.
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(given) = request.header("authorization").and_then(|v| v.strip_prefix("Bearer ")) else {
+            return false;
+        };
+        given.len() == self.token.len()
+            && given.bytes().zip(self.token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+/// Bind the control API. Only loopback addresses are allowed.
+pub async fn bind_api(addr: SocketAddr) -> Result<TcpListener> {
+    if !addr.ip().is_loopback() {
+        bail!("The API is only served on localhost, not {}", addr.ip());
+    }
+    TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen for API requests on {}", addr))
+}
+
+/// Answer API requests on `listener` until the task is dropped.
+pub async fn serve_api(listener: TcpListener, api: Arc<ControlApi>) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let api = api.clone();
+        tokio::spawn(async move {
+            // Clients that hang up early are their own problem
+            let _ = answer(stream, &api).await;
+        });
+    }
+}
+
+/// An HTTP request, as much of it as we look at.
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// The value of a header, by lowercase name.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 1024];
+    let head_end = loop {
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if data.len() > MAX_REQUEST_HEAD {
+            bail!("Request head too large");
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            bail!("Connection closed mid-request");
+        }
+        data.extend_from_slice(&buf[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_end]).into_owned();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let length = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .map(|(_, value)| value.parse::<usize>())
+        .transpose()
+        .context("Bad Content-Length")?
+        .unwrap_or(0);
+    if length > MAX_REQUEST_BODY {
+        bail!("Request body too large");
+    }
+    let mut body = data.split_off(head_end + 4);
+    while body.len() < length {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            bail!("Connection closed mid-body");
+        }
+        body.extend_from_slice(&buf[..n]);
+    }
+    body.truncate(length);
+
+    Ok(Request { method, path, headers, body })
+}
+
+/// A response: status line, and a JSON body.
+type Response = (&'static str, String);
+
+async fn answer(mut stream: TcpStream, api: &ControlApi) -> Result<()> {
+    let (status, body) = match read_request(&mut stream).await {
+        Ok(request) if !api.authorized(&request) => ("401 Unauthorized", error_body("Missing or wrong API token")),
+        Ok(request) if request.method == "GET" && request.path == "/events" => {
+            return stream_events(stream, api.incoming.subscribe()).await;
+        }
+        Ok(request) => route(&request, api).await,
+        Err(e) => ("400 Bad Request", error_body(&e.to_string())),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// The body of a `POST /messages` request.
+#[derive(Deserialize)]
+struct SendBody {
+    /// Contact alias.
+    to: String,
+    text: String,
+}
+
+/// What `POST /messages` returns.
+#[derive(Serialize)]
+struct SentBody {
+    id: uuid::Uuid,
+}
+
+async fn route(request: &Request, api: &ControlApi) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/conversations") => match api.db.call(|db| db.list_conversations()).await {
+            Ok(conversations) => {
+                let list: Vec<ConversationOutput> = conversations.into_iter().map(conversation_output).collect();
+                ("200 OK", serde_json::to_string(&list).unwrap_or_default())
+            }
+            Err(e) => ("500 Internal Server Error", error_body(&format!("{:#}", e))),
+        },
+        ("POST", "/messages") => {
+            let body: SendBody = match serde_json::from_slice(&request.body) {
+                Ok(body) => body,
+                Err(e) => return ("400 Bad Request", error_body(&format!("Expected {{\"to\", \"text\"}}: {}", e))),
+            };
+            if body.text.trim().is_empty() {
+                return ("400 Bad Request", error_body("Message is empty"));
+            }
+            let (reply, replied) = oneshot::channel();
+            let send = ApiSend { to: body.to, text: body.text, reply };
+            if api.sends.send(send).await.is_err() {
+                return ("503 Service Unavailable", error_body("Whisper is shutting down"));
+            }
+            match replied.await {
+                Ok(Ok(id)) => ("202 Accepted", serde_json::to_string(&SentBody { id }).unwrap_or_default()),
+                Ok(Err(e)) => ("422 Unprocessable Entity", error_body(&format!("{:#}", e))),
+                Err(_) => ("503 Service Unavailable", error_body("Whisper is shutting down")),
+            }
+        }
+        (_, "/conversations" | "/messages" | "/events") => ("405 Method Not Allowed", error_body("Method not allowed")),
+        _ => ("404 Not Found", error_body("Try /messages, /conversations or /events")),
+    }
+}
+
+/// Send incoming messages as server-sent events until the client leaves.
+async fn stream_events(mut stream: TcpStream, mut incoming: broadcast::Receiver<String>) -> Result<()> {
+    stream
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n")
+        .await?;
+    let mut keepalive = tokio::time::interval(EVENT_KEEPALIVE);
+    loop {
+        let chunk = tokio::select! {
+            _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+            message = incoming.recv() => match message {
+                Ok(json) => format!("event: message\ndata: {}\n\n", json),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+        };
+        stream.write_all(chunk.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn conversation_output(conversation: Conversation) -> ConversationOutput {
+    let (kind, id) = match &conversation.with {
+        Recipient::Direct(peer_id) => ("direct", peer_id.to_string()),
+        Recipient::Group(group_id) => ("group", group_id.to_string()),
+    };
+    ConversationOutput {
+        last_activity: conversation.last_activity(),
+        preview: conversation.last_message.map(|msg| msg.content.summary()),
+        name: conversation.name,
+        kind,
+        id,
+        unread: conversation.unread,
+        pinned: conversation.pinned,
+        archived: conversation.archived,
+    }
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Contact;
+    use crate::storage::Database;
+    use libp2p::PeerId;
+    use tempfile::TempDir;
+
+    async fn request(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    async fn start_api() -> (SocketAddr, ApiLink, tokio::task::JoinHandle<()>) {
+        let db = Database::open_in_memory().unwrap();
+        db.upsert_contact(&Contact::new(PeerId::random(), "bob".to_string(), Vec::new())).unwrap();
+        let (api, link) = ControlApi::new("secret".to_string(), DatabaseHandle::spawn(db).unwrap());
+        let listener = bind_api("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (addr, link, tokio::spawn(serve_api(listener, api)))
+    }
+
+    #[tokio::test]
+    async fn requires_the_token() {
+        let (addr, _link, server) = start_api().await;
+        let response = request(addr, "GET /conversations HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+        let response = request(addr, "GET /conversations HTTP/1.1\r\nAuthorization: Bearer wrong!\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn lists_conversations() {
+        let (addr, _link, server) = start_api().await;
+        let response = request(addr, "GET /conversations HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains(r#""name":"bob""#));
+        assert!(response.contains(r#""kind":"direct""#));
+
+        let response = request(addr, "DELETE /conversations HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"));
+        let response = request(addr, "GET / HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404"));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn sends_go_to_the_listen_loop() {
+        let (addr, mut link, server) = start_api().await;
+        let id = uuid::Uuid::new_v4();
+        tokio::spawn(async move {
+            while let Some(send) = link.sends.recv().await {
+                let result = if send.to == "bob" { Ok(id) } else { Err(anyhow::anyhow!("Contact not found")) };
+                let _ = send.reply.send(result);
+            }
+        });
+
+        let body = r#"{"to":"bob","text":"hi"}"#;
+        let post = format!(
+            "POST /messages HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let response = request(addr, &post).await;
+        assert!(response.starts_with("HTTP/1.1 202"));
+        assert!(response.contains(&id.to_string()));
+
+        let body = r#"{"to":"carol","text":"hi"}"#;
+        let post = format!(
+            "POST /messages HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        assert!(request(addr, &post).await.starts_with("HTTP/1.1 422"));
+        let post = "POST /messages HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: 2\r\n\r\n{}";
+        assert!(request(addr, post).await.starts_with("HTTP/1.1 400"));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn streams_incoming_messages() {
+        let (addr, link, server) = start_api().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /events HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut received = String::new();
+        let mut buf = [0u8; 1024];
+        while !received.contains("data: {\"text\":\"hi\"}") {
+            // Keep publishing until the stream has subscribed
+            let _ = link.incoming.send(r#"{"text":"hi"}"#.to_string());
+            let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+                .await
+                .expect("event should arrive")
+                .unwrap();
+            received.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+        assert!(received.starts_with("HTTP/1.1 200 OK"));
+        assert!(received.contains("text/event-stream"));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn refuses_public_addresses() {
+        assert!(bind_api("0.0.0.0:0".parse().unwrap()).await.is_err());
+    }
+
+    #[test]
+    fn token_is_created_once() {
+        let temp = TempDir::new().unwrap();
+        let token = load_api_token(temp.path()).unwrap();
+        assert_eq!(token.len(), 64);
+        assert_eq!(load_api_token(temp.path()).unwrap(), token);
+    }
+}
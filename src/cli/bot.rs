@@ -0,0 +1,185 @@
+//! The auto-responder behind `whisper bot`.
+//!
+//! Each text message from an allowed contact is piped to the user's
+//! command on stdin, with who sent it in `WHISPER_FROM`,
+//! `WHISPER_PEER_ID` and `WHISPER_MESSAGE_ID`. Whatever the command prints
+//! is sent back as the reply; printing nothing sends nothing. Any
+//! interpreter works, so a bot can be a shell, Python, Lua or Rhai script.
+
+use std::collections::HashSet;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use libp2p::PeerId;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::{mpsc, Semaphore};
+
+/// How long the command may take to answer one message.
+const BOT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Commands running at once. Messages beyond this wait their turn.
+const BOT_CONCURRENCY: usize = 4;
+
+/// Longest reply sent, in bytes. Longer output is an error.
+const MAX_REPLY: usize = 64 * 1024;
+
+/// A message for the bot to answer.
+pub struct BotMessage {
+    pub from: PeerId,
+    pub alias: String,
+    pub id: uuid::Uuid,
+    pub text: String,
+}
+
+/// What the command answered, to send back.
+pub struct BotReply {
+    pub to: PeerId,
+    pub text: String,
+}
+
+/// Runs the bot command for messages from allowed contacts.
+pub struct Bot {
+    command: Arc<[String]>,
+    allowed: HashSet<PeerId>,
+    replies: mpsc::Sender<BotReply>,
+    slots: Arc<Semaphore>,
+}
+
+impl Bot {
+    /// A bot running `command` (program, then arguments) for messages from
+    /// `allowed`, and where its replies come out.
+    pub fn new(command: Vec<String>, allowed: HashSet<PeerId>) -> Result<(Self, mpsc::Receiver<BotReply>)> {
+        if command.is_empty() {
+            bail!("No bot command given");
+        }
+        let (replies, receiver) = mpsc::channel(16);
+        let bot = Self {
+            command: command.into(),
+            allowed,
+            replies,
+            slots: Arc::new(Semaphore::new(BOT_CONCURRENCY)),
+        };
+        Ok((bot, receiver))
+    }
+
+    /// Whether messages from `peer` are answered.
+    pub fn answers(&self, peer: &PeerId) -> bool {
+        self.allowed.contains(peer)
+    }
+
+    /// Run the command for `message` in the background. Failures are
+    /// reported on stderr and get no reply.
+    pub fn answer(&self, message: BotMessage) {
+        let (command, replies, slots) = (self.command.clone(), self.replies.clone(), self.slots.clone());
+        tokio::spawn(async move {
+            let Ok(_slot) = slots.acquire().await else {
+                return;
+            };
+            match run_command(&command, &message).await {
+                Ok(Some(text)) => {
+                    let _ = replies.send(BotReply { to: message.from, text }).await;
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("Bot command failed for a message from {}: {:#}", message.alias, e),
+            }
+        });
+    }
+}
+
+/// Run `command` with `message` on stdin, returning its output as the
+/// reply, or `None` if it printed nothing.
+async fn run_command(command: &[String], message: &BotMessage) -> Result<Option<String>> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .env("WHISPER_FROM", &message.alias)
+        .env("WHISPER_PEER_ID", message.from.to_string())
+        .env("WHISPER_MESSAGE_ID", message.id.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run {}", command[0]))?;
+
+    let mut stdin = child.stdin.take().context("No stdin for the bot command")?;
+    let text = message.text.clone();
+    // A command that doesn't read its input is fine
+    let feed = tokio::spawn(async move {
+        let _ = stdin.write_all(text.as_bytes()).await;
+    });
+
+    let output = tokio::time::timeout(BOT_TIMEOUT, child.wait_with_output())
+        .await
+        .with_context(|| format!("No answer within {}s", BOT_TIMEOUT.as_secs()))??;
+    feed.abort();
+    if !output.status.success() {
+        bail!("{} exited with {}", command[0], output.status);
+    }
+    if output.stdout.len() > MAX_REPLY {
+        bail!("Reply is over {} bytes", MAX_REPLY);
+    }
+
+    let mut reply = String::from_utf8(output.stdout).context("Reply is not UTF-8")?;
+    // Drop the newline `echo` and friends end with
+    if reply.ends_with('\n') {
+        reply.pop();
+        if reply.ends_with('\r') {
+            reply.pop();
+        }
+    }
+    Ok((!reply.trim().is_empty()).then_some(reply))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> BotMessage {
+        BotMessage {
+            from: PeerId::random(),
+            alias: "alice".to_string(),
+            id: uuid::Uuid::new_v4(),
+            text: text.to_string(),
+        }
+    }
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["sh".to_string(), "-c".to_string(), script.to_string()]
+    }
+
+    #[tokio::test]
+    async fn output_is_the_reply() {
+        let reply = run_command(&sh("printf 'you said: '; cat; echo"), &message("ping")).await.unwrap();
+        assert_eq!(reply.as_deref(), Some("you said: ping"));
+
+        let reply = run_command(&sh("echo \"hi $WHISPER_FROM\""), &message("")).await.unwrap();
+        assert_eq!(reply.as_deref(), Some("hi alice"));
+
+        assert_eq!(run_command(&sh("true"), &message("ignored")).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn failures_get_no_reply() {
+        assert!(run_command(&sh("echo oops; exit 3"), &message("hi")).await.is_err());
+        assert!(run_command(&["/nonexistent/bot".to_string()], &message("hi")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn only_allowed_contacts_are_answered() {
+        let alice = PeerId::random();
+        let (bot, mut replies) = Bot::new(sh("cat"), HashSet::from([alice])).unwrap();
+        assert!(bot.answers(&alice));
+        assert!(!bot.answers(&PeerId::random()));
+        assert!(Bot::new(Vec::new(), HashSet::new()).is_err());
+
+        bot.answer(BotMessage { from: alice, ..message("echo me") });
+        let reply = tokio::time::timeout(Duration::from_secs(5), replies.recv())
+            .await
+            .expect("bot should reply")
+            .unwrap();
+        assert_eq!((reply.to, reply.text.as_str()), (alice, "echo me"));
+    }
+}
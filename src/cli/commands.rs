@@ -1,84 +1,722 @@
 //! CLI command implementations.
 
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io;
+use std::io::{self, Read};
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
-use bincode;
 use chrono::Utc;
-use crossterm::{
-    event::{self, Event},
-    execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+use crossterm::event::{self, Event};
+use futures::FutureExt;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use serde::Serialize;
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
 };
-use libp2p::PeerId;
-use ratatui::{
-    backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
-    Terminal,
+use tokio::sync::mpsc;
+use zeroize::Zeroizing;
+
+use super::api::{api_token_path, bind_api, load_api_token, serve_api, ApiLink, ApiSend, ControlApi};
+use super::bot::{Bot, BotMessage, BotReply};
+use super::config::{configured_kdf, NetworkConfig};
+use super::matrix::{run_matrix_sync, MatrixClient, MatrixMessage};
+use super::metrics::{bind_metrics, serve_metrics};
+use super::webhook::{parse_webhook_url, WebhookSender};
+use super::output::{
+    print_json, AuditLogOutput, AuditOutput, ConnectOutput, ContactOutput, DeadLetterOutput, GroupOutput, OutputFormat,
+    PeerOutput, PeersOutput, PendingOutput, RequestOutput, StatsOutput, StatusOutput, TrafficOutput,
+};
+use crate::client::{
+    admits_sender, alert_for, announce_devices, announce_presence, answer_history_request, attribute_device,
+    authenticate_from, contact_card, create_node, deposit_pending, encrypt_with_session, ensure_key_verified, forward_mail,
+    gossiped_post, is_replay, offer_history, open_delivery, open_from_peer, publish_channel_post,
+    receive_channel_joined, receive_channel_post, receive_channel_subscribe, receive_contact_card, receive_deposit,
+    receive_device_list, receive_history, receive_invite_acceptance, receive_presence, receive_receipt,
+    refuse_blocked, request_history, retry_pending, seal_for_contact, sealed_for_devices, send_missing_history, stamp_for, start_listening, watch_contacts,
+    EncryptionKeys, PENDING_TTL_DAYS, RETRY_INTERVAL,
 };
-use tokio::sync::Mutex;
-
 use crate::crypto::{
-    decrypt_message, ed25519_pk_to_x25519, encrypt_message, generate_group_key,
-    keypair_to_encryption_keys,
+    decrypt_message, ed25519_pk_to_x25519, emoji_fingerprint, encrypt_message, format_safety_number,
+    generate_group_key, keypair_to_encryption_keys, safety_number, LockedSecretKey,
 };
 
-/// Wire message prefix for receipts.
-const RECEIPT_PREFIX: &[u8] = b"RCPT:";
+use crate::identity::{
+    contact_uri, create_invite, export_contact_bundle, export_public_key, generate_keypair,
+    generate_signed_prekey, import_contact_bundle, keypair_from_mnemonic, keypair_to_mnemonic, keypair_to_peer_id, load_keypair, parse_contact_uri, parse_invite,
+    render_qr, save_keypair, Contact, IssuedInvite, Presence, PresenceStatus, SignedPrekey, TrustLevel, TrustPolicy,
+    TrustRequirement, CONTACT_URI_SCHEME, PRESENCE_INTERVAL_SECS,
+};
+use crate::message::{
+    merge_messages, Authenticity, Channel, ChannelPost, ConversationExport, DeviceLink, DeviceList, Envelope,
+    ExportFormat, FileOffer, Group, GroupInvite, GroupKeyUpdate, GroupLeave, GroupMemberUpdate, GroupMetadata,
+    GroupSync, InviteAcceptance, LinkedDevice, MemberChange, Message, MessageContent, MessageQueue, MessageStatus,
+    PendingContactCard, PendingGroupInvite, ReceiptType, Recipient, DEFAULT_STAMP_DIFFICULTY,
+};
+use crate::network::{
+    is_onion_address, normalize_username, split_peer_id, FileChunkRequest, Latency, NodeEvent, NodeHandle, NodeMetrics,
+    Reachability, Traffic, TrafficProtocol, TransferDirection, DEFAULT_TOR_PROXY,
+};
+use crate::storage::{
+    abandon_rekey, calibrate_kdf, create_salt_file, derive_rekeyed_key, finish_rekey, is_first_run, prepare_rekey,
+    rekey_pending, staged_for_rekey, Alert, AuditKind, Database, DatabaseHandle, Inbox, NotificationLevel,
+    NotificationSettings, QueueEntry, RetentionPolicy, Webhook, KDF_TARGET,
+};
+use crate::ui::{
+    App, AppMode, DisplayMessage, ImageProtocol, InputAction, Preview, TerminalGuard, DELETED_MESSAGE, HISTORY_PAGE,
+    TYPING_TIMEOUT,
+    cell_size, clear_previews, draw_previews, is_image, open_externally,
+    render_chat, render_connect, render_conversations, render_empty, render_rename, render_search,
+    render_sidebar, render_status, render_warning,
+    render_transfers,
+    short_peer_id, transfers_height,
+};
 
-/// Wire message prefix for file chunks.
-const FILE_CHUNK_PREFIX: &[u8] = b"FILE:";
+/// Check if a received envelope is a receipt.
+/// Returns Some((message_id, receipt_type)) if it's a receipt, None otherwise.
+fn parse_receipt(envelope: &Envelope) -> Option<(uuid::Uuid, ReceiptType)> {
+    match envelope.payload {
+        MessageContent::Receipt(id, receipt_type) => Some((id, receipt_type)),
+        _ => None,
+    }
+}
 
-/// Wire message prefix for file transfer completion.
-const FILE_COMPLETE_PREFIX: &[u8] = b"FDNE:";
+/// Create a wire receipt message.
+fn create_receipt(
+    keypair: &libp2p::identity::Keypair,
+    message_id: &uuid::Uuid,
+    receipt_type: ReceiptType,
+) -> Result<Vec<u8>> {
+    Envelope::new(keypair_to_peer_id(keypair), MessageContent::Receipt(*message_id, receipt_type))
+        .encode_signed(keypair)
+}
 
-/// Parse a wire message to check if it's a receipt.
-/// Returns Some((message_id, receipt_type)) if it's a receipt, None otherwise.
-fn parse_receipt(data: &[u8]) -> Option<(uuid::Uuid, crate::message::ReceiptType)> {
-    if !data.starts_with(RECEIPT_PREFIX) {
-        return None;
-    }
-    let payload = &data[RECEIPT_PREFIX.len()..];
-    // Format: "D:<uuid>" for delivered, "R:<uuid>" for read
-    if payload.len() < 38 {
-        return None;
-    }
-    let receipt_type = match payload[0] {
-        b'D' => crate::message::ReceiptType::Delivered,
-        b'R' => crate::message::ReceiptType::Read,
+/// Whether a typing indicator is recent enough to show. Indicators that
+/// sat in a send queue are stale by the time they arrive.
+fn typing_is_fresh(envelope: &Envelope) -> bool {
+    Utc::now()
+        .signed_duration_since(envelope.timestamp)
+        .to_std()
+        // A timestamp from the future means clock skew; treat as fresh
+        .map_or(true, |age| age < TYPING_TIMEOUT)
+}
+
+/// How a stored message appears in the chat history, if it's shown at all.
+fn history_message(msg: Message, our_peer_id: PeerId, unverified: bool) -> Option<DisplayMessage> {
+    let attachment = image_attachment(&msg.content);
+    let text = match msg.content {
+        MessageContent::Text(text) => text,
+        MessageContent::File(offer) => file_text(&offer),
+        content @ (MessageContent::Location { .. } | MessageContent::ContactCard { .. }) => content.summary(),
+        MessageContent::Tombstone => DELETED_MESSAGE.to_string(),
         _ => return None,
     };
-    if payload[1] != b':' {
-        return None;
+    Some(
+        DisplayMessage::new(msg.from, text, msg.timestamp, msg.from == our_peer_id)
+            .with_id(msg.id)
+            .with_unverified(unverified)
+            .with_attachment(attachment),
+    )
+}
+
+/// How a file offer shows in the chat. Images get a hint for opening
+/// them, for terminals that can't show their preview.
+fn file_text(offer: &FileOffer) -> String {
+    if is_image(&offer.filename) {
+        format!("[image] {} ({} bytes) - press o to open", offer.filename, offer.total_size)
+    } else {
+        format!("[file] {} ({} bytes)", offer.filename, offer.total_size)
     }
-    let uuid_str = std::str::from_utf8(&payload[2..38]).ok()?;
-    let id = uuid::Uuid::parse_str(uuid_str).ok()?;
-    Some((id, receipt_type))
 }
 
-/// Create a wire receipt message.
-fn create_receipt(message_id: &uuid::Uuid, receipt_type: crate::message::ReceiptType) -> Vec<u8> {
-    let type_char = match receipt_type {
-        crate::message::ReceiptType::Delivered => 'D',
-        crate::message::ReceiptType::Read => 'R',
+/// How a received contact card shows, keeping it for `whisper contact
+/// accept` if it introduces someone new.
+fn contact_card_text(db: &Database, our_peer_id: &PeerId, from: PeerId, card: &MessageContent) -> String {
+    let MessageContent::ContactCard { peer_id, public_key, alias } = card else {
+        return card.summary();
     };
-    format!("RCPT:{}:{}", type_char, message_id).into_bytes()
+    match receive_contact_card(db, our_peer_id, from, peer_id, public_key, alias) {
+        Ok(Some(_)) => format!("{} - run: whisper contact accept {}", card.summary(), alias),
+        _ => card.summary(),
+    }
+}
+
+/// The transfer ID of the image a message offers, if it offers one.
+fn image_attachment(content: &MessageContent) -> Option<uuid::Uuid> {
+    match content {
+        MessageContent::File(offer) if is_image(&offer.filename) => Some(offer.transfer_id),
+        _ => None,
+    }
+}
+
+/// A transfer's file name and contents, once all of it is here: files
+/// we sent are whole from the start, files we receive once verified.
+fn attachment_contents(
+    db: &Database,
+    our_peer_id: PeerId,
+    transfer_id: &uuid::Uuid,
+) -> Result<Option<(String, Vec<u8>)>> {
+    let Some(transfer) = db.get_file_transfer(transfer_id)? else {
+        return Ok(None);
+    };
+    if transfer.from != our_peer_id && transfer.status != FileTransferStatus::Complete {
+        return Ok(None);
+    }
+    Ok(Some((transfer.filename, db.reassemble_file(transfer_id)?)))
+}
+
+/// Load the next page of the open chat's history, older than what's
+/// already loaded. Received messages not yet read wait to be viewed.
+fn load_history(db: &Database, app: &mut App) -> Result<()> {
+    let Some(peer_id) = app.current_chat else {
+        return Ok(());
+    };
+    let page = read_history(db, &peer_id, app.chat.history_loaded())?;
+    show_history(app, page);
+    Ok(())
+}
+
+/// Read the page of history with `peer_id` before the `loaded` newest
+/// messages, newest first, with whether each is unverified.
+fn read_history(db: &Database, peer_id: &PeerId, loaded: usize) -> Result<Vec<(Message, bool)>> {
+    let page = db.get_messages_with_peer_page(peer_id, HISTORY_PAGE, loaded)?;
+    Ok(page
+        .into_iter()
+        .map(|msg| {
+            let unverified = db.is_message_unverified(&msg.id).unwrap_or(false);
+            (msg, unverified)
+        })
+        .collect())
+}
+
+/// Add a page from `read_history` to the top of the open chat.
+fn show_history(app: &mut App, page: Vec<(Message, bool)>) {
+    let our_peer_id = app.our_peer_id.unwrap_or_else(PeerId::random);
+    let fetched = page.len();
+    let mut older = Vec::new();
+    for (msg, unverified) in page.into_iter().rev() {
+        if msg.from != our_peer_id && !matches!(msg.status, MessageStatus::Read) {
+            app.mark_unread(msg.from, msg.id);
+        }
+        older.extend(history_message(msg, our_peer_id, unverified));
+    }
+    app.chat.prepend_history(older, fetched);
+}
+
+/// Width of the conversation sidebar in the chat TUI.
+const SIDEBAR_WIDTH: u16 = 24;
+
+/// Maximum number of results shown by the TUI search.
+const TUI_SEARCH_LIMIT: usize = 50;
+
+/// How often `whisper listen` applies retention policies.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often `whisper listen --metrics` measures the persistent queue.
+const QUEUE_METRICS_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often running sessions re-announce our presence.
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(PRESENCE_INTERVAL_SECS);
+
+/// How long `whisper connect` and the chat TUI wait for a handshake.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `whisper add --username` waits to join the DHT, and then for
+/// the lookup.
+const USERNAME_LOOKUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long `whisper channel post` waits for a subscriber to come online
+/// and take the post.
+const CHANNEL_PUBLISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `whisper channel post` keeps running once the post is out,
+/// so it leaves before the node stops.
+const CHANNEL_PUBLISH_LINGER: Duration = Duration::from_secs(1);
+
+/// How long `whisper send --file` waits on the contact to take the next
+/// chunk or message before giving up on the transfer.
+const FILE_CHUNK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many chunks `whisper send --file` sends ahead of the contact's acks.
+/// Kept far below how many message keys a receiver will skip.
+const FILE_CHUNK_WINDOW: usize = 8;
+
+/// Our identity and node, shared with work sent to the storage thread.
+struct Session {
+    node: NodeHandle,
+    keypair: libp2p::identity::Keypair,
+    enc_keys: (sodiumoxide::crypto::box_::PublicKey, LockedSecretKey),
+}
+
+impl Session {
+    fn new(node: NodeHandle, keypair: libp2p::identity::Keypair) -> Result<Arc<Self>> {
+        let (enc_pk, enc_sk) = keypair_to_encryption_keys(&keypair)
+            .context("Failed to derive encryption keys")?;
+        let enc_keys = (enc_pk, LockedSecretKey::new(enc_sk));
+        Ok(Arc::new(Self { node, keypair, enc_keys }))
+    }
+
+    fn peer_id(&self) -> PeerId {
+        self.node.peer_id()
+    }
+
+    fn keys(&self) -> EncryptionKeys<'_> {
+        (&self.enc_keys.0, &*self.enc_keys.1)
+    }
+
+    /// Run `f` on the storage thread with this session.
+    async fn call<T, F>(self: &Arc<Self>, db: &DatabaseHandle, f: F) -> Result<T>
+    where
+        F: FnOnce(&Database, &Session) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let session = Arc::clone(self);
+        db.call(move |db| f(db, &session)).await
+    }
+}
+
+/// Search text messages for the TUI's `/` search view.
+fn search_results(db: &Database, our_peer_id: Option<PeerId>, query: &str) -> Vec<DisplayMessage> {
+    db.search_messages(query, TUI_SEARCH_LIMIT)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|msg| match msg.content {
+            MessageContent::Text(text) => Some(DisplayMessage::new(
+                msg.from,
+                text,
+                msg.timestamp,
+                Some(msg.from) == our_peer_id,
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Mark a received message as read and, unless the user turned read
+/// receipts off for this contact or the trust policy wants them trusted
+/// more, tell the sender. Receipts for peers we aren't connected to wait in
+/// the persistent queue.
+fn mark_read(
+    db: &Database,
+    node: &NodeHandle,
+    keypair: &libp2p::identity::Keypair,
+    peer_id: PeerId,
+    message_id: &uuid::Uuid,
+    connected: bool,
+) -> Result<()> {
+    db.update_message_status(message_id, &MessageStatus::Read)?;
+    let trust = db.get_contact(&peer_id)?.map_or(TrustLevel::Unknown, |contact| contact.trust_level);
+    if !db.read_receipts_enabled(&peer_id)? || !db.trust_policy()?.read_receipts.admits(trust) {
+        return Ok(());
+    }
+    let receipt = create_receipt(keypair, message_id, ReceiptType::Read)?;
+    if connected {
+        node.send_message(peer_id, receipt);
+    } else {
+        MessageQueue::new(db).enqueue(&uuid::Uuid::new_v4(), &peer_id, &receipt)?;
+    }
+    Ok(())
+}
+
+/// Decrypt a received group invite and queue it for the user to accept.
+/// Returns `None` if we're already in the group, or the trust policy turns
+/// down invites from the sender.
+fn receive_group_invite(
+    db: &Database,
+    our_keys: EncryptionKeys,
+    from: PeerId,
+    invite: &GroupInvite,
+) -> Result<Option<PendingGroupInvite>> {
+    let trust = db.get_contact(&from)?.map_or(TrustLevel::Unknown, |contact| contact.trust_level);
+    if !db.trust_policy()?.group_invites.admits(trust) || db.get_group(&invite.group_id)?.is_some() {
+        return Ok(None);
+    }
+    let symmetric_key = decrypt_message(&invite.encrypted_key, our_keys.0, our_keys.1)
+        .context("Failed to decrypt group key")?;
+    let pending = PendingGroupInvite {
+        group_id: invite.group_id,
+        name: invite.name.clone(),
+        from,
+        symmetric_key,
+        received_at: Utc::now(),
+    };
+    db.save_group_invite(&pending)?;
+    Ok(Some(pending))
+}
+
+/// Decrypt and store a rotated group key. Only the group's owner or an
+/// admin may rotate it. Returns true if the key changed.
+fn receive_group_key_update(
+    db: &Database,
+    our_keys: EncryptionKeys,
+    from: PeerId,
+    update: &GroupKeyUpdate,
+) -> Result<bool> {
+    let group = match db.get_group(&update.group_id)? {
+        Some(group) => group,
+        None => return Ok(false),
+    };
+    if !group.can_manage(&from) {
+        return Ok(false);
+    }
+    let symmetric_key = decrypt_message(&update.encrypted_key, our_keys.0, our_keys.1)
+        .context("Failed to decrypt group key")?;
+    let changed = db.set_group_key(&group.id, update.epoch, &symmetric_key)?;
+    if changed {
+        let detail = format!("Key for group '{}' rotated to epoch {}", group.name, update.epoch);
+        let _ = db.record_audit(AuditKind::KeyChanged, Some(&from), &detail);
+    }
+    Ok(changed)
+}
+
+/// Apply a membership change sent by another member, if they're allowed
+/// to make it. Being removed ourselves drops the group. Returns true if our
+/// copy of the group changed.
+fn receive_group_member_update(
+    db: &Database,
+    our_peer_id: PeerId,
+    from: PeerId,
+    update: &GroupMemberUpdate,
+) -> Result<bool> {
+    let group = match db.get_group(&update.group_id)? {
+        Some(group) => group,
+        None => return Ok(false),
+    };
+    if !update.is_allowed(&group, &from) {
+        return Ok(false);
+    }
+    match update.change {
+        MemberChange::Added => {
+            if group.is_member(&update.peer_id) {
+                return Ok(false);
+            }
+            db.add_group_member(&group.id, &update.peer_id)?;
+            Ok(true)
+        }
+        MemberChange::Removed if update.peer_id == our_peer_id => db.delete_group(&group.id),
+        MemberChange::Removed => db.remove_group_member(&group.id, &update.peer_id),
+        MemberChange::Role(role) => db.set_member_role(&group.id, &update.peer_id, role),
+        MemberChange::Owner => {
+            if !group.is_member(&update.peer_id) {
+                return Ok(false);
+            }
+            db.transfer_group_ownership(&group.id, &update.peer_id)
+        }
+    }
+}
+
+/// Apply a signed leave notice from a member. If we own the group the key
+/// is rotated so they can't read what follows. Returns true if the member
+/// was removed.
+fn receive_group_leave(
+    db: &Database,
+    keypair: &libp2p::identity::Keypair,
+    from: PeerId,
+    leave: &GroupLeave,
+) -> Result<bool> {
+    let group = match db.get_group(&leave.group_id)? {
+        Some(group) if group.is_member(&from) => group,
+        _ => return Ok(false),
+    };
+    let contact = match db.get_contact(&from)? {
+        Some(contact) => contact,
+        None => return Ok(false),
+    };
+    if !leave.verify(&from, &contact.public_key) {
+        return Ok(false);
+    }
+
+    db.record_group_departure(&group.id, &from, leave.left_at)?;
+    if group.is_owner(&keypair_to_peer_id(keypair)) {
+        if let Some(group) = db.get_group(&group.id)? {
+            rotate_group_key(db, keypair, &group)?;
+        }
+    }
+    Ok(true)
+}
+
+/// Merge a member's signed view of a group into ours.
+///
+/// Metadata is only taken from the group's owner or an admin, and only if
+/// it's newer than what we last applied; only the owner can hand over
+/// ownership. A newer key epoch is picked up either way. Returns true if
+/// our copy of the group changed.
+fn receive_group_sync(
+    db: &Database,
+    our_peer_id: PeerId,
+    our_keys: EncryptionKeys,
+    from: PeerId,
+    sync: &GroupSync,
+) -> Result<bool> {
+    let metadata = &sync.metadata;
+    let group = match db.get_group(&metadata.group_id)? {
+        Some(group) if group.can_manage(&from) => group,
+        _ => return Ok(false),
+    };
+    let contact = match db.get_contact(&from)? {
+        Some(contact) => contact,
+        None => return Ok(false),
+    };
+    if !sync.verify(&from, &contact.public_key) {
+        return Ok(false);
+    }
+
+    let mut changed = false;
+    if let Some(encrypted_key) = &metadata.encrypted_key {
+        if metadata.key_epoch > db.group_key_epoch(&group.id)? {
+            let symmetric_key = decrypt_message(encrypted_key, our_keys.0, our_keys.1)
+                .context("Failed to decrypt group key")?;
+            changed |= db.set_group_key(&group.id, metadata.key_epoch, &symmetric_key)?;
+        }
+    }
+
+    // Versions are stored to the millisecond
+    let newer = db
+        .group_updated_at(&group.id)?
+        .is_none_or(|ours| metadata.updated_at.timestamp_millis() > ours.timestamp_millis());
+    let owner = metadata.owner();
+    if !newer || (owner != group.owner && !group.is_owner(&from)) {
+        return Ok(changed);
+    }
+
+    if !metadata.has_member(&our_peer_id) {
+        return db.delete_group(&group.id);
+    }
+
+    db.update_group_settings(&group.id, Some(&metadata.name), Some(metadata.description.as_deref()))?;
+    if let Some(owner) = owner {
+        if group.owner != Some(owner) {
+            db.transfer_group_ownership(&group.id, &owner)?;
+        }
+    }
+    for member in &group.members {
+        if !metadata.has_member(&member.peer_id) {
+            db.remove_group_member(&group.id, &member.peer_id)?;
+        }
+    }
+    for member in &metadata.members {
+        if group.get_member_role(&member.peer_id) != Some(member.role) {
+            db.add_group_member_with_role(&group.id, &member.peer_id, member.role)?;
+        }
+    }
+    db.set_group_updated_at(&group.id, metadata.updated_at)?;
+
+    Ok(true)
+}
+
+/// Queue our signed view of each group we manage that `peer_id` is in, so
+/// their copy catches up when they connect. Returns how many were queued.
+fn queue_group_sync(db: &Database, keypair: &libp2p::identity::Keypair, peer_id: &PeerId) -> Result<usize> {
+    let my_peer_id = keypair_to_peer_id(keypair);
+
+    let mut queued = 0;
+    for group in db.list_groups()? {
+        if !group.can_manage(&my_peer_id) || !group.is_member(peer_id) {
+            continue;
+        }
+        let key_epoch = db.group_key_epoch(&group.id)?;
+        let updated_at = db.group_updated_at(&group.id)?.unwrap_or(group.created_at);
+        queued += queue_sealed(db, keypair, [*peer_id], |recipient_pk| {
+            let encrypted_key = encrypt_message(&group.symmetric_key, recipient_pk)
+                .context("Failed to encrypt group key")?;
+            let metadata = GroupMetadata::new(&group, key_epoch, updated_at, Some(encrypted_key));
+            Ok(MessageContent::GroupSync(GroupSync::sign(keypair, metadata)?))
+        })?
+        .len();
+    }
+
+    Ok(queued)
+}
+
+/// Seal a control message for each recipient we hold an identity key for
+/// and queue it for delivery. `payload` builds the message given the
+/// recipient's encryption key. Returns the peers it was queued for.
+fn queue_sealed(
+    db: &Database,
+    keypair: &libp2p::identity::Keypair,
+    recipients: impl IntoIterator<Item = PeerId>,
+    mut payload: impl FnMut(&sodiumoxide::crypto::box_::PublicKey) -> Result<MessageContent>,
+) -> Result<Vec<PeerId>> {
+    let my_peer_id = keypair_to_peer_id(keypair);
+    let (our_enc_pk, our_enc_sk) = keypair_to_encryption_keys(keypair)?;
+    let mut queued = Vec::new();
+    for peer_id in recipients {
+        if peer_id == my_peer_id {
+            continue;
+        }
+        let contact = match db.get_contact(&peer_id)? {
+            Some(contact) if !contact.public_key.is_empty() => contact,
+            _ => continue,
+        };
+        let Ok(recipient_pk) = ed25519_pk_to_x25519(&contact.public_key) else {
+            continue;
+        };
+        let envelope = Envelope::new(my_peer_id, payload(&recipient_pk)?);
+        let data = seal_for_contact(
+            db,
+            (&our_enc_pk, &our_enc_sk),
+            &contact.peer_id,
+            &contact.public_key,
+            &envelope.clone().encode_signed(keypair)?,
+        )?;
+        MessageQueue::new(db).enqueue(&envelope.id, &contact.peer_id, &data)?;
+        queued.push(contact.peer_id);
+    }
+
+    Ok(queued)
+}
+
+/// Apply a request to delete a message. Only whoever sent the message can
+/// have it deleted, and only with a request they signed: anyone could put
+/// their name on an unsigned one. Returns true if our copy was replaced
+/// with a tombstone.
+fn receive_delete_request(
+    db: &Database,
+    from: PeerId,
+    authenticity: Authenticity,
+    message_id: &uuid::Uuid,
+) -> Result<bool> {
+    if authenticity != Authenticity::Verified {
+        return Ok(false);
+    }
+    match db.get_message(message_id)? {
+        Some(msg) if msg.from == from && !matches!(msg.content, MessageContent::Tombstone) => {
+            db.tombstone_message(message_id)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Resolve a full or abbreviated message ID.
+fn resolve_message_id(db: &Database, prefix: &str) -> Result<uuid::Uuid> {
+    match db.find_message_ids(prefix, 2)?.as_slice() {
+        [id] => Ok(*id),
+        [] => anyhow::bail!("Message '{}' not found", prefix),
+        _ => anyhow::bail!("Message ID '{}' is ambiguous, use more of it", prefix),
+    }
+}
+
+/// Tell the group's members about a membership change. A removed member is
+/// told too; an added one isn't, as their invite covers it.
+fn announce_member_change(
+    db: &Database,
+    keypair: &libp2p::identity::Keypair,
+    group: &Group,
+    peer_id: PeerId,
+    change: MemberChange,
+) -> Result<Vec<PeerId>> {
+    let mut recipients: Vec<PeerId> = group
+        .member_peer_ids()
+        .into_iter()
+        .filter(|member| *member != peer_id)
+        .collect();
+    if change == MemberChange::Removed {
+        recipients.push(peer_id);
+    }
+    // Our view is now the newest for group sync
+    db.set_group_updated_at(&group.id, Utc::now())?;
+
+    queue_sealed(db, keypair, recipients, |_| {
+        Ok(MessageContent::GroupMemberUpdate(GroupMemberUpdate {
+            group_id: group.id,
+            peer_id,
+            change,
+        }))
+    })
+}
+
+/// Give a group a fresh key so departed members can't read new traffic.
+///
+/// The key is queued, sealed, for each remaining member we hold a public
+/// key for. Returns the new epoch and the members it was queued for.
+fn rotate_group_key(
+    db: &Database,
+    keypair: &libp2p::identity::Keypair,
+    group: &Group,
+) -> Result<(u32, Vec<PeerId>)> {
+    let symmetric_key = generate_group_key();
+    let epoch = db.group_key_epoch(&group.id)? + 1;
+    db.set_group_key(&group.id, epoch, &symmetric_key)?;
+    let detail = format!("Rotated the key for group '{}' to epoch {}", group.name, epoch);
+    db.record_audit(AuditKind::KeyChanged, None, &detail)?;
+
+    let queued = queue_sealed(db, keypair, group.member_peer_ids(), |recipient_pk| {
+        let encrypted_key = encrypt_message(&symmetric_key, recipient_pk)
+            .context("Failed to encrypt group key")?;
+        Ok(MessageContent::GroupKeyUpdate(GroupKeyUpdate {
+            group_id: group.id,
+            epoch,
+            encrypted_key,
+        }))
+    })?;
+
+    Ok((epoch, queued))
+}
+
+/// Our current signed prekey, generating the first one if needed.
+fn current_prekey(db: &Database, keypair: &libp2p::identity::Keypair) -> Result<SignedPrekey> {
+    if let Some(prekey) = db.latest_prekey()? {
+        return Ok(prekey);
+    }
+    let prekey = generate_signed_prekey(keypair, 1)?;
+    db.save_prekey(&prekey)?;
+    Ok(prekey)
+}
+
+/// Verify and store a received file chunk, bumping the transfer's progress.
+fn store_file_chunk(db: &Database, chunk: &crate::message::FileChunk) {
+    if !chunk.verify() {
+        return;
+    }
+    if db.insert_file_chunk(chunk).is_err() {
+        return;
+    }
+    if let Ok(Some(transfer)) = db.get_file_transfer(&chunk.transfer_id) {
+        let received = transfer.chunks_received.saturating_add(1);
+        let _ = db.update_file_transfer_progress(&transfer.id, received);
+    }
+}
+
+/// Record a finished incoming transfer and, once all its chunks are in
+/// and the file checksum matches, mark it complete.
+fn receive_file_complete(db: &Database, from: PeerId, our_peer_id: PeerId, complete: &FileTransferComplete) {
+    // Create incoming transfer record if not exists
+    let transfer = FileTransfer::new_incoming(
+        complete.transfer_id,
+        from,
+        Recipient::Direct(our_peer_id),
+        complete.filename.clone(),
+        complete.total_size,
+        ((complete.total_size as usize).div_ceil(crate::message::FileChunk::CHUNK_SIZE)) as u32,
+        complete.file_checksum,
+    );
+    let _ = db.insert_file_transfer(&transfer);
+    // Try to reassemble if we have all chunks
+    if let Ok(chunks) = db.get_file_chunks(&complete.transfer_id) {
+        if chunks.len() as u32 >= transfer.total_chunks {
+            // Reassemble and verify
+            if let Ok(data) = crate::message::FileTransfer::reassemble_file(&chunks) {
+                use sha2::{Sha256, Digest};
+                let mut hasher = Sha256::new();
+                hasher.update(&data);
+                let checksum: [u8; 32] = hasher.finalize().into();
+                if checksum == complete.file_checksum {
+                    // File verified! Mark as complete and move it out of the database
+                    let _ = db.update_file_transfer_status(&complete.transfer_id, FileTransferStatus::Complete);
+                    // On failure the chunks are kept, so the file is still readable
+                    let _ = db.store_file_blob(&complete.transfer_id, &data);
+                }
+            }
+        }
+    }
 }
-use crate::identity::{
-    export_public_key, generate_keypair, import_public_key, keypair_to_peer_id, load_keypair,
-    save_keypair, Contact, TrustLevel,
-};
-use crate::message::{Group, Message, MessageContent, MessageStatus, Recipient};
-use crate::network::{NodeEvent, WhisperNode};
-use crate::storage::Database;
-use crate::ui::{
-    App, AppMode, DisplayMessage, InputAction,
-    render_chat, render_contacts, render_empty, render_status,
-};
 
 /// Default keypair filename.
 pub const KEYPAIR_FILE: &str = "identity.key";
@@ -104,8 +742,74 @@ fn open_database(data_dir: &Path, passphrase: &str) -> Result<Database> {
         .context("Failed to open database - incorrect passphrase?")
 }
 
+/// Finish a `whisper db rekey` that stopped after the database took its
+/// new key, if `passphrase` opens it, so the keypair sealed with the new
+/// passphrase is in place before anything loads it.
+pub fn finish_interrupted_rekey(data_dir: &Path, passphrase: &str) {
+    if rekey_pending(data_dir) && database_path(data_dir).exists() {
+        let _ = open_database(data_dir, passphrase);
+    }
+}
+
 /// Initialize a new identity.
 pub async fn handle_init(data_dir: &Path, passphrase: &str) -> Result<()> {
+    let (peer_id, public_key) = init_identity(data_dir, passphrase)?;
+
+    println!("Identity created!");
+    println!("Peer ID: {}", peer_id);
+    println!("Public Key: {}", public_key);
+    println!("Saved to: {:?}", keypair_path(data_dir));
+
+    Ok(())
+}
+
+/// Choose Argon2 parameters that take about half a second here, for the
+/// database `whisper init` is about to create.
+pub async fn handle_kdf_benchmark(data_dir: &Path) -> Result<()> {
+    if keypair_path(data_dir).exists() || !is_first_run(data_dir) {
+        anyhow::bail!("{} already has a database key; --kdf-benchmark only applies to new ones", data_dir.display());
+    }
+    println!("Calibrating key derivation...");
+    let params = calibrate_kdf(KDF_TARGET)?;
+    create_salt_file(data_dir, &params)?;
+    println!(
+        "Using Argon2id with {} MiB of memory and {} iterations",
+        params.memory_kib / 1024,
+        params.iterations
+    );
+    Ok(())
+}
+
+/// Restore an identity from the seed phrase `whisper key backup` printed,
+/// with a fresh database.
+pub async fn handle_init_from_seed(data_dir: &Path, passphrase: &str) -> Result<()> {
+    println!("Enter your 24-word seed phrase:");
+    let mut phrase = Zeroizing::new(String::new());
+    io::stdin().read_line(&mut phrase)?;
+    let keypair = keypair_from_mnemonic(&phrase)?;
+    let (peer_id, public_key) = create_identity(data_dir, passphrase, keypair)?;
+
+    println!("Identity restored!");
+    println!("Peer ID: {}", peer_id);
+    println!("Public Key: {}", public_key);
+    println!("Saved to: {:?}", keypair_path(data_dir));
+    println!("Contacts and history aren't part of the seed phrase, so they start empty.");
+
+    Ok(())
+}
+
+/// Create a new identity and encrypted database in `data_dir`. Returns our
+/// peer ID and the contact bundle to share: public key and signed prekey.
+pub fn init_identity(data_dir: &Path, passphrase: &str) -> Result<(PeerId, String)> {
+    create_identity(data_dir, passphrase, generate_keypair())
+}
+
+/// Set up `data_dir` around an identity keypair, new or restored.
+fn create_identity(
+    data_dir: &Path,
+    passphrase: &str,
+    keypair: libp2p::identity::Keypair,
+) -> Result<(PeerId, String)> {
     // Create data directory if needed
     std::fs::create_dir_all(data_dir).context("Failed to create data directory")?;
 
@@ -116,29 +820,96 @@ pub async fn handle_init(data_dir: &Path, passphrase: &str) -> Result<()> {
         anyhow::bail!("Identity already exists at {:?}", key_path);
     }
 
-    // Generate new keypair
-    let keypair = generate_keypair();
     let peer_id = keypair_to_peer_id(&keypair);
-    let public_key = export_public_key(&keypair);
+
+    // The database key is derived with the configured costs, unless
+    // `--kdf-benchmark` already chose some
+    if is_first_run(data_dir) {
+        create_salt_file(data_dir, &configured_kdf(data_dir)?)?;
+    }
 
     // Save keypair
     save_keypair(&keypair, &key_path, passphrase).context("Failed to save keypair")?;
 
-    // Initialize encrypted database
-    let _db = open_database(data_dir, passphrase)?;
+    // Initialize encrypted database with our first signed prekey
+    let db = open_database(data_dir, passphrase)?;
+    let prekey = current_prekey(&db, &keypair)?;
+    let public_key = export_contact_bundle(&keypair, &prekey.public())?;
 
-    println!("Identity created!");
-    println!("Peer ID: {}", peer_id);
-    println!("Public Key: {}", public_key);
-    println!("Saved to: {:?}", key_path);
+    Ok((peer_id, public_key))
+}
 
-    Ok(())
+/// Read a message body piped in on stdin. One trailing newline, as added
+/// by `echo`, is dropped; other line breaks are kept.
+fn read_message_text(mut reader: impl Read) -> Result<String> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).context("Failed to read from stdin")?;
+    let mut text = String::from_utf8(bytes)
+        .map_err(|_| anyhow::anyhow!("Message on stdin is not valid UTF-8 (use --file - to send binary data)"))?;
+    if text.ends_with('\n') {
+        text.pop();
+        if text.ends_with('\r') {
+            text.pop();
+        }
+    }
+    if text.trim().is_empty() {
+        anyhow::bail!("Message on stdin is empty");
+    }
+    Ok(text)
 }
 
-/// Send a message to a contact.
+/// Send a message to a contact. A message of `-` is read from stdin.
 pub async fn handle_send(alias: &str, message: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let message = if message == "-" { read_message_text(io::stdin())? } else { message.to_string() };
+    let db = open_database(data_dir, passphrase)?;
+    send_content(db, alias, MessageContent::Text(message), data_dir, passphrase).await
+}
+
+/// Send a position, given as `LAT,LON`, to a contact.
+pub async fn handle_send_location(
+    alias: &str,
+    location: &str,
+    label: Option<&str>,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
+    let (lat, lon) = parse_location(location)?;
     let db = open_database(data_dir, passphrase)?;
+    let content = MessageContent::Location { lat, lon, label: label.map(str::to_string) };
+    send_content(db, alias, content, data_dir, passphrase).await
+}
+
+/// Introduce one contact to another by sending them its card.
+pub async fn handle_send_card(alias: &str, card_alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let card = db
+        .get_contact_by_alias(card_alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", card_alias))?;
+    let content = contact_card(&card)?;
+    send_content(db, alias, content, data_dir, passphrase).await
+}
 
+/// Parse a `LAT,LON` pair in decimal degrees.
+fn parse_location(location: &str) -> Result<(f64, f64)> {
+    let (lat, lon) = location
+        .split_once(',')
+        .context("Location must be LAT,LON, e.g. 52.52,13.405")?;
+    let lat: f64 = lat.trim().parse().context("Latitude is not a number")?;
+    let lon: f64 = lon.trim().parse().context("Longitude is not a number")?;
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        anyhow::bail!("Location out of range: latitude is -90 to 90, longitude -180 to 180");
+    }
+    Ok((lat, lon))
+}
+
+/// Store `content` as a message to a contact, queue it and try to send it.
+async fn send_content(
+    db: Database,
+    alias: &str,
+    content: MessageContent,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
     // Load our keypair
     let key_path = keypair_path(data_dir);
     if !key_path.exists() {
@@ -151,45 +922,64 @@ pub async fn handle_send(alias: &str, message: &str, data_dir: &Path, passphrase
     let contact = db
         .get_contact_by_alias(alias)?
         .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+    ensure_key_verified(&db, &contact)?;
 
     // Create and store the message
-    let msg = Message::new_text(
-        our_peer_id,
-        Recipient::Direct(contact.peer_id),
-        message.to_string(),
-    );
-    db.insert_message(&msg)?;
-
-    // Encrypt the message
-    let encrypted_data = if !contact.public_key.is_empty() {
-        match ed25519_pk_to_x25519(&contact.public_key) {
-            Ok(recipient_pk) => {
-                encrypt_message(message.as_bytes(), &recipient_pk)
-                    .unwrap_or_else(|_| message.as_bytes().to_vec())
-            }
-            Err(_) => message.as_bytes().to_vec(),
-        }
-    } else {
-        message.as_bytes().to_vec()
+    let msg = Message {
+        id: uuid::Uuid::new_v4(),
+        from: our_peer_id,
+        to: Recipient::Direct(contact.peer_id),
+        content,
+        timestamp: Utc::now(),
+        status: MessageStatus::Pending,
     };
 
-    // Store in persistent queue (survives restarts)
-    db.queue_pending_message(&msg.id, &contact.peer_id, &encrypted_data)?;
+    // Wrap in an envelope; the stamp is worked out before anything is locked
+    let (our_enc_pk, our_enc_sk) = keypair_to_encryption_keys(&keypair)?;
+    let wire = stamp_for(&db, Envelope::from_message(&msg), &contact.peer_id)?.encode_signed(&keypair)?;
+
+    // Store the message, step the session and queue it all at once, so a
+    // crash can't leave a stored message that never goes out
+    let (encrypted_data, copies) = db.transaction(|db| {
+        db.insert_message(&msg)?;
+
+        // Encrypt over the contact's session, and for each of their linked devices
+        let encrypted_data = seal_for_contact(
+            db,
+            (&our_enc_pk, &our_enc_sk),
+            &contact.peer_id,
+            &contact.public_key,
+            &wire,
+        )?;
+        let copies = sealed_for_devices(db, (&our_enc_pk, &our_enc_sk), &contact.peer_id, &wire)?;
+
+        // Store in persistent queue (survives restarts)
+        let queue = MessageQueue::new(db);
+        queue.enqueue(&msg.id, &contact.peer_id, &encrypted_data)?;
+        for (device, data) in &copies {
+            queue.enqueue(&uuid::Uuid::new_v4(), device, data)?;
+        }
+        Ok((encrypted_data, copies))
+    })?;
 
     // Try to send now
-    let mut node = WhisperNode::new(keypair).await.context("Failed to create network node")?;
-    node.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    let network = NetworkConfig::load(data_dir)?;
+    let mut node = create_node(&db, &network, keypair).await?;
+    start_listening(&db, &network, &mut node)?;
     node.send_message(contact.peer_id, encrypted_data);
+    for (device, data) in copies {
+        node.send_message(device, data);
+    }
 
-    println!("Message to {}: {}", contact.alias, message);
+    println!("Message to {}: {}", contact.alias, msg.content.summary());
     println!("(Queued persistently - will deliver when recipient connects.)");
 
     Ok(())
 }
 
 /// Start interactive chat with a contact.
-pub async fn handle_chat(alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
-    let db = open_database(data_dir, passphrase)?;
+pub async fn handle_chat(alias: &str, ephemeral: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = if ephemeral { open_ephemeral_database(data_dir, passphrase)? } else { open_database(data_dir, passphrase)? };
 
     // Load our keypair
     let key_path = keypair_path(data_dir);
@@ -210,103 +1000,250 @@ pub async fn handle_chat(alias: &str, data_dir: &Path, passphrase: &str) -> Resu
     // Create app state
     let mut app = App::new();
     app.set_peer_id(our_peer_id);
+    app.raw_markdown = db.raw_markdown()?;
     for c in contacts {
         app.add_contact(c);
     }
 
-    // Set current chat to the specified contact
-    app.current_chat = Some(contact.peer_id);
-    app.mode = AppMode::Chat;
-
-    // Find the contact index for selection
-    if let Some(idx) = app.contacts.iter().position(|c| c.peer_id == contact.peer_id) {
-        app.selected_contact = idx;
+    for contact in db.list_contacts()? {
+        if db.key_change(&contact.peer_id)?.is_some() {
+            app.key_changed.insert(contact.peer_id);
+        }
     }
 
-    // Load message history
-    let messages = db.get_messages_with_peer(&contact.peer_id, 100)?;
-    for msg in messages {
-        if let MessageContent::Text(text) = msg.content {
-            let is_ours = our_peer_id == msg.from;
-            app.messages.push(DisplayMessage::new(
-                msg.from,
-                text,
-                msg.timestamp,
-                is_ours,
-            ));
-        }
-    }
+    // Conversations for the sidebar and inbox
+    app.set_conversations(db.list_conversations()?);
+    app.message_requests = db.count_message_request_senders()?;
 
-    // Derive encryption keys from our identity keypair
-    let (our_enc_pk, our_enc_sk) = keypair_to_encryption_keys(&keypair)
-        .context("Failed to derive encryption keys")?;
+    // Open the chat with the latest page of history; older pages load on scroll
+    app.open_chat(contact.peer_id);
+    load_history(&db, &mut app)?;
 
     // Create and start the network node
-    let mut node = WhisperNode::new(keypair).await.context("Failed to create network node")?;
+    let network = NetworkConfig::load(data_dir)?;
+    let mut node = create_node(&db, &network, keypair.clone()).await?;
     
-    // Listen on a random port
-    node.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    // Listen on the configured addresses, or our usual port
+    start_listening(&db, &network, &mut node)?;
     
-    // Share the node for the TUI to send messages
-    let node = Arc::new(Mutex::new(node));
+    // Drive the node in the background; the TUI sends through the handle
+    let mut events = node.subscribe();
+    let node = node.spawn();
+    watch_contacts(&db, &node)?;
+    let session = Session::new(node.clone(), keypair)?;
+    let db = DatabaseHandle::spawn(db)?;
 
     // Run the TUI with network integration
-    run_tui_with_network(&mut app, &db, node, &our_enc_pk, &our_enc_sk).await?;
+    let result = run_tui_with_network(&mut app, &db, &session, &mut events).await;
+    node.shutdown();
+    // Storage is wrapped up even when the TUI failed, but its error comes first
+    let flushed = db.flush().await;
+    let forgotten = if ephemeral { forget_replaced_sessions(&db, data_dir, passphrase).await } else { Ok(()) };
+    result?;
+    flushed?;
+    forgotten
+}
+
+/// Open an in-memory copy of the database, for `whisper chat --ephemeral`.
+fn open_ephemeral_database(data_dir: &Path, passphrase: &str) -> Result<Database> {
+    let key = crate::storage::derive_database_key(passphrase, data_dir)?;
+    Database::open_ephemeral_copy(&database_path(data_dir), &key)
+        .context("Failed to open database - incorrect passphrase?")
+}
 
+/// After an ephemeral chat, drop the stored sessions with everyone it
+/// started a new one with. Peers moved on to the throwaway session, so
+/// the stored one can't talk to them any more; the next chat starts afresh.
+async fn forget_replaced_sessions(db: &DatabaseHandle, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let peers = db.call(|db| db.session_peers()).await?;
+    if peers.is_empty() {
+        return Ok(());
+    }
+    let stored = open_database(data_dir, passphrase)?;
+    for peer_id in peers {
+        stored.delete_session(&peer_id)?;
+    }
     Ok(())
 }
 
-/// Run the TUI event loop with network integration.
+/// Run the TUI event loop with network integration. Storage work runs on
+/// the storage thread, so the UI and network aren't held up by queries.
 async fn run_tui_with_network(
     app: &mut App,
-    db: &Database,
-    node: Arc<Mutex<WhisperNode>>,
-    our_enc_pk: &sodiumoxide::crypto::box_::PublicKey,
-    our_enc_sk: &sodiumoxide::crypto::box_::SecretKey,
+    db: &DatabaseHandle,
+    session: &Arc<Session>,
+    events: &mut broadcast::Receiver<NodeEvent>,
 ) -> Result<()> {
-    // Setup terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
+    // Setup terminal; it's restored when the guard drops, however we leave
+    let mut terminal = TerminalGuard::enter()?;
+    let mut interrupted = std::pin::pin!(tokio::signal::ctrl_c());
+
+    // Track connected peers for the status bar and read receipts
+    let mut connected: HashSet<PeerId> = HashSet::new();
+    let mut latencies: HashMap<PeerId, Latency> = HashMap::new();
+    let mut presence_due = Instant::now() + PRESENCE_INTERVAL;
+    let mut retry_due = Instant::now();
 
-    // Track connected peers for status bar
-    let mut connected_count = 0usize;
+    // Image previews, where the terminal can draw them
+    app.image_protocol = ImageProtocol::detect();
+    let cell = cell_size();
+    let mut shown: Vec<(uuid::Uuid, Rect)> = Vec::new();
 
     // Main loop
     loop {
+        // Ctrl+C typed in the TUI arrives as a key; this is a SIGINT from elsewhere
+        if interrupted.as_mut().now_or_never().is_some() {
+            break;
+        }
+
+        for id in app.sync_previews() {
+            let our_peer_id = session.peer_id();
+            let contents = db
+                .call(move |db| attachment_contents(db, our_peer_id, &id))
+                .await
+                .unwrap_or_default();
+            let preview = contents
+                .zip(app.image_protocol)
+                .and_then(|((_, data), protocol)| Preview::encode(protocol, &data, cell).ok());
+            app.set_preview(id, preview);
+        }
+
         // Draw
+        let typing = app.typing_label(Instant::now());
+        let key_warning = app.key_warning();
+        let mut placements = Vec::new();
         terminal.draw(|frame| {
+            let transfer_rows = transfers_height(&app.transfers);
             let chunks = Layout::default()
                 .direction(Direction::Vertical)
-                .constraints([Constraint::Min(3), Constraint::Length(3)])
+                .constraints([
+                    Constraint::Min(3),
+                    Constraint::Length(transfer_rows),
+                    Constraint::Length(3),
+                ])
                 .split(frame.area());
 
             match app.mode {
-                AppMode::Contacts => {
-                    if app.contacts.is_empty() {
-                        render_empty(frame, chunks[0], "No contacts. Add with: whisper add <alias> <peer_id>");
+                AppMode::Contacts | AppMode::Rename | AppMode::Connect => {
+                    let list_area = if app.mode == AppMode::Contacts {
+                        chunks[0]
+                    } else {
+                        let rows = Layout::default()
+                            .direction(Direction::Vertical)
+                            .constraints([Constraint::Min(3), Constraint::Length(3)])
+                            .split(chunks[0]);
+                        if app.mode == AppMode::Rename {
+                            render_rename(frame, rows[1], &app.rename, app.rename_error.as_deref());
+                        } else {
+                            render_connect(frame, rows[1], &app.connect, app.connect_result.as_ref());
+                        }
+                        rows[0]
+                    };
+                    if app.conversations.is_empty() {
+                        let empty = if app.show_archived {
+                            "No archived conversations. Press A to go back"
+                        } else if app.archived_count() > 0 {
+                            "Every conversation is archived. Press A to see them"
+                        } else {
+                            "No contacts. Add with: whisper add <alias> <peer_id>"
+                        };
+                        render_empty(frame, list_area, empty);
                     } else {
-                        render_contacts(frame, chunks[0], &app.contacts, app.selected_contact);
+                        render_conversations(
+                            frame,
+                            list_area,
+                            &app.conversations,
+                            &app.contacts,
+                            app.selected_conversation,
+                            app.our_peer_id,
+                            (!app.show_archived).then(|| app.archived_count()),
+                        );
                     }
                 }
                 AppMode::Chat | AppMode::Input => {
-                    render_chat(
+                    let chat_area = if app.show_sidebar {
+                        let columns = Layout::default()
+                            .direction(Direction::Horizontal)
+                            .constraints([Constraint::Length(SIDEBAR_WIDTH), Constraint::Min(20)])
+                            .split(chunks[0]);
+                        render_sidebar(frame, columns[0], &app.conversations, app.current_chat);
+                        columns[1]
+                    } else {
+                        chunks[0]
+                    };
+                    let chat_area = match &key_warning {
+                        Some(warning) => {
+                            let rows = Layout::default()
+                                .direction(Direction::Vertical)
+                                .constraints([Constraint::Length(3), Constraint::Min(3)])
+                                .split(chat_area);
+                            render_warning(frame, rows[0], warning);
+                            rows[1]
+                        }
+                        None => chat_area,
+                    };
+                    placements = render_chat(
                         frame,
-                        chunks[0],
-                        &app.messages,
+                        chat_area,
+                        &app.chat,
                         &app.input,
-                        app.mode == AppMode::Input,
+                        (app.mode == AppMode::Input).then_some(app.cursor),
+                        typing.as_deref(),
+                        app.raw_markdown,
                     );
                 }
+                AppMode::Search => {
+                    render_search(frame, chunks[0], &app.search_results, &app.search, &app.contacts);
+                }
+            }
+
+            // File transfer progress bars
+            if transfer_rows > 0 {
+                render_transfers(frame, chunks[1], &app.transfers);
             }
 
-            // Status bar with connected peer count
+            // Status bar with connected peer count, and how the open chat's path is doing
             let peer_id = app.our_peer_id.unwrap_or_else(PeerId::random);
-            render_status(frame, chunks[1], &peer_id, connected_count);
+            let latency = app.current_chat.and_then(|peer| latencies.get(&peer).copied());
+            render_status(frame, chunks[2], &peer_id, connected.len(), latency, app.message_requests);
         })?;
 
+        // Previews go over the rows kept for them once the frame is drawn.
+        // When they move, the screen is wiped and drawn again from scratch.
+        if let Some(protocol) = app.image_protocol.filter(|_| placements != shown) {
+            if !shown.is_empty() {
+                clear_previews(terminal.backend_mut(), protocol)?;
+                terminal.clear()?;
+                shown.clear();
+                continue;
+            }
+            draw_previews(terminal.backend_mut(), &app.previews, &placements)?;
+            shown = placements;
+        }
+
+        // Messages on screen have been seen
+        let viewed: Vec<_> = app
+            .take_viewed()
+            .into_iter()
+            .map(|(peer_id, message_id)| (peer_id, message_id, connected.contains(&peer_id)))
+            .collect();
+        let read_chat = app
+            .viewing()
+            .filter(|peer_id| app.clear_unread(&Recipient::Direct(*peer_id)));
+        if !viewed.is_empty() || read_chat.is_some() {
+            let _ = session
+                .call(db, move |db, session| {
+                    for (peer_id, message_id, connected) in viewed {
+                        let _ = mark_read(db, &session.node, &session.keypair, peer_id, &message_id, connected);
+                    }
+                    if let Some(peer_id) = read_chat {
+                        db.mark_conversation_read(&Recipient::Direct(peer_id))?;
+                    }
+                    Ok(())
+                })
+                .await;
+        }
+
         // Poll for keyboard input (non-blocking)
         if event::poll(Duration::from_millis(50))? {
             if let Event::Key(key) = event::read()? {
@@ -315,58 +1252,111 @@ async fn run_tui_with_network(
                 match action {
                     InputAction::Send(text) => {
                         if let Some(peer_id) = app.current_chat {
-                            // Get contact's public key for encryption
-                            let contact_opt = db.get_contact(&peer_id).ok().flatten();
-                            
                             // Create and store message (plaintext in our local DB)
-                            let from = app.our_peer_id.unwrap_or_else(PeerId::random);
                             let msg = Message::new_text(
-                                from,
+                                session.peer_id(),
                                 Recipient::Direct(peer_id),
                                 text.clone(),
                             );
+                            let stored = msg.clone();
+                            let _ = session
+                                .call(db, move |db, session| {
+                                    let _ = db.insert_message(&stored);
+
+                                    // Wrap in an envelope and encrypt over the contact's session
+                                    let public_key = db
+                                        .get_contact(&peer_id)
+                                        .ok()
+                                        .flatten()
+                                        .map(|c| c.public_key)
+                                        .unwrap_or_default();
+                                    let wire = stamp_for(db, Envelope::from_message(&stored), &peer_id)?
+                                        .encode_signed(&session.keypair)?;
+                                    let data = seal_for_contact(db, session.keys(), &peer_id, &public_key, &wire)?;
+                                    session.node.send_message(peer_id, data);
+                                    for (device, data) in sealed_for_devices(db, session.keys(), &peer_id, &wire)? {
+                                        session.node.send_message(device, data);
+                                    }
+                                    Ok(())
+                                })
+                                .await;
+                            app.record_message(Recipient::Direct(peer_id), &msg);
 
-                            // Store in database
-                            let _ = db.insert_message(&msg);
-
-                            // Encrypt and send over network
-                            {
-                                let mut node = node.lock().await;
-                                
-                                // Try to encrypt with contact's public key
-                                let data = if let Some(contact) = contact_opt {
-                                    if !contact.public_key.is_empty() {
-                                        // Convert Ed25519 public key to X25519 for encryption
-                                        match ed25519_pk_to_x25519(&contact.public_key) {
-                                            Ok(recipient_pk) => {
-                                                match encrypt_message(text.as_bytes(), &recipient_pk) {
-                                                    Ok(encrypted) => encrypted,
-                                                    Err(_) => text.as_bytes().to_vec(), // Fallback
-                                                }
-                                            }
-                                            Err(_) => text.as_bytes().to_vec(), // Fallback
-                                        }
+                            // Add to display
+                            app.chat.messages.push(
+                                DisplayMessage::new(msg.from, text, Utc::now(), true).with_id(msg.id),
+                            );
+                        }
+                    }
+                    InputAction::Delete(id) => {
+                        if let Some(peer_id) = app.current_chat {
+                            app.mark_deleted(&id);
+                            let is_connected = connected.contains(&peer_id);
+                            let _ = session
+                                .call(db, move |db, session| {
+                                    let _ = db.tombstone_message(&id);
+
+                                    // Best effort: ask them to drop their copy too
+                                    let public_key = db
+                                        .get_contact(&peer_id)
+                                        .ok()
+                                        .flatten()
+                                        .map(|c| c.public_key)
+                                        .unwrap_or_default();
+                                    let envelope = Envelope::new(session.peer_id(), MessageContent::DeleteRequest(id));
+                                    let wire = envelope.clone().encode_signed(&session.keypair)?;
+                                    let data = seal_for_contact(db, session.keys(), &peer_id, &public_key, &wire)?;
+                                    if is_connected {
+                                        session.node.send_message(peer_id, data);
                                     } else {
-                                        // No public key stored, send unencrypted (for now)
-                                        text.as_bytes().to_vec()
+                                        MessageQueue::new(db).enqueue(&envelope.id, &peer_id, &data)?;
                                     }
-                                } else {
-                                    // Contact not found, send unencrypted
-                                    text.as_bytes().to_vec()
-                                };
-                                
-                                node.send_message(peer_id, data);
+                                    Ok(())
+                                })
+                                .await;
+                        }
+                    }
+                    InputAction::LoadOlder => {
+                        if let Some(peer_id) = app.current_chat {
+                            let loaded = app.chat.history_loaded();
+                            if let Ok(page) = db.call(move |db| read_history(db, &peer_id, loaded)).await {
+                                show_history(app, page);
                             }
-
-                            // Add to display
-                            app.messages.push(DisplayMessage::new(
-                                from,
-                                text,
-                                Utc::now(),
-                                true,
-                            ));
                         }
                     }
+                    InputAction::Search(query) => {
+                        let our_peer_id = app.our_peer_id;
+                        if let Ok(results) = db.call(move |db| Ok(search_results(db, our_peer_id, &query))).await {
+                            app.search_results = results;
+                        }
+                    }
+                    InputAction::Rename(peer_id, alias) => {
+                        let new_alias = alias.clone();
+                        match db.call(move |db| db.rename_contact(&peer_id, &new_alias)).await {
+                            Ok(_) => app.contact_renamed(peer_id, &alias),
+                            Err(e) => app.rename_error = Some(e.to_string()),
+                        }
+                    }
+                    InputAction::Connect(address) => {
+                        let result = match parse_connect_address(&address) {
+                            Ok(addr) => connect_and_record(db, &session.node, addr).await,
+                            Err(e) => Err(e),
+                        };
+                        app.connect_result = Some(result.map(|reached| reached.name()).map_err(|e| e.to_string()));
+                    }
+                    InputAction::Open(id) => {
+                        let our_peer_id = session.peer_id();
+                        let contents = db.call(move |db| attachment_contents(db, our_peer_id, &id)).await;
+                        if let Ok(Some((filename, data))) = contents {
+                            let _ = open_externally(&filename, &data);
+                        }
+                    }
+                    InputAction::Pin(with, pinned) => {
+                        let _ = db.call(move |db| db.set_pinned(&with, pinned)).await;
+                    }
+                    InputAction::Archive(with, archived) => {
+                        let _ = db.call(move |db| db.set_archived(&with, archived)).await;
+                    }
                     InputAction::Cancel => {}
                     InputAction::None => {}
                 }
@@ -377,173 +1367,434 @@ async fn run_tui_with_network(
             }
         }
 
-        // Poll network for events (with timeout so we don't block)
-        {
-            let mut node = node.lock().await;
-            // Use tokio::select with a timeout to poll network without blocking
-            let poll_result = tokio::time::timeout(
-                Duration::from_millis(10),
-                node.poll_event()
-            ).await;
-
-            if let Ok(Some(event)) = poll_result {
-                match event {
-                    NodeEvent::PeerConnected(peer_id) => {
-                        connected_count += 1;
-                        // Update last_seen for this contact if we have them
-                        if let Ok(Some(mut contact)) = db.get_contact(&peer_id) {
-                            contact.last_seen = Some(Utc::now());
-                            let _ = db.upsert_contact(&contact);
-                        }
-                        
-                        // Flush pending messages for this peer from persistent queue
-                        if let Ok(pending) = db.get_pending_for_peer(&peer_id) {
-                            for (msg_id, encrypted_data) in pending {
-                                node.send_message(peer_id, encrypted_data);
-                                // Remove from queue after sending
-                                let _ = db.remove_pending_message(&msg_id);
-                            }
+        // Keep our presence fresh with contacts who can see it
+        if Instant::now() >= presence_due {
+            presence_due = Instant::now() + PRESENCE_INTERVAL;
+            let peers: Vec<PeerId> = connected.iter().copied().collect();
+            let _ = session
+                .call(db, move |db, session| {
+                    announce_presence_to(db, session, &peers);
+                    Ok(())
+                })
+                .await;
+        }
+
+        // Look again for peers with messages waiting
+        if Instant::now() >= retry_due {
+            retry_due = Instant::now() + RETRY_INTERVAL;
+            let peers = connected.clone();
+            let _ = session.call(db, move |db, session| retry_pending(db, &session.node, &peers)).await;
+        }
+
+        // Let the other side know we're composing, rate-limited
+        if app.should_send_typing(Instant::now()) {
+            if let Some(peer_id) = app.current_chat {
+                let _ = session
+                    .call(db, move |db, session| {
+                        let public_key = db
+                            .get_contact(&peer_id)
+                            .ok()
+                            .flatten()
+                            .map(|c| c.public_key)
+                            .unwrap_or_default();
+                        let wire = Envelope::new(session.peer_id(), MessageContent::Typing).encode_signed(&session.keypair)?;
+                        let data = seal_for_contact(db, session.keys(), &peer_id, &public_key, &wire)?;
+                        session.node.send_message(peer_id, data);
+                        Ok(())
+                    })
+                    .await;
+            }
+        }
+
+        // Drain network events without blocking the UI
+        loop {
+            let event = match events.try_recv() {
+                Ok(event) => event,
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            };
+
+            match &event {
+                NodeEvent::PeerConnected(peer_id) => {
+                    connected.insert(*peer_id);
+                }
+                NodeEvent::PeerDisconnected(peer_id) => {
+                    connected.remove(peer_id);
+                    latencies.remove(peer_id);
+                    continue;
+                }
+                NodeEvent::Latency { peer, latency } => {
+                    latencies.insert(*peer, *latency);
+                }
+                NodeEvent::TransferProgress { transfer_id, chunks_done, total_chunks, direction, .. } => {
+                    app.update_transfer(
+                        *transfer_id,
+                        *chunks_done,
+                        *total_chunks,
+                        *direction == TransferDirection::Outgoing,
+                    );
+                    continue;
+                }
+                _ => {}
+            }
+
+            let updates = session
+                .call(db, move |db, session| Ok(direct_event(db, session, event)))
+                .await
+                .unwrap_or_default();
+            for update in updates {
+                match update {
+                    ChatUpdate::Transfer { id, filename, total_chunks } => {
+                        app.track_transfer(id, filename, total_chunks, false);
+                    }
+                    ChatUpdate::Typing(from) => app.set_typing(from, Instant::now()),
+                    ChatUpdate::Presence { from, presence } => app.set_presence(from, presence),
+                    ChatUpdate::Deleted(id) => app.mark_deleted(&id),
+                    ChatUpdate::KeyChanged(from) => {
+                        app.key_changed.insert(from);
+                    }
+                    ChatUpdate::Message { from, msg, text, unverified, alert } => {
+                        if alert == Some(Alert::Bell) && app.viewing() != Some(from) {
+                            ring_bell();
                         }
+                        // Their message has arrived, so they're done typing
+                        app.clear_typing(&from);
+                        // Show it in the sender's chat if it's loaded
+                        app.record_message(Recipient::Direct(from), &msg);
+                        app.handle_message(
+                            DisplayMessage::new(from, text, msg.timestamp, false)
+                                .with_id(msg.id)
+                                .with_unverified(unverified)
+                                .with_attachment(image_attachment(&msg.content)),
+                        );
+                        app.mark_unread(from, msg.id);
                     }
-                    NodeEvent::PeerDisconnected(_) => {
-                        connected_count = connected_count.saturating_sub(1);
+                    ChatUpdate::Requests { from, waiting } => {
+                        app.clear_typing(&from);
+                        app.message_requests = waiting;
                     }
-                    NodeEvent::MessageReceived { from, data } => {
-                        // Try to decrypt with our secret key, fall back to plaintext
-                        let decrypted = match decrypt_message(&data, our_enc_pk, our_enc_sk) {
-                            Ok(plaintext) => plaintext,
-                            Err(_) => data.clone(), // Not encrypted or wrong key
-                        };
+                }
+            }
+        }
+    }
 
-                        // Check if this is a receipt
-                        if let Some((msg_id, receipt_type)) = parse_receipt(&decrypted) {
-                            // Update the message status in our database
-                            let new_status = match receipt_type {
-                                crate::message::ReceiptType::Delivered => MessageStatus::Delivered,
-                                crate::message::ReceiptType::Read => MessageStatus::Read,
-                            };
-                            let _ = db.update_message_status(&msg_id, &new_status);
-                            // Don't display receipts in chat
-                            continue;
-                        }
+    Ok(())
+}
 
-                        // Check if this is a file chunk
-                        if decrypted.starts_with(FILE_CHUNK_PREFIX) {
-                            if let Ok(chunk) = bincode::deserialize::<crate::message::FileChunk>(&decrypted[FILE_CHUNK_PREFIX.len()..]) {
-                                // Verify checksum
-                                if chunk.verify() {
-                                    // Save chunk to database
-                                    let _ = db.insert_file_chunk(&chunk);
-                                    // Update transfer progress if it exists
-                                    if let Ok(Some(mut transfer)) = db.get_file_transfer(&chunk.transfer_id) {
-                                        transfer.chunks_received = transfer.chunks_received.saturating_add(1);
-                                        let _ = db.update_file_transfer_progress(&transfer.id, transfer.chunks_received);
-                                    }
-                                }
-                            }
-                            continue;
-                        }
+/// A change to the chat TUI from a network event.
+enum ChatUpdate {
+    /// A peer offered us a file.
+    Transfer { id: uuid::Uuid, filename: String, total_chunks: u32 },
+    /// A peer is typing.
+    Typing(PeerId),
+    /// A contact announced their presence.
+    Presence { from: PeerId, presence: Presence },
+    /// A message was deleted at its sender's request.
+    Deleted(uuid::Uuid),
+    /// A contact's identity key changed; nothing goes to them until it's
+    /// verified.
+    KeyChanged(PeerId),
+    /// A message arrived from a contact, alerting the user as its
+    /// conversation's notification settings say.
+    Message { from: PeerId, msg: Box<Message>, text: String, unverified: bool, alert: Option<Alert> },
+    /// A message request arrived; this many senders are waiting.
+    Requests { from: PeerId, waiting: usize },
+}
 
-                        // Check if this is a file transfer completion
-                        if decrypted.starts_with(FILE_COMPLETE_PREFIX) {
-                            if let Ok(complete) = bincode::deserialize::<FileTransferComplete>(&decrypted[FILE_COMPLETE_PREFIX.len()..]) {
-                                // Create incoming transfer record if not exists
-                                let transfer = FileTransfer::new_incoming(
-                                    complete.transfer_id,
-                                    from,
-                                    Recipient::Direct(app.our_peer_id.unwrap_or_else(PeerId::random)),
-                                    complete.filename.clone(),
-                                    complete.total_size,
-                                    ((complete.total_size as usize).div_ceil(crate::message::FileChunk::CHUNK_SIZE)) as u32,
-                                    complete.file_checksum,
-                                );
-                                let _ = db.insert_file_transfer(&transfer);
-                                // Try to reassemble if we have all chunks
-                                if let Ok(chunks) = db.get_file_chunks(&complete.transfer_id) {
-                                    if chunks.len() as u32 >= transfer.total_chunks {
-                                        // Reassemble and verify
-                                        if let Ok(data) = crate::message::FileTransfer::reassemble_file(&chunks) {
-                                            use sha2::{Sha256, Digest};
-                                            let mut hasher = Sha256::new();
-                                            hasher.update(&data);
-                                            let checksum: [u8; 32] = hasher.finalize().into();
-                                            if checksum == complete.file_checksum {
-                                                // File verified! Mark as complete
-                                                let _ = db.update_file_transfer_status(&complete.transfer_id, FileTransferStatus::Complete);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            continue;
-                        }
+/// Ring the terminal bell.
+fn ring_bell() {
+    let mut stdout = io::stdout();
+    let _ = io::Write::write_all(&mut stdout, b"\x07").and_then(|()| io::Write::flush(&mut stdout));
+}
+
+/// Apply a network event for the chat TUI to the database, returning what
+/// to change on screen.
+fn direct_event(db: &Database, session: &Session, event: NodeEvent) -> Vec<ChatUpdate> {
+    let (node, keypair, our_keys) = (&session.node, &session.keypair, session.keys());
+    let our_peer_id = session.peer_id();
+    let mut updates = Vec::new();
+
+    match event {
+        NodeEvent::PeerConnected(peer_id) => {
+            // Update last_seen for this contact if we have them
+            if let Ok(Some(mut contact)) = db.get_contact(&peer_id) {
+                contact.last_seen = Some(Utc::now());
+                let _ = db.upsert_contact(&contact);
+            }
+            let _ = announce_presence(db, node, keypair, our_keys, &peer_id);
+            let _ = announce_devices(db, node, keypair, our_keys, &peer_id);
+            let _ = offer_history(db, node, keypair, our_keys, &peer_id);
+            let _ = request_history(db, node, keypair, &peer_id);
+
+            // Bring their copy of groups we manage up to date
+            let _ = queue_group_sync(db, keypair, &peer_id);
+
+            // Flush pending messages for this peer from persistent queue
+            let _ = MessageQueue::new(db).flush(&peer_id, |data| node.send_message(peer_id, data));
+            let _ = forward_mail(db, node, keypair, our_keys, &peer_id);
+            let _ = deposit_pending(db, node, keypair, our_keys, &peer_id);
+        }
+        NodeEvent::MessageReceived { from, data } => {
+            if refuse_blocked(db, &from) {
+                return updates;
+            }
+            // Decrypt over the sender's session, falling back to plaintext
+            let decrypted = open_from_peer(db, our_keys, &from, &data);
 
-                        // Regular text message
-                        let text = String::from_utf8_lossy(&decrypted).to_string();
+            let Ok(mut envelope) = Envelope::decode(&decrypted) else {
+                return updates; // Not a whisper envelope
+            };
+            let authenticity = authenticate_from(db, &envelope, &from);
+            if authenticity == Authenticity::Forged || is_replay(db, &envelope) {
+                return updates;
+            }
+            // Strangers' first messages must be stamped
+            if !admits_sender(db, &our_peer_id, &envelope) {
+                return updates;
+            }
+            if db.key_change(&envelope.sender).ok().flatten().is_some() {
+                updates.push(ChatUpdate::KeyChanged(envelope.sender));
+            }
+            attribute_device(db, &mut envelope);
+
+            // Check if this is a receipt
+            if let Some((msg_id, receipt_type)) = parse_receipt(&envelope) {
+                // Update the message status in our database
+                let _ = receive_receipt(db, &envelope.sender, &msg_id, receipt_type);
+                // Don't display receipts in chat
+                return updates;
+            }
 
-                        // Store in database
-                        let msg = Message::new_text(
-                            from,
-                            Recipient::Direct(app.our_peer_id.unwrap_or_else(PeerId::random)),
-                            text.clone(),
-                        );
-                        let _ = db.insert_message(&msg);
-
-                        // Send delivery receipt back to sender
-                        let receipt = create_receipt(&msg.id, crate::message::ReceiptType::Delivered);
-                        node.send_message(from, receipt);
-
-                        // Add to display if it's from current chat
-                        if app.current_chat == Some(from) {
-                            app.messages.push(DisplayMessage::new(
-                                from,
-                                text,
-                                Utc::now(),
-                                false,
-                            ));
+            let text = match &envelope.payload {
+                MessageContent::Text(text) => text.clone(),
+                MessageContent::File(offer) => {
+                    // Record the incoming transfer so chunks have somewhere to land
+                    let transfer = FileTransfer::new_incoming(
+                        offer.transfer_id,
+                        from,
+                        Recipient::Direct(our_peer_id),
+                        offer.filename.clone(),
+                        offer.total_size,
+                        offer.total_chunks,
+                        offer.file_checksum,
+                    );
+                    let _ = db.insert_file_transfer(&transfer);
+                    updates.push(ChatUpdate::Transfer {
+                        id: offer.transfer_id,
+                        filename: offer.filename.clone(),
+                        total_chunks: offer.total_chunks,
+                    });
+                    file_text(offer)
+                }
+                MessageContent::Location { .. } => envelope.payload.summary(),
+                MessageContent::ContactCard { .. } => contact_card_text(db, &our_peer_id, from, &envelope.payload),
+                MessageContent::FileChunk(chunk) => {
+                    store_file_chunk(db, chunk);
+                    return updates;
+                }
+                MessageContent::FileComplete(complete) => {
+                    receive_file_complete(db, from, our_peer_id, complete);
+                    return updates;
+                }
+                MessageContent::GroupInvite(invite) => {
+                    match receive_group_invite(db, our_keys, from, invite) {
+                        Ok(Some(pending)) => format!(
+                            "[invite] group '{}' - run: whisper group accept {}",
+                            pending.name, pending.name
+                        ),
+                        _ => return updates,
+                    }
+                }
+                MessageContent::GroupKeyUpdate(update) => {
+                    let _ = receive_group_key_update(db, our_keys, from, update);
+                    return updates;
+                }
+                MessageContent::GroupMemberUpdate(update) => {
+                    let _ = receive_group_member_update(db, our_peer_id, from, update);
+                    return updates;
+                }
+                MessageContent::GroupLeave(leave) => {
+                    let _ = receive_group_leave(db, keypair, from, leave);
+                    return updates;
+                }
+                MessageContent::GroupSync(sync) => {
+                    let _ = receive_group_sync(db, our_peer_id, our_keys, from, sync);
+                    return updates;
+                }
+                MessageContent::Typing => {
+                    if typing_is_fresh(&envelope) {
+                        updates.push(ChatUpdate::Typing(envelope.sender));
+                    }
+                    return updates;
+                }
+                MessageContent::Presence(status) => {
+                    if let Ok(Some(presence)) = receive_presence(db, &from, *status) {
+                        updates.push(ChatUpdate::Presence { from, presence });
+                    }
+                    return updates;
+                }
+                MessageContent::DeleteRequest(id) => {
+                    if receive_delete_request(db, envelope.sender, authenticity, id).unwrap_or(false) {
+                        updates.push(ChatUpdate::Deleted(*id));
+                    }
+                    return updates;
+                }
+                MessageContent::MailboxDeposit(_) => {
+                    let _ = receive_deposit(db, node, &from, &envelope);
+                    return updates;
+                }
+                MessageContent::MailboxDelivery(delivery) => {
+                    let Some(data) = open_delivery(db, our_keys, delivery) else {
+                        return updates;
+                    };
+                    return direct_event(db, session, NodeEvent::MessageReceived { from: delivery.from, data });
+                }
+                MessageContent::DeviceList(list) => {
+                    let _ = receive_device_list(db, list);
+                    return updates;
+                }
+                MessageContent::HistoryOffer(ids) => {
+                    if authenticity == Authenticity::Verified {
+                        let _ = send_missing_history(db, node, keypair, our_keys, &from, ids);
+                    }
+                    return updates;
+                }
+                MessageContent::HistoryRequest(request) => {
+                    if authenticity == Authenticity::Verified {
+                        let _ = answer_history_request(db, node, keypair, our_keys, &from, request);
+                    }
+                    return updates;
+                }
+                MessageContent::ChannelSubscribe(id) => {
+                    if authenticity == Authenticity::Verified {
+                        let _ = receive_channel_subscribe(db, node, keypair, our_keys, &from, id);
+                    }
+                    return updates;
+                }
+                MessageContent::ChannelPost(post) => {
+                    let _ = receive_channel_post(db, post);
+                    return updates;
+                }
+                MessageContent::History(messages) => {
+                    if authenticity == Authenticity::Verified {
+                        let _ = receive_history(db, &our_peer_id, &from, messages);
+                    }
+                    return updates;
+                }
+                MessageContent::InviteAccepted(acceptance) => {
+                    if authenticity == Authenticity::Verified {
+                        if let Ok(Some(contact)) = receive_invite_acceptance(db, from, acceptance, Utc::now()) {
+                            node.watch_peer(contact.peer_id);
                         }
                     }
-                    NodeEvent::Listening(addr) => {
-                        // Could display this somewhere
-                        let _ = addr;
+                    return updates;
+                }
+                MessageContent::Receipt(..) | MessageContent::Tombstone => return updates,
+            };
+
+            // Store in database under the sender's message ID.
+            // Messages from strangers wait in message requests.
+            let msg = envelope.into_message(Recipient::Direct(our_peer_id));
+            let inbox = db.receive_message(&msg).ok().flatten();
+            let unverified = authenticity == Authenticity::Unverified;
+            if inbox.is_some() && unverified {
+                let _ = db.mark_message_unverified(&msg.id);
+            }
+
+            // Send delivery receipt back to sender
+            if let Ok(receipt) = create_receipt(keypair, &msg.id, ReceiptType::Delivered) {
+                node.send_message(from, receipt);
+            }
+
+            match inbox {
+                Some(Inbox::Conversation) => {
+                    let from = msg.from;
+                    let alert = alert_for(db, &Recipient::Direct(from), &msg, Utc::now()).ok().flatten();
+                    updates.push(ChatUpdate::Message { from, msg: Box::new(msg), text, unverified, alert });
+                }
+                Some(Inbox::Request) => {
+                    if let Ok(waiting) = db.count_message_request_senders() {
+                        updates.push(ChatUpdate::Requests { from: msg.from, waiting });
                     }
-                    NodeEvent::MessageSent { .. } => {
-                        // Message confirmed sent
+                }
+                None => {}
+            }
+        }
+        NodeEvent::FileChunkReceived { from, data, .. } => {
+            let decrypted = open_from_peer(db, our_keys, &from, &data);
+            if let Ok(envelope) = Envelope::decode(&decrypted) {
+                if let MessageContent::FileChunk(chunk) = &envelope.payload {
+                    if authenticate_from(db, &envelope, &from) != Authenticity::Forged {
+                        store_file_chunk(db, chunk);
                     }
                 }
             }
         }
+        NodeEvent::ReachabilityChanged(reachability) => {
+            let _ = db.save_reachability(reachability);
+        }
+        NodeEvent::PeerAddress { peer, address, reached } => {
+            let _ = db.record_peer_address(&peer, &address, reached);
+        }
+        NodeEvent::Latency { peer, latency } => {
+            let _ = db.record_latency(&peer, latency);
+        }
+        NodeEvent::PeerIdentified { peer, protocol_version, agent_version, .. } => {
+            let _ = db.record_peer_version(&peer, &protocol_version, &agent_version);
+        }
+        NodeEvent::Traffic(traffic) => {
+            let _ = db.record_traffic(&traffic);
+        }
+        NodeEvent::ChannelPostReceived { channel_id, data, .. } => {
+            if let Some(post) = gossiped_post(&channel_id, &data) {
+                let _ = receive_channel_post(db, &post);
+            }
+        }
+        NodeEvent::ChannelJoined { peer, channel_id } => {
+            let _ = receive_channel_joined(db, node, keypair, our_keys, &peer, &channel_id);
+        }
+        // Handled by the UI, or nothing to do
+        NodeEvent::PeerDisconnected(_)
+        | NodeEvent::TransferProgress { .. }
+        | NodeEvent::Listening(_)
+        | NodeEvent::MessageSent { .. }
+        | NodeEvent::RateLimited { .. }
+        | NodeEvent::ProtocolViolation { .. }
+        | NodeEvent::DhtReady { .. }
+        | NodeEvent::RelayReservation { .. }
+        | NodeEvent::RelayReservationLost { .. }
+        | NodeEvent::PostPublished { .. } => {}
     }
-
-    // Restore terminal
-    disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
-    terminal.show_cursor()?;
-
-    Ok(())
+    updates
 }
 
-/// Run the TUI event loop for group chat with multicast.
+/// Run the TUI event loop for group chat with multicast. Storage work runs
+/// on the storage thread, like the direct chat TUI.
 async fn run_group_tui_with_network(
     app: &mut App,
-    db: &Database,
-    node: Arc<Mutex<WhisperNode>>,
+    db: &DatabaseHandle,
+    session: &Arc<Session>,
+    events: &mut broadcast::Receiver<NodeEvent>,
     group: &Group,
-    our_enc_pk: &sodiumoxide::crypto::box_::PublicKey,
-    our_enc_sk: &sodiumoxide::crypto::box_::SecretKey,
 ) -> Result<()> {
-    use crate::crypto::{encrypt_for_group, decrypt_from_group};
-    
-    // Setup terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
+    use crate::crypto::encrypt_for_group;
+
+    // Setup terminal; it's restored when the guard drops, however we leave
+    let mut terminal = TerminalGuard::enter()?;
+    let mut interrupted = std::pin::pin!(tokio::signal::ctrl_c());
 
-    let mut connected_count = 0usize;
+    // Our own copy, refreshed when the group key is rotated
+    let mut group = group.clone();
+    let mut connected: HashSet<PeerId> = HashSet::new();
+    let mut presence_due = Instant::now() + PRESENCE_INTERVAL;
+    let mut retry_due = Instant::now();
 
     loop {
+        // Ctrl+C typed in the TUI arrives as a key; this is a SIGINT from elsewhere
+        if interrupted.as_mut().now_or_never().is_some() {
+            break;
+        }
+
         // Draw
         terminal.draw(|frame| {
             let chunks = Layout::default()
@@ -551,18 +1802,43 @@ async fn run_group_tui_with_network(
                 .constraints([Constraint::Min(3), Constraint::Length(3)])
                 .split(frame.area());
 
-            render_chat(
-                frame,
-                chunks[0],
-                &app.messages,
-                &app.input,
-                app.mode == AppMode::Input,
-            );
+            if app.mode == AppMode::Search {
+                render_search(frame, chunks[0], &app.search_results, &app.search, &app.contacts);
+            } else {
+                render_chat(
+                    frame,
+                    chunks[0],
+                    &app.chat,
+                    &app.input,
+                    (app.mode == AppMode::Input).then_some(app.cursor),
+                    None,
+                    app.raw_markdown,
+                );
+            }
 
             let peer_id = app.our_peer_id.unwrap_or_else(PeerId::random);
-            render_status(frame, chunks[1], &peer_id, connected_count);
+            render_status(frame, chunks[1], &peer_id, connected.len(), None, app.message_requests);
         })?;
 
+        // Keep our presence fresh with contacts who can see it
+        if Instant::now() >= presence_due {
+            presence_due = Instant::now() + PRESENCE_INTERVAL;
+            let peers: Vec<PeerId> = connected.iter().copied().collect();
+            let _ = session
+                .call(db, move |db, session| {
+                    announce_presence_to(db, session, &peers);
+                    Ok(())
+                })
+                .await;
+        }
+
+        // Look again for peers with messages waiting
+        if Instant::now() >= retry_due {
+            retry_due = Instant::now() + RETRY_INTERVAL;
+            let peers = connected.clone();
+            let _ = session.call(db, move |db, session| retry_pending(db, &session.node, &peers)).await;
+        }
+
         // Poll keyboard
         if event::poll(Duration::from_millis(50))? {
             if let Event::Key(key) = event::read()? {
@@ -570,38 +1846,74 @@ async fn run_group_tui_with_network(
 
                 match action {
                     InputAction::Send(text) => {
-                        let from = app.our_peer_id.unwrap_or_else(PeerId::random);
-                        
+                        let from = session.peer_id();
+
                         // Store message with group recipient
                         let msg = Message::new_text(
                             from,
                             Recipient::Group(group.id),
                             text.clone(),
                         );
-                        let _ = db.insert_message(&msg);
-
-                        // Encrypt with group's symmetric key
-                        let encrypted = encrypt_for_group(text.as_bytes(), &group.symmetric_key)
-                            .unwrap_or_else(|_| text.as_bytes().to_vec());
+                        let stored = msg.clone();
+                        let _ = db
+                            .call(move |db| {
+                                let _ = db.insert_message(&stored);
+                                db.mark_conversation_read(&stored.to)
+                            })
+                            .await;
+
+                        // Wrap in an envelope and encrypt with group's symmetric key
+                        let wire = match Envelope::from_message(&msg).encode_signed(&session.keypair) {
+                            Ok(wire) => wire,
+                            Err(_) => continue,
+                        };
+                        let encrypted = encrypt_for_group(&wire, &group.symmetric_key)
+                            .unwrap_or(wire);
 
                         // Send to ALL group members (multicast)
-                        {
-                            let mut node = node.lock().await;
-                            for member in &group.members {
-                                // Don't send to ourselves
-                                if member.peer_id != from {
-                                    node.send_message(member.peer_id, encrypted.clone());
-                                }
+                        for member in &group.members {
+                            // Don't send to ourselves
+                            if member.peer_id != from {
+                                session.node.send_message(member.peer_id, encrypted.clone());
                             }
                         }
 
                         // Add to display
-                        app.messages.push(DisplayMessage::new(
-                            from,
-                            text,
-                            Utc::now(),
-                            true,
-                        ));
+                        app.chat.messages.push(
+                            DisplayMessage::new(from, text, Utc::now(), true).with_id(msg.id),
+                        );
+                    }
+                    InputAction::Delete(id) => {
+                        let _ = db.call(move |db| db.tombstone_message(&id)).await;
+                        app.mark_deleted(&id);
+
+                        // Best effort: ask every member to drop their copy
+                        let from = session.peer_id();
+                        let wire = match Envelope::new(from, MessageContent::DeleteRequest(id)).encode_signed(&session.keypair) {
+                            Ok(wire) => wire,
+                            Err(_) => continue,
+                        };
+                        let encrypted = encrypt_for_group(&wire, &group.symmetric_key)
+                            .unwrap_or(wire);
+                        for member in &group.members {
+                            if member.peer_id != from {
+                                session.node.send_message(member.peer_id, encrypted.clone());
+                            }
+                        }
+                    }
+                    // Only messages received in this session are shown, and
+                    // there's no contact list to rename from
+                    InputAction::LoadOlder
+                    | InputAction::Rename(..)
+                    | InputAction::Connect(_)
+                    | InputAction::Open(_)
+                    | InputAction::Pin(..)
+                    | InputAction::Archive(..) => {}
+                    InputAction::Search(query) => {
+                        let our_peer_id = app.our_peer_id;
+                        if let Ok(results) = db.call(move |db| Ok(search_results(db, our_peer_id, &query))).await {
+                            app.search_results = results;
+                        }
                     }
                     InputAction::Cancel => {}
                     InputAction::None => {}
@@ -613,1225 +1925,6259 @@ async fn run_group_tui_with_network(
             }
         }
 
-        // Poll network
-        {
-            let mut node = node.lock().await;
-            let poll_result = tokio::time::timeout(
-                Duration::from_millis(10),
-                node.poll_event()
-            ).await;
-
-            if let Ok(Some(event)) = poll_result {
-                match event {
-                    NodeEvent::PeerConnected(peer_id) => {
-                        connected_count += 1;
-                        if let Ok(Some(mut contact)) = db.get_contact(&peer_id) {
-                            contact.last_seen = Some(Utc::now());
-                            let _ = db.upsert_contact(&contact);
-                        }
-                        
-                        // Flush pending messages for this peer from persistent queue
-                        if let Ok(pending) = db.get_pending_for_peer(&peer_id) {
-                            for (msg_id, encrypted_data) in pending {
-                                node.send_message(peer_id, encrypted_data);
-                                let _ = db.remove_pending_message(&msg_id);
-                            }
-                        }
-                    }
-                    NodeEvent::PeerDisconnected(_) => {
-                        connected_count = connected_count.saturating_sub(1);
-                    }
-                    NodeEvent::MessageReceived { from, data } => {
-                        // Try group decryption first, then DM decryption, then plaintext
-                        let decrypted = if let Ok(plaintext) = decrypt_from_group(&data, &group.symmetric_key) {
-                            plaintext
-                        } else if let Ok(plaintext) = decrypt_message(&data, our_enc_pk, our_enc_sk) {
-                            plaintext
-                        } else {
-                            data.clone()
-                        };
-
-                        // Check if this is a receipt
-                        if let Some((msg_id, receipt_type)) = parse_receipt(&decrypted) {
-                            let new_status = match receipt_type {
-                                crate::message::ReceiptType::Delivered => MessageStatus::Delivered,
-                                crate::message::ReceiptType::Read => MessageStatus::Read,
-                            };
-                            let _ = db.update_message_status(&msg_id, &new_status);
-                            continue;
-                        }
-
-                        let text = String::from_utf8_lossy(&decrypted).to_string();
-
-                        // Store in database
-                        let msg = Message::new_text(
-                            from,
-                            Recipient::Group(group.id),
-                            text.clone(),
-                        );
-                        let _ = db.insert_message(&msg);
+        // Drain network events without blocking the UI
+        loop {
+            let event = match events.try_recv() {
+                Ok(event) => event,
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            };
 
-                        // Send delivery receipt back to sender
-                        let receipt = create_receipt(&msg.id, crate::message::ReceiptType::Delivered);
-                        node.send_message(from, receipt);
+            match &event {
+                NodeEvent::PeerConnected(peer_id) => {
+                    connected.insert(*peer_id);
+                }
+                NodeEvent::PeerDisconnected(peer_id) => {
+                    connected.remove(peer_id);
+                    continue;
+                }
+                _ => {}
+            }
 
-                        // Add to display (all group messages shown)
-                        app.messages.push(DisplayMessage::new(
+            let current = group.clone();
+            let updates = session
+                .call(db, move |db, session| Ok(group_event(db, session, &current, event)))
+                .await
+                .unwrap_or_default();
+            for update in updates {
+                match update {
+                    GroupUpdate::Refreshed(updated) => group = updated,
+                    GroupUpdate::Removed(from) => {
+                        // We were removed; stop sending to the group
+                        group.members.clear();
+                        app.chat.messages.push(DisplayMessage::new(
                             from,
-                            text,
+                            "[removed from group]".to_string(),
                             Utc::now(),
                             false,
                         ));
                     }
-                    NodeEvent::Listening(_) | NodeEvent::MessageSent { .. } => {}
+                    GroupUpdate::Left { from, at } => {
+                        app.chat.messages.push(DisplayMessage::new(
+                            from,
+                            "[left the group]".to_string(),
+                            at,
+                            false,
+                        ));
+                    }
+                    GroupUpdate::Deleted(id) => app.mark_deleted(&id),
+                    // Add to display (all group messages shown)
+                    GroupUpdate::Message { from, msg, text, unverified } => {
+                        app.chat.messages.push(
+                            DisplayMessage::new(from, text, msg.timestamp, false)
+                                .with_id(msg.id)
+                                .with_unverified(unverified),
+                        );
+                    }
                 }
             }
         }
     }
 
-    // Restore terminal
-    disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
-    terminal.show_cursor()?;
-
     Ok(())
 }
 
-/// List all contacts.
-pub async fn handle_contacts(data_dir: &Path, passphrase: &str) -> Result<()> {
-    let db = open_database(data_dir, passphrase)?;
-
-    let contacts = db.list_contacts()?;
-
-    if contacts.is_empty() {
-        println!("No contacts yet. Add one with: whisper add <alias> <peer_id>");
-        return Ok(());
-    }
+/// A change to the group chat TUI from a network event.
+enum GroupUpdate {
+    /// The group's members or key changed.
+    Refreshed(Group),
+    /// This peer removed us from the group.
+    Removed(PeerId),
+    /// A member left the group.
+    Left { from: PeerId, at: chrono::DateTime<Utc> },
+    /// A message was deleted at its sender's request.
+    Deleted(uuid::Uuid),
+    /// A message arrived in the group.
+    Message { from: PeerId, msg: Box<Message>, text: String, unverified: bool },
+}
 
-    println!("Contacts:");
-    for contact in contacts {
-        let status = match contact.trust_level {
-            TrustLevel::Trusted => "✓ Trusted",
-            TrustLevel::Verified => "◆ Verified",
-            TrustLevel::Blocked => "✗ Blocked",
-            TrustLevel::Unknown => "? Unknown",
-        };
-        println!("  {} [{}] - {}", contact.alias, status, contact.peer_id);
+/// The group as now stored, after a change that may have removed us.
+fn refreshed_group(db: &Database, group_id: &uuid::Uuid, from: PeerId) -> Option<GroupUpdate> {
+    match db.get_group(group_id) {
+        Ok(Some(updated)) => Some(GroupUpdate::Refreshed(updated)),
+        Ok(None) => Some(GroupUpdate::Removed(from)),
+        Err(_) => None,
     }
-
-    Ok(())
 }
 
-/// Add a new contact.
-pub async fn handle_add_contact(alias: &str, peer_id_str: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
-    let db = open_database(data_dir, passphrase)?;
+/// Apply a network event for the group chat TUI to the database,
+/// returning what to change on screen.
+fn group_event(db: &Database, session: &Session, group: &Group, event: NodeEvent) -> Vec<GroupUpdate> {
+    use crate::crypto::decrypt_from_group;
 
-    // Parse peer ID
-    let peer_id: PeerId = peer_id_str
-        .parse()
-        .context("Invalid peer ID format")?;
+    let (node, keypair, our_keys) = (&session.node, &session.keypair, session.keys());
+    let our_peer_id = session.peer_id();
+    let mut updates = Vec::new();
 
-    // Create contact
-    let contact = Contact {
-        peer_id,
-        alias: alias.to_string(),
-        public_key: vec![], // Will be exchanged when connecting
-        trust_level: TrustLevel::Unknown,
-        last_seen: None,
-    };
+    match event {
+        NodeEvent::PeerConnected(peer_id) => {
+            if let Ok(Some(mut contact)) = db.get_contact(&peer_id) {
+                contact.last_seen = Some(Utc::now());
+                let _ = db.upsert_contact(&contact);
+            }
+            let _ = announce_presence(db, node, keypair, our_keys, &peer_id);
+            let _ = announce_devices(db, node, keypair, our_keys, &peer_id);
+            let _ = offer_history(db, node, keypair, our_keys, &peer_id);
+            let _ = request_history(db, node, keypair, &peer_id);
+
+            // Bring their copy of groups we manage up to date
+            let _ = queue_group_sync(db, keypair, &peer_id);
+
+            // Flush pending messages for this peer from persistent queue
+            let _ = MessageQueue::new(db).flush(&peer_id, |data| node.send_message(peer_id, data));
+            let _ = forward_mail(db, node, keypair, our_keys, &peer_id);
+            let _ = deposit_pending(db, node, keypair, our_keys, &peer_id);
+        }
+        NodeEvent::MessageReceived { from, data } => {
+            if refuse_blocked(db, &from) {
+                return updates;
+            }
+            // Try group decryption first, then DM decryption, then plaintext
+            let decrypted = match decrypt_from_group(&data, &group.symmetric_key) {
+                Ok(plaintext) => plaintext,
+                Err(_) => open_from_peer(db, our_keys, &from, &data),
+            };
 
-    // Save to database
-    db.upsert_contact(&contact)?;
+            let Ok(envelope) = Envelope::decode(&decrypted) else {
+                return updates;
+            };
+            let authenticity = authenticate_from(db, &envelope, &from);
+            if authenticity == Authenticity::Forged || is_replay(db, &envelope) {
+                return updates;
+            }
 
-    println!("Added contact: {} ({})", alias, peer_id);
+            // Check if this is a receipt
+            if let Some((msg_id, receipt_type)) = parse_receipt(&envelope) {
+                let _ = receive_receipt(db, &envelope.sender, &msg_id, receipt_type);
+                return updates;
+            }
 
-    Ok(())
-}
-
-/// Show node status.
-pub async fn handle_status(data_dir: &Path, passphrase: &str) -> Result<()> {
-    let key_path = keypair_path(data_dir);
-
-    if !key_path.exists() {
-        println!("No identity found. Run: whisper init");
-        return Ok(());
-    }
+            let text = match &envelope.payload {
+                MessageContent::Text(text) => text.clone(),
+                MessageContent::GroupInvite(invite) => {
+                    // Invites to other groups wait for `whisper group accept`
+                    let _ = receive_group_invite(db, our_keys, from, invite);
+                    return updates;
+                }
+                MessageContent::ContactCard { peer_id, public_key, alias } => {
+                    // Cards wait for `whisper contact accept` too
+                    let _ = receive_contact_card(db, &our_peer_id, from, peer_id, public_key, alias);
+                    return updates;
+                }
+                MessageContent::GroupKeyUpdate(update) => {
+                    // Switch to the new key if it's for this group
+                    let rotated = receive_group_key_update(db, our_keys, from, update)
+                        .unwrap_or(false);
+                    if rotated && update.group_id == group.id {
+                        if let Ok(Some(updated)) = db.get_group(&group.id) {
+                            updates.push(GroupUpdate::Refreshed(updated));
+                        }
+                    }
+                    return updates;
+                }
+                MessageContent::GroupMemberUpdate(update) => {
+                    let changed = receive_group_member_update(db, our_peer_id, from, update)
+                        .unwrap_or(false);
+                    if changed && update.group_id == group.id {
+                        updates.extend(refreshed_group(db, &group.id, from));
+                    }
+                    return updates;
+                }
+                MessageContent::GroupLeave(leave) => {
+                    let left = receive_group_leave(db, keypair, from, leave)
+                        .unwrap_or(false);
+                    if left && leave.group_id == group.id {
+                        if let Ok(Some(updated)) = db.get_group(&group.id) {
+                            updates.push(GroupUpdate::Refreshed(updated));
+                        }
+                        updates.push(GroupUpdate::Left { from, at: leave.left_at });
+                    }
+                    return updates;
+                }
+                MessageContent::GroupSync(sync) => {
+                    let changed = receive_group_sync(db, our_peer_id, our_keys, from, sync)
+                        .unwrap_or(false);
+                    if changed && sync.metadata.group_id == group.id {
+                        updates.extend(refreshed_group(db, &group.id, from));
+                    }
+                    return updates;
+                }
+                MessageContent::DeleteRequest(id) => {
+                    if receive_delete_request(db, envelope.sender, authenticity, id).unwrap_or(false) {
+                        updates.push(GroupUpdate::Deleted(*id));
+                    }
+                    return updates;
+                }
+                MessageContent::Presence(status) => {
+                    let _ = receive_presence(db, &from, *status);
+                    return updates;
+                }
+                MessageContent::HistoryOffer(ids) if authenticity == Authenticity::Verified => {
+                    let _ = send_missing_history(db, node, keypair, our_keys, &from, ids);
+                    return updates;
+                }
+                MessageContent::HistoryRequest(request) if authenticity == Authenticity::Verified => {
+                    let _ = answer_history_request(db, node, keypair, our_keys, &from, request);
+                    return updates;
+                }
+                MessageContent::ChannelSubscribe(id) if authenticity == Authenticity::Verified => {
+                    let _ = receive_channel_subscribe(db, node, keypair, our_keys, &from, id);
+                    return updates;
+                }
+                MessageContent::ChannelPost(post) => {
+                    let _ = receive_channel_post(db, post);
+                    return updates;
+                }
+                MessageContent::History(messages) if authenticity == Authenticity::Verified => {
+                    let _ = receive_history(db, &our_peer_id, &from, messages);
+                    return updates;
+                }
+                _ => return updates,
+            };
 
-    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
-    let peer_id = keypair_to_peer_id(&keypair);
-    let public_key = export_public_key(&keypair);
+            // Nothing a member sends after leaving is accepted
+            if let Ok(Some(left_at)) = db.group_departure(&group.id, &envelope.sender) {
+                if envelope.timestamp > left_at {
+                    return updates;
+                }
+            }
 
-    let db = open_database(data_dir, passphrase)?;
-    let contacts = db.list_contacts()?;
+            // Store in database under the sender's message ID
+            let msg = envelope.into_message(Recipient::Group(group.id));
+            let is_new = db.insert_message(&msg).is_ok();
+            let unverified = authenticity == Authenticity::Unverified;
+            if is_new && unverified {
+                let _ = db.mark_message_unverified(&msg.id);
+            }
 
-    println!("Whisper Status");
-    println!("==============");
-    println!("Peer ID: {}", peer_id);
-    println!("Public Key: {}", public_key);
-    println!("Contacts: {}", contacts.len());
-    println!("Data Dir: {:?}", data_dir);
+            // Send delivery receipt back to sender
+            if let Ok(receipt) = create_receipt(keypair, &msg.id, ReceiptType::Delivered) {
+                node.send_message(from, receipt);
+            }
 
-    Ok(())
+            if is_new {
+                let _ = db.mark_conversation_read(&Recipient::Group(group.id));
+                updates.push(GroupUpdate::Message { from, msg: Box::new(msg), text, unverified });
+            }
+        }
+        NodeEvent::ReachabilityChanged(reachability) => {
+            let _ = db.save_reachability(reachability);
+        }
+        NodeEvent::PeerAddress { peer, address, reached } => {
+            let _ = db.record_peer_address(&peer, &address, reached);
+        }
+        NodeEvent::Latency { peer, latency } => {
+            let _ = db.record_latency(&peer, latency);
+        }
+        NodeEvent::PeerIdentified { peer, protocol_version, agent_version, .. } => {
+            let _ = db.record_peer_version(&peer, &protocol_version, &agent_version);
+        }
+        NodeEvent::Traffic(traffic) => {
+            let _ = db.record_traffic(&traffic);
+        }
+        NodeEvent::ChannelPostReceived { channel_id, data, .. } => {
+            if let Some(post) = gossiped_post(&channel_id, &data) {
+                let _ = receive_channel_post(db, &post);
+            }
+        }
+        NodeEvent::ChannelJoined { peer, channel_id } => {
+            let _ = receive_channel_joined(db, node, keypair, our_keys, &peer, &channel_id);
+        }
+        NodeEvent::PeerDisconnected(_)
+        | NodeEvent::Listening(_)
+        | NodeEvent::MessageSent { .. }
+        | NodeEvent::FileChunkReceived { .. }
+        | NodeEvent::TransferProgress { .. }
+        | NodeEvent::RateLimited { .. }
+        | NodeEvent::ProtocolViolation { .. }
+        | NodeEvent::DhtReady { .. }
+        | NodeEvent::RelayReservation { .. }
+        | NodeEvent::RelayReservationLost { .. }
+        | NodeEvent::PostPublished { .. } => {}
+    }
+    updates
 }
 
-/// Set trust level for a contact.
-pub async fn handle_trust(alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
-    let db = open_database(data_dir, passphrase)?;
+/// A received message as printed by `whisper listen --json`.
+#[derive(Serialize)]
+struct ListenedMessage<'a> {
+    id: uuid::Uuid,
+    from: String,
+    alias: Option<&'a str>,
+    /// The group it was sent to, for group messages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<&'a str>,
+    timestamp: chrono::DateTime<Utc>,
+    text: &'a str,
+    /// Whether the sender was verified from a signature.
+    verified: bool,
+}
 
-    let mut contact = db
-        .get_contact_by_alias(alias)?
-        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+/// A channel post as printed by `whisper listen --json`.
+#[derive(Serialize)]
+struct ListenedPost<'a> {
+    id: uuid::Uuid,
+    channel: &'a str,
+    from: String,
+    timestamp: chrono::DateTime<Utc>,
+    text: &'a str,
+}
 
-    contact.trust_level = TrustLevel::Trusted;
-    db.upsert_contact(&contact)?;
+/// One line of `whisper listen` output for a channel post.
+fn post_line(channel: &Channel, post: &ChannelPost, json: bool) -> Result<String> {
+    if json {
+        let line = ListenedPost {
+            id: post.id,
+            channel: &channel.name,
+            from: channel.owner.to_string(),
+            timestamp: post.posted_at,
+            text: &post.text,
+        };
+        return serde_json::to_string(&line).context("Failed to encode post");
+    }
+    Ok(format!("[{}] ~{}: {}", post.posted_at.format("%Y-%m-%d %H:%M:%S"), channel.name, post.text))
+}
 
-    println!("Marked {} as trusted", alias);
+/// One line of `whisper listen` output for a received message, sent to
+/// `group` if it's a group message.
+fn listen_line(
+    msg: &Message,
+    alias: Option<&str>,
+    group: Option<&str>,
+    text: &str,
+    verified: bool,
+    json: bool,
+) -> Result<String> {
+    if json {
+        let line = ListenedMessage {
+            id: msg.id,
+            from: msg.from.to_string(),
+            alias,
+            group,
+            timestamp: msg.timestamp,
+            text,
+            verified,
+        };
+        return serde_json::to_string(&line).context("Failed to encode message");
+    }
+    let mut name = alias.map_or_else(|| short_peer_id(&msg.from), str::to_string);
+    if !verified {
+        name.push_str(" (unverified)");
+    }
+    if let Some(group) = group {
+        name = format!("#{} {}", group, name);
+    }
+    Ok(format!("[{}] {}: {}", msg.timestamp.format("%Y-%m-%d %H:%M:%S"), name, text))
+}
 
-    Ok(())
+/// Run the node without the TUI, printing each direct and group message as
+/// it arrives until interrupted, and posting it to the webhook if one is
+/// set. Status goes to stderr so stdout can be piped. With `api`, also
+/// serve the control API there.
+pub async fn handle_listen(
+    json: bool,
+    rendezvous_server: bool,
+    metrics: Option<SocketAddr>,
+    api: Option<SocketAddr>,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
+    let options = ListenOptions { json, rendezvous_server, metrics, api, ..Default::default() };
+    listen(options, data_dir, passphrase).await
 }
 
-/// Block a contact.
-pub async fn handle_block(alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+/// Run a bot: `whisper listen`, with each text message from the contacts
+/// in `allow` piped to `command` and its output sent back as the reply.
+pub async fn handle_bot(command: Vec<String>, allow: &[String], data_dir: &Path, passphrase: &str) -> Result<()> {
+    if allow.is_empty() {
+        anyhow::bail!("Name the contacts the bot answers with --allow <alias>");
+    }
     let db = open_database(data_dir, passphrase)?;
+    let mut allowed = HashSet::new();
+    for alias in allow {
+        let contact = db
+            .get_contact_by_alias(alias)?
+            .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+        allowed.insert(contact.peer_id);
+    }
+    drop(db);
 
-    let mut contact = db
-        .get_contact_by_alias(alias)?
-        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+    let bot = Bot::new(command, allowed)?;
+    eprintln!("Answering {}", allow.join(", "));
+    listen(ListenOptions { bot: Some(bot), ..Default::default() }, data_dir, passphrase).await
+}
 
-    contact.trust_level = TrustLevel::Blocked;
-    db.upsert_contact(&contact)?;
+/// Relay messages between a group and a Matrix room, logged in to
+/// `homeserver` with `token`, until interrupted.
+pub async fn handle_bridge_matrix(
+    group_name: &str,
+    homeserver: &str,
+    room: &str,
+    token: String,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
+    let group = open_database(data_dir, passphrase)?
+        .get_group_by_name(group_name)?
+        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+    let matrix = MatrixClient::connect(homeserver, token, room).await?;
+    eprintln!("Bridging group '{}' with {} as {}", group.name, room, matrix.user_id());
+    let bridge = MatrixBridge { matrix, group: group.id };
+    listen(ListenOptions { bridge: Some(bridge), ..Default::default() }, data_dir, passphrase).await
+}
 
-    println!("Blocked {}", alias);
+/// How `whisper listen`, and the commands built on it, run.
+#[derive(Default)]
+struct ListenOptions {
+    /// Print JSON instead of text.
+    json: bool,
+    rendezvous_server: bool,
+    /// Where to serve metrics.
+    metrics: Option<SocketAddr>,
+    /// Where to serve the control API.
+    api: Option<SocketAddr>,
+    /// The bot, for `whisper bot`, and its replies.
+    bot: Option<(Bot, mpsc::Receiver<BotReply>)>,
+    /// The Matrix room, for `whisper bridge matrix`.
+    bridge: Option<MatrixBridge>,
+}
 
-    Ok(())
+/// A group relayed to and from a Matrix room.
+struct MatrixBridge {
+    matrix: MatrixClient,
+    group: uuid::Uuid,
 }
 
-/// Export public key to stdout.
-pub async fn handle_export_key(data_dir: &Path, passphrase: &str) -> Result<()> {
-    let key_path = keypair_path(data_dir);
+/// `whisper listen` and the commands built on it.
+async fn listen(options: ListenOptions, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let ListenOptions { json, rendezvous_server, metrics, api, bot, bridge } = options;
+    let db = open_database(data_dir, passphrase)?;
 
+    let key_path = keypair_path(data_dir);
     if !key_path.exists() {
         anyhow::bail!("No identity found. Run: whisper init");
     }
-
     let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
-    let public_key = export_public_key(&keypair);
 
-    println!("{}", public_key);
+    let webhook = match db.webhook()? {
+        Some(webhook) => Some(WebhookSender::new(webhook, db.socks_proxy()?)?),
+        None => None,
+    };
 
-    Ok(())
-}
+    let network = NetworkConfig::load(data_dir)?;
+    let mut node = create_node(&db, &network, keypair.clone()).await?;
+    start_listening(&db, &network, &mut node)?;
+    if rendezvous_server {
+        node.serve_rendezvous();
+    }
 
-/// Import a contact from a key file.
-pub async fn handle_import_contact(file: &Path, alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
-    let db = open_database(data_dir, passphrase)?;
+    // The node keeps its own metrics current; the persistent queue is ours
+    let mut queued = None;
+    let mut metrics_server = None;
+    if let Some(addr) = metrics {
+        let mut registry = Registry::default();
+        node.set_metrics(NodeMetrics::register(&mut registry));
+        let gauge = Gauge::default();
+        registry.register(
+            "whisper_queued_messages",
+            "Messages stored until their recipient connects",
+            gauge.clone(),
+        );
+        queued = Some(gauge);
+        let listener = bind_metrics(addr).await?;
+        eprintln!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+        metrics_server = Some(tokio::spawn(serve_metrics(listener, Arc::new(registry))));
+    }
 
-    // Read public key from file
-    let key_data = fs::read_to_string(file).context("Failed to read key file")?;
-    let key_data = key_data.trim();
+    let mut events = node.subscribe();
+    let node = node.spawn();
 
-    // Parse public key and derive peer ID
-    let public_key = import_public_key(key_data).context("Invalid public key format")?;
-    let peer_id = PeerId::from(public_key.clone());
-    
-    // Extract raw Ed25519 bytes (32 bytes) for encryption key derivation
-    let key_bytes = public_key.clone()
-        .try_into_ed25519()
-        .map(|ed_pk| ed_pk.to_bytes().to_vec())
-        .unwrap_or_else(|_| public_key.encode_protobuf()); // Fallback to protobuf if not Ed25519
+    eprintln!("Listening as {} (Ctrl+C to stop)", keypair_to_peer_id(&keypair));
+    if rendezvous_server {
+        eprintln!("Serving as a rendezvous point");
+    }
+    watch_contacts(&db, &node)?;
+    let session = Session::new(node.clone(), keypair)?;
+    let db = DatabaseHandle::spawn(db)?;
+
+    let mut api_link = None;
+    let mut api_server = None;
+    if let Some(addr) = api {
+        let token = load_api_token(data_dir)?;
+        let (control, link) = ControlApi::new(token, db.clone());
+        let listener = bind_api(addr).await?;
+        eprintln!(
+            "Serving the API on http://{} (token in {:?})",
+            listener.local_addr()?,
+            api_token_path(data_dir)
+        );
+        api_link = Some(link);
+        api_server = Some(tokio::spawn(serve_api(listener, control)));
+    }
 
-    // Create contact
-    let contact = Contact {
-        peer_id,
-        alias: alias.to_string(),
-        public_key: key_bytes,
-        trust_level: TrustLevel::Unknown,
-        last_seen: None,
-    };
+    let mut bridge_sync = None;
+    let bridge = bridge.map(|bridge| {
+        let (relay, relayed) = mpsc::channel(64);
+        bridge_sync = Some(tokio::spawn(run_matrix_sync(bridge.matrix.clone(), db.clone(), relay)));
+        (bridge, relayed)
+    });
+
+    let outputs = ListenOutputs { json, queued, api: api_link, webhook, bot, bridge };
+    let result = run_listen(&db, &session, &mut events, outputs).await;
+    node.shutdown();
+    for task in [metrics_server, api_server, bridge_sync].into_iter().flatten() {
+        task.abort();
+    }
+    let flushed = db.flush().await;
+    result?;
+    flushed
+}
 
-    db.upsert_contact(&contact)?;
+/// Where `whisper listen` reports what it hears, besides stdout.
+struct ListenOutputs {
+    /// Print JSON instead of text.
+    json: bool,
+    /// Gauge of the persistent queue, with `--metrics`.
+    queued: Option<Gauge>,
+    /// The control API, with `--api`.
+    api: Option<ApiLink>,
+    webhook: Option<WebhookSender>,
+    /// The bot, with `whisper bot`, and its replies.
+    bot: Option<(Bot, mpsc::Receiver<BotReply>)>,
+    /// The Matrix bridge, and messages from the room.
+    bridge: Option<(MatrixBridge, mpsc::Receiver<MatrixMessage>)>,
+}
 
-    println!("Imported contact: {} ({})", alias, peer_id);
+/// Event loop for `whisper listen`. Events are handled on the storage
+/// thread, so a slow query doesn't hold up the runtime.
+async fn run_listen(
+    db: &DatabaseHandle,
+    session: &Arc<Session>,
+    events: &mut broadcast::Receiver<NodeEvent>,
+    outputs: ListenOutputs,
+) -> Result<()> {
+    let ListenOutputs { json, queued, mut api, webhook, bot, bridge } = outputs;
+    let (bot, mut bot_replies) = bot.unzip();
+    let (bridge, mut from_matrix) = bridge.unzip();
+    // Ticks immediately, so old messages go at startup too
+    let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+    let mut queue_check = tokio::time::interval(QUEUE_METRICS_INTERVAL);
+    let mut presence = tokio::time::interval_at(tokio::time::Instant::now() + PRESENCE_INTERVAL, PRESENCE_INTERVAL);
+    let mut retry = tokio::time::interval(RETRY_INTERVAL);
+    let mut connected: HashSet<PeerId> = HashSet::new();
 
-    Ok(())
-}
+    loop {
+        let event = tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = prune.tick() => {
+                match db.call(|db| db.prune_messages(Utc::now(), false)).await {
+                    Ok(pruned) if !pruned.is_empty() => {
+                        let total: usize = pruned.iter().map(|p| p.messages).sum();
+                        eprintln!("Pruned {} message(s) past their retention", total);
+                    }
+                    Ok(_) => {}
+                    Err(e) => eprintln!("Failed to prune messages: {}", e),
+                }
+                if let Ok(expired) = db.call(|db| db.expire_mail(Utc::now())).await {
+                    if expired > 0 {
+                        eprintln!("Dropped {} undelivered message(s) held as a mailbox", expired);
+                    }
+                }
+                continue;
+            }
+            _ = presence.tick() => {
+                let peers: Vec<PeerId> = connected.iter().copied().collect();
+                let _ = session
+                    .call(db, move |db, session| {
+                        announce_presence_to(db, session, &peers);
+                        Ok(())
+                    })
+                    .await;
+                continue;
+            }
+            _ = retry.tick() => {
+                let peers = connected.clone();
+                match session.call(db, move |db, session| retry_pending(db, &session.node, &peers)).await {
+                    Ok(retries) => {
+                        if retries.dead > 0 {
+                            eprintln!("Gave up on {} queued message(s); see whisper queue list", retries.dead);
+                        }
+                        if retries.expired > 0 {
+                            eprintln!("Dropped {} message(s) queued too long", retries.expired);
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to retry queued messages: {}", e),
+                }
+                continue;
+            }
+            Some(send) = next_api_send(&mut api) => {
+                let ApiSend { to, text, reply } = send;
+                let connected = connected.clone();
+                let sent = session
+                    .call(db, move |db, session| {
+                        let contact = db
+                            .get_contact_by_alias(&to)?
+                            .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", to))?;
+                        send_text_to(db, session, &contact, text, &connected)
+                    })
+                    .await;
+                let _ = reply.send(sent);
+                continue;
+            }
+            Some(reply) = next_bot_reply(&mut bot_replies) => {
+                let connected = connected.clone();
+                let sent = session
+                    .call(db, move |db, session| {
+                        let contact = db
+                            .get_contact(&reply.to)?
+                            .ok_or_else(|| anyhow::anyhow!("{} is no longer a contact", reply.to))?;
+                        send_text_to(db, session, &contact, reply.text, &connected)
+                    })
+                    .await;
+                if let Err(e) = sent {
+                    eprintln!("Failed to send the bot's reply: {:#}", e);
+                }
+                continue;
+            }
+            Some(message) = next_matrix_message(&mut from_matrix) => {
+                let Some(group) = bridge.as_ref().map(|bridge| bridge.group) else {
+                    continue;
+                };
+                let text = format!("{}: {}", message.sender, message.text);
+                let sent = session
+                    .call(db, move |db, session| send_group_text(db, session, &group, text))
+                    .await;
+                if let Err(e) = sent {
+                    eprintln!("Failed to relay a Matrix message: {:#}", e);
+                }
+                continue;
+            }
+            _ = queue_check.tick(), if queued.is_some() => {
+                if let (Some(gauge), Ok(pending)) = (&queued, db.call(|db| MessageQueue::new(db).total_pending()).await) {
+                    gauge.set(pending as i64);
+                }
+                continue;
+            }
+            event = events.recv() => match event {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+        };
 
-/// List connected peers.
-/// 
-/// Since Whisper doesn't run a background daemon, this shows:
-/// 1. Contacts with recent last_seen timestamps (recently online)
-/// 2. Pending messages waiting for delivery
-pub async fn handle_peers(data_dir: &Path, passphrase: &str) -> Result<()> {
-    let key_path = keypair_path(data_dir);
+        match &event {
+            NodeEvent::PeerConnected(peer_id) => {
+                connected.insert(*peer_id);
+            }
+            NodeEvent::PeerDisconnected(peer_id) => {
+                connected.remove(peer_id);
+            }
+            _ => {}
+        }
 
-    if !key_path.exists() {
-        anyhow::bail!("No identity found. Run: whisper init");
-    }
+        // Others on networks without mDNS need one of these to connect
+        if let NodeEvent::Listening(addr) = &event {
+            eprintln!("Reachable at {}", addr.clone().with(Protocol::P2p(session.peer_id())));
+            continue;
+        }
 
-    let db = open_database(data_dir, passphrase)?;
+        if let NodeEvent::RateLimited { peer, ignored_for } = event {
+            if ignored_for.is_zero() {
+                eprintln!("Too many incoming messages; dropping some");
+            } else {
+                eprintln!("Ignoring {} for {}s: too many messages", peer, ignored_for.as_secs());
+            }
+            continue;
+        }
 
-    println!("Peer Status");
-    println!("===========");
-    println!();
+        if let NodeEvent::ProtocolViolation { peer, reason } = event {
+            eprintln!("Refused a message from {}: {}", peer, reason);
+            continue;
+        }
 
-    // Show contacts with last_seen info
-    let contacts = db.list_contacts()?;
-    let now = Utc::now();
+        if let NodeEvent::DhtReady { peers } = event {
+            eprintln!("Joined the DHT ({} peers)", peers);
+            continue;
+        }
 
-    println!("Known Contacts:");
-    if contacts.is_empty() {
-        println!("  (none)");
-    } else {
-        for contact in &contacts {
-            let status = match contact.last_seen {
-                Some(seen) => {
-                    let ago = now.signed_duration_since(seen);
-                    if ago.num_minutes() < 5 {
-                        "recently online".to_string()
-                    } else if ago.num_hours() < 1 {
-                        format!("{}m ago", ago.num_minutes())
-                    } else if ago.num_hours() < 24 {
-                        format!("{}h ago", ago.num_hours())
-                    } else {
-                        format!("{}d ago", ago.num_days())
-                    }
-                }
-                None => "never seen".to_string(),
-            };
-            println!("  {} - {}", contact.alias, status);
+        match event {
+            NodeEvent::RelayReservation { relay, renewal: false } => {
+                eprintln!("Reachable through relay {}", relay);
+                continue;
+            }
+            NodeEvent::RelayReservationLost { relay } => {
+                eprintln!("Lost relay {}; will ask again when it's back", relay);
+                continue;
+            }
+            _ => {}
         }
-    }
 
-    // Show pending messages
-    let pending = db.get_all_pending()?;
-    println!();
-    println!("Pending Messages: {}", pending.len());
-    if !pending.is_empty() {
-        // Group by peer
-        let mut by_peer: std::collections::HashMap<PeerId, usize> = std::collections::HashMap::new();
-        for (_, peer_id, _) in &pending {
-            *by_peer.entry(*peer_id).or_insert(0) += 1;
+        if let NodeEvent::PeerIdentified { peer, ref protocol_version, compatible: false, .. } = event {
+            eprintln!("Disconnected {}: it speaks {}, which we can't", peer, protocol_version);
         }
-        for (peer_id, count) in by_peer {
-            // Try to find alias
-            let alias = contacts.iter()
-                .find(|c| c.peer_id == peer_id)
-                .map(|c| c.alias.as_str())
-                .unwrap_or("unknown");
-            println!("  {} messages for {}", count, alias);
+
+        let heard = session
+            .call(db, move |db, session| listen_event(db, session, event, json))
+            .await?;
+        match heard {
+            Some(Heard::Message(received)) => {
+                let Received { line, json, conversation, from, sender, id, text, notify } = *received;
+                println!("{}", line);
+                if let Some(webhook) = webhook.as_ref().filter(|_| notify) {
+                    webhook.post(&conversation, json.clone());
+                }
+                if let Some(api) = &api {
+                    // Nobody following the event stream is fine
+                    let _ = api.incoming.send(json);
+                }
+                let Some(text) = text else {
+                    continue;
+                };
+                if let Some(bridge) = bridge.as_ref().filter(|bridge| conversation == Recipient::Group(bridge.group)) {
+                    let matrix = bridge.matrix.clone();
+                    let (sender, text) = (sender.clone(), text.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = matrix.post_message(&sender, &text).await {
+                            eprintln!("Failed to relay a message to Matrix: {:#}", e);
+                        }
+                    });
+                }
+                if let Some(bot) = bot.as_ref().filter(|bot| bot.answers(&from)) {
+                    if matches!(conversation, Recipient::Direct(_)) {
+                        bot.answer(BotMessage { from, alias: sender, id, text });
+                    }
+                }
+            }
+            Some(Heard::Request(from)) => {
+                eprintln!("Message request from {} - run: whisper requests list", from);
+            }
+            Some(Heard::Post(line)) => println!("{}", line),
+            None => {}
         }
     }
 
-    println!();
-    println!("Note: Whisper connects when you start a chat session.");
-    println!("Use 'whisper chat <alias>' to connect and deliver pending messages.");
-
     Ok(())
 }
 
-/// Create a new group.
-pub async fn handle_group_create(name: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
-    let db = open_database(data_dir, passphrase)?;
-
-    // Load our keypair to get our peer ID (we become the owner)
-    let key_path = keypair_path(data_dir);
-    if !key_path.exists() {
-        anyhow::bail!("No identity found. Run: whisper init");
+/// The next message the control API wants sent. Never ready without the API.
+async fn next_api_send(api: &mut Option<ApiLink>) -> Option<ApiSend> {
+    match api {
+        Some(api) => api.sends.recv().await,
+        None => std::future::pending().await,
     }
-    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
-    let my_peer_id = keypair_to_peer_id(&keypair);
+}
 
-    // Check if group already exists
-    if db.get_group_by_name(name)?.is_some() {
-        anyhow::bail!("Group '{}' already exists", name);
+/// The bot's next reply. Never ready without a bot.
+async fn next_bot_reply(replies: &mut Option<mpsc::Receiver<BotReply>>) -> Option<BotReply> {
+    match replies {
+        Some(replies) => replies.recv().await,
+        None => std::future::pending().await,
     }
+}
 
-    // Generate symmetric key for group
-    let symmetric_key = generate_group_key();
+/// The next message from the bridged Matrix room. Never ready without one.
+async fn next_matrix_message(messages: &mut Option<mpsc::Receiver<MatrixMessage>>) -> Option<MatrixMessage> {
+    match messages {
+        Some(messages) => messages.recv().await,
+        None => std::future::pending().await,
+    }
+}
 
-    // Create group with us as owner
-    let group = Group::new(name.to_string(), symmetric_key, Some(my_peer_id));
-    db.create_group(&group)?;
+/// Store and send a text message to a group's members. Those who aren't
+/// connected get it when they are, while this session lasts.
+fn send_group_text(db: &Database, session: &Session, group_id: &uuid::Uuid, text: String) -> Result<uuid::Uuid> {
+    use crate::crypto::encrypt_for_group;
 
-    println!("Created group: {}", name);
-    println!("Group ID: {}", group.id);
-    println!("You are the owner of this group.");
+    let group = db
+        .get_group(group_id)?
+        .ok_or_else(|| anyhow::anyhow!("The group is gone"))?;
+    let msg = Message::new_text(session.peer_id(), Recipient::Group(group.id), text);
+    db.insert_message(&msg)?;
 
-    Ok(())
+    let wire = Envelope::from_message(&msg).encode_signed(&session.keypair)?;
+    let encrypted = encrypt_for_group(&wire, &group.symmetric_key)?;
+    for member in group.member_peer_ids() {
+        if member != session.peer_id() {
+            session.node.send_message(member, encrypted.clone());
+        }
+    }
+    Ok(msg.id)
 }
 
-/// Invite a contact to a group.
-/// 
-/// This adds them to the group AND sends them the encrypted group key.
-pub async fn handle_group_invite(group_name: &str, alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
-    let db = open_database(data_dir, passphrase)?;
+/// Store and send a text message to a contact, queueing it until they
+/// connect if they aren't now. Returns the message ID.
+fn send_text_to(
+    db: &Database,
+    session: &Session,
+    contact: &Contact,
+    text: String,
+    connected: &HashSet<PeerId>,
+) -> Result<uuid::Uuid> {
+    ensure_key_verified(db, contact)?;
+    let msg = Message::new_text(session.peer_id(), Recipient::Direct(contact.peer_id), text);
+    let wire = stamp_for(db, Envelope::from_message(&msg), &contact.peer_id)?.encode_signed(&session.keypair)?;
+
+    // Stored, sealed and queued together; sent once that's committed
+    let (data, copies) = db.transaction(|db| {
+        db.insert_message(&msg)?;
+        let data = seal_for_contact(db, session.keys(), &contact.peer_id, &contact.public_key, &wire)?;
+        let mut copies = Vec::new();
+        for (device, data) in sealed_for_devices(db, session.keys(), &contact.peer_id, &wire)? {
+            if connected.contains(&device) {
+                copies.push((device, data));
+            } else {
+                MessageQueue::new(db).enqueue(&uuid::Uuid::new_v4(), &device, &data)?;
+            }
+        }
+        if !connected.contains(&contact.peer_id) {
+            // Flushed when they connect
+            MessageQueue::new(db).enqueue(&msg.id, &contact.peer_id, &data)?;
+        }
+        Ok((data, copies))
+    })?;
+    for (device, data) in copies {
+        session.node.send_message(device, data);
+    }
+    if connected.contains(&contact.peer_id) {
+        session.node.send_message(contact.peer_id, data);
+    } else {
+        session.node.connect_peer(contact.peer_id);
+        // And left with any mailbox that's around, in case they don't
+        for mailbox in db.mailbox_peers()?.iter().filter(|mailbox| connected.contains(mailbox)) {
+            let _ = deposit_pending(db, &session.node, &session.keypair, session.keys(), mailbox);
+        }
+    }
+    Ok(msg.id)
+}
 
-    // Load our keypair
-    let key_path = keypair_path(data_dir);
-    if !key_path.exists() {
-        anyhow::bail!("No identity found. Run: whisper init");
+/// Re-announce our presence to the connected contacts we share it with,
+/// so it doesn't time out on their side.
+fn announce_presence_to(db: &Database, session: &Session, peers: &[PeerId]) {
+    for peer_id in peers {
+        let _ = announce_presence(db, &session.node, &session.keypair, session.keys(), peer_id);
     }
-    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+}
 
-    // Get group
-    let group = db
-        .get_group_by_name(group_name)?
-        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+/// What `whisper listen` reports for an event.
+enum Heard {
+    /// A received message.
+    Message(Box<Received>),
+    /// A message request from this peer.
+    Request(PeerId),
+    /// A post to a channel we subscribe to, as the line for stdout.
+    Post(String),
+}
 
-    // Get contact
-    let contact = db
-        .get_contact_by_alias(alias)?
-        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+/// A message `whisper listen` received.
+struct Received {
+    /// The line for stdout.
+    line: String,
+    /// As JSON, for the API and webhook.
+    json: String,
+    conversation: Recipient,
+    from: PeerId,
+    /// The sender's alias, or their short peer ID.
+    sender: String,
+    id: uuid::Uuid,
+    /// The text, for text messages.
+    text: Option<String>,
+    /// Whether the conversation's notification settings let it reach the
+    /// webhook.
+    notify: bool,
+}
 
-    // Add member to local database
-    db.add_group_member(&group.id, &contact.peer_id)?;
+/// Apply one event for `whisper listen`, returning what to report.
+fn listen_event(db: &Database, session: &Session, event: NodeEvent, json: bool) -> Result<Option<Heard>> {
+    let (node, keypair, (our_enc_pk, our_enc_sk)) = (&session.node, &session.keypair, session.keys());
+    let our_peer_id = session.peer_id();
 
-    // Send encrypted group key to the invited member
-    // Format: "GROUP_INVITE:<group_name>:<group_id>:<encrypted_symmetric_key>"
-    if !contact.public_key.is_empty() {
-        if let Ok(recipient_pk) = ed25519_pk_to_x25519(&contact.public_key) {
-            // Encrypt the symmetric key with the recipient's public key
-            let encrypted_key = encrypt_message(&group.symmetric_key, &recipient_pk)
-                .context("Failed to encrypt group key")?;
-            
-            // Create invite payload
-            let invite_payload = format!(
-                "GROUP_INVITE:{}:{}:",
-                group.name,
-                group.id
-            );
-            let mut invite_data = invite_payload.into_bytes();
-            invite_data.extend_from_slice(&encrypted_key);
+    match event {
+        NodeEvent::PeerConnected(peer_id) => {
+            if let Ok(Some(mut contact)) = db.get_contact(&peer_id) {
+                contact.last_seen = Some(Utc::now());
+                let _ = db.upsert_contact(&contact);
+            }
+            let _ = announce_presence(db, node, keypair, (our_enc_pk, our_enc_sk), &peer_id);
+            let _ = announce_devices(db, node, keypair, (our_enc_pk, our_enc_sk), &peer_id);
+            let _ = offer_history(db, node, keypair, (our_enc_pk, our_enc_sk), &peer_id);
+            let _ = request_history(db, node, keypair, &peer_id);
+            let _ = queue_group_sync(db, keypair, &peer_id);
+
+            // Flush pending messages for this peer from persistent queue
+            let _ = MessageQueue::new(db).flush(&peer_id, |data| node.send_message(peer_id, data));
+            let _ = forward_mail(db, node, keypair, (our_enc_pk, our_enc_sk), &peer_id);
+            let _ = deposit_pending(db, node, keypair, (our_enc_pk, our_enc_sk), &peer_id);
+        }
+        NodeEvent::MessageReceived { from, data } => {
+            if refuse_blocked(db, &from) {
+                return Ok(None);
+            }
+            let (group, decrypted) = match sending_group(db, &from, &data) {
+                Some((group, plaintext)) => (Some(group), plaintext),
+                None => (None, open_from_peer(db, (our_enc_pk, our_enc_sk), &from, &data)),
+            };
+            let Ok(mut envelope) = Envelope::decode(&decrypted) else {
+                return Ok(None); // Not a whisper envelope
+            };
+            let authenticity = authenticate_from(db, &envelope, &from);
+            if authenticity == Authenticity::Forged || is_replay(db, &envelope) {
+                return Ok(None);
+            }
+            attribute_device(db, &mut envelope);
 
-            // Queue for delivery
-            let invite_id = uuid::Uuid::new_v4();
-            db.queue_pending_message(&invite_id, &contact.peer_id, &invite_data)?;
+            if let Some((msg_id, receipt_type)) = parse_receipt(&envelope) {
+                let _ = receive_receipt(db, &envelope.sender, &msg_id, receipt_type);
+                return Ok(None);
+            }
 
-            // Try to send now
-            let mut node = WhisperNode::new(keypair).await.context("Failed to create network node")?;
-            node.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-            node.send_message(contact.peer_id, invite_data);
+            if let Some(group) = group {
+                return listen_group_message(db, session, &group, from, envelope, authenticity, json);
+            }
+            // Strangers' first messages must be stamped
+            if !admits_sender(db, &our_peer_id, &envelope) {
+                return Ok(None);
+            }
 
-            println!("Invited {} to group {} (group key sent encrypted)", alias, group_name);
-        } else {
-            println!("Invited {} to group {} (no public key - key exchange needed)", alias, group_name);
+            let is_text = matches!(envelope.payload, MessageContent::Text(_));
+            let text = match &envelope.payload {
+                MessageContent::Text(text) => text.clone(),
+                MessageContent::File(offer) => {
+                    let transfer = FileTransfer::new_incoming(
+                        offer.transfer_id,
+                        from,
+                        Recipient::Direct(our_peer_id),
+                        offer.filename.clone(),
+                        offer.total_size,
+                        offer.total_chunks,
+                        offer.file_checksum,
+                    );
+                    let _ = db.insert_file_transfer(&transfer);
+                    format!("[file] {} ({} bytes)", offer.filename, offer.total_size)
+                }
+                MessageContent::Location { .. } => envelope.payload.summary(),
+                MessageContent::ContactCard { .. } => contact_card_text(db, &our_peer_id, from, &envelope.payload),
+                MessageContent::FileChunk(chunk) => {
+                    store_file_chunk(db, chunk);
+                    return Ok(None);
+                }
+                MessageContent::FileComplete(complete) => {
+                    receive_file_complete(db, from, our_peer_id, complete);
+                    return Ok(None);
+                }
+                MessageContent::GroupInvite(invite) => {
+                    match receive_group_invite(db, (our_enc_pk, our_enc_sk), from, invite) {
+                        Ok(Some(pending)) => format!(
+                            "[invite] group '{}' - run: whisper group accept {}",
+                            pending.name, pending.name
+                        ),
+                        _ => return Ok(None),
+                    }
+                }
+                MessageContent::GroupKeyUpdate(update) => {
+                    let _ = receive_group_key_update(db, (our_enc_pk, our_enc_sk), from, update);
+                    return Ok(None);
+                }
+                MessageContent::GroupMemberUpdate(update) => {
+                    let _ = receive_group_member_update(db, our_peer_id, from, update);
+                    return Ok(None);
+                }
+                MessageContent::GroupLeave(leave) => {
+                    let _ = receive_group_leave(db, keypair, from, leave);
+                    return Ok(None);
+                }
+                MessageContent::GroupSync(sync) => {
+                    let _ = receive_group_sync(db, our_peer_id, (our_enc_pk, our_enc_sk), from, sync);
+                    return Ok(None);
+                }
+                MessageContent::DeleteRequest(id) => {
+                    let _ = receive_delete_request(db, envelope.sender, authenticity, id);
+                    return Ok(None);
+                }
+                MessageContent::Presence(status) => {
+                    let _ = receive_presence(db, &from, *status);
+                    return Ok(None);
+                }
+                MessageContent::MailboxDeposit(_) => {
+                    let _ = receive_deposit(db, node, &from, &envelope);
+                    return Ok(None);
+                }
+                MessageContent::MailboxDelivery(delivery) => {
+                    let Some(data) = open_delivery(db, (our_enc_pk, our_enc_sk), delivery) else {
+                        return Ok(None);
+                    };
+                    return listen_event(db, session, NodeEvent::MessageReceived { from: delivery.from, data }, json);
+                }
+                MessageContent::DeviceList(list) => {
+                    let _ = receive_device_list(db, list);
+                    return Ok(None);
+                }
+                MessageContent::HistoryOffer(ids) => {
+                    if authenticity == Authenticity::Verified {
+                        let _ = send_missing_history(db, node, keypair, (our_enc_pk, our_enc_sk), &from, ids);
+                    }
+                    return Ok(None);
+                }
+                MessageContent::HistoryRequest(request) => {
+                    if authenticity == Authenticity::Verified {
+                        let _ = answer_history_request(db, node, keypair, (our_enc_pk, our_enc_sk), &from, request);
+                    }
+                    return Ok(None);
+                }
+                MessageContent::ChannelSubscribe(id) => {
+                    if authenticity == Authenticity::Verified
+                        && receive_channel_subscribe(db, node, keypair, (our_enc_pk, our_enc_sk), &from, id)?
+                    {
+                        eprintln!("{} subscribed to a channel", short_peer_id(&from));
+                    }
+                    return Ok(None);
+                }
+                MessageContent::ChannelPost(post) => {
+                    return match receive_channel_post(db, post)? {
+                        Some(channel) => Ok(Some(Heard::Post(post_line(&channel, post, json)?))),
+                        None => Ok(None),
+                    };
+                }
+                MessageContent::History(messages) => {
+                    if authenticity == Authenticity::Verified {
+                        let added = receive_history(db, &our_peer_id, &from, messages).map_or(0, |(added, _)| added);
+                        if added > 0 {
+                            eprintln!("Synced {} message(s) from {}", added, short_peer_id(&from));
+                        }
+                    }
+                    return Ok(None);
+                }
+                MessageContent::InviteAccepted(acceptance) => {
+                    if authenticity == Authenticity::Verified {
+                        if let Some(contact) = receive_invite_acceptance(db, from, acceptance, Utc::now())? {
+                            node.watch_peer(contact.peer_id);
+                            eprintln!("{} accepted your invite; added as {}", short_peer_id(&from), contact.alias);
+                        }
+                    }
+                    return Ok(None);
+                }
+                MessageContent::Typing | MessageContent::Receipt(..) | MessageContent::Tombstone => return Ok(None),
+            };
+
+            let msg = envelope.into_message(Recipient::Direct(our_peer_id));
+            let inbox = db.receive_message(&msg).ok().flatten();
+            let verified = authenticity == Authenticity::Verified;
+            if inbox.is_some() && !verified {
+                let _ = db.mark_message_unverified(&msg.id);
+            }
+
+            if let Ok(receipt) = create_receipt(keypair, &msg.id, ReceiptType::Delivered) {
+                node.send_message(from, receipt);
+            }
+
+            match inbox {
+                Some(Inbox::Conversation) => {
+                    let contact = db.get_contact(&msg.from).ok().flatten();
+                    let alias = contact.as_ref().map(|c| c.alias.as_str());
+                    let line = listen_line(&msg, alias, None, &text, verified, json)?;
+                    let json = listen_line(&msg, alias, None, &text, verified, true)?;
+                    let notify = alert_for(db, &Recipient::Direct(msg.from), &msg, Utc::now())?.is_some();
+                    return Ok(Some(Heard::Message(Box::new(Received {
+                        line,
+                        json,
+                        conversation: msg.to,
+                        from: msg.from,
+                        sender: alias.map_or_else(|| short_peer_id(&msg.from), str::to_string),
+                        id: msg.id,
+                        text: is_text.then_some(text),
+                        notify,
+                    }))));
+                }
+                // Strangers don't get into scripts until accepted
+                Some(Inbox::Request) => return Ok(Some(Heard::Request(msg.from))),
+                None => {}
+            }
         }
-    } else {
-        println!("Invited {} to group {} (no public key - key exchange needed)", alias, group_name);
+        NodeEvent::FileChunkReceived { from, data, .. } => {
+            let decrypted = open_from_peer(db, (our_enc_pk, our_enc_sk), &from, &data);
+            if let Ok(envelope) = Envelope::decode(&decrypted) {
+                if let MessageContent::FileChunk(chunk) = &envelope.payload {
+                    if authenticate_from(db, &envelope, &from) != Authenticity::Forged {
+                        store_file_chunk(db, chunk);
+                    }
+                }
+            }
+        }
+        NodeEvent::ReachabilityChanged(reachability) => {
+            let _ = db.save_reachability(reachability);
+        }
+        NodeEvent::PeerAddress { peer, address, reached } => {
+            let _ = db.record_peer_address(&peer, &address, reached);
+        }
+        NodeEvent::Latency { peer, latency } => {
+            let _ = db.record_latency(&peer, latency);
+        }
+        NodeEvent::PeerIdentified { peer, protocol_version, agent_version, .. } => {
+            let _ = db.record_peer_version(&peer, &protocol_version, &agent_version);
+        }
+        NodeEvent::Traffic(traffic) => {
+            let _ = db.record_traffic(&traffic);
+        }
+        NodeEvent::ChannelPostReceived { channel_id, data, .. } => {
+            let Some(post) = gossiped_post(&channel_id, &data) else {
+                return Ok(None);
+            };
+            if let Some(channel) = receive_channel_post(db, &post)? {
+                return Ok(Some(Heard::Post(post_line(&channel, &post, json)?)));
+            }
+        }
+        NodeEvent::ChannelJoined { peer, channel_id } => {
+            receive_channel_joined(db, node, keypair, (our_enc_pk, our_enc_sk), &peer, &channel_id)?;
+        }
+        NodeEvent::PeerDisconnected(_)
+        | NodeEvent::Listening(_)
+        | NodeEvent::MessageSent { .. }
+        | NodeEvent::TransferProgress { .. }
+        | NodeEvent::RateLimited { .. }
+        | NodeEvent::ProtocolViolation { .. }
+        | NodeEvent::DhtReady { .. }
+        | NodeEvent::RelayReservation { .. }
+        | NodeEvent::RelayReservationLost { .. }
+        | NodeEvent::PostPublished { .. } => {}
     }
+    Ok(None)
+}
 
-    Ok(())
+/// The group `from` sent `data` to, found by whose key decrypts it, and
+/// the plaintext.
+fn sending_group(db: &Database, from: &PeerId, data: &[u8]) -> Option<(Group, Vec<u8>)> {
+    use crate::crypto::decrypt_from_group;
+
+    db.list_groups()
+        .ok()?
+        .into_iter()
+        .filter(|group| group.is_member(from))
+        .find_map(|group| {
+            let plaintext = decrypt_from_group(data, &group.symmetric_key).ok()?;
+            Some((group, plaintext))
+        })
 }
 
-/// Open interactive group chat.
-pub async fn handle_group_chat(name: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
-    let db = open_database(data_dir, passphrase)?;
+/// Store a message sent to one of our groups for `whisper listen`.
+/// Only text and deletions are handled; the rest waits for the group chat.
+fn listen_group_message(
+    db: &Database,
+    session: &Session,
+    group: &Group,
+    from: PeerId,
+    envelope: Envelope,
+    authenticity: Authenticity,
+    json: bool,
+) -> Result<Option<Heard>> {
+    let text = match &envelope.payload {
+        MessageContent::Text(text) => text.clone(),
+        MessageContent::DeleteRequest(id) => {
+            let _ = receive_delete_request(db, envelope.sender, authenticity, id);
+            return Ok(None);
+        }
+        _ => return Ok(None),
+    };
 
-    // Load our keypair
-    let key_path = keypair_path(data_dir);
-    if !key_path.exists() {
-        anyhow::bail!("No identity found. Run: whisper init");
+    // Nothing a member sends after leaving is accepted
+    if let Some(left_at) = db.group_departure(&group.id, &envelope.sender)? {
+        if envelope.timestamp > left_at {
+            return Ok(None);
+        }
     }
-    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
-    let our_peer_id = keypair_to_peer_id(&keypair);
-
-    // Verify group exists
-    let group = db
-        .get_group_by_name(name)?
-        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", name))?;
 
-    if group.members.is_empty() {
-        println!("Group '{}' has no members. Invite contacts with: whisper group invite {} <alias>", name, name);
-        return Ok(());
+    let msg = envelope.into_message(Recipient::Group(group.id));
+    if db.insert_message(&msg).is_err() {
+        return Ok(None); // Already have it
     }
-
-    // Load all contacts for the sidebar
-    let contacts = db.list_contacts()?;
-
-    // Create app state
-    let mut app = App::new();
-    app.set_peer_id(our_peer_id);
-    for c in contacts {
-        app.add_contact(c);
+    let verified = authenticity == Authenticity::Verified;
+    if !verified {
+        let _ = db.mark_message_unverified(&msg.id);
+    }
+    if let Ok(receipt) = create_receipt(&session.keypair, &msg.id, ReceiptType::Delivered) {
+        session.node.send_message(from, receipt);
     }
 
-    // Set mode to chat
-    app.mode = AppMode::Chat;
-
-    // Derive encryption keys from our identity keypair (for fallback DM decryption)
-    let (our_enc_pk, our_enc_sk) = keypair_to_encryption_keys(&keypair)
-        .context("Failed to derive encryption keys")?;
-
-    // Create and start the network node
-    let mut node = WhisperNode::new(keypair).await.context("Failed to create network node")?;
-    node.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-    let node = Arc::new(Mutex::new(node));
-
-    // Run the group TUI with multicast to all members
-    run_group_tui_with_network(&mut app, &db, node, &group, &our_enc_pk, &our_enc_sk).await?;
-
-    Ok(())
+    let contact = db.get_contact(&msg.from)?;
+    let alias = contact.as_ref().map(|c| c.alias.as_str());
+    let line = listen_line(&msg, alias, Some(&group.name), &text, verified, json)?;
+    let json = listen_line(&msg, alias, Some(&group.name), &text, verified, true)?;
+    let notify = alert_for(db, &msg.to, &msg, Utc::now())?.is_some();
+    Ok(Some(Heard::Message(Box::new(Received {
+        line,
+        json,
+        conversation: msg.to,
+        from: msg.from,
+        sender: alias.map_or_else(|| short_peer_id(&msg.from), str::to_string),
+        id: msg.id,
+        text: Some(text),
+        notify,
+    }))))
 }
 
-/// List all groups.
-pub async fn handle_group_list(data_dir: &Path, passphrase: &str) -> Result<()> {
+/// List contacts, pinned ones first, or only those tagged `tag`. Archived
+/// contacts are left out unless `archived` is set.
+pub async fn handle_contacts(
+    tag: Option<&str>,
+    archived: bool,
+    output: OutputFormat,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
     let db = open_database(data_dir, passphrase)?;
 
-    let groups = db.list_groups()?;
+    let mut contacts = db.list_contacts()?;
+    if let Some(tag) = tag {
+        let tag = normalize_tag(tag)?;
+        contacts.retain(|c| c.has_tag(&tag));
+    }
+    let mut placed = Vec::with_capacity(contacts.len());
+    let mut key_changes = HashMap::new();
+    for contact in contacts {
+        let place = db.conversation_place(&Recipient::Direct(contact.peer_id))?;
+        if let Some(changed_at) = db.key_change(&contact.peer_id)? {
+            key_changes.insert(contact.peer_id, changed_at);
+        }
+        placed.push((place, contact));
+    }
+    let hidden = if archived { 0 } else { placed.iter().filter(|((_, archived), _)| *archived).count() };
+    placed.retain(|((_, is_archived), _)| archived || !is_archived);
+    placed.sort_by_key(|((pinned, _), _)| !pinned);
+    let unread = db.unread_counts()?;
+    let now = Utc::now();
 
-    if groups.is_empty() {
-        println!("No groups yet. Create one with: whisper group create <name>");
+    if output == OutputFormat::Json {
+        let contacts: Vec<ContactOutput> = placed
+            .into_iter()
+            .map(|((pinned, archived), c)| ContactOutput {
+                unread: unread.get(&c.peer_id).copied().unwrap_or(0),
+                pinned,
+                archived,
+                key_changed_at: key_changes.get(&c.peer_id).copied(),
+                presence: c.current_presence(now),
+                alias: c.alias,
+                peer_id: c.peer_id.to_string(),
+                trust: c.trust_level,
+                last_seen: c.last_seen,
+                note: c.note,
+                tags: c.tags,
+            })
+            .collect();
+        return print_json(&contacts);
+    }
+
+    if placed.is_empty() {
+        match tag {
+            Some(tag) => println!("No contacts tagged '{}'", tag),
+            None if hidden > 0 => println!("Every contact is archived. See them with: whisper contacts --archived"),
+            None => println!("No contacts yet. Add one with: whisper add <alias> <peer_id>"),
+        }
         return Ok(());
     }
 
-    println!("Groups:");
-    for group in groups {
-        let owner_str = if group.owner.is_some() { " [owner]" } else { "" };
-        println!("  {} ({} members){}", group.name, group.members.len(), owner_str);
-        if let Some(desc) = &group.description {
-            println!("    {}", desc);
+    println!("Contacts:");
+    for ((pinned, _), contact) in placed {
+        let mut alias = contact.alias.clone();
+        if let Some(key) = contact.identity_key() {
+            alias.push_str(&format!(" {}", emoji_fingerprint(&key)));
+        }
+        if pinned {
+            alias.push_str(" ★");
+        }
+        let status = match contact.trust_level {
+            TrustLevel::Trusted => "✓ Trusted",
+            TrustLevel::Verified => "◆ Verified",
+            TrustLevel::Blocked => "✗ Blocked",
+            TrustLevel::Unknown => "? Unknown",
+        };
+        let tags: String = contact.tags.iter().map(|t| format!(" #{}", t)).collect();
+        let presence = contact
+            .current_presence(now)
+            .map(|p| format!(" ({})", p))
+            .unwrap_or_default();
+        match unread.get(&contact.peer_id) {
+            Some(count) => println!(
+                "  {} ({}) [{}] - {}{}{}",
+                alias, count, status, contact.peer_id, tags, presence
+            ),
+            None => println!("  {} [{}] - {}{}{}", alias, status, contact.peer_id, tags, presence),
+        }
+        if let Some(changed_at) = key_changes.get(&contact.peer_id) {
+            println!("    {}", key_change_warning(&contact.alias, *changed_at));
+        }
+        if let Some(note) = &contact.note {
+            println!("    {}", note);
         }
     }
+    if hidden > 0 {
+        println!("{} archived. See them with: whisper contacts --archived", hidden);
+    }
 
     Ok(())
 }
 
-/// Kick a member from a group (owner/admin only).
-pub async fn handle_group_kick(group_name: &str, alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
-    let db = open_database(data_dir, passphrase)?;
+/// Check a tag is one word of letters, digits, - and _, dropping a leading
+/// `#` and lowercasing it.
+fn normalize_tag(tag: &str) -> Result<String> {
+    let tag = tag.trim().trim_start_matches('#').to_lowercase();
+    let valid = !tag.is_empty()
+        && tag.len() <= 32
+        && tag.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        anyhow::bail!("Invalid tag '{}': use letters, digits, - and _", tag);
+    }
+    Ok(tag)
+}
 
-    // Load our keypair
-    let key_path = keypair_path(data_dir);
-    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
-    let my_peer_id = keypair_to_peer_id(&keypair);
+/// Show, set or clear the note on a contact.
+pub async fn handle_contact_note(
+    alias: &str,
+    text: Option<&str>,
+    clear: bool,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
 
-    // Get group
-    let group = db
-        .get_group_by_name(group_name)?
-        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+    let contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
 
-    // Check permissions
-    if !group.can_manage(&my_peer_id) {
-        anyhow::bail!("You don't have permission to kick members from this group");
+    match (text.map(str::trim), clear) {
+        (_, true) | (Some(""), _) => {
+            db.set_contact_note(&contact.peer_id, None)?;
+            println!("Cleared the note on {}", alias);
+        }
+        (Some(text), false) => {
+            db.set_contact_note(&contact.peer_id, Some(text))?;
+            println!("Saved the note on {}", alias);
+        }
+        (None, false) => match &contact.note {
+            Some(note) => println!("{}", note),
+            None => println!("No note on {}", alias),
+        },
     }
 
-    // Get contact
+    Ok(())
+}
+
+/// Add tags to a contact, or with `remove`, take them off.
+pub async fn handle_contact_tag(alias: &str, tags: &[String], remove: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
     let contact = db
         .get_contact_by_alias(alias)?
         .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+    // Check them all before changing any
+    let tags = tags.iter().map(|t| normalize_tag(t)).collect::<Result<Vec<_>>>()?;
 
-    // Can't kick the owner
-    if group.is_owner(&contact.peer_id) {
-        anyhow::bail!("Cannot kick the group owner");
+    for tag in &tags {
+        if remove {
+            db.remove_contact_tag(&contact.peer_id, tag)?;
+        } else {
+            db.add_contact_tag(&contact.peer_id, tag)?;
+        }
     }
 
-    // Remove member
-    if db.remove_group_member(&group.id, &contact.peer_id)? {
-        println!("Kicked {} from group '{}'", alias, group_name);
+    let contact = db.get_contact(&contact.peer_id)?.unwrap_or(contact);
+    if contact.tags.is_empty() {
+        println!("{} has no tags", alias);
     } else {
-        println!("{} is not a member of group '{}'", alias, group_name);
+        let tags: Vec<String> = contact.tags.iter().map(|t| format!("#{}", t)).collect();
+        println!("{}: {}", alias, tags.join(" "));
     }
 
     Ok(())
 }
 
-/// Promote a member to admin (owner only).
-pub async fn handle_group_promote(group_name: &str, alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
-    use crate::message::MemberRole;
-    
+/// Save a fixed address for a contact, such as their onion address, to
+/// dial whenever we look for them.
+pub async fn handle_contact_address(alias: &str, address: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
     let db = open_database(data_dir, passphrase)?;
 
-    // Load our keypair
-    let key_path = keypair_path(data_dir);
-    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
-    let my_peer_id = keypair_to_peer_id(&keypair);
-
-    // Get group
-    let group = db
-        .get_group_by_name(group_name)?
-        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
-
-    // Only owner can promote
-    if !group.is_owner(&my_peer_id) {
-        anyhow::bail!("Only the group owner can promote members to admin");
-    }
-
-    // Get contact
     let contact = db
         .get_contact_by_alias(alias)?
         .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
-
-    // Check if they're a member
-    if !group.is_member(&contact.peer_id) {
-        anyhow::bail!("{} is not a member of group '{}'", alias, group_name);
+    let (addr, peer) = split_peer_id(&parse_connect_address(address)?);
+    if peer.is_some_and(|peer| peer != contact.peer_id) {
+        anyhow::bail!("That address is for a different peer than {}", alias);
     }
 
-    // Promote
-    if db.set_member_role(&group.id, &contact.peer_id, MemberRole::Admin)? {
-        println!("Promoted {} to admin in group '{}'", alias, group_name);
-    } else {
-        anyhow::bail!("Failed to promote {}", alias);
+    // Ranked with addresses that worked, so it isn't capped away before
+    // we've had a chance to use it
+    db.record_peer_address(&contact.peer_id, &addr, true)?;
+    println!("Saved {} for {}", addr, alias);
+    if is_onion_address(&addr) && db.socks_proxy()?.is_none() {
+        println!("Onion addresses need Tor. Set it with: whisper proxy {}", DEFAULT_TOR_PROXY);
     }
-
     Ok(())
 }
 
-/// Demote an admin to member (owner only).
-pub async fn handle_group_demote(group_name: &str, alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
-    use crate::message::MemberRole;
-    
+/// Parse a `--since` date: `YYYY-MM-DD` (midnight UTC) or RFC 3339.
+fn parse_since(s: &str) -> Result<chrono::DateTime<Utc>> {
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(dt.with_timezone(&Utc));
+    }
+    let date = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .with_context(|| format!("Invalid date '{}' (expected YYYY-MM-DD or RFC 3339)", s))?;
+    Ok(date.and_hms_opt(0, 0, 0).expect("midnight is valid").and_utc())
+}
+
+/// Print a conversation with a contact in the given format.
+pub async fn handle_export(
+    alias: &str,
+    format: ExportFormat,
+    since: Option<&str>,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
     let db = open_database(data_dir, passphrase)?;
 
-    // Load our keypair
     let key_path = keypair_path(data_dir);
-    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
-    let my_peer_id = keypair_to_peer_id(&keypair);
-
-    // Get group
-    let group = db
-        .get_group_by_name(group_name)?
-        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
-
-    // Only owner can demote
-    if !group.is_owner(&my_peer_id) {
-        anyhow::bail!("Only the group owner can demote admins");
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
     }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
 
-    // Get contact
     let contact = db
         .get_contact_by_alias(alias)?
         .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
 
-    // Demote
-    if db.set_member_role(&group.id, &contact.peer_id, MemberRole::Member)? {
-        println!("Demoted {} from admin in group '{}'", alias, group_name);
-    } else {
-        anyhow::bail!("{} is not a member of group '{}'", alias, group_name);
-    }
+    let since = since.map(parse_since).transpose()?;
+    let messages = db.get_conversation(&contact.peer_id, since)?;
+    let export = ConversationExport::new(&our_peer_id, &contact.alias, &contact.peer_id, &messages);
+
+    print!("{}", export.render(format)?);
 
     Ok(())
 }
 
-/// Transfer group ownership (owner only).
-pub async fn handle_group_transfer(group_name: &str, alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
-    let db = open_database(data_dir, passphrase)?;
+/// Delivery status of a message we sent, as a glyph.
+fn status_glyph(status: &MessageStatus) -> &'static str {
+    match status {
+        MessageStatus::Pending => "…",
+        MessageStatus::Sent => "✓",
+        MessageStatus::Delivered => "✓✓",
+        MessageStatus::Read => "◉",
+        MessageStatus::Failed(_) => "✗",
+        MessageStatus::Expired => "⌛",
+    }
+}
 
-    // Load our keypair
-    let key_path = keypair_path(data_dir);
-    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
-    let my_peer_id = keypair_to_peer_id(&keypair);
+/// One message as printed by `whisper history`: → for sent, ← for
+/// received, with the delivery status after our own messages.
+fn history_line(msg: &Message, our_peer_id: &PeerId) -> String {
+    let sent = msg.from == *our_peer_id;
+    let header = format!("[{}] {} ", msg.timestamp.format("%Y-%m-%d %H:%M"), if sent { "→" } else { "←" });
+    // Continuation lines line up under the first
+    let indent = " ".repeat(header.chars().count());
+    let mut line = header;
+    line.push_str(&msg.content.summary().replace('\n', &format!("\n{}", indent)));
+    if sent {
+        line.push_str("  ");
+        line.push_str(status_glyph(&msg.status));
+    }
+    line
+}
 
-    // Get group
-    let group = db
-        .get_group_by_name(group_name)?
-        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+/// Print the stored conversation with a contact, oldest first: the last
+/// `limit` direct messages, optionally only those from `since` on.
+pub async fn handle_history(
+    alias: &str,
+    limit: usize,
+    since: Option<&str>,
+    output: OutputFormat,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
 
-    // Only owner can transfer
-    if !group.is_owner(&my_peer_id) {
-        anyhow::bail!("Only the group owner can transfer ownership");
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
     }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
 
-    // Get contact
     let contact = db
         .get_contact_by_alias(alias)?
         .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
 
-    // Check if they're a member
-    if !group.is_member(&contact.peer_id) {
-        anyhow::bail!("{} is not a member of group '{}'. Invite them first.", alias, group_name);
+    let since = since.map(parse_since).transpose()?;
+    let mut messages: Vec<Message> = db
+        .get_conversation(&contact.peer_id, since)?
+        .into_iter()
+        .filter(|m| matches!(m.to, Recipient::Direct(_)))
+        .collect();
+    messages.drain(..messages.len().saturating_sub(limit));
+
+    if output == OutputFormat::Json {
+        let export = ConversationExport::new(&our_peer_id, &contact.alias, &contact.peer_id, &messages);
+        return print_json(&export.messages);
     }
 
-    // Transfer ownership
-    if db.transfer_group_ownership(&group.id, &contact.peer_id)? {
-        println!("Transferred ownership of group '{}' to {}", group_name, alias);
-    } else {
-        anyhow::bail!("Failed to transfer ownership");
+    if messages.is_empty() {
+        println!("No messages with {} yet", contact.alias);
+        return Ok(());
+    }
+
+    if let Some(changed_at) = db.key_change(&contact.peer_id)? {
+        println!("{}", key_change_warning(&contact.alias, changed_at));
+    }
+    println!("Conversation with {} ({} message(s)):", contact.alias, messages.len());
+    for msg in &messages {
+        println!("  {}", history_line(msg, &our_peer_id));
     }
 
     Ok(())
 }
 
-/// Update group settings (owner/admin only).
-pub async fn handle_group_settings(
-    group_name: &str,
-    new_name: Option<&str>,
-    description: Option<&str>,
-    data_dir: &Path,
-    passphrase: &str,
-) -> Result<()> {
-    let db = open_database(data_dir, passphrase)?;
-
-    // Load our keypair
-    let key_path = keypair_path(data_dir);
-    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
-    let my_peer_id = keypair_to_peer_id(&keypair);
-
-    // Get group
-    let group = db
-        .get_group_by_name(group_name)?
-        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
-
-    // Check permissions
-    if !group.can_manage(&my_peer_id) {
-        anyhow::bail!("You don't have permission to modify this group's settings");
-    }
+/// The warning for a contact whose identity key changed since they were
+/// last verified.
+fn key_change_warning(alias: &str, changed_at: chrono::DateTime<Utc>) -> String {
+    format!(
+        "⚠ {}'s identity key changed on {}. Messages to them wait until you run: whisper verify {}",
+        alias,
+        changed_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"),
+        alias
+    )
+}
 
-    // Update settings
-    let desc_update = description.map(|d| if d.is_empty() { None } else { Some(d) });
-    
-    if db.update_group_settings(&group.id, new_name, desc_update)? {
-        if let Some(n) = new_name {
-            println!("Updated group name to: {}", n);
-        }
-        if let Some(d) = description {
-            if d.is_empty() {
-                println!("Cleared group description");
-            } else {
-                println!("Updated group description: {}", d);
+/// Merge an exported conversation into the database. Messages are
+/// deduplicated by ID and the more final status wins, as in
+/// `merge_messages`. Returns (new messages, updated statuses).
+fn import_conversation(db: &Database, export: ConversationExport) -> Result<(usize, usize)> {
+    let peer_id: PeerId = export.peer_id.parse().context("Invalid peer ID in export")?;
+
+    let local = db.get_conversation(&peer_id, None)?;
+    let local_status: HashMap<uuid::Uuid, MessageStatus> =
+        local.iter().map(|m| (m.id, m.status.clone())).collect();
+    let remote = export
+        .messages
+        .into_iter()
+        .map(|m| m.into_message())
+        .collect::<Result<Vec<_>>>()?;
+
+    let (added, updated) = db.transaction(|db| {
+        let (mut new, mut updated) = (Vec::new(), 0);
+        for msg in merge_messages(local, remote) {
+            match local_status.get(&msg.id) {
+                None => new.push(msg),
+                Some(status) if *status != msg.status => {
+                    db.update_message_status(&msg.id, &msg.status)?;
+                    updated += 1;
+                }
+                Some(_) => {}
             }
         }
-    } else {
-        println!("No changes made");
+        // IDs already used by another conversation are skipped
+        Ok((db.insert_messages_batch(&new)?, updated))
+    })?;
+
+    // Keep the alias from the export if this peer is new to us
+    if db.get_contact(&peer_id)?.is_none() && db.get_contact_by_alias(&export.alias)?.is_none() {
+        db.upsert_contact(&Contact::new(peer_id, export.alias, Vec::new()))?;
     }
 
+    Ok((added, updated))
+}
+
+/// Import a conversation from a JSON export.
+pub async fn handle_import_history(file: &Path, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let json = fs::read_to_string(file).with_context(|| format!("Failed to read {}", file.display()))?;
+    let export = ConversationExport::from_json(&json)?;
+    let alias = export.alias.clone();
+
+    let (added, updated) = import_conversation(&db, export)?;
+    println!(
+        "Imported {} new message(s) with {} ({} status update(s))",
+        added, alias, updated
+    );
+
     Ok(())
 }
 
-/// Show group info including members and their roles.
-pub async fn handle_group_info(group_name: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+/// Search message history.
+pub async fn handle_search(query: &str, limit: usize, data_dir: &Path, passphrase: &str) -> Result<()> {
     let db = open_database(data_dir, passphrase)?;
 
-    // Get group
-    let group = db
-        .get_group_by_name(group_name)?
-        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
 
-    println!("Group: {}", group.name);
-    println!("ID: {}", group.id);
-    if let Some(desc) = &group.description {
-        println!("Description: {}", desc);
+    let results = db.search_messages(query, limit)?;
+    if results.is_empty() {
+        println!("No messages match '{}'", query);
+        return Ok(());
     }
-    println!("Created: {}", group.created_at);
-    
-    println!("\nMembers ({}):", group.members.len());
-    
-    // Try to resolve member names from contacts
-    for member in &group.members {
-        let is_owner = group.is_owner(&member.peer_id);
-        let alias = db.get_contact(&member.peer_id)?
-            .map(|c| c.alias)
-            .unwrap_or_else(|| member.peer_id.to_string());
-        
-        let role_str = if is_owner {
-            "owner"
-        } else {
-            match member.role {
-                crate::message::MemberRole::Admin => "admin",
-                crate::message::MemberRole::Member => "member",
-            }
+
+    let contacts = db.list_contacts()?;
+    let name = |peer_id: &PeerId| {
+        if *peer_id == our_peer_id {
+            return "you".to_string();
+        }
+        contacts
+            .iter()
+            .find(|c| c.peer_id == *peer_id)
+            .map(|c| c.alias.clone())
+            .unwrap_or_else(|| short_peer_id(peer_id))
+    };
+
+    println!("Found {} message(s):", results.len());
+    for msg in results {
+        let MessageContent::Text(text) = &msg.content else { continue };
+        let to = match &msg.to {
+            Recipient::Direct(peer_id) => name(peer_id),
+            Recipient::Group(group_id) => db
+                .get_group(group_id)?
+                .map(|g| format!("#{}", g.name))
+                .unwrap_or_else(|| "#unknown".to_string()),
         };
-        
-        println!("  {} [{}]", alias, role_str);
+        println!(
+            "  [{}] {} {} -> {}: {}",
+            msg.timestamp.format("%Y-%m-%d %H:%M"),
+            &msg.id.to_string()[..8],
+            name(&msg.from),
+            to,
+            text
+        );
     }
 
     Ok(())
 }
 
-// === File Transfer Commands ===
-
-use crate::message::{FileTransfer, FileTransferComplete, FileTransferStatus};
-
-/// Send a file to a contact.
-pub async fn handle_file_send(alias: &str, file_path: &Path, data_dir: &Path, passphrase: &str) -> Result<()> {
+/// Delete a message. Our own messages are deleted for everyone by default:
+/// the local copy becomes a tombstone and each recipient is asked to do the
+/// same when they next connect. `local` removes only this device's copy.
+pub async fn handle_delete(id: &str, local: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
     let db = open_database(data_dir, passphrase)?;
-    let keypair = load_keypair(&keypair_path(data_dir), passphrase)?;
-    let our_peer_id = keypair_to_peer_id(&keypair);
 
-    // Find the contact
-    let contact = db.get_contact_by_alias(alias)?
-        .with_context(|| format!("Contact '{}' not found", alias))?;
+    let message_id = resolve_message_id(&db, id)?;
+    let short_id = &message_id.to_string()[..8];
 
-    // Read the file
-    let file_data = fs::read(file_path)
-        .with_context(|| format!("Failed to read file: {}", file_path.display()))?;
+    if local {
+        db.delete_message(&message_id)?;
+        println!("Deleted message {} from this device", short_id);
+        return Ok(());
+    }
 
-    let filename = file_path
-        .file_name()
-        .map(|s| s.to_string_lossy().to_string())
-        .unwrap_or_else(|| "unknown".to_string());
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
 
-    // Create the transfer record
-    let transfer = FileTransfer::new_outgoing(
-        our_peer_id,
-        Recipient::Direct(contact.peer_id),
-        filename.clone(),
-        &file_data,
-    );
+    let message = db
+        .get_message(&message_id)?
+        .ok_or_else(|| anyhow::anyhow!("Message '{}' not found", id))?;
+    if message.from != our_peer_id {
+        anyhow::bail!("Only your own messages can be deleted for everyone. Use --local to delete your copy");
+    }
 
-    // Save to database
-    db.insert_file_transfer(&transfer)?;
+    let recipients = match &message.to {
+        Recipient::Direct(peer_id) => vec![*peer_id],
+        Recipient::Group(group_id) => db
+            .get_group(group_id)?
+            .map(|g| g.member_peer_ids())
+            .unwrap_or_default(),
+    };
+    db.tombstone_message(&message_id)?;
+    let queued = queue_sealed(&db, &keypair, recipients, |_| {
+        Ok(MessageContent::DeleteRequest(message_id))
+    })?;
+
+    println!("Deleted message {}", short_id);
+    if !queued.is_empty() {
+        println!(
+            "Asked {} recipient(s) to delete their copy when they next connect",
+            queued.len()
+        );
+    }
 
-    // Create chunks and save them
-    let chunks = FileTransfer::create_chunks(transfer.id, &file_data);
-    for chunk in &chunks {
-        db.insert_file_chunk(chunk)?;
+    Ok(())
+}
+
+/// Find the conversation `name` refers to: a contact alias, else a group
+/// name. Returns it with its display name.
+fn resolve_conversation(db: &Database, name: &str) -> Result<(Recipient, String)> {
+    if let Some(contact) = db.get_contact_by_alias(name)? {
+        return Ok((Recipient::Direct(contact.peer_id), contact.alias));
+    }
+    if let Some(group) = db.get_group_by_name(name)? {
+        return Ok((Recipient::Group(group.id), group.name));
     }
+    anyhow::bail!("No contact or group named '{}'", name)
+}
 
-    println!("File transfer created:");
-    println!("  ID: {}", transfer.id);
-    println!("  File: {}", filename);
-    println!("  Size: {} bytes", file_data.len());
-    println!("  Chunks: {}", chunks.len());
-    println!();
+/// A retention policy in words.
+fn describe_retention(policy: &RetentionPolicy) -> String {
+    match (policy.max_age_days, policy.max_messages) {
+        (None, None) => "keep everything".to_string(),
+        (Some(days), None) => format!("keep {} days", days),
+        (None, Some(max)) => format!("keep the newest {} messages", max),
+        (Some(days), Some(max)) => format!("keep {} days, at most {} messages", days, max),
+    }
+}
 
-    // Try to send over network if contact has public key
-    if !contact.public_key.is_empty() {
-        println!("Sending file to {}...", alias);
-        
-        // Convert Ed25519 public key to X25519 for encryption
-        let recipient_pk = match ed25519_pk_to_x25519(&contact.public_key) {
-            Ok(pk) => pk,
-            Err(_) => {
-                println!("Warning: Could not convert contact's public key. Chunks stored locally only.");
-                println!("Transfer queued. Use 'whisper file status {}' to check progress.", transfer.id);
-                return Ok(());
-            }
-        };
+/// Show or change the retention policy for a conversation, or with no
+/// conversation the default. `policy` replaces the setting and `clear`
+/// removes it; with neither the setting is shown.
+pub async fn handle_retention(
+    conversation: Option<&str>,
+    policy: Option<RetentionPolicy>,
+    clear: bool,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
 
-        // Create and start network node
-        let mut node = WhisperNode::new(keypair.clone()).await.context("Failed to create network node")?;
-        node.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-        
-        // Send each chunk
-        let total = chunks.len();
-        for (i, chunk) in chunks.iter().enumerate() {
-            // Serialize the chunk
-            let chunk_data = bincode::serialize(chunk)?;
-            
-            // Create wire message: FILE:<chunk_data>
-            let mut wire_msg = FILE_CHUNK_PREFIX.to_vec();
-            wire_msg.extend_from_slice(&chunk_data);
-            
-            // Encrypt for recipient
-            let encrypted = encrypt_message(&wire_msg, &recipient_pk)?;
-            
-            // Send via network
-            node.send_message(contact.peer_id, encrypted);
-            
-            // Progress indicator
-            let progress = ((i + 1) as f32 / total as f32 * 100.0) as u32;
-            print!("\r  Sending chunk {}/{} ({}%)", i + 1, total, progress);
-            io::Write::flush(&mut io::stdout())?;
+    let target = conversation.map(|name| resolve_conversation(&db, name)).transpose()?;
+    let with = target.as_ref().map(|(with, _)| with);
+    let label = target
+        .as_ref()
+        .map_or_else(|| "Default retention".to_string(), |(_, name)| format!("Retention for {}", name));
+
+    if let Some(policy) = policy {
+        db.set_retention_policy(with, &policy)?;
+        println!("{}: {}", label, describe_retention(&policy));
+        println!("Run 'whisper prune --dry-run' to see what this removes");
+    } else if clear {
+        let removed = db.clear_retention_policy(with)?;
+        match (&target, removed) {
+            (_, false) => println!("{} was not set", label),
+            (Some((_, name)), true) => println!("{} now uses the default retention", name),
+            (None, true) => println!("Default retention cleared: messages are kept"),
         }
-        
-        // Send completion notification
-        let complete = FileTransferComplete {
-            transfer_id: transfer.id,
-            filename: filename.clone(),
-            total_size: transfer.total_size,
-            file_checksum: transfer.file_checksum,
-        };
-        let complete_data = bincode::serialize(&complete)?;
-        let mut wire_msg = FILE_COMPLETE_PREFIX.to_vec();
-        wire_msg.extend_from_slice(&complete_data);
-        let encrypted = encrypt_message(&wire_msg, &recipient_pk)?;
-        node.send_message(contact.peer_id, encrypted);
-        
-        println!("\n  File transfer queued for delivery.");
-        println!("  Chunks will be sent when peer is online.");
     } else {
-        println!("Warning: Contact has no public key stored. Cannot encrypt file.");
-        println!("Use 'whisper import-contact' to add their public key.");
+        match with {
+            Some(with) => match db.retention_policy(Some(with))? {
+                Some(own) => println!("{}: {}", label, describe_retention(&own)),
+                None => println!(
+                    "{}: {} (default)",
+                    label,
+                    describe_retention(&db.effective_retention_policy(with)?)
+                ),
+            },
+            None => println!("{}: {}", label, describe_retention(&db.retention_policy(None)?.unwrap_or_default())),
+        }
     }
 
-    println!();
-    println!("Use 'whisper file status {}' to check progress.", transfer.id);
-
     Ok(())
 }
 
-/// List file transfers.
-pub async fn handle_file_list(data_dir: &Path, passphrase: &str) -> Result<()> {
+/// Delete messages outside their conversation's retention policy, or with
+/// `dry_run` list what would go.
+pub async fn handle_prune(dry_run: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
     let db = open_database(data_dir, passphrase)?;
 
-    let transfers = db.list_file_transfers(None)?;
-
-    if transfers.is_empty() {
-        println!("No file transfers.");
+    let pruned = db.prune_messages(Utc::now(), dry_run)?;
+    if pruned.is_empty() {
+        println!("Nothing to prune");
         return Ok(());
     }
 
-    println!("File transfers:");
-    println!();
+    let total: usize = pruned.iter().map(|p| p.messages).sum();
+    let verb = if dry_run { "Would delete" } else { "Deleted" };
+    println!("{} {} message(s):", verb, total);
+    for conversation in &pruned {
+        println!("  {}: {}", conversation.name, conversation.messages);
+    }
 
-    for transfer in transfers {
-        let status_str = match transfer.status {
-            FileTransferStatus::Pending => "Pending",
-            FileTransferStatus::InProgress => "In Progress",
-            FileTransferStatus::Complete => "Complete",
-            FileTransferStatus::Failed => "Failed",
-            FileTransferStatus::Cancelled => "Cancelled",
-        };
+    Ok(())
+}
 
-        let direction = if transfer.chunks_received == 0 && transfer.status == FileTransferStatus::Pending {
-            "outgoing"
-        } else {
-            "incoming"
-        };
+/// Pin a conversation to the top of the inbox and contact list, or with
+/// `pinned` false unpin it.
+pub async fn handle_pin(conversation: &str, pinned: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
 
-        println!("  {} [{}] ({}) - {:.1}%",
-            transfer.filename,
-            status_str,
-            direction,
-            transfer.progress()
-        );
-        println!("    ID: {}", transfer.id);
-        println!("    Size: {} bytes ({} chunks)", transfer.total_size, transfer.total_chunks);
-        println!();
+    let (with, name) = resolve_conversation(&db, conversation)?;
+    db.set_pinned(&with, pinned)?;
+    if pinned {
+        println!("Pinned {}", name);
+    } else {
+        println!("Unpinned {}", name);
     }
+    Ok(())
+}
+
+/// Archive a conversation out of the inbox and contact list, or with
+/// `archived` false bring it back.
+pub async fn handle_archive(conversation: &str, archived: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
 
+    let (with, name) = resolve_conversation(&db, conversation)?;
+    db.set_archived(&with, archived)?;
+    if archived {
+        println!("Archived {}. Bring it back with: whisper archive {} --undo", name, name);
+    } else {
+        println!("Unarchived {}", name);
+    }
     Ok(())
 }
 
-/// Show status of a specific transfer.
-pub async fn handle_file_status(id_str: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+/// A conversation's notification settings in words, as of `now`.
+fn describe_notifications(settings: &NotificationSettings, now: chrono::DateTime<Utc>) -> String {
+    let level = match settings.level {
+        NotificationLevel::All => "every message notifies",
+        NotificationLevel::Mentions => "only mentions notify",
+        NotificationLevel::None => "nothing notifies",
+    };
+    let mut words = level.to_string();
+    if settings.is_muted(now) {
+        match settings.muted_until {
+            Some(until) => {
+                let until = until.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M");
+                words.push_str(&format!(", muted until {}", until));
+            }
+            None => words.push_str(", muted"),
+        }
+    }
+    if settings.alert == Alert::Bell {
+        words.push_str(", rings the bell");
+    }
+    words
+}
+
+/// Show or change how a conversation notifies the user. `mute_hours`
+/// mutes for that long, with `mute` muting until `unmute`.
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_notify(
+    conversation: &str,
+    level: Option<&str>,
+    mute: bool,
+    mute_hours: Option<u32>,
+    unmute: bool,
+    alert: Option<&str>,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
     let db = open_database(data_dir, passphrase)?;
 
-    let id = uuid::Uuid::parse_str(id_str)
-        .with_context(|| format!("Invalid transfer ID: {}", id_str))?;
+    let (with, name) = resolve_conversation(&db, conversation)?;
+    let mut settings = db.notification_settings(&with)?;
+    let now = Utc::now();
+    let changed = level.is_some() || mute || mute_hours.is_some() || unmute || alert.is_some();
+    if let Some(level) = level {
+        settings.level = NotificationLevel::parse(&level.to_lowercase())
+            .ok_or_else(|| anyhow::anyhow!("Unknown level '{}': use all, mentions or none", level))?;
+    }
+    if let Some(alert) = alert {
+        settings.alert = Alert::parse(&alert.to_lowercase())
+            .ok_or_else(|| anyhow::anyhow!("Unknown alert '{}': use quiet or bell", alert))?;
+    }
+    if mute || mute_hours.is_some() {
+        settings.muted = true;
+        settings.muted_until = mute_hours.map(|hours| now + chrono::Duration::hours(i64::from(hours)));
+    }
+    if unmute {
+        settings.muted = false;
+        settings.muted_until = None;
+    }
+    if changed {
+        db.set_notification_settings(&with, &settings)?;
+    }
 
-    let transfer = db.get_file_transfer(&id)?
-        .with_context(|| format!("Transfer not found: {}", id))?;
+    println!("Notifications for {}: {}", name, describe_notifications(&settings, now));
+    Ok(())
+}
 
-    let status_str = match transfer.status {
-        FileTransferStatus::Pending => "Pending",
-        FileTransferStatus::InProgress => "In Progress",
-        FileTransferStatus::Complete => "Complete",
-        FileTransferStatus::Failed => "Failed",
-        FileTransferStatus::Cancelled => "Cancelled",
+/// Add a new contact.
+pub async fn handle_add_contact(alias: &str, peer_id_str: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    // Parse peer ID
+    let peer_id: PeerId = peer_id_str
+        .parse()
+        .context("Invalid peer ID format")?;
+
+    // Create contact
+    let contact = Contact {
+        peer_id,
+        alias: alias.to_string(),
+        public_key: vec![], // Will be exchanged when connecting
+        trust_level: TrustLevel::Unknown,
+        last_seen: None,
+        note: None,
+        tags: Vec::new(),
+        presence: None,
     };
 
-    println!("File Transfer Status:");
-    println!();
-    println!("  ID: {}", transfer.id);
-    println!("  File: {}", transfer.filename);
-    println!("  Size: {} bytes", transfer.total_size);
-    println!("  Status: {}", status_str);
-    println!("  Progress: {:.1}% ({}/{} chunks)",
-        transfer.progress(),
-        transfer.chunks_received,
-        transfer.total_chunks
-    );
-    println!("  Created: {}", transfer.created_at);
+    // Save to database
+    db.upsert_contact(&contact)?;
 
-    if transfer.status == FileTransferStatus::Complete {
-        println!();
-        println!("Transfer complete. File can be reassembled.");
-    }
+    println!("Added contact: {} ({})", alias, peer_id);
 
     Ok(())
 }
 
-/// Cancel an in-progress transfer.
-pub async fn handle_file_cancel(id_str: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+/// Add a contact by the username they registered, checking the claim is
+/// signed by the key it names.
+pub async fn handle_add_contact_by_username(
+    alias: &str,
+    username: &str,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
+    let username = normalize_username(username)?;
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
     let db = open_database(data_dir, passphrase)?;
 
-    let id = uuid::Uuid::parse_str(id_str)
-        .with_context(|| format!("Invalid transfer ID: {}", id_str))?;
+    let node = create_node(&db, &NetworkConfig::load(data_dir)?, keypair).await?;
+    let mut events = node.subscribe();
+    let node = node.spawn();
+    println!("Looking up @{}...", username);
+    let claims = async {
+        // Lookups go through the DHT, so we must be in it first
+        tokio::time::timeout(USERNAME_LOOKUP_TIMEOUT, async {
+            loop {
+                match events.recv().await {
+                    Ok(NodeEvent::DhtReady { .. }) => return Ok(()),
+                    Err(RecvError::Closed) => anyhow::bail!("Node task stopped"),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("Couldn't join the DHT to look up @{}", username))??;
+        tokio::time::timeout(USERNAME_LOOKUP_TIMEOUT, node.lookup_username(&username))
+            .await
+            .map_err(|_| anyhow::anyhow!("No answer for @{} after {}s", username, USERNAME_LOOKUP_TIMEOUT.as_secs()))?
+    }
+    .await;
+    node.shutdown();
+
+    let claim = match claims?.as_slice() {
+        [] => anyhow::bail!("No one has registered @{}", username),
+        [claim] => claim.clone(),
+        claims => {
+            let peers: Vec<String> = claims.iter().map(|c| c.peer_id.to_string()).collect();
+            anyhow::bail!(
+                "@{} is claimed by several peers: {}. Ask them which is theirs and run: whisper add {} <peer id>",
+                username,
+                peers.join(", "),
+                alias
+            );
+        }
+    };
 
-    let transfer = db.get_file_transfer(&id)?
-        .with_context(|| format!("Transfer not found: {}", id))?;
+    let contact = Contact {
+        peer_id: claim.peer_id,
+        alias: alias.to_string(),
+        public_key: contact_key_bytes(&claim.public_key),
+        trust_level: TrustLevel::Unknown,
+        last_seen: None,
+        note: None,
+        tags: Vec::new(),
+        presence: None,
+    };
+    db.upsert_contact(&contact)?;
 
-    match transfer.status {
-        FileTransferStatus::Complete => {
-            println!("Transfer already complete. Cannot cancel.");
-            return Ok(());
+    println!("Added contact: {} (@{}, {})", alias, username, claim.peer_id);
+
+    Ok(())
+}
+
+/// Show, register or clear the username others can add us by. Claims are
+/// published whenever the node runs.
+pub async fn handle_username(username: Option<&str>, clear: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    if let Some(username) = username {
+        let username = normalize_username(username)?;
+        db.set_username(Some(&username))?;
+        println!("Username: @{}", username);
+        println!("It's published in the DHT while whisper runs. Others can add you with:");
+        println!("  whisper add <alias> --username @{}", username);
+    } else if clear {
+        db.set_username(None)?;
+        println!("Username cleared; the published claim expires within a few hours");
+    } else {
+        match db.username()? {
+            Some(username) => println!("Username: @{}", username),
+            None => println!("No username registered"),
         }
-        FileTransferStatus::Cancelled => {
-            println!("Transfer already cancelled.");
-            return Ok(());
+    }
+    Ok(())
+}
+
+/// Show node status.
+pub async fn handle_status(output: OutputFormat, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let key_path = keypair_path(data_dir);
+
+    if !key_path.exists() {
+        if output == OutputFormat::Json {
+            anyhow::bail!("No identity found. Run: whisper init");
         }
-        _ => {}
+        println!("No identity found. Run: whisper init");
+        return Ok(());
     }
 
-    // Update status to cancelled
-    db.update_file_transfer(&id, transfer.chunks_received, &FileTransferStatus::Cancelled)?;
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let peer_id = keypair_to_peer_id(&keypair);
+    let public_key = export_public_key(&keypair);
 
-    println!("Transfer cancelled: {}", transfer.filename);
+    let db = open_database(data_dir, passphrase)?;
+    let contacts = db.list_contacts()?;
+
+    if output == OutputFormat::Json {
+        let last = db.last_reachability()?;
+        return print_json(&StatusOutput {
+            peer_id: peer_id.to_string(),
+            public_key,
+            contacts: contacts.len(),
+            reachability: last.map_or(Reachability::Unknown, |(r, _)| r).as_str(),
+            reachability_at: last.map(|(_, at)| at),
+            data_dir: data_dir.to_path_buf(),
+        });
+    }
+
+    println!("Whisper Status");
+    println!("==============");
+    println!("Peer ID: {}", peer_id);
+    println!("Public Key: {}", public_key);
+    println!("Contacts: {}", contacts.len());
+    match db.last_reachability()? {
+        Some((reachability, observed_at)) => println!(
+            "Reachability: {} (as of {})",
+            reachability,
+            observed_at.format("%Y-%m-%d %H:%M")
+        ),
+        None => println!("Reachability: {}", Reachability::Unknown),
+    }
+    println!("Data Dir: {:?}", data_dir);
 
     Ok(())
 }
 
-/// Resume an interrupted file transfer.
-pub async fn handle_file_resume(id_str: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+/// Change a contact's trust level, recording the change in the audit log.
+fn set_trust_level(db: &Database, contact: &mut Contact, level: TrustLevel) -> Result<()> {
+    let previous = contact.trust_level;
+    contact.trust_level = level;
+    db.upsert_contact(contact)?;
+    if previous != level {
+        let detail = format!("{:?} → {:?}", previous, level);
+        db.record_audit(AuditKind::TrustChanged, Some(&contact.peer_id), &detail)?;
+    }
+    Ok(())
+}
+
+/// Set trust level for a contact.
+pub async fn handle_trust(alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
     let db = open_database(data_dir, passphrase)?;
-    let keypair = load_keypair(&keypair_path(data_dir), passphrase)?;
 
-    let id = uuid::Uuid::parse_str(id_str)
-        .with_context(|| format!("Invalid transfer ID: {}", id_str))?;
+    let mut contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
 
-    let transfer = db.get_file_transfer(&id)?
-        .with_context(|| format!("Transfer not found: {}", id))?;
+    set_trust_level(&db, &mut contact, TrustLevel::Trusted)?;
 
-    // Check if resumable
-    match transfer.status {
-        FileTransferStatus::Complete => {
-            println!("Transfer already complete.");
-            return Ok(());
-        }
-        FileTransferStatus::Cancelled => {
-            println!("Transfer was cancelled. Cannot resume.");
-            return Ok(());
+    println!("Marked {} as trusted", alias);
+
+    Ok(())
+}
+
+/// Block a contact.
+pub async fn handle_block(alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let mut contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+
+    set_trust_level(&db, &mut contact, TrustLevel::Blocked)?;
+
+    println!("Blocked {}", alias);
+
+    Ok(())
+}
+
+/// Give a contact a new alias.
+pub async fn handle_rename(old_alias: &str, new_alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let contact = db
+        .get_contact_by_alias(old_alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", old_alias))?;
+    let new_alias = new_alias.trim();
+    if new_alias.is_empty() {
+        anyhow::bail!("Alias cannot be empty");
+    }
+
+    db.rename_contact(&contact.peer_id, new_alias)?;
+    println!("Renamed {} to {}", old_alias, new_alias);
+
+    Ok(())
+}
+
+/// Remove a contact. With `purge_messages`, our conversation with them and
+/// anything still queued for them go too.
+pub async fn handle_remove(alias: &str, purge_messages: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+
+    db.delete_contact(&contact.peer_id)?;
+    println!("Removed {}", alias);
+
+    if purge_messages {
+        let (messages, pending) = db.delete_conversation(&contact.peer_id)?;
+        println!("Deleted {} message(s) and {} queued message(s)", messages, pending);
+    }
+
+    Ok(())
+}
+
+/// Message requests grouped by sender, in the order senders first wrote.
+fn group_requests(messages: Vec<Message>) -> Vec<(PeerId, Vec<Message>)> {
+    let mut senders: Vec<(PeerId, Vec<Message>)> = Vec::new();
+    for msg in messages {
+        match senders.iter_mut().find(|(from, _)| *from == msg.from) {
+            Some((_, held)) => held.push(msg),
+            None => senders.push((msg.from, vec![msg])),
         }
-        _ => {}
     }
+    senders
+}
 
-    // Get existing chunks
-    let existing_chunks = db.get_file_chunks(&id)?;
-    let existing_indices: std::collections::HashSet<u32> = existing_chunks.iter()
-        .map(|c| c.chunk_index)
+/// Find who sent message requests by peer ID or a unique prefix of it.
+fn find_request_sender(db: &Database, query: &str) -> Result<PeerId> {
+    let senders: Vec<PeerId> = group_requests(db.list_message_requests()?)
+        .into_iter()
+        .map(|(from, _)| from)
         .collect();
+    if let Some(exact) = senders.iter().find(|from| from.to_string() == query) {
+        return Ok(*exact);
+    }
+    let matches: Vec<&PeerId> = senders.iter().filter(|from| from.to_string().starts_with(query)).collect();
+    match matches.as_slice() {
+        [from] => Ok(**from),
+        [] => anyhow::bail!("No message requests from '{}'", query),
+        _ => anyhow::bail!("'{}' matches more than one sender; use more of the peer ID", query),
+    }
+}
 
-    // Find missing chunk indices
-    let missing: Vec<u32> = (0..transfer.total_chunks)
-        .filter(|i| !existing_indices.contains(i))
-        .collect();
+/// List messages from peers who aren't contacts yet.
+pub async fn handle_requests_list(output: OutputFormat, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let requests = group_requests(db.list_message_requests()?);
+
+    if output == OutputFormat::Json {
+        let requests: Vec<RequestOutput> = requests
+            .iter()
+            .map(|(from, messages)| {
+                let latest = messages.last().expect("senders have at least one message");
+                RequestOutput {
+                    peer_id: from.to_string(),
+                    messages: messages.len(),
+                    latest: latest.timestamp,
+                    preview: latest.content.summary(),
+                }
+            })
+            .collect();
+        return print_json(&requests);
+    }
 
-    if missing.is_empty() {
-        println!("All chunks present. Marking as complete.");
-        db.update_file_transfer_status(&id, FileTransferStatus::Complete)?;
+    if requests.is_empty() {
+        println!("No message requests.");
         return Ok(());
     }
 
-    // Get recipient from transfer
-    let recipient_peer_id = match &transfer.to {
-        Recipient::Direct(peer_id) => *peer_id,
-        Recipient::Group(_) => {
-            println!("Group file transfers not yet supported for resume.");
-            return Ok(());
-        }
-    };
+    println!("Message requests:");
+    for (from, messages) in &requests {
+        let latest = messages.last().expect("senders have at least one message");
+        println!(
+            "  {} ({} message(s), latest {})",
+            from,
+            messages.len(),
+            latest.timestamp.format("%Y-%m-%d %H:%M")
+        );
+        let preview = latest.content.summary();
+        println!("    {}", preview.lines().next().unwrap_or_default());
+    }
+    println!("Accept with: whisper requests accept <peer-id> <alias>");
+
+    Ok(())
+}
+
+/// Accept a stranger's messages: add them as a contact under `alias` and
+/// move what they sent into a normal conversation.
+pub async fn handle_requests_accept(peer: &str, alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let from = find_request_sender(&db, peer)?;
+    // Contacts the trust policy held back are already added
+    if let Some(contact) = db.get_contact(&from)? {
+        let moved = db.accept_message_requests(&from)?;
+        println!("Moved {} message(s) from {} into your conversation", moved, contact.alias);
+        return Ok(());
+    }
+    if db.get_contact_by_alias(alias)?.is_some() {
+        anyhow::bail!("Contact '{}' already exists", alias);
+    }
+
+    // Public key is exchanged when connecting, as with `whisper add`
+    db.upsert_contact(&Contact::new(from, alias.to_string(), vec![]))?;
+    let moved = db.accept_message_requests(&from)?;
+
+    println!("Added contact: {} ({})", alias, from);
+    println!("Moved {} message(s) into your conversation", moved);
+
+    Ok(())
+}
+
+/// Decline a stranger's messages, deleting them.
+pub async fn handle_requests_decline(peer: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let from = find_request_sender(&db, peer)?;
+    let deleted = db.delete_message_requests(&from)?;
+
+    println!("Declined {} message(s) from {}", deleted, from);
+
+    Ok(())
+}
+
+/// Safety number for our conversation with a contact.
+fn contact_safety_number(keypair: &libp2p::identity::Keypair, contact: &Contact) -> Result<String> {
+    if contact.public_key.is_empty() {
+        anyhow::bail!(
+            "No public key for {}. Import their key with: whisper import-contact",
+            contact.alias
+        );
+    }
+    let our_key = keypair
+        .public()
+        .try_into_ed25519()
+        .context("Identity key is not Ed25519")?
+        .to_bytes();
+    Ok(safety_number(
+        &our_key,
+        &keypair_to_peer_id(keypair),
+        &contact.public_key,
+        &contact.peer_id,
+    ))
+}
+
+/// Whether a prompt answer is a yes.
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+/// Show the safety number for a contact and mark them verified once the
+/// user confirms it matches what the contact sees.
+pub async fn handle_verify(alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+
+    let mut contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+
+    if let Some(changed_at) = db.key_change(&contact.peer_id)? {
+        println!("{}", key_change_warning(alias, changed_at));
+        println!();
+    }
+    let number = contact_safety_number(&keypair, &contact)?;
+    println!("Safety number with {}:", alias);
+    println!();
+    println!("{}", format_safety_number(&number));
+    println!();
+    if let Some(their_key) = contact.identity_key() {
+        println!("{}: {}", alias, emoji_fingerprint(&their_key));
+    }
+    if let Ok(our_key) = keypair.public().try_into_ed25519() {
+        println!("You: {}", emoji_fingerprint(&our_key.to_bytes()));
+    }
+    println!();
+    println!("Compare it with {} in person or over a channel you trust.", alias);
+    print!("Does it match? [y/N] ");
+    io::Write::flush(&mut io::stdout())?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    if !is_yes(&answer) {
+        println!("Not verified. If the numbers differ, someone may be impersonating {}.", alias);
+        return Ok(());
+    }
+
+    set_trust_level(&db, &mut contact, TrustLevel::Verified)?;
+    db.clear_key_change(&contact.peer_id)?;
+    println!("Marked {} as verified", alias);
+
+    Ok(())
+}
+
+/// Turn read receipts to a contact on or off.
+pub async fn handle_receipts(alias: &str, enabled: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+
+    db.set_read_receipts(&contact.peer_id, enabled)?;
+
+    let state = if enabled { "on" } else { "off" };
+    println!("Read receipts to {} are {}", alias, state);
+
+    Ok(())
+}
+
+/// Turn peer exchange on or off, or for one contact, whether it covers
+/// them. Takes effect the next time the node starts.
+pub async fn handle_pex(enabled: bool, alias: Option<&str>, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let state = if enabled { "on" } else { "off" };
+
+    let Some(alias) = alias else {
+        db.set_pex_enabled(enabled)?;
+        println!("Peer exchange is {}", state);
+        return Ok(());
+    };
+    let contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+    db.set_share_addresses(&contact.peer_id, enabled)?;
+    println!("Peer exchange for {} is {}", alias, state);
+    if enabled && !contact.is_trusted() {
+        println!("Their addresses can be passed on; hints are only traded with trusted contacts");
+    }
+
+    Ok(())
+}
+
+/// Export public key to stdout.
+pub async fn handle_export_key(qr: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let key_path = keypair_path(data_dir);
+
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let db = open_database(data_dir, passphrase)?;
+    let prekey = current_prekey(&db, &keypair)?;
+
+    let bundle = export_contact_bundle(&keypair, &prekey.public())?;
+
+    if qr {
+        let peer_id = keypair_to_peer_id(&keypair);
+        println!("{}", render_qr(&contact_uri(&peer_id, &bundle))?);
+        println!("Peer ID: {}", peer_id);
+        println!("Scan with any QR reader, then run: whisper import-contact - <alias>");
+    } else {
+        println!("{}", bundle);
+    }
+
+    Ok(())
+}
+
+/// Re-encrypt the database under a key derived with a new salt, and the
+/// new passphrase if there is one, which the keypair is sealed with too.
+/// The database is checked before and after. Everything the new key
+/// replaces is staged before the database takes it, so if we stop after
+/// that, the next open finishes the rekey.
+pub async fn handle_db_rekey(new_passphrase: Option<&str>, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let db = open_database(data_dir, passphrase)?;
+    db.integrity_check().context("Not rekeying a damaged database")?;
+
+    let new_passphrase = new_passphrase.unwrap_or(passphrase);
+    prepare_rekey(data_dir, &configured_kdf(data_dir)?)?;
+    let staged = if new_passphrase != passphrase {
+        save_keypair(&keypair, &staged_for_rekey(data_dir, KEYPAIR_FILE), new_passphrase)
+            .context("Failed to save keypair")
+    } else {
+        Ok(())
+    };
+    let rekeyed = staged
+        .and_then(|()| derive_rekeyed_key(new_passphrase, data_dir))
+        .and_then(|key| db.rekey(&key));
+    if let Err(e) = rekeyed {
+        abandon_rekey(data_dir);
+        return Err(e);
+    }
+    finish_rekey(data_dir)?;
+    drop(db);
+
+    let db = open_database(data_dir, new_passphrase).context("The rekeyed database didn't open")?;
+    db.integrity_check()?;
+    if new_passphrase != passphrase {
+        println!("Database rekeyed and passphrase changed");
+    } else {
+        println!("Database rekeyed");
+    }
+
+    Ok(())
+}
+
+/// Print the identity's seed phrase, for restoring it with
+/// `whisper init --from-seed`.
+pub async fn handle_key_backup(data_dir: &Path, passphrase: &str) -> Result<()> {
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let phrase = keypair_to_mnemonic(&keypair)?;
+
+    println!("Seed phrase for {}:", keypair_to_peer_id(&keypair));
+    println!();
+    let words: Vec<&str> = phrase.split(' ').collect();
+    for (row, chunk) in words.chunks(4).enumerate() {
+        let line: Vec<String> = chunk
+            .iter()
+            .enumerate()
+            .map(|(i, word)| format!("{:>2}. {:<10}", row * 4 + i + 1, word))
+            .collect();
+        println!("  {}", line.join(" ").trim_end());
+    }
+    println!();
+    println!("Write these words down and keep them somewhere safe. Anyone who has them can be you.");
+    println!("Restore with: whisper init --from-seed");
+
+    Ok(())
+}
+
+/// A contact's key as stored: raw Ed25519 bytes, for deriving encryption
+/// keys, or the protobuf encoding of any other kind of key.
+fn contact_key_bytes(public_key: &libp2p::identity::PublicKey) -> Vec<u8> {
+    public_key
+        .clone()
+        .try_into_ed25519()
+        .map(|ed_pk| ed_pk.to_bytes().to_vec())
+        .unwrap_or_else(|_| public_key.encode_protobuf())
+}
+
+/// Import a contact from a key file.
+pub async fn handle_import_contact(file: &Path, alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    // Read public key from file, or from stdin for pasted text
+    let key_data = if file == Path::new("-") {
+        io::read_to_string(io::stdin()).context("Failed to read from stdin")?
+    } else {
+        fs::read_to_string(file).context("Failed to read key file")?
+    };
+    let key_data = key_data.trim();
+
+    // Parse public key (and signed prekey, if present) and derive peer ID.
+    // Scanned QR codes carry the peer ID too, which must match the key.
+    let (public_key, prekey) = if key_data.starts_with(CONTACT_URI_SCHEME) {
+        parse_contact_uri(key_data)?
+    } else {
+        import_contact_bundle(key_data).context("Invalid public key format")?
+    };
+    let peer_id = PeerId::from(public_key.clone());
+
+    // Create contact
+    let contact = Contact {
+        peer_id,
+        alias: alias.to_string(),
+        public_key: contact_key_bytes(&public_key),
+        trust_level: TrustLevel::Unknown,
+        last_seen: None,
+        note: None,
+        tags: Vec::new(),
+        presence: None,
+    };
+
+    db.upsert_contact(&contact)?;
+    if let Some(prekey) = prekey {
+        if db.get_contact_prekey(&peer_id)?.is_some_and(|old| old != prekey) {
+            db.record_audit(AuditKind::KeyChanged, Some(&peer_id), "Imported a different signed prekey")?;
+        }
+        db.save_contact_prekey(&peer_id, &prekey)?;
+    }
+
+    println!("Imported contact: {} ({})", alias, peer_id);
+
+    Ok(())
+}
+
+/// Make a one-time invite code. Whoever accepts it before it expires is
+/// added as a contact, under `alias` if one is given.
+pub async fn handle_invite_create(alias: Option<&str>, hours: u64, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let db = open_database(data_dir, passphrase)?;
+    if let Some(alias) = alias {
+        if db.get_contact_by_alias(alias)?.is_some() {
+            anyhow::bail!("Contact '{}' already exists", alias);
+        }
+    }
+
+    // Where to find us: our rendezvous point, if we use one
+    let now = Utc::now();
+    let expires_at = now + chrono::Duration::hours(i64::try_from(hours)?);
+    let (invite, code) = create_invite(&keypair, db.rendezvous_point()?, db.username()?, expires_at)?;
+    db.save_invite(&IssuedInvite {
+        id: invite.id,
+        alias: alias.map(str::to_string),
+        created_at: now,
+        expires_at: invite.expires_at,
+        used_by: None,
+    })?;
+
+    println!("{}", code);
+    println!();
+    println!("Works once, until {}. Share it, and have them run: whisper invite accept <code>", invite.expires_at);
+    println!("They're added as a contact when their acceptance reaches you.");
+
+    Ok(())
+}
+
+/// Accept an invite: add the inviter, and send them our keys so they add
+/// us back.
+pub async fn handle_invite_accept(code: &str, alias: Option<&str>, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let invite = parse_invite(code)?;
+    if invite.is_expired(Utc::now()) {
+        anyhow::bail!("This invite expired at {}. Ask for a new one", invite.expires_at);
+    }
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    if invite.peer_id == keypair_to_peer_id(&keypair) {
+        anyhow::bail!("That's your own invite");
+    }
+    let db = open_database(data_dir, passphrase)?;
+    if let Some(contact) = db.get_contact(&invite.peer_id)? {
+        anyhow::bail!("They're already a contact: {}", contact.alias);
+    }
+    let alias = match alias {
+        Some(alias) => alias.to_string(),
+        None => invite
+            .name
+            .as_deref()
+            .and_then(|name| normalize_username(name).ok())
+            .context("The invite doesn't say what to call them. Pick an alias with --alias")?,
+    };
+    if db.get_contact_by_alias(&alias)?.is_some() {
+        anyhow::bail!("Contact '{}' already exists. Pick another alias with --alias", alias);
+    }
+
+    db.upsert_contact(&Contact::new(invite.peer_id, alias.clone(), contact_key_bytes(&invite.public_key)))?;
+    println!("Added contact: {} ({})", alias, invite.peer_id);
+    if let Some(point) = &invite.rendezvous {
+        if db.rendezvous_point()?.is_none() {
+            db.set_rendezvous_point(Some(point))?;
+            println!("Using their rendezvous point: {}", point);
+        }
+    }
+
+    // Our keys, so they can add us back and start sessions with us
+    let acceptance = InviteAcceptance {
+        invite_id: invite.id,
+        prekey: Some(current_prekey(&db, &keypair)?.public()),
+        name: db.username()?,
+    };
+    send_content(db, &alias, MessageContent::InviteAccepted(acceptance), data_dir, passphrase).await
+}
+
+/// Report traffic totals across sessions, per protocol and per contact,
+/// and how many messages are waiting to be delivered.
+pub async fn handle_stats(output: OutputFormat, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let stats = stats_report(&db)?;
+
+    if output == OutputFormat::Json {
+        return print_json(&stats);
+    }
+
+    println!("Traffic");
+    println!("=======");
+    println!("Sent: {}  Received: {}", format_bytes(stats.bytes_sent), format_bytes(stats.bytes_received));
+    println!();
+    println!("By protocol:");
+    for r in &stats.protocols {
+        println!("  {:<12} ↑ {:>10}  ↓ {:>10}", r.name, format_bytes(r.bytes_sent), format_bytes(r.bytes_received));
+    }
+    println!();
+    println!("By contact:");
+    if stats.contacts.is_empty() {
+        println!("  (none)");
+    }
+    for r in stats.contacts.iter().chain(std::iter::once(&stats.other_peers)) {
+        println!("  {:<12} ↑ {:>10}  ↓ {:>10}", r.name, format_bytes(r.bytes_sent), format_bytes(r.bytes_received));
+    }
+    println!();
+    println!("Pending Messages: {}", stats.pending_messages);
+
+    Ok(())
+}
+
+/// Stored traffic broken down for `whisper stats`, busiest contacts first.
+fn stats_report(db: &Database) -> Result<StatsOutput> {
+    let traffic = db.traffic()?;
+    let contacts = db.list_contacts()?;
+    let pending_messages = MessageQueue::new(db).total_pending()?;
+
+    let row = |name: &str, bytes: Traffic| TrafficOutput {
+        name: name.to_string(),
+        bytes_sent: bytes.sent,
+        bytes_received: bytes.received,
+    };
+    let mut other = traffic.totals();
+    let mut by_contact = Vec::new();
+    for contact in &contacts {
+        let bytes = traffic.peer(&contact.peer_id);
+        other.sent = other.sent.saturating_sub(bytes.sent);
+        other.received = other.received.saturating_sub(bytes.received);
+        if bytes.total() > 0 {
+            by_contact.push(row(&contact.alias, bytes));
+        }
+    }
+    by_contact.sort_by_key(|r| std::cmp::Reverse(r.bytes_sent + r.bytes_received));
+    let totals = traffic.totals();
+    Ok(StatsOutput {
+        bytes_sent: totals.sent,
+        bytes_received: totals.received,
+        protocols: TrafficProtocol::ALL
+            .into_iter()
+            .map(|protocol| row(protocol.as_str(), traffic.protocol(protocol)))
+            .collect(),
+        contacts: by_contact,
+        other_peers: row("other peers", other),
+        pending_messages,
+    })
+}
+
+/// Show the audit log, latest `limit` entries, and whether its hash chain
+/// is intact.
+pub async fn handle_audit(
+    kind: Option<AuditKind>,
+    since: Option<&str>,
+    limit: usize,
+    output: OutputFormat,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let since = since.map(parse_since).transpose()?;
+    let report = audit_report(&db, kind, since, limit)?;
+
+    if output == OutputFormat::Json {
+        return print_json(&report);
+    }
+
+    if report.events.is_empty() {
+        println!("No audit events");
+    }
+    for event in &report.events {
+        let who = event
+            .alias
+            .clone()
+            .or_else(|| event.peer_id.as_deref()?.parse().ok().map(|p: PeerId| short_peer_id(&p)))
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{}  {:<17}  {:<12}  {}",
+            event.at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"),
+            event.kind,
+            who,
+            event.detail
+        );
+    }
+    if let Some(id) = report.altered_from {
+        println!();
+        println!("Warning: the audit log was altered at entry {}; it and later entries can't be trusted", id);
+    }
+
+    Ok(())
+}
+
+/// The audit log with contact aliases filled in, for `whisper audit`.
+fn audit_report(
+    db: &Database,
+    kind: Option<AuditKind>,
+    since: Option<chrono::DateTime<Utc>>,
+    limit: usize,
+) -> Result<AuditLogOutput> {
+    let aliases: HashMap<PeerId, String> = db
+        .list_contacts()?
+        .into_iter()
+        .map(|c| (c.peer_id, c.alias))
+        .collect();
+    let altered_from = db.verify_audit_log()?;
+    let events = db
+        .audit_log(kind, since, limit)?
+        .into_iter()
+        .map(|event| AuditOutput {
+            id: event.id,
+            at: event.at,
+            kind: event.kind.as_str(),
+            peer_id: event.peer_id.map(|p| p.to_string()),
+            alias: event.peer_id.and_then(|p| aliases.get(&p).cloned()),
+            detail: event.detail,
+        })
+        .collect();
+    Ok(AuditLogOutput {
+        intact: altered_from.is_none(),
+        altered_from,
+        events,
+    })
+}
+
+/// A byte count for people, e.g. "512 B" or "1.5 MB".
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// List connected peers.
+/// 
+/// Since Whisper doesn't run a background daemon, this shows:
+/// 1. Contacts with recent last_seen timestamps (recently online), and the
+///    round trip last measured to each
+/// 2. Pending messages waiting for delivery
+pub async fn handle_peers(output: OutputFormat, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let key_path = keypair_path(data_dir);
+
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+
+    let db = open_database(data_dir, passphrase)?;
+    let queue = MessageQueue::new(&db);
+
+    if output == OutputFormat::Json {
+        let contacts = db.list_contacts()?;
+        let pending = queue
+            .counts_by_peer()?
+            .into_iter()
+            .map(|(peer_id, messages)| PendingOutput {
+                peer_id: peer_id.to_string(),
+                alias: contacts.iter().find(|c| c.peer_id == peer_id).map(|c| c.alias.clone()),
+                messages,
+            })
+            .collect();
+        let dead_letters = queue
+            .dead_letters()?
+            .into_iter()
+            .map(|dead| DeadLetterOutput {
+                id: dead.id.to_string(),
+                peer_id: dead.to.to_string(),
+                alias: contacts.iter().find(|c| c.peer_id == dead.to).map(|c| c.alias.clone()),
+                queued_at: dead.queued_at,
+                attempts: dead.attempts,
+                dead_at: dead.dead_at,
+            })
+            .collect();
+        let now = Utc::now();
+        let contacts = contacts
+            .into_iter()
+            .map(|c| {
+                let latency = db.peer_latency(&c.peer_id).ok().flatten().map(|l| l.latency);
+                let version = db.peer_version(&c.peer_id).ok().flatten();
+                PeerOutput {
+                    presence: c.current_presence(now),
+                    latency_ms: latency.map(|l| l.rtt.as_millis() as u64),
+                    relayed: latency.map(|l| l.relayed),
+                    protocol_version: version.as_ref().map(|v| v.protocol_version.clone()),
+                    agent_version: version.map(|v| v.agent_version),
+                    alias: c.alias,
+                    peer_id: c.peer_id.to_string(),
+                    last_seen: c.last_seen,
+                }
+            })
+            .collect();
+        return print_json(&PeersOutput { contacts, pending, dead_letters });
+    }
+
+    println!("Peer Status");
+    println!("===========");
+    println!();
+
+    // Show contacts with last_seen info
+    let contacts = db.list_contacts()?;
+    let now = Utc::now();
+
+    println!("Known Contacts:");
+    if contacts.is_empty() {
+        println!("  (none)");
+    } else {
+        for contact in &contacts {
+            let status = match (contact.current_presence(now), contact.last_seen) {
+                // What they announced beats guessing from when we last saw them
+                (Some(presence), _) => presence.to_string(),
+                (None, Some(seen)) => {
+                    let ago = now.signed_duration_since(seen);
+                    if ago.num_minutes() < 5 {
+                        "recently online".to_string()
+                    } else if ago.num_hours() < 1 {
+                        format!("{}m ago", ago.num_minutes())
+                    } else if ago.num_hours() < 24 {
+                        format!("{}h ago", ago.num_hours())
+                    } else {
+                        format!("{}d ago", ago.num_days())
+                    }
+                }
+                (None, None) => "never seen".to_string(),
+            };
+            let mut details = Vec::new();
+            if let Some(measured) = db.peer_latency(&contact.peer_id)? {
+                details.push(measured.latency.to_string());
+            }
+            if let Some(version) = db.peer_version(&contact.peer_id)? {
+                details.push(version.agent_version);
+            }
+            if details.is_empty() {
+                println!("  {} - {}", contact.alias, status);
+            } else {
+                println!("  {} - {} ({})", contact.alias, status, details.join(", "));
+            }
+        }
+    }
+
+    // Show pending messages
+    let pending = queue.counts_by_peer()?;
+    println!();
+    println!("Pending Messages: {}", pending.iter().map(|(_, count)| count).sum::<usize>());
+    if !pending.is_empty() {
+        for (peer_id, count) in pending {
+            // Try to find alias
+            let alias = contacts.iter()
+                .find(|c| c.peer_id == peer_id)
+                .map(|c| c.alias.as_str())
+                .unwrap_or("unknown");
+            println!("  {} messages for {}", count, alias);
+        }
+    }
+
+    // And those given up on
+    let dead_letters = queue.dead_letters()?;
+    if !dead_letters.is_empty() {
+        println!();
+        println!("Undelivered (gave up): {}", dead_letters.len());
+        for dead in &dead_letters {
+            let alias = contacts.iter()
+                .find(|c| c.peer_id == dead.to)
+                .map(|c| c.alias.clone())
+                .unwrap_or_else(|| short_peer_id(&dead.to));
+            println!(
+                "  {} for {} - queued {}, gave up {} after {} attempts",
+                dead.id,
+                alias,
+                dead.queued_at.format("%Y-%m-%d %H:%M"),
+                dead.dead_at.format("%Y-%m-%d %H:%M"),
+                dead.attempts
+            );
+        }
+    }
+
+    println!();
+    println!("Note: Whisper connects when you start a chat session.");
+    println!("Queued messages are retried while 'whisper chat' or 'whisper listen' runs.");
+
+    Ok(())
+}
+
+/// Parse an address for `whisper connect`: a multiaddr, or `ip:port` for
+/// TCP or `<name>.onion:port` for Tor, so people can pass one along
+/// without knowing multiaddr syntax.
+fn parse_connect_address(input: &str) -> Result<Multiaddr> {
+    if let Ok(addr) = input.parse::<Multiaddr>() {
+        return Ok(addr);
+    }
+    if let Some((host, port)) = input.rsplit_once(':') {
+        if let Some(name) = host.strip_suffix(".onion") {
+            return format!("/onion3/{}:{}", name.to_ascii_lowercase(), port)
+                .parse()
+                .map_err(|_| anyhow::anyhow!("'{}' is not a valid onion address", input));
+        }
+    }
+    let socket: SocketAddr = input
+        .parse()
+        .map_err(|_| anyhow::anyhow!("'{}' is not a multiaddr or ip:port", input))?;
+    Ok(Multiaddr::from(socket.ip()).with(Protocol::Tcp(socket.port())))
+}
+
+/// Who answered a manual connect.
+struct Reached {
+    peer_id: PeerId,
+    /// The address dialed, without any `/p2p` suffix.
+    address: Multiaddr,
+    /// Their alias, if they're a contact.
+    alias: Option<String>,
+}
+
+impl Reached {
+    /// How to refer to them on screen.
+    fn name(&self) -> String {
+        match &self.alias {
+            Some(alias) => alias.clone(),
+            None => short_peer_id(&self.peer_id),
+        }
+    }
+}
+
+/// Dial `addr`, wait for the handshake and remember the address for
+/// whoever answered, so later sessions dial them there.
+async fn connect_and_record(db: &DatabaseHandle, node: &NodeHandle, addr: Multiaddr) -> Result<Reached> {
+    let peer_id = tokio::time::timeout(CONNECT_TIMEOUT, node.connect(addr.clone()))
+        .await
+        .map_err(|_| anyhow::anyhow!("No answer from {} after {}s", addr, CONNECT_TIMEOUT.as_secs()))??;
+    let (address, _) = split_peer_id(&addr);
+    let recorded = address.clone();
+    let alias = db
+        .call(move |db| {
+            db.record_peer_address(&peer_id, &recorded, true)?;
+            Ok(db.get_contact(&peer_id)?.map(|c| c.alias))
+        })
+        .await?;
+    Ok(Reached { peer_id, address, alias })
+}
+
+/// Connect to a peer at an explicit address, for networks where mDNS
+/// can't find them.
+pub async fn handle_connect(address: &str, output: OutputFormat, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let addr = parse_connect_address(address)?;
+
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let db = open_database(data_dir, passphrase)?;
+
+    let node = create_node(&db, &NetworkConfig::load(data_dir)?, keypair).await?.spawn();
+    let db = DatabaseHandle::spawn(db)?;
+    if output != OutputFormat::Json {
+        println!("Connecting to {}...", addr);
+    }
+    let result = connect_and_record(&db, &node, addr).await;
+    node.shutdown();
+    let reached = result?;
+
+    if output == OutputFormat::Json {
+        return print_json(&ConnectOutput {
+            peer_id: reached.peer_id.to_string(),
+            address: reached.address.to_string(),
+            alias: reached.alias,
+        });
+    }
+
+    println!("Connected to {} at {}", reached.peer_id, reached.address);
+    match &reached.alias {
+        Some(alias) => println!("That's {}; their address is saved for next time.", alias),
+        None => println!("Not a contact yet. Add them with: whisper add <alias> {}", reached.peer_id),
+    }
+    Ok(())
+}
+
+/// Show, set or clear the rendezvous point used to find contacts when the
+/// DHT can't.
+pub async fn handle_rendezvous(address: Option<&str>, clear: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    if let Some(address) = address {
+        let addr: Multiaddr = address
+            .parse()
+            .map_err(|_| anyhow::anyhow!("'{}' is not a multiaddr", address))?;
+        if split_peer_id(&addr).1.is_none() {
+            anyhow::bail!("The address must end with the point's peer ID: .../p2p/<peer id>");
+        }
+        db.set_rendezvous_point(Some(&addr))?;
+        println!("Rendezvous point: {}", addr);
+    } else if clear {
+        db.set_rendezvous_point(None)?;
+        println!("Rendezvous point cleared");
+    } else {
+        match db.rendezvous_point()? {
+            Some(addr) => println!("Rendezvous point: {}", addr),
+            None => println!("No rendezvous point set"),
+        }
+    }
+    Ok(())
+}
+
+/// Show, set or clear the SOCKS5 proxy, such as Tor, that every
+/// connection goes through. Takes effect the next time whisper starts.
+pub async fn handle_proxy(address: Option<&str>, clear: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    if let Some(address) = address {
+        let proxy: SocketAddr = address
+            .parse()
+            .map_err(|_| anyhow::anyhow!("'{}' is not an ip:port, e.g. {}", address, DEFAULT_TOR_PROXY))?;
+        db.set_socks_proxy(Some(proxy))?;
+        println!("SOCKS proxy: {}", proxy);
+        println!("Connections go through it and local discovery is off.");
+    } else if clear {
+        db.set_socks_proxy(None)?;
+        println!("SOCKS proxy cleared");
+    } else {
+        match db.socks_proxy()? {
+            Some(proxy) => println!("SOCKS proxy: {}", proxy),
+            None => println!("No SOCKS proxy set"),
+        }
+    }
+    Ok(())
+}
+
+/// Show, set or clear the port to accept WebSocket connections on, for
+/// browsers and networks that only let HTTP through. Takes effect the
+/// next time whisper starts.
+pub async fn handle_websocket(port: Option<u16>, clear: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    if let Some(port) = port {
+        if port == 0 {
+            anyhow::bail!("Pick a fixed port, so peers know where to connect");
+        }
+        db.set_websocket_port(Some(port))?;
+        println!("WebSocket port: {}", port);
+    } else if clear {
+        db.set_websocket_port(None)?;
+        println!("WebSocket listening off");
+    } else {
+        match db.websocket_port()? {
+            Some(port) => println!("WebSocket port: {}", port),
+            None => println!("Not listening for WebSockets"),
+        }
+    }
+    Ok(())
+}
+
+/// Show, set or clear the webhook `whisper listen` posts incoming messages
+/// to, optionally only those in the conversations named in `only`.
+pub async fn handle_webhook(
+    url: Option<&str>,
+    only: &[String],
+    clear: bool,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    if let Some(url) = url {
+        let url = parse_webhook_url(url)?;
+        let mut names = Vec::new();
+        let mut conversations = Vec::new();
+        for name in only {
+            let (with, name) = resolve_conversation(&db, name)?;
+            conversations.push(with);
+            names.push(name);
+        }
+        db.set_webhook(Some(&Webhook { url: url.to_string(), only: conversations }))?;
+        println!("Webhook: {}", url);
+        if !names.is_empty() {
+            println!("Only for: {}", names.join(", "));
+        }
+        println!("Takes effect the next time 'whisper listen' starts.");
+    } else if clear {
+        db.set_webhook(None)?;
+        println!("Webhook cleared");
+    } else {
+        match db.webhook()? {
+            Some(webhook) => {
+                println!("Webhook: {}", webhook.url);
+                if !webhook.only.is_empty() {
+                    let names: Vec<String> = db
+                        .list_conversations()?
+                        .into_iter()
+                        .filter(|c| webhook.only.contains(&c.with))
+                        .map(|c| c.name)
+                        .collect();
+                    println!("Only for: {}", names.join(", "));
+                }
+            }
+            None => println!("No webhook set"),
+        }
+    }
+    Ok(())
+}
+
+/// Show the mailboxes we leave messages with, and whether we hold
+/// messages for others.
+pub async fn handle_mailbox_list(data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let mailboxes = db.mailbox_peers()?;
+    if mailboxes.is_empty() {
+        println!("No mailboxes. Add a contact who serves as one with: whisper mailbox add <alias>");
+    } else {
+        println!("Mailboxes:");
+        for peer_id in mailboxes {
+            match db.get_contact(&peer_id)? {
+                Some(contact) => println!("  {} ({})", contact.alias, short_peer_id(&peer_id)),
+                None => println!("  {}", peer_id),
+            }
+        }
+    }
+    if db.mailbox_serving()? {
+        println!("Serving as a mailbox: holding {} message(s)", db.count_mail()?);
+    } else {
+        println!("Not serving as a mailbox");
+    }
+    Ok(())
+}
+
+/// List the offline queue: what's waiting, when it's next tried, and
+/// what was given up on.
+pub async fn handle_queue_list(data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let queue = MessageQueue::new(&db).entries()?;
+    if queue.is_empty() {
+        println!("Nothing queued.");
+        return Ok(());
+    }
+
+    let now = Utc::now();
+    for entry in &queue {
+        let to = db
+            .get_contact(&entry.to)?
+            .map(|c| c.alias)
+            .unwrap_or_else(|| short_peer_id(&entry.to));
+        let state = match (entry.dead_at, entry.next_attempt_at) {
+            (Some(dead_at), _) => format!("gave up {}", dead_at.format("%Y-%m-%d %H:%M")),
+            (None, Some(at)) if at > now => format!("next try {}", at.format("%H:%M:%S")),
+            (None, _) => "waiting".to_string(),
+        };
+        println!(
+            "  {} to {} - queued {}, {} attempt(s), {}",
+            entry.id,
+            to,
+            entry.queued_at.format("%Y-%m-%d %H:%M"),
+            entry.attempts,
+            state
+        );
+    }
+    println!("Queued messages expire after {} days.", PENDING_TTL_DAYS);
+    Ok(())
+}
+
+/// Find a queued message by its ID or the start of it.
+fn find_queued(queue: &MessageQueue, query: &str) -> Result<QueueEntry> {
+    let queue = queue.entries()?;
+    let matches: Vec<&QueueEntry> = queue.iter().filter(|e| e.id.to_string().starts_with(query)).collect();
+    match matches.as_slice() {
+        [entry] => Ok((*entry).clone()),
+        [] => anyhow::bail!("No queued message '{}'", query),
+        _ => anyhow::bail!("'{}' matches more than one queued message; use more of the ID", query),
+    }
+}
+
+/// Start a queued message's attempts over, or every dead letter's.
+pub async fn handle_queue_retry(id: Option<&str>, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let queue = MessageQueue::new(&db);
+    let ids: Vec<uuid::Uuid> = match id {
+        Some(query) => vec![find_queued(&queue, query)?.id],
+        None => queue.dead_letters()?.into_iter().map(|dead| dead.id).collect(),
+    };
+    for id in &ids {
+        queue.retry(id)?;
+    }
+    println!("Retrying {} message(s) while whisper chat or listen runs.", ids.len());
+    Ok(())
+}
+
+/// Drop a queued message, marking it failed.
+pub async fn handle_queue_drop(id: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let queue = MessageQueue::new(&db);
+    let entry = find_queued(&queue, id)?;
+    queue.remove(&entry.id)?;
+    db.update_message_status(&entry.id, &MessageStatus::Failed("dropped from the queue".to_string()))?;
+    println!("Dropped {}", entry.id);
+    Ok(())
+}
+
+/// Leave messages for offline contacts with a contact.
+pub async fn handle_mailbox_add(alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+
+    if db.add_mailbox_peer(&contact.peer_id)? {
+        println!("Messages for offline contacts will be left with {}", alias);
+        println!("{} needs to run 'whisper mailbox serve' and trust you for it to work.", alias);
+    } else {
+        println!("{} is already a mailbox", alias);
+    }
+    Ok(())
+}
+
+/// Stop leaving messages with a contact. What it already holds is still
+/// delivered.
+pub async fn handle_mailbox_remove(alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+
+    if !db.remove_mailbox_peer(&contact.peer_id)? {
+        anyhow::bail!("{} is not a mailbox", alias);
+    }
+    println!("No longer leaving messages with {}", alias);
+    Ok(())
+}
+
+/// Start or stop holding messages from trusted contacts for peers who are
+/// offline. Messages already held are still delivered.
+pub async fn handle_mailbox_serve(serving: bool, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    db.set_mailbox_serving(serving)?;
+    if serving {
+        println!("Serving as a mailbox for trusted and verified contacts");
+        println!("Messages are passed on while whisper runs, e.g. with 'whisper listen'.");
+    } else {
+        println!("No longer serving as a mailbox");
+    }
+    Ok(())
+}
+
+/// Show whether chats render markdown, or turn it on or off.
+pub async fn handle_markdown(enabled: Option<bool>, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    if let Some(enabled) = enabled {
+        db.set_raw_markdown(!enabled)?;
+    }
+    if db.raw_markdown()? {
+        println!("Chats show messages as typed (press m in a chat to switch)");
+    } else {
+        println!("Chats render markdown (press m in a chat to switch)");
+    }
+    Ok(())
+}
+
+/// Show how much proof of work strangers' first messages need, or set it.
+/// Senders pay at least the default, so asking more than that turns away
+/// strangers who haven't raised theirs too.
+pub async fn handle_stamps(difficulty: Option<u8>, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    if let Some(difficulty) = difficulty {
+        db.set_stamp_difficulty(difficulty)?;
+    }
+    match db.stamp_difficulty()? {
+        0 => println!("Strangers' first messages get in without a stamp"),
+        bits if bits > DEFAULT_STAMP_DIFFICULTY => println!(
+            "Strangers' first messages need a {}-bit stamp, more than the default of {}: \
+             only strangers who ask as much of others get in",
+            bits, DEFAULT_STAMP_DIFFICULTY
+        ),
+        bits => println!("Strangers' first messages need a {}-bit stamp (about {} hashes)", bits, 1u64 << bits),
+    }
+    Ok(())
+}
+
+/// Show the trust policy, changing any parts given first.
+pub async fn handle_policy(
+    read_receipts: Option<&str>,
+    conversations: Option<&str>,
+    group_invites: Option<&str>,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let mut policy = db.trust_policy()?;
+    let parse = |requirement: &str| {
+        TrustRequirement::parse(&requirement.to_lowercase()).ok_or_else(|| {
+            anyhow::anyhow!("Unknown requirement '{}': use anyone, trusted or verified", requirement)
+        })
+    };
+    if let Some(requirement) = read_receipts {
+        policy.read_receipts = parse(requirement)?;
+    }
+    if let Some(requirement) = conversations {
+        policy.conversations = parse(requirement)?;
+    }
+    if let Some(requirement) = group_invites {
+        policy.group_invites = parse(requirement)?;
+    }
+    db.set_trust_policy(&policy)?;
+    print_trust_policy(&policy);
+    Ok(())
+}
+
+/// Print how trusted senders must be for each part of the policy.
+fn print_trust_policy(policy: &TrustPolicy) {
+    println!("Read receipts go to:     {}", policy.read_receipts.as_str());
+    println!("Conversations open for:  {}", policy.conversations.as_str());
+    println!("Group invites kept from: {}", policy.group_invites.as_str());
+    if policy.conversations != TrustRequirement::Anyone {
+        println!("Messages from anyone else wait in: whisper requests");
+    }
+    println!("Blocked peers are always refused.");
+}
+
+/// Offer to link this device to another identity, printing the code to
+/// run `whisper device link` with there.
+pub async fn handle_device_join(identity: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let keypair = load_keypair(&keypair_path(data_dir), passphrase).context("Failed to load keypair")?;
+    let identity: PeerId = identity.parse().context("Invalid peer ID")?;
+    if identity == keypair_to_peer_id(&keypair) {
+        anyhow::bail!("That's this device's own identity");
+    }
+
+    let code = DeviceLink::new(&keypair, identity)?.to_code()?;
+    db.set_linked_to(Some(&identity))?;
+    println!("On the device with identity {}, run:", short_peer_id(&identity));
+    println!("  whisper device link {}", code);
+    println!("This device picks up the device list when the two connect.");
+    Ok(())
+}
+
+/// Link the device that produced `code` to our identity, and let it know.
+/// Contacts learn of it the next time they connect.
+pub async fn handle_device_link(code: &str, name: Option<&str>, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let keypair = load_keypair(&keypair_path(data_dir), passphrase).context("Failed to load keypair")?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
+    if let Some(identity) = db.linked_to()? {
+        anyhow::bail!("This device is linked to {}; link devices from there", short_peer_id(&identity));
+    }
+
+    let link = DeviceLink::from_code(code)?;
+    if link.identity != our_peer_id {
+        anyhow::bail!("That code is for linking to {}, not this identity", short_peer_id(&link.identity));
+    }
+    let name = name.map_or_else(|| short_peer_id(&link.device), str::to_string);
+    let mut devices = db.device_list(&our_peer_id)?.map(|list| list.devices).unwrap_or_default();
+    if devices.iter().any(|device| device.name == name && device.peer_id() != link.device) {
+        anyhow::bail!("Another device is already called '{}'", name);
+    }
+    devices.retain(|device| device.peer_id() != link.device);
+    let device = link.device;
+    devices.push(LinkedDevice { name: name.clone(), link });
+
+    let list = DeviceList::sign(&keypair, devices)?;
+    db.save_device_list(&list)?;
+
+    // Sent when it next connects, so it knows it's linked
+    let (our_enc_pk, our_enc_sk) = keypair_to_encryption_keys(&keypair)?;
+    let wire = Envelope::new(our_peer_id, MessageContent::DeviceList(list)).encode_signed(&keypair)?;
+    let data = seal_for_contact(&db, (&our_enc_pk, &our_enc_sk), &device, &[], &wire)?;
+    MessageQueue::new(&db).enqueue(&uuid::Uuid::new_v4(), &device, &data)?;
+
+    println!("Linked {} ({})", name, short_peer_id(&device));
+    println!("Contacts send to it too once they next connect to a device of yours.");
+    Ok(())
+}
+
+/// Show the devices linked to our identity.
+pub async fn handle_device_list(data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let keypair = load_keypair(&keypair_path(data_dir), passphrase).context("Failed to load keypair")?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
+    let identity = db.linked_to()?.unwrap_or(our_peer_id);
+
+    let this = |peer_id: &PeerId| if *peer_id == our_peer_id { " (this device)" } else { "" };
+    println!("Identity: {}{}", identity, this(&identity));
+    match db.device_list(&identity)? {
+        Some(list) if !list.devices.is_empty() => {
+            println!("Linked devices:");
+            for device in &list.devices {
+                let peer_id = device.peer_id();
+                println!("  {} ({}){}", device.name, short_peer_id(&peer_id), this(&peer_id));
+            }
+        }
+        _ if identity != our_peer_id => println!("Waiting for the device list from {}", short_peer_id(&identity)),
+        _ => println!("No linked devices. On the new device, run: whisper device join {}", identity),
+    }
+    Ok(())
+}
+
+/// Unlink a device. Contacts stop sending to it once they have the new
+/// list.
+pub async fn handle_device_unlink(name: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let keypair = load_keypair(&keypair_path(data_dir), passphrase).context("Failed to load keypair")?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
+
+    let mut devices = db.device_list(&our_peer_id)?.map(|list| list.devices).unwrap_or_default();
+    let before = devices.len();
+    devices.retain(|device| device.name != name);
+    if devices.len() == before {
+        anyhow::bail!("No linked device called '{}'", name);
+    }
+    db.save_device_list(&DeviceList::sign(&keypair, devices)?)?;
+    println!("Unlinked {}", name);
+    Ok(())
+}
+
+/// Create a broadcast channel, printing the code subscribers need.
+pub async fn handle_channel_create(name: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let keypair = load_keypair(&keypair_path(data_dir), passphrase).context("Failed to load keypair")?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
+    if db.get_channel_by_name(name, &our_peer_id)?.is_some_and(|channel| channel.owner == our_peer_id) {
+        anyhow::bail!("You already have a channel called '{}'", name);
+    }
+
+    let channel = Channel::new(name.to_string(), our_peer_id);
+    db.add_channel(&channel)?;
+    println!("Created channel '{}'. To subscribe, run:", name);
+    println!("  whisper channel subscribe {}", channel.to_code()?);
+    Ok(())
+}
+
+/// Subscribe to a channel from its code. The owner learns of it when
+/// they next connect.
+pub async fn handle_channel_subscribe(code: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let keypair = load_keypair(&keypair_path(data_dir), passphrase).context("Failed to load keypair")?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
+
+    let channel = Channel::from_code(code)?;
+    if channel.owner == our_peer_id {
+        anyhow::bail!("'{}' is your own channel", channel.name);
+    }
+    if !db.add_channel(&channel)? {
+        println!("Already subscribed to '{}'", channel.name);
+        return Ok(());
+    }
+
+    let (our_enc_pk, our_enc_sk) = keypair_to_encryption_keys(&keypair)?;
+    let public_key = db.get_contact(&channel.owner)?.map(|contact| contact.public_key).unwrap_or_default();
+    let wire = Envelope::new(our_peer_id, MessageContent::ChannelSubscribe(channel.id)).encode_signed(&keypair)?;
+    let data = seal_for_contact(&db, (&our_enc_pk, &our_enc_sk), &channel.owner, &public_key, &wire)?;
+    MessageQueue::new(&db).enqueue(&uuid::Uuid::new_v4(), &channel.owner, &data)?;
+
+    let network = NetworkConfig::load(data_dir)?;
+    let mut node = create_node(&db, &network, keypair).await?;
+    start_listening(&db, &network, &mut node)?;
+    node.send_message(channel.owner, data);
+
+    println!("Subscribed to '{}' from {}", channel.name, short_peer_id(&channel.owner));
+    println!("Posts arrive while whisper runs, e.g. with 'whisper listen'.");
+    Ok(())
+}
+
+/// Post to one of our channels, publishing it on the channel's topic once
+/// a subscriber is online to take it.
+pub async fn handle_channel_post(name: &str, text: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let keypair = load_keypair(&keypair_path(data_dir), passphrase).context("Failed to load keypair")?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
+    let channel = db
+        .get_channel_by_name(name, &our_peer_id)?
+        .ok_or_else(|| anyhow::anyhow!("Channel '{}' not found", name))?;
+
+    let post = ChannelPost::sign(&keypair, &channel, text.to_string())?;
+    db.store_channel_post(&post)?;
+
+    let subscribers = db.channel_subscribers(&channel.id)?;
+    println!("Posted to '{}' for {} subscriber(s)", name, subscribers.len());
+    if subscribers.is_empty() {
+        return Ok(());
+    }
+
+    let network = NetworkConfig::load(data_dir)?;
+    let mut node = create_node(&db, &network, keypair.clone()).await?;
+    start_listening(&db, &network, &mut node)?;
+    let mut events = node.subscribe();
+    let node = node.spawn();
+    watch_contacts(&db, &node)?;
+    for subscriber in subscribers {
+        node.connect_peer(subscriber);
+    }
+    publish_channel_post(&node, &keypair, &post)?;
+
+    let published = tokio::time::timeout(CHANNEL_PUBLISH_TIMEOUT, async {
+        loop {
+            match events.recv().await {
+                Ok(NodeEvent::PostPublished { channel_id }) if channel_id == channel.id => return true,
+                Err(RecvError::Closed) => return false,
+                _ => {}
+            }
+        }
+    })
+    .await
+    .unwrap_or(false);
+    if published {
+        tokio::time::sleep(CHANNEL_PUBLISH_LINGER).await;
+        println!("(Published to the subscribers online.)");
+    } else {
+        println!("(No subscribers online. They're sent recent posts when they next connect while whisper runs.)");
+    }
+    node.shutdown();
+    Ok(())
+}
+
+/// List the channels we own or subscribe to.
+pub async fn handle_channel_list(data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let keypair = load_keypair(&keypair_path(data_dir), passphrase).context("Failed to load keypair")?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
+
+    let channels = db.list_channels()?;
+    if channels.is_empty() {
+        println!("No channels. Create one with: whisper channel create <name>");
+        return Ok(());
+    }
+    println!("Channels:");
+    for channel in channels {
+        if channel.owner == our_peer_id {
+            let subscribers = db.channel_subscribers(&channel.id)?.len();
+            println!("  {} (yours, {} subscriber(s))", channel.name, subscribers);
+        } else {
+            let owner = db
+                .get_contact(&channel.owner)?
+                .map_or_else(|| short_peer_id(&channel.owner), |contact| contact.alias);
+            println!("  {} (from {})", channel.name, owner);
+        }
+    }
+    Ok(())
+}
+
+/// Show the latest posts to a channel.
+pub async fn handle_channel_read(name: &str, limit: usize, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let keypair = load_keypair(&keypair_path(data_dir), passphrase).context("Failed to load keypair")?;
+    let channel = db
+        .get_channel_by_name(name, &keypair_to_peer_id(&keypair))?
+        .ok_or_else(|| anyhow::anyhow!("Channel '{}' not found", name))?;
+
+    let posts = db.channel_posts(&channel.id, limit)?;
+    if posts.is_empty() {
+        println!("No posts in '{}' yet", name);
+    }
+    for post in posts {
+        println!("{}", post_line(&channel, &post, false)?);
+    }
+    Ok(())
+}
+
+/// Show or set the presence announced to trusted and verified contacts.
+/// Running sessions pick a change up on their next announcement.
+pub async fn handle_presence(status: Option<&str>, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    match status {
+        Some(status) => {
+            let status = PresenceStatus::parse(&status.to_lowercase())
+                .ok_or_else(|| anyhow::anyhow!("Unknown status '{}': use online, away or offline", status))?;
+            db.set_own_presence(status)?;
+            println!("Presence: {}", status);
+        }
+        None => println!("Presence: {}", db.own_presence()?),
+    }
+    Ok(())
+}
+
+/// Create a new group.
+pub async fn handle_group_create(name: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    // Load our keypair to get our peer ID (we become the owner)
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let my_peer_id = keypair_to_peer_id(&keypair);
+
+    // Check if group already exists
+    if db.get_group_by_name(name)?.is_some() {
+        anyhow::bail!("Group '{}' already exists", name);
+    }
+
+    // Generate symmetric key for group
+    let symmetric_key = generate_group_key();
+
+    // Create group with us as owner
+    let group = Group::new(name.to_string(), symmetric_key, Some(my_peer_id));
+    db.create_group(&group)?;
+
+    println!("Created group: {}", name);
+    println!("Group ID: {}", group.id);
+    println!("You are the owner of this group.");
+
+    Ok(())
+}
+
+/// Invite a contact to a group.
+/// 
+/// This adds them to the group AND sends them the encrypted group key.
+pub async fn handle_group_invite(group_name: &str, alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    // Load our keypair
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+
+    // Get group
+    let group = db
+        .get_group_by_name(group_name)?
+        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+
+    // Only owners and admins change membership
+    if !group.can_manage(&keypair_to_peer_id(&keypair)) {
+        anyhow::bail!("You don't have permission to invite members to this group");
+    }
+
+    // Get contact
+    let contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+
+    // Add member to local database and tell the others
+    db.add_group_member(&group.id, &contact.peer_id)?;
+    announce_member_change(&db, &keypair, &group, contact.peer_id, MemberChange::Added)?;
+
+    // Send encrypted group key to the invited member
+    if !contact.public_key.is_empty() {
+        if let Ok(recipient_pk) = ed25519_pk_to_x25519(&contact.public_key) {
+            // Encrypt the symmetric key with the recipient's public key
+            let encrypted_key = encrypt_message(&group.symmetric_key, &recipient_pk)
+                .context("Failed to encrypt group key")?;
+            
+            // Create invite envelope
+            let invite = Envelope::new(
+                keypair_to_peer_id(&keypair),
+                MessageContent::GroupInvite(GroupInvite {
+                    group_id: group.id,
+                    name: group.name.clone(),
+                    encrypted_key,
+                }),
+            );
+            let (our_enc_pk, our_enc_sk) = keypair_to_encryption_keys(&keypair)?;
+            let invite_data = seal_for_contact(
+                &db,
+                (&our_enc_pk, &our_enc_sk),
+                &contact.peer_id,
+                &contact.public_key,
+                &invite.clone().encode_signed(&keypair)?,
+            )?;
+
+            // Queue for delivery
+            MessageQueue::new(&db).enqueue(&invite.id, &contact.peer_id, &invite_data)?;
+
+            // Try to send now
+            let network = NetworkConfig::load(data_dir)?;
+            let mut node = create_node(&db, &network, keypair).await?;
+            start_listening(&db, &network, &mut node)?;
+            node.send_message(contact.peer_id, invite_data);
+
+            println!("Invited {} to group {} (group key sent encrypted)", alias, group_name);
+        } else {
+            println!("Invited {} to group {} (no public key - key exchange needed)", alias, group_name);
+        }
+    } else {
+        println!("Invited {} to group {} (no public key - key exchange needed)", alias, group_name);
+    }
+
+    Ok(())
+}
+
+/// Open interactive group chat.
+pub async fn handle_group_chat(name: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    // Load our keypair
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
+
+    // Verify group exists
+    let group = db
+        .get_group_by_name(name)?
+        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", name))?;
+
+    if group.members.is_empty() {
+        println!("Group '{}' has no members. Invite contacts with: whisper group invite {} <alias>", name, name);
+        return Ok(());
+    }
+
+    // Load all contacts for the sidebar
+    let contacts = db.list_contacts()?;
+
+    // Create app state
+    let mut app = App::new();
+    app.set_peer_id(our_peer_id);
+    app.raw_markdown = db.raw_markdown()?;
+    for c in contacts {
+        app.add_contact(c);
+    }
+
+    app.message_requests = db.count_message_request_senders()?;
+
+    // Set mode to chat
+    app.mode = AppMode::Chat;
+
+    // Create and start the network node
+    let network = NetworkConfig::load(data_dir)?;
+    let mut node = create_node(&db, &network, keypair.clone()).await?;
+    start_listening(&db, &network, &mut node)?;
+    let mut events = node.subscribe();
+    let node = node.spawn();
+
+    // Opening the group chat counts as reading it
+    db.mark_conversation_read(&Recipient::Group(group.id))?;
+
+    // Run the group TUI with multicast to all members
+    watch_contacts(&db, &node)?;
+    let session = Session::new(node.clone(), keypair)?;
+    let db = DatabaseHandle::spawn(db)?;
+    let result = run_group_tui_with_network(&mut app, &db, &session, &mut events, &group).await;
+    node.shutdown();
+    // Storage is wrapped up even when the TUI failed, but its error comes first
+    let flushed = db.flush().await;
+    result?;
+    flushed
+}
+
+/// List all groups.
+pub async fn handle_group_list(output: OutputFormat, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let groups = db.list_groups()?;
+
+    if output == OutputFormat::Json {
+        let groups: Vec<GroupOutput> = groups
+            .into_iter()
+            .map(|g| GroupOutput {
+                id: g.id,
+                members: g.members.len(),
+                owner: g.owner.map(|o| o.to_string()),
+                name: g.name,
+                description: g.description,
+            })
+            .collect();
+        return print_json(&groups);
+    }
+
+    if groups.is_empty() {
+        println!("No groups yet. Create one with: whisper group create <name>");
+        return Ok(());
+    }
+
+    println!("Groups:");
+    for group in groups {
+        let owner_str = if group.owner.is_some() { " [owner]" } else { "" };
+        println!("  {} ({} members){}", group.name, group.members.len(), owner_str);
+        if let Some(desc) = &group.description {
+            println!("    {}", desc);
+        }
+    }
+
+    Ok(())
+}
+
+/// Kick a member from a group (owner/admin only).
+pub async fn handle_group_kick(group_name: &str, alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    // Load our keypair
+    let key_path = keypair_path(data_dir);
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let my_peer_id = keypair_to_peer_id(&keypair);
+
+    // Get group
+    let group = db
+        .get_group_by_name(group_name)?
+        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+
+    // Check permissions
+    if !group.can_manage(&my_peer_id) {
+        anyhow::bail!("You don't have permission to kick members from this group");
+    }
+
+    // Get contact
+    let contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+
+    // Can't kick the owner
+    if group.is_owner(&contact.peer_id) {
+        anyhow::bail!("Cannot kick the group owner");
+    }
+
+    // Remove member
+    if !db.remove_group_member(&group.id, &contact.peer_id)? {
+        println!("{} is not a member of group '{}'", alias, group_name);
+        return Ok(());
+    }
+    println!("Kicked {} from group '{}'", alias, group_name);
+
+    // They still hold the old key, so replace it
+    let group = db
+        .get_group(&group.id)?
+        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+    announce_member_change(&db, &keypair, &group, contact.peer_id, MemberChange::Removed)?;
+    let (epoch, queued) = rotate_group_key(&db, &keypair, &group)?;
+    println!(
+        "Rotated group key (epoch {}); queued for {} member(s), delivered when they connect",
+        epoch,
+        queued.len()
+    );
+
+    Ok(())
+}
+
+/// Leave a group, sending a signed leave notice to the other members.
+pub async fn handle_group_leave(group_name: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    // Load our keypair
+    let key_path = keypair_path(data_dir);
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let my_peer_id = keypair_to_peer_id(&keypair);
+
+    // Get group
+    let group = db
+        .get_group_by_name(group_name)?
+        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+
+    // The group needs someone to manage it
+    if group.is_owner(&my_peer_id) {
+        anyhow::bail!(
+            "You own group '{}'. Transfer ownership first: whisper group transfer {} <alias>",
+            group_name,
+            group_name
+        );
+    }
+
+    let leave = GroupLeave::new(&keypair, group.id).context("Failed to sign leave notice")?;
+    let queued = queue_sealed(&db, &keypair, group.member_peer_ids(), |_| {
+        Ok(MessageContent::GroupLeave(leave.clone()))
+    })?;
+
+    // Dropping the group discards its key, so nothing more is decrypted
+    db.delete_group(&group.id)?;
+    println!(
+        "Left group '{}'; {} member(s) will be told when they connect",
+        group_name,
+        queued.len()
+    );
+
+    Ok(())
+}
+
+/// Promote a member to admin (owner only).
+pub async fn handle_group_promote(group_name: &str, alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    use crate::message::MemberRole;
+    
+    let db = open_database(data_dir, passphrase)?;
+
+    // Load our keypair
+    let key_path = keypair_path(data_dir);
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let my_peer_id = keypair_to_peer_id(&keypair);
+
+    // Get group
+    let group = db
+        .get_group_by_name(group_name)?
+        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+
+    // Only owner can promote
+    if !group.is_owner(&my_peer_id) {
+        anyhow::bail!("Only the group owner can promote members to admin");
+    }
+
+    // Get contact
+    let contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+
+    // Check if they're a member
+    if !group.is_member(&contact.peer_id) {
+        anyhow::bail!("{} is not a member of group '{}'", alias, group_name);
+    }
+
+    // Promote
+    if db.set_member_role(&group.id, &contact.peer_id, MemberRole::Admin)? {
+        announce_member_change(&db, &keypair, &group, contact.peer_id, MemberChange::Role(MemberRole::Admin))?;
+        println!("Promoted {} to admin in group '{}'", alias, group_name);
+    } else {
+        anyhow::bail!("Failed to promote {}", alias);
+    }
+
+    Ok(())
+}
+
+/// Demote an admin to member (owner only).
+pub async fn handle_group_demote(group_name: &str, alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    use crate::message::MemberRole;
+    
+    let db = open_database(data_dir, passphrase)?;
+
+    // Load our keypair
+    let key_path = keypair_path(data_dir);
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let my_peer_id = keypair_to_peer_id(&keypair);
+
+    // Get group
+    let group = db
+        .get_group_by_name(group_name)?
+        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+
+    // Only owner can demote
+    if !group.is_owner(&my_peer_id) {
+        anyhow::bail!("Only the group owner can demote admins");
+    }
+
+    // Get contact
+    let contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+
+    // Demote
+    if db.set_member_role(&group.id, &contact.peer_id, MemberRole::Member)? {
+        announce_member_change(&db, &keypair, &group, contact.peer_id, MemberChange::Role(MemberRole::Member))?;
+        println!("Demoted {} from admin in group '{}'", alias, group_name);
+    } else {
+        anyhow::bail!("{} is not a member of group '{}'", alias, group_name);
+    }
+
+    Ok(())
+}
+
+/// Transfer group ownership (owner only).
+pub async fn handle_group_transfer(group_name: &str, alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    // Load our keypair
+    let key_path = keypair_path(data_dir);
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let my_peer_id = keypair_to_peer_id(&keypair);
+
+    // Get group
+    let group = db
+        .get_group_by_name(group_name)?
+        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+
+    // Only owner can transfer
+    if !group.is_owner(&my_peer_id) {
+        anyhow::bail!("Only the group owner can transfer ownership");
+    }
+
+    // Get contact
+    let contact = db
+        .get_contact_by_alias(alias)?
+        .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+
+    // Check if they're a member
+    if !group.is_member(&contact.peer_id) {
+        anyhow::bail!("{} is not a member of group '{}'. Invite them first.", alias, group_name);
+    }
+
+    // Transfer ownership
+    if db.transfer_group_ownership(&group.id, &contact.peer_id)? {
+        announce_member_change(&db, &keypair, &group, contact.peer_id, MemberChange::Owner)?;
+        println!("Transferred ownership of group '{}' to {}", group_name, alias);
+    } else {
+        anyhow::bail!("Failed to transfer ownership");
+    }
+
+    Ok(())
+}
+
+/// Update group settings (owner/admin only).
+pub async fn handle_group_settings(
+    group_name: &str,
+    new_name: Option<&str>,
+    description: Option<&str>,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    // Load our keypair
+    let key_path = keypair_path(data_dir);
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+    let my_peer_id = keypair_to_peer_id(&keypair);
+
+    // Get group
+    let group = db
+        .get_group_by_name(group_name)?
+        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+
+    // Check permissions
+    if !group.can_manage(&my_peer_id) {
+        anyhow::bail!("You don't have permission to modify this group's settings");
+    }
+
+    // Update settings
+    let desc_update = description.map(|d| if d.is_empty() { None } else { Some(d) });
+    
+    if db.update_group_settings(&group.id, new_name, desc_update)? {
+        db.set_group_updated_at(&group.id, Utc::now())?;
+        if let Some(n) = new_name {
+            println!("Updated group name to: {}", n);
+        }
+        if let Some(d) = description {
+            if d.is_empty() {
+                println!("Cleared group description");
+            } else {
+                println!("Updated group description: {}", d);
+            }
+        }
+    } else {
+        println!("No changes made");
+    }
+
+    Ok(())
+}
+
+/// Show group info including members and their roles.
+pub async fn handle_group_info(group_name: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    // Get group
+    let group = db
+        .get_group_by_name(group_name)?
+        .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", group_name))?;
+
+    println!("Group: {}", group.name);
+    println!("ID: {}", group.id);
+    if let Some(desc) = &group.description {
+        println!("Description: {}", desc);
+    }
+    println!("Created: {}", group.created_at);
+    
+    println!("\nMembers ({}):", group.members.len());
+    
+    // Try to resolve member names from contacts
+    for member in &group.members {
+        let is_owner = group.is_owner(&member.peer_id);
+        let alias = db.get_contact(&member.peer_id)?
+            .map(|c| c.alias)
+            .unwrap_or_else(|| member.peer_id.to_string());
+        
+        let role_str = if is_owner {
+            "owner"
+        } else {
+            match member.role {
+                crate::message::MemberRole::Admin => "admin",
+                crate::message::MemberRole::Member => "member",
+            }
+        };
+        
+        println!("  {} [{}]", alias, role_str);
+    }
+
+    Ok(())
+}
+
+/// List pending group invites.
+pub async fn handle_group_invites(data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let invites = db.list_group_invites()?;
+
+    if invites.is_empty() {
+        println!("No pending group invites.");
+        return Ok(());
+    }
+
+    println!("Pending group invites:");
+    for invite in invites {
+        let from = db.get_contact(&invite.from)?
+            .map(|c| c.alias)
+            .unwrap_or_else(|| invite.from.to_string());
+        println!("  {} (from {}, {})", invite.name, from, invite.received_at.format("%Y-%m-%d %H:%M"));
+    }
+
+    Ok(())
+}
+
+/// Find a pending invite by group name.
+fn find_group_invite(db: &Database, group_name: &str) -> Result<PendingGroupInvite> {
+    db.list_group_invites()?
+        .into_iter()
+        .find(|invite| invite.name == group_name)
+        .ok_or_else(|| anyhow::anyhow!("No pending invite for group '{}'", group_name))
+}
+
+/// Accept a pending group invite, joining the group locally.
+pub async fn handle_group_accept(group_name: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let key_path = keypair_path(data_dir);
+    if !key_path.exists() {
+        anyhow::bail!("No identity found. Run: whisper init");
+    }
+    let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+
+    let invite = find_group_invite(&db, group_name)?;
+    if db.get_group_by_name(group_name)?.is_some() {
+        anyhow::bail!("Group '{}' already exists locally", group_name);
+    }
+
+    let group_id = invite.group_id;
+    let group = invite.into_group(keypair_to_peer_id(&keypair));
+    db.create_group(&group)?;
+    db.delete_group_invite(&group_id)?;
+
+    println!("Joined group: {}", group_name);
+
+    Ok(())
+}
+
+/// Decline a pending group invite.
+pub async fn handle_group_decline(group_name: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let invite = find_group_invite(&db, group_name)?;
+    db.delete_group_invite(&invite.group_id)?;
+
+    println!("Declined invite to group: {}", group_name);
+
+    Ok(())
+}
+
+/// List contact cards waiting to be added or declined.
+pub async fn handle_contact_cards(data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let cards = db.list_contact_cards()?;
+
+    if cards.is_empty() {
+        println!("No pending contact cards.");
+        return Ok(());
+    }
+
+    println!("Pending contact cards:");
+    for card in cards {
+        let from = db.get_contact(&card.from)?
+            .map(|c| c.alias)
+            .unwrap_or_else(|| card.from.to_string());
+        println!(
+            "  {} {} (from {}, {})",
+            card.alias,
+            card.peer_id,
+            from,
+            card.received_at.format("%Y-%m-%d %H:%M")
+        );
+    }
+
+    Ok(())
+}
+
+/// Find a pending contact card by the alias it was sent under.
+fn find_contact_card(db: &Database, alias: &str) -> Result<PendingContactCard> {
+    db.list_contact_cards()?
+        .into_iter()
+        .find(|card| card.alias == alias)
+        .ok_or_else(|| anyhow::anyhow!("No pending contact card for '{}'", alias))
+}
+
+/// Add the contact a card introduces, under the card's alias unless
+/// `save_as` is given.
+pub async fn handle_contact_accept(
+    alias: &str,
+    save_as: Option<&str>,
+    data_dir: &Path,
+    passphrase: &str,
+) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let card = find_contact_card(&db, alias)?;
+    let save_as = save_as.unwrap_or(alias);
+    if db.get_contact_by_alias(save_as)?.is_some() {
+        anyhow::bail!("Contact '{}' already exists. Pick another alias with --as", save_as);
+    }
+
+    // Vouched for by whoever sent the card, not verified by us
+    db.upsert_contact(&Contact::new(card.peer_id, save_as.to_string(), card.public_key))?;
+    db.delete_contact_card(&card.peer_id)?;
+
+    println!("Added contact: {} ({})", save_as, card.peer_id);
+
+    Ok(())
+}
+
+/// Decline a pending contact card.
+pub async fn handle_contact_decline(alias: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let card = find_contact_card(&db, alias)?;
+    db.delete_contact_card(&card.peer_id)?;
+
+    println!("Declined contact card: {}", alias);
+
+    Ok(())
+}
+
+// === File Transfer Commands ===
+
+use crate::message::{FileTransfer, FileTransferComplete, FileTransferStatus};
+
+/// Name given to files piped in with `whisper send --file -`.
+const STDIN_FILENAME: &str = "stdin";
+
+/// Send a file to a contact.
+pub async fn handle_file_send(alias: &str, file_path: &Path, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let keypair = load_keypair(&keypair_path(data_dir), passphrase)?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
+
+    // Find the contact
+    let contact = db.get_contact_by_alias(alias)?
+        .with_context(|| format!("Contact '{}' not found", alias))?;
+
+    // Read the file, or raw bytes from stdin for `-`
+    let (file_data, filename) = if file_path == Path::new("-") {
+        let mut data = Vec::new();
+        io::stdin().read_to_end(&mut data).context("Failed to read from stdin")?;
+        if data.is_empty() {
+            anyhow::bail!("Nothing to send: stdin is empty");
+        }
+        (data, STDIN_FILENAME.to_string())
+    } else {
+        let data = fs::read(file_path)
+            .with_context(|| format!("Failed to read file: {}", file_path.display()))?;
+        let filename = file_path
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        (data, filename)
+    };
+
+    // Create the transfer record
+    let transfer = FileTransfer::new_outgoing(
+        our_peer_id,
+        Recipient::Direct(contact.peer_id),
+        filename.clone(),
+        &file_data,
+    );
+
+    // Save to database
+    db.insert_file_transfer(&transfer)?;
+
+    // Create chunks and save them
+    let chunks = FileTransfer::create_chunks(transfer.id, &file_data);
+    for chunk in &chunks {
+        db.insert_file_chunk(chunk)?;
+    }
+
+    println!("File transfer created:");
+    println!("  ID: {}", transfer.id);
+    println!("  File: {}", filename);
+    println!("  Size: {} bytes", file_data.len());
+    println!("  Chunks: {}", chunks.len());
+    println!();
+
+    // Try to send over network if contact has public key
+    if !contact.public_key.is_empty() {
+        // Make sure the contact's key is usable before streaming anything
+        if ed25519_pk_to_x25519(&contact.public_key).is_err() {
+            println!("Warning: Could not convert contact's public key. Chunks stored locally only.");
+            println!("Transfer queued. Use 'whisper file status {}' to check progress.", transfer.id);
+            return Ok(());
+        }
+
+        // Files go straight to the contact: nothing is kept to send later
+        let network = NetworkConfig::load(data_dir)?;
+        let mut node = create_node(&db, &network, keypair.clone()).await?;
+        start_listening(&db, &network, &mut node)?;
+        let mut events = node.subscribe();
+        let node = node.spawn();
+        watch_contacts(&db, &node)?;
+        node.connect_peer(contact.peer_id);
+
+        let sent = send_file(&db, &node, &mut events, &keypair, &contact, &transfer, &chunks).await;
+        node.shutdown();
+        if let Err(e) = sent {
+            db.update_file_transfer_status(&transfer.id, FileTransferStatus::Failed)?;
+            return Err(e.context(format!(
+                "File not sent. Try again when {} is online and running whisper",
+                alias
+            )));
+        }
+        db.update_file_transfer_status(&transfer.id, FileTransferStatus::Complete)?;
+        println!("\n  File sent to {}.", alias);
+    } else {
+        println!("Warning: Contact has no public key stored. Cannot encrypt file.");
+        println!("Use 'whisper import-contact' to add their public key.");
+        println!();
+        println!("Use 'whisper file status {}' to check progress.", transfer.id);
+    }
+
+    Ok(())
+}
+
+/// Send a file to a contact over a running node: offer it once they're
+/// connected, then stream the chunks and say it's complete once they've
+/// taken every one.
+async fn send_file(
+    db: &Database,
+    node: &NodeHandle,
+    events: &mut broadcast::Receiver<NodeEvent>,
+    keypair: &libp2p::identity::Keypair,
+    contact: &Contact,
+    transfer: &FileTransfer,
+    chunks: &[crate::message::FileChunk],
+) -> Result<()> {
+    let our_peer_id = keypair_to_peer_id(keypair);
+    let (our_enc_pk, our_enc_sk) = keypair_to_encryption_keys(keypair)?;
+    let our_keys = (&our_enc_pk, &our_enc_sk);
+    let peer = contact.peer_id;
+
+    println!("Connecting to {}...", contact.alias);
+    wait_for_transfer(events, CONNECT_TIMEOUT, |event| match event {
+        NodeEvent::PeerConnected(p) if *p == peer => Some(()),
+        _ => None,
+    })
+    .await
+    .with_context(|| format!("{} isn't online", contact.alias))?;
+
+    // Announce the file over the message protocol and keep it in history
+    let offer = Message {
+        id: uuid::Uuid::new_v4(),
+        from: our_peer_id,
+        to: Recipient::Direct(peer),
+        content: MessageContent::File(transfer.offer()),
+        timestamp: Utc::now(),
+        status: MessageStatus::Pending,
+    };
+    db.insert_message(&offer)?;
+    let wire_msg = stamp_for(db, Envelope::from_message(&offer), &peer)?.encode_signed(keypair)?;
+    node.send_message(peer, encrypt_with_session(db, our_keys, &peer, &contact.public_key, &wire_msg)?);
+    wait_for_message_sent(events, peer).await?;
+
+    // Each chunk advances the session's ratchet, so chunks are only encrypted
+    // a window ahead of the contact's acks: a transfer that breaks off leaves
+    // the contact no more than that many message keys to skip.
+    let mut pending = chunks.iter();
+    let send_chunk = |chunk: &crate::message::FileChunk| -> Result<()> {
+        let wire_msg = Envelope::new(our_peer_id, MessageContent::FileChunk(chunk.clone())).encode_signed(keypair)?;
+        let encrypted = encrypt_with_session(db, our_keys, &peer, &contact.public_key, &wire_msg)?;
+        node.send_file_chunk(peer, FileChunkRequest {
+            transfer_id: transfer.id,
+            chunk_index: chunk.chunk_index,
+            total_chunks: chunk.total_chunks,
+            data: encrypted,
+        });
+        Ok(())
+    };
+    for chunk in pending.by_ref().take(FILE_CHUNK_WINDOW) {
+        send_chunk(chunk)?;
+    }
+
+    // Progress is what the contact has acknowledged, not what's been handed to the node
+    let total = transfer.total_chunks;
+    let mut done = 0;
+    while done < total {
+        let acked = wait_for_transfer(events, FILE_CHUNK_TIMEOUT, |event| match event {
+            NodeEvent::TransferProgress { transfer_id, chunks_done, direction: TransferDirection::Outgoing, .. }
+                if *transfer_id == transfer.id =>
+            {
+                Some(Ok(*chunks_done))
+            }
+            NodeEvent::PeerDisconnected(p) if *p == peer => Some(Err(anyhow::anyhow!("{} went offline", contact.alias))),
+            _ => None,
+        })
+        .await
+        .with_context(|| format!("{} stopped taking chunks", contact.alias))??;
+        for chunk in pending.by_ref().take(acked.saturating_sub(done) as usize) {
+            send_chunk(chunk)?;
+        }
+        done = acked;
+        print!("\r  Sent chunk {}/{} ({}%)", done, total, done * 100 / total);
+        io::Write::flush(&mut io::stdout())?;
+    }
+
+    // Send completion notification
+    let complete = FileTransferComplete {
+        transfer_id: transfer.id,
+        filename: transfer.filename.clone(),
+        total_size: transfer.total_size,
+        file_checksum: transfer.file_checksum,
+    };
+    let wire_msg = Envelope::new(our_peer_id, MessageContent::FileComplete(complete)).encode_signed(keypair)?;
+    node.send_message(peer, encrypt_with_session(db, our_keys, &peer, &contact.public_key, &wire_msg)?);
+    wait_for_message_sent(events, peer).await
+}
+
+/// Wait for a message to a contact to be delivered while sending them a file.
+async fn wait_for_message_sent(events: &mut broadcast::Receiver<NodeEvent>, peer: PeerId) -> Result<()> {
+    wait_for_transfer(events, FILE_CHUNK_TIMEOUT, |event| match event {
+        NodeEvent::MessageSent { to } if *to == peer => Some(Ok(())),
+        NodeEvent::PeerDisconnected(p) if *p == peer => Some(Err(anyhow::anyhow!("The contact went offline"))),
+        _ => None,
+    })
+    .await
+    .context("The contact didn't take the message")?
+}
+
+/// Wait for the first node event `matches` picks out. Errors if none comes
+/// within `timeout`, or the node stops.
+async fn wait_for_transfer<T>(
+    events: &mut broadcast::Receiver<NodeEvent>,
+    timeout: Duration,
+    mut matches: impl FnMut(&NodeEvent) -> Option<T>,
+) -> Result<T> {
+    tokio::time::timeout(timeout, async {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if let Some(found) = matches(&event) {
+                        return Ok(found);
+                    }
+                }
+                Err(RecvError::Closed) => anyhow::bail!("The node stopped"),
+                Err(RecvError::Lagged(_)) => {}
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("Timed out"))?
+}
+
+/// List file transfers.
+pub async fn handle_file_list(data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let transfers = db.list_file_transfers(None)?;
+
+    if transfers.is_empty() {
+        println!("No file transfers.");
+        return Ok(());
+    }
+
+    println!("File transfers:");
+    println!();
+
+    for transfer in transfers {
+        let status_str = match transfer.status {
+            FileTransferStatus::Pending => "Pending",
+            FileTransferStatus::InProgress => "In Progress",
+            FileTransferStatus::Complete => "Complete",
+            FileTransferStatus::Failed => "Failed",
+            FileTransferStatus::Cancelled => "Cancelled",
+        };
+
+        let direction = if transfer.chunks_received == 0 && transfer.status == FileTransferStatus::Pending {
+            "outgoing"
+        } else {
+            "incoming"
+        };
+
+        println!("  {} [{}] ({}) - {:.1}%",
+            transfer.filename,
+            status_str,
+            direction,
+            transfer.progress()
+        );
+        println!("    ID: {}", transfer.id);
+        println!("    Size: {} bytes ({} chunks)", transfer.total_size, transfer.total_chunks);
+        println!();
+    }
+
+    Ok(())
+}
+
+/// Show status of a specific transfer.
+pub async fn handle_file_status(id_str: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let id = uuid::Uuid::parse_str(id_str)
+        .with_context(|| format!("Invalid transfer ID: {}", id_str))?;
+
+    let transfer = db.get_file_transfer(&id)?
+        .with_context(|| format!("Transfer not found: {}", id))?;
+
+    let status_str = match transfer.status {
+        FileTransferStatus::Pending => "Pending",
+        FileTransferStatus::InProgress => "In Progress",
+        FileTransferStatus::Complete => "Complete",
+        FileTransferStatus::Failed => "Failed",
+        FileTransferStatus::Cancelled => "Cancelled",
+    };
+
+    println!("File Transfer Status:");
+    println!();
+    println!("  ID: {}", transfer.id);
+    println!("  File: {}", transfer.filename);
+    println!("  Size: {} bytes", transfer.total_size);
+    println!("  Status: {}", status_str);
+    println!("  Progress: {:.1}% ({}/{} chunks)",
+        transfer.progress(),
+        transfer.chunks_received,
+        transfer.total_chunks
+    );
+    println!("  Created: {}", transfer.created_at);
+
+    if transfer.status == FileTransferStatus::Complete {
+        println!();
+        println!("Transfer complete. File can be reassembled.");
+    }
+
+    Ok(())
+}
+
+/// Cancel an in-progress transfer.
+pub async fn handle_file_cancel(id_str: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+
+    let id = uuid::Uuid::parse_str(id_str)
+        .with_context(|| format!("Invalid transfer ID: {}", id_str))?;
+
+    let transfer = db.get_file_transfer(&id)?
+        .with_context(|| format!("Transfer not found: {}", id))?;
+
+    match transfer.status {
+        FileTransferStatus::Complete => {
+            println!("Transfer already complete. Cannot cancel.");
+            return Ok(());
+        }
+        FileTransferStatus::Cancelled => {
+            println!("Transfer already cancelled.");
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // Update status to cancelled
+    db.update_file_transfer(&id, transfer.chunks_received, &FileTransferStatus::Cancelled)?;
+
+    println!("Transfer cancelled: {}", transfer.filename);
+
+    Ok(())
+}
+
+/// Resume an interrupted file transfer.
+pub async fn handle_file_resume(id_str: &str, data_dir: &Path, passphrase: &str) -> Result<()> {
+    let db = open_database(data_dir, passphrase)?;
+    let keypair = load_keypair(&keypair_path(data_dir), passphrase)?;
+    let our_peer_id = keypair_to_peer_id(&keypair);
+
+    let id = uuid::Uuid::parse_str(id_str)
+        .with_context(|| format!("Invalid transfer ID: {}", id_str))?;
+
+    let transfer = db.get_file_transfer(&id)?
+        .with_context(|| format!("Transfer not found: {}", id))?;
+
+    // Check if resumable
+    match transfer.status {
+        FileTransferStatus::Complete => {
+            println!("Transfer already complete.");
+            return Ok(());
+        }
+        FileTransferStatus::Cancelled => {
+            println!("Transfer was cancelled. Cannot resume.");
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // Get existing chunks
+    let existing_chunks = db.get_file_chunks(&id)?;
+    let existing_indices: std::collections::HashSet<u32> = existing_chunks.iter()
+        .map(|c| c.chunk_index)
+        .collect();
+
+    // Find missing chunk indices
+    let missing: Vec<u32> = (0..transfer.total_chunks)
+        .filter(|i| !existing_indices.contains(i))
+        .collect();
+
+    if missing.is_empty() {
+        println!("All chunks present. Marking as complete.");
+        db.update_file_transfer_status(&id, FileTransferStatus::Complete)?;
+        return Ok(());
+    }
+
+    // Get recipient from transfer
+    let recipient_peer_id = match &transfer.to {
+        Recipient::Direct(peer_id) => *peer_id,
+        Recipient::Group(_) => {
+            println!("Group file transfers not yet supported for resume.");
+            return Ok(());
+        }
+    };
+
+    // Find contact with matching peer_id to get their public key
+    let contacts = db.list_contacts()?;
+    let contact = contacts.iter()
+        .find(|c| c.peer_id == recipient_peer_id)
+        .with_context(|| "Recipient contact not found")?;
+
+    if contact.public_key.is_empty() {
+        println!("Contact has no public key. Cannot encrypt.");
+        return Ok(());
+    }
+
+    let (our_enc_pk, our_enc_sk) = keypair_to_encryption_keys(&keypair)?;
+    let our_keys = (&our_enc_pk, &our_enc_sk);
+
+    // Create network node
+    let network = NetworkConfig::load(data_dir)?;
+    let mut node = create_node(&db, &network, keypair.clone()).await?;
+    start_listening(&db, &network, &mut node)?;
+
+    // Resend missing chunks
+    println!("Resuming transfer: {} missing chunks of {}", missing.len(), transfer.total_chunks);
+
+    for (i, chunk_index) in missing.iter().enumerate() {
+        // Get the chunk data - we need to recreate it from the original file
+        // For now, we can only resume if we have all chunks stored locally
+        if let Ok(Some(chunk)) = db.get_file_chunk(&id, *chunk_index) {
+            let chunk_index = chunk.chunk_index;
+            let total_chunks = chunk.total_chunks;
+            let wire_msg = Envelope::new(our_peer_id, MessageContent::FileChunk(chunk)).encode_signed(&keypair)?;
+            let encrypted = encrypt_with_session(&db, our_keys, &recipient_peer_id, &contact.public_key, &wire_msg)?;
+            node.send_file_chunk(recipient_peer_id, FileChunkRequest {
+                transfer_id: id,
+                chunk_index,
+                total_chunks,
+                data: encrypted,
+            });
+
+            let progress = ((i + 1) as f32 / missing.len() as f32 * 100.0) as u32;
+            print!("\r  Resending chunk {}/{} ({}%)", i + 1, missing.len(), progress);
+            io::Write::flush(&mut io::stdout())?;
+        } else {
+            println!("\nWarning: Chunk {} not found locally. Cannot resume fully.", chunk_index);
+        }
+    }
+
+    // Update status to in progress
+    db.update_file_transfer_status(&id, FileTransferStatus::InProgress)?;
+
+    println!("\n  Resume complete. Missing chunks queued for delivery.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::decrypt_with_session;
+    use crate::crypto::SessionMessage;
+    use crate::network::{NetworkStats, WhisperNode};
+    use crate::message::MemberRole;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn init_creates_keypair() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+
+        handle_init(data_dir, "test_pass").await.unwrap();
+
+        assert!(keypair_path(data_dir).exists());
+        assert!(database_path(data_dir).exists());
+    }
+
+    #[tokio::test]
+    async fn init_fails_if_exists() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+
+        handle_init(data_dir, "test_pass").await.unwrap();
+        let result = handle_init(data_dir, "test_pass").await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn add_contact_works() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+
+        // Initialize first
+        handle_init(data_dir, "test").await.unwrap();
+
+        // Add a contact
+        let peer_id = PeerId::random();
+        handle_add_contact("alice", &peer_id.to_string(), data_dir, "test")
+            .await
+            .unwrap();
+
+        // Verify it was added
+        let db = open_database(data_dir, "test").unwrap();
+        let contact = db.get_contact_by_alias("alice").unwrap();
+        assert!(contact.is_some());
+    }
+
+    #[tokio::test]
+    async fn contacts_lists_all() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+
+        handle_init(data_dir, "test").await.unwrap();
+
+        let peer1 = PeerId::random();
+        let peer2 = PeerId::random();
+
+        handle_add_contact("alice", &peer1.to_string(), data_dir, "test")
+            .await
+            .unwrap();
+        handle_add_contact("bob", &peer2.to_string(), data_dir, "test")
+            .await
+            .unwrap();
+
+        // Verify via database
+        let db = open_database(data_dir, "test").unwrap();
+        let contacts = db.list_contacts().unwrap();
+        assert_eq!(contacts.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn status_shows_info() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+
+        handle_init(data_dir, "test").await.unwrap();
+
+        // Should not error
+        handle_status(OutputFormat::Text, data_dir, "test").await.unwrap();
+        handle_status(OutputFormat::Json, data_dir, "test").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn trust_changes_level() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+
+        handle_init(data_dir, "test").await.unwrap();
+
+        let peer = PeerId::random();
+        handle_add_contact("alice", &peer.to_string(), data_dir, "test")
+            .await
+            .unwrap();
+
+        handle_trust("alice", data_dir, "test").await.unwrap();
+
+        let db = open_database(data_dir, "test").unwrap();
+        let contact = db.get_contact_by_alias("alice").unwrap().unwrap();
+        assert!(matches!(contact.trust_level, TrustLevel::Trusted));
+    }
+
+    #[tokio::test]
+    async fn block_changes_level() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+
+        handle_init(data_dir, "test").await.unwrap();
+
+        let peer = PeerId::random();
+        handle_add_contact("alice", &peer.to_string(), data_dir, "test")
+            .await
+            .unwrap();
+
+        handle_block("alice", data_dir, "test").await.unwrap();
+
+        let db = open_database(data_dir, "test").unwrap();
+        let contact = db.get_contact_by_alias("alice").unwrap().unwrap();
+        assert!(matches!(contact.trust_level, TrustLevel::Blocked));
+    }
+
+    #[tokio::test]
+    async fn trust_changes_are_audited() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+
+        handle_init(data_dir, "test").await.unwrap();
+        let peer = PeerId::random();
+        handle_add_contact("alice", &peer.to_string(), data_dir, "test")
+            .await
+            .unwrap();
+
+        handle_trust("alice", data_dir, "test").await.unwrap();
+        // Trusting again changes nothing, so isn't recorded
+        handle_trust("alice", data_dir, "test").await.unwrap();
+        handle_block("alice", data_dir, "test").await.unwrap();
+
+        let db = open_database(data_dir, "test").unwrap();
+        let report = audit_report(&db, Some(AuditKind::TrustChanged), None, 10).unwrap();
+        assert!(report.intact);
+        let details: Vec<_> = report.events.iter().map(|e| e.detail.as_str()).collect();
+        assert_eq!(details, ["Unknown → Trusted", "Trusted → Blocked"]);
+        assert_eq!(report.events[0].alias.as_deref(), Some("alice"));
+
+        handle_audit(None, None, 10, OutputFormat::Text, data_dir, "test").await.unwrap();
+        handle_audit(None, Some("2026-01-01"), 10, OutputFormat::Json, data_dir, "test").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn contact_notes_and_tags() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let alice = PeerId::random();
+        handle_add_contact("alice", &alice.to_string(), data_dir, "test").await.unwrap();
+        handle_contact_note("alice", Some("met at FOSDEM"), false, data_dir, "test").await.unwrap();
+        handle_contact_tag("alice", &["#Work".to_string(), "oss".to_string()], false, data_dir, "test")
+            .await
+            .unwrap();
+        handle_contact_tag("alice", &["oss".to_string()], true, data_dir, "test").await.unwrap();
+        // One bad tag rejects the lot
+        assert!(handle_contact_tag("alice", &["ok".to_string(), "not ok".to_string()], false, data_dir, "test")
+            .await
+            .is_err());
+        handle_contacts(Some("work"), false, OutputFormat::Text, data_dir, "test").await.unwrap();
+
+        let db = open_database(data_dir, "test").unwrap();
+        let contact = db.get_contact(&alice).unwrap().unwrap();
+        assert_eq!(contact.note.as_deref(), Some("met at FOSDEM"));
+        assert_eq!(contact.tags, vec!["work".to_string()]);
+        drop(db);
+
+        handle_contact_note("alice", None, true, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert!(db.get_contact(&alice).unwrap().unwrap().note.is_none());
+    }
+
+    #[test]
+    fn tags_are_normalized() {
+        assert_eq!(normalize_tag(" #Work ").unwrap(), "work");
+        assert_eq!(normalize_tag("side-project_2").unwrap(), "side-project_2");
+        assert!(normalize_tag("#").is_err());
+        assert!(normalize_tag("a,b").is_err());
+        assert!(normalize_tag("two words").is_err());
+    }
+
+    #[tokio::test]
+    async fn rename_changes_alias() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let alice = PeerId::random();
+        handle_add_contact("alice", &alice.to_string(), data_dir, "test").await.unwrap();
+        handle_add_contact("bob", &PeerId::random().to_string(), data_dir, "test").await.unwrap();
+
+        handle_rename("alice", "ally", data_dir, "test").await.unwrap();
+        assert!(handle_rename("ally", "bob", data_dir, "test").await.is_err());
+        assert!(handle_rename("ally", " ", data_dir, "test").await.is_err());
+        assert!(handle_rename("alice", "al", data_dir, "test").await.is_err());
+
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.get_contact(&alice).unwrap().unwrap().alias, "ally");
+    }
+
+    #[tokio::test]
+    async fn remove_keeps_history_unless_purged() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        handle_add_contact("alice", &alice.to_string(), data_dir, "test").await.unwrap();
+        handle_add_contact("bob", &bob.to_string(), data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        db.insert_message(&Message::new_text(alice, Recipient::Direct(PeerId::random()), "hi".to_string()))
+            .unwrap();
+        db.insert_message(&Message::new_text(bob, Recipient::Direct(PeerId::random()), "hi".to_string()))
+            .unwrap();
+        drop(db);
+
+        handle_remove("alice", false, data_dir, "test").await.unwrap();
+        handle_remove("bob", true, data_dir, "test").await.unwrap();
+        assert!(handle_remove("bob", true, data_dir, "test").await.is_err());
+
+        let db = open_database(data_dir, "test").unwrap();
+        assert!(db.get_contact(&alice).unwrap().is_none());
+        assert!(db.get_contact(&bob).unwrap().is_none());
+        assert_eq!(db.get_messages_with_peer(&alice, 10).unwrap().len(), 1);
+        assert!(db.get_messages_with_peer(&bob, 10).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn webhook_is_saved_with_its_conversations() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+        let alice = PeerId::random();
+        handle_add_contact("alice", &alice.to_string(), data_dir, "test").await.unwrap();
+
+        let only = vec!["alice".to_string()];
+        handle_webhook(Some("http://127.0.0.1:8123/hook"), &only, false, data_dir, "test").await.unwrap();
+        let webhook = open_database(data_dir, "test").unwrap().webhook().unwrap().unwrap();
+        assert_eq!(webhook.url, "http://127.0.0.1:8123/hook");
+        assert_eq!(webhook.only, vec![Recipient::Direct(alice)]);
+
+        let nobody = vec!["nobody".to_string()];
+        assert!(handle_webhook(Some("http://127.0.0.1:8123/hook"), &nobody, false, data_dir, "test").await.is_err());
+        assert!(handle_webhook(Some("ftp://example.com"), &[], false, data_dir, "test").await.is_err());
+        handle_webhook(None, &[], true, data_dir, "test").await.unwrap();
+        assert!(open_database(data_dir, "test").unwrap().webhook().unwrap().is_none());
+    }
+
+    #[test]
+    fn group_messages_are_found_by_key() {
+        use crate::crypto::encrypt_for_group;
+
+        let db = Database::open_in_memory().unwrap();
+        let member = PeerId::random();
+        let mut group = Group::new("team".to_string(), vec![7u8; 32], None);
+        group.add_member(member);
+        db.create_group(&group).unwrap();
+
+        let data = encrypt_for_group(b"hello", &group.symmetric_key).unwrap();
+        let (found, plaintext) = sending_group(&db, &member, &data).unwrap();
+        assert_eq!((found.id, plaintext.as_slice()), (group.id, &b"hello"[..]));
+
+        // Not from a member, or not under the key
+        assert!(sending_group(&db, &PeerId::random(), &data).is_none());
+        assert!(sending_group(&db, &member, b"direct message").is_none());
+    }
+
+    #[tokio::test]
+    async fn retention_settings_drive_prune() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let alice = PeerId::random();
+        handle_add_contact("alice", &alice.to_string(), data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        for _ in 0..3 {
+            db.insert_message(&Message::new_text(alice, Recipient::Direct(PeerId::random()), "hi".to_string()))
+                .unwrap();
+        }
+        drop(db);
+
+        let keep_one = RetentionPolicy { max_age_days: None, max_messages: Some(1) };
+        handle_retention(Some("alice"), Some(keep_one), false, data_dir, "test").await.unwrap();
+        handle_retention(Some("alice"), None, false, data_dir, "test").await.unwrap();
+        assert!(handle_retention(Some("nobody"), None, false, data_dir, "test").await.is_err());
+
+        handle_prune(true, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.get_messages_with_peer(&alice, 10).unwrap().len(), 3);
+        drop(db);
+
+        handle_prune(false, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.get_messages_with_peer(&alice, 10).unwrap().len(), 1);
+        drop(db);
+
+        handle_retention(Some("alice"), None, true, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert!(db.retention_policy(Some(&Recipient::Direct(alice))).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn pin_and_archive_conversations() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let alice = PeerId::random();
+        handle_add_contact("alice", &alice.to_string(), data_dir, "test").await.unwrap();
+        handle_pin("alice", true, data_dir, "test").await.unwrap();
+        handle_archive("alice", true, data_dir, "test").await.unwrap();
+        handle_contacts(None, false, OutputFormat::Text, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.conversation_place(&Recipient::Direct(alice)).unwrap(), (true, true));
+        drop(db);
+
+        handle_archive("alice", false, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.conversation_place(&Recipient::Direct(alice)).unwrap(), (true, false));
+        assert!(handle_pin("nobody", true, data_dir, "test").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sending_waits_for_a_changed_key_to_be_verified() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let alice = PeerId::random();
+        handle_add_contact("alice", &alice.to_string(), data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        db.record_key_change(&alice, &[7; 32], Utc::now()).unwrap();
+        drop(db);
+
+        let err = handle_send("alice", "hi", data_dir, "test").await.unwrap_err();
+        assert!(err.to_string().contains("whisper verify alice"));
+        let db = open_database(data_dir, "test").unwrap();
+        assert!(db.get_messages_with_peer(&alice, 10).unwrap().is_empty());
+        handle_history("alice", 10, None, OutputFormat::Text, data_dir, "test").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn notify_mutes_and_unmutes() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let alice = PeerId::random();
+        handle_add_contact("alice", &alice.to_string(), data_dir, "test").await.unwrap();
+        handle_notify("alice", Some("mentions"), false, Some(8), false, Some("bell"), data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        let settings = db.notification_settings(&Recipient::Direct(alice)).unwrap();
+        assert_eq!(settings.level, NotificationLevel::Mentions);
+        assert_eq!(settings.alert, Alert::Bell);
+        assert!(settings.is_muted(Utc::now()));
+        assert!(!settings.is_muted(Utc::now() + chrono::Duration::hours(9)));
+        drop(db);
+
+        handle_notify("alice", None, false, None, true, None, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        let settings = db.notification_settings(&Recipient::Direct(alice)).unwrap();
+        assert!(!settings.is_muted(Utc::now()));
+        assert_eq!(settings.level, NotificationLevel::Mentions);
+        assert!(handle_notify("nobody", None, true, None, false, None, data_dir, "test").await.is_err());
+    }
+
+    #[test]
+    fn retention_is_described() {
+        let mut policy = RetentionPolicy::default();
+        assert_eq!(describe_retention(&policy), "keep everything");
+        policy.max_age_days = Some(90);
+        assert_eq!(describe_retention(&policy), "keep 90 days");
+        policy.max_messages = Some(10000);
+        assert_eq!(describe_retention(&policy), "keep 90 days, at most 10000 messages");
+    }
+
+    #[tokio::test]
+    async fn requests_accept_and_decline() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let (me, carol, dave) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let db = open_database(data_dir, "test").unwrap();
+        for (from, text) in [(carol, "hi"), (dave, "buy now"), (carol, "it's carol")] {
+            db.receive_message(&Message::new_text(from, Recipient::Direct(me), text.to_string())).unwrap();
+        }
+        let senders = group_requests(db.list_message_requests().unwrap());
+        assert_eq!(senders.len(), 2);
+        assert_eq!(senders[0].0, carol);
+        assert_eq!(senders[0].1.len(), 2);
+        drop(db);
+
+        handle_requests_list(OutputFormat::Text, data_dir, "test").await.unwrap();
+        assert!(handle_requests_accept("12D3KooWnobody", "x", data_dir, "test").await.is_err());
+        handle_requests_accept(&carol.to_string()[..20], "carol", data_dir, "test").await.unwrap();
+        handle_requests_decline(&dave.to_string(), data_dir, "test").await.unwrap();
+        assert!(handle_requests_decline(&dave.to_string(), data_dir, "test").await.is_err());
+
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.get_contact_by_alias("carol").unwrap().unwrap().peer_id, carol);
+        assert_eq!(db.get_messages_with_peer(&carol, 10).unwrap().len(), 2);
+        assert!(db.get_messages_with_peer(&dave, 10).unwrap().is_empty());
+        assert!(db.list_message_requests().unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_since_accepts_dates() {
+        let day = parse_since("2026-03-01").unwrap();
+        assert_eq!(day.to_rfc3339(), "2026-03-01T00:00:00+00:00");
+        let exact = parse_since("2026-03-01T12:30:00+02:00").unwrap();
+        assert_eq!(exact.to_rfc3339(), "2026-03-01T10:30:00+00:00");
+        assert!(parse_since("yesterday").is_err());
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_retried_and_dropped() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let db = open_database(data_dir, "test").unwrap();
+        let (me, alice) = (PeerId::random(), PeerId::random());
+        let msg = Message::new_text(me, Recipient::Direct(alice), "hello?".to_string());
+        db.insert_message(&msg).unwrap();
+        db.queue_pending_message(&msg.id, &alice, b"sealed").unwrap();
+        db.increment_pending_attempts(&msg.id).unwrap();
+        db.dead_letter_pending(&msg.id, Utc::now()).unwrap();
+        drop(db);
+
+        handle_queue_retry(None, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        let queue = db.list_queue().unwrap();
+        assert_eq!((queue[0].attempts, queue[0].dead_at), (0, None));
+        drop(db);
+
+        assert!(handle_queue_drop("nope", data_dir, "test").await.is_err());
+        handle_queue_drop(&msg.id.to_string()[..8], data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert!(db.list_queue().unwrap().is_empty());
+        assert!(matches!(db.get_message(&msg.id).unwrap().unwrap().status, MessageStatus::Failed(_)));
+    }
+
+    #[test]
+    fn parse_location_checks_the_range() {
+        assert_eq!(parse_location("52.52, 13.405").unwrap(), (52.52, 13.405));
+        assert_eq!(parse_location("-33.87,151.21").unwrap(), (-33.87, 151.21));
+        assert!(parse_location("91,0").is_err());
+        assert!(parse_location("0,181").is_err());
+        assert!(parse_location("52.52").is_err());
+    }
+
+    #[tokio::test]
+    async fn contact_cards_are_accepted_under_their_alias() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let db = open_database(data_dir, "test").unwrap();
+        let carol = libp2p::identity::Keypair::generate_ed25519();
+        let public_key = carol.public().try_into_ed25519().unwrap().to_bytes().to_vec();
+        let card = |peer_id| PendingContactCard {
+            peer_id,
+            public_key: public_key.clone(),
+            alias: "carol".to_string(),
+            from: PeerId::random(),
+            received_at: Utc::now(),
+        };
+        db.save_contact_card(&card(carol.public().to_peer_id())).unwrap();
+        db.upsert_contact(&Contact::new(PeerId::random(), "carol".to_string(), Vec::new())).unwrap();
+        drop(db);
+
+        // The alias is taken, so it needs another
+        assert!(handle_contact_accept("carol", None, data_dir, "test").await.is_err());
+        handle_contact_accept("carol", Some("carol2"), data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        let contact = db.get_contact_by_alias("carol2").unwrap().unwrap();
+        assert_eq!((contact.peer_id, contact.public_key), (carol.public().to_peer_id(), public_key.clone()));
+        assert!(db.list_contact_cards().unwrap().is_empty());
+
+        db.save_contact_card(&card(PeerId::random())).unwrap();
+        drop(db);
+        handle_contact_decline("carol", data_dir, "test").await.unwrap();
+        assert!(handle_contact_decline("carol", data_dir, "test").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn export_requires_known_contact() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+
+        handle_init(data_dir, "test").await.unwrap();
+        let peer = PeerId::random();
+        handle_add_contact("alice", &peer.to_string(), data_dir, "test")
+            .await
+            .unwrap();
+
+        handle_export("alice", ExportFormat::Txt, Some("2026-01-01"), data_dir, "test")
+            .await
+            .unwrap();
+        assert!(handle_export("bob", ExportFormat::Json, None, data_dir, "test").await.is_err());
+        assert!(handle_export("alice", ExportFormat::Json, Some("soon"), data_dir, "test").await.is_err());
+    }
+
+    #[test]
+    fn import_merges_by_id_and_status() {
+        let db = Database::open_in_memory().unwrap();
+        let (us, alice) = (PeerId::random(), PeerId::random());
+
+        let mut delivered = Message::new_text(us, Recipient::Direct(alice), "hello".to_string());
+        delivered.status = MessageStatus::Delivered;
+        db.insert_message(&delivered).unwrap();
+
+        // The other machine saw the read receipt and has a newer message
+        let mut read = delivered.clone();
+        read.status = MessageStatus::Read;
+        let reply = Message::new_text(alice, Recipient::Direct(us), "hi".to_string());
+        let export = ConversationExport::new(&us, "alice", &alice, &[read, reply.clone()]);
+
+        let (added, updated) = import_conversation(&db, export.clone()).unwrap();
+        assert_eq!((added, updated), (1, 1));
+        let stored = db.get_conversation(&alice, None).unwrap();
+        assert_eq!(stored.len(), 2);
+        assert!(stored.iter().any(|m| m.id == delivered.id && matches!(m.status, MessageStatus::Read)));
+        assert_eq!(db.get_contact_by_alias("alice").unwrap().unwrap().peer_id, alice);
+
+        // Importing again changes nothing
+        assert_eq!(import_conversation(&db, export).unwrap(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn import_history_reads_export_file() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let (us, alice) = (PeerId::random(), PeerId::random());
+        let msg = Message::new_text(alice, Recipient::Direct(us), "archived".to_string());
+        let export = ConversationExport::new(&us, "alice", &alice, &[msg]);
+        let path = temp.path().join("alice.json");
+        fs::write(&path, export.render(ExportFormat::Json).unwrap()).unwrap();
+
+        handle_import_history(&path, data_dir, "test").await.unwrap();
+
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.get_conversation(&alice, None).unwrap().len(), 1);
+        assert!(handle_import_history(&temp.path().join("missing.json"), data_dir, "test").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn search_finds_stored_messages() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+
+        handle_init(data_dir, "test").await.unwrap();
+
+        let db = open_database(data_dir, "test").unwrap();
+        let alice = PeerId::random();
+        let msg = Message::new_text(alice, Recipient::Direct(PeerId::random()), "meet at noon".to_string());
+        db.insert_message(&msg).unwrap();
+
+        handle_search("noon", 10, data_dir, "test").await.unwrap();
+        handle_search("midnight", 10, data_dir, "test").await.unwrap();
+
+        let results = search_results(&db, None, "noon");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].content, "meet at noon");
+        assert!(!results[0].is_ours);
+    }
+
+    #[test]
+    fn init_derives_the_database_key_with_the_configured_costs() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(crate::cli::config_path(dir.path()), "[kdf]\nmemory_kib = 8192\niterations = 1\n").unwrap();
+        init_identity(dir.path(), "test").unwrap();
+
+        let params = crate::storage::kdf_params(dir.path()).unwrap().unwrap();
+        assert_eq!((params.memory_kib, params.iterations), (8192, 1));
+        open_database(dir.path(), "test").unwrap();
+    }
+
+    #[tokio::test]
+    async fn rekeying_keeps_the_data_and_can_change_the_passphrase() {
+        let dir = TempDir::new().unwrap();
+        init_identity(dir.path(), "old").unwrap();
+        handle_add_contact("alice", &PeerId::random().to_string(), dir.path(), "old").await.unwrap();
+        let old_key = crate::storage::derive_database_key("old", dir.path()).unwrap();
+
+        handle_db_rekey(None, dir.path(), "old").await.unwrap();
+        assert_ne!(crate::storage::derive_database_key("old", dir.path()).unwrap(), old_key);
+        assert!(open_database(dir.path(), "old").unwrap().get_contact_by_alias("alice").unwrap().is_some());
+
+        handle_db_rekey(Some("new"), dir.path(), "old").await.unwrap();
+        assert!(open_database(dir.path(), "old").is_err());
+        assert!(load_keypair(&keypair_path(dir.path()), "old").is_err());
+        assert!(open_database(dir.path(), "new").unwrap().get_contact_by_alias("alice").unwrap().is_some());
+        load_keypair(&keypair_path(dir.path()), "new").unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_rekey_cut_off_after_the_database_took_its_key_is_finished_on_open() {
+        let dir = TempDir::new().unwrap();
+        init_identity(dir.path(), "old").unwrap();
+        let keypair = load_keypair(&keypair_path(dir.path()), "old").unwrap();
+
+        // Stop where handle_db_rekey would finish the rekey
+        let db = open_database(dir.path(), "old").unwrap();
+        prepare_rekey(dir.path(), &configured_kdf(dir.path()).unwrap()).unwrap();
+        save_keypair(&keypair, &staged_for_rekey(dir.path(), KEYPAIR_FILE), "new").unwrap();
+        db.rekey(&derive_rekeyed_key("new", dir.path()).unwrap()).unwrap();
+        drop(db);
+
+        // Only the new passphrase finishes it
+        finish_interrupted_rekey(dir.path(), "old");
+        assert!(rekey_pending(dir.path()));
+        finish_interrupted_rekey(dir.path(), "new");
+        assert!(!rekey_pending(dir.path()));
+        assert_eq!(load_keypair(&keypair_path(dir.path()), "new").unwrap().public(), keypair.public());
+        open_database(dir.path(), "new").unwrap();
+        assert!(open_database(dir.path(), "old").is_err());
+
+        // One that never reached the database doesn't get in the way, and
+        // the next rekey drops it
+        prepare_rekey(dir.path(), &configured_kdf(dir.path()).unwrap()).unwrap();
+        open_database(dir.path(), "new").unwrap();
+        handle_db_rekey(None, dir.path(), "new").await.unwrap();
+        assert!(!rekey_pending(dir.path()));
+        open_database(dir.path(), "new").unwrap();
+    }
+
+    #[tokio::test]
+    async fn ephemeral_sessions_replace_the_stored_ones() {
+        let dir = TempDir::new().unwrap();
+        init_identity(dir.path(), "test").unwrap();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        {
+            let db = open_database(dir.path(), "test").unwrap();
+            db.save_session(&alice, &[1]).unwrap();
+            db.save_session(&bob, &[2]).unwrap();
+        }
+
+        let db = DatabaseHandle::spawn(open_ephemeral_database(dir.path(), "test").unwrap()).unwrap();
+        db.call(move |db| db.save_session(&alice, &[3])).await.unwrap();
+        forget_replaced_sessions(&db, dir.path(), "test").await.unwrap();
+
+        let stored = open_database(dir.path(), "test").unwrap();
+        assert!(stored.get_session(&alice).unwrap().is_none());
+        assert_eq!(stored.get_session(&bob).unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn seed_phrase_restores_the_same_identity() {
+        let (original, restored) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (peer_id, _) = init_identity(original.path(), "test").unwrap();
+        let keypair = load_keypair(&keypair_path(original.path()), "test").unwrap();
+        let phrase = keypair_to_mnemonic(&keypair).unwrap();
+
+        let keypair = keypair_from_mnemonic(&phrase).unwrap();
+        let (restored_id, _) = create_identity(restored.path(), "other", keypair.clone()).unwrap();
+        assert_eq!(restored_id, peer_id);
+        assert!(create_identity(restored.path(), "other", keypair).is_err());
+    }
+
+    #[test]
+    fn safety_number_matches_on_both_sides() {
+        let alice_kp = generate_keypair();
+        let bob_kp = generate_keypair();
+        let key_bytes = |kp: &libp2p::identity::Keypair| {
+            kp.public().try_into_ed25519().unwrap().to_bytes().to_vec()
+        };
+        let bob = Contact::new(keypair_to_peer_id(&bob_kp), "bob".to_string(), key_bytes(&bob_kp));
+        let alice = Contact::new(keypair_to_peer_id(&alice_kp), "alice".to_string(), key_bytes(&alice_kp));
+
+        assert_eq!(
+            contact_safety_number(&alice_kp, &bob).unwrap(),
+            contact_safety_number(&bob_kp, &alice).unwrap()
+        );
+
+        let keyless = Contact::new(PeerId::random(), "carol".to_string(), Vec::new());
+        assert!(contact_safety_number(&alice_kp, &keyless).is_err());
+    }
+
+    #[test]
+    fn only_yes_confirms() {
+        assert!(is_yes("y\n"));
+        assert!(is_yes(" YES "));
+        assert!(!is_yes("\n"));
+        assert!(!is_yes("no"));
+    }
+
+    #[tokio::test]
+    async fn receipts_toggle_per_contact() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+
+        handle_init(data_dir, "test").await.unwrap();
+
+        let peer = PeerId::random();
+        handle_add_contact("alice", &peer.to_string(), data_dir, "test")
+            .await
+            .unwrap();
+
+        handle_receipts("alice", false, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert!(!db.read_receipts_enabled(&peer).unwrap());
+
+        assert!(handle_receipts("nobody", true, data_dir, "test").await.is_err());
+    }
+
+    #[test]
+    fn bytes_are_formatted_for_people() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KB");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3.0 MB");
+    }
+
+    #[test]
+    fn stats_split_contacts_from_other_peers() {
+        let db = Database::open_in_memory().unwrap();
+        let alice = PeerId::random();
+        db.upsert_contact(&Contact::new(alice, "alice".to_string(), Vec::new())).unwrap();
+        db.upsert_contact(&Contact::new(PeerId::random(), "quiet".to_string(), Vec::new())).unwrap();
+        let mut report = NetworkStats::default();
+        report.record_sent(alice, TrafficProtocol::Messages, 2048);
+        report.record_received(PeerId::random(), TrafficProtocol::Rendezvous, 100);
+        db.record_traffic(&report).unwrap();
+        db.queue_pending_message(&uuid::Uuid::new_v4(), &alice, &[1]).unwrap();
+
+        let stats = stats_report(&db).unwrap();
+        assert_eq!((stats.bytes_sent, stats.bytes_received), (2048, 100));
+        assert_eq!(stats.contacts.len(), 1);
+        assert_eq!(stats.contacts[0].name, "alice");
+        assert_eq!(stats.other_peers.bytes_received, 100);
+        assert_eq!(stats.protocols[0].bytes_sent, 2048);
+        assert_eq!(stats.pending_messages, 1);
+    }
+
+    #[tokio::test]
+    async fn mailboxes_are_added_and_removed() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+        handle_add_contact("carol", &PeerId::random().to_string(), data_dir, "test").await.unwrap();
+
+        handle_mailbox_add("carol", data_dir, "test").await.unwrap();
+        handle_mailbox_add("carol", data_dir, "test").await.unwrap();
+        assert!(handle_mailbox_add("nobody", data_dir, "test").await.is_err());
+        handle_mailbox_serve(true, data_dir, "test").await.unwrap();
+        handle_mailbox_list(data_dir, "test").await.unwrap();
+
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.mailbox_peers().unwrap().len(), 1);
+        assert!(db.mailbox_serving().unwrap());
+        drop(db);
+
+        handle_mailbox_remove("carol", data_dir, "test").await.unwrap();
+        assert!(handle_mailbox_remove("carol", data_dir, "test").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn devices_are_linked_and_unlinked() {
+        let (primary, laptop) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        handle_init(primary.path(), "test").await.unwrap();
+        handle_init(laptop.path(), "test").await.unwrap();
+        let identity = load_keypair(&keypair_path(primary.path()), "test").unwrap();
+        let identity = keypair_to_peer_id(&identity);
+        let device = load_keypair(&keypair_path(laptop.path()), "test").unwrap();
+
+        handle_device_join(&identity.to_string(), laptop.path(), "test").await.unwrap();
+        let code = DeviceLink::new(&device, identity).unwrap().to_code().unwrap();
+        assert!(handle_device_link(&code, None, laptop.path(), "test").await.is_err(), "not the primary");
+        handle_device_link(&code, Some("laptop"), primary.path(), "test").await.unwrap();
+        handle_device_list(primary.path(), "test").await.unwrap();
+
+        let db = open_database(primary.path(), "test").unwrap();
+        assert_eq!(db.linked_devices(&identity).unwrap(), vec![keypair_to_peer_id(&device)]);
+        assert_eq!(db.get_pending_for_peer(&keypair_to_peer_id(&device)).unwrap().len(), 1);
+        drop(db);
+
+        assert!(handle_device_unlink("phone", primary.path(), "test").await.is_err());
+        handle_device_unlink("laptop", primary.path(), "test").await.unwrap();
+        let db = open_database(primary.path(), "test").unwrap();
+        assert!(db.linked_devices(&identity).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn channels_are_created_subscribed_and_posted_to() {
+        let (owner, reader) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        handle_init(owner.path(), "test").await.unwrap();
+        handle_init(reader.path(), "test").await.unwrap();
+
+        handle_channel_create("news", owner.path(), "test").await.unwrap();
+        assert!(handle_channel_create("news", owner.path(), "test").await.is_err());
+        let db = open_database(owner.path(), "test").unwrap();
+        let channel = db.list_channels().unwrap().remove(0);
+        drop(db);
+
+        handle_channel_subscribe(&channel.to_code().unwrap(), reader.path(), "test").await.unwrap();
+        assert!(handle_channel_subscribe(&channel.to_code().unwrap(), owner.path(), "test").await.is_err());
+        assert!(handle_channel_post("news", "not mine", reader.path(), "test").await.is_err());
+
+        // Posts are stored, and go out over gossip rather than the queue
+        handle_channel_post("news", "first post", owner.path(), "test").await.unwrap();
+        handle_channel_list(owner.path(), "test").await.unwrap();
+        handle_channel_read("news", 10, owner.path(), "test").await.unwrap();
+        let db = open_database(owner.path(), "test").unwrap();
+        assert_eq!(db.channel_posts(&channel.id, 10).unwrap().len(), 1);
+        let reader_id = keypair_to_peer_id(&load_keypair(&keypair_path(reader.path()), "test").unwrap());
+        assert!(db.get_pending_for_peer(&reader_id).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn presence_is_set_and_validated() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        handle_presence(Some("Away"), data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.own_presence().unwrap(), PresenceStatus::Away);
+        drop(db);
+
+        assert!(handle_presence(Some("busy"), data_dir, "test").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn mark_read_queues_receipt_unless_disabled() {
+        let db = Database::open_in_memory().unwrap();
+        let keypair = generate_keypair();
+        let node = WhisperNode::new(keypair.clone()).await.unwrap().spawn();
+        let us = keypair_to_peer_id(&keypair);
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+
+        let from_alice = Message::new_text(alice, Recipient::Direct(us), "hi".to_string());
+        db.insert_message(&from_alice).unwrap();
+        mark_read(&db, &node, &keypair, alice, &from_alice.id, false).unwrap();
+
+        let stored = db.get_messages_with_peer(&alice, 10).unwrap();
+        assert!(matches!(stored[0].status, MessageStatus::Read));
+        let pending = db.get_pending_for_peer(&alice).unwrap();
+        assert_eq!(pending.len(), 1);
+        let receipt = Envelope::decode(&pending[0].1).unwrap();
+        assert!(matches!(parse_receipt(&receipt), Some((id, ReceiptType::Read)) if id == from_alice.id));
+
+        db.set_read_receipts(&bob, false).unwrap();
+        let from_bob = Message::new_text(bob, Recipient::Direct(us), "hey".to_string());
+        db.insert_message(&from_bob).unwrap();
+        mark_read(&db, &node, &keypair, bob, &from_bob.id, false).unwrap();
+
+        assert!(matches!(db.get_messages_with_peer(&bob, 10).unwrap()[0].status, MessageStatus::Read));
+        assert!(db.get_pending_for_peer(&bob).unwrap().is_empty());
+
+        node.shutdown();
+    }
+
+    #[tokio::test]
+    async fn trust_policy_holds_back_untrusted_contacts() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+        handle_policy(Some("verified"), Some("Trusted"), Some("trusted"), data_dir, "test").await.unwrap();
+        assert!(handle_policy(None, Some("friends"), None, data_dir, "test").await.is_err());
+
+        let keypair = load_keypair(&keypair_path(data_dir), "test").unwrap();
+        let (our_pk, our_sk) = keypair_to_encryption_keys(&keypair).unwrap();
+        let us = keypair_to_peer_id(&keypair);
+        let node = WhisperNode::new(keypair.clone()).await.unwrap().spawn();
+        let db = open_database(data_dir, "test").unwrap();
+        let mut carol = Contact::new(PeerId::random(), "carol".to_string(), vec![]);
+        db.upsert_contact(&carol).unwrap();
+
+        // An unknown contact isn't sent receipts, and their messages wait as requests
+        let earlier = Message::new_text(carol.peer_id, Recipient::Direct(us), "from before".to_string());
+        db.insert_message(&earlier).unwrap();
+        mark_read(&db, &node, &keypair, carol.peer_id, &earlier.id, false).unwrap();
+        assert!(db.get_pending_for_peer(&carol.peer_id).unwrap().is_empty());
+        let hello = Message::new_text(carol.peer_id, Recipient::Direct(us), "hi".to_string());
+        assert_eq!(db.receive_message(&hello).unwrap(), Some(Inbox::Request));
+
+        // Their group invites are dropped until they're trusted
+        let invite = GroupInvite {
+            group_id: uuid::Uuid::new_v4(),
+            name: "friends".to_string(),
+            encrypted_key: encrypt_message(&generate_group_key(), &our_pk).unwrap(),
+        };
+        assert!(receive_group_invite(&db, (&our_pk, &our_sk), carol.peer_id, &invite).unwrap().is_none());
+        carol.trust_level = TrustLevel::Trusted;
+        db.upsert_contact(&carol).unwrap();
+        assert!(receive_group_invite(&db, (&our_pk, &our_sk), carol.peer_id, &invite).unwrap().is_some());
+        drop(db);
+
+        // Accepting the request keeps the contact as it was
+        handle_requests_accept(&carol.peer_id.to_string(), "someone", data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.get_contact(&carol.peer_id).unwrap().unwrap().alias, "carol");
+        assert_eq!(db.get_messages_with_peer(&carol.peer_id, 10).unwrap().len(), 2);
+        assert!(db.list_message_requests().unwrap().is_empty());
+
+        node.shutdown();
+    }
+
+    #[tokio::test]
+    async fn chat_events_are_applied_on_the_storage_thread() {
+        let keypair = generate_keypair();
+        let node = WhisperNode::new(keypair.clone()).await.unwrap().spawn();
+        let session = Session::new(node.clone(), keypair).unwrap();
+        let db = DatabaseHandle::spawn(Database::open_in_memory().unwrap()).unwrap();
+
+        let alice = generate_keypair();
+        let alice_id = keypair_to_peer_id(&alice);
+        db.upsert_contact(Contact::new(alice_id, "alice".to_string(), Vec::new())).await.unwrap();
+        let stranger = generate_keypair();
+
+        let us = session.peer_id();
+        let receive_stamped = |from: &libp2p::identity::Keypair, payload: MessageContent, stamp: u8| {
+            let sender = keypair_to_peer_id(from);
+            let mut envelope = Envelope::new(sender, payload);
+            if stamp > 0 {
+                envelope = envelope.stamped(&us, stamp).unwrap();
+            }
+            let event = NodeEvent::MessageReceived { from: sender, data: envelope.encode_signed(from).unwrap() };
+            session.call(&db, move |db, session| Ok(direct_event(db, session, event)))
+        };
+        let receive = |from: &libp2p::identity::Keypair, payload: MessageContent| receive_stamped(from, payload, 0);
+
+        let updates = receive(&alice, MessageContent::Text("hi".to_string())).await.unwrap();
+        assert!(matches!(
+            updates.as_slice(),
+            [ChatUpdate::Message { from, text, unverified: false, .. }] if *from == alice_id && text == "hi"
+        ));
+        assert_eq!(db.get_messages_with_peer(alice_id, 10).await.unwrap().len(), 1);
+
+        let updates = receive(&alice, MessageContent::Typing).await.unwrap();
+        assert!(matches!(updates.as_slice(), [ChatUpdate::Typing(from)] if *from == alice_id));
+
+        let updates = receive(&alice, MessageContent::Presence(PresenceStatus::Away)).await.unwrap();
+        assert!(matches!(
+            updates.as_slice(),
+            [ChatUpdate::Presence { from, presence }] if *from == alice_id && presence.status == PresenceStatus::Away
+        ));
+        let stored = db.get_contact(alice_id).await.unwrap().unwrap();
+        assert_eq!(stored.current_presence(Utc::now()), Some(PresenceStatus::Away));
+        // Strangers can't set a presence
+        let updates = receive(&stranger, MessageContent::Presence(PresenceStatus::Online)).await.unwrap();
+        assert!(updates.is_empty());
+
+        // Their first message needs a stamp of the difficulty we ask for
+        db.call(|db| db.set_stamp_difficulty(8)).await.unwrap();
+        let updates = receive(&stranger, MessageContent::Text("buy now".to_string())).await.unwrap();
+        assert!(updates.is_empty());
+        let updates = receive_stamped(&stranger, MessageContent::Text("buy now".to_string()), 8).await.unwrap();
+        assert!(matches!(updates.as_slice(), [ChatUpdate::Requests { waiting: 1, .. }]));
+        // After which they're waiting in requests, and needn't
+        let updates = receive(&stranger, MessageContent::Text("really".to_string())).await.unwrap();
+        assert!(matches!(updates.as_slice(), [ChatUpdate::Requests { waiting: 1, .. }]));
+
+        // Blocked contacts are dropped, and the attempt audited
+        let mut blocked = db.get_contact(alice_id).await.unwrap().unwrap();
+        blocked.trust_level = TrustLevel::Blocked;
+        db.upsert_contact(blocked).await.unwrap();
+        let updates = receive(&alice, MessageContent::Text("let me in".to_string())).await.unwrap();
+        assert!(updates.is_empty());
+        assert_eq!(db.get_messages_with_peer(alice_id, 10).await.unwrap().len(), 1);
+        let audit = db.call(|db| db.audit_log(Some(AuditKind::BlockedPeer), None, 10)).await.unwrap();
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0].peer_id, Some(alice_id));
+
+        node.shutdown();
+    }
+
+    #[tokio::test]
+    async fn spoofed_delete_requests_are_ignored() {
+        let keypair = generate_keypair();
+        let node = WhisperNode::new(keypair.clone()).await.unwrap().spawn();
+        let session = Session::new(node.clone(), keypair).unwrap();
+        let db = DatabaseHandle::spawn(Database::open_in_memory().unwrap()).unwrap();
+
+        let alice = generate_keypair();
+        let alice_id = keypair_to_peer_id(&alice);
+        db.upsert_contact(Contact::new(alice_id, "alice".to_string(), Vec::new())).await.unwrap();
+        let msg = Message::new_text(alice_id, Recipient::Direct(session.peer_id()), "hi".to_string());
+        db.insert_message(msg.clone()).await.unwrap();
+
+        let receive = |from: PeerId, data: Vec<u8>| {
+            let event = NodeEvent::MessageReceived { from, data };
+            session.call(&db, move |db, session| Ok(direct_event(db, session, event)))
+        };
+        let request = || Envelope::new(alice_id, MessageContent::DeleteRequest(msg.id));
+        let is_deleted = || async {
+            let stored = db.call(move |db| db.get_message(&msg.id)).await.unwrap().unwrap();
+            matches!(stored.content, MessageContent::Tombstone)
+        };
+
+        // Another peer putting alice's name on an unsigned request
+        let mallory = PeerId::random();
+        assert!(receive(mallory, request().encode().unwrap()).await.unwrap().is_empty());
+        assert!(!is_deleted().await);
+        // Or alice herself, unsigned
+        assert!(receive(alice_id, request().encode().unwrap()).await.unwrap().is_empty());
+        assert!(!is_deleted().await);
+
+        // Signed by alice, it is honoured
+        let updates = receive(alice_id, request().encode_signed(&alice).unwrap()).await.unwrap();
+        assert!(matches!(updates.as_slice(), [ChatUpdate::Deleted(id)] if *id == msg.id));
+        assert!(is_deleted().await);
+
+        node.shutdown();
+    }
+
+    #[test]
+    fn keypair_path_is_correct() {
+        let dir = Path::new("/tmp/whisper");
+        assert_eq!(keypair_path(dir), PathBuf::from("/tmp/whisper/identity.key"));
+    }
+
+    #[test]
+    fn database_path_is_correct() {
+        let dir = Path::new("/tmp/whisper");
+        assert_eq!(database_path(dir), PathBuf::from("/tmp/whisper/whisper.db"));
+    }
+
+    #[test]
+    fn connect_address_accepts_multiaddr_or_ip_port() {
+        let tcp: Multiaddr = "/ip4/203.0.113.5/tcp/4001".parse().unwrap();
+        assert_eq!(parse_connect_address("/ip4/203.0.113.5/tcp/4001").unwrap(), tcp);
+        assert_eq!(parse_connect_address("203.0.113.5:4001").unwrap(), tcp);
+        assert_eq!(
+            parse_connect_address("[2001:db8::1]:4001").unwrap(),
+            "/ip6/2001:db8::1/tcp/4001".parse::<Multiaddr>().unwrap()
+        );
+        assert!(parse_connect_address("example.com").is_err());
+
+        let onion = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd";
+        assert_eq!(
+            parse_connect_address(&format!("{}.onion:4001", onion)).unwrap(),
+            format!("/onion3/{}:4001", onion).parse::<Multiaddr>().unwrap()
+        );
+        assert!(parse_connect_address("short.onion:4001").is_err());
+    }
+
+    #[tokio::test]
+    async fn websocket_port_set_and_clear() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        assert!(handle_websocket(Some(0), false, data_dir, "test").await.is_err());
+        handle_websocket(Some(8080), false, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.websocket_port().unwrap(), Some(8080));
+        drop(db);
+
+        handle_websocket(None, true, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert!(db.websocket_port().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn username_set_and_clear() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        assert!(handle_username(Some("@a b"), false, data_dir, "test").await.is_err());
+        handle_username(Some("@Alice"), false, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.username().unwrap().as_deref(), Some("alice"));
+        drop(db);
 
-    // Find contact with matching peer_id to get their public key
-    let contacts = db.list_contacts()?;
-    let contact = contacts.iter()
-        .find(|c| c.peer_id == recipient_peer_id)
-        .with_context(|| "Recipient contact not found")?;
+        handle_username(None, true, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert!(db.username().unwrap().is_none());
+    }
 
-    if contact.public_key.is_empty() {
-        println!("Contact has no public key. Cannot encrypt.");
-        return Ok(());
+    #[tokio::test]
+    async fn invites_are_kept_and_checked_before_accepting() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        handle_invite_create(Some("bob"), 2, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        let invites = db.list_invites().unwrap();
+        assert_eq!(invites.len(), 1);
+        assert_eq!(invites[0].alias.as_deref(), Some("bob"));
+        assert_eq!(invites[0].expires_at - invites[0].created_at, chrono::Duration::hours(2));
+        drop(db);
+
+        // Our own invite, and someone's expired one, are no good
+        let keypair = load_keypair(&keypair_path(data_dir), "test").unwrap();
+        let (_, own) = create_invite(&keypair, None, None, Utc::now() + chrono::Duration::hours(1)).unwrap();
+        let err = handle_invite_accept(&own, Some("me"), data_dir, "test").await.unwrap_err();
+        assert!(err.to_string().contains("own invite"));
+        let carol = generate_keypair();
+        let (_, expired) = create_invite(&carol, None, None, Utc::now() - chrono::Duration::hours(1)).unwrap();
+        let err = handle_invite_accept(&expired, Some("carol"), data_dir, "test").await.unwrap_err();
+        assert!(err.to_string().contains("expired"));
+
+        // Without a name to go by, we need an alias
+        let (_, unnamed) = create_invite(&carol, None, None, Utc::now() + chrono::Duration::hours(1)).unwrap();
+        let err = handle_invite_accept(&unnamed, None, data_dir, "test").await.unwrap_err();
+        assert!(err.to_string().contains("--alias"));
+        let db = open_database(data_dir, "test").unwrap();
+        assert!(db.list_contacts().unwrap().is_empty());
     }
 
-    let recipient_pk = ed25519_pk_to_x25519(&contact.public_key)?;
+    #[tokio::test]
+    async fn proxy_and_contact_addresses() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
 
-    // Create network node
-    let mut node = WhisperNode::new(keypair).await.context("Failed to create network node")?;
-    node.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+        assert!(handle_proxy(Some("localhost"), false, data_dir, "test").await.is_err());
+        handle_proxy(Some(DEFAULT_TOR_PROXY), false, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.socks_proxy().unwrap(), Some(DEFAULT_TOR_PROXY.parse().unwrap()));
+        drop(db);
 
-    // Resend missing chunks
-    println!("Resuming transfer: {} missing chunks of {}", missing.len(), transfer.total_chunks);
+        let alice = PeerId::random();
+        handle_add_contact("alice", &alice.to_string(), data_dir, "test").await.unwrap();
+        let onion = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd";
+        handle_contact_address("alice", &format!("{}.onion:4001", onion), data_dir, "test").await.unwrap();
+        let someone_else = format!("/ip4/203.0.113.5/tcp/4001/p2p/{}", PeerId::random());
+        assert!(handle_contact_address("alice", &someone_else, data_dir, "test").await.is_err());
+        assert!(handle_contact_address("bob", "203.0.113.5:4001", data_dir, "test").await.is_err());
 
-    for (i, chunk_index) in missing.iter().enumerate() {
-        // Get the chunk data - we need to recreate it from the original file
-        // For now, we can only resume if we have all chunks stored locally
-        if let Ok(Some(chunk)) = db.get_file_chunk(&id, *chunk_index) {
-            let chunk_data = bincode::serialize(&chunk)?;
-            let mut wire_msg = FILE_CHUNK_PREFIX.to_vec();
-            wire_msg.extend_from_slice(&chunk_data);
-            let encrypted = encrypt_message(&wire_msg, &recipient_pk)?;
-            node.send_message(recipient_peer_id, encrypted);
+        let db = open_database(data_dir, "test").unwrap();
+        let addresses = db.peer_addresses(&alice).unwrap();
+        assert_eq!(addresses.len(), 1);
+        assert!(is_onion_address(&addresses[0].address));
+        drop(db);
 
-            let progress = ((i + 1) as f32 / missing.len() as f32 * 100.0) as u32;
-            print!("\r  Resending chunk {}/{} ({}%)", i + 1, missing.len(), progress);
-            io::Write::flush(&mut io::stdout())?;
-        } else {
-            println!("\nWarning: Chunk {} not found locally. Cannot resume fully.", chunk_index);
-        }
+        handle_proxy(None, true, data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        assert!(db.socks_proxy().unwrap().is_none());
     }
 
-    // Update status to in progress
-    db.update_file_transfer_status(&id, FileTransferStatus::InProgress)?;
+    #[tokio::test]
+    async fn connect_remembers_the_address() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
 
-    println!("\n  Resume complete. Missing chunks queued for delivery.");
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let bob_id = bob.peer_id();
+        let mut bob_events = bob.subscribe();
+        let bob = bob.spawn();
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                break addr;
+            }
+        };
 
-    Ok(())
-}
+        let with_peer = bob_addr.clone().with(Protocol::P2p(bob_id));
+        handle_connect(&with_peer.to_string(), OutputFormat::Json, data_dir, "test").await.unwrap();
+        bob.shutdown();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+        let db = open_database(data_dir, "test").unwrap();
+        let known = db.peer_addresses(&bob_id).unwrap();
+        assert_eq!(known.len(), 1);
+        assert_eq!(known[0].address, bob_addr);
+        assert!(known[0].last_success.is_some());
+    }
 
     #[tokio::test]
-    async fn init_creates_keypair() {
+    async fn send_to_unknown_contact_fails() {
         let temp = TempDir::new().unwrap();
         let data_dir = temp.path();
 
-        handle_init(data_dir, "test_pass").await.unwrap();
+        handle_init(data_dir, "test").await.unwrap();
 
-        assert!(keypair_path(data_dir).exists());
-        assert!(database_path(data_dir).exists());
+        // Try to send to non-existent contact
+        let result = handle_send("nobody", "hello", data_dir, "test").await;
+        assert!(result.is_err());
     }
 
     #[tokio::test]
-    async fn init_fails_if_exists() {
+    async fn export_key_works() {
         let temp = TempDir::new().unwrap();
         let data_dir = temp.path();
 
-        handle_init(data_dir, "test_pass").await.unwrap();
-        let result = handle_init(data_dir, "test_pass").await;
+        handle_init(data_dir, "test").await.unwrap();
+
+        // Should not error
+        handle_export_key(false, data_dir, "test").await.unwrap();
+        handle_export_key(true, data_dir, "test").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn export_key_fails_without_identity() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
 
+        let result = handle_export_key(false, data_dir, "test").await;
         assert!(result.is_err());
     }
 
     #[tokio::test]
-    async fn add_contact_works() {
+    async fn group_create_works() {
         let temp = TempDir::new().unwrap();
         let data_dir = temp.path();
 
-        // Initialize first
         handle_init(data_dir, "test").await.unwrap();
+        handle_group_create("test-group", data_dir, "test").await.unwrap();
 
-        // Add a contact
-        let peer_id = PeerId::random();
-        handle_add_contact("alice", &peer_id.to_string(), data_dir, "test")
-            .await
-            .unwrap();
-
-        // Verify it was added
         let db = open_database(data_dir, "test").unwrap();
-        let contact = db.get_contact_by_alias("alice").unwrap();
-        assert!(contact.is_some());
+        let group = db.get_group_by_name("test-group").unwrap();
+        assert!(group.is_some());
     }
 
     #[tokio::test]
-    async fn contacts_lists_all() {
+    async fn received_group_invite_can_be_accepted() {
         let temp = TempDir::new().unwrap();
         let data_dir = temp.path();
-
         handle_init(data_dir, "test").await.unwrap();
 
-        let peer1 = PeerId::random();
-        let peer2 = PeerId::random();
+        let keypair = load_keypair(&keypair_path(data_dir), "test").unwrap();
+        let (our_pk, our_sk) = keypair_to_encryption_keys(&keypair).unwrap();
+        let inviter = PeerId::random();
+        let group_key = generate_group_key();
+        let invite = GroupInvite {
+            group_id: uuid::Uuid::new_v4(),
+            name: "friends".to_string(),
+            encrypted_key: encrypt_message(&group_key, &our_pk).unwrap(),
+        };
 
-        handle_add_contact("alice", &peer1.to_string(), data_dir, "test")
-            .await
-            .unwrap();
-        handle_add_contact("bob", &peer2.to_string(), data_dir, "test")
-            .await
-            .unwrap();
+        {
+            let db = open_database(data_dir, "test").unwrap();
+            let pending = receive_group_invite(&db, (&our_pk, &our_sk), inviter, &invite)
+                .unwrap()
+                .unwrap();
+            assert_eq!(pending.symmetric_key, *group_key);
+        }
+
+        handle_group_accept("friends", data_dir, "test").await.unwrap();
 
-        // Verify via database
         let db = open_database(data_dir, "test").unwrap();
-        let contacts = db.list_contacts().unwrap();
-        assert_eq!(contacts.len(), 2);
+        let group = db.get_group(&invite.group_id).unwrap().unwrap();
+        assert_eq!(group.symmetric_key, group_key);
+        assert!(group.is_owner(&inviter));
+        assert!(group.is_member(&keypair_to_peer_id(&keypair)));
+        assert!(db.list_group_invites().unwrap().is_empty());
+
+        // Re-sent invites for a group we're in are ignored
+        assert!(receive_group_invite(&db, (&our_pk, &our_sk), inviter, &invite).unwrap().is_none());
     }
 
     #[tokio::test]
-    async fn status_shows_info() {
+    async fn group_invite_decline_removes_it() {
         let temp = TempDir::new().unwrap();
         let data_dir = temp.path();
-
         handle_init(data_dir, "test").await.unwrap();
 
-        // Should not error
-        handle_status(data_dir, "test").await.unwrap();
+        let keypair = load_keypair(&keypair_path(data_dir), "test").unwrap();
+        let (our_pk, our_sk) = keypair_to_encryption_keys(&keypair).unwrap();
+        let invite = GroupInvite {
+            group_id: uuid::Uuid::new_v4(),
+            name: "spam".to_string(),
+            encrypted_key: encrypt_message(&generate_group_key(), &our_pk).unwrap(),
+        };
+        {
+            let db = open_database(data_dir, "test").unwrap();
+            receive_group_invite(&db, (&our_pk, &our_sk), PeerId::random(), &invite).unwrap();
+        }
+
+        handle_group_decline("spam", data_dir, "test").await.unwrap();
+        assert!(handle_group_accept("spam", data_dir, "test").await.is_err());
+
+        let db = open_database(data_dir, "test").unwrap();
+        assert!(db.get_group(&invite.group_id).unwrap().is_none());
+    }
+
+    /// A contact with a real identity key, so group keys can be sealed for them.
+    fn keyed_contact(db: &Database, alias: &str) -> (libp2p::identity::Keypair, PeerId) {
+        let keypair = generate_keypair();
+        let peer_id = keypair_to_peer_id(&keypair);
+        let public_key = keypair.public().try_into_ed25519().unwrap().to_bytes().to_vec();
+        db.upsert_contact(&Contact::new(peer_id, alias.to_string(), public_key)).unwrap();
+        (keypair, peer_id)
     }
 
     #[tokio::test]
-    async fn trust_changes_level() {
+    async fn group_kick_rotates_key_for_remaining_members() {
         let temp = TempDir::new().unwrap();
         let data_dir = temp.path();
-
         handle_init(data_dir, "test").await.unwrap();
+        handle_group_create("team", data_dir, "test").await.unwrap();
 
-        let peer = PeerId::random();
-        handle_add_contact("alice", &peer.to_string(), data_dir, "test")
-            .await
-            .unwrap();
+        let (alice, bob, old_key) = {
+            let db = open_database(data_dir, "test").unwrap();
+            let (_, alice) = keyed_contact(&db, "alice");
+            let (_, bob) = keyed_contact(&db, "bob");
+            let group = db.get_group_by_name("team").unwrap().unwrap();
+            db.add_group_member(&group.id, &alice).unwrap();
+            db.add_group_member(&group.id, &bob).unwrap();
+            (alice, bob, group.symmetric_key)
+        };
 
-        handle_trust("alice", data_dir, "test").await.unwrap();
+        handle_group_kick("team", "bob", data_dir, "test").await.unwrap();
 
         let db = open_database(data_dir, "test").unwrap();
-        let contact = db.get_contact_by_alias("alice").unwrap().unwrap();
-        assert!(matches!(contact.trust_level, TrustLevel::Trusted));
+        let group = db.get_group_by_name("team").unwrap().unwrap();
+        assert_ne!(group.symmetric_key, old_key);
+        assert_eq!(db.group_key_epoch(&group.id).unwrap(), 1);
+        // Alice hears about the removal and gets the new key; Bob only
+        // hears he was removed
+        assert_eq!(db.get_pending_for_peer(&alice).unwrap().len(), 2);
+        assert_eq!(db.get_pending_for_peer(&bob).unwrap().len(), 1);
     }
 
     #[tokio::test]
-    async fn block_changes_level() {
+    async fn group_leave_notifies_members_and_drops_group() {
         let temp = TempDir::new().unwrap();
         let data_dir = temp.path();
-
         handle_init(data_dir, "test").await.unwrap();
+        handle_group_create("mine", data_dir, "test").await.unwrap();
+
+        let (owner, other) = {
+            let db = open_database(data_dir, "test").unwrap();
+            let keypair = load_keypair(&keypair_path(data_dir), "test").unwrap();
+            let (_, owner) = keyed_contact(&db, "alice");
+            let (_, other) = keyed_contact(&db, "bob");
+            let mut group = Group::new("theirs".to_string(), generate_group_key(), Some(owner));
+            group.add_member(owner);
+            group.add_member(other);
+            group.add_member(keypair_to_peer_id(&keypair));
+            db.create_group(&group).unwrap();
+            (owner, other)
+        };
 
-        let peer = PeerId::random();
-        handle_add_contact("alice", &peer.to_string(), data_dir, "test")
-            .await
-            .unwrap();
+        // Owners have to hand the group over first
+        assert!(handle_group_leave("mine", data_dir, "test").await.is_err());
 
-        handle_block("alice", data_dir, "test").await.unwrap();
+        handle_group_leave("theirs", data_dir, "test").await.unwrap();
 
         let db = open_database(data_dir, "test").unwrap();
-        let contact = db.get_contact_by_alias("alice").unwrap().unwrap();
-        assert!(matches!(contact.trust_level, TrustLevel::Blocked));
+        assert!(db.get_group_by_name("theirs").unwrap().is_none());
+        assert_eq!(db.get_pending_for_peer(&owner).unwrap().len(), 1);
+        assert_eq!(db.get_pending_for_peer(&other).unwrap().len(), 1);
     }
 
     #[test]
-    fn keypair_path_is_correct() {
-        let dir = Path::new("/tmp/whisper");
-        assert_eq!(keypair_path(dir), PathBuf::from("/tmp/whisper/identity.key"));
+    fn signed_group_leave_removes_member_and_rotates_key() {
+        let db = Database::open_in_memory().unwrap();
+        let our_keypair = generate_keypair();
+        let us = keypair_to_peer_id(&our_keypair);
+        let (bob_keypair, bob) = keyed_contact(&db, "bob");
+        let (_, carol) = keyed_contact(&db, "carol");
+
+        let mut group = Group::new("team".to_string(), generate_group_key(), Some(us));
+        group.add_member(bob);
+        group.add_member(carol);
+        db.create_group(&group).unwrap();
+
+        // A notice signed by someone else is ignored
+        let forged = GroupLeave::new(&generate_keypair(), group.id).unwrap();
+        assert!(!receive_group_leave(&db, &our_keypair, bob, &forged).unwrap());
+
+        let leave = GroupLeave::new(&bob_keypair, group.id).unwrap();
+        assert!(receive_group_leave(&db, &our_keypair, bob, &leave).unwrap());
+
+        let stored = db.get_group(&group.id).unwrap().unwrap();
+        assert!(!stored.is_member(&bob));
+        assert!(db.group_departure(&group.id, &bob).unwrap().is_some());
+
+        // As owner we rotated the key for the members who remain
+        assert_eq!(db.group_key_epoch(&group.id).unwrap(), 1);
+        assert_eq!(db.get_pending_for_peer(&carol).unwrap().len(), 1);
+        assert!(db.get_pending_for_peer(&bob).unwrap().is_empty());
+
+        // Replays do nothing once they're gone
+        assert!(!receive_group_leave(&db, &our_keypair, bob, &leave).unwrap());
     }
 
     #[test]
-    fn database_path_is_correct() {
-        let dir = Path::new("/tmp/whisper");
-        assert_eq!(database_path(dir), PathBuf::from("/tmp/whisper/whisper.db"));
+    fn delete_requests_only_honoured_from_the_sender() {
+        let db = Database::open_in_memory().unwrap();
+        let (us, bob) = (PeerId::random(), PeerId::random());
+        let msg = Message::new_text(bob, Recipient::Direct(us), "oops".to_string());
+        db.insert_message(&msg).unwrap();
+
+        let signed = Authenticity::Verified;
+        assert!(!receive_delete_request(&db, PeerId::random(), signed, &msg.id).unwrap());
+        assert!(!receive_delete_request(&db, bob, Authenticity::Unverified, &msg.id).unwrap());
+        assert!(receive_delete_request(&db, bob, signed, &msg.id).unwrap());
+        let stored = db.get_message(&msg.id).unwrap().unwrap();
+        assert!(matches!(stored.content, MessageContent::Tombstone));
+
+        // Already deleted, or never seen
+        assert!(!receive_delete_request(&db, bob, signed, &msg.id).unwrap());
+        assert!(!receive_delete_request(&db, bob, signed, &uuid::Uuid::new_v4()).unwrap());
     }
 
     #[tokio::test]
-    async fn send_to_unknown_contact_fails() {
+    async fn delete_for_everyone_queues_request() {
         let temp = TempDir::new().unwrap();
         let data_dir = temp.path();
-
         handle_init(data_dir, "test").await.unwrap();
+        let us = keypair_to_peer_id(&load_keypair(&keypair_path(data_dir), "test").unwrap());
+
+        let (ours, theirs, bob) = {
+            let db = open_database(data_dir, "test").unwrap();
+            let (_, bob) = keyed_contact(&db, "bob");
+            let ours = Message::new_text(us, Recipient::Direct(bob), "hello".to_string());
+            let theirs = Message::new_text(bob, Recipient::Direct(us), "hi".to_string());
+            db.insert_message(&ours).unwrap();
+            db.insert_message(&theirs).unwrap();
+            (ours, theirs, bob)
+        };
 
-        // Try to send to non-existent contact
-        let result = handle_send("nobody", "hello", data_dir, "test").await;
-        assert!(result.is_err());
-    }
+        handle_delete(&ours.id.to_string()[..8], false, data_dir, "test").await.unwrap();
+        // Someone else's message can only be deleted locally
+        assert!(handle_delete(&theirs.id.to_string(), false, data_dir, "test").await.is_err());
+        handle_delete(&theirs.id.to_string(), true, data_dir, "test").await.unwrap();
 
-    #[tokio::test]
-    async fn export_key_works() {
-        let temp = TempDir::new().unwrap();
-        let data_dir = temp.path();
+        let db = open_database(data_dir, "test").unwrap();
+        let stored = db.get_message(&ours.id).unwrap().unwrap();
+        assert!(matches!(stored.content, MessageContent::Tombstone));
+        assert_eq!(db.get_pending_for_peer(&bob).unwrap().len(), 1);
+        assert!(db.get_message(&theirs.id).unwrap().is_none());
+        assert!(handle_delete("zzzz", true, data_dir, "test").await.is_err());
+    }
 
-        handle_init(data_dir, "test").await.unwrap();
+    #[test]
+    fn group_sync_takes_newer_metadata_from_managers() {
+        let db = Database::open_in_memory().unwrap();
+        let our_keypair = generate_keypair();
+        let us = keypair_to_peer_id(&our_keypair);
+        let (our_pk, our_sk) = keypair_to_encryption_keys(&our_keypair).unwrap();
+        let (owner_keypair, owner) = keyed_contact(&db, "alice");
+        let (member_keypair, member) = keyed_contact(&db, "bob");
+        let stale = PeerId::random();
+        let newcomer = PeerId::random();
+
+        let mut ours = Group::new("team".to_string(), generate_group_key(), Some(owner));
+        ours.add_member(owner);
+        ours.add_member(member);
+        ours.add_member(us);
+        ours.add_member(stale);
+        db.create_group(&ours).unwrap();
+
+        // The owner has since renamed the group, swapped a member and
+        // rotated the key
+        let mut theirs = ours.clone();
+        theirs.name = "crew".to_string();
+        theirs.remove_member(&stale);
+        theirs.add_member_with_role(newcomer, MemberRole::Admin);
+        let new_key = generate_group_key();
+        let encrypted_key = encrypt_message(&new_key, &our_pk).unwrap();
+        let metadata = GroupMetadata::new(&theirs, 1, Utc::now(), Some(encrypted_key));
+
+        // Plain members can't push their view
+        let from_member = GroupSync::sign(&member_keypair, metadata.clone()).unwrap();
+        assert!(!receive_group_sync(&db, us, (&our_pk, &our_sk), member, &from_member).unwrap());
+
+        let sync = GroupSync::sign(&owner_keypair, metadata.clone()).unwrap();
+        assert!(receive_group_sync(&db, us, (&our_pk, &our_sk), owner, &sync).unwrap());
+        let stored = db.get_group(&ours.id).unwrap().unwrap();
+        assert_eq!(stored.name, "crew");
+        assert!(!stored.is_member(&stale));
+        assert!(stored.is_admin(&newcomer));
+        assert_eq!(stored.symmetric_key, new_key);
+        assert_eq!(db.group_key_epoch(&ours.id).unwrap(), 1);
+
+        // The same or older metadata is ignored
+        assert!(!receive_group_sync(&db, us, (&our_pk, &our_sk), owner, &sync).unwrap());
+        let mut older = metadata;
+        older.updated_at -= chrono::Duration::hours(1);
+        older.name = "old name".to_string();
+        let older = GroupSync::sign(&owner_keypair, older).unwrap();
+        assert!(!receive_group_sync(&db, us, (&our_pk, &our_sk), owner, &older).unwrap());
+        assert_eq!(db.get_group(&ours.id).unwrap().unwrap().name, "crew");
+    }
 
-        // Should not error
-        handle_export_key(data_dir, "test").await.unwrap();
+    #[test]
+    fn group_sync_queued_for_members_of_groups_we_manage() {
+        let db = Database::open_in_memory().unwrap();
+        let keypair = generate_keypair();
+        let (_, member) = keyed_contact(&db, "alice");
+        let (_, outsider) = keyed_contact(&db, "bob");
+
+        let mut group = Group::new("team".to_string(), generate_group_key(), Some(keypair_to_peer_id(&keypair)));
+        group.add_member(member);
+        db.create_group(&group).unwrap();
+
+        assert_eq!(queue_group_sync(&db, &keypair, &member).unwrap(), 1);
+        assert_eq!(queue_group_sync(&db, &keypair, &outsider).unwrap(), 0);
+        assert_eq!(db.get_pending_for_peer(&member).unwrap().len(), 1);
     }
 
     #[tokio::test]
-    async fn export_key_fails_without_identity() {
+    async fn group_invite_requires_manager() {
         let temp = TempDir::new().unwrap();
         let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
 
-        let result = handle_export_key(data_dir, "test").await;
-        assert!(result.is_err());
+        let db = open_database(data_dir, "test").unwrap();
+        let keypair = load_keypair(&keypair_path(data_dir), "test").unwrap();
+        let mut group = Group::new("theirs".to_string(), generate_group_key(), Some(PeerId::random()));
+        group.add_member(keypair_to_peer_id(&keypair));
+        db.create_group(&group).unwrap();
+        keyed_contact(&db, "alice");
+        drop(db);
+
+        assert!(handle_group_invite("theirs", "alice", data_dir, "test").await.is_err());
+    }
+
+    #[test]
+    fn group_member_updates_respect_roles() {
+        let db = Database::open_in_memory().unwrap();
+        let us = PeerId::random();
+        let owner = PeerId::random();
+        let admin = PeerId::random();
+        let member = PeerId::random();
+        let newcomer = PeerId::random();
+
+        let mut group = Group::new("team".to_string(), generate_group_key(), Some(owner));
+        group.add_member(owner);
+        group.add_member_with_role(admin, MemberRole::Admin);
+        group.add_member(member);
+        group.add_member(us);
+        db.create_group(&group).unwrap();
+
+        let update = |peer_id, change| GroupMemberUpdate { group_id: group.id, peer_id, change };
+
+        // Plain members can't add anyone; admins can
+        assert!(!receive_group_member_update(&db, us, member, &update(newcomer, MemberChange::Added)).unwrap());
+        assert!(receive_group_member_update(&db, us, admin, &update(newcomer, MemberChange::Added)).unwrap());
+
+        // Only the owner changes roles, and nobody removes the owner
+        let promote = update(member, MemberChange::Role(MemberRole::Admin));
+        assert!(!receive_group_member_update(&db, us, admin, &promote).unwrap());
+        assert!(receive_group_member_update(&db, us, owner, &promote).unwrap());
+        assert!(!receive_group_member_update(&db, us, admin, &update(owner, MemberChange::Removed)).unwrap());
+
+        assert!(receive_group_member_update(&db, us, admin, &update(newcomer, MemberChange::Removed)).unwrap());
+        let stored = db.get_group(&group.id).unwrap().unwrap();
+        assert!(!stored.is_member(&newcomer));
+        assert!(stored.is_admin(&member));
+
+        // Being removed ourselves drops the group
+        assert!(receive_group_member_update(&db, us, owner, &update(us, MemberChange::Removed)).unwrap());
+        assert!(db.get_group(&group.id).unwrap().is_none());
     }
 
     #[tokio::test]
-    async fn group_create_works() {
+    async fn group_key_update_only_accepted_from_managers() {
         let temp = TempDir::new().unwrap();
         let data_dir = temp.path();
-
         handle_init(data_dir, "test").await.unwrap();
-        handle_group_create("test-group", data_dir, "test").await.unwrap();
 
+        let keypair = load_keypair(&keypair_path(data_dir), "test").unwrap();
+        let (our_pk, our_sk) = keypair_to_encryption_keys(&keypair).unwrap();
         let db = open_database(data_dir, "test").unwrap();
-        let group = db.get_group_by_name("test-group").unwrap();
-        assert!(group.is_some());
+
+        let owner = PeerId::random();
+        let member = PeerId::random();
+        let mut group = Group::new("team".to_string(), generate_group_key(), Some(owner));
+        group.add_member(owner);
+        group.add_member(member);
+        group.add_member(keypair_to_peer_id(&keypair));
+        db.create_group(&group).unwrap();
+
+        let new_key = generate_group_key();
+        let update = GroupKeyUpdate {
+            group_id: group.id,
+            epoch: 1,
+            encrypted_key: encrypt_message(&new_key, &our_pk).unwrap(),
+        };
+
+        assert!(!receive_group_key_update(&db, (&our_pk, &our_sk), member, &update).unwrap());
+        assert_eq!(db.group_key_epoch(&group.id).unwrap(), 0);
+
+        assert!(receive_group_key_update(&db, (&our_pk, &our_sk), owner, &update).unwrap());
+        assert_eq!(db.get_group(&group.id).unwrap().unwrap().symmetric_key, new_key);
+
+        // Replays of the same epoch change nothing
+        assert!(!receive_group_key_update(&db, (&our_pk, &our_sk), owner, &update).unwrap());
+
+        // Only the rotation that took is audited
+        let audit = db.audit_log(Some(AuditKind::KeyChanged), None, 10).unwrap();
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0].peer_id, Some(owner));
     }
 
     #[tokio::test]
@@ -1892,7 +8238,8 @@ mod tests {
         handle_group_create("group2", data_dir, "test").await.unwrap();
 
         // Should not error
-        handle_group_list(data_dir, "test").await.unwrap();
+        handle_group_list(OutputFormat::Text, data_dir, "test").await.unwrap();
+        handle_group_list(OutputFormat::Json, data_dir, "test").await.unwrap();
     }
 
     #[tokio::test]
@@ -1903,7 +8250,8 @@ mod tests {
         handle_init(data_dir, "test").await.unwrap();
 
         // Should not error
-        handle_peers(data_dir, "test").await.unwrap();
+        handle_peers(OutputFormat::Text, data_dir, "test").await.unwrap();
+        handle_peers(OutputFormat::Json, data_dir, "test").await.unwrap();
     }
 
     // Receipt tests
@@ -1911,43 +8259,293 @@ mod tests {
     #[test]
     fn create_and_parse_delivered_receipt() {
         let msg_id = uuid::Uuid::new_v4();
-        let receipt = create_receipt(&msg_id, crate::message::ReceiptType::Delivered);
+        let receipt = create_receipt(&generate_keypair(), &msg_id, ReceiptType::Delivered).unwrap();
         
-        let parsed = parse_receipt(&receipt);
+        let envelope = Envelope::decode(&receipt).unwrap();
+        let parsed = parse_receipt(&envelope);
         assert!(parsed.is_some());
         
         let (parsed_id, parsed_type) = parsed.unwrap();
         assert_eq!(parsed_id, msg_id);
-        assert!(matches!(parsed_type, crate::message::ReceiptType::Delivered));
+        assert!(matches!(parsed_type, ReceiptType::Delivered));
     }
 
     #[test]
     fn create_and_parse_read_receipt() {
         let msg_id = uuid::Uuid::new_v4();
-        let receipt = create_receipt(&msg_id, crate::message::ReceiptType::Read);
+        let receipt = create_receipt(&generate_keypair(), &msg_id, ReceiptType::Read).unwrap();
         
-        let parsed = parse_receipt(&receipt);
+        let envelope = Envelope::decode(&receipt).unwrap();
+        let parsed = parse_receipt(&envelope);
         assert!(parsed.is_some());
         
         let (parsed_id, parsed_type) = parsed.unwrap();
         assert_eq!(parsed_id, msg_id);
-        assert!(matches!(parsed_type, crate::message::ReceiptType::Read));
+        assert!(matches!(parsed_type, ReceiptType::Read));
+    }
+
+    #[test]
+    fn receipt_carries_signed_sender() {
+        let keypair = generate_keypair();
+        let receipt = create_receipt(&keypair, &uuid::Uuid::new_v4(), ReceiptType::Delivered).unwrap();
+        let envelope = Envelope::decode(&receipt).unwrap();
+        assert_eq!(envelope.sender, keypair_to_peer_id(&keypair));
+        assert_eq!(envelope.authenticate(Some(&keypair.public())), Authenticity::Verified);
+    }
+
+    #[test]
+    fn receipt_resolves_original_outgoing_message() {
+        let alice = PeerId::random();
+        let bob_keypair = generate_keypair();
+        let bob = keypair_to_peer_id(&bob_keypair);
+        let alice_db = Database::open_in_memory().unwrap();
+        let bob_db = Database::open_in_memory().unwrap();
+
+        // Alice sends
+        let outgoing = Message::new_text(alice, Recipient::Direct(bob), "hi".to_string());
+        alice_db.insert_message(&outgoing).unwrap();
+        let wire = Envelope::from_message(&outgoing).encode().unwrap();
+
+        // Bob receives and acknowledges
+        let received = Envelope::decode(&wire).unwrap().into_message(Recipient::Direct(bob));
+        bob_db.insert_message(&received).unwrap();
+        let receipt = create_receipt(&bob_keypair, &received.id, ReceiptType::Delivered).unwrap();
+
+        // Alice applies the receipt to her copy
+        let (msg_id, _) = parse_receipt(&Envelope::decode(&receipt).unwrap()).unwrap();
+        assert!(alice_db.update_message_status(&msg_id, &MessageStatus::Delivered).unwrap());
+    }
+
+    #[test]
+    fn stale_typing_indicators_are_ignored() {
+        let mut envelope = Envelope::new(PeerId::random(), MessageContent::Typing);
+        assert!(typing_is_fresh(&envelope));
+
+        envelope.timestamp = Utc::now() - chrono::Duration::seconds(60);
+        assert!(!typing_is_fresh(&envelope));
+    }
+
+    #[test]
+    fn stdin_message_keeps_lines_but_not_trailing_newline() {
+        assert_eq!(read_message_text(&b"report attached\n"[..]).unwrap(), "report attached");
+        assert_eq!(read_message_text(&b"line one\r\nline two\r\n"[..]).unwrap(), "line one\r\nline two");
+        assert_eq!(read_message_text(&b"a\n\nb\n\n"[..]).unwrap(), "a\n\nb\n");
+        assert!(read_message_text(&b"\n"[..]).is_err());
+        assert!(read_message_text(&[0xff, 0xfe][..]).is_err());
+    }
+
+    #[test]
+    fn history_lines_show_direction_and_status() {
+        let (me, alice) = (PeerId::random(), PeerId::random());
+        let timestamp = "2026-03-01T09:30:00Z".parse().unwrap();
+
+        let mut sent = Message::new_text(me, Recipient::Direct(alice), "hi\nthere".to_string());
+        sent.timestamp = timestamp;
+        sent.status = MessageStatus::Delivered;
+        assert_eq!(history_line(&sent, &me), "[2026-03-01 09:30] → hi\n                     there  ✓✓");
+
+        let mut received = Message::new_text(alice, Recipient::Direct(me), "hello".to_string());
+        received.timestamp = timestamp;
+        received.status = MessageStatus::Read;
+        assert_eq!(history_line(&received, &me), "[2026-03-01 09:30] ← hello");
+    }
+
+    #[tokio::test]
+    async fn history_prints_latest_messages() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let alice = PeerId::random();
+        handle_add_contact("alice", &alice.to_string(), data_dir, "test").await.unwrap();
+        let db = open_database(data_dir, "test").unwrap();
+        for i in 0..3 {
+            db.insert_message(&Message::new_text(alice, Recipient::Direct(PeerId::random()), format!("msg {}", i)))
+                .unwrap();
+        }
+        drop(db);
+
+        handle_history("alice", 2, None, OutputFormat::Text, data_dir, "test").await.unwrap();
+        handle_history("alice", 2, Some("2020-01-01"), OutputFormat::Json, data_dir, "test").await.unwrap();
+        assert!(handle_history("bob", 2, None, OutputFormat::Text, data_dir, "test").await.is_err());
+        assert!(handle_history("alice", 2, Some("yesterday"), OutputFormat::Text, data_dir, "test").await.is_err());
+    }
+
+    #[test]
+    fn listen_lines_are_plain_or_json() {
+        let from = PeerId::random();
+        let mut msg = Message::new_text(from, Recipient::Direct(PeerId::random()), "hi\nthere".to_string());
+        msg.timestamp = "2026-03-01T09:30:00Z".parse().unwrap();
+
+        let line = listen_line(&msg, Some("alice"), None, "hi\nthere", true, false).unwrap();
+        assert_eq!(line, "[2026-03-01 09:30:00] alice: hi\nthere");
+        let line = listen_line(&msg, None, None, "hi", true, false).unwrap();
+        assert!(line.contains(&short_peer_id(&from)));
+        let line = listen_line(&msg, Some("alice"), None, "hi", false, false).unwrap();
+        assert_eq!(line, "[2026-03-01 09:30:00] alice (unverified): hi");
+        let line = listen_line(&msg, Some("alice"), Some("team"), "hi", true, false).unwrap();
+        assert_eq!(line, "[2026-03-01 09:30:00] #team alice: hi");
+        let line = listen_line(&msg, Some("alice"), Some("team"), "hi", true, true).unwrap();
+        assert!(line.contains(r#""group":"team""#));
+
+        // JSON stays on one line
+        let line = listen_line(&msg, Some("alice"), None, "hi\nthere", true, true).unwrap();
+        assert!(!line.contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["from"], from.to_string());
+        assert_eq!(value["alias"], "alice");
+        assert_eq!(value["text"], "hi\nthere");
+        assert_eq!(value["id"], msg.id.to_string());
+        assert_eq!(value["timestamp"], "2026-03-01T09:30:00Z");
+        assert_eq!(value["verified"], true);
+        assert!(value.get("group").is_none());
     }
 
     #[test]
     fn parse_receipt_rejects_non_receipts() {
-        let text_msg = b"Hello, world!";
-        assert!(parse_receipt(text_msg).is_none());
+        let text_msg = Envelope::new(PeerId::random(), MessageContent::Text("Hello, world!".to_string()));
+        assert!(parse_receipt(&text_msg).is_none());
+    }
+
+    #[test]
+    fn legacy_prefixed_receipts_are_not_envelopes() {
+        assert!(Envelope::decode(b"RCPT:D:12345678-1234-1234-1234-123456789012").is_err());
+        assert!(Envelope::decode(b"RCPT:D:123").is_err());
+    }
+
+    #[test]
+    fn only_contacts_without_a_key_get_plaintext() {
+        let keys = keypair_to_encryption_keys(&generate_keypair()).unwrap();
+        let db = Database::open_in_memory().unwrap();
+
+        // A peer ID that doesn't embed a key, and no key stored for it
+        let keyless = PeerId::random();
+        assert_eq!(seal_for_contact(&db, (&keys.0, &keys.1), &keyless, &[], b"hello").unwrap(), b"hello");
+
+        let bob = keypair_to_peer_id(&generate_keypair());
+        let sealed = seal_for_contact(&db, (&keys.0, &keys.1), &bob, &[], b"hello").unwrap();
+        assert!(SessionMessage::from_bytes(&sealed).is_ok());
+    }
+
+    #[test]
+    fn direct_messages_use_ratchet_sessions() {
+        let alice_kp = generate_keypair();
+        let bob_kp = generate_keypair();
+        let alice = keypair_to_peer_id(&alice_kp);
+        let bob = keypair_to_peer_id(&bob_kp);
+        let alice_keys = keypair_to_encryption_keys(&alice_kp).unwrap();
+        let bob_keys = keypair_to_encryption_keys(&bob_kp).unwrap();
+        let alice_db = Database::open_in_memory().unwrap();
+        let bob_db = Database::open_in_memory().unwrap();
+
+        // First message starts a session from Bob's identity key
+        let wire = seal_for_contact(&alice_db, (&alice_keys.0, &alice_keys.1), &bob, &[], b"hello").unwrap();
+        assert!(SessionMessage::from_bytes(&wire).unwrap().init.is_some());
+        assert_eq!(open_from_peer(&bob_db, (&bob_keys.0, &bob_keys.1), &alice, &wire), b"hello");
+
+        // Bob replies on the same session, which acknowledges it
+        let reply = seal_for_contact(&bob_db, (&bob_keys.0, &bob_keys.1), &alice, &[], b"hi").unwrap();
+        assert_eq!(open_from_peer(&alice_db, (&alice_keys.0, &alice_keys.1), &bob, &reply), b"hi");
+
+        let next = seal_for_contact(&alice_db, (&alice_keys.0, &alice_keys.1), &bob, &[], b"again").unwrap();
+        assert!(SessionMessage::from_bytes(&next).unwrap().init.is_none());
+        assert_eq!(open_from_peer(&bob_db, (&bob_keys.0, &bob_keys.1), &alice, &next), b"again");
+        assert!(bob_db.audit_log(None, None, 10).unwrap().is_empty());
+
+        // Alice starts over, say after a reinstall: Bob notes the new session
+        let fresh_db = Database::open_in_memory().unwrap();
+        let restart = seal_for_contact(&fresh_db, (&alice_keys.0, &alice_keys.1), &bob, &[], b"it's me").unwrap();
+        assert_eq!(open_from_peer(&bob_db, (&bob_keys.0, &bob_keys.1), &alice, &restart), b"it's me");
+        let audit = bob_db.audit_log(None, None, 10).unwrap();
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0].kind, AuditKind::KeyChanged);
+    }
+
+    #[test]
+    fn first_contact_uses_imported_prekey() {
+        let alice_kp = generate_keypair();
+        let bob_kp = generate_keypair();
+        let alice = keypair_to_peer_id(&alice_kp);
+        let bob = keypair_to_peer_id(&bob_kp);
+        let alice_keys = keypair_to_encryption_keys(&alice_kp).unwrap();
+        let bob_keys = keypair_to_encryption_keys(&bob_kp).unwrap();
+        let alice_db = Database::open_in_memory().unwrap();
+        let bob_db = Database::open_in_memory().unwrap();
+
+        // Alice imports Bob's bundle while Bob is offline
+        let bob_prekey = current_prekey(&bob_db, &bob_kp).unwrap();
+        let bundle = export_contact_bundle(&bob_kp, &bob_prekey.public()).unwrap();
+        let (_, prekey) = import_contact_bundle(&bundle).unwrap();
+        alice_db.save_contact_prekey(&bob, &prekey.unwrap()).unwrap();
+
+        let wire = seal_for_contact(&alice_db, (&alice_keys.0, &alice_keys.1), &bob, &[], b"hello").unwrap();
+        let init = SessionMessage::from_bytes(&wire).unwrap().init.unwrap();
+        assert_eq!(init.prekey_id, Some(bob_prekey.id));
+        assert_eq!(open_from_peer(&bob_db, (&bob_keys.0, &bob_keys.1), &alice, &wire), b"hello");
+    }
+
+    #[tokio::test]
+    async fn import_contact_stores_prekey() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let bob_kp = generate_keypair();
+        let bob = keypair_to_peer_id(&bob_kp);
+        let prekey = generate_signed_prekey(&bob_kp, 4).unwrap();
+        let file = data_dir.join("bob.key");
+        fs::write(&file, export_contact_bundle(&bob_kp, &prekey.public()).unwrap()).unwrap();
+
+        handle_import_contact(&file, "bob", data_dir, "test").await.unwrap();
+
+        let db = open_database(data_dir, "test").unwrap();
+        assert!(db.get_contact_by_alias("bob").unwrap().is_some());
+        assert_eq!(db.get_contact_prekey(&bob).unwrap(), Some(prekey.public()));
+    }
+
+    #[tokio::test]
+    async fn import_contact_accepts_scanned_qr_text() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path();
+        handle_init(data_dir, "test").await.unwrap();
+
+        let bob_kp = generate_keypair();
+        let bob = keypair_to_peer_id(&bob_kp);
+        let prekey = generate_signed_prekey(&bob_kp, 2).unwrap();
+        let bundle = export_contact_bundle(&bob_kp, &prekey.public()).unwrap();
+        let file = data_dir.join("bob.txt");
+        fs::write(&file, format!("{}\n", contact_uri(&bob, &bundle))).unwrap();
+
+        handle_import_contact(&file, "bob", data_dir, "test").await.unwrap();
+
+        let db = open_database(data_dir, "test").unwrap();
+        assert_eq!(db.get_contact_by_alias("bob").unwrap().unwrap().peer_id, bob);
+
+        // A code whose peer ID doesn't match its key is refused
+        fs::write(&file, contact_uri(&PeerId::random(), &bundle)).unwrap();
+        assert!(handle_import_contact(&file, "mallory", data_dir, "test").await.is_err());
     }
 
     #[test]
-    fn parse_receipt_rejects_malformed() {
-        // Wrong prefix
-        assert!(parse_receipt(b"RECEIPT:D:12345").is_none());
-        // Too short
-        assert!(parse_receipt(b"RCPT:D:123").is_none());
-        // Invalid type
-        assert!(parse_receipt(b"RCPT:X:12345678-1234-1234-1234-123456789012").is_none());
+    fn session_init_from_wrong_peer_is_rejected() {
+        let alice_kp = generate_keypair();
+        let bob_kp = generate_keypair();
+        let bob = keypair_to_peer_id(&bob_kp);
+        let alice_keys = keypair_to_encryption_keys(&alice_kp).unwrap();
+        let bob_keys = keypair_to_encryption_keys(&bob_kp).unwrap();
+        let alice_db = Database::open_in_memory().unwrap();
+        let bob_db = Database::open_in_memory().unwrap();
+
+        let wire = seal_for_contact(&alice_db, (&alice_keys.0, &alice_keys.1), &bob, &[], b"hello").unwrap();
+        let message = SessionMessage::from_bytes(&wire).unwrap();
+        let mallory = PeerId::random();
+        assert!(decrypt_with_session(&bob_db, (&bob_keys.0, &bob_keys.1), &mallory, &message).is_err());
+
+        // Nothing opens it, so it's audited
+        assert_eq!(open_from_peer(&bob_db, (&bob_keys.0, &bob_keys.1), &mallory, &wire), wire);
+        let audit = bob_db.audit_log(Some(AuditKind::DecryptionFailed), None, 10).unwrap();
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0].peer_id, Some(mallory));
     }
 
     // File transfer tests
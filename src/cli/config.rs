@@ -0,0 +1,225 @@
+//! Settings kept in `config.toml` in the data directory.
+//!
+//! The file lists bootstrap nodes and relays to use on top of the built-in
+//! ones, and addresses to listen on. Users can edit it by hand;
+//! `--bootstrap`, `--relay` and `--listen` add to it. A `[kdf]` table sets
+//! the Argon2 memory and iterations for databases created from then on.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+
+use crate::network::extract_peer_id;
+use crate::storage::KdfParams;
+
+/// Config file name within the data directory.
+const CONFIG_FILE: &str = "config.toml";
+
+/// The file as written: addresses are strings until they're checked.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ConfigFile {
+    bootstrap: Vec<String>,
+    relays: Vec<String>,
+    listen: Vec<String>,
+    #[serde(skip_serializing_if = "KdfConfig::is_empty")]
+    kdf: KdfConfig,
+}
+
+/// Argon2 costs to derive new database keys with; the defaults where
+/// they're left out.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct KdfConfig {
+    memory_kib: Option<u32>,
+    iterations: Option<u32>,
+}
+
+impl KdfConfig {
+    fn is_empty(&self) -> bool {
+        self.memory_kib.is_none() && self.iterations.is_none()
+    }
+}
+
+/// Bootstrap nodes, relays and listen addresses the user added.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkConfig {
+    /// Nodes to bootstrap the DHT from.
+    pub bootstrap: Vec<Multiaddr>,
+    /// Relays to listen through.
+    pub relays: Vec<Multiaddr>,
+    /// Addresses to listen on. Without any, the node keeps to one port
+    /// from run to run.
+    pub listen: Vec<Multiaddr>,
+}
+
+/// Path of the config file in a data directory.
+pub fn config_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(CONFIG_FILE)
+}
+
+/// Read the config file in `data_dir`; empty if there isn't one.
+fn load_file(data_dir: &Path) -> Result<ConfigFile> {
+    let path = config_path(data_dir);
+    if !path.exists() {
+        return Ok(ConfigFile::default());
+    }
+    let text = fs::read_to_string(&path).context("Failed to read config file")?;
+    toml::from_str(&text).with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+/// The Argon2 parameters the config asks new databases to use.
+pub fn configured_kdf(data_dir: &Path) -> Result<KdfParams> {
+    let kdf = load_file(data_dir)?.kdf;
+    let defaults = KdfParams::default();
+    let params = KdfParams {
+        memory_kib: kdf.memory_kib.unwrap_or(defaults.memory_kib),
+        iterations: kdf.iterations.unwrap_or(defaults.iterations),
+        ..defaults
+    };
+    // Checks the values are in range
+    params
+        .to_string()
+        .parse()
+        .with_context(|| format!("In {}", config_path(data_dir).display()))
+}
+
+impl NetworkConfig {
+    /// Read the config in `data_dir`; empty if there's no file.
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = config_path(data_dir);
+        let file = load_file(data_dir)?;
+        Ok(Self {
+            bootstrap: parse_nodes(&file.bootstrap).with_context(|| format!("In {}", path.display()))?,
+            relays: parse_nodes(&file.relays).with_context(|| format!("In {}", path.display()))?,
+            listen: parse_addresses(&file.listen).with_context(|| format!("In {}", path.display()))?,
+        })
+    }
+
+    /// Write the config to `data_dir`, keeping its other settings.
+    pub fn save(&self, data_dir: &Path) -> Result<()> {
+        let file = ConfigFile {
+            bootstrap: self.bootstrap.iter().map(ToString::to_string).collect(),
+            relays: self.relays.iter().map(ToString::to_string).collect(),
+            listen: self.listen.iter().map(ToString::to_string).collect(),
+            ..load_file(data_dir)?
+        };
+        fs::create_dir_all(data_dir)?;
+        fs::write(config_path(data_dir), toml::to_string(&file)?).context("Failed to write config file")
+    }
+
+    /// Add whatever `other` has that this doesn't. Returns whether
+    /// anything was added.
+    pub fn add(&mut self, other: &NetworkConfig) -> Result<bool> {
+        for node in other.bootstrap.iter().chain(&other.relays) {
+            check_node(node)?;
+        }
+        let mut added = false;
+        for (ours, theirs) in [
+            (&mut self.bootstrap, &other.bootstrap),
+            (&mut self.relays, &other.relays),
+            (&mut self.listen, &other.listen),
+        ] {
+            for addr in theirs {
+                if !ours.contains(addr) {
+                    ours.push(addr.clone());
+                    added = true;
+                }
+            }
+        }
+        Ok(added)
+    }
+}
+
+/// Save bootstrap nodes, relays and listen addresses given on the command
+/// line to the config, so they're used from then on.
+pub fn add_to_config(data_dir: &Path, added: &NetworkConfig) -> Result<()> {
+    let mut config = NetworkConfig::load(data_dir)?;
+    if config.add(added)? {
+        config.save(data_dir)?;
+    }
+    Ok(())
+}
+
+/// Nodes are dialed by peer ID, so their addresses must end with one.
+fn check_node(addr: &Multiaddr) -> Result<()> {
+    if extract_peer_id(addr).is_none() {
+        anyhow::bail!("{} must end with /p2p/<peer id>", addr);
+    }
+    Ok(())
+}
+
+fn parse_nodes(nodes: &[String]) -> Result<Vec<Multiaddr>> {
+    let nodes = parse_addresses(nodes)?;
+    for node in &nodes {
+        check_node(node)?;
+    }
+    Ok(nodes)
+}
+
+fn parse_addresses(addrs: &[String]) -> Result<Vec<Multiaddr>> {
+    addrs
+        .iter()
+        .map(|addr| addr.parse().with_context(|| format!("Invalid address '{}'", addr)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const NODE: &str = "/ip4/10.0.0.1/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ";
+
+    #[test]
+    fn missing_config_is_empty() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(NetworkConfig::load(dir.path()).unwrap(), NetworkConfig::default());
+    }
+
+    #[test]
+    fn added_nodes_are_saved_once() {
+        let dir = TempDir::new().unwrap();
+        let node: Multiaddr = NODE.parse().unwrap();
+        let port: Multiaddr = "/ip4/0.0.0.0/tcp/7777".parse().unwrap();
+        let added = NetworkConfig { bootstrap: vec![node.clone()], relays: vec![node.clone()], listen: vec![port] };
+        add_to_config(dir.path(), &added).unwrap();
+        add_to_config(dir.path(), &NetworkConfig { bootstrap: vec![node], ..Default::default() }).unwrap();
+        assert_eq!(NetworkConfig::load(dir.path()).unwrap(), added);
+
+        // Nodes need a peer ID to be dialed
+        let bare: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        assert!(add_to_config(dir.path(), &NetworkConfig { relays: vec![bare], ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn hand_written_config_is_checked() {
+        let dir = TempDir::new().unwrap();
+        fs::write(config_path(dir.path()), format!("relays = [\"{}\"]\n", NODE)).unwrap();
+        let config = NetworkConfig::load(dir.path()).unwrap();
+        assert!(config.bootstrap.is_empty());
+        assert_eq!(config.relays.len(), 1);
+
+        fs::write(config_path(dir.path()), "bootstrap = [\"not an address\"]\n").unwrap();
+        assert!(NetworkConfig::load(dir.path()).is_err());
+    }
+
+    #[test]
+    fn kdf_settings_survive_adding_nodes() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(configured_kdf(dir.path()).unwrap(), KdfParams::default());
+
+        fs::write(config_path(dir.path()), "[kdf]\nmemory_kib = 65536\n").unwrap();
+        let node: Multiaddr = NODE.parse().unwrap();
+        add_to_config(dir.path(), &NetworkConfig { relays: vec![node], ..Default::default() }).unwrap();
+        let params = configured_kdf(dir.path()).unwrap();
+        assert_eq!(params.memory_kib, 65536);
+        assert_eq!(params.iterations, KdfParams::default().iterations);
+
+        fs::write(config_path(dir.path()), "[kdf]\niterations = 0\n").unwrap();
+        assert!(configured_kdf(dir.path()).is_err());
+    }
+}
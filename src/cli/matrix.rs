@@ -0,0 +1,278 @@
+//! The Matrix side of `whisper bridge matrix`.
+//!
+//! The bridge logs in to a homeserver as an ordinary (bot) user with an
+//! access token and relays between one room and one whisper group. Group
+//! messages are posted to the room as `name: text`, with the name in bold
+//! for clients that show HTML. Room messages are sent to the group, from
+//! us, as `name: text` with Matrix reply quotes removed; names are the
+//! sender's Matrix localpart. The sync position is saved, so a restart
+//! picks up where it left off rather than replaying the room.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::storage::DatabaseHandle;
+
+/// How long each sync request waits on the server for new events.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait before syncing again after an error.
+const SYNC_RETRY: Duration = Duration::from_secs(10);
+
+/// A message from the room, to relay to the group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatrixMessage {
+    /// The sender's localpart.
+    pub sender: String,
+    pub text: String,
+}
+
+/// A logged-in Matrix user in the bridged room.
+#[derive(Clone)]
+pub struct MatrixClient {
+    homeserver: reqwest::Url,
+    token: String,
+    room: String,
+    /// Our Matrix ID, so our own posts aren't relayed back.
+    user_id: String,
+    client: reqwest::Client,
+}
+
+impl MatrixClient {
+    /// Log in to `homeserver` with `token` and join `room`, by ID or alias.
+    pub async fn connect(homeserver: &str, token: String, room: &str) -> Result<Self> {
+        let homeserver = reqwest::Url::parse(homeserver).with_context(|| format!("'{}' is not a URL", homeserver))?;
+        // Sync requests hang for SYNC_TIMEOUT by design
+        let client = reqwest::Client::builder()
+            .timeout(SYNC_TIMEOUT + Duration::from_secs(30))
+            .build()
+            .context("Failed to set up the Matrix client")?;
+        let mut matrix = Self {
+            homeserver,
+            token,
+            room: String::new(),
+            user_id: String::new(),
+            client,
+        };
+
+        let whoami = matrix.get("account/whoami").await.context("Matrix login failed")?;
+        matrix.user_id = whoami["user_id"].as_str().context("Matrix didn't say who we are")?.to_string();
+        let joined = matrix
+            .post(&format!("join/{}", encode(room)), json!({}))
+            .await
+            .with_context(|| format!("Failed to join {}", room))?;
+        matrix.room = joined["room_id"].as_str().context("Matrix didn't return the room ID")?.to_string();
+        Ok(matrix)
+    }
+
+    /// Our Matrix ID.
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    /// Post a group message from `name` to the room.
+    pub async fn post_message(&self, name: &str, text: &str) -> Result<()> {
+        let content = json!({
+            "msgtype": "m.text",
+            "body": format!("{}: {}", name, text),
+            "format": "org.matrix.custom.html",
+            "formatted_body": format!("<strong>{}</strong>: {}", escape_html(name), escape_html(text).replace('\n', "<br>")),
+        });
+        let path = format!(
+            "rooms/{}/send/m.room.message/{}",
+            encode(&self.room),
+            uuid::Uuid::new_v4()
+        );
+        self.request(self.client.put(self.url(&path)?).json(&content)).await?;
+        Ok(())
+    }
+
+    /// Wait for new messages in the room after `since`. Returns them with
+    /// the position to sync from next; without `since`, returns no
+    /// messages, just the current position.
+    async fn sync(&self, since: Option<&str>) -> Result<(String, Vec<MatrixMessage>)> {
+        let filter = json!({
+            "room": { "rooms": [self.room], "timeline": { "types": ["m.room.message"] } },
+            "presence": { "types": [] },
+            "account_data": { "types": [] },
+        });
+        let mut url = self.url("sync")?;
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("filter", &filter.to_string());
+            if let Some(since) = since {
+                query.append_pair("since", since);
+                query.append_pair("timeout", &SYNC_TIMEOUT.as_millis().to_string());
+            }
+        }
+        let response = self.request(self.client.get(url)).await?;
+        let next = response["next_batch"].as_str().context("Sync response has no next_batch")?.to_string();
+        if since.is_none() {
+            return Ok((next, Vec::new()));
+        }
+        let events = &response["rooms"]["join"][&self.room]["timeline"]["events"];
+        let messages = events
+            .as_array()
+            .map(|events| events.iter().filter_map(|event| self.relayed(event)).collect())
+            .unwrap_or_default();
+        Ok((next, messages))
+    }
+
+    /// The message to relay for a room event, if any.
+    fn relayed(&self, event: &Value) -> Option<MatrixMessage> {
+        if event["type"] != "m.room.message" || event["sender"] == self.user_id.as_str() {
+            return None;
+        }
+        let sender = localpart(event["sender"].as_str()?);
+        let body = strip_reply_fallback(event["content"]["body"].as_str()?);
+        let text = match event["content"]["msgtype"].as_str()? {
+            "m.text" | "m.notice" => body.to_string(),
+            "m.emote" => format!("* {} {}", sender, body),
+            // Images and files would need uploading; say what was missed
+            other => format!("[{}] {}", other.trim_start_matches("m."), body),
+        };
+        Some(MatrixMessage { sender, text })
+    }
+
+    fn url(&self, path: &str) -> Result<reqwest::Url> {
+        self.homeserver
+            .join(&format!("/_matrix/client/v3/{}", path))
+            .context("Bad Matrix URL")
+    }
+
+    async fn get(&self, path: &str) -> Result<Value> {
+        self.request(self.client.get(self.url(path)?)).await
+    }
+
+    async fn post(&self, path: &str, body: Value) -> Result<Value> {
+        self.request(self.client.post(self.url(path)?).json(&body)).await
+    }
+
+    async fn request(&self, request: reqwest::RequestBuilder) -> Result<Value> {
+        let response = request.bearer_auth(&self.token).send().await.context("Matrix request failed")?;
+        let status = response.status();
+        let body: Value = response.json().await.unwrap_or(Value::Null);
+        if !status.is_success() {
+            let error = body["error"].as_str().unwrap_or_else(|| status.canonical_reason().unwrap_or("error"));
+            bail!("Matrix said {}: {}", status.as_u16(), error);
+        }
+        Ok(body)
+    }
+}
+
+/// Pass room messages to `relay` until it's dropped, saving the sync
+/// position in `db` as it goes.
+pub async fn run_matrix_sync(matrix: MatrixClient, db: DatabaseHandle, relay: mpsc::Sender<MatrixMessage>) {
+    let room = matrix.room.clone();
+    let saved = db.call(move |db| db.matrix_sync_token(&room)).await.ok().flatten();
+    let mut since = saved;
+    loop {
+        match matrix.sync(since.as_deref()).await {
+            Ok((next, messages)) => {
+                for message in messages {
+                    if relay.send(message).await.is_err() {
+                        return;
+                    }
+                }
+                let (room, token) = (matrix.room.clone(), next.clone());
+                let _ = db.call(move |db| db.set_matrix_sync_token(&room, &token)).await;
+                since = Some(next);
+            }
+            Err(e) => {
+                eprintln!("Matrix sync failed: {:#}", e);
+                tokio::time::sleep(SYNC_RETRY).await;
+            }
+        }
+    }
+}
+
+/// `@alice:example.org` to `alice`.
+fn localpart(user_id: &str) -> String {
+    let user = user_id.strip_prefix('@').unwrap_or(user_id);
+    user.split(':').next().unwrap_or(user).to_string()
+}
+
+/// Drop the `> quoted` lines Matrix clients put at the top of replies.
+fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+    match body.find("\n\n") {
+        Some(end) if body[..end].lines().all(|line| line.starts_with('>')) => &body[end + 2..],
+        _ => body,
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Percent-encode a room ID or alias for a URL path.
+fn encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> MatrixClient {
+        MatrixClient {
+            homeserver: "https://matrix.example.org".parse().unwrap(),
+            token: "token".to_string(),
+            room: "!room:example.org".to_string(),
+            user_id: "@bridge:example.org".to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn event(sender: &str, msgtype: &str, body: &str) -> Value {
+        json!({
+            "type": "m.room.message",
+            "sender": sender,
+            "content": { "msgtype": msgtype, "body": body },
+        })
+    }
+
+    #[test]
+    fn room_messages_are_relayed_with_the_sender() {
+        let matrix = client();
+        let relayed = matrix.relayed(&event("@alice:example.org", "m.text", "hi all")).unwrap();
+        assert_eq!(relayed, MatrixMessage { sender: "alice".to_string(), text: "hi all".to_string() });
+
+        let emote = matrix.relayed(&event("@alice:example.org", "m.emote", "waves")).unwrap();
+        assert_eq!(emote.text, "* alice waves");
+        let image = matrix.relayed(&event("@alice:example.org", "m.image", "cat.png")).unwrap();
+        assert_eq!(image.text, "[image] cat.png");
+
+        // Our own posts would loop back
+        assert!(matrix.relayed(&event("@bridge:example.org", "m.text", "alice: hi")).is_none());
+    }
+
+    #[test]
+    fn reply_quotes_are_dropped() {
+        assert_eq!(strip_reply_fallback("> <@bob:x> earlier\n> more\n\nmy answer"), "my answer");
+        assert_eq!(strip_reply_fallback("> not a reply"), "> not a reply");
+        assert_eq!(strip_reply_fallback("plain\n\ntext"), "plain\n\ntext");
+    }
+
+    #[test]
+    fn names_and_paths_are_encoded() {
+        assert_eq!(localpart("@alice:example.org"), "alice");
+        assert_eq!(encode("!room:example.org"), "%21room%3Aexample.org");
+        assert_eq!(encode("#whisper:example.org"), "%23whisper%3Aexample.org");
+        assert_eq!(escape_html("<b> & \"x\""), "&lt;b&gt; &amp; &quot;x&quot;");
+    }
+}
@@ -0,0 +1,130 @@
+//! The `/metrics` endpoint for `whisper listen --metrics`.
+//!
+//! A minimal HTTP/1.1 responder: each connection gets one response and is
+//! closed. It only binds to loopback addresses, so the metrics, which say
+//! who you talk to and how much, never leave the machine unless the user
+//! puts a proxy in front of it.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use prometheus_client::registry::Registry;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::network::encode_metrics;
+
+/// Where `--metrics` listens when no address is given.
+pub const DEFAULT_METRICS_ADDR: &str = "127.0.0.1:9464";
+
+/// Largest request head we read.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Content type of the Prometheus text format.
+const METRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Bind the metrics endpoint. Only loopback addresses are allowed.
+pub async fn bind_metrics(addr: SocketAddr) -> Result<TcpListener> {
+    if !addr.ip().is_loopback() {
+        bail!("Metrics are only served on localhost, not {}", addr.ip());
+    }
+    TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen for metrics on {}", addr))
+}
+
+/// Answer scrapes on `listener` until the task is dropped.
+pub async fn serve_metrics(listener: TcpListener, registry: Arc<Registry>) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            // A scraper that hangs up early is its own problem
+            let _ = answer(stream, &registry).await;
+        });
+    }
+}
+
+async fn answer(mut stream: TcpStream, registry: &Registry) -> Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            bail!("Request head too large");
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let request_line = String::from_utf8_lossy(&head);
+    let request_line = request_line.lines().next().unwrap_or_default();
+    let (status, content_type, body) = route(request_line, registry);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Status, content type and body for a request line.
+fn route(request_line: &str, registry: &Registry) -> (&'static str, &'static str, String) {
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    match (method, path) {
+        ("GET", "/metrics") => ("200 OK", METRICS_CONTENT_TYPE, encode_metrics(registry)),
+        (_, "/metrics") => ("405 Method Not Allowed", "text/plain", "Only GET is supported\n".to_string()),
+        _ => ("404 Not Found", "text/plain", "Try /metrics\n".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NodeMetrics;
+
+    async fn get(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_on_localhost() {
+        let mut registry = Registry::default();
+        let metrics = NodeMetrics::register(&mut registry);
+        metrics.connected_peers.set(2);
+
+        let listener = bind_metrics("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_metrics(listener, Arc::new(registry)));
+
+        let response = get(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("whisper_connected_peers 2"));
+
+        let response = get(addr, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404"));
+        let response = get(addr, "POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"));
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn refuses_public_addresses() {
+        assert!(bind_metrics("0.0.0.0:0".parse().unwrap()).await.is_err());
+    }
+}
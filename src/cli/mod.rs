@@ -1,5 +1,23 @@
 //! CLI command handlers.
 
+mod api;
+mod bot;
 mod commands;
+mod config;
+mod matrix;
+mod metrics;
+mod output;
+mod passphrase;
+mod profile;
+mod webhook;
 
+pub use api::{api_token_path, DEFAULT_API_ADDR};
 pub use commands::*;
+pub use config::{add_to_config, config_path, configured_kdf, NetworkConfig};
+pub use metrics::DEFAULT_METRICS_ADDR;
+pub use output::OutputFormat;
+pub use passphrase::{ask_new_passphrase, ask_passphrase, PassphraseUse};
+pub use profile::{
+    current_profile, handle_profile_create, handle_profile_list, handle_profile_switch,
+    profile_dir, resolve_data_dir, DEFAULT_PROFILE,
+};
@@ -0,0 +1,242 @@
+//! Machine-readable output for commands run with `--output json`.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::identity::{PresenceStatus, TrustLevel};
+
+/// How commands print their results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable text.
+    #[default]
+    Text,
+    /// JSON for scripts and tools like jq.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" | "txt" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => anyhow::bail!("Unknown output format '{}' (expected text or json)", s),
+        }
+    }
+}
+
+/// Print `value` as pretty JSON on stdout.
+pub fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value).context("Failed to encode output")?);
+    Ok(())
+}
+
+/// A contact as listed by `whisper contacts`.
+#[derive(Debug, Serialize)]
+pub struct ContactOutput {
+    pub alias: String,
+    pub peer_id: String,
+    pub trust: TrustLevel,
+    pub unread: usize,
+    pub pinned: bool,
+    pub archived: bool,
+    /// When their identity key changed, if it has since they were last
+    /// verified.
+    pub key_changed_at: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Their announced presence, if they share it with us.
+    pub presence: Option<PresenceStatus>,
+    pub note: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// What `whisper status` reports.
+#[derive(Debug, Serialize)]
+pub struct StatusOutput {
+    pub peer_id: String,
+    pub public_key: String,
+    pub contacts: usize,
+    pub reachability: &'static str,
+    /// When the reachability was last observed, if ever.
+    pub reachability_at: Option<DateTime<Utc>>,
+    pub data_dir: PathBuf,
+}
+
+/// What `whisper peers` reports.
+#[derive(Debug, Serialize)]
+pub struct PeersOutput {
+    pub contacts: Vec<PeerOutput>,
+    pub pending: Vec<PendingOutput>,
+    pub dead_letters: Vec<DeadLetterOutput>,
+}
+
+/// Who answered `whisper connect`.
+#[derive(Debug, Serialize)]
+pub struct ConnectOutput {
+    pub peer_id: String,
+    pub address: String,
+    /// Their alias, if they're a contact.
+    pub alias: Option<String>,
+}
+
+/// A known contact and when we last saw them.
+#[derive(Debug, Serialize)]
+pub struct PeerOutput {
+    pub alias: String,
+    pub peer_id: String,
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Their announced presence, if they share it with us.
+    pub presence: Option<PresenceStatus>,
+    /// Last measured round trip, in milliseconds.
+    pub latency_ms: Option<u64>,
+    /// Whether that round trip went through a relay.
+    pub relayed: Option<bool>,
+    /// The protocol version they last identified with.
+    pub protocol_version: Option<String>,
+    /// The software they last identified as running.
+    pub agent_version: Option<String>,
+}
+
+/// Messages waiting for a peer to connect.
+#[derive(Debug, Serialize)]
+pub struct PendingOutput {
+    pub peer_id: String,
+    pub alias: Option<String>,
+    pub messages: usize,
+}
+
+/// A queued message given up on after too many delivery attempts.
+#[derive(Debug, Serialize)]
+pub struct DeadLetterOutput {
+    pub id: String,
+    pub peer_id: String,
+    pub alias: Option<String>,
+    pub queued_at: DateTime<Utc>,
+    pub attempts: u32,
+    pub dead_at: DateTime<Utc>,
+}
+
+/// A conversation as listed by the control API's `GET /conversations`.
+#[derive(Debug, Serialize)]
+pub struct ConversationOutput {
+    /// Contact alias or group name.
+    pub name: String,
+    /// `direct` or `group`.
+    pub kind: &'static str,
+    /// Peer ID or group ID.
+    pub id: String,
+    pub unread: usize,
+    pub pinned: bool,
+    pub archived: bool,
+    pub last_activity: Option<DateTime<Utc>>,
+    /// Summary of the latest message.
+    pub preview: Option<String>,
+}
+
+/// Someone waiting in `whisper requests list`.
+#[derive(Debug, Serialize)]
+pub struct RequestOutput {
+    pub peer_id: String,
+    pub messages: usize,
+    pub latest: DateTime<Utc>,
+    /// Summary of their latest message.
+    pub preview: String,
+}
+
+/// What `whisper stats` reports. Byte counts are totals across sessions.
+#[derive(Debug, Serialize)]
+pub struct StatsOutput {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub protocols: Vec<TrafficOutput>,
+    pub contacts: Vec<TrafficOutput>,
+    /// Traffic with peers who aren't contacts, such as relays and
+    /// rendezvous points.
+    pub other_peers: TrafficOutput,
+    /// Messages waiting for their recipient to connect.
+    pub pending_messages: usize,
+}
+
+/// Bytes exchanged over one protocol, or with one contact.
+#[derive(Debug, Serialize)]
+pub struct TrafficOutput {
+    pub name: String,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// What `whisper audit` reports.
+#[derive(Debug, Serialize)]
+pub struct AuditLogOutput {
+    /// Whether every entry's hash matches the chain before it.
+    pub intact: bool,
+    /// The first entry that doesn't, if any.
+    pub altered_from: Option<i64>,
+    pub events: Vec<AuditOutput>,
+}
+
+/// One entry in the audit log.
+#[derive(Debug, Serialize)]
+pub struct AuditOutput {
+    pub id: i64,
+    pub at: DateTime<Utc>,
+    pub kind: &'static str,
+    pub peer_id: Option<String>,
+    /// The peer's alias, if they're a contact.
+    pub alias: Option<String>,
+    pub detail: String,
+}
+
+/// A group as listed by `whisper group list`.
+#[derive(Debug, Serialize)]
+pub struct GroupOutput {
+    pub id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub members: usize,
+    pub owner: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_output_formats() {
+        assert_eq!("json".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert_eq!("JSON".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert_eq!("text".parse::<OutputFormat>().unwrap(), OutputFormat::Text);
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn contact_output_serializes() {
+        let contact = ContactOutput {
+            alias: "alice".to_string(),
+            peer_id: "12D3KooW".to_string(),
+            trust: TrustLevel::Trusted,
+            unread: 2,
+            pinned: false,
+            archived: false,
+            key_changed_at: None,
+            last_seen: None,
+            presence: Some(PresenceStatus::Away),
+            note: None,
+            tags: vec!["work".to_string()],
+        };
+        let value = serde_json::to_value(&contact).unwrap();
+        assert_eq!(value["alias"], "alice");
+        assert_eq!(value["trust"], "Trusted");
+        assert_eq!(value["unread"], 2);
+        assert!(value["last_seen"].is_null());
+        assert_eq!(value["presence"], "away");
+        assert_eq!(value["tags"][0], "work");
+    }
+}
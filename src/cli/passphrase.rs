@@ -0,0 +1,126 @@
+//! Asking for the passphrase when neither `--passphrase` nor
+//! `WHISPER_PASSPHRASE` gives one.
+//!
+//! A new passphrase is typed twice; an existing one is checked against the
+//! keypair file, with a few tries before giving up. Input is hidden, and
+//! read from the controlling terminal rather than stdin, so a message can
+//! still be piped in. With no terminal at all, the passphrase has to be
+//! given with `--passphrase` or `WHISPER_PASSPHRASE`.
+
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use zeroize::Zeroizing;
+
+use super::commands::{finish_interrupted_rekey, keypair_path};
+use crate::identity::load_keypair;
+
+/// Tries at a new passphrase or an existing one before giving up.
+const ATTEMPTS: usize = 3;
+
+/// What a command needs the passphrase for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PassphraseUse {
+    /// Encrypting a new identity
+    New,
+    /// Opening the identity in the data directory
+    Existing,
+    /// Nothing
+    Unused,
+}
+
+/// Ask for the passphrase on the terminal.
+pub fn ask_passphrase(data_dir: &Path, usage: PassphraseUse) -> Result<Zeroizing<String>> {
+    let prompt = |text: &str| rpassword::prompt_password(text).map(Zeroizing::new);
+    match usage {
+        PassphraseUse::Unused => Ok(Zeroizing::default()),
+        // Commands bail with "No identity found" on their own
+        PassphraseUse::Existing if !keypair_path(data_dir).exists() => Ok(Zeroizing::default()),
+        _ if !has_terminal() => anyhow::bail!("{}", NO_TERMINAL),
+        PassphraseUse::Existing => existing_passphrase(data_dir, prompt),
+        PassphraseUse::New => new_passphrase(prompt),
+    }
+}
+
+/// Ask for a passphrase to change to.
+pub fn ask_new_passphrase() -> Result<Zeroizing<String>> {
+    if !has_terminal() {
+        anyhow::bail!("A new passphrase can only be typed in on a terminal");
+    }
+    new_passphrase(|text: &str| rpassword::prompt_password(text).map(Zeroizing::new))
+}
+
+/// Why we can't ask, with no terminal to ask on.
+const NO_TERMINAL: &str = "No terminal to ask for the passphrase on. Pass --passphrase or set WHISPER_PASSPHRASE";
+
+/// Whether there's a terminal to ask on. The prompt reads from the
+/// controlling terminal, not stdin, so that's what has to open.
+fn has_terminal() -> bool {
+    #[cfg(windows)]
+    let terminal = "CONIN$";
+    #[cfg(not(windows))]
+    let terminal = "/dev/tty";
+    std::fs::OpenOptions::new().read(true).write(true).open(terminal).is_ok()
+}
+
+/// Ask for a new passphrase twice, until both match.
+fn new_passphrase(mut prompt: impl FnMut(&str) -> io::Result<Zeroizing<String>>) -> Result<Zeroizing<String>> {
+    for _ in 0..ATTEMPTS {
+        let passphrase = prompt("New passphrase: ").context("Failed to read passphrase")?;
+        if passphrase.is_empty() {
+            eprintln!("Without a passphrase your identity key is only as safe as this disk.");
+        }
+        let again = prompt("Repeat passphrase: ").context("Failed to read passphrase")?;
+        if passphrase == again {
+            return Ok(passphrase);
+        }
+        eprintln!("Passphrases don't match. Try again.");
+    }
+    anyhow::bail!("Passphrases didn't match")
+}
+
+/// Ask for the passphrase until it opens the keypair in `data_dir`.
+fn existing_passphrase(
+    data_dir: &Path,
+    mut prompt: impl FnMut(&str) -> io::Result<Zeroizing<String>>,
+) -> Result<Zeroizing<String>> {
+    let key_path = keypair_path(data_dir);
+    for _ in 0..ATTEMPTS {
+        let passphrase = prompt("Passphrase: ").context("Failed to read passphrase")?;
+        finish_interrupted_rekey(data_dir, &passphrase);
+        if load_keypair(&key_path, &passphrase).is_ok() {
+            return Ok(passphrase);
+        }
+        eprintln!("Wrong passphrase. Try again.");
+    }
+    anyhow::bail!("Wrong passphrase")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::init_identity;
+    use tempfile::TempDir;
+
+    /// A prompt that gives these answers in turn.
+    fn answers(answers: &[&str]) -> impl FnMut(&str) -> io::Result<Zeroizing<String>> {
+        let mut answers: Vec<String> = answers.iter().rev().map(|a| a.to_string()).collect();
+        move |_| Ok(Zeroizing::new(answers.pop().expect("asked too many times")))
+    }
+
+    #[test]
+    fn new_passphrase_must_be_typed_twice() {
+        assert_eq!(*new_passphrase(answers(&["one", "two", "three", "three"])).unwrap(), "three");
+        assert!(new_passphrase(answers(&["a", "b", "c", "d", "e", "f"])).is_err());
+    }
+
+    #[test]
+    fn existing_passphrase_is_retried_until_it_opens_the_keypair() {
+        let dir = TempDir::new().unwrap();
+        init_identity(dir.path(), "right").unwrap();
+
+        assert_eq!(*existing_passphrase(dir.path(), answers(&["wrong", "right"])).unwrap(), "right");
+        assert!(existing_passphrase(dir.path(), answers(&["a", "b", "c"])).is_err());
+    }
+}
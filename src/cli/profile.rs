@@ -0,0 +1,177 @@
+//! Identity profiles: separate keypairs and databases under one data directory.
+//!
+//! The default profile lives directly in the data directory; named profiles
+//! live in `profiles/<name>` beneath it. `whisper profile switch` records
+//! the active profile so later commands pick it up without `--profile`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use super::commands::{handle_init, keypair_path};
+
+/// Name of the profile stored directly in the data directory.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Subdirectory holding named profiles.
+const PROFILES_DIR: &str = "profiles";
+
+/// File recording the active profile.
+const CURRENT_PROFILE_FILE: &str = "current_profile";
+
+/// Check a profile name is safe to use as a directory name.
+fn validate_profile_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        anyhow::bail!("Invalid profile name '{}': use letters, digits, - and _", name);
+    }
+    Ok(())
+}
+
+/// Directory for a profile.
+pub fn profile_dir(base_dir: &Path, name: &str) -> PathBuf {
+    if name == DEFAULT_PROFILE {
+        base_dir.to_path_buf()
+    } else {
+        base_dir.join(PROFILES_DIR).join(name)
+    }
+}
+
+/// The profile chosen with `whisper profile switch`, or the default.
+pub fn current_profile(base_dir: &Path) -> String {
+    fs::read_to_string(base_dir.join(CURRENT_PROFILE_FILE))
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| validate_profile_name(name).is_ok())
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_string())
+}
+
+/// Resolve the data directory for a command: the `--profile` given, else
+/// the active profile.
+pub fn resolve_data_dir(base_dir: &Path, profile: Option<&str>) -> Result<PathBuf> {
+    let name = match profile {
+        Some(name) => {
+            validate_profile_name(name)?;
+            name.to_string()
+        }
+        None => current_profile(base_dir),
+    };
+    Ok(profile_dir(base_dir, &name))
+}
+
+/// Names of all profiles, default first.
+fn list_profiles(base_dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let dir = base_dir.join(PROFILES_DIR);
+    if dir.exists() {
+        for entry in fs::read_dir(&dir).context("Failed to read profiles directory")? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+    }
+    names.sort();
+    names.insert(0, DEFAULT_PROFILE.to_string());
+    Ok(names)
+}
+
+/// List profiles, marking the active one.
+pub async fn handle_profile_list(base_dir: &Path) -> Result<()> {
+    let current = current_profile(base_dir);
+
+    println!("Profiles:");
+    for name in list_profiles(base_dir)? {
+        let marker = if name == current { "*" } else { " " };
+        let state = if keypair_path(&profile_dir(base_dir, &name)).exists() {
+            ""
+        } else {
+            " (no identity)"
+        };
+        println!("{} {}{}", marker, name, state);
+    }
+
+    Ok(())
+}
+
+/// Create a profile with a fresh identity.
+pub async fn handle_profile_create(name: &str, base_dir: &Path, passphrase: &str) -> Result<()> {
+    validate_profile_name(name)?;
+    if name == DEFAULT_PROFILE {
+        anyhow::bail!("The default profile always exists. Run: whisper init");
+    }
+
+    let dir = profile_dir(base_dir, name);
+    if keypair_path(&dir).exists() {
+        anyhow::bail!("Profile '{}' already exists", name);
+    }
+
+    println!("Creating profile '{}'", name);
+    handle_init(&dir, passphrase).await?;
+    println!("Use it with: whisper --profile {} ... or whisper profile switch {}", name, name);
+
+    Ok(())
+}
+
+/// Make a profile the active one.
+pub async fn handle_profile_switch(name: &str, base_dir: &Path) -> Result<()> {
+    validate_profile_name(name)?;
+    if name != DEFAULT_PROFILE && !profile_dir(base_dir, name).exists() {
+        anyhow::bail!("Profile '{}' not found. Create it with: whisper profile create {}", name, name);
+    }
+
+    fs::create_dir_all(base_dir).context("Failed to create data directory")?;
+    fs::write(base_dir.join(CURRENT_PROFILE_FILE), name).context("Failed to save active profile")?;
+    println!("Switched to profile '{}'", name);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn default_profile_is_the_data_dir() {
+        let base = Path::new("/tmp/whisper");
+        assert_eq!(resolve_data_dir(base, None).unwrap(), base);
+        assert_eq!(
+            resolve_data_dir(base, Some("work")).unwrap(),
+            PathBuf::from("/tmp/whisper/profiles/work")
+        );
+    }
+
+    #[test]
+    fn profile_names_are_validated() {
+        let base = Path::new("/tmp/whisper");
+        assert!(resolve_data_dir(base, Some("../escape")).is_err());
+        assert!(resolve_data_dir(base, Some("")).is_err());
+        assert!(resolve_data_dir(base, Some("work_2-b")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn create_and_switch_profiles() {
+        let temp = TempDir::new().unwrap();
+        let base = temp.path();
+
+        handle_profile_create("work", base, "test").await.unwrap();
+        assert!(keypair_path(&base.join("profiles/work")).exists());
+        assert!(handle_profile_create("work", base, "test").await.is_err());
+        assert_eq!(list_profiles(base).unwrap(), vec!["default", "work"]);
+
+        assert!(handle_profile_switch("home", base).await.is_err());
+        handle_profile_switch("work", base).await.unwrap();
+        assert_eq!(current_profile(base), "work");
+        assert_eq!(resolve_data_dir(base, None).unwrap(), base.join("profiles/work"));
+
+        // An explicit --profile wins over the active one
+        assert_eq!(resolve_data_dir(base, Some(DEFAULT_PROFILE)).unwrap(), base);
+
+        handle_profile_switch(DEFAULT_PROFILE, base).await.unwrap();
+        assert_eq!(resolve_data_dir(base, None).unwrap(), base);
+    }
+}
@@ -0,0 +1,112 @@
+//! Posting incoming messages to the webhook set with `whisper webhook`.
+//!
+//! Each message `whisper listen` receives in a conversation the webhook
+//! wants is sent as one JSON POST, the same object `listen --json`
+//! prints. Posts happen in the background and aren't retried: a webhook
+//! that's down misses messages rather than holding up the node. With a
+//! SOCKS proxy set, posts go through it like every other connection.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use crate::message::Recipient;
+use crate::storage::Webhook;
+
+/// How long a post may take before it's given up on.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Check that `url` is something we can post to.
+pub fn parse_webhook_url(url: &str) -> Result<reqwest::Url> {
+    let parsed = reqwest::Url::parse(url).with_context(|| format!("'{}' is not a URL", url))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        bail!("Webhooks must be http:// or https:// URLs, not {}://", parsed.scheme());
+    }
+    Ok(parsed)
+}
+
+/// Posts messages to a webhook.
+pub struct WebhookSender {
+    webhook: Webhook,
+    client: reqwest::Client,
+}
+
+impl WebhookSender {
+    /// A sender for `webhook`, connecting through `proxy` if there is one.
+    pub fn new(webhook: Webhook, proxy: Option<SocketAddr>) -> Result<Self> {
+        parse_webhook_url(&webhook.url)?;
+        let mut client = reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT);
+        if let Some(proxy) = proxy {
+            // socks5h, so the proxy resolves the host name
+            let proxy = reqwest::Proxy::all(format!("socks5h://{}", proxy)).context("Bad SOCKS proxy")?;
+            client = client.proxy(proxy);
+        }
+        let client = client.build().context("Failed to set up the webhook client")?;
+        Ok(Self { webhook, client })
+    }
+
+    /// Post `json`, a message in `conversation`, if the webhook wants it.
+    /// Failures are reported on stderr.
+    pub fn post(&self, conversation: &Recipient, json: String) {
+        if !self.webhook.wants(conversation) {
+            return;
+        }
+        let request = self
+            .client
+            .post(&self.webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(json);
+        tokio::spawn(async move {
+            match request.send().await.and_then(|response| response.error_for_status()) {
+                Ok(_) => {}
+                Err(e) => eprintln!("Webhook failed: {}", e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::PeerId;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn only_http_urls_are_accepted() {
+        assert!(parse_webhook_url("https://ntfy.sh/whisper").is_ok());
+        assert!(parse_webhook_url("http://127.0.0.1:8123/api/webhook/x").is_ok());
+        assert!(parse_webhook_url("ftp://example.com").is_err());
+        assert!(parse_webhook_url("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn posts_wanted_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let alice = Recipient::Direct(PeerId::random());
+        let webhook = Webhook { url, only: vec![alice] };
+        let sender = WebhookSender::new(webhook, None).unwrap();
+
+        // Not wanted, so never arrives ahead of the one that is
+        sender.post(&Recipient::Direct(PeerId::random()), r#"{"text":"skip"}"#.to_string());
+        sender.post(&alice, r#"{"text":"hi"}"#.to_string());
+
+        let (mut stream, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .expect("webhook should be posted")
+            .unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !String::from_utf8_lossy(&request).ends_with(r#"{"text":"hi"}"#) {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "request ended early");
+            request.extend_from_slice(&buf[..n]);
+        }
+        let request = String::from_utf8_lossy(&request);
+        assert!(request.starts_with("POST /hook HTTP/1.1"));
+        assert!(request.to_ascii_lowercase().contains("content-type: application/json"));
+        stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+//! Contact cards, shared by the CLI and `WhisperClient`.
+//!
+//! A card introduces one of the sender's contacts to the recipient. It's
+//! kept until the user adds the contact or declines, and only if the key
+//! it carries is the one behind the peer ID it names.
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use libp2p::identity::{ed25519, PublicKey};
+use libp2p::PeerId;
+
+use crate::identity::Contact;
+use crate::message::{MessageContent, PendingContactCard};
+use crate::storage::Database;
+
+/// A card introducing `contact`. Contacts added by peer ID alone get the
+/// key embedded in it.
+pub(crate) fn contact_card(contact: &Contact) -> Result<MessageContent> {
+    let public_key = if contact.public_key.is_empty() {
+        PublicKey::try_decode_protobuf(contact.peer_id.as_ref().digest())
+            .ok()
+            .and_then(|key| key.try_into_ed25519().ok())
+            .map(|key| key.to_bytes().to_vec())
+            .with_context(|| format!("No identity key known for '{}'", contact.alias))?
+    } else {
+        contact.public_key.clone()
+    };
+    Ok(MessageContent::ContactCard {
+        peer_id: contact.peer_id,
+        public_key,
+        alias: contact.alias.clone(),
+    })
+}
+
+/// Keep a card `from` sent until the user adds the contact or declines.
+/// Returns `None` for cards naming us or an existing contact, and for
+/// keys that don't match the peer ID.
+pub(crate) fn receive_contact_card(
+    db: &Database,
+    our_peer_id: &PeerId,
+    from: PeerId,
+    peer_id: &PeerId,
+    public_key: &[u8],
+    alias: &str,
+) -> Result<Option<PendingContactCard>> {
+    let matches = ed25519::PublicKey::try_from_bytes(public_key)
+        .is_ok_and(|key| PublicKey::from(key).to_peer_id() == *peer_id);
+    if !matches || peer_id == our_peer_id || db.get_contact(peer_id)?.is_some() {
+        return Ok(None);
+    }
+    let card = PendingContactCard {
+        peer_id: *peer_id,
+        public_key: public_key.to_vec(),
+        alias: alias.to_string(),
+        from,
+        received_at: Utc::now(),
+    };
+    db.save_contact_card(&card)?;
+    Ok(Some(card))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    #[test]
+    fn cards_are_kept_only_for_new_contacts_with_matching_keys() {
+        let db = Database::open_in_memory().unwrap();
+        let (us, alice) = (PeerId::random(), PeerId::random());
+        let carol = Keypair::generate_ed25519();
+        let carol_id = carol.public().to_peer_id();
+
+        // Added by peer ID alone, so the key comes from the peer ID
+        let MessageContent::ContactCard { peer_id, public_key, alias } =
+            contact_card(&Contact::new(carol_id, "carol".to_string(), Vec::new())).unwrap()
+        else {
+            panic!("not a contact card");
+        };
+        assert_eq!(public_key, carol.public().try_into_ed25519().unwrap().to_bytes().to_vec());
+
+        let other_key = Keypair::generate_ed25519().public().try_into_ed25519().unwrap().to_bytes();
+        assert_eq!(receive_contact_card(&db, &us, alice, &peer_id, &other_key, &alias).unwrap(), None);
+        assert_eq!(receive_contact_card(&db, &carol_id, alice, &peer_id, &public_key, &alias).unwrap(), None);
+
+        let card = receive_contact_card(&db, &us, alice, &peer_id, &public_key, &alias).unwrap().unwrap();
+        assert_eq!((card.peer_id, card.alias.as_str(), card.from), (carol_id, "carol", alice));
+        assert_eq!(db.list_contact_cards().unwrap().len(), 1);
+
+        db.upsert_contact(&Contact::new(carol_id, "carol".to_string(), public_key.clone())).unwrap();
+        assert_eq!(receive_contact_card(&db, &us, alice, &peer_id, &public_key, &alias).unwrap(), None);
+        assert!(db.delete_contact_card(&carol_id).unwrap());
+        assert!(db.list_contact_cards().unwrap().is_empty());
+    }
+}
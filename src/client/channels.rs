@@ -0,0 +1,189 @@
+//! Broadcast channels, shared by the CLI and `WhisperClient`.
+//!
+//! Posts go out over gossipsub, on a topic per channel that the owner and
+//! every subscriber join. Anyone with a channel's code can subscribe by
+//! telling its owner, who sends them the channel's recent posts, and again
+//! whenever they rejoin the topic, to cover what they missed while away.
+//! Posts are checked against the owner's key before they're stored, so
+//! they can arrive from anyone.
+
+use anyhow::Result;
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use uuid::Uuid;
+
+use super::session::{seal_for_contact, EncryptionKeys};
+use crate::message::{Channel, ChannelPost, Envelope, MessageContent};
+use crate::network::NodeHandle;
+use crate::storage::Database;
+
+/// How many recent posts a subscriber is sent when they subscribe or
+/// rejoin the topic.
+pub(crate) const CATCH_UP_POSTS: usize = 20;
+
+/// Join the topic of every channel we own or subscribe to.
+pub(crate) fn join_channels(db: &Database, node: &NodeHandle) -> Result<()> {
+    for channel in db.list_channels()? {
+        node.join_channel(channel.id);
+    }
+    Ok(())
+}
+
+/// Publish one of our posts on its channel's topic.
+pub(crate) fn publish_channel_post(node: &NodeHandle, keypair: &Keypair, post: &ChannelPost) -> Result<()> {
+    let wire = Envelope::new(PeerId::from(keypair.public()), MessageContent::ChannelPost(post.clone()))
+        .encode_signed(keypair)?;
+    node.publish_post(post.channel_id, wire);
+    Ok(())
+}
+
+/// The post in what came in on `channel_id`'s topic, if it's one for that
+/// channel. It still has to be checked against the owner's key.
+pub(crate) fn gossiped_post(channel_id: &Uuid, data: &[u8]) -> Option<ChannelPost> {
+    match Envelope::decode(data).ok()?.payload {
+        MessageContent::ChannelPost(post) if post.channel_id == *channel_id => Some(post),
+        _ => None,
+    }
+}
+
+/// `post` sealed for `subscriber`, ready to send or queue.
+pub(crate) fn seal_channel_post(
+    db: &Database,
+    keypair: &Keypair,
+    our_keys: EncryptionKeys,
+    post: &ChannelPost,
+    subscriber: &PeerId,
+) -> Result<Vec<u8>> {
+    let public_key = db.get_contact(subscriber)?.map(|contact| contact.public_key).unwrap_or_default();
+    let wire = Envelope::new(PeerId::from(keypair.public()), MessageContent::ChannelPost(post.clone()))
+        .encode_signed(keypair)?;
+    seal_for_contact(db, our_keys, subscriber, &public_key, &wire)
+}
+
+/// Add `from` as a subscriber to one of our channels, sending them its
+/// recent posts. Returns whether they're new.
+pub(crate) fn receive_channel_subscribe(
+    db: &Database,
+    node: &NodeHandle,
+    keypair: &Keypair,
+    our_keys: EncryptionKeys,
+    from: &PeerId,
+    channel_id: &Uuid,
+) -> Result<bool> {
+    let ours = db.get_channel(channel_id)?.is_some_and(|channel| channel.owner == node.peer_id());
+    if !ours || !db.add_channel_subscriber(channel_id, from)? {
+        return Ok(false);
+    }
+    send_recent_posts(db, node, keypair, our_keys, from, channel_id)?;
+    Ok(true)
+}
+
+/// A peer joined the topic of one of our channels. Subscribers are sent
+/// its recent posts, in case they missed any while away. Returns whether
+/// they were.
+pub(crate) fn receive_channel_joined(
+    db: &Database,
+    node: &NodeHandle,
+    keypair: &Keypair,
+    our_keys: EncryptionKeys,
+    peer: &PeerId,
+    channel_id: &Uuid,
+) -> Result<bool> {
+    let ours = db.get_channel(channel_id)?.is_some_and(|channel| channel.owner == node.peer_id());
+    if !ours || !db.channel_subscribers(channel_id)?.contains(peer) {
+        return Ok(false);
+    }
+    send_recent_posts(db, node, keypair, our_keys, peer, channel_id)?;
+    Ok(true)
+}
+
+/// Send a subscriber a channel's latest posts directly.
+fn send_recent_posts(
+    db: &Database,
+    node: &NodeHandle,
+    keypair: &Keypair,
+    our_keys: EncryptionKeys,
+    subscriber: &PeerId,
+    channel_id: &Uuid,
+) -> Result<()> {
+    for post in db.channel_posts(channel_id, CATCH_UP_POSTS)? {
+        node.send_message(*subscriber, seal_channel_post(db, keypair, our_keys, &post, subscriber)?);
+    }
+    Ok(())
+}
+
+/// Store a post to a channel we subscribe to, if its owner signed it.
+/// Returns the channel if the post is new.
+pub(crate) fn receive_channel_post(db: &Database, post: &ChannelPost) -> Result<Option<Channel>> {
+    let Some(channel) = db.get_channel(&post.channel_id)? else {
+        return Ok(None);
+    };
+    if !post.verify(&channel) || !db.store_channel_post(post)? {
+        return Ok(None);
+    }
+    Ok(Some(channel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn posts_are_kept_only_from_the_owner() {
+        let db = Database::open_in_memory().unwrap();
+        let (owner, other) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let channel = Channel::new("news".to_string(), PeerId::from(owner.public()));
+        let post = ChannelPost::sign(&owner, &channel, "first post".to_string()).unwrap();
+
+        assert!(receive_channel_post(&db, &post).unwrap().is_none(), "not subscribed");
+        db.add_channel(&channel).unwrap();
+
+        // Someone else's post, passed off as the owner's
+        let impostor = Channel { owner: PeerId::from(other.public()), ..channel.clone() };
+        let forged = ChannelPost::sign(&other, &impostor, "fake news".to_string()).unwrap();
+        assert!(receive_channel_post(&db, &forged).unwrap().is_none());
+
+        assert_eq!(receive_channel_post(&db, &post).unwrap(), Some(channel.clone()));
+        assert!(receive_channel_post(&db, &post).unwrap().is_none(), "seen it");
+        assert_eq!(db.channel_posts(&channel.id, 10).unwrap(), vec![post]);
+    }
+
+    #[test]
+    fn gossiped_posts_must_be_for_the_topic() {
+        let owner = Keypair::generate_ed25519();
+        let channel = Channel::new("news".to_string(), PeerId::from(owner.public()));
+        let post = ChannelPost::sign(&owner, &channel, "first post".to_string()).unwrap();
+        let wire = Envelope::new(channel.owner, MessageContent::ChannelPost(post.clone()))
+            .encode_signed(&owner)
+            .unwrap();
+
+        assert_eq!(gossiped_post(&channel.id, &wire), Some(post));
+        assert!(gossiped_post(&Uuid::new_v4(), &wire).is_none(), "posted on another channel's topic");
+        let text = Envelope::new(channel.owner, MessageContent::Text("hi".to_string())).encode().unwrap();
+        assert!(gossiped_post(&channel.id, &text).is_none());
+        assert!(gossiped_post(&channel.id, b"junk").is_none());
+    }
+
+    #[tokio::test]
+    async fn only_subscribers_rejoining_our_channels_catch_up() {
+        let db = Database::open_in_memory().unwrap();
+        let owner = Keypair::generate_ed25519();
+        let node = crate::network::WhisperNode::new(owner.clone()).await.unwrap().spawn();
+        let keys = crate::crypto::keypair_to_encryption_keys(&owner).unwrap();
+        let our_keys = (&keys.0, &keys.1);
+        let (subscriber, stranger) = (PeerId::random(), PeerId::random());
+
+        let ours = Channel::new("news".to_string(), node.peer_id());
+        let theirs = Channel::new("elsewhere".to_string(), PeerId::random());
+        db.add_channel(&ours).unwrap();
+        db.add_channel(&theirs).unwrap();
+        db.add_channel_subscriber(&ours.id, &subscriber).unwrap();
+        db.store_channel_post(&ChannelPost::sign(&owner, &ours, "first post".to_string()).unwrap()).unwrap();
+
+        assert!(receive_channel_joined(&db, &node, &owner, our_keys, &subscriber, &ours.id).unwrap());
+        assert!(!receive_channel_joined(&db, &node, &owner, our_keys, &stranger, &ours.id).unwrap());
+        assert!(!receive_channel_joined(&db, &node, &owner, our_keys, &subscriber, &theirs.id).unwrap());
+
+        node.shutdown();
+    }
+}
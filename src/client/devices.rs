@@ -0,0 +1,261 @@
+//! Linked devices, shared by the CLI and `WhisperClient`.
+//!
+//! Device lists are announced to contacts when they connect, and to the
+//! devices on them, so everyone sending to an identity also reaches its
+//! other devices. Envelopes a listed device signs are taken as coming from
+//! the identity once their signature and sequence number have been checked
+//! against the device itself.
+//!
+//! An identity's own devices also keep each other's history: when two of
+//! them connect, each offers the IDs of the messages it has and the other
+//! sends back what's missing, so a newly linked device catches up on every
+//! conversation.
+
+use anyhow::Result;
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use uuid::Uuid;
+
+use super::history::{merge_into, send_history};
+use super::session::{seal_for_contact, EncryptionKeys};
+use crate::identity::TrustLevel;
+use crate::message::{diff_messages, DeviceList, Envelope, ExportedMessage, MessageContent, Recipient};
+use crate::network::NodeHandle;
+use crate::storage::Database;
+
+/// Send `peer` the device list of the identity we belong to, if it has
+/// one and they're a contact or one of its devices. Returns whether it
+/// was sent.
+pub(crate) fn announce_devices(
+    db: &Database,
+    node: &NodeHandle,
+    keypair: &Keypair,
+    our_keys: EncryptionKeys,
+    peer: &PeerId,
+) -> Result<bool> {
+    let identity = db.linked_to()?.unwrap_or(node.peer_id());
+    let Some(list) = db.device_list(&identity)? else {
+        return Ok(false);
+    };
+    let contact = db.get_contact(peer)?;
+    let wanted = list.device_ids().contains(peer) || *peer == identity
+        || contact.as_ref().is_some_and(|contact| contact.trust_level != TrustLevel::Blocked);
+    if !wanted {
+        return Ok(false);
+    }
+
+    let public_key = contact.map(|contact| contact.public_key).unwrap_or_default();
+    let wire = Envelope::new(node.peer_id(), MessageContent::DeviceList(list)).encode_signed(keypair)?;
+    node.send_message(*peer, seal_for_contact(db, our_keys, peer, &public_key, &wire)?);
+    Ok(true)
+}
+
+/// Store a device list someone sent us, if it's genuine, newer than the
+/// one we have, and belongs to a contact or the identity we joined.
+/// Returns whether it was stored.
+pub(crate) fn receive_device_list(db: &Database, list: &DeviceList) -> Result<bool> {
+    if !list.verify() {
+        return Ok(false);
+    }
+    let known = db
+        .get_contact(&list.identity)?
+        .is_some_and(|contact| contact.trust_level != TrustLevel::Blocked)
+        || db.linked_to()? == Some(list.identity);
+    if !known {
+        return Ok(false);
+    }
+    db.save_device_list(list)
+}
+
+/// `wire` sealed for each of `peer`'s linked devices, to send alongside
+/// the copy for `peer` itself.
+pub(crate) fn sealed_for_devices(
+    db: &Database,
+    our_keys: EncryptionKeys,
+    peer: &PeerId,
+    wire: &[u8],
+) -> Result<Vec<(PeerId, Vec<u8>)>> {
+    db.linked_devices(peer)?
+        .into_iter()
+        .map(|device| Ok((device, seal_for_contact(db, our_keys, &device, &[], wire)?)))
+        .collect()
+}
+
+/// Take an envelope signed by a linked device as coming from the device's
+/// identity. Call it only after checking the signature and replay.
+pub(crate) fn attribute_device(db: &Database, envelope: &mut Envelope) {
+    if let Ok(Some(identity)) = db.device_identity(&envelope.sender) {
+        envelope.sender = identity;
+    }
+}
+
+/// The other devices of the identity we belong to, primary included.
+pub(crate) fn own_devices(db: &Database, our_peer_id: &PeerId) -> Result<Vec<PeerId>> {
+    let identity = db.linked_to()?.unwrap_or(*our_peer_id);
+    let mut devices = db.linked_devices(&identity)?;
+    devices.push(identity);
+    devices.retain(|device| device != our_peer_id);
+    Ok(devices)
+}
+
+/// Offer `peer` our message IDs, if it's one of our own devices. Those we
+/// deleted are offered too, so it doesn't send them back. Returns whether
+/// it was offered.
+pub(crate) fn offer_history(
+    db: &Database,
+    node: &NodeHandle,
+    keypair: &Keypair,
+    our_keys: EncryptionKeys,
+    peer: &PeerId,
+) -> Result<bool> {
+    if !own_devices(db, &node.peer_id())?.contains(peer) {
+        return Ok(false);
+    }
+    let mut ids: Vec<Uuid> = db.all_messages()?.into_iter().map(|msg| msg.id).collect();
+    ids.extend(db.deleted_message_ids()?);
+    let wire = Envelope::new(node.peer_id(), MessageContent::HistoryOffer(ids)).encode_signed(keypair)?;
+    node.send_message(*peer, seal_for_contact(db, our_keys, peer, &[], &wire)?);
+    Ok(true)
+}
+
+/// Send one of our own devices the messages its offer is missing. Returns
+/// how many were sent.
+pub(crate) fn send_missing_history(
+    db: &Database,
+    node: &NodeHandle,
+    keypair: &Keypair,
+    our_keys: EncryptionKeys,
+    from: &PeerId,
+    their_ids: &[Uuid],
+) -> Result<usize> {
+    let our_peer_id = node.peer_id();
+    if !own_devices(db, &our_peer_id)?.contains(from) {
+        return Ok(0);
+    }
+    let local = db.all_messages()?;
+    let missing = diff_messages(&local, their_ids);
+    send_history(db, node, keypair, our_keys, from, &[], &missing)
+}
+
+/// Merge history one of our own devices sent, taking its messages as ours
+/// where it sent or received them. Returns (new messages, updated
+/// statuses), or `None` if `from` isn't one of our devices.
+pub(super) fn merge_own_history(
+    db: &Database,
+    our_peer_id: &PeerId,
+    from: &PeerId,
+    messages: &[ExportedMessage],
+) -> Result<Option<(usize, usize)>> {
+    let mut ours = own_devices(db, our_peer_id)?;
+    if !ours.contains(from) {
+        return Ok(None);
+    }
+    if let Some(identity) = db.linked_to()? {
+        ours.push(identity);
+    }
+
+    let remote = messages
+        .iter()
+        .filter_map(|exported| exported.clone().into_message().ok())
+        .map(|mut msg| {
+            if ours.contains(&msg.from) {
+                msg.from = *our_peer_id;
+            }
+            if matches!(msg.to, Recipient::Direct(peer) if ours.contains(&peer)) {
+                msg.to = Recipient::Direct(*our_peer_id);
+            }
+            msg
+        })
+        .collect();
+    merge_into(db, remote).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keypair_to_encryption_keys;
+    use crate::identity::Contact;
+    use crate::message::{DeviceLink, LinkedDevice, Message, MessageStatus};
+
+    #[test]
+    fn device_lists_are_kept_for_contacts_only() {
+        let db = Database::open_in_memory().unwrap();
+        let (primary, laptop) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let (identity, device) = (primary.public().to_peer_id(), laptop.public().to_peer_id());
+        let linked = LinkedDevice {
+            name: "laptop".to_string(),
+            link: DeviceLink::new(&laptop, identity).unwrap(),
+        };
+        let list = DeviceList::sign(&primary, vec![linked]).unwrap();
+
+        assert!(!receive_device_list(&db, &list).unwrap(), "stranger");
+
+        let public_key = primary.public().try_into_ed25519().unwrap().to_bytes().to_vec();
+        db.upsert_contact(&Contact::new(identity, "alice".to_string(), public_key)).unwrap();
+        let mut forged = list.clone();
+        forged.devices[0].name = "phone".to_string();
+        assert!(!receive_device_list(&db, &forged).unwrap(), "forged");
+        assert!(receive_device_list(&db, &list).unwrap());
+
+        let mut envelope = Envelope::new(device, MessageContent::Text("from my laptop".to_string()));
+        attribute_device(&db, &mut envelope);
+        assert_eq!(envelope.sender, identity);
+        let our_keys = keypair_to_encryption_keys(&Keypair::generate_ed25519()).unwrap();
+        let sealed = sealed_for_devices(&db, (&our_keys.0, &our_keys.1), &identity, b"hi").unwrap();
+        assert_eq!(sealed.iter().map(|(peer_id, _)| *peer_id).collect::<Vec<_>>(), vec![device]);
+    }
+
+    #[test]
+    fn history_from_our_own_devices_is_merged_as_ours() {
+        let (primary, laptop, bob) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let (phone_db, laptop_db) = (Database::open_in_memory().unwrap(), Database::open_in_memory().unwrap());
+        laptop_db.set_linked_to(Some(&primary)).unwrap();
+
+        let sent = Message::new_text(primary, Recipient::Direct(bob), "sent from my phone".to_string());
+        let mut received = Message::new_text(bob, Recipient::Direct(primary), "hi".to_string());
+        received.status = MessageStatus::Read;
+        phone_db.insert_message(&sent).unwrap();
+        phone_db.insert_message(&received).unwrap();
+        // The laptop has one of them already, less far along
+        received.status = MessageStatus::Delivered;
+        laptop_db.insert_message(&received).unwrap();
+
+        let ids: Vec<Uuid> = laptop_db.all_messages().unwrap().iter().map(|m| m.id).collect();
+        let phone_messages = phone_db.all_messages().unwrap();
+        let missing: Vec<ExportedMessage> = phone_messages
+            .iter()
+            .map(|msg| ExportedMessage::new(msg, &primary))
+            .collect();
+        assert_eq!(diff_messages(&phone_messages, &ids).len(), 1);
+
+        assert_eq!(merge_own_history(&laptop_db, &laptop, &PeerId::random(), &missing).unwrap(), None, "stranger");
+        assert_eq!(merge_own_history(&laptop_db, &laptop, &primary, &missing).unwrap(), Some((1, 1)));
+
+        let synced = laptop_db.get_message(&sent.id).unwrap().unwrap();
+        assert_eq!((synced.from, synced.to), (laptop, Recipient::Direct(bob)));
+        assert_eq!(laptop_db.get_message(&received.id).unwrap().unwrap().status, MessageStatus::Read);
+    }
+
+    #[test]
+    fn messages_deleted_on_one_device_stay_deleted() {
+        let (primary, laptop, bob) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let (phone_db, laptop_db) = (Database::open_in_memory().unwrap(), Database::open_in_memory().unwrap());
+        laptop_db.set_linked_to(Some(&primary)).unwrap();
+
+        let msg = Message::new_text(bob, Recipient::Direct(primary), "delete me".to_string());
+        phone_db.insert_message(&msg).unwrap();
+        laptop_db.insert_message(&msg).unwrap();
+        assert!(laptop_db.delete_message(&msg.id).unwrap());
+
+        // The laptop's offer still names it, so the phone has nothing to send
+        let mut offered: Vec<Uuid> = laptop_db.all_messages().unwrap().iter().map(|m| m.id).collect();
+        offered.extend(laptop_db.deleted_message_ids().unwrap());
+        let phone_messages = phone_db.all_messages().unwrap();
+        assert!(diff_messages(&phone_messages, &offered).is_empty());
+
+        // And it isn't restored if the phone sends it anyway
+        let history = vec![ExportedMessage::new(&msg, &primary)];
+        assert_eq!(merge_own_history(&laptop_db, &laptop, &primary, &history).unwrap(), Some((0, 0)));
+        assert!(laptop_db.get_message(&msg.id).unwrap().is_none());
+    }
+}
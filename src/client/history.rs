@@ -0,0 +1,222 @@
+//! History sync with contacts, shared by the CLI and `WhisperClient`.
+//!
+//! When a contact connects, we ask for our conversation's messages since
+//! they last answered, and they ask us the same. Each side keeps what it
+//! was missing of the other's messages and takes the other's word for the
+//! status of the messages it sent them, so messages that crossed while one
+//! side was offline, and read receipts that never arrived, converge.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use uuid::Uuid;
+
+use super::devices::{merge_own_history, own_devices};
+use super::session::{seal_for_contact, EncryptionKeys};
+use crate::identity::TrustLevel;
+use crate::message::{
+    filter_history, merge_messages, Envelope, ExportedMessage, HistoryRequest, Message, MessageContent,
+    MessageStatus, Recipient,
+};
+use crate::network::NodeHandle;
+use crate::storage::Database;
+
+/// Most messages sent in one `History` batch.
+pub(crate) const HISTORY_BATCH_SIZE: usize = 200;
+
+/// Most messages asked for, or sent, in answer to one history request.
+pub(crate) const HISTORY_REQUEST_LIMIT: usize = 1000;
+
+/// How far back the first request to a contact goes, in days.
+pub(crate) const FIRST_SYNC_DAYS: i64 = 7;
+
+/// How far before the last sync the next request starts, in minutes,
+/// allowing for the two clocks disagreeing.
+const SYNC_OVERLAP_MINUTES: i64 = 10;
+
+/// Ask `peer` for our conversation's messages since we last synced, if
+/// they're a contact. Returns whether we asked.
+pub(crate) fn request_history(db: &Database, node: &NodeHandle, keypair: &Keypair, peer: &PeerId) -> Result<bool> {
+    if syncing_contact(db, &node.peer_id(), peer)?.is_none() {
+        return Ok(false);
+    }
+    let since = match db.history_synced_at(peer)? {
+        Some(synced_at) => synced_at - Duration::minutes(SYNC_OVERLAP_MINUTES),
+        None => Utc::now() - Duration::days(FIRST_SYNC_DAYS),
+    };
+    let request = HistoryRequest::with_limit(since, HISTORY_REQUEST_LIMIT);
+    let wire = Envelope::new(node.peer_id(), MessageContent::HistoryRequest(request)).encode_signed(keypair)?;
+    // Like a receipt, it's signed but not sealed: it gives nothing away,
+    // and starting a session for it would race their own request
+    node.send_message(*peer, wire);
+    Ok(true)
+}
+
+/// Answer a contact's history request with our direct messages with them
+/// since the time they gave. Returns how many were sent.
+pub(crate) fn answer_history_request(
+    db: &Database,
+    node: &NodeHandle,
+    keypair: &Keypair,
+    our_keys: EncryptionKeys,
+    from: &PeerId,
+    request: &HistoryRequest,
+) -> Result<usize> {
+    let Some(public_key) = syncing_contact(db, &node.peer_id(), from)? else {
+        return Ok(0);
+    };
+    let conversation: Vec<Message> = db
+        .get_conversation(from, Some(request.since))?
+        .into_iter()
+        .filter(|msg| matches!(msg.to, Recipient::Direct(_)))
+        .collect();
+    let limit = request.limit.unwrap_or(HISTORY_REQUEST_LIMIT).min(HISTORY_REQUEST_LIMIT);
+    let messages = filter_history(&conversation, request.since, Some(limit));
+    send_history(db, node, keypair, our_keys, from, &public_key, &messages)
+}
+
+/// Merge history someone sent: one of our own devices' messages, or a
+/// contact's answer to our request. Returns (new messages, updated
+/// statuses).
+pub(crate) fn receive_history(
+    db: &Database,
+    our_peer_id: &PeerId,
+    from: &PeerId,
+    messages: &[ExportedMessage],
+) -> Result<(usize, usize)> {
+    if let Some(merged) = merge_own_history(db, our_peer_id, from, messages)? {
+        return Ok(merged);
+    }
+    if syncing_contact(db, our_peer_id, from)?.is_none() {
+        return Ok((0, 0));
+    }
+
+    // Their own messages to us, and their word on the status of ours; a
+    // contact can't add messages to our side of the conversation
+    let mut remote = Vec::new();
+    for exported in messages {
+        let Ok(mut msg) = exported.clone().into_message() else {
+            continue;
+        };
+        if msg.from == *from {
+            msg.to = Recipient::Direct(*our_peer_id);
+            remote.push(msg);
+        } else if let Some(ours) = db.get_message(&msg.id)? {
+            if ours.from == *our_peer_id && ours.to == Recipient::Direct(*from) {
+                remote.push(Message { status: msg.status, ..ours });
+            }
+        }
+    }
+    let merged = merge_into(db, remote)?;
+    db.set_history_synced_at(from, Utc::now())?;
+    Ok(merged)
+}
+
+/// Send `messages` to `peer` in `History` batches, sealed for
+/// `public_key` (empty for the key in their peer ID). Returns how many
+/// were sent.
+pub(super) fn send_history(
+    db: &Database,
+    node: &NodeHandle,
+    keypair: &Keypair,
+    our_keys: EncryptionKeys,
+    peer: &PeerId,
+    public_key: &[u8],
+    messages: &[&Message],
+) -> Result<usize> {
+    let our_peer_id = node.peer_id();
+    for batch in messages.chunks(HISTORY_BATCH_SIZE) {
+        let batch = batch.iter().map(|msg| ExportedMessage::new(msg, &our_peer_id)).collect();
+        let wire = Envelope::new(our_peer_id, MessageContent::History(batch)).encode_signed(keypair)?;
+        node.send_message(*peer, seal_for_contact(db, our_keys, peer, public_key, &wire)?);
+    }
+    Ok(messages.len())
+}
+
+/// Store `remote` merged with what we have, keeping the more final status
+/// as `merge_messages` does. Messages we deleted stay deleted. Returns (new
+/// messages, updated statuses).
+pub(super) fn merge_into(db: &Database, mut remote: Vec<Message>) -> Result<(usize, usize)> {
+    db.transaction(|db| {
+        let mut deleted = HashSet::new();
+        for msg in &remote {
+            if db.was_message_deleted(&msg.id)? {
+                deleted.insert(msg.id);
+            }
+        }
+        remote.retain(|msg| !deleted.contains(&msg.id));
+
+        let mut local = Vec::new();
+        for msg in &remote {
+            local.extend(db.get_message(&msg.id)?);
+        }
+        let local_status: HashMap<Uuid, MessageStatus> = local.iter().map(|m| (m.id, m.status.clone())).collect();
+
+        let (mut new, mut updated) = (Vec::new(), 0);
+        for msg in merge_messages(local, remote) {
+            match local_status.get(&msg.id) {
+                None => new.push(msg),
+                Some(status) if *status != msg.status => {
+                    db.update_message_status(&msg.id, &msg.status)?;
+                    updated += 1;
+                }
+                Some(_) => {}
+            }
+        }
+        Ok((db.insert_messages_batch(&new)?, updated))
+    })
+}
+
+/// The stored public key of `peer`, if they're a contact we sync history
+/// with: not blocked, and not one of our own devices.
+fn syncing_contact(db: &Database, our_peer_id: &PeerId, peer: &PeerId) -> Result<Option<Vec<u8>>> {
+    let Some(contact) = db.get_contact(peer)? else {
+        return Ok(None);
+    };
+    if contact.trust_level == TrustLevel::Blocked || own_devices(db, our_peer_id)?.contains(peer) {
+        return Ok(None);
+    }
+    Ok(Some(contact.public_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Contact;
+
+    #[test]
+    fn contacts_sync_their_messages_and_our_statuses_only() {
+        let db = Database::open_in_memory().unwrap();
+        let (us, bob) = (PeerId::random(), PeerId::random());
+        db.upsert_contact(&Contact::new(bob, "bob".to_string(), Vec::new())).unwrap();
+
+        let ours = Message::new_text(us, Recipient::Direct(bob), "did you get this?".to_string());
+        db.insert_message(&ours).unwrap();
+        let mut read = ours.clone();
+        read.status = MessageStatus::Read;
+        let theirs = Message::new_text(bob, Recipient::Direct(us), "yes".to_string());
+        let forged = Message::new_text(us, Recipient::Direct(bob), "I owe bob money".to_string());
+        let history: Vec<ExportedMessage> =
+            [&read, &theirs, &forged].iter().map(|msg| ExportedMessage::new(msg, &bob)).collect();
+
+        assert_eq!(receive_history(&db, &us, &PeerId::random(), &history).unwrap(), (0, 0), "stranger");
+        assert_eq!(db.history_synced_at(&bob).unwrap(), None);
+
+        assert_eq!(receive_history(&db, &us, &bob, &history).unwrap(), (1, 1));
+        assert_eq!(db.get_message(&ours.id).unwrap().unwrap().status, MessageStatus::Read);
+        assert!(db.get_message(&theirs.id).unwrap().is_some());
+        assert!(db.get_message(&forged.id).unwrap().is_none());
+        assert!(db.history_synced_at(&bob).unwrap().is_some());
+
+        // Merging again changes nothing
+        assert_eq!(receive_history(&db, &us, &bob, &history).unwrap(), (0, 0));
+
+        // Nor does it bring back what we deleted
+        assert!(db.delete_message(&theirs.id).unwrap());
+        assert_eq!(receive_history(&db, &us, &bob, &history).unwrap(), (0, 0));
+        assert!(db.get_message(&theirs.id).unwrap().is_none());
+    }
+}
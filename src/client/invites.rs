@@ -0,0 +1,100 @@
+//! Invites, shared by the CLI and `WhisperClient`.
+//!
+//! Accepting an invite adds the inviter, and sends back an acceptance
+//! that adds the accepter on the inviter's side, once per invite.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use libp2p::identity::PublicKey;
+use libp2p::PeerId;
+
+use crate::identity::Contact;
+use crate::message::InviteAcceptance;
+use crate::network::normalize_username;
+use crate::storage::Database;
+
+/// Add whoever accepted one of our invites, using it up. Returns `None`
+/// for invites that are unknown, used or expired at `now`, and for
+/// senders who are contacts already.
+pub(crate) fn receive_invite_acceptance(
+    db: &Database,
+    from: PeerId,
+    acceptance: &InviteAcceptance,
+    now: DateTime<Utc>,
+) -> Result<Option<Contact>> {
+    let Some(public_key) = PublicKey::try_decode_protobuf(from.as_ref().digest())
+        .ok()
+        .filter(|key| key.to_peer_id() == from)
+    else {
+        return Ok(None);
+    };
+    if db.get_contact(&from)?.is_some() {
+        return Ok(None);
+    }
+    let Some(invite) = db.redeem_invite(&acceptance.invite_id, &from, now)? else {
+        return Ok(None);
+    };
+
+    // The alias we chose when inviting, else the one they'd like, as long
+    // as it's free
+    let suggested = acceptance.name.as_deref().and_then(|name| normalize_username(name).ok());
+    let alias = [invite.alias, suggested]
+        .into_iter()
+        .flatten()
+        .find(|alias| db.get_contact_by_alias(alias).ok().flatten().is_none())
+        .unwrap_or_else(|| from.to_string());
+
+    let key_bytes = public_key
+        .clone()
+        .try_into_ed25519()
+        .map(|key| key.to_bytes().to_vec())
+        .unwrap_or_default();
+    let contact = Contact::new(from, alias, key_bytes);
+    db.upsert_contact(&contact)?;
+    if let Some(prekey) = acceptance.prekey.as_ref().filter(|prekey| prekey.verify(&public_key)) {
+        db.save_contact_prekey(&from, prekey)?;
+    }
+    Ok(Some(contact))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{generate_signed_prekey, IssuedInvite, TrustLevel};
+    use libp2p::identity::Keypair;
+
+    #[test]
+    fn acceptance_adds_the_accepter_once() {
+        let db = Database::open_in_memory().unwrap();
+        let now = Utc::now();
+        db.save_invite(&IssuedInvite {
+            id: [1; 8],
+            alias: None,
+            created_at: now,
+            expires_at: now + chrono::Duration::hours(1),
+            used_by: None,
+        })
+        .unwrap();
+        let bob = Keypair::generate_ed25519();
+        let bob_id = bob.public().to_peer_id();
+        let prekey = generate_signed_prekey(&bob, 1).unwrap().public();
+        let acceptance = InviteAcceptance {
+            invite_id: [1; 8],
+            prekey: Some(prekey.clone()),
+            name: Some("@Bob".to_string()),
+        };
+
+        let contact = receive_invite_acceptance(&db, bob_id, &acceptance, now).unwrap().unwrap();
+        assert_eq!(contact.alias, "bob");
+        assert_eq!(contact.public_key, bob.public().try_into_ed25519().unwrap().to_bytes().to_vec());
+        assert_eq!(contact.trust_level, TrustLevel::Unknown);
+        assert_eq!(db.get_contact_prekey(&bob_id).unwrap(), Some(prekey));
+
+        // The invite is used up, and unknown ones add no one
+        let mallory = Keypair::generate_ed25519().public().to_peer_id();
+        assert!(receive_invite_acceptance(&db, mallory, &acceptance, now).unwrap().is_none());
+        let unknown = InviteAcceptance { invite_id: [2; 8], ..acceptance };
+        assert!(receive_invite_acceptance(&db, mallory, &unknown, now).unwrap().is_none());
+        assert!(db.get_contact(&mallory).unwrap().is_none());
+    }
+}
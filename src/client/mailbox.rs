@@ -0,0 +1,209 @@
+//! Store-and-forward mailboxes, shared by the CLI and `WhisperClient`.
+//!
+//! A mailbox is a contact who has agreed, with `whisper mailbox serve`, to
+//! hold messages for peers who are offline. When a mailbox we use
+//! connects, whatever is still queued for other peers is left with it,
+//! sealed for the recipient exactly as it would have been sent. The
+//! mailbox passes it on when the recipient connects, and drops it once it
+//! expires. The recipient opens it as if it came straight from the sender,
+//! so a mailbox can hold mail back but can't read or forge it.
+
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+
+use super::session::{authenticate, decrypt_with_session, seal_for_contact, EncryptionKeys};
+use crate::crypto::SessionMessage;
+use crate::identity::TrustLevel;
+use crate::message::{Authenticity, Envelope, MailboxDelivery, MailboxDeposit, MessageContent};
+use crate::network::NodeHandle;
+use crate::storage::Database;
+
+/// How long a mailbox holds mail, in days, counted from when it was
+/// queued. Anything older would be taken for a replay on arrival.
+pub(crate) const MAILBOX_TTL_DAYS: i64 = 7;
+
+/// Most messages a mailbox holds from any one sender.
+pub(crate) const MAX_MAIL_PER_SENDER: usize = 1000;
+
+/// Leave what's queued for other peers with `mailbox`, if it's one of
+/// ours. Returns how many messages were left.
+pub(crate) fn deposit_pending(
+    db: &Database,
+    node: &NodeHandle,
+    keypair: &Keypair,
+    our_keys: EncryptionKeys,
+    mailbox: &PeerId,
+) -> Result<usize> {
+    if !db.mailbox_peers()?.contains(mailbox) {
+        return Ok(0);
+    }
+    let Some(contact) = db.get_contact(mailbox)? else {
+        return Ok(0);
+    };
+
+    let mut left = 0;
+    for queued in db.pending_for_mailbox(mailbox)? {
+        let expires_at = queued.queued_at + Duration::days(MAILBOX_TTL_DAYS);
+        if expires_at <= Utc::now() {
+            continue;
+        }
+        let deposit = MailboxDeposit { to: queued.to, data: queued.data, expires_at };
+        let wire = Envelope::new(node.peer_id(), MessageContent::MailboxDeposit(deposit)).encode_signed(keypair)?;
+        node.send_message(*mailbox, seal_for_contact(db, our_keys, mailbox, &contact.public_key, &wire)?);
+        db.record_mailbox_deposit(&queued.id, mailbox)?;
+        left += 1;
+    }
+    Ok(left)
+}
+
+/// Hold a deposit from `from`, if we're serving as a mailbox and they're a
+/// contact we trust. Returns whether it was stored.
+pub(crate) fn receive_deposit(db: &Database, node: &NodeHandle, from: &PeerId, envelope: &Envelope) -> Result<bool> {
+    let MessageContent::MailboxDeposit(deposit) = &envelope.payload else {
+        return Ok(false);
+    };
+    if !db.mailbox_serving()? || envelope.sender != *from || deposit.to == node.peer_id() {
+        return Ok(false);
+    }
+    // Holding mail costs us space, so only for people we vouch for
+    let trusted = db
+        .get_contact(from)?
+        .is_some_and(|contact| matches!(contact.trust_level, TrustLevel::Trusted | TrustLevel::Verified));
+    if !trusted || authenticate(db, envelope) != Authenticity::Verified {
+        return Ok(false);
+    }
+    if db.count_mail_from(from)? >= MAX_MAIL_PER_SENDER {
+        return Ok(false);
+    }
+
+    let expires_at = deposit.expires_at.min(Utc::now() + Duration::days(MAILBOX_TTL_DAYS));
+    let stored = db.store_mail(&envelope.id, from, &deposit.to, &deposit.data, expires_at)?;
+    if stored {
+        node.connect_peer(deposit.to);
+    }
+    Ok(stored)
+}
+
+/// Pass on the mail we hold for `peer`, who just connected. Returns how
+/// many messages were forwarded.
+pub(crate) fn forward_mail(
+    db: &Database,
+    node: &NodeHandle,
+    keypair: &Keypair,
+    our_keys: EncryptionKeys,
+    peer: &PeerId,
+) -> Result<usize> {
+    let mail = db.take_mail_for(peer, Utc::now())?;
+    if mail.is_empty() {
+        return Ok(0);
+    }
+    // They needn't be our contact; without a stored key, seal for the one
+    // in their peer ID
+    let public_key = db.get_contact(peer)?.map(|contact| contact.public_key).unwrap_or_default();
+    let forwarded = mail.len();
+    for (from, data) in mail {
+        let delivery = MailboxDelivery { from, data };
+        let wire = Envelope::new(node.peer_id(), MessageContent::MailboxDelivery(delivery)).encode_signed(keypair)?;
+        node.send_message(*peer, seal_for_contact(db, our_keys, peer, &public_key, &wire)?);
+    }
+    Ok(forwarded)
+}
+
+/// Open mail a mailbox forwarded, returning the sender's envelope bytes to
+/// handle as a message from `delivery.from`. Only envelopes signed by that
+/// sender are accepted, and never another mailbox message. Mail that
+/// can't be opened is dropped quietly: it's usually a copy of a message
+/// the sender already got to us directly.
+pub(crate) fn open_delivery(db: &Database, our_keys: EncryptionKeys, delivery: &MailboxDelivery) -> Option<Vec<u8>> {
+    let plaintext = match SessionMessage::from_bytes(&delivery.data) {
+        Ok(message) => decrypt_with_session(db, our_keys, &delivery.from, &message).ok()?,
+        Err(_) => delivery.data.clone(),
+    };
+    let envelope = Envelope::decode(&plaintext).ok()?;
+    let nested = matches!(
+        envelope.payload,
+        MessageContent::MailboxDeposit(_) | MessageContent::MailboxDelivery(_)
+    );
+    let genuine = envelope.sender == delivery.from && authenticate(db, &envelope) == Authenticity::Verified;
+    (genuine && !nested).then_some(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keypair_to_encryption_keys;
+    use crate::identity::Contact;
+
+    fn contact(keypair: &Keypair, alias: &str, trust_level: TrustLevel) -> Contact {
+        let public_key = keypair.public().try_into_ed25519().unwrap().to_bytes().to_vec();
+        let mut contact = Contact::new(keypair.public().to_peer_id(), alias.to_string(), public_key);
+        contact.trust_level = trust_level;
+        contact
+    }
+
+    #[test]
+    fn delivered_mail_opens_as_the_senders_message() {
+        let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let (alice_db, bob_db) = (Database::open_in_memory().unwrap(), Database::open_in_memory().unwrap());
+        let alice_keys = keypair_to_encryption_keys(&alice).unwrap();
+        let bob_keys = keypair_to_encryption_keys(&bob).unwrap();
+        let bob_contact = contact(&bob, "bob", TrustLevel::Unknown);
+
+        let wire = Envelope::new(alice.public().to_peer_id(), MessageContent::Text("while you were out".to_string()))
+            .encode_signed(&alice)
+            .unwrap();
+        let sealed = seal_for_contact(
+            &alice_db,
+            (&alice_keys.0, &alice_keys.1),
+            &bob_contact.peer_id,
+            &bob_contact.public_key,
+            &wire,
+        )
+        .unwrap();
+
+        // A mailbox can't pass it off as someone else's
+        let forged = MailboxDelivery { from: PeerId::random(), data: wire.clone() };
+        assert!(open_delivery(&bob_db, (&bob_keys.0, &bob_keys.1), &forged).is_none());
+
+        let delivery = MailboxDelivery { from: alice.public().to_peer_id(), data: sealed };
+        let opened = open_delivery(&bob_db, (&bob_keys.0, &bob_keys.1), &delivery).unwrap();
+        let envelope = Envelope::decode(&opened).unwrap();
+        assert!(matches!(envelope.payload, MessageContent::Text(ref t) if t == "while you were out"));
+
+        // A second copy, e.g. after it also arrived directly, is dropped
+        assert!(open_delivery(&bob_db, (&bob_keys.0, &bob_keys.1), &delivery).is_none());
+    }
+
+    #[tokio::test]
+    async fn deposits_are_held_only_for_trusted_contacts() {
+        let (alice, carol) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let db = Database::open_in_memory().unwrap();
+        let node = crate::network::WhisperNode::new(carol).await.unwrap().spawn();
+        let alice_id = alice.public().to_peer_id();
+        let deposit = |to: PeerId| {
+            let payload = MessageContent::MailboxDeposit(MailboxDeposit {
+                to,
+                data: b"sealed".to_vec(),
+                expires_at: Utc::now() + Duration::days(30),
+            });
+            Envelope::new(alice_id, payload).signed(&alice).unwrap()
+        };
+        let bob = PeerId::random();
+
+        db.upsert_contact(&contact(&alice, "alice", TrustLevel::Trusted)).unwrap();
+        assert!(!receive_deposit(&db, &node, &alice_id, &deposit(bob)).unwrap(), "not serving");
+
+        db.set_mailbox_serving(true).unwrap();
+        assert!(!receive_deposit(&db, &node, &PeerId::random(), &deposit(bob)).unwrap(), "relayed");
+        assert!(receive_deposit(&db, &node, &alice_id, &deposit(bob)).unwrap());
+
+        db.upsert_contact(&contact(&alice, "alice", TrustLevel::Unknown)).unwrap();
+        assert!(!receive_deposit(&db, &node, &alice_id, &deposit(bob)).unwrap(), "not trusted");
+
+        // Held no longer than we allow
+        let held = db.take_mail_for(&bob, Utc::now() + Duration::days(MAILBOX_TTL_DAYS + 1)).unwrap();
+        assert!(held.is_empty());
+    }
+}
@@ -0,0 +1,861 @@
+//! High-level async client for embedding Whisper.
+//!
+//! `WhisperClient` runs a `WhisperNode` in a background task and applies
+//! its events to the database, so GUI and bot authors don't have to
+//! reimplement the CLI's event loop.
+
+mod cards;
+mod channels;
+mod devices;
+mod history;
+mod invites;
+mod mailbox;
+mod notifications;
+mod retry;
+mod session;
+
+pub(crate) use cards::{contact_card, receive_contact_card};
+pub(crate) use channels::{
+    gossiped_post, join_channels, publish_channel_post, receive_channel_joined, receive_channel_post,
+    receive_channel_subscribe,
+};
+pub(crate) use devices::{
+    announce_devices, attribute_device, offer_history, receive_device_list, sealed_for_devices, send_missing_history,
+};
+pub(crate) use history::{answer_history_request, receive_history, request_history};
+pub(crate) use invites::receive_invite_acceptance;
+pub(crate) use mailbox::{deposit_pending, forward_mail, open_delivery, receive_deposit};
+pub(crate) use notifications::alert_for;
+pub(crate) use retry::{retry_pending, PENDING_TTL_DAYS, RETRY_INTERVAL};
+
+pub(crate) use session::{
+    admits_sender, authenticate_from, encrypt_with_session, ensure_key_verified, is_replay, open_from_peer,
+    refuse_blocked, seal_for_contact, stamp_for, EncryptionKeys,
+};
+#[cfg(test)]
+pub(crate) use session::{authenticate, decrypt_with_session};
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use libp2p::{identity::Keypair, Multiaddr, PeerId};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+use crate::cli::{database_path, finish_interrupted_rekey, keypair_path, NetworkConfig};
+use crate::crypto::{keypair_to_encryption_keys, LockedSecretKey};
+use crate::identity::{
+    keypair_to_peer_id, load_keypair, Contact, Presence, PresenceStatus, TrustLevel, PRESENCE_INTERVAL_SECS,
+};
+use crate::message::{
+    Authenticity, Envelope, Message, MessageContent, MessageQueue, MessageStatus, ReceiptType, Recipient,
+};
+use crate::network::{
+    bootstrap_nodes, public_relays, NodeEvent, NodeHandle, PexPolicy, TransportConfig, WhisperNode,
+    EVENT_CHANNEL_CAPACITY,
+};
+use crate::storage::{Database, DatabaseHandle};
+
+/// Our X25519 identity keypair, owned by the client and shared with its
+/// tasks. The secret half stays in locked memory.
+type OwnedEncryptionKeys = Arc<(sodiumoxide::crypto::box_::PublicKey, LockedSecretKey)>;
+
+/// Peers we currently have a connection to, shared with the event task.
+type Connected = Arc<Mutex<HashSet<PeerId>>>;
+
+/// Async handle to a running Whisper node.
+///
+/// Received direct messages are decrypted, stored, and acknowledged by a
+/// background task; those from peers who aren't contacts are held as
+/// message requests. `NodeEvent::MessageReceived` events from
+/// `subscribe_events` carry the decrypted envelope bytes (see `Envelope::decode`).
+pub struct WhisperClient {
+    peer_id: PeerId,
+    keypair: Keypair,
+    enc_keys: OwnedEncryptionKeys,
+    db: DatabaseHandle,
+    connected: Connected,
+    node: NodeHandle,
+    events: broadcast::Sender<NodeEvent>,
+    task: JoinHandle<()>,
+}
+
+impl WhisperClient {
+    /// Open the identity and database in `data_dir` and start the node.
+    pub async fn open(data_dir: &Path, passphrase: &str) -> Result<Self> {
+        let key_path = keypair_path(data_dir);
+        if !key_path.exists() {
+            anyhow::bail!("No identity found. Run: whisper init");
+        }
+        finish_interrupted_rekey(data_dir, passphrase);
+        let keypair = load_keypair(&key_path, passphrase).context("Failed to load keypair")?;
+        let db = Database::open_with_passphrase(&database_path(data_dir), passphrase, data_dir)
+            .context("Failed to open database - incorrect passphrase?")?;
+        Self::start_with_network(keypair, db, &NetworkConfig::load(data_dir)?).await
+    }
+
+    /// Start a node for `keypair` listening on `listen_addr`, backed by `db`.
+    /// The database moves to its own thread; see `database`.
+    pub async fn start(keypair: Keypair, db: Database, listen_addr: Multiaddr) -> Result<Self> {
+        let network = NetworkConfig { listen: vec![listen_addr], ..Default::default() };
+        Self::start_with_network(keypair, db, &network).await
+    }
+
+    /// Like `start`, using the bootstrap nodes, relays and listen
+    /// addresses in `network`; see `start_listening` for where it listens without any.
+    pub async fn start_with_network(keypair: Keypair, db: Database, network: &NetworkConfig) -> Result<Self> {
+        let peer_id = keypair_to_peer_id(&keypair);
+        let (enc_pk, enc_sk) = keypair_to_encryption_keys(&keypair)?;
+        let enc_keys = Arc::new((enc_pk, LockedSecretKey::new(enc_sk)));
+
+        let mut node = create_node(&db, network, keypair.clone()).await?;
+        start_listening(&db, network, &mut node)?;
+        let node_events = node.subscribe();
+        let node = node.spawn();
+        watch_contacts(&db, &node)?;
+
+        let db = DatabaseHandle::spawn(db)?;
+        let connected = Connected::default();
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+
+        let task = tokio::spawn(process_events(
+            node.clone(),
+            db.clone(),
+            Arc::clone(&connected),
+            keypair.clone(),
+            enc_keys.clone(),
+            node_events,
+            events.clone(),
+        ));
+
+        Ok(Self {
+            peer_id,
+            keypair,
+            enc_keys,
+            db,
+            connected,
+            node,
+            events,
+            task,
+        })
+    }
+
+    /// Our peer ID.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Subscribe to node events. Each subscriber sees every event sent
+    /// after it subscribed.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<NodeEvent> {
+        self.events.subscribe()
+    }
+
+    /// The client's database, for queries the client doesn't wrap. The
+    /// handle can be cloned and used from any task.
+    pub fn database(&self) -> DatabaseHandle {
+        self.db.clone()
+    }
+
+    /// List all contacts.
+    pub async fn contacts(&self) -> Result<Vec<Contact>> {
+        self.db.list_contacts().await
+    }
+
+    /// Add or update a contact.
+    pub async fn add_contact(&self, contact: &Contact) -> Result<()> {
+        self.db.upsert_contact(contact.clone()).await
+    }
+
+    /// Recent messages exchanged with a peer, newest first.
+    pub async fn messages_with(&self, peer_id: &PeerId, limit: usize) -> Result<Vec<Message>> {
+        self.db.get_messages_with_peer(*peer_id, limit).await
+    }
+
+    /// Dial a peer at a specific address.
+    pub async fn dial(&self, addr: Multiaddr) -> Result<()> {
+        self.node.dial(addr).await
+    }
+
+    /// Send a text message to a contact by alias.
+    ///
+    /// The message is stored and queued persistently. If the contact is
+    /// connected it's sent right away, otherwise when they next connect.
+    pub async fn send_text(&self, alias: &str, text: &str) -> Result<Message> {
+        let (alias, text) = (alias.to_string(), text.to_string());
+        let (peer_id, keypair, enc_keys) = (self.peer_id, self.keypair.clone(), self.enc_keys.clone());
+        let (node, connected) = (self.node.clone(), Arc::clone(&self.connected));
+        self.db
+            .call(move |db| {
+                let contact = db
+                    .get_contact_by_alias(&alias)?
+                    .ok_or_else(|| anyhow::anyhow!("Contact '{}' not found", alias))?;
+                ensure_key_verified(db, &contact)?;
+
+                let msg = Message::new_text(peer_id, Recipient::Direct(contact.peer_id), text);
+                db.insert_message(&msg)?;
+
+                let wire = stamp_for(db, Envelope::from_message(&msg), &contact.peer_id)?.encode_signed(&keypair)?;
+                let data = seal_for_contact(
+                    db,
+                    (&enc_keys.0, &*enc_keys.1),
+                    &contact.peer_id,
+                    &contact.public_key,
+                    &wire,
+                )?;
+
+                let our_keys = (&enc_keys.0, &*enc_keys.1);
+                let copies = sealed_for_devices(db, our_keys, &contact.peer_id, &wire)?;
+                // The event task flushes the queue when they connect
+                if is_connected(&connected, &contact.peer_id) {
+                    node.send_message(contact.peer_id, data);
+                } else {
+                    MessageQueue::new(db).enqueue(&msg.id, &contact.peer_id, &data)?;
+                }
+                for (device, data) in copies {
+                    if is_connected(&connected, &device) {
+                        node.send_message(device, data);
+                    } else {
+                        MessageQueue::new(db).enqueue(&uuid::Uuid::new_v4(), &device, &data)?;
+                    }
+                }
+                Ok(msg)
+            })
+            .await
+    }
+
+    /// Stop the node and wait for the event task to exit.
+    pub async fn shutdown(self) -> Result<()> {
+        self.node.shutdown();
+        self.task.abort();
+        match self.task.await {
+            Err(e) if e.is_panic() => anyhow::bail!("Event task panicked"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Create a node that reaches the network the way the user set up:
+/// through their SOCKS proxy, if they have one, with the bootstrap nodes
+/// and relays in `network` as well as the built-in ones, and claiming
+/// their username. The settings are read before the future is returned,
+/// so it doesn't borrow `db` and can be sent between threads.
+pub(crate) fn create_node(
+    db: &Database,
+    network: &NetworkConfig,
+    keypair: Keypair,
+) -> impl Future<Output = Result<WhisperNode>> {
+    let transport = transport_config(db);
+    let (bootstrap, relays) = (bootstrap_nodes(&network.bootstrap), public_relays(&network.relays));
+    let username = db.username();
+    async move {
+        let mut node = WhisperNode::with_transport(keypair, transport?)
+            .await
+            .context("Failed to create network node")?;
+        node.set_bootstrap_nodes(bootstrap);
+        node.set_relays(relays);
+        node.set_username(username?.as_deref())?;
+        Ok(node)
+    }
+}
+
+/// Listen on the addresses in `network` or, without any, on the port
+/// we listened on before, picking one the first time. Keeping to a port
+/// means forwarding it once gives a stable address. If another process
+/// has it, as when sending while `whisper listen` runs, any port will do.
+/// The port is taken on IPv6 too where the host has it.
+pub(crate) fn start_listening(db: &Database, network: &NetworkConfig, node: &mut WhisperNode) -> Result<()> {
+    if !network.listen.is_empty() {
+        for addr in &network.listen {
+            node.listen_on(addr.clone())?;
+        }
+        return Ok(());
+    }
+    let port = match db.listen_port()? {
+        Some(port) => port,
+        None => {
+            let port = std::net::TcpListener::bind(("0.0.0.0", 0))?.local_addr()?.port();
+            db.set_listen_port(Some(port))?;
+            port
+        }
+    };
+    let port = if node.listen_on(format!("/ip4/0.0.0.0/tcp/{}", port).parse()?).is_ok() {
+        port
+    } else {
+        node.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+        0
+    };
+    // Not every host has IPv6
+    let _ = node.listen_on(format!("/ip6/::/tcp/{}", port).parse()?);
+    Ok(())
+}
+
+/// The transport settings saved with `whisper proxy` and `whisper websocket`.
+fn transport_config(db: &Database) -> Result<TransportConfig> {
+    Ok(TransportConfig {
+        socks_proxy: db.socks_proxy()?,
+        websocket_port: db.websocket_port()?,
+    })
+}
+
+/// Give the node the addresses we remember, have it keep contacts
+/// connected and hang up on blocked ones, join our channels' topics, and
+/// dial anyone we have queued messages for.
+pub(crate) fn watch_contacts(db: &Database, node: &NodeHandle) -> Result<()> {
+    // Worst first, so the best address for each peer ends up in front
+    for known in db.all_peer_addresses()?.into_iter().rev() {
+        if known.last_success.is_some() {
+            node.add_reached_address(known.peer_id, known.address);
+        } else {
+            node.add_address(known.peer_id, known.address);
+        }
+    }
+    let mut blocked = HashSet::new();
+    for contact in db.list_contacts()? {
+        if contact.trust_level == TrustLevel::Blocked {
+            blocked.insert(contact.peer_id);
+        } else {
+            node.watch_peer(contact.peer_id);
+        }
+    }
+    node.set_blocked_peers(blocked);
+    // After the watches, so we register for every contact
+    if let Some(point) = db.rendezvous_point()? {
+        node.set_rendezvous_point(point);
+    }
+    node.set_pex_policy(pex_policy(db)?);
+    join_channels(db, node)?;
+    for peer_id in MessageQueue::new(db).peers_with_pending()? {
+        node.connect_peer(peer_id);
+    }
+    Ok(())
+}
+
+/// Who we trade address hints with, and about whom. With peer exchange
+/// on, the addresses of contacts who haven't been left out are passed on,
+/// to the trusted ones among them.
+pub(crate) fn pex_policy(db: &Database) -> Result<PexPolicy> {
+    let mut policy = PexPolicy::default();
+    if !db.pex_enabled()? {
+        return Ok(policy);
+    }
+    for contact in db.list_contacts()? {
+        if contact.trust_level == TrustLevel::Blocked || !db.shares_addresses(&contact.peer_id)? {
+            continue;
+        }
+        policy.shared.insert(contact.peer_id);
+        if contact.is_trusted() {
+            policy.trusted.insert(contact.peer_id);
+        }
+    }
+    Ok(policy)
+}
+
+/// Tell a contact our presence, if they're trusted with it. Returns
+/// whether anything was sent.
+pub(crate) fn announce_presence(
+    db: &Database,
+    node: &NodeHandle,
+    keypair: &Keypair,
+    our_keys: EncryptionKeys,
+    peer_id: &PeerId,
+) -> Result<bool> {
+    let Some(contact) = db.get_contact(peer_id)? else {
+        return Ok(false);
+    };
+    if !contact.shares_presence() {
+        return Ok(false);
+    }
+    let status = db.own_presence()?;
+    let wire = Envelope::new(node.peer_id(), MessageContent::Presence(status)).encode_signed(keypair)?;
+    let data = seal_for_contact(db, our_keys, peer_id, &contact.public_key, &wire)?;
+    node.send_message(*peer_id, data);
+    Ok(true)
+}
+
+/// Record a contact's presence announcement, returning it unless the
+/// sender isn't a contact.
+pub(crate) fn receive_presence(db: &Database, from: &PeerId, status: PresenceStatus) -> Result<Option<Presence>> {
+    let presence = Presence { status, at: Utc::now() };
+    Ok(db.set_contact_presence(from, presence)?.then_some(presence))
+}
+
+/// Apply a delivery or read receipt from `from`. Only a message's
+/// recipient can say it arrived or was read, or for a group message one
+/// of the group's members; receipts for anyone else's messages are
+/// ignored. Returns whether the status was updated.
+pub(crate) fn receive_receipt(db: &Database, from: &PeerId, message_id: &uuid::Uuid, receipt: ReceiptType) -> Result<bool> {
+    let Some(msg) = db.get_message(message_id)? else {
+        return Ok(false);
+    };
+    let recipient = match msg.to {
+        Recipient::Direct(to) => to == *from,
+        Recipient::Group(group_id) => db.get_group(&group_id)?.is_some_and(|group| group.is_member(from)),
+    };
+    if !recipient {
+        return Ok(false);
+    }
+    let status = match receipt {
+        ReceiptType::Delivered => MessageStatus::Delivered,
+        ReceiptType::Read => MessageStatus::Read,
+    };
+    db.update_message_status(message_id, &status)
+}
+
+/// Whether we're connected to `peer_id`.
+fn is_connected(connected: &Connected, peer_id: &PeerId) -> bool {
+    connected.lock().is_ok_and(|connected| connected.contains(peer_id))
+}
+
+/// Apply node events to local state and re-broadcast them to subscribers.
+async fn process_events(
+    node: NodeHandle,
+    db: DatabaseHandle,
+    connected: Connected,
+    keypair: Keypair,
+    enc_keys: OwnedEncryptionKeys,
+    mut node_events: broadcast::Receiver<NodeEvent>,
+    events: broadcast::Sender<NodeEvent>,
+) {
+    let every = Duration::from_secs(PRESENCE_INTERVAL_SECS);
+    let mut presence = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+    let mut retry = tokio::time::interval(RETRY_INTERVAL);
+    loop {
+        let received = tokio::select! {
+            received = node_events.recv() => received,
+            _ = retry.tick() => {
+                // Look again for peers with messages waiting
+                let peers: HashSet<PeerId> = match connected.lock() {
+                    Ok(peers) => peers.clone(),
+                    Err(_) => break,
+                };
+                let node = node.clone();
+                let _ = db.call(move |db| retry_pending(db, &node, &peers)).await;
+                continue;
+            }
+            _ = presence.tick() => {
+                // Keep our presence fresh with connected contacts
+                let peers: Vec<PeerId> = match connected.lock() {
+                    Ok(peers) => peers.iter().copied().collect(),
+                    Err(_) => break,
+                };
+                let (node, keypair, enc_keys) = (node.clone(), keypair.clone(), enc_keys.clone());
+                let _ = db
+                    .call(move |db| {
+                        for peer_id in &peers {
+                            let _ = announce_presence(db, &node, &keypair, (&enc_keys.0, &*enc_keys.1), peer_id);
+                        }
+                        Ok(())
+                    })
+                    .await;
+                continue;
+            }
+        };
+        let event = match received {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        {
+            let Ok(mut peers) = connected.lock() else { break };
+            match &event {
+                NodeEvent::PeerConnected(peer_id) => peers.insert(*peer_id),
+                NodeEvent::PeerDisconnected(peer_id) => peers.remove(peer_id),
+                _ => false,
+            };
+        }
+
+        let (node, keypair, enc_keys) = (node.clone(), keypair.clone(), enc_keys.clone());
+        let handled = db
+            .call(move |db| {
+                let our_keys = (&enc_keys.0, &*enc_keys.1);
+                Ok(handle_event(db, &node, &keypair, our_keys, event))
+            })
+            .await;
+        match handled {
+            Ok(Some(event)) => {
+                // No subscribers is fine
+                let _ = events.send(event);
+            }
+            Ok(None) => {}
+            Err(_) => break,
+        }
+    }
+}
+
+/// Apply a node event to the database, returning the event to broadcast.
+fn handle_event(
+    db: &Database,
+    node: &NodeHandle,
+    keypair: &Keypair,
+    our_keys: EncryptionKeys,
+    event: NodeEvent,
+) -> Option<NodeEvent> {
+    match event {
+        NodeEvent::PeerConnected(peer_id) => {
+            if let Ok(Some(mut contact)) = db.get_contact(&peer_id) {
+                contact.last_seen = Some(Utc::now());
+                let _ = db.upsert_contact(&contact);
+            }
+            let _ = announce_presence(db, node, keypair, our_keys, &peer_id);
+            let _ = announce_devices(db, node, keypair, our_keys, &peer_id);
+            let _ = offer_history(db, node, keypair, our_keys, &peer_id);
+            let _ = request_history(db, node, keypair, &peer_id);
+            // Flush the persistent queue for this peer
+            let _ = MessageQueue::new(db).flush(&peer_id, |data| node.send_message(peer_id, data));
+            let _ = forward_mail(db, node, keypair, our_keys, &peer_id);
+            let _ = deposit_pending(db, node, keypair, our_keys, &peer_id);
+            Some(event)
+        }
+        NodeEvent::MessageReceived { from, data } => {
+            if refuse_blocked(db, &from) {
+                return None;
+            }
+            let decrypted = open_from_peer(db, our_keys, &from, &data);
+            let mut envelope = Envelope::decode(&decrypted).ok()?;
+            let authenticity = authenticate_from(db, &envelope, &from);
+            if authenticity == Authenticity::Forged || is_replay(db, &envelope) {
+                return None;
+            }
+            // Strangers' first messages must be stamped
+            if !admits_sender(db, &node.peer_id(), &envelope) {
+                return None;
+            }
+            attribute_device(db, &mut envelope);
+
+            match &envelope.payload {
+                MessageContent::Receipt(id, receipt_type) => {
+                    let _ = receive_receipt(db, &envelope.sender, id, *receipt_type);
+                }
+                MessageContent::Text(_) | MessageContent::Location { .. } | MessageContent::ContactCard { .. } => {
+                    if let MessageContent::ContactCard { peer_id, public_key, alias } = &envelope.payload {
+                        let _ = receive_contact_card(db, &node.peer_id(), from, peer_id, public_key, alias);
+                    }
+                    let msg = envelope.clone().into_message(Recipient::Direct(node.peer_id()));
+                    if let Ok(Some(_)) = db.receive_message(&msg) {
+                        if authenticity == Authenticity::Unverified {
+                            let _ = db.mark_message_unverified(&msg.id);
+                        }
+                    }
+                    let receipt = Envelope::new(
+                        node.peer_id(),
+                        MessageContent::Receipt(msg.id, ReceiptType::Delivered),
+                    );
+                    if let Ok(receipt) = receipt.encode_signed(keypair) {
+                        node.send_message(from, receipt);
+                    }
+                }
+                MessageContent::Presence(status) => {
+                    let _ = receive_presence(db, &from, *status);
+                }
+                MessageContent::MailboxDeposit(_) => {
+                    let _ = receive_deposit(db, node, &from, &envelope);
+                }
+                MessageContent::MailboxDelivery(delivery) => {
+                    let data = open_delivery(db, our_keys, delivery)?;
+                    let event = NodeEvent::MessageReceived { from: delivery.from, data };
+                    return handle_event(db, node, keypair, our_keys, event);
+                }
+                MessageContent::DeviceList(list) => {
+                    let _ = receive_device_list(db, list);
+                }
+                MessageContent::HistoryOffer(ids) if authenticity == Authenticity::Verified => {
+                    let _ = send_missing_history(db, node, keypair, our_keys, &from, ids);
+                }
+                MessageContent::ChannelSubscribe(id) if authenticity == Authenticity::Verified => {
+                    let _ = receive_channel_subscribe(db, node, keypair, our_keys, &from, id);
+                }
+                MessageContent::ChannelPost(post) => {
+                    let _ = receive_channel_post(db, post);
+                }
+                MessageContent::HistoryRequest(request) if authenticity == Authenticity::Verified => {
+                    let _ = answer_history_request(db, node, keypair, our_keys, &from, request);
+                }
+                MessageContent::History(messages) if authenticity == Authenticity::Verified => {
+                    let _ = receive_history(db, &node.peer_id(), &from, messages);
+                }
+                MessageContent::InviteAccepted(acceptance) if authenticity == Authenticity::Verified => {
+                    if let Ok(Some(contact)) = receive_invite_acceptance(db, from, acceptance, Utc::now()) {
+                        node.watch_peer(contact.peer_id);
+                    }
+                }
+                _ => {}
+            }
+
+            Some(NodeEvent::MessageReceived { from, data: decrypted })
+        }
+        NodeEvent::ReachabilityChanged(reachability) => {
+            let _ = db.save_reachability(reachability);
+            Some(event)
+        }
+        NodeEvent::PeerAddress { peer, ref address, reached } => {
+            let _ = db.record_peer_address(&peer, address, reached);
+            Some(event)
+        }
+        NodeEvent::Latency { peer, latency } => {
+            let _ = db.record_latency(&peer, latency);
+            Some(event)
+        }
+        NodeEvent::PeerIdentified { peer, ref protocol_version, ref agent_version, .. } => {
+            let _ = db.record_peer_version(&peer, protocol_version, agent_version);
+            Some(event)
+        }
+        NodeEvent::Traffic(ref traffic) => {
+            let _ = db.record_traffic(traffic);
+            Some(event)
+        }
+        NodeEvent::ChannelPostReceived { channel_id, ref data, .. } => {
+            if let Some(post) = gossiped_post(&channel_id, data) {
+                let _ = receive_channel_post(db, &post);
+            }
+            Some(event)
+        }
+        NodeEvent::ChannelJoined { peer, channel_id } => {
+            let _ = receive_channel_joined(db, node, keypair, our_keys, &peer, &channel_id);
+            Some(event)
+        }
+        _ => Some(event),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::generate_keypair;
+    use crate::message::DEFAULT_STAMP_DIFFICULTY;
+
+    async fn start_client() -> WhisperClient {
+        let db = Database::open_in_memory().unwrap();
+        WhisperClient::start(generate_keypair(), db, "/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn listen_addr(events: &mut broadcast::Receiver<NodeEvent>) -> Multiaddr {
+        loop {
+            if let Ok(NodeEvent::Listening(addr)) = events.recv().await {
+                return addr;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn contacts_roundtrip() {
+        let client = start_client().await;
+        assert!(client.contacts().await.unwrap().is_empty());
+
+        let contact = Contact::new(PeerId::random(), "bob".to_string(), Vec::new());
+        client.add_contact(&contact).await.unwrap();
+
+        let contacts = client.contacts().await.unwrap();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].alias, "bob");
+
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn nodes_keep_to_one_port() {
+        let db = Database::open_in_memory().unwrap();
+        let network = NetworkConfig::default();
+        let mut first = create_node(&db, &network, generate_keypair()).await.unwrap();
+        let mut events = first.subscribe();
+        start_listening(&db, &network, &mut first).unwrap();
+        let port = db.listen_port().unwrap().expect("a port is chosen");
+        let _first = first.spawn();
+        assert!(listen_addr(&mut events).await.to_string().ends_with(&format!("/tcp/{}", port)));
+
+        // A second node can't have it while the first does, so takes another
+        let mut second = create_node(&db, &network, generate_keypair()).await.unwrap();
+        start_listening(&db, &network, &mut second).unwrap();
+        assert_eq!(db.listen_port().unwrap(), Some(port));
+    }
+
+    #[test]
+    fn strangers_are_stamped_until_they_write_back() {
+        let db = Database::open_in_memory().unwrap();
+        let (us, bob) = (PeerId::random(), PeerId::random());
+        let envelope = || Envelope::new(us, MessageContent::Text("hi".to_string()));
+
+        let stamped = stamp_for(&db, envelope(), &bob).unwrap();
+        assert!(stamped.has_stamp(&bob, DEFAULT_STAMP_DIFFICULTY));
+
+        // Once they've written to us, they know us
+        db.insert_message(&Message::new_text(bob, Recipient::Direct(us), "hello".to_string())).unwrap();
+        assert!(stamp_for(&db, envelope(), &bob).unwrap().stamp.is_none());
+    }
+
+    #[test]
+    fn pex_follows_trust_and_sharing_settings() {
+        let db = Database::open_in_memory().unwrap();
+        let contact = |alias: &str, trust_level| {
+            let mut contact = Contact::new(PeerId::random(), alias.to_string(), Vec::new());
+            contact.trust_level = trust_level;
+            db.upsert_contact(&contact).unwrap();
+            contact.peer_id
+        };
+        let alice = contact("alice", TrustLevel::Verified);
+        let bob = contact("bob", TrustLevel::Unknown);
+        let carol = contact("carol", TrustLevel::Trusted);
+        let mallory = contact("mallory", TrustLevel::Blocked);
+        db.set_share_addresses(&carol, false).unwrap();
+
+        let policy = pex_policy(&db).unwrap();
+        assert_eq!(policy.trusted, [alice].into());
+        assert_eq!(policy.shared, [alice, bob].into());
+        assert!(!policy.shared.contains(&mallory));
+
+        db.set_pex_enabled(false).unwrap();
+        assert_eq!(pex_policy(&db).unwrap(), PexPolicy::default());
+    }
+
+    #[test]
+    fn replayed_envelopes_are_detected() {
+        let db = Database::open_in_memory().unwrap();
+        let sender = PeerId::random();
+        let wire = Envelope::new(sender, MessageContent::Text("hi".to_string())).encode().unwrap();
+
+        assert!(!is_replay(&db, &Envelope::decode(&wire).unwrap()));
+        assert!(is_replay(&db, &Envelope::decode(&wire).unwrap()));
+
+        let typing = Envelope::new(sender, MessageContent::Typing).encode().unwrap();
+        assert!(!is_replay(&db, &Envelope::decode(&typing).unwrap()));
+        assert!(!is_replay(&db, &Envelope::decode(&typing).unwrap()));
+    }
+
+    #[test]
+    fn envelopes_are_authenticated_against_the_sender() {
+        let db = Database::open_in_memory().unwrap();
+        let keypair = generate_keypair();
+        let sender = keypair.public().to_peer_id();
+        let envelope = Envelope::new(sender, MessageContent::Text("hi".to_string()));
+
+        // Peer IDs embed the key, so no contact is needed
+        let signed = envelope.clone().signed(&keypair).unwrap();
+        assert_eq!(authenticate(&db, &signed), Authenticity::Verified);
+        assert_eq!(authenticate(&db, &envelope), Authenticity::Unverified);
+
+        let forged = envelope.clone().signed(&generate_keypair()).unwrap();
+        assert_eq!(authenticate(&db, &forged), Authenticity::Forged);
+
+        // Unsigned, it only speaks for the peer it came from
+        let other = PeerId::random();
+        assert_eq!(authenticate_from(&db, &envelope, &sender), Authenticity::Unverified);
+        assert_eq!(authenticate_from(&db, &envelope, &other), Authenticity::Forged);
+        assert_eq!(authenticate_from(&db, &signed, &other), Authenticity::Verified);
+    }
+
+    #[test]
+    fn receipts_only_count_from_the_recipient() {
+        let db = Database::open_in_memory().unwrap();
+        let (us, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let msg = Message::new_text(us, Recipient::Direct(bob), "hi".to_string());
+        db.insert_message(&msg).unwrap();
+        let status = || db.get_message(&msg.id).unwrap().unwrap().status;
+
+        assert!(!receive_receipt(&db, &carol, &msg.id, ReceiptType::Read).unwrap());
+        assert!(matches!(status(), MessageStatus::Pending));
+        assert!(receive_receipt(&db, &bob, &msg.id, ReceiptType::Delivered).unwrap());
+        assert!(matches!(status(), MessageStatus::Delivered));
+        assert!(!receive_receipt(&db, &bob, &uuid::Uuid::new_v4(), ReceiptType::Read).unwrap());
+
+        // For group messages, any member can
+        let mut group = crate::message::Group::new("friends".to_string(), crate::crypto::generate_group_key(), Some(us));
+        group.add_member(bob);
+        db.create_group(&group).unwrap();
+        let msg = Message::new_text(us, Recipient::Group(group.id), "hi all".to_string());
+        db.insert_message(&msg).unwrap();
+        assert!(!receive_receipt(&db, &carol, &msg.id, ReceiptType::Read).unwrap());
+        assert!(receive_receipt(&db, &bob, &msg.id, ReceiptType::Read).unwrap());
+    }
+
+    #[test]
+    fn changed_identity_keys_are_pinned_and_flagged() {
+        let db = Database::open_in_memory().unwrap();
+        let keypair = generate_keypair();
+        let sender = keypair.public().to_peer_id();
+        let key = keypair.public().try_into_ed25519().unwrap().to_bytes().to_vec();
+        let signed = || Envelope::new(sender, MessageContent::Text("hi".to_string())).signed(&keypair).unwrap();
+
+        // Added by peer ID alone, the key is pinned on first use
+        db.upsert_contact(&Contact::new(sender, "alice".to_string(), Vec::new())).unwrap();
+        assert_eq!(authenticate(&db, &signed()), Authenticity::Verified);
+        assert_eq!(db.get_contact(&sender).unwrap().unwrap().public_key, key);
+        assert_eq!(db.key_change(&sender).unwrap(), None);
+
+        // A key we stored that they no longer sign with is a change
+        let old_key = generate_keypair().public().try_into_ed25519().unwrap().to_bytes().to_vec();
+        let mut contact = Contact::new(sender, "alice".to_string(), old_key);
+        contact.trust_level = TrustLevel::Verified;
+        db.upsert_contact(&contact).unwrap();
+        assert_eq!(authenticate(&db, &signed()), Authenticity::Verified);
+        let contact = db.get_contact(&sender).unwrap().unwrap();
+        assert_eq!(contact.public_key, key);
+        assert_eq!(contact.trust_level, TrustLevel::Unknown);
+        assert!(db.key_change(&sender).unwrap().is_some());
+        assert!(ensure_key_verified(&db, &contact).is_err());
+
+        // Forgeries don't change anything
+        db.clear_key_change(&sender).unwrap();
+        let forged = Envelope::new(sender, MessageContent::Text("hi".to_string())).signed(&generate_keypair()).unwrap();
+        assert_eq!(authenticate(&db, &forged), Authenticity::Forged);
+        assert_eq!(db.key_change(&sender).unwrap(), None);
+        assert!(ensure_key_verified(&db, &contact).is_ok());
+    }
+
+    #[tokio::test]
+    async fn send_text_to_unknown_alias_fails() {
+        let client = start_client().await;
+        assert!(client.send_text("nobody", "hi").await.is_err());
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_text_between_clients() {
+        let alice = start_client().await;
+        let bob = start_client().await;
+        let mut bob_events = bob.subscribe_events();
+        let bob_addr = listen_addr(&mut bob_events).await;
+
+        alice
+            .add_contact(&Contact::new(bob.peer_id(), "bob".to_string(), Vec::new()))
+            .await
+            .unwrap();
+        bob.add_contact(&Contact::new(alice.peer_id(), "alice".to_string(), Vec::new()))
+            .await
+            .unwrap();
+
+        alice.dial(bob_addr).await.unwrap();
+        let sent = alice.send_text("bob", "hello bob").await.unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                // Skipping the history request sent on connecting
+                if let Ok(NodeEvent::MessageReceived { data, .. }) = bob_events.recv().await {
+                    let envelope = Envelope::decode(&data).unwrap();
+                    if matches!(envelope.payload, MessageContent::Text(_)) {
+                        return envelope;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("message should arrive");
+
+        assert_eq!(received.id, sent.id);
+        assert!(matches!(received.payload, MessageContent::Text(ref t) if t == "hello bob"));
+        assert!(received.signature.is_some());
+        assert_eq!(bob.messages_with(&alice.peer_id(), 10).await.unwrap().len(), 1);
+        let sent_id = sent.id;
+        let unverified = bob.database().call(move |db| db.is_message_unverified(&sent_id)).await.unwrap();
+        assert!(!unverified);
+
+        alice.shutdown().await.unwrap();
+        bob.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+//! Notification settings, shared by the chat TUI and `whisper listen`.
+//!
+//! Each conversation decides which messages notify the user: all of them,
+//! only those mentioning our username, or none, and not while muted. What
+//! notifies rings the chat's alert and reaches the webhook.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+use crate::message::{Message, MessageContent, Recipient};
+use crate::storage::{Alert, Database};
+
+/// How to alert the user to `msg` in the conversation `with`, or `None`
+/// if its notification settings keep it quiet at `now`.
+pub(crate) fn alert_for(db: &Database, with: &Recipient, msg: &Message, now: DateTime<Utc>) -> Result<Option<Alert>> {
+    let settings = db.notification_settings(with)?;
+    let mentioned = match (&msg.content, db.username()?) {
+        (MessageContent::Text(text), Some(username)) => mentions(text, &username),
+        _ => false,
+    };
+    Ok(settings.notifies(mentioned, now).then_some(settings.alert))
+}
+
+/// Whether `text` mentions `@username`, in any case, as a word of its own.
+fn mentions(text: &str, username: &str) -> bool {
+    let text = text.to_ascii_lowercase();
+    let wanted = format!("@{}", username);
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    text.match_indices(&wanted).any(|(at, _)| {
+        let before = text[..at].chars().next_back();
+        let after = text[at + wanted.len()..].chars().next();
+        !before.is_some_and(is_name_char) && !after.is_some_and(is_name_char)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{NotificationLevel, NotificationSettings};
+    use libp2p::PeerId;
+
+    #[test]
+    fn mentions_only_notify_when_named() {
+        let db = Database::open_in_memory().unwrap();
+        let alice = PeerId::random();
+        let with = Recipient::Direct(alice);
+        let settings = NotificationSettings { level: NotificationLevel::Mentions, ..NotificationSettings::default() };
+        db.set_notification_settings(&with, &settings).unwrap();
+        db.set_username(Some("bob")).unwrap();
+        let now = Utc::now();
+
+        let say = |text: &str| Message::new_text(alice, Recipient::Direct(PeerId::random()), text.to_string());
+        assert_eq!(alert_for(&db, &with, &say("hey @Bob, lunch?"), now).unwrap(), Some(Alert::Quiet));
+        assert_eq!(alert_for(&db, &with, &say("@bobby and bob@example.com"), now).unwrap(), None);
+        assert_eq!(alert_for(&db, &with, &say("no one in particular"), now).unwrap(), None);
+
+        // Other conversations notify for everything
+        let other = Recipient::Direct(PeerId::random());
+        assert_eq!(alert_for(&db, &other, &say("hi"), now).unwrap(), Some(Alert::Quiet));
+    }
+}
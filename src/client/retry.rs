@@ -0,0 +1,164 @@
+//! Retrying queued messages, shared by the CLI and `WhisperClient`.
+//!
+//! Queued messages go out when their recipient connects. While a session
+//! runs, each one whose retry is due counts an attempt and its recipient
+//! is looked for again. The wait between attempts doubles each time, and
+//! a message still undelivered after `MAX_DELIVERY_ATTEMPTS` becomes a
+//! dead letter: kept, shown in `whisper peers`, but no longer sent.
+//! Anything still queued after `PENDING_TTL_DAYS` is dropped and its
+//! message marked expired.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use libp2p::PeerId;
+
+use crate::message::{MessageQueue, MessageStatus};
+use crate::network::NodeHandle;
+use crate::storage::Database;
+
+/// How often running sessions look for retries that are due.
+pub(crate) const RETRY_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Wait after the first failed attempt; doubled for each one after.
+pub(crate) const RETRY_BASE_SECS: i64 = 30;
+
+/// Longest wait between attempts.
+pub(crate) const RETRY_MAX_SECS: i64 = 60 * 60;
+
+/// Attempts before a queued message is given up on.
+pub(crate) const MAX_DELIVERY_ATTEMPTS: u32 = 20;
+
+/// Days a message is kept in the queue before it expires.
+pub(crate) const PENDING_TTL_DAYS: i64 = 14;
+
+/// What a pass over the queue decided.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Retries {
+    /// Connected peers with messages due, to send them now.
+    pub(crate) flush: Vec<PeerId>,
+    /// Peers to look for again.
+    pub(crate) dial: Vec<PeerId>,
+    /// Messages given up on in this pass.
+    pub(crate) dead: usize,
+    /// Messages dropped for being queued too long.
+    pub(crate) expired: usize,
+}
+
+/// How long to wait after `attempts` failed attempts.
+pub(crate) fn retry_delay(attempts: u32) -> chrono::Duration {
+    let doublings = attempts.saturating_sub(1).min(16);
+    chrono::Duration::seconds((RETRY_BASE_SECS << doublings).min(RETRY_MAX_SECS))
+}
+
+/// Expire messages queued too long, then count an attempt at each queued
+/// message that's due, scheduling its next one or giving up on it.
+/// Messages for connected peers aren't counted; they're sent as they are.
+pub(crate) fn plan_retries(db: &Database, connected: &HashSet<PeerId>, now: DateTime<Utc>) -> Result<Retries> {
+    let queue = MessageQueue::new(db);
+    let mut retries = Retries::default();
+    for id in queue.expire(now - chrono::Duration::days(PENDING_TTL_DAYS))? {
+        // Copies for linked devices have no message of their own
+        db.update_message_status(&id, &MessageStatus::Expired)?;
+        retries.expired += 1;
+    }
+    for (id, to) in queue.due(now)? {
+        if connected.contains(&to) {
+            if !retries.flush.contains(&to) {
+                retries.flush.push(to);
+            }
+            continue;
+        }
+        let attempts = queue.record_attempt(&id)?;
+        if attempts >= MAX_DELIVERY_ATTEMPTS {
+            queue.give_up(&id, now)?;
+            retries.dead += 1;
+            continue;
+        }
+        queue.schedule(&id, now + retry_delay(attempts))?;
+        if !retries.dial.contains(&to) {
+            retries.dial.push(to);
+        }
+    }
+    Ok(retries)
+}
+
+/// Retry the queued messages that are due.
+pub(crate) fn retry_pending(db: &Database, node: &NodeHandle, connected: &HashSet<PeerId>) -> Result<Retries> {
+    let retries = plan_retries(db, connected, Utc::now())?;
+    for &peer_id in &retries.flush {
+        MessageQueue::new(db).flush(&peer_id, |data| node.send_message(peer_id, data))?;
+    }
+    for &peer_id in &retries.dial {
+        node.connect_peer(peer_id);
+    }
+    Ok(retries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn retries_back_off_until_the_message_is_given_up_on() {
+        assert_eq!(retry_delay(1).num_seconds(), RETRY_BASE_SECS);
+        assert_eq!(retry_delay(3).num_seconds(), RETRY_BASE_SECS * 4);
+        assert_eq!(retry_delay(MAX_DELIVERY_ATTEMPTS).num_seconds(), RETRY_MAX_SECS);
+
+        let db = Database::open_in_memory().unwrap();
+        let (away, here) = (PeerId::random(), PeerId::random());
+        let id = Uuid::new_v4();
+        db.queue_pending_message(&id, &away, b"hello").unwrap();
+        db.queue_pending_message(&Uuid::new_v4(), &here, b"hi").unwrap();
+        let connected = HashSet::from([here]);
+
+        let mut now = Utc::now();
+        let retries = plan_retries(&db, &connected, now).unwrap();
+        assert_eq!(retries, Retries { flush: vec![here], dial: vec![away], ..Retries::default() });
+        for (id, _) in db.get_pending_for_peer(&here).unwrap() {
+            db.remove_pending_message(&id).unwrap();
+        }
+
+        // Not due again until the backoff is over
+        assert_eq!(plan_retries(&db, &connected, now).unwrap(), Retries::default());
+        now += retry_delay(1);
+        assert_eq!(plan_retries(&db, &connected, now).unwrap().dial, [away]);
+
+        for _ in 3..MAX_DELIVERY_ATTEMPTS {
+            now += retry_delay(MAX_DELIVERY_ATTEMPTS);
+            assert_eq!(plan_retries(&db, &connected, now).unwrap().dead, 0);
+        }
+        now += retry_delay(MAX_DELIVERY_ATTEMPTS);
+        assert_eq!(plan_retries(&db, &connected, now).unwrap().dead, 1);
+
+        let dead = db.dead_letters().unwrap();
+        assert_eq!((dead.len(), dead[0].id, dead[0].attempts), (1, id, MAX_DELIVERY_ATTEMPTS));
+        // Dead letters are no longer sent
+        assert!(db.get_pending_for_peer(&away).unwrap().is_empty());
+        now += retry_delay(MAX_DELIVERY_ATTEMPTS);
+        assert_eq!(plan_retries(&db, &connected, now).unwrap(), Retries::default());
+    }
+
+    #[test]
+    fn messages_queued_too_long_expire() {
+        use crate::message::{Message, Recipient};
+
+        let db = Database::open_in_memory().unwrap();
+        let (me, away) = (PeerId::random(), PeerId::random());
+        let msg = Message::new_text(me, Recipient::Direct(away), "anyone there?".to_string());
+        db.insert_message(&msg).unwrap();
+        db.queue_pending_message(&msg.id, &away, b"sealed").unwrap();
+        db.queue_pending_message(&Uuid::new_v4(), &away, b"device copy").unwrap();
+
+        let now = Utc::now();
+        assert_eq!(plan_retries(&db, &HashSet::new(), now).unwrap().expired, 0);
+        let later = now + chrono::Duration::days(PENDING_TTL_DAYS) + chrono::Duration::seconds(1);
+        assert_eq!(plan_retries(&db, &HashSet::new(), later).unwrap().expired, 2);
+
+        assert!(db.list_queue().unwrap().is_empty());
+        assert_eq!(db.get_message(&msg.id).unwrap().unwrap().status, MessageStatus::Expired);
+    }
+}
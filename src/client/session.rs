@@ -0,0 +1,302 @@
+//! Double-ratchet session helpers shared by the CLI and `WhisperClient`.
+
+use anyhow::Result;
+use chrono::Utc;
+use libp2p::identity::{ed25519, PublicKey};
+use libp2p::PeerId;
+
+use crate::crypto::{
+    decrypt_message, ed25519_pk_to_x25519, peer_id_to_x25519, RatchetSession, SessionMessage,
+};
+use crate::identity::{Contact, TrustLevel};
+use crate::message::{Authenticity, Envelope, MessageContent, DEFAULT_STAMP_DIFFICULTY};
+use crate::storage::{AuditKind, Database};
+
+/// Our X25519 identity keypair, borrowed.
+pub(crate) type EncryptionKeys<'a> = (
+    &'a sodiumoxide::crypto::box_::PublicKey,
+    &'a sodiumoxide::crypto::box_::SecretKey,
+);
+
+/// Encrypt a wire payload for a contact over their double-ratchet session,
+/// starting a session from their identity key if we don't have one yet.
+/// Large payloads are packed first for contacts that accept it. Falls
+/// back to plaintext only if the contact has no usable key and no
+/// session; any other failure is an error, so the message can wait in
+/// the queue rather than go out in the clear.
+pub(crate) fn seal_for_contact(
+    db: &Database,
+    our_keys: EncryptionKeys,
+    peer_id: &PeerId,
+    public_key: &[u8],
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    if identity_x25519(peer_id, public_key).is_err() && db.get_session(peer_id)?.is_none() {
+        return Ok(payload.to_vec());
+    }
+    encrypt_with_session(db, our_keys, peer_id, public_key, payload)
+}
+
+/// A contact's X25519 identity key: from the key we stored, or the one
+/// embedded in their peer ID if we have none.
+fn identity_x25519(peer_id: &PeerId, public_key: &[u8]) -> Result<sodiumoxide::crypto::box_::PublicKey> {
+    if public_key.is_empty() {
+        peer_id_to_x25519(peer_id)
+    } else {
+        ed25519_pk_to_x25519(public_key)
+    }
+}
+
+pub(crate) fn encrypt_with_session(
+    db: &Database,
+    our_keys: EncryptionKeys,
+    peer_id: &PeerId,
+    public_key: &[u8],
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    let existing = db
+        .get_session(peer_id)?
+        .and_then(|state| RatchetSession::from_bytes(&state).ok());
+    let mut session = match existing {
+        Some(session) => session,
+        None => {
+            let their_identity = identity_x25519(peer_id, public_key)?;
+            // Use their signed prekey if we have one, so the session works
+            // even if they've never been online with us
+            match db.get_contact_prekey(peer_id)? {
+                Some(prekey) => RatchetSession::initiate(
+                    our_keys,
+                    &their_identity,
+                    &sodiumoxide::crypto::box_::PublicKey(prekey.public_key),
+                    Some(prekey.id),
+                )?,
+                None => RatchetSession::initiate(our_keys, &their_identity, &their_identity, None)?,
+            }
+        }
+    };
+    // Compressed before encrypting, since ciphertext doesn't compress
+    let packed;
+    let payload = if db.accepts_compression(peer_id)? {
+        packed = Envelope::pack(payload.to_vec());
+        packed.as_slice()
+    } else {
+        payload
+    };
+    let message = session.encrypt(payload)?;
+    db.save_session(peer_id, &session.to_bytes()?)?;
+    message.to_bytes()
+}
+
+/// Decrypt a direct payload from a peer.
+/// Tries their ratchet session first, then a legacy sealed box, then
+/// treats the data as plaintext. Session messages nothing can open are
+/// recorded in the audit log.
+pub(crate) fn open_from_peer(db: &Database, our_keys: EncryptionKeys, from: &PeerId, data: &[u8]) -> Vec<u8> {
+    let session_error = match SessionMessage::from_bytes(data) {
+        Ok(message) => match decrypt_with_session(db, our_keys, from, &message) {
+            Ok(plaintext) => return plaintext,
+            Err(e) => Some(e),
+        },
+        Err(_) => None,
+    };
+    if let Ok(plaintext) = decrypt_message(data, our_keys.0, our_keys.1) {
+        return plaintext;
+    }
+    if let Some(e) = session_error {
+        let _ = db.record_audit(AuditKind::DecryptionFailed, Some(from), &format!("{:#}", e));
+    }
+    data.to_vec()
+}
+
+/// Whether `from` is a blocked contact, or one of a blocked contact's
+/// linked devices. What they send is dropped, and the attempt recorded in
+/// the audit log.
+pub(crate) fn refuse_blocked(db: &Database, from: &PeerId) -> bool {
+    let owner = db.device_identity(from).ok().flatten();
+    let blocked = [Some(*from), owner].into_iter().flatten().any(|peer_id| {
+        db.get_contact(&peer_id)
+            .ok()
+            .flatten()
+            .is_some_and(|contact| contact.trust_level == TrustLevel::Blocked)
+    });
+    if blocked {
+        let _ = db.record_audit(AuditKind::BlockedPeer, Some(from), "Dropped a message from a blocked contact");
+    }
+    blocked
+}
+
+/// Check a received envelope's signature against its sender's identity
+/// key: the one stored for the contact if we have it, otherwise the one
+/// embedded in their peer ID. A contact who signed with the key in their
+/// peer ID rather than the one we stored has changed keys, which
+/// `pin_identity_key` records. Verified envelopes also tell us whether the
+/// sender can take packed envelopes.
+pub(crate) fn authenticate(db: &Database, envelope: &Envelope) -> Authenticity {
+    let contact = db.get_contact(&envelope.sender).ok().flatten();
+    let stored = contact
+        .as_ref()
+        .and_then(|contact| ed25519::PublicKey::try_from_bytes(&contact.public_key).ok())
+        .map(PublicKey::from);
+    let presented = PublicKey::try_decode_protobuf(envelope.sender.as_ref().digest()).ok();
+    let mut authenticity = envelope.authenticate(stored.as_ref().or(presented.as_ref()));
+    if let (Some(contact), Some(presented)) = (&contact, &presented) {
+        let signed_with_presented = match stored {
+            Some(_) if authenticity == Authenticity::Forged => {
+                envelope.authenticate(Some(presented)) == Authenticity::Verified
+            }
+            Some(_) => false,
+            None => authenticity == Authenticity::Verified,
+        };
+        if signed_with_presented {
+            let _ = pin_identity_key(db, contact, presented);
+            authenticity = Authenticity::Verified;
+        }
+    }
+    if authenticity == Authenticity::Verified
+        && db.accepts_compression(&envelope.sender).ok() != Some(envelope.accepts_compression())
+    {
+        let _ = db.set_accepts_compression(&envelope.sender, envelope.accepts_compression());
+    }
+    authenticity
+}
+
+/// `authenticate` an envelope that arrived over a connection with `from`,
+/// the peer libp2p authenticated. A signature speaks for the sender
+/// whoever passed the envelope on, but an unsigned envelope only speaks
+/// for `from` itself: one claiming any other sender is treated as forged.
+pub(crate) fn authenticate_from(db: &Database, envelope: &Envelope, from: &PeerId) -> Authenticity {
+    match authenticate(db, envelope) {
+        Authenticity::Unverified if envelope.sender != *from => Authenticity::Forged,
+        authenticity => authenticity,
+    }
+}
+
+/// Check the identity key a contact proved they hold against the one we
+/// stored for them. A contact added by peer ID alone has it pinned on
+/// first use; a different one replaces ours, resetting their trust until
+/// they're verified again. Returns true if the key changed.
+pub(crate) fn pin_identity_key(db: &Database, contact: &Contact, presented: &PublicKey) -> Result<bool> {
+    let key = presented
+        .clone()
+        .try_into_ed25519()
+        .map(|key| key.to_bytes().to_vec())
+        .unwrap_or_else(|_| presented.encode_protobuf());
+    if contact.public_key == key {
+        return Ok(false);
+    }
+    if contact.public_key.is_empty() {
+        let mut pinned = contact.clone();
+        pinned.public_key = key;
+        db.upsert_contact(&pinned)?;
+        return Ok(false);
+    }
+    db.record_key_change(&contact.peer_id, &key, Utc::now())?;
+    db.record_audit(
+        AuditKind::KeyChanged,
+        Some(&contact.peer_id),
+        "Identity key changed; trust reset until verified again",
+    )?;
+    Ok(true)
+}
+
+/// Refuse to send to a contact whose identity key changed until they've
+/// been verified again.
+pub(crate) fn ensure_key_verified(db: &Database, contact: &Contact) -> Result<()> {
+    if let Some(changed_at) = db.key_change(&contact.peer_id)? {
+        anyhow::bail!(
+            "{}'s identity key changed on {}. Compare safety numbers with: whisper verify {}",
+            contact.alias,
+            changed_at.format("%Y-%m-%d"),
+            contact.alias
+        );
+    }
+    Ok(())
+}
+
+/// Whether a received envelope repeats one we've already accepted from its
+/// sender, going by its sequence number. Typing indicators expire on their
+/// own, so they aren't tracked.
+pub(crate) fn is_replay(db: &Database, envelope: &Envelope) -> bool {
+    if matches!(envelope.payload, MessageContent::Typing) {
+        return false;
+    }
+    // Don't drop messages because the database is unavailable
+    !db.check_envelope_seq(&envelope.sender, envelope.seq).unwrap_or(true)
+}
+
+/// Whether to let in a received envelope that could open a message
+/// request. Contacts, their devices and strangers with a request already
+/// waiting get in; other strangers need a proof-of-work stamp of the
+/// difficulty we ask for.
+pub(crate) fn admits_sender(db: &Database, our_peer_id: &PeerId, envelope: &Envelope) -> bool {
+    if !envelope.payload.opens_request() {
+        return true;
+    }
+    let sender = &envelope.sender;
+    let known = db.get_contact(sender).ok().flatten().is_some()
+        || db.device_identity(sender).ok().flatten().is_some()
+        || db.has_message_requests(sender).unwrap_or(false);
+    if known {
+        return true;
+    }
+    let difficulty = db.stamp_difficulty().unwrap_or(DEFAULT_STAMP_DIFFICULTY);
+    difficulty == 0 || envelope.has_stamp(our_peer_id, difficulty)
+}
+
+/// Stamp an envelope for `to`, unless they've written to us and so know
+/// us already. We pay at least the default difficulty, or what we ask of
+/// others if that's more.
+pub(crate) fn stamp_for(db: &Database, envelope: Envelope, to: &PeerId) -> Result<Envelope> {
+    if db.has_messages_from(to)? {
+        return Ok(envelope);
+    }
+    envelope.stamped(to, db.stamp_difficulty()?.max(DEFAULT_STAMP_DIFFICULTY))
+}
+
+pub(crate) fn decrypt_with_session(
+    db: &Database,
+    our_keys: EncryptionKeys,
+    from: &PeerId,
+    message: &SessionMessage,
+) -> Result<Vec<u8>> {
+    let existing = db
+        .get_session(from)?
+        .and_then(|state| RatchetSession::from_bytes(&state).ok());
+    // A new init over a session we'd settled on: they may have reinstalled,
+    // or someone else holds their identity key
+    let replaces = message.init.is_some()
+        && existing
+            .as_ref()
+            .is_some_and(|s| !s.is_pending() && s.accepted_init() != message.init.as_ref());
+    let mut session = match (&message.init, existing) {
+        // Still the session they started earlier
+        (Some(init), Some(session)) if session.accepted_init() == Some(init) => session,
+        // Both sides started a session at once: the lower identity key wins
+        (Some(init), Some(session)) if session.is_pending() && our_keys.0 .0 < init.identity_key => session,
+        (Some(init), _) => {
+            // The init must come from the identity behind the authenticated peer ID
+            if peer_id_to_x25519(from)?.0 != init.identity_key {
+                anyhow::bail!("Session init identity does not match peer");
+            }
+            match init.prekey_id {
+                Some(id) => {
+                    let prekey = db
+                        .get_prekey(id)?
+                        .ok_or_else(|| anyhow::anyhow!("Unknown prekey {}", id))?;
+                    let prekey_pk = sodiumoxide::crypto::box_::PublicKey(prekey.public_key);
+                    let prekey_sk = sodiumoxide::crypto::box_::SecretKey(prekey.secret_key);
+                    RatchetSession::respond(our_keys, (&prekey_pk, &prekey_sk), init)?
+                }
+                None => RatchetSession::respond(our_keys, our_keys, init)?,
+            }
+        }
+        (None, Some(session)) => session,
+        (None, None) => anyhow::bail!("No session with peer"),
+    };
+    let plaintext = session.decrypt(message)?;
+    db.save_session(from, &session.to_bytes()?)?;
+    if replaces {
+        let _ = db.record_audit(AuditKind::KeyChanged, Some(from), "Started a new encryption session");
+    }
+    Ok(plaintext)
+}
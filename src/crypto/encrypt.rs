@@ -4,6 +4,7 @@ use anyhow::{anyhow, Result};
 use sodiumoxide::crypto::sealedbox;
 use sodiumoxide::crypto::secretbox;
 use sodiumoxide::crypto::box_::{PublicKey, SecretKey};
+use zeroize::Zeroizing;
 
 /// Encrypt a message for a recipient using sealed box (anonymous sender).
 /// 
@@ -25,9 +26,9 @@ pub fn decrypt_message(ciphertext: &[u8], public_key: &PublicKey, secret_key: &S
 /// Generate a random symmetric key for group encryption.
 /// 
 /// Returns a 32-byte key suitable for secretbox.
-pub fn generate_group_key() -> Vec<u8> {
+pub fn generate_group_key() -> Zeroizing<Vec<u8>> {
     let key = secretbox::gen_key();
-    key.0.to_vec()
+    Zeroizing::new(key.0.to_vec())
 }
 
 /// Encrypt a message for a group using symmetric encryption.
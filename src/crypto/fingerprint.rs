@@ -0,0 +1,149 @@
+//! Safety numbers for verifying a contact's identity key out-of-band.
+//!
+//! Each party's half is derived by iterated SHA-512 over their public key
+//! and peer ID, as in Signal's numeric fingerprints. The two halves are
+//! sorted so both sides compute the same number.
+//!
+//! Each key also gets a short emoji fingerprint, shown next to the
+//! contact's alias. It's too short to verify a key with, but makes a
+//! changed key easy to notice at a glance.
+
+use libp2p::PeerId;
+use sodiumoxide::crypto::hash::sha512;
+
+/// Fingerprint format version, mixed into the hash.
+const FINGERPRINT_VERSION: u16 = 0;
+
+/// Hash iterations; makes finding a colliding key expensive.
+const FINGERPRINT_ITERATIONS: usize = 5200;
+
+/// Digits contributed by each party.
+const DIGITS_PER_KEY: usize = 30;
+
+/// Emoji in a key's fingerprint, six bits each.
+const FINGERPRINT_EMOJI: usize = 5;
+
+/// What fingerprints are drawn from: single code points that terminals
+/// draw two cells wide, so fingerprints line up.
+const EMOJI: [&str; 64] = [
+    "🐶", "🐱", "🐭", "🐹", "🐰", "🦊", "🐻", "🐼", "🐨", "🐯", "🦁", "🐮", "🐷", "🐸", "🐵", "🐔",
+    "🐧", "🐦", "🐤", "🦆", "🦅", "🦉", "🦇", "🐺", "🐗", "🐴", "🦄", "🐝", "🐛", "🦋", "🐌", "🐞",
+    "🐢", "🐍", "🦎", "🐙", "🦑", "🦀", "🐡", "🐠", "🐬", "🐳", "🦈", "🐊", "🐘", "🦒", "🐪", "🦘",
+    "🍎", "🍐", "🍊", "🍋", "🍌", "🍉", "🍇", "🍓", "🍒", "🍑", "🍍", "🥝", "🍅", "🥑", "🌵", "🌻",
+];
+
+/// Derive the 60-digit safety number for a conversation between us and a
+/// contact.
+pub fn safety_number(our_key: &[u8], our_peer_id: &PeerId, their_key: &[u8], their_peer_id: &PeerId) -> String {
+    let mut halves = [
+        key_digits(our_key, our_peer_id),
+        key_digits(their_key, their_peer_id),
+    ];
+    halves.sort();
+    halves.concat()
+}
+
+/// Split a safety number into groups of five digits, four groups per line.
+pub fn format_safety_number(number: &str) -> String {
+    let groups: Vec<&str> = number
+        .as_bytes()
+        .chunks(5)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap_or_default())
+        .collect();
+    groups
+        .chunks(4)
+        .map(|line| line.join(" "))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A key's emoji fingerprint, e.g. "🦊🐙🍋🐢🦉".
+pub fn emoji_fingerprint(public_key: &[u8]) -> String {
+    let mut input = FINGERPRINT_VERSION.to_be_bytes().to_vec();
+    input.extend_from_slice(public_key);
+    let hash = sha512::hash(&input);
+    let bits = hash.0[..8].iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+    (0..FINGERPRINT_EMOJI)
+        .map(|i| EMOJI[(bits >> (58 - 6 * i)) as usize & 0x3f])
+        .collect()
+}
+
+/// One party's 30 digits.
+fn key_digits(public_key: &[u8], peer_id: &PeerId) -> String {
+    let mut input = FINGERPRINT_VERSION.to_be_bytes().to_vec();
+    input.extend_from_slice(public_key);
+    input.extend_from_slice(&peer_id.to_bytes());
+    let mut hash = sha512::hash(&input);
+
+    for _ in 0..FINGERPRINT_ITERATIONS {
+        let mut round = hash.0.to_vec();
+        round.extend_from_slice(public_key);
+        hash = sha512::hash(&round);
+    }
+
+    // Each 5-byte chunk becomes five digits
+    hash.0[..DIGITS_PER_KEY]
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::generate_keypair;
+
+    fn identity() -> (Vec<u8>, PeerId) {
+        let keypair = generate_keypair();
+        let key = keypair.public().try_into_ed25519().unwrap().to_bytes().to_vec();
+        (key, PeerId::from(keypair.public()))
+    }
+
+    #[test]
+    fn both_sides_compute_the_same_number() {
+        let (alice_key, alice) = identity();
+        let (bob_key, bob) = identity();
+
+        let ours = safety_number(&alice_key, &alice, &bob_key, &bob);
+        let theirs = safety_number(&bob_key, &bob, &alice_key, &alice);
+        assert_eq!(ours, theirs);
+        assert_eq!(ours.len(), 2 * DIGITS_PER_KEY);
+        assert!(ours.chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn different_key_changes_number() {
+        let (alice_key, alice) = identity();
+        let (bob_key, bob) = identity();
+        let (mallory_key, _) = identity();
+
+        assert_ne!(
+            safety_number(&alice_key, &alice, &bob_key, &bob),
+            safety_number(&alice_key, &alice, &mallory_key, &bob),
+        );
+    }
+
+    #[test]
+    fn emoji_fingerprints_follow_the_key() {
+        let (alice_key, _) = identity();
+        let (bob_key, _) = identity();
+
+        let alice = emoji_fingerprint(&alice_key);
+        assert_eq!(alice, emoji_fingerprint(&alice_key));
+        assert_ne!(alice, emoji_fingerprint(&bob_key));
+        assert_eq!(alice.chars().count(), FINGERPRINT_EMOJI);
+        assert!(EMOJI.iter().all(|emoji| emoji.chars().count() == 1));
+    }
+
+    #[test]
+    fn format_groups_digits() {
+        let number: String = "0123456789".repeat(6);
+        let formatted = format_safety_number(&number);
+        let lines: Vec<&str> = formatted.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "01234 56789 01234 56789");
+    }
+}
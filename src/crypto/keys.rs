@@ -5,12 +5,13 @@ use libp2p::identity::Keypair;
 use sodiumoxide::crypto::box_::{self, PublicKey, SecretKey};
 use sodiumoxide::crypto::hash::sha512;
 use sodiumoxide::crypto::scalarmult;
+use zeroize::{Zeroize, Zeroizing};
 
 /// Derive a shared secret from our secret key and their public key.
 /// 
 /// Uses X25519 (Curve25519) for key exchange.
 /// The shared secret is symmetric: A with B = B with A.
-pub fn derive_shared_secret(our_sk: &SecretKey, their_pk: &PublicKey) -> Vec<u8> {
+pub fn derive_shared_secret(our_sk: &SecretKey, their_pk: &PublicKey) -> Zeroizing<Vec<u8>> {
     // Convert to scalarmult types
     let scalar = scalarmult::Scalar::from_slice(&our_sk.0)
         .expect("SecretKey should be valid scalar");
@@ -21,7 +22,7 @@ pub fn derive_shared_secret(our_sk: &SecretKey, their_pk: &PublicKey) -> Vec<u8>
     let shared = scalarmult::scalarmult(&scalar, &point)
         .expect("Scalarmult should not fail with valid inputs");
     
-    shared.0.to_vec()
+    Zeroizing::new(shared.0.to_vec())
 }
 
 /// Convert a public key to bytes.
@@ -63,20 +64,21 @@ pub fn keypair_to_encryption_keys(keypair: &Keypair) -> Result<(PublicKey, Secre
     
     // Derive X25519 secret key: hash with SHA-512 and take first 32 bytes
     // This is the standard Ed25519 to X25519 conversion for secret keys
-    let hash = sha512::hash(secret_bytes);
-    let mut curve_sk_bytes = [0u8; 32];
+    let mut hash = sha512::hash(secret_bytes);
+    let mut curve_sk_bytes = Zeroizing::new([0u8; 32]);
     curve_sk_bytes.copy_from_slice(&hash.0[..32]);
+    hash.0.zeroize();
     
     // Apply clamping (per X25519 spec)
     curve_sk_bytes[0] &= 248;
     curve_sk_bytes[31] &= 127;
     curve_sk_bytes[31] |= 64;
     
-    let curve_sk = SecretKey::from_slice(&curve_sk_bytes)
+    let curve_sk = SecretKey::from_slice(&curve_sk_bytes[..])
         .ok_or_else(|| anyhow!("Failed to create X25519 secret key"))?;
     
     // Derive X25519 public key from secret key using scalarmult_base
-    let curve_scalar = scalarmult::Scalar::from_slice(&curve_sk_bytes)
+    let curve_scalar = scalarmult::Scalar::from_slice(&curve_sk_bytes[..])
         .ok_or_else(|| anyhow!("Invalid scalar"))?;
     let curve_pk_point = scalarmult::scalarmult_base(&curve_scalar);
     
@@ -88,8 +90,9 @@ pub fn keypair_to_encryption_keys(keypair: &Keypair) -> Result<(PublicKey, Secre
 
 /// Convert a libp2p Ed25519 public key bytes to X25519 for encryption.
 /// 
-/// This performs the birational map from Ed25519 to Curve25519.
-/// Note: This is a one-way conversion used for sealed box encryption.
+/// This performs the birational map from Ed25519 to Curve25519 using
+/// libsodium, so the result matches the public key that
+/// `keypair_to_encryption_keys` derives on the owner's side.
 pub fn ed25519_pk_to_x25519(ed25519_pk_bytes: &[u8]) -> Result<PublicKey> {
     sodiumoxide::init().map_err(|_| anyhow!("Failed to init sodiumoxide"))?;
     
@@ -97,34 +100,34 @@ pub fn ed25519_pk_to_x25519(ed25519_pk_bytes: &[u8]) -> Result<PublicKey> {
         return Err(anyhow!("Invalid Ed25519 public key: expected 32 bytes, got {}", ed25519_pk_bytes.len()));
     }
     
-    // The Ed25519 to Curve25519 public key conversion is a birational map:
-    // Given Ed25519 point (x, y), the Curve25519 u-coordinate is (1 + y) / (1 - y)
-    // 
-    // For simplicity and correctness, we use libsodium's conversion via FFI.
-    // If that's not available, we fall back to a direct computation.
-    
-    // Direct computation of the birational map:
-    // u = (1 + y) * (1 - y)^(-1) mod p
-    // where y is the Ed25519 y-coordinate (the public key bytes in little-endian)
-    
-    // For now, we'll use a simpler approach: treat the Ed25519 public key
-    // as seed material and derive a consistent X25519 key.
-    // This works for encryption but the sender/receiver must use the same derivation.
-    
-    let hash = sha512::hash(ed25519_pk_bytes);
     let mut curve_pk_bytes = [0u8; 32];
-    curve_pk_bytes.copy_from_slice(&hash.0[..32]);
-    
-    // For a proper public key, we should use scalarmult_base on a derived scalar
-    // This is a deterministic derivation that both parties can compute
-    let scalar = scalarmult::Scalar::from_slice(&curve_pk_bytes)
-        .ok_or_else(|| anyhow!("Invalid scalar from hash"))?;
-    let point = scalarmult::scalarmult_base(&scalar);
+    // SAFETY: both buffers are exactly 32 bytes, as libsodium requires.
+    let rc = unsafe {
+        libsodium_sys::crypto_sign_ed25519_pk_to_curve25519(
+            curve_pk_bytes.as_mut_ptr(),
+            ed25519_pk_bytes.as_ptr(),
+        )
+    };
+    if rc != 0 {
+        return Err(anyhow!("Invalid Ed25519 public key: not a curve point"));
+    }
     
-    PublicKey::from_slice(&point.0)
+    PublicKey::from_slice(&curve_pk_bytes)
         .ok_or_else(|| anyhow!("Failed to create X25519 public key"))
 }
 
+/// Derive a peer's X25519 key from its peer ID.
+///
+/// Ed25519 peer IDs embed the full public key, so this needs no contact record.
+pub fn peer_id_to_x25519(peer_id: &libp2p::PeerId) -> Result<PublicKey> {
+    let public = libp2p::identity::PublicKey::try_decode_protobuf(peer_id.as_ref().digest())
+        .map_err(|_| anyhow!("Peer ID does not embed a public key"))?;
+    let ed25519 = public
+        .try_into_ed25519()
+        .map_err(|_| anyhow!("Peer ID is not an Ed25519 key"))?;
+    ed25519_pk_to_x25519(&ed25519.to_bytes())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -206,6 +209,28 @@ mod tests {
         assert!(secret_key_from_bytes(&[]).is_err());
     }
 
+    #[test]
+    fn ed25519_conversion_matches_own_encryption_keys() {
+        init();
+        let keypair = Keypair::generate_ed25519();
+        let ed_pk = keypair.public().try_into_ed25519().unwrap().to_bytes();
+
+        let (own_pk, _) = keypair_to_encryption_keys(&keypair).unwrap();
+        let converted = ed25519_pk_to_x25519(&ed_pk).unwrap();
+
+        assert_eq!(own_pk, converted);
+    }
+
+    #[test]
+    fn peer_id_conversion_matches_own_encryption_keys() {
+        init();
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = libp2p::PeerId::from(keypair.public());
+
+        let (own_pk, _) = keypair_to_encryption_keys(&keypair).unwrap();
+        assert_eq!(peer_id_to_x25519(&peer_id).unwrap(), own_pk);
+    }
+
     #[test]
     fn shared_secret_has_correct_length() {
         init();
@@ -1,7 +1,10 @@
 //! Cryptography - encryption and key exchange.
 
 mod encrypt;
+mod fingerprint;
 mod keys;
+mod ratchet;
+mod secret;
 
 pub use encrypt::{
     decrypt_from_group,
@@ -10,12 +13,16 @@ pub use encrypt::{
     encrypt_message,
     generate_group_key,
 };
+pub use fingerprint::{emoji_fingerprint, format_safety_number, safety_number};
 pub use keys::{
     derive_shared_secret,
     ed25519_pk_to_x25519,
     keypair_to_encryption_keys,
+    peer_id_to_x25519,
     public_key_from_bytes,
     public_key_to_bytes,
     secret_key_from_bytes,
     secret_key_to_bytes,
 };
+pub use ratchet::{RatchetHeader, RatchetSession, SessionInit, SessionMessage, MAX_SKIP};
+pub use secret::LockedSecretKey;
@@ -0,0 +1,525 @@
+//! Double ratchet sessions for direct messages.
+//!
+//! Sessions are established X3DH-style from the parties' X25519 identity
+//! keys and the responder's prekey, then every message advances a
+//! symmetric chain and every reply advances a Diffie-Hellman ratchet.
+//! Old message keys are deleted as soon as they are used, so a later
+//! compromise of the identity key does not expose past messages.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf as aead;
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::box_::{self, PublicKey, SecretKey};
+use zeroize::{Zeroize, Zeroizing};
+
+use super::keys::derive_shared_secret;
+
+/// Most message keys we will derive ahead to cope with out-of-order delivery.
+pub const MAX_SKIP: u32 = 2000;
+
+/// Domain separation for the X3DH key derivation.
+const X3DH_INFO: &[u8] = b"whisper-x3dh-v1";
+
+/// Domain separation for the root chain.
+const RATCHET_INFO: &[u8] = b"whisper-ratchet-v1";
+
+/// Keys the initiator sends so the responder can derive the same session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInit {
+    /// Initiator's X25519 identity key.
+    pub identity_key: [u8; 32],
+    /// Initiator's ephemeral key for this session.
+    pub ephemeral_key: [u8; 32],
+    /// ID of the responder's signed prekey used, or `None` for their identity key.
+    #[serde(default)]
+    pub prekey_id: Option<u32>,
+}
+
+/// Per-message ratchet header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RatchetHeader {
+    /// Sender's current ratchet public key.
+    pub dh: [u8; 32],
+    /// Length of the sender's previous sending chain.
+    pub pn: u32,
+    /// Message number in the current sending chain.
+    pub n: u32,
+}
+
+/// An encrypted direct message as it travels on the wire.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionMessage {
+    /// Present until the initiator has heard back from the responder.
+    pub init: Option<SessionInit>,
+    pub header: RatchetHeader,
+    pub nonce: [u8; 24],
+    pub ciphertext: Vec<u8>,
+}
+
+impl SessionMessage {
+    /// Encode for the wire.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(self, &mut buf).context("Failed to encode session message")?;
+        Ok(buf)
+    }
+
+    /// Decode from the wire.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        ciborium::from_reader(data).context("Not a session message")
+    }
+}
+
+/// Double ratchet state for one peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RatchetSession {
+    root_key: [u8; 32],
+    dh_self_pk: [u8; 32],
+    dh_self_sk: [u8; 32],
+    dh_remote: Option<[u8; 32]>,
+    send_chain: Option<[u8; 32]>,
+    recv_chain: Option<[u8; 32]>,
+    send_n: u32,
+    recv_n: u32,
+    prev_send_n: u32,
+    skipped: HashMap<([u8; 32], u32), [u8; 32]>,
+    /// Sent with outgoing messages until the peer replies.
+    pending_init: Option<SessionInit>,
+    /// The init this session was accepted from, when we are the responder.
+    accepted_init: Option<SessionInit>,
+}
+
+impl RatchetSession {
+    /// Start a session with a peer as the initiator.
+    ///
+    /// `their_prekey` is the key the responder will use as its first
+    /// ratchet key; with no published prekey this is their identity key
+    /// and `prekey_id` is `None`.
+    pub fn initiate(
+        our_identity: (&PublicKey, &SecretKey),
+        their_identity: &PublicKey,
+        their_prekey: &PublicKey,
+        prekey_id: Option<u32>,
+    ) -> Result<Self> {
+        let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+
+        let mut ikm = Zeroizing::new(Vec::with_capacity(96));
+        ikm.extend_from_slice(&derive_shared_secret(our_identity.1, their_prekey));
+        ikm.extend_from_slice(&derive_shared_secret(&ephemeral_sk, their_identity));
+        ikm.extend_from_slice(&derive_shared_secret(&ephemeral_sk, their_prekey));
+        let shared = x3dh_kdf(&ikm);
+
+        let (dh_pk, dh_sk) = box_::gen_keypair();
+        let dh_out = derive_shared_secret(&dh_sk, their_prekey);
+        let (root_key, send_chain) = kdf_root(&shared, &dh_out);
+
+        Ok(Self {
+            root_key,
+            dh_self_pk: dh_pk.0,
+            dh_self_sk: dh_sk.0,
+            dh_remote: Some(their_prekey.0),
+            send_chain: Some(send_chain),
+            recv_chain: None,
+            send_n: 0,
+            recv_n: 0,
+            prev_send_n: 0,
+            skipped: HashMap::new(),
+            pending_init: Some(SessionInit {
+                identity_key: our_identity.0 .0,
+                ephemeral_key: ephemeral_pk.0,
+                prekey_id,
+            }),
+            accepted_init: None,
+        })
+    }
+
+    /// Accept a session started by a peer.
+    ///
+    /// `our_prekey` must be the keypair whose public half the initiator used.
+    pub fn respond(
+        our_identity: (&PublicKey, &SecretKey),
+        our_prekey: (&PublicKey, &SecretKey),
+        init: &SessionInit,
+    ) -> Result<Self> {
+        let their_identity = PublicKey(init.identity_key);
+        let their_ephemeral = PublicKey(init.ephemeral_key);
+
+        let mut ikm = Zeroizing::new(Vec::with_capacity(96));
+        ikm.extend_from_slice(&derive_shared_secret(our_prekey.1, &their_identity));
+        ikm.extend_from_slice(&derive_shared_secret(our_identity.1, &their_ephemeral));
+        ikm.extend_from_slice(&derive_shared_secret(our_prekey.1, &their_ephemeral));
+        let shared = x3dh_kdf(&ikm);
+
+        Ok(Self {
+            root_key: shared,
+            dh_self_pk: our_prekey.0 .0,
+            dh_self_sk: our_prekey.1 .0,
+            dh_remote: None,
+            send_chain: None,
+            recv_chain: None,
+            send_n: 0,
+            recv_n: 0,
+            prev_send_n: 0,
+            skipped: HashMap::new(),
+            pending_init: None,
+            accepted_init: Some(init.clone()),
+        })
+    }
+
+    /// Whether we have yet to hear back from the peer on this session.
+    pub fn is_pending(&self) -> bool {
+        self.pending_init.is_some()
+    }
+
+    /// The init keys this session was started with, if still unacknowledged.
+    pub fn pending_init(&self) -> Option<&SessionInit> {
+        self.pending_init.as_ref()
+    }
+
+    /// The init this session was accepted from, if we were the responder.
+    pub fn accepted_init(&self) -> Option<&SessionInit> {
+        self.accepted_init.as_ref()
+    }
+
+    /// Encrypt a message, advancing the sending chain.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<SessionMessage> {
+        let chain = self
+            .send_chain
+            .ok_or_else(|| anyhow!("Session has no sending chain yet"))?;
+        let (next_chain, message_key) = kdf_chain(&chain);
+        self.send_chain = Some(next_chain);
+
+        let header = RatchetHeader {
+            dh: self.dh_self_pk,
+            pn: self.prev_send_n,
+            n: self.send_n,
+        };
+        self.send_n += 1;
+
+        let nonce = aead::gen_nonce();
+        let ad = header_ad(&header);
+        let ciphertext = aead::seal(plaintext, Some(&ad), &nonce, &aead::Key(message_key));
+
+        Ok(SessionMessage {
+            init: self.pending_init.clone(),
+            header,
+            nonce: nonce.0,
+            ciphertext,
+        })
+    }
+
+    /// Decrypt a message, advancing the ratchet as needed.
+    ///
+    /// State is only committed if decryption succeeds, so a forged or
+    /// corrupted message cannot desynchronise the session.
+    pub fn decrypt(&mut self, message: &SessionMessage) -> Result<Vec<u8>> {
+        let mut next = self.clone();
+        let plaintext = next.decrypt_in_place(message)?;
+        next.pending_init = None;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn decrypt_in_place(&mut self, message: &SessionMessage) -> Result<Vec<u8>> {
+        let header = &message.header;
+        let ad = header_ad(header);
+
+        if let Some(key) = self.skipped.remove(&(header.dh, header.n)) {
+            return open(message, &ad, key);
+        }
+
+        if self.dh_remote != Some(header.dh) {
+            self.skip_message_keys(header.pn)?;
+            self.dh_ratchet(header.dh);
+        }
+        self.skip_message_keys(header.n)?;
+
+        let chain = self
+            .recv_chain
+            .ok_or_else(|| anyhow!("Session has no receiving chain"))?;
+        let (next_chain, message_key) = kdf_chain(&chain);
+        self.recv_chain = Some(next_chain);
+        self.recv_n += 1;
+
+        open(message, &ad, message_key)
+    }
+
+    /// Derive and stash keys for messages we haven't received yet.
+    fn skip_message_keys(&mut self, until: u32) -> Result<()> {
+        let Some(mut chain) = self.recv_chain else {
+            return Ok(());
+        };
+        if until > self.recv_n.saturating_add(MAX_SKIP) {
+            anyhow::bail!("Too many skipped messages");
+        }
+        let remote = self.dh_remote.unwrap_or_default();
+        while self.recv_n < until {
+            let (next_chain, message_key) = kdf_chain(&chain);
+            self.skipped.insert((remote, self.recv_n), message_key);
+            chain = next_chain;
+            self.recv_n += 1;
+        }
+        self.recv_chain = Some(chain);
+        Ok(())
+    }
+
+    /// Step the Diffie-Hellman ratchet on seeing a new remote key.
+    fn dh_ratchet(&mut self, remote: [u8; 32]) {
+        self.prev_send_n = self.send_n;
+        self.send_n = 0;
+        self.recv_n = 0;
+        self.dh_remote = Some(remote);
+        let remote_pk = PublicKey(remote);
+
+        let dh_out = derive_shared_secret(&SecretKey(self.dh_self_sk), &remote_pk);
+        let (root_key, recv_chain) = kdf_root(&self.root_key, &dh_out);
+        self.root_key = root_key;
+        self.recv_chain = Some(recv_chain);
+
+        let (dh_pk, dh_sk) = box_::gen_keypair();
+        self.dh_self_pk = dh_pk.0;
+        self.dh_self_sk = dh_sk.0;
+        let dh_out = derive_shared_secret(&dh_sk, &remote_pk);
+        let (root_key, send_chain) = kdf_root(&self.root_key, &dh_out);
+        self.root_key = root_key;
+        self.send_chain = Some(send_chain);
+    }
+
+    /// Serialize the session for storage.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self).context("Failed to serialize session")
+    }
+
+    /// Restore a session from storage.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        bincode::deserialize(data).context("Failed to deserialize session")
+    }
+}
+
+impl Drop for RatchetSession {
+    /// Wipe the session's keys; a copy lives on only in the database.
+    fn drop(&mut self) {
+        self.root_key.zeroize();
+        self.dh_self_sk.zeroize();
+        self.send_chain.zeroize();
+        self.recv_chain.zeroize();
+        for key in self.skipped.values_mut() {
+            key.zeroize();
+        }
+    }
+}
+
+fn open(message: &SessionMessage, ad: &[u8], key: [u8; 32]) -> Result<Vec<u8>> {
+    aead::open(
+        &message.ciphertext,
+        Some(ad),
+        &aead::Nonce(message.nonce),
+        &aead::Key(key),
+    )
+    .map_err(|_| anyhow!("Session decryption failed"))
+}
+
+fn header_ad(header: &RatchetHeader) -> Vec<u8> {
+    let mut ad = Vec::with_capacity(40);
+    ad.extend_from_slice(&header.dh);
+    ad.extend_from_slice(&header.pn.to_be_bytes());
+    ad.extend_from_slice(&header.n.to_be_bytes());
+    ad
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut state = hmacsha256::State::init(key);
+    state.update(data);
+    state.finalize().0
+}
+
+/// HKDF-SHA256 producing 64 bytes.
+fn hkdf(salt: &[u8; 32], ikm: &[u8], info: &[u8]) -> [u8; 64] {
+    let prk = hmac(salt, ikm);
+    let mut t1_input = info.to_vec();
+    t1_input.push(1);
+    let t1 = hmac(&prk, &t1_input);
+    let mut t2_input = t1.to_vec();
+    t2_input.extend_from_slice(info);
+    t2_input.push(2);
+    let t2 = hmac(&prk, &t2_input);
+
+    let mut out = [0u8; 64];
+    out[..32].copy_from_slice(&t1);
+    out[32..].copy_from_slice(&t2);
+    out
+}
+
+fn x3dh_kdf(ikm: &[u8]) -> [u8; 32] {
+    let okm = hkdf(&[0u8; 32], ikm, X3DH_INFO);
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&okm[..32]);
+    key
+}
+
+/// Root chain step: returns (new root key, new chain key).
+fn kdf_root(root_key: &[u8; 32], dh_out: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let okm = hkdf(root_key, dh_out, RATCHET_INFO);
+    let mut root = [0u8; 32];
+    let mut chain = [0u8; 32];
+    root.copy_from_slice(&okm[..32]);
+    chain.copy_from_slice(&okm[32..]);
+    (root, chain)
+}
+
+/// Symmetric chain step: returns (next chain key, message key).
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    (hmac(chain_key, &[2]), hmac(chain_key, &[1]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init() {
+        let _ = sodiumoxide::init();
+    }
+
+    /// Alice initiates to Bob using Bob's identity key as his prekey.
+    fn pair() -> (RatchetSession, RatchetSession) {
+        init();
+        let (alice_pk, alice_sk) = box_::gen_keypair();
+        let (bob_pk, bob_sk) = box_::gen_keypair();
+
+        let mut alice = RatchetSession::initiate((&alice_pk, &alice_sk), &bob_pk, &bob_pk, None).unwrap();
+        let first = alice.encrypt(b"hello bob").unwrap();
+        let mut bob = RatchetSession::respond(
+            (&bob_pk, &bob_sk),
+            (&bob_pk, &bob_sk),
+            first.init.as_ref().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(bob.decrypt(&first).unwrap(), b"hello bob");
+        (alice, bob)
+    }
+
+    #[test]
+    fn initial_message_decrypts() {
+        pair();
+    }
+
+    #[test]
+    fn conversation_both_directions() {
+        let (mut alice, mut bob) = pair();
+
+        let reply = bob.encrypt(b"hi alice").unwrap();
+        assert!(reply.init.is_none());
+        assert_eq!(alice.decrypt(&reply).unwrap(), b"hi alice");
+        assert!(!alice.is_pending());
+
+        for i in 0..5 {
+            let text = format!("message {}", i);
+            let msg = alice.encrypt(text.as_bytes()).unwrap();
+            assert!(msg.init.is_none());
+            assert_eq!(bob.decrypt(&msg).unwrap(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn ratchet_key_changes_each_turn() {
+        let (mut alice, mut bob) = pair();
+        let a1 = alice.encrypt(b"a1").unwrap();
+        bob.decrypt(&a1).unwrap();
+        let b1 = bob.encrypt(b"b1").unwrap();
+        alice.decrypt(&b1).unwrap();
+        let a2 = alice.encrypt(b"a2").unwrap();
+
+        assert_ne!(a1.header.dh, a2.header.dh);
+    }
+
+    #[test]
+    fn out_of_order_messages_decrypt() {
+        let (mut alice, mut bob) = pair();
+        let m1 = alice.encrypt(b"one").unwrap();
+        let m2 = alice.encrypt(b"two").unwrap();
+        let m3 = alice.encrypt(b"three").unwrap();
+
+        assert_eq!(bob.decrypt(&m3).unwrap(), b"three");
+        assert_eq!(bob.decrypt(&m1).unwrap(), b"one");
+        assert_eq!(bob.decrypt(&m2).unwrap(), b"two");
+    }
+
+    #[test]
+    fn replayed_message_fails() {
+        let (mut alice, mut bob) = pair();
+        let msg = alice.encrypt(b"once").unwrap();
+        bob.decrypt(&msg).unwrap();
+        assert!(bob.decrypt(&msg).is_err());
+    }
+
+    #[test]
+    fn tampered_message_leaves_state_intact() {
+        let (mut alice, mut bob) = pair();
+        let mut bad = alice.encrypt(b"secret").unwrap();
+        bad.ciphertext[0] ^= 0xff;
+        assert!(bob.decrypt(&bad).is_err());
+
+        let good = alice.encrypt(b"still works").unwrap();
+        assert_eq!(bob.decrypt(&good).unwrap(), b"still works");
+    }
+
+    #[test]
+    fn session_with_signed_prekey() {
+        init();
+        let (alice_pk, alice_sk) = box_::gen_keypair();
+        let (bob_pk, bob_sk) = box_::gen_keypair();
+        let (spk_pk, spk_sk) = box_::gen_keypair();
+
+        let mut alice =
+            RatchetSession::initiate((&alice_pk, &alice_sk), &bob_pk, &spk_pk, Some(7)).unwrap();
+        let msg = alice.encrypt(b"while you were away").unwrap();
+        let init = msg.init.as_ref().unwrap();
+        assert_eq!(init.prekey_id, Some(7));
+
+        let mut bob = RatchetSession::respond((&bob_pk, &bob_sk), (&spk_pk, &spk_sk), init).unwrap();
+        assert_eq!(bob.decrypt(&msg).unwrap(), b"while you were away");
+
+        // Responding with the identity key instead of the prekey must fail.
+        let mut wrong = RatchetSession::respond((&bob_pk, &bob_sk), (&bob_pk, &bob_sk), init).unwrap();
+        assert!(wrong.decrypt(&msg).is_err());
+    }
+
+    #[test]
+    fn wrong_identity_cannot_respond() {
+        init();
+        let (alice_pk, alice_sk) = box_::gen_keypair();
+        let (bob_pk, _bob_sk) = box_::gen_keypair();
+        let (eve_pk, eve_sk) = box_::gen_keypair();
+
+        let mut alice = RatchetSession::initiate((&alice_pk, &alice_sk), &bob_pk, &bob_pk, None).unwrap();
+        let msg = alice.encrypt(b"for bob").unwrap();
+        let mut eve = RatchetSession::respond(
+            (&eve_pk, &eve_sk),
+            (&eve_pk, &eve_sk),
+            msg.init.as_ref().unwrap(),
+        )
+        .unwrap();
+        assert!(eve.decrypt(&msg).is_err());
+    }
+
+    #[test]
+    fn session_survives_serialization() {
+        let (alice, mut bob) = pair();
+        let mut alice = RatchetSession::from_bytes(&alice.to_bytes().unwrap()).unwrap();
+
+        let msg = alice.encrypt(b"after restart").unwrap();
+        let wire = SessionMessage::from_bytes(&msg.to_bytes().unwrap()).unwrap();
+        assert_eq!(bob.decrypt(&wire).unwrap(), b"after restart");
+    }
+
+    #[test]
+    fn too_many_skipped_rejected() {
+        let (mut alice, mut bob) = pair();
+        let mut msg = alice.encrypt(b"far ahead").unwrap();
+        msg.header.n = MAX_SKIP + 10;
+        assert!(bob.decrypt(&msg).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! Keeping long-lived secret keys out of swap and core dumps.
+//!
+//! Short-lived secrets are wrapped in `zeroize::Zeroizing` where they're
+//! made. Keys held for a whole session live in a `LockedSecretKey`: one
+//! heap allocation that libsodium locks into RAM and marks as excluded
+//! from core dumps where the OS supports it, and wipes when dropped.
+
+use std::fmt;
+use std::ops::Deref;
+
+use sodiumoxide::crypto::box_::SecretKey;
+
+/// An X25519 secret key pinned in locked memory.
+pub struct LockedSecretKey(Box<SecretKey>);
+
+impl LockedSecretKey {
+    /// Move `key` into locked memory. Locking is best effort: without
+    /// permission to lock more memory the key is still wiped on drop.
+    pub fn new(key: SecretKey) -> Self {
+        let _ = sodiumoxide::init();
+        let mut boxed = Box::new(key);
+        let _ = sodiumoxide::utils::mlock(&mut boxed.0);
+        Self(boxed)
+    }
+}
+
+impl Deref for LockedSecretKey {
+    type Target = SecretKey;
+
+    fn deref(&self) -> &SecretKey {
+        &self.0
+    }
+}
+
+impl Clone for LockedSecretKey {
+    fn clone(&self) -> Self {
+        Self::new(SecretKey(self.0 .0))
+    }
+}
+
+impl Drop for LockedSecretKey {
+    fn drop(&mut self) {
+        // Zeroes the key as it unlocks it; SecretKey zeroes itself too
+        let _ = sodiumoxide::utils::munlock(&mut self.0 .0);
+    }
+}
+
+impl fmt::Debug for LockedSecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LockedSecretKey(****)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sodiumoxide::crypto::box_;
+
+    #[test]
+    fn locked_keys_work_like_the_key() {
+        let (pk, sk) = box_::gen_keypair();
+        let locked = LockedSecretKey::new(sk.clone());
+        assert_eq!(*locked, sk);
+        assert_eq!(*locked.clone(), sk);
+        assert_eq!(format!("{:?}", locked), "LockedSecretKey(****)");
+
+        let sealed = sodiumoxide::crypto::sealedbox::seal(b"hi", &pk);
+        assert_eq!(sodiumoxide::crypto::sealedbox::open(&sealed, &pk, &locked).unwrap(), b"hi");
+    }
+}
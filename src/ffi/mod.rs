@@ -0,0 +1,279 @@
+//! UniFFI bindings for Swift, Kotlin and other foreign languages.
+//!
+//! A thin layer over `WhisperClient` with the handful of calls an app
+//! needs: create an identity, open it, add contacts, send text and listen
+//! for events. Peer IDs and message IDs cross the boundary as strings and
+//! every error as a `WhisperError` carrying the full message. Generate the
+//! bindings with the `uniffi-bindgen` binary; see the README.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use libp2p::PeerId;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::RwLock;
+
+use crate::cli::init_identity as create_identity;
+use crate::client::WhisperClient;
+use crate::identity::{Contact, TrustLevel};
+use crate::message::{Envelope, MessageContent};
+use crate::network::NodeEvent;
+
+/// Anything that went wrong, with the reason.
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+pub enum WhisperError {
+    #[error("{message}")]
+    Failed { message: String },
+}
+
+impl From<anyhow::Error> for WhisperError {
+    fn from(e: anyhow::Error) -> Self {
+        WhisperError::Failed { message: format!("{:#}", e) }
+    }
+}
+
+/// A new identity.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct Identity {
+    pub peer_id: String,
+    /// Public key and signed prekey, to give to contacts.
+    pub contact_bundle: String,
+}
+
+/// How far a contact is trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum Trust {
+    Unknown,
+    Verified,
+    Trusted,
+    Blocked,
+}
+
+impl From<TrustLevel> for Trust {
+    fn from(level: TrustLevel) -> Self {
+        match level {
+            TrustLevel::Unknown => Trust::Unknown,
+            TrustLevel::Verified => Trust::Verified,
+            TrustLevel::Trusted => Trust::Trusted,
+            TrustLevel::Blocked => Trust::Blocked,
+        }
+    }
+}
+
+/// A contact, as apps see it.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ContactInfo {
+    pub alias: String,
+    pub peer_id: String,
+    pub trust: Trust,
+}
+
+impl From<Contact> for ContactInfo {
+    fn from(contact: Contact) -> Self {
+        Self {
+            alias: contact.alias,
+            peer_id: contact.peer_id.to_string(),
+            trust: contact.trust_level.into(),
+        }
+    }
+}
+
+/// Something that happened on the network.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Enum)]
+pub enum ClientEvent {
+    PeerConnected { peer_id: String },
+    PeerDisconnected { peer_id: String },
+    /// A text message arrived and was stored.
+    MessageReceived { from: String, message_id: String, text: String },
+    /// A peer acknowledged a message.
+    MessageSent { to: String },
+}
+
+impl ClientEvent {
+    /// The app-facing form of a node event, if apps care about it.
+    fn from_node(event: NodeEvent) -> Option<Self> {
+        match event {
+            NodeEvent::PeerConnected(peer) => Some(ClientEvent::PeerConnected { peer_id: peer.to_string() }),
+            NodeEvent::PeerDisconnected(peer) => Some(ClientEvent::PeerDisconnected { peer_id: peer.to_string() }),
+            NodeEvent::MessageReceived { from, data } => {
+                let envelope = Envelope::decode(&data).ok()?;
+                match envelope.payload {
+                    MessageContent::Text(text) => Some(ClientEvent::MessageReceived {
+                        from: from.to_string(),
+                        message_id: envelope.id.to_string(),
+                        text,
+                    }),
+                    _ => None,
+                }
+            }
+            NodeEvent::MessageSent { to } => Some(ClientEvent::MessageSent { to: to.to_string() }),
+            _ => None,
+        }
+    }
+}
+
+/// Receives events from `WhisperCore::subscribe`. Called from a background
+/// thread.
+#[uniffi::export(with_foreign)]
+pub trait EventListener: Send + Sync {
+    fn on_event(&self, event: ClientEvent);
+}
+
+/// Create an identity and encrypted database in `data_dir`.
+#[uniffi::export]
+pub fn init_identity(data_dir: String, passphrase: String) -> Result<Identity, WhisperError> {
+    let (peer_id, contact_bundle) = create_identity(&PathBuf::from(data_dir), &passphrase)?;
+    Ok(Identity { peer_id: peer_id.to_string(), contact_bundle })
+}
+
+/// A running Whisper node.
+#[derive(uniffi::Object)]
+pub struct WhisperCore {
+    /// `None` once shut down.
+    client: RwLock<Option<WhisperClient>>,
+    peer_id: PeerId,
+    runtime: tokio::runtime::Handle,
+}
+
+#[uniffi::export(async_runtime = "tokio")]
+impl WhisperCore {
+    /// Open the identity in `data_dir` and start the node.
+    #[uniffi::constructor]
+    pub async fn open(data_dir: String, passphrase: String) -> Result<Arc<Self>, WhisperError> {
+        let client = WhisperClient::open(&PathBuf::from(data_dir), &passphrase).await?;
+        Ok(Arc::new(Self {
+            peer_id: client.peer_id(),
+            client: RwLock::new(Some(client)),
+            runtime: tokio::runtime::Handle::current(),
+        }))
+    }
+
+    /// Our peer ID.
+    pub fn peer_id(&self) -> String {
+        self.peer_id.to_string()
+    }
+
+    /// Every contact.
+    pub async fn contacts(&self) -> Result<Vec<ContactInfo>, WhisperError> {
+        let client = self.client.read().await;
+        let contacts = running(&client)?.contacts().await?;
+        Ok(contacts.into_iter().map(ContactInfo::from).collect())
+    }
+
+    /// Add a contact by peer ID. Keys are exchanged when they connect.
+    pub async fn add_contact(&self, alias: String, peer_id: String) -> Result<(), WhisperError> {
+        let peer_id: PeerId = peer_id
+            .parse()
+            .map_err(|_| anyhow::anyhow!("'{}' is not a valid peer ID", peer_id))?;
+        let contact = Contact::new(peer_id, alias, Vec::new());
+        let client = self.client.read().await;
+        Ok(running(&client)?.add_contact(&contact).await?)
+    }
+
+    /// Send a text message to a contact. Returns the message ID; it goes out
+    /// now if they're connected and when they next connect otherwise.
+    pub async fn send_text(&self, alias: String, text: String) -> Result<String, WhisperError> {
+        let client = self.client.read().await;
+        let message = running(&client)?.send_text(&alias, &text).await?;
+        Ok(message.id.to_string())
+    }
+
+    /// Pass every event from now on to `listener`, until shutdown.
+    pub fn subscribe(&self, listener: Arc<dyn EventListener>) -> Result<(), WhisperError> {
+        let client = self.client.try_read().map_err(|_| anyhow::anyhow!("Whisper is shutting down"))?;
+        let mut events = running(&client)?.subscribe_events();
+        self.runtime.spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        if let Some(event) = ClientEvent::from_node(event) {
+                            listener.on_event(event);
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Stop the node. Later calls fail.
+    pub async fn shutdown(&self) -> Result<(), WhisperError> {
+        if let Some(client) = self.client.write().await.take() {
+            client.shutdown().await?;
+        }
+        Ok(())
+    }
+}
+
+fn running(client: &Option<WhisperClient>) -> anyhow::Result<&WhisperClient> {
+    client.as_ref().ok_or_else(|| anyhow::anyhow!("Whisper has been shut down"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::generate_keypair;
+    use crate::message::{Message, Recipient};
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    struct Collect(Mutex<Vec<ClientEvent>>);
+
+    impl EventListener for Collect {
+        fn on_event(&self, event: ClientEvent) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn open_add_send_and_shut_down() {
+        let temp = TempDir::new().unwrap();
+        let data_dir = temp.path().to_string_lossy().to_string();
+        let identity = init_identity(data_dir.clone(), "test".into()).unwrap();
+        assert!(init_identity(data_dir.clone(), "test".into()).is_err());
+        assert!(WhisperCore::open(data_dir.clone(), "wrong".into()).await.is_err());
+
+        let core = WhisperCore::open(data_dir, "test".into()).await.unwrap();
+        assert_eq!(core.peer_id(), identity.peer_id);
+        core.subscribe(Arc::new(Collect(Mutex::new(Vec::new())))).unwrap();
+
+        let bob = PeerId::random();
+        assert!(core.add_contact("bob".into(), "not a peer".into()).await.is_err());
+        core.add_contact("bob".into(), bob.to_string()).await.unwrap();
+        let contacts = core.contacts().await.unwrap();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!((contacts[0].alias.as_str(), contacts[0].trust), ("bob", Trust::Unknown));
+
+        // Bob isn't connected, so it's queued
+        let id = core.send_text("bob".into(), "hi".into()).await.unwrap();
+        assert!(!id.is_empty());
+        assert!(core.send_text("carol".into(), "hi".into()).await.is_err());
+
+        core.shutdown().await.unwrap();
+        assert!(core.contacts().await.is_err());
+    }
+
+    #[test]
+    fn node_events_become_client_events() {
+        let keypair = generate_keypair();
+        let from = PeerId::from(keypair.public());
+        let msg = Message::new_text(from, Recipient::Direct(PeerId::random()), "hello".into());
+        let data = Envelope::from_message(&msg).encode_signed(&keypair).unwrap();
+
+        assert_eq!(
+            ClientEvent::from_node(NodeEvent::MessageReceived { from, data }),
+            Some(ClientEvent::MessageReceived {
+                from: from.to_string(),
+                message_id: msg.id.to_string(),
+                text: "hello".into(),
+            })
+        );
+        assert_eq!(
+            ClientEvent::from_node(NodeEvent::PeerConnected(from)),
+            Some(ClientEvent::PeerConnected { peer_id: from.to_string() })
+        );
+        assert_eq!(ClientEvent::from_node(NodeEvent::Listening("/ip4/127.0.0.1/tcp/1".parse().unwrap())), None);
+    }
+}
@@ -6,6 +6,13 @@ use chrono::{DateTime, Utc};
 use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
 
+/// How often presence is announced to connected contacts, in seconds.
+pub const PRESENCE_INTERVAL_SECS: u64 = 5 * 60;
+
+/// How long an announcement holds without a refresh, in seconds. After
+/// that the contact counts as offline.
+pub const PRESENCE_TIMEOUT_SECS: u64 = 3 * PRESENCE_INTERVAL_SECS;
+
 /// Trust level for a contact.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TrustLevel {
@@ -15,6 +22,62 @@ pub enum TrustLevel {
     Blocked,
 }
 
+/// A status announced to contacts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
+impl PresenceStatus {
+    /// Stable name used for storage and the CLI.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PresenceStatus::Online => "online",
+            PresenceStatus::Away => "away",
+            PresenceStatus::Offline => "offline",
+        }
+    }
+
+    /// Parse a name produced by `as_str`.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "online" => Some(PresenceStatus::Online),
+            "away" => Some(PresenceStatus::Away),
+            "offline" => Some(PresenceStatus::Offline),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PresenceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The last status a contact announced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Presence {
+    pub status: PresenceStatus,
+    /// When it was announced.
+    pub at: DateTime<Utc>,
+}
+
+impl Presence {
+    /// Their status at `now`. Contacts re-announce while connected, so
+    /// one that has gone quiet for `PRESENCE_TIMEOUT_SECS` is offline.
+    pub fn current(&self, now: DateTime<Utc>) -> PresenceStatus {
+        if now.signed_duration_since(self.at).num_seconds() >= PRESENCE_TIMEOUT_SECS as i64 {
+            PresenceStatus::Offline
+        } else {
+            self.status
+        }
+    }
+}
+
 /// A contact in the address book.
 #[derive(Debug, Clone)]
 pub struct Contact {
@@ -23,6 +86,12 @@ pub struct Contact {
     pub public_key: Vec<u8>,
     pub trust_level: TrustLevel,
     pub last_seen: Option<DateTime<Utc>>,
+    /// Free-form note about the contact.
+    pub note: Option<String>,
+    /// Tags for grouping contacts, sorted.
+    pub tags: Vec<String>,
+    /// Their last presence announcement, if they've sent one.
+    pub presence: Option<Presence>,
 }
 
 /// Contact storage.
@@ -120,7 +189,43 @@ impl Contact {
             public_key,
             trust_level: TrustLevel::Unknown,
             last_seen: None,
+            note: None,
+            tags: Vec::new(),
+            presence: None,
+        }
+    }
+
+    /// Whether the contact is marked trusted or verified.
+    pub fn is_trusted(&self) -> bool {
+        matches!(self.trust_level, TrustLevel::Trusted | TrustLevel::Verified)
+    }
+
+    /// Whether we tell this contact our presence. Only contacts marked
+    /// trusted or verified learn when we're around.
+    pub fn shares_presence(&self) -> bool {
+        self.is_trusted()
+    }
+
+    /// Their status at `now`, if they've ever announced one.
+    pub fn current_presence(&self, now: DateTime<Utc>) -> Option<PresenceStatus> {
+        self.presence.map(|p| p.current(now))
+    }
+
+    /// Whether the contact has `tag`.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    /// Their identity key: the one stored, or for contacts added by peer
+    /// ID alone, the one embedded in it.
+    pub fn identity_key(&self) -> Option<Vec<u8>> {
+        if !self.public_key.is_empty() {
+            return Some(self.public_key.clone());
         }
+        libp2p::identity::PublicKey::try_decode_protobuf(self.peer_id.as_ref().digest())
+            .ok()
+            .and_then(|key| key.try_into_ed25519().ok())
+            .map(|key| key.to_bytes().to_vec())
     }
 }
 
@@ -137,6 +242,33 @@ mod tests {
         Contact::new(make_peer_id(), alias.to_string(), vec![1, 2, 3])
     }
 
+    #[test]
+    fn presence_goes_offline_when_not_refreshed() {
+        let at = Utc::now();
+        let presence = Presence { status: PresenceStatus::Away, at };
+        assert_eq!(presence.current(at), PresenceStatus::Away);
+        let later = at + chrono::Duration::seconds(PRESENCE_TIMEOUT_SECS as i64);
+        assert_eq!(presence.current(later), PresenceStatus::Offline);
+
+        let mut contact = make_contact("alice");
+        assert_eq!(contact.current_presence(at), None);
+        assert!(!contact.shares_presence());
+        contact.trust_level = TrustLevel::Verified;
+        assert!(contact.shares_presence());
+        for status in [PresenceStatus::Online, PresenceStatus::Away, PresenceStatus::Offline] {
+            assert_eq!(PresenceStatus::parse(status.as_str()), Some(status));
+        }
+    }
+
+    #[test]
+    fn identity_key_falls_back_to_the_peer_id() {
+        let keypair = Keypair::generate_ed25519();
+        let key = keypair.public().try_into_ed25519().unwrap().to_bytes().to_vec();
+        let contact = Contact::new(PeerId::from(keypair.public()), "alice".to_string(), Vec::new());
+        assert_eq!(contact.identity_key(), Some(key));
+        assert_eq!(make_contact("bob").identity_key(), Some(vec![1, 2, 3]));
+    }
+
     #[test]
     fn add_contact_works() {
         let mut store = ContactStore::new();
@@ -0,0 +1,174 @@
+//! One-time invite codes.
+//!
+//! An invite is a short code, signed by whoever made it, for adding them
+//! without swapping keys first. It carries their peer ID, from which their
+//! key follows, the rendezvous point where they can be found, and when it
+//! stops working. Whoever accepts it answers with their own keys, and the
+//! inviter adds them back: once per invite, and only before it expires.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+/// Prefix of an invite code.
+pub const INVITE_PREFIX: &str = "whisper-invite:";
+
+/// Length of an invite's ID, in bytes.
+pub const INVITE_ID_LEN: usize = 8;
+
+/// Signature context for invites.
+const INVITE_SIGNATURE_CONTEXT: &[u8] = b"whisper invite";
+
+/// Identifies an invite, so it can be used once.
+pub type InviteId = [u8; INVITE_ID_LEN];
+
+/// An invite, as decoded from its code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    pub id: InviteId,
+    pub peer_id: PeerId,
+    pub public_key: PublicKey,
+    /// Where the inviter registers, if they use a rendezvous point.
+    pub rendezvous: Option<Multiaddr>,
+    /// What the inviter would like to be called, e.g. their username.
+    pub name: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// An invite we handed out, as we keep it until it's used or expires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IssuedInvite {
+    pub id: InviteId,
+    /// The alias to add whoever accepts it under, if we chose one.
+    pub alias: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Who accepted it, once someone has.
+    pub used_by: Option<PeerId>,
+}
+
+/// What's signed.
+#[derive(Serialize, Deserialize)]
+struct WireInvite {
+    id: InviteId,
+    peer_id: Vec<u8>,
+    rendezvous: Option<Vec<u8>>,
+    name: Option<String>,
+    expires_at: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignedInvite {
+    invite: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl Invite {
+    /// Whether the invite has stopped working at `now`.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        now >= self.expires_at
+    }
+}
+
+/// Make an invite to ourselves, returning it and its code.
+pub fn create_invite(
+    keypair: &Keypair,
+    rendezvous: Option<Multiaddr>,
+    name: Option<String>,
+    expires_at: DateTime<Utc>,
+) -> Result<(Invite, String)> {
+    let peer_id = keypair.public().to_peer_id();
+    let id: InviteId = rand::random();
+    let wire = WireInvite {
+        id,
+        peer_id: peer_id.to_bytes(),
+        rendezvous: rendezvous.as_ref().map(|addr| addr.to_vec()),
+        name: name.clone(),
+        expires_at: expires_at.timestamp(),
+    };
+    let invite = bincode::serialize(&wire)?;
+    let signature = keypair
+        .sign(&[INVITE_SIGNATURE_CONTEXT, &invite].concat())
+        .context("Failed to sign invite")?;
+    let code = bincode::serialize(&SignedInvite { invite, signature })?;
+    let invite = Invite {
+        id,
+        peer_id,
+        public_key: keypair.public(),
+        rendezvous,
+        name,
+        expires_at: DateTime::from_timestamp(wire.expires_at, 0).unwrap_or(expires_at),
+    };
+    Ok((invite, format!("{}{}", INVITE_PREFIX, URL_SAFE_NO_PAD.encode(code))))
+}
+
+/// Read an invite code, checking it was signed by the peer it names.
+/// Whether it has expired is up to the caller.
+pub fn parse_invite(code: &str) -> Result<Invite> {
+    let encoded = code
+        .trim()
+        .strip_prefix(INVITE_PREFIX)
+        .ok_or_else(|| anyhow!("Not a whisper invite code"))?;
+    let bytes = URL_SAFE_NO_PAD.decode(encoded).context("Invite code is damaged")?;
+    let signed: SignedInvite = bincode::deserialize(&bytes).context("Invite code is damaged")?;
+    let wire: WireInvite = bincode::deserialize(&signed.invite).context("Invite code is damaged")?;
+
+    let peer_id = PeerId::from_bytes(&wire.peer_id).context("Invalid peer ID in invite")?;
+    // Ed25519 peer IDs embed their key
+    let public_key = PublicKey::try_decode_protobuf(peer_id.as_ref().digest())
+        .ok()
+        .filter(|key| key.to_peer_id() == peer_id)
+        .ok_or_else(|| anyhow!("Invite does not carry the inviter's key"))?;
+    if !public_key.verify(&[INVITE_SIGNATURE_CONTEXT, &signed.invite].concat(), &signed.signature) {
+        bail!("Invite signature does not match its peer ID");
+    }
+    let rendezvous = wire
+        .rendezvous
+        .map(Multiaddr::try_from)
+        .transpose()
+        .context("Invalid rendezvous point in invite")?;
+    let expires_at = DateTime::from_timestamp(wire.expires_at, 0).context("Invalid expiry in invite")?;
+    Ok(Invite { id: wire.id, peer_id, public_key, rendezvous, name: wire.name, expires_at })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn invite_roundtrip() {
+        let alice = Keypair::generate_ed25519();
+        let point: Multiaddr = "/ip4/203.0.113.7/tcp/4001/p2p/12D3KooWGzh6KXrrRhdPHh6HPqBzQbjyEsUXSHoCTzYv9ddhBVob"
+            .parse()
+            .unwrap();
+        let expires_at = Utc::now() + Duration::hours(1);
+        let (made, code) = create_invite(&alice, Some(point.clone()), Some("alice".to_string()), expires_at).unwrap();
+        assert!(code.starts_with(INVITE_PREFIX));
+
+        let invite = parse_invite(&format!("  {}\n", code)).unwrap();
+        assert_eq!(invite, made);
+        assert_eq!(invite.peer_id, alice.public().to_peer_id());
+        assert_eq!(invite.rendezvous, Some(point));
+        assert!(!invite.is_expired(Utc::now()));
+        assert!(invite.is_expired(expires_at + Duration::seconds(1)));
+    }
+
+    #[test]
+    fn tampered_invites_are_refused() {
+        let alice = Keypair::generate_ed25519();
+        let (_, code) = create_invite(&alice, None, None, Utc::now() + Duration::hours(1)).unwrap();
+
+        let mut bytes = URL_SAFE_NO_PAD.decode(code.strip_prefix(INVITE_PREFIX).unwrap()).unwrap();
+        // Somewhere in the expiry
+        let at = bytes.len() - 80;
+        bytes[at] ^= 1;
+        let tampered = format!("{}{}", INVITE_PREFIX, URL_SAFE_NO_PAD.encode(bytes));
+        assert!(parse_invite(&tampered).is_err());
+        assert!(parse_invite(code.strip_prefix(INVITE_PREFIX).unwrap()).is_err());
+        assert!(parse_invite("whisper-invite:!!").is_err());
+    }
+}
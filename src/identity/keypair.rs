@@ -1,14 +1,19 @@
 //! Ed25519 keypair generation and storage.
 
+#[cfg(feature = "native")]
 use std::fs;
+#[cfg(feature = "native")]
 use std::path::Path;
 
 use anyhow::{anyhow, Context, Result};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use libp2p::identity::Keypair;
 use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::box_;
 use sodiumoxide::crypto::pwhash;
 use sodiumoxide::crypto::secretbox;
+use zeroize::Zeroizing;
 
 /// Generate a new Ed25519 keypair.
 pub fn generate_keypair() -> Keypair {
@@ -17,28 +22,32 @@ pub fn generate_keypair() -> Keypair {
 
 /// Derive encryption key from passphrase using Argon2.
 fn derive_key(passphrase: &str, salt: &pwhash::Salt) -> Result<secretbox::Key> {
-    let mut key_bytes = [0u8; secretbox::KEYBYTES];
+    let mut key_bytes = Zeroizing::new([0u8; secretbox::KEYBYTES]);
     pwhash::derive_key(
-        &mut key_bytes,
+        &mut key_bytes[..],
         passphrase.as_bytes(),
         salt,
         pwhash::OPSLIMIT_INTERACTIVE,
         pwhash::MEMLIMIT_INTERACTIVE,
     )
     .map_err(|_| anyhow!("Failed to derive key from passphrase"))?;
-    Ok(secretbox::Key(key_bytes))
+    // secretbox::Key wipes its copy when dropped
+    Ok(secretbox::Key(*key_bytes))
 }
 
-/// Save keypair to file, encrypted with passphrase.
+/// Encrypt a keypair with a passphrase, for storing wherever suits:
+/// a file, or browser storage for web clients.
 ///
 /// Format: salt (32 bytes) || nonce (24 bytes) || ciphertext
-pub fn save_keypair(keypair: &Keypair, path: &Path, passphrase: &str) -> Result<()> {
+pub fn seal_keypair(keypair: &Keypair, passphrase: &str) -> Result<Vec<u8>> {
     sodiumoxide::init().map_err(|_| anyhow!("Failed to init sodiumoxide"))?;
 
     // Get the secret key bytes
-    let keypair_bytes = keypair
-        .to_protobuf_encoding()
-        .context("Failed to encode keypair")?;
+    let keypair_bytes = Zeroizing::new(
+        keypair
+            .to_protobuf_encoding()
+            .context("Failed to encode keypair")?,
+    );
 
     // Generate salt and derive key
     let salt = pwhash::gen_salt();
@@ -53,22 +62,13 @@ pub fn save_keypair(keypair: &Keypair, path: &Path, passphrase: &str) -> Result<
     output.extend_from_slice(&salt.0);
     output.extend_from_slice(&nonce.0);
     output.extend_from_slice(&ciphertext);
-
-    // Create parent directories if needed
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
-    }
-
-    fs::write(path, &output).context("Failed to write keypair file")?;
-    Ok(())
+    Ok(output)
 }
 
-/// Load keypair from file, decrypting with passphrase.
-pub fn load_keypair(path: &Path, passphrase: &str) -> Result<Keypair> {
+/// Decrypt a keypair sealed by `seal_keypair`.
+pub fn open_keypair(data: &[u8], passphrase: &str) -> Result<Keypair> {
     sodiumoxide::init().map_err(|_| anyhow!("Failed to init sodiumoxide"))?;
 
-    let data = fs::read(path).context("Failed to read keypair file")?;
-
     if data.len() < 32 + 24 + 1 {
         return Err(anyhow!("Invalid keypair file: too short"));
     }
@@ -81,13 +81,61 @@ pub fn load_keypair(path: &Path, passphrase: &str) -> Result<Keypair> {
 
     // Derive key and decrypt
     let key = derive_key(passphrase, &salt)?;
-    let plaintext = secretbox::open(ciphertext, &nonce, &key)
-        .map_err(|_| anyhow!("Failed to decrypt keypair: wrong passphrase?"))?;
+    let plaintext = Zeroizing::new(
+        secretbox::open(ciphertext, &nonce, &key)
+            .map_err(|_| anyhow!("Failed to decrypt keypair: wrong passphrase?"))?,
+    );
 
     // Parse keypair from protobuf
     Keypair::from_protobuf_encoding(&plaintext).context("Failed to decode keypair")
 }
 
+/// Save keypair to file, encrypted with passphrase.
+#[cfg(feature = "native")]
+pub fn save_keypair(keypair: &Keypair, path: &Path, passphrase: &str) -> Result<()> {
+    let output = seal_keypair(keypair, passphrase)?;
+
+    // Create parent directories if needed
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, &output).context("Failed to write keypair file")?;
+    Ok(())
+}
+
+/// Load keypair from file, decrypting with passphrase.
+#[cfg(feature = "native")]
+pub fn load_keypair(path: &Path, passphrase: &str) -> Result<Keypair> {
+    let data = fs::read(path).context("Failed to read keypair file")?;
+    open_keypair(&data, passphrase)
+}
+
+/// Write an identity's secret key as a 24-word BIP39 phrase, for backing
+/// it up on paper.
+pub fn keypair_to_mnemonic(keypair: &Keypair) -> Result<Zeroizing<String>> {
+    let keypair = keypair
+        .clone()
+        .try_into_ed25519()
+        .map_err(|_| anyhow!("Identity key is not Ed25519"))?;
+    let mnemonic = bip39::Mnemonic::from_entropy(keypair.secret().as_ref())
+        .map_err(|e| anyhow!("Failed to encode seed phrase: {}", e))?;
+    Ok(Zeroizing::new(mnemonic.to_string()))
+}
+
+/// Recover an identity from the phrase `keypair_to_mnemonic` wrote. Case
+/// and spacing don't matter; a mistyped word fails the checksum.
+pub fn keypair_from_mnemonic(phrase: &str) -> Result<Keypair> {
+    let phrase = Zeroizing::new(phrase.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase());
+    let mnemonic =
+        bip39::Mnemonic::parse(phrase.as_str()).map_err(|e| anyhow!("Invalid seed phrase: {}", e))?;
+    let mut secret = Zeroizing::new(mnemonic.to_entropy());
+    if secret.len() != 32 {
+        anyhow::bail!("Invalid seed phrase: expected 24 words, got {}", mnemonic.word_count());
+    }
+    Keypair::ed25519_from_bytes(&mut secret[..]).context("Invalid seed phrase")
+}
+
 /// Export public key as base64 string.
 pub fn export_public_key(keypair: &Keypair) -> String {
     let public = keypair.public();
@@ -103,6 +151,106 @@ pub fn import_public_key(encoded: &str) -> Result<libp2p::identity::PublicKey> {
     libp2p::identity::PublicKey::try_decode_protobuf(&bytes).context("Invalid public key format")
 }
 
+/// Domain separation for prekey signatures.
+const PREKEY_SIGNATURE_CONTEXT: &[u8] = b"whisper-prekey-v1";
+
+/// Our signed prekey, including its secret half.
+#[derive(Debug, Clone)]
+pub struct SignedPrekey {
+    pub id: u32,
+    pub public_key: [u8; 32],
+    pub secret_key: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+/// A contact's signed prekey, as published in their contact bundle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicPrekey {
+    pub id: u32,
+    pub public_key: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+impl SignedPrekey {
+    /// The part of the prekey we hand out to contacts.
+    pub fn public(&self) -> PublicPrekey {
+        PublicPrekey {
+            id: self.id,
+            public_key: self.public_key,
+            signature: self.signature.clone(),
+        }
+    }
+}
+
+impl PublicPrekey {
+    /// Check the prekey was signed by `identity`.
+    pub fn verify(&self, identity: &libp2p::identity::PublicKey) -> bool {
+        identity.verify(&prekey_signing_bytes(self.id, &self.public_key), &self.signature)
+    }
+}
+
+fn prekey_signing_bytes(id: u32, public_key: &[u8; 32]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(PREKEY_SIGNATURE_CONTEXT.len() + 4 + 32);
+    buf.extend_from_slice(PREKEY_SIGNATURE_CONTEXT);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(public_key);
+    buf
+}
+
+/// Generate an X25519 prekey signed by our identity key.
+pub fn generate_signed_prekey(keypair: &Keypair, id: u32) -> Result<SignedPrekey> {
+    let (public, secret) = box_::gen_keypair();
+    let signature = keypair
+        .sign(&prekey_signing_bytes(id, &public.0))
+        .context("Failed to sign prekey")?;
+    Ok(SignedPrekey {
+        id,
+        public_key: public.0,
+        secret_key: secret.0,
+        signature,
+    })
+}
+
+/// Contact bundle: identity key plus an optional signed prekey.
+#[derive(Serialize, Deserialize)]
+struct ContactBundle {
+    public_key: Vec<u8>,
+    prekey: Option<PublicPrekey>,
+}
+
+/// Export our identity key and signed prekey as a base64 contact bundle.
+pub fn export_contact_bundle(keypair: &Keypair, prekey: &PublicPrekey) -> Result<String> {
+    let bundle = ContactBundle {
+        public_key: keypair.public().encode_protobuf(),
+        prekey: Some(prekey.clone()),
+    };
+    let mut buf = Vec::new();
+    ciborium::into_writer(&bundle, &mut buf).context("Failed to encode contact bundle")?;
+    Ok(BASE64.encode(&buf))
+}
+
+/// Import a contact bundle, verifying its prekey signature.
+/// Also accepts a bare exported public key, which has no prekey.
+pub fn import_contact_bundle(
+    encoded: &str,
+) -> Result<(libp2p::identity::PublicKey, Option<PublicPrekey>)> {
+    let bytes = BASE64
+        .decode(encoded)
+        .context("Invalid base64 encoding")?;
+    let bundle: ContactBundle = match ciborium::from_reader(bytes.as_slice()) {
+        Ok(bundle) => bundle,
+        Err(_) => return Ok((import_public_key(encoded)?, None)),
+    };
+    let public_key = libp2p::identity::PublicKey::try_decode_protobuf(&bundle.public_key)
+        .context("Invalid public key format")?;
+    if let Some(prekey) = &bundle.prekey {
+        if !prekey.verify(&public_key) {
+            return Err(anyhow!("Prekey signature does not match identity"));
+        }
+    }
+    Ok((public_key, bundle.prekey))
+}
+
 /// Derive PeerId from keypair.
 pub fn keypair_to_peer_id(keypair: &Keypair) -> PeerId {
     PeerId::from(keypair.public())
@@ -111,6 +259,7 @@ pub fn keypair_to_peer_id(keypair: &Keypair) -> PeerId {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "native")]
     use tempfile::tempdir;
 
     #[test]
@@ -121,6 +270,25 @@ mod tests {
     }
 
     #[test]
+    fn mnemonic_restores_the_identity() {
+        let original = Keypair::ed25519_from_bytes((0..32).collect::<Vec<u8>>()).unwrap();
+        let phrase = keypair_to_mnemonic(&original).unwrap();
+        assert_eq!(phrase.split(' ').count(), 24);
+
+        // Spacing and case are forgiven
+        let retyped = format!("  {}\n", phrase.to_uppercase().replace(' ', "   "));
+        let restored = keypair_from_mnemonic(&retyped).unwrap();
+        assert_eq!(keypair_to_peer_id(&original), keypair_to_peer_id(&restored));
+
+        // A swapped word fails the checksum
+        let mut words: Vec<&str> = phrase.split(' ').collect();
+        words.swap(0, 1);
+        assert!(keypair_from_mnemonic(&words.join(" ")).is_err());
+        assert!(keypair_from_mnemonic("abandon abandon").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "native")]
     fn save_load_roundtrip() {
         let dir = tempdir().unwrap();
         let path = dir.path().join("key.bin");
@@ -138,6 +306,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "native")]
     fn wrong_passphrase_fails() {
         let dir = tempdir().unwrap();
         let path = dir.path().join("key.bin");
@@ -149,6 +318,16 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn sealed_keypair_opens_with_passphrase() {
+        let original = generate_keypair();
+        let sealed = seal_keypair(&original, "pass").unwrap();
+        let opened = open_keypair(&sealed, "pass").unwrap();
+        assert_eq!(keypair_to_peer_id(&original), keypair_to_peer_id(&opened));
+        assert!(open_keypair(&sealed, "wrong").is_err());
+        assert!(open_keypair(&sealed[..40], "pass").is_err());
+    }
+
     #[test]
     fn export_public_key_produces_base64() {
         let kp = generate_keypair();
@@ -182,6 +361,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "native")]
     fn creates_parent_directories() {
         let dir = tempdir().unwrap();
         let path = dir.path().join("nested").join("dirs").join("key.bin");
@@ -192,6 +372,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "native")]
     fn empty_passphrase_works() {
         let dir = tempdir().unwrap();
         let path = dir.path().join("key.bin");
@@ -207,6 +388,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "native")]
     fn invalid_file_rejected() {
         let dir = tempdir().unwrap();
         let path = dir.path().join("bad.bin");
@@ -215,4 +397,42 @@ mod tests {
         let result = load_keypair(&path, "pass");
         assert!(result.is_err());
     }
+
+    #[test]
+    fn signed_prekey_verifies() {
+        let kp = generate_keypair();
+        let prekey = generate_signed_prekey(&kp, 1).unwrap();
+        assert!(prekey.public().verify(&kp.public()));
+
+        let other = generate_keypair();
+        assert!(!prekey.public().verify(&other.public()));
+    }
+
+    #[test]
+    fn contact_bundle_roundtrip() {
+        let kp = generate_keypair();
+        let prekey = generate_signed_prekey(&kp, 3).unwrap();
+        let exported = export_contact_bundle(&kp, &prekey.public()).unwrap();
+
+        let (public, imported) = import_contact_bundle(&exported).unwrap();
+        assert_eq!(public, kp.public());
+        assert_eq!(imported, Some(prekey.public()));
+    }
+
+    #[test]
+    fn contact_bundle_rejects_forged_prekey() {
+        let kp = generate_keypair();
+        let mut prekey = generate_signed_prekey(&kp, 1).unwrap().public();
+        prekey.public_key[0] ^= 0xff;
+        let exported = export_contact_bundle(&kp, &prekey).unwrap();
+        assert!(import_contact_bundle(&exported).is_err());
+    }
+
+    #[test]
+    fn contact_bundle_accepts_bare_public_key() {
+        let kp = generate_keypair();
+        let (public, prekey) = import_contact_bundle(&export_public_key(&kp)).unwrap();
+        assert_eq!(public, kp.public());
+        assert!(prekey.is_none());
+    }
 }
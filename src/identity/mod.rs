@@ -1,10 +1,23 @@
 //! Identity management - keypairs and contacts.
 
 mod contacts;
+mod invite;
 mod keypair;
+mod policy;
+mod qr;
 
-pub use contacts::{Contact, ContactStore, TrustLevel};
+pub use contacts::{
+    Contact, ContactStore, Presence, PresenceStatus, TrustLevel, PRESENCE_INTERVAL_SECS,
+    PRESENCE_TIMEOUT_SECS,
+};
+pub use invite::{create_invite, parse_invite, Invite, InviteId, IssuedInvite, INVITE_ID_LEN, INVITE_PREFIX};
 pub use keypair::{
-    export_public_key, generate_keypair, import_public_key, keypair_to_peer_id, load_keypair,
-    save_keypair,
+    export_contact_bundle, export_public_key, generate_keypair, generate_signed_prekey,
+    import_contact_bundle, import_public_key, keypair_from_mnemonic, keypair_to_mnemonic, keypair_to_peer_id,
+    open_keypair, seal_keypair,
+    PublicPrekey, SignedPrekey,
 };
+#[cfg(feature = "native")]
+pub use keypair::{load_keypair, save_keypair};
+pub use policy::{TrustPolicy, TrustRequirement};
+pub use qr::{contact_uri, parse_contact_uri, render_qr, CONTACT_URI_SCHEME};
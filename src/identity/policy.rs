@@ -0,0 +1,87 @@
+//! What senders are allowed, by how much we trust them.
+//!
+//! Blocked peers are always refused. Past that, the user can ask for more
+//! trust before sending someone read receipts, letting their messages
+//! straight into a conversation, or keeping their group invites. Out of
+//! the box everyone not blocked gets all three.
+
+use super::contacts::TrustLevel;
+
+/// How trusted a sender must be.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrustRequirement {
+    /// Anyone who isn't blocked, strangers included.
+    #[default]
+    Anyone,
+    /// Contacts marked trusted or verified.
+    Trusted,
+    /// Contacts whose safety number we've verified.
+    Verified,
+}
+
+impl TrustRequirement {
+    /// Whether a sender at `level` is trusted enough. Strangers count as
+    /// `Unknown`.
+    pub fn admits(&self, level: TrustLevel) -> bool {
+        match self {
+            TrustRequirement::Anyone => level != TrustLevel::Blocked,
+            TrustRequirement::Trusted => matches!(level, TrustLevel::Trusted | TrustLevel::Verified),
+            TrustRequirement::Verified => level == TrustLevel::Verified,
+        }
+    }
+
+    /// Stable name used for storage and the CLI.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrustRequirement::Anyone => "anyone",
+            TrustRequirement::Trusted => "trusted",
+            TrustRequirement::Verified => "verified",
+        }
+    }
+
+    /// Parse a name produced by `as_str`.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "anyone" => Some(TrustRequirement::Anyone),
+            "trusted" => Some(TrustRequirement::Trusted),
+            "verified" => Some(TrustRequirement::Verified),
+            _ => None,
+        }
+    }
+}
+
+/// How trusted a sender must be for each thing we do for them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrustPolicy {
+    /// Who we tell when we've read their messages, on top of the
+    /// per-contact setting.
+    pub read_receipts: TrustRequirement,
+    /// Whose messages go straight into a conversation. Everyone else's
+    /// wait as message requests.
+    pub conversations: TrustRequirement,
+    /// Whose group invites we keep for the user to answer. The rest are
+    /// dropped.
+    pub group_invites: TrustRequirement,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requirements_admit_enough_trust() {
+        let levels = [TrustLevel::Unknown, TrustLevel::Trusted, TrustLevel::Verified, TrustLevel::Blocked];
+        let admitted = |requirement: TrustRequirement| {
+            levels.iter().filter(|level| requirement.admits(**level)).count()
+        };
+        assert_eq!(admitted(TrustRequirement::Anyone), 3);
+        assert_eq!(admitted(TrustRequirement::Trusted), 2);
+        assert_eq!(admitted(TrustRequirement::Verified), 1);
+        assert!(!TrustRequirement::Verified.admits(TrustLevel::Trusted));
+
+        for requirement in [TrustRequirement::Anyone, TrustRequirement::Trusted, TrustRequirement::Verified] {
+            assert_eq!(TrustRequirement::parse(requirement.as_str()), Some(requirement));
+        }
+        assert_eq!(TrustRequirement::parse("nobody"), None);
+    }
+}
@@ -0,0 +1,86 @@
+//! QR code contact exchange.
+
+use anyhow::{anyhow, Context, Result};
+use libp2p::identity::PublicKey;
+use libp2p::PeerId;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+
+use super::keypair::{import_contact_bundle, PublicPrekey};
+
+/// Prefix of a contact payload, as encoded in a QR code.
+pub const CONTACT_URI_SCHEME: &str = "whisper:";
+
+/// Contact payload for a QR code: our peer ID and contact bundle.
+pub fn contact_uri(peer_id: &PeerId, bundle: &str) -> String {
+    format!("{}{}:{}", CONTACT_URI_SCHEME, peer_id, bundle)
+}
+
+/// Parse a contact payload, checking the bundle's key matches the peer ID.
+pub fn parse_contact_uri(uri: &str) -> Result<(PublicKey, Option<PublicPrekey>)> {
+    let rest = uri
+        .trim()
+        .strip_prefix(CONTACT_URI_SCHEME)
+        .ok_or_else(|| anyhow!("Not a whisper contact code"))?;
+    let (peer_id, bundle) = rest
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Contact code is missing its key"))?;
+    let peer_id: PeerId = peer_id.parse().context("Invalid peer ID in contact code")?;
+
+    let (public_key, prekey) = import_contact_bundle(bundle)?;
+    if PeerId::from(public_key.clone()) != peer_id {
+        return Err(anyhow!("Contact code key does not match its peer ID"));
+    }
+    Ok((public_key, prekey))
+}
+
+/// Render text as a QR code using Unicode half blocks, for printing to a
+/// terminal.
+pub fn render_qr(text: &str) -> Result<String> {
+    let code = QrCode::new(text.as_bytes()).context("Text is too long for a QR code")?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{export_contact_bundle, generate_keypair, generate_signed_prekey};
+
+    fn bundle() -> (PeerId, String) {
+        let keypair = generate_keypair();
+        let prekey = generate_signed_prekey(&keypair, 1).unwrap();
+        let bundle = export_contact_bundle(&keypair, &prekey.public()).unwrap();
+        (PeerId::from(keypair.public()), bundle)
+    }
+
+    #[test]
+    fn contact_uri_roundtrips() {
+        let (peer_id, bundle) = bundle();
+        let uri = contact_uri(&peer_id, &bundle);
+        assert!(uri.starts_with(CONTACT_URI_SCHEME));
+
+        let (public_key, prekey) = parse_contact_uri(&uri).unwrap();
+        assert_eq!(PeerId::from(public_key), peer_id);
+        assert!(prekey.is_some());
+    }
+
+    #[test]
+    fn mismatched_peer_id_is_rejected() {
+        let (_, bundle) = bundle();
+        let uri = contact_uri(&PeerId::random(), &bundle);
+        assert!(parse_contact_uri(&uri).is_err());
+        assert!(parse_contact_uri(&bundle).is_err());
+    }
+
+    #[test]
+    fn render_qr_produces_blocks() {
+        let (peer_id, bundle) = bundle();
+        let qr = render_qr(&contact_uri(&peer_id, &bundle)).unwrap();
+        assert!(qr.lines().count() > 10);
+        assert!(qr.contains('█') || qr.contains('▀') || qr.contains('▄'));
+    }
+}
@@ -1,17 +1,36 @@
 //! Whisper - Decentralized P2P Messaging Library
 //!
 //! Core library for peer-to-peer encrypted messaging.
+//!
+//! The `native` feature, on by default, adds everything that needs an
+//! operating system: the CLI, the TUI, the database and the tokio-based
+//! node. Without it `crypto`, `identity`, `message` and the protocol parts
+//! of `network` build for `wasm32-unknown-unknown`, for web clients.
 
+#[cfg(feature = "native")]
 pub mod cli;
+#[cfg(feature = "native")]
+pub mod client;
 pub mod crypto;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod identity;
 pub mod message;
 pub mod network;
+#[cfg(feature = "native")]
 pub mod storage;
+#[cfg(feature = "native")]
 pub mod ui;
 
 // Re-export commonly used types
+#[cfg(feature = "native")]
+pub use client::WhisperClient;
 pub use identity::{Contact, ContactStore, TrustLevel};
 pub use message::{Message, MessageStatus, Recipient};
+#[cfg(feature = "native")]
 pub use network::WhisperNode;
-pub use storage::Database;
+#[cfg(feature = "native")]
+pub use storage::{Database, DatabaseHandle};
+
+#[cfg(feature = "ffi")]
+uniffi::setup_scaffolding!();
@@ -2,12 +2,20 @@
 //!
 //! No servers. No tracking. Just you and whoever you're talking to.
 
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use libp2p::Multiaddr;
+use zeroize::Zeroizing;
 
-use whisper::cli;
+use whisper::cli::{self, OutputFormat};
+use whisper::message::{ExportFormat, MAX_STAMP_DIFFICULTY};
+use whisper::storage::{AuditKind, RetentionPolicy};
+
+/// What `whisper policy` accepts for each part of the trust policy.
+const TRUST_REQUIREMENTS: [&str; 3] = ["anyone", "trusted", "verified"];
 
 /// Decentralized peer-to-peer messaging.
 #[derive(Parser)]
@@ -23,50 +31,151 @@ pub struct Cli {
     #[arg(long, default_value = "~/.whisper")]
     pub data_dir: PathBuf,
 
-    /// Passphrase for keypair encryption (or set WHISPER_PASSPHRASE)
-    #[arg(long, env = "WHISPER_PASSPHRASE", default_value = "")]
-    pub passphrase: String,
+    /// Identity profile to use (defaults to the one set by `profile switch`)
+    #[arg(long, env = "WHISPER_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Passphrase for keypair encryption (or set WHISPER_PASSPHRASE); asked for when not given
+    #[arg(long, env = "WHISPER_PASSPHRASE")]
+    pub passphrase: Option<String>,
+
+    /// Output format for listings and status: text or json
+    #[arg(long, global = true, default_value = "text")]
+    pub output: OutputFormat,
+
+    /// Bootstrap the DHT from this node too, and save it to config.toml (repeatable)
+    #[arg(long, global = true, value_name = "MULTIADDR")]
+    pub bootstrap: Vec<Multiaddr>,
+
+    /// Listen through this relay too, and save it to config.toml (repeatable)
+    #[arg(long, global = true, value_name = "MULTIADDR")]
+    pub relay: Vec<Multiaddr>,
+
+    /// Listen on this address, e.g. /ip4/0.0.0.0/tcp/7777, and save it to config.toml (repeatable)
+    #[arg(long, global = true, value_name = "MULTIADDR")]
+    pub listen: Vec<Multiaddr>,
 }
 
 #[derive(Subcommand, Debug, Clone)]
 pub enum Commands {
     /// Initialize a new identity
-    Init,
+    Init {
+        /// Restore the identity from a seed phrase made by `whisper key backup`
+        #[arg(long)]
+        from_seed: bool,
+        /// Tune the database key derivation to take about half a second here
+        #[arg(long)]
+        kdf_benchmark: bool,
+    },
 
     /// Export your public key
-    ExportKey,
+    ExportKey {
+        /// Show a QR code to scan instead of the base64 bundle
+        #[arg(long)]
+        qr: bool,
+    },
 
-    /// Import a contact from a key file
+    /// Import a contact from a key file or scanned QR code
     ImportContact {
-        /// Path to the key file
+        /// Path to the key file, or - to paste a bundle or scanned QR code text
         file: std::path::PathBuf,
         /// Alias for the contact
         alias: String,
     },
 
-    /// Send a message to a contact
+    /// Send a message or file to a contact
     Send {
         /// Contact alias
         alias: String,
-        /// Message text
-        message: String,
+        /// Message text, or - to read it from stdin
+        #[arg(required_unless_present_any = ["file", "location", "card"])]
+        message: Option<String>,
+        /// Send a file instead of a text message (- reads its bytes from stdin)
+        #[arg(long, conflicts_with_all = ["message", "location", "card"])]
+        file: Option<PathBuf>,
+        /// Send a position instead, as LAT,LON in decimal degrees
+        #[arg(long, value_name = "LAT,LON", allow_hyphen_values = true, conflicts_with_all = ["message", "card"])]
+        location: Option<String>,
+        /// Name for the position, e.g. a place or address
+        #[arg(long, requires = "location", conflicts_with_all = ["message", "file", "card"])]
+        label: Option<String>,
+        /// Introduce one of your contacts by sending their card
+        #[arg(long, value_name = "CONTACT", conflicts_with = "message")]
+        card: Option<String>,
     },
 
     /// Open interactive chat with a contact
     Chat {
         /// Contact alias
         alias: String,
+        /// Keep everything from this session in memory, for shared machines
+        #[arg(long)]
+        ephemeral: bool,
     },
 
+    /// Print incoming messages without the TUI, one per line
+    Listen {
+        /// Print each message as a JSON object
+        #[arg(long)]
+        json: bool,
+        /// Also act as a rendezvous point for other peers
+        #[arg(long)]
+        rendezvous_server: bool,
+        /// Serve Prometheus metrics on localhost (default 127.0.0.1:9464)
+        #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = cli::DEFAULT_METRICS_ADDR)]
+        metrics: Option<SocketAddr>,
+        /// Serve the control API on localhost (default 127.0.0.1:9465)
+        #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = cli::DEFAULT_API_ADDR)]
+        api: Option<SocketAddr>,
+    },
+
+    /// Answer messages with a command: each text message from an allowed
+    /// contact goes to its stdin, and what it prints is sent back
+    Bot {
+        /// Contact whose messages the bot answers (repeatable)
+        #[arg(long, required = true)]
+        allow: Vec<String>,
+        /// Command to run, then its arguments, e.g. -- python3 bot.py
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+
+    /// Relay a group to another network
+    #[command(subcommand)]
+    Bridge(BridgeCommands),
+
     /// List all contacts
-    Contacts,
+    Contacts {
+        /// Only list contacts with this tag
+        #[arg(long)]
+        tag: Option<String>,
+        /// Include archived contacts
+        #[arg(long)]
+        archived: bool,
+    },
+
+    /// Contact notes and tags
+    #[command(subcommand)]
+    Contact(ContactCommands),
+
+    /// Messages from peers who aren't contacts yet
+    #[command(subcommand)]
+    Requests(RequestCommands),
+
+    /// One-time invite codes for adding contacts
+    #[command(subcommand)]
+    Invite(InviteCommands),
 
     /// Add a new contact
     Add {
         /// Alias for the contact
         alias: String,
         /// Peer ID of the contact
-        peer_id: String,
+        #[arg(required_unless_present = "username", conflicts_with = "username")]
+        peer_id: Option<String>,
+        /// Look the contact up by the username they registered, e.g. @alice
+        #[arg(long)]
+        username: Option<String>,
     },
 
     /// Mark a contact as trusted
@@ -75,25 +184,556 @@ pub enum Commands {
         alias: String,
     },
 
+    /// Give a contact a new alias
+    Rename {
+        /// Current alias
+        old_alias: String,
+        /// New alias
+        new_alias: String,
+    },
+
+    /// Remove a contact
+    Remove {
+        /// Contact alias
+        alias: String,
+        /// Also delete the conversation and any messages still queued for them
+        #[arg(long)]
+        purge_messages: bool,
+    },
+
     /// Block a contact
     Block {
         /// Contact alias
         alias: String,
     },
 
+    /// Compare safety numbers with a contact and mark them verified
+    Verify {
+        /// Contact alias
+        alias: String,
+    },
+
+    /// Turn read receipts to a contact on or off
+    Receipts {
+        /// Contact alias
+        alias: String,
+        /// on or off
+        #[arg(value_parser = clap::builder::BoolishValueParser::new())]
+        enabled: bool,
+    },
+
+    /// Turn peer exchange of contact addresses on or off
+    Pex {
+        /// on or off
+        #[arg(value_parser = clap::builder::BoolishValueParser::new())]
+        enabled: bool,
+        /// Only for this contact: whether their addresses are passed on and hints traded with them
+        #[arg(long)]
+        contact: Option<String>,
+    },
+
+    /// Export a conversation with a contact
+    Export {
+        /// Contact alias
+        alias: String,
+        /// Output format: json, markdown or txt
+        #[arg(long, default_value = "json")]
+        format: ExportFormat,
+        /// Only include messages from this date (YYYY-MM-DD or RFC 3339)
+        #[arg(long)]
+        since: Option<String>,
+    },
+
+    /// Import a conversation written by `whisper export --format json`
+    ImportHistory {
+        /// Path to the JSON export
+        file: PathBuf,
+    },
+
+    /// Show the conversation with a contact
+    History {
+        /// Contact alias
+        alias: String,
+        /// Show at most this many of the latest messages
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+        /// Only include messages from this date (YYYY-MM-DD or RFC 3339)
+        #[arg(long)]
+        since: Option<String>,
+    },
+
+    /// Search message history
+    Search {
+        /// Words to look for
+        #[arg(required = true)]
+        text: Vec<String>,
+        /// Maximum number of results
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Delete a message, for everyone if you sent it
+    Delete {
+        /// Message ID, or its first few characters as shown by `whisper search`
+        id: String,
+        /// Only delete the copy on this device
+        #[arg(long)]
+        local: bool,
+    },
+
+    /// Show or set how long messages are kept
+    Retention {
+        /// Contact alias or group name; omit for the default for all conversations
+        conversation: Option<String>,
+        /// Delete messages older than this many days
+        #[arg(long)]
+        days: Option<u32>,
+        /// Keep only this many of the newest messages
+        #[arg(long)]
+        messages: Option<u32>,
+        /// Keep every message, overriding the default
+        #[arg(long, conflicts_with_all = ["days", "messages", "clear"])]
+        keep_all: bool,
+        /// Remove the setting, so a conversation goes back to the default
+        #[arg(long, conflicts_with_all = ["days", "messages"])]
+        clear: bool,
+    },
+
+    /// Pin a conversation to the top of the inbox and contact list
+    Pin {
+        /// Contact alias or group name
+        conversation: String,
+        /// Unpin it instead
+        #[arg(long)]
+        undo: bool,
+    },
+
+    /// Archive a conversation out of the inbox and contact list
+    Archive {
+        /// Contact alias or group name
+        conversation: String,
+        /// Bring it back instead
+        #[arg(long)]
+        undo: bool,
+    },
+
+    /// Show or set how a conversation notifies you
+    Notify {
+        /// Contact alias or group name
+        conversation: String,
+        /// Which messages notify: all, mentions or none
+        #[arg(long, value_parser = ["all", "mentions", "none"])]
+        level: Option<String>,
+        /// Mute until unmuted
+        #[arg(long, conflicts_with = "unmute")]
+        mute: bool,
+        /// Mute for this many hours
+        #[arg(long, conflicts_with_all = ["mute", "unmute"])]
+        mute_hours: Option<u32>,
+        /// Lift a mute
+        #[arg(long)]
+        unmute: bool,
+        /// How the chat alerts you: quiet or bell
+        #[arg(long, value_parser = ["quiet", "bell"])]
+        alert: Option<String>,
+    },
+
+    /// Delete messages that retention settings no longer keep
+    Prune {
+        /// Show what would be deleted without deleting anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+
     /// Show network status
     Status,
 
     /// List connected peers
     Peers,
 
+    /// Show bytes sent and received, per protocol and contact
+    Stats,
+
+    /// Review the audit log of security-relevant events
+    Audit {
+        /// Only show one kind: key-changed, decryption-failed, blocked-peer or trust-changed
+        #[arg(long)]
+        kind: Option<AuditKind>,
+        /// Only include events from this date (YYYY-MM-DD or RFC 3339)
+        #[arg(long)]
+        since: Option<String>,
+        /// Show at most this many of the latest events
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+
+    /// Connect to a peer at an explicit address and remember it
+    Connect {
+        /// Multiaddr to dial, e.g. /ip4/203.0.113.5/tcp/4001, or ip:port
+        address: String,
+    },
+
+    /// Show, register or clear the username others can add you by
+    Username {
+        /// Username to claim, e.g. @alice
+        username: Option<String>,
+        /// Stop claiming a username
+        #[arg(long, conflicts_with = "username")]
+        clear: bool,
+    },
+
+    /// Show or set the rendezvous point used to find contacts behind NAT
+    Rendezvous {
+        /// Multiaddr of the point, ending with /p2p/<peer id>
+        address: Option<String>,
+        /// Stop using a rendezvous point
+        #[arg(long, conflicts_with = "address")]
+        clear: bool,
+    },
+
+    /// Show or set a SOCKS5 proxy, such as Tor at 127.0.0.1:9050, to
+    /// connect through. Local discovery and public listening are off while
+    /// it's set.
+    Proxy {
+        /// Proxy address, ip:port
+        address: Option<String>,
+        /// Connect directly again
+        #[arg(long, conflicts_with = "address")]
+        clear: bool,
+    },
+
+    /// Show or set the port to accept WebSocket connections on, for
+    /// browsers and networks that only let HTTP through
+    Websocket {
+        /// Port to listen on, e.g. 8080
+        port: Option<u16>,
+        /// Stop listening for WebSockets
+        #[arg(long, conflicts_with = "port")]
+        clear: bool,
+    },
+
+    /// Show or set a webhook that `whisper listen` posts each incoming
+    /// message to as JSON
+    Webhook {
+        /// http:// or https:// URL
+        url: Option<String>,
+        /// Only post messages from this contact or group (repeatable)
+        #[arg(long, requires = "url")]
+        only: Vec<String>,
+        /// Stop posting messages
+        #[arg(long, conflicts_with = "url")]
+        clear: bool,
+    },
+
+    /// Leave messages for offline contacts with a mailbox peer, or hold
+    /// them for others as one
+    #[command(subcommand)]
+    Mailbox(MailboxCommands),
+
+    /// Look after messages waiting to be delivered
+    #[command(subcommand)]
+    Queue(QueueCommands),
+
+    /// Link other devices to this identity, or this device to another
+    #[command(subcommand)]
+    Device(DeviceCommands),
+
+    /// Show or set the presence trusted contacts see
+    Presence {
+        /// online, away or offline
+        status: Option<String>,
+    },
+
+    /// Show whether chats render markdown, or turn it on or off
+    Markdown {
+        /// on or off
+        #[arg(value_parser = clap::builder::BoolishValueParser::new())]
+        enabled: Option<bool>,
+    },
+
+    /// Show or set the proof of work a stranger's first message needs
+    Stamps {
+        /// Zero bits the stamp must have; 0 lets strangers in without one
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=MAX_STAMP_DIFFICULTY as i64))]
+        difficulty: Option<u8>,
+    },
+
+    /// Show or set how trusted senders must be for what we do for them
+    Policy {
+        /// Who gets read receipts: anyone, trusted or verified
+        #[arg(long, value_parser = TRUST_REQUIREMENTS)]
+        read_receipts: Option<String>,
+        /// Whose messages skip message requests: anyone, trusted or verified
+        #[arg(long, value_parser = TRUST_REQUIREMENTS)]
+        conversations: Option<String>,
+        /// Whose group invites are kept: anyone, trusted or verified
+        #[arg(long, value_parser = TRUST_REQUIREMENTS)]
+        group_invites: Option<String>,
+    },
+
     /// Group commands
     #[command(subcommand)]
     Group(GroupCommands),
 
+    /// Broadcast channels: only the owner posts, subscribers read
+    #[command(subcommand)]
+    Channel(ChannelCommands),
+
     /// File transfer commands
     #[command(subcommand)]
     File(FileCommands),
+
+    /// Identity profile commands
+    #[command(subcommand)]
+    Profile(ProfileCommands),
+
+    /// Identity key commands
+    #[command(subcommand)]
+    Key(KeyCommands),
+
+    /// Database commands
+    #[command(subcommand)]
+    Db(DbCommands),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum DbCommands {
+    /// Re-encrypt the database under a key derived with a new salt
+    Rekey {
+        /// Change the passphrase too, for the keypair as well as the database
+        #[arg(long)]
+        change_passphrase: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum KeyCommands {
+    /// Show the seed phrase to restore your identity from
+    Backup,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ProfileCommands {
+    /// List profiles
+    List,
+
+    /// Create a profile with its own identity and database
+    Create {
+        /// Profile name
+        name: String,
+    },
+
+    /// Make a profile the default for later commands
+    Switch {
+        /// Profile name
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ContactCommands {
+    /// Show, set or clear the note on a contact
+    Note {
+        /// Contact alias
+        alias: String,
+        /// Note text; omit to show the current note
+        text: Vec<String>,
+        /// Remove the note
+        #[arg(long, conflicts_with = "text")]
+        clear: bool,
+    },
+
+    /// Add tags to a contact, or remove them
+    Tag {
+        /// Contact alias
+        alias: String,
+        /// Tags, e.g. work family
+        #[arg(required = true)]
+        tags: Vec<String>,
+        /// Remove these tags instead
+        #[arg(long)]
+        remove: bool,
+    },
+
+    /// Save a fixed address to reach a contact at, e.g. their onion address
+    Address {
+        /// Contact alias
+        alias: String,
+        /// Multiaddr, ip:port or <name>.onion:port
+        address: String,
+    },
+
+    /// List contact cards others have sent you
+    Cards,
+
+    /// Add the contact a card introduces
+    Accept {
+        /// Alias the card was sent under
+        alias: String,
+        /// Save the contact under a different alias
+        #[arg(long = "as", value_name = "ALIAS")]
+        save_as: Option<String>,
+    },
+
+    /// Decline a contact card
+    Decline {
+        /// Alias the card was sent under
+        alias: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum BridgeCommands {
+    /// Relay messages between a group and a Matrix room, as a bot user
+    Matrix {
+        /// Group name
+        group: String,
+        /// Homeserver URL, e.g. https://matrix.org
+        #[arg(long)]
+        homeserver: String,
+        /// Room ID or alias, e.g. '#whisper:matrix.org'
+        #[arg(long)]
+        room: String,
+        /// Access token of the bot user
+        #[arg(long, env = "WHISPER_MATRIX_TOKEN", hide_env_values = true)]
+        token: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum MailboxCommands {
+    /// Show the mailboxes we use and whether we are one
+    List,
+    /// Leave messages for offline contacts with this contact
+    Add {
+        /// Contact alias
+        alias: String,
+    },
+    /// Stop leaving messages with this contact
+    Remove {
+        /// Contact alias
+        alias: String,
+    },
+    /// Hold messages from trusted contacts for peers who are offline, and
+    /// pass them on when they connect
+    Serve {
+        /// Stop holding new messages
+        #[arg(long)]
+        off: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum QueueCommands {
+    /// List queued messages, including those given up on
+    List,
+    /// Try a message given up on again, or all of them
+    Retry {
+        /// Message ID, or the start of one
+        id: Option<String>,
+    },
+    /// Stop trying to deliver a message
+    Drop {
+        /// Message ID, or the start of one
+        id: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ChannelCommands {
+    /// Create a channel and print the code to subscribe with
+    Create {
+        /// Channel name
+        name: String,
+    },
+    /// Subscribe to a channel
+    Subscribe {
+        /// Code from 'whisper channel create'
+        code: String,
+    },
+    /// Post to one of your channels
+    Post {
+        /// Channel name
+        name: String,
+        /// Text to post
+        message: String,
+    },
+    /// List the channels you own or subscribe to
+    List,
+    /// Show the latest posts to a channel
+    Read {
+        /// Channel name
+        name: String,
+        /// Show at most this many posts
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum DeviceCommands {
+    /// Offer to link this device to an identity, printing a link code
+    Join {
+        /// Peer ID of the identity's primary device
+        identity: String,
+    },
+    /// Link the device that printed this code to our identity
+    Link {
+        /// Code from 'whisper device join'
+        code: String,
+        /// Name to show for the device
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Show the devices linked to our identity
+    List,
+    /// Unlink a device
+    Unlink {
+        /// Device name
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum RequestCommands {
+    /// List who is waiting and what they sent
+    List,
+
+    /// Add the sender as a contact and move their messages into a chat
+    Accept {
+        /// Sender's peer ID, or a unique prefix of it
+        peer_id: String,
+        /// Alias for the new contact
+        alias: String,
+    },
+
+    /// Delete a sender's messages
+    Decline {
+        /// Sender's peer ID, or a unique prefix of it
+        peer_id: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum InviteCommands {
+    /// Make an invite code that adds whoever accepts it
+    Create {
+        /// Alias to add whoever accepts it under
+        #[arg(long)]
+        alias: Option<String>,
+        /// Hours until the invite stops working
+        #[arg(long, default_value_t = 24, value_parser = clap::value_parser!(u64).range(1..=720))]
+        hours: u64,
+    },
+
+    /// Add the inviter as a contact and send them your keys
+    Accept {
+        /// Invite code, starting with whisper-invite:
+        code: String,
+        /// Alias for the inviter, if the invite doesn't suggest one
+        #[arg(long)]
+        alias: Option<String>,
+    },
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -127,7 +767,8 @@ pub enum GroupCommands {
         name: String,
     },
 
-    /// Kick a member from the group (owner/admin only)
+    /// Remove a member from the group and rotate its key (owner/admin only)
+    #[command(visible_alias = "remove")]
     Kick {
         /// Group name
         name: String,
@@ -159,6 +800,27 @@ pub enum GroupCommands {
         alias: String,
     },
 
+    /// List pending group invites
+    Invites,
+
+    /// Accept a pending group invite
+    Accept {
+        /// Group name
+        name: String,
+    },
+
+    /// Decline a pending group invite
+    Decline {
+        /// Group name
+        name: String,
+    },
+
+    /// Leave a group and tell the remaining members
+    Leave {
+        /// Group name
+        name: String,
+    },
+
     /// Update group settings (owner/admin only)
     Settings {
         /// Group name
@@ -204,6 +866,15 @@ pub enum FileCommands {
     },
 }
 
+/// Whether a command creates an identity, opens one or needs no passphrase.
+fn passphrase_use(command: &Commands) -> cli::PassphraseUse {
+    match command {
+        Commands::Init { .. } | Commands::Profile(ProfileCommands::Create { .. }) => cli::PassphraseUse::New,
+        Commands::Profile(_) => cli::PassphraseUse::Unused,
+        _ => cli::PassphraseUse::Existing,
+    }
+}
+
 /// Expand ~ to home directory.
 pub fn expand_data_dir(path: PathBuf) -> PathBuf {
     if path.starts_with("~") {
@@ -221,42 +892,283 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
     let cli = Cli::parse();
-    let data_dir = expand_data_dir(cli.data_dir);
-    let passphrase = cli.passphrase;
+    let base_dir = expand_data_dir(cli.data_dir);
+    let data_dir = cli::resolve_data_dir(&base_dir, cli.profile.as_deref())?;
+    // Wiped when main returns
+    let passphrase = match cli.passphrase {
+        Some(passphrase) => Zeroizing::new(passphrase),
+        None => cli::ask_passphrase(&data_dir, passphrase_use(&cli.command))?,
+    };
+    cli::finish_interrupted_rekey(&data_dir, &passphrase);
+    let output = cli.output;
+    let added = cli::NetworkConfig { bootstrap: cli.bootstrap, relays: cli.relay, listen: cli.listen };
+    if added != cli::NetworkConfig::default() {
+        cli::add_to_config(&data_dir, &added)?;
+    }
 
     match cli.command {
-        Commands::Init => {
-            cli::handle_init(&data_dir, &passphrase).await?;
+        Commands::Init { from_seed, kdf_benchmark } => {
+            if kdf_benchmark {
+                cli::handle_kdf_benchmark(&data_dir).await?;
+            }
+            if from_seed {
+                cli::handle_init_from_seed(&data_dir, &passphrase).await?;
+            } else {
+                cli::handle_init(&data_dir, &passphrase).await?;
+            }
         }
-        Commands::ExportKey => {
-            cli::handle_export_key(&data_dir, &passphrase).await?;
+        Commands::ExportKey { qr } => {
+            cli::handle_export_key(qr, &data_dir, &passphrase).await?;
         }
         Commands::ImportContact { file, alias } => {
             cli::handle_import_contact(&file, &alias, &data_dir, &passphrase).await?;
         }
-        Commands::Send { alias, message } => {
-            cli::handle_send(&alias, &message, &data_dir, &passphrase).await?;
+        Commands::Send { alias, message, file, location, label, card } => {
+            if let Some(file) = file {
+                cli::handle_file_send(&alias, &file, &data_dir, &passphrase).await?;
+            } else if let Some(location) = location {
+                cli::handle_send_location(&alias, &location, label.as_deref(), &data_dir, &passphrase).await?;
+            } else if let Some(card) = card {
+                cli::handle_send_card(&alias, &card, &data_dir, &passphrase).await?;
+            } else if let Some(message) = message {
+                cli::handle_send(&alias, &message, &data_dir, &passphrase).await?;
+            }
+        }
+        Commands::Chat { alias, ephemeral } => {
+            cli::handle_chat(&alias, ephemeral, &data_dir, &passphrase).await?;
+        }
+        Commands::Listen { json, rendezvous_server, metrics, api } => {
+            let json = json || output == OutputFormat::Json;
+            cli::handle_listen(json, rendezvous_server, metrics, api, &data_dir, &passphrase).await?;
         }
-        Commands::Chat { alias } => {
-            cli::handle_chat(&alias, &data_dir, &passphrase).await?;
+        Commands::Bot { allow, command } => {
+            cli::handle_bot(command, &allow, &data_dir, &passphrase).await?;
         }
-        Commands::Contacts => {
-            cli::handle_contacts(&data_dir, &passphrase).await?;
+        Commands::Bridge(BridgeCommands::Matrix { group, homeserver, room, token }) => {
+            cli::handle_bridge_matrix(&group, &homeserver, &room, token, &data_dir, &passphrase).await?;
         }
-        Commands::Add { alias, peer_id } => {
-            cli::handle_add_contact(&alias, &peer_id, &data_dir, &passphrase).await?;
+        Commands::Contacts { tag, archived } => {
+            cli::handle_contacts(tag.as_deref(), archived, output, &data_dir, &passphrase).await?;
         }
+        Commands::Contact(cmd) => match cmd {
+            ContactCommands::Note { alias, text, clear } => {
+                let text = (!text.is_empty()).then(|| text.join(" "));
+                cli::handle_contact_note(&alias, text.as_deref(), clear, &data_dir, &passphrase).await?;
+            }
+            ContactCommands::Tag { alias, tags, remove } => {
+                cli::handle_contact_tag(&alias, &tags, remove, &data_dir, &passphrase).await?;
+            }
+            ContactCommands::Address { alias, address } => {
+                cli::handle_contact_address(&alias, &address, &data_dir, &passphrase).await?;
+            }
+            ContactCommands::Cards => {
+                cli::handle_contact_cards(&data_dir, &passphrase).await?;
+            }
+            ContactCommands::Accept { alias, save_as } => {
+                cli::handle_contact_accept(&alias, save_as.as_deref(), &data_dir, &passphrase).await?;
+            }
+            ContactCommands::Decline { alias } => {
+                cli::handle_contact_decline(&alias, &data_dir, &passphrase).await?;
+            }
+        },
+        Commands::Requests(cmd) => match cmd {
+            RequestCommands::List => {
+                cli::handle_requests_list(output, &data_dir, &passphrase).await?;
+            }
+            RequestCommands::Accept { peer_id, alias } => {
+                cli::handle_requests_accept(&peer_id, &alias, &data_dir, &passphrase).await?;
+            }
+            RequestCommands::Decline { peer_id } => {
+                cli::handle_requests_decline(&peer_id, &data_dir, &passphrase).await?;
+            }
+        },
+        Commands::Invite(cmd) => match cmd {
+            InviteCommands::Create { alias, hours } => {
+                cli::handle_invite_create(alias.as_deref(), hours, &data_dir, &passphrase).await?;
+            }
+            InviteCommands::Accept { code, alias } => {
+                cli::handle_invite_accept(&code, alias.as_deref(), &data_dir, &passphrase).await?;
+            }
+        },
+        Commands::Add { alias, peer_id, username } => match (peer_id, username) {
+            (_, Some(username)) => {
+                cli::handle_add_contact_by_username(&alias, &username, &data_dir, &passphrase).await?;
+            }
+            (Some(peer_id), None) => {
+                cli::handle_add_contact(&alias, &peer_id, &data_dir, &passphrase).await?;
+            }
+            // clap requires one or the other
+            (None, None) => unreachable!(),
+        },
         Commands::Trust { alias } => {
             cli::handle_trust(&alias, &data_dir, &passphrase).await?;
         }
+        Commands::Rename { old_alias, new_alias } => {
+            cli::handle_rename(&old_alias, &new_alias, &data_dir, &passphrase).await?;
+        }
+        Commands::Remove { alias, purge_messages } => {
+            cli::handle_remove(&alias, purge_messages, &data_dir, &passphrase).await?;
+        }
         Commands::Block { alias } => {
             cli::handle_block(&alias, &data_dir, &passphrase).await?;
         }
+        Commands::Export { alias, format, since } => {
+            cli::handle_export(&alias, format, since.as_deref(), &data_dir, &passphrase).await?;
+        }
+        Commands::ImportHistory { file } => {
+            cli::handle_import_history(&file, &data_dir, &passphrase).await?;
+        }
+        Commands::History { alias, limit, since } => {
+            cli::handle_history(&alias, limit, since.as_deref(), output, &data_dir, &passphrase).await?;
+        }
+        Commands::Search { text, limit } => {
+            cli::handle_search(&text.join(" "), limit, &data_dir, &passphrase).await?;
+        }
+        Commands::Delete { id, local } => {
+            cli::handle_delete(&id, local, &data_dir, &passphrase).await?;
+        }
+        Commands::Retention { conversation, days, messages, keep_all, clear } => {
+            let policy = (keep_all || days.is_some() || messages.is_some()).then_some(RetentionPolicy {
+                max_age_days: days,
+                max_messages: messages,
+            });
+            cli::handle_retention(conversation.as_deref(), policy, clear, &data_dir, &passphrase).await?;
+        }
+        Commands::Pin { conversation, undo } => {
+            cli::handle_pin(&conversation, !undo, &data_dir, &passphrase).await?;
+        }
+        Commands::Archive { conversation, undo } => {
+            cli::handle_archive(&conversation, !undo, &data_dir, &passphrase).await?;
+        }
+        Commands::Notify { conversation, level, mute, mute_hours, unmute, alert } => {
+            cli::handle_notify(
+                &conversation,
+                level.as_deref(),
+                mute,
+                mute_hours,
+                unmute,
+                alert.as_deref(),
+                &data_dir,
+                &passphrase,
+            )
+            .await?;
+        }
+        Commands::Prune { dry_run } => {
+            cli::handle_prune(dry_run, &data_dir, &passphrase).await?;
+        }
+        Commands::Verify { alias } => {
+            cli::handle_verify(&alias, &data_dir, &passphrase).await?;
+        }
+        Commands::Receipts { alias, enabled } => {
+            cli::handle_receipts(&alias, enabled, &data_dir, &passphrase).await?;
+        }
+        Commands::Pex { enabled, contact } => {
+            cli::handle_pex(enabled, contact.as_deref(), &data_dir, &passphrase).await?;
+        }
         Commands::Status => {
-            cli::handle_status(&data_dir, &passphrase).await?;
+            cli::handle_status(output, &data_dir, &passphrase).await?;
         }
         Commands::Peers => {
-            cli::handle_peers(&data_dir, &passphrase).await?;
+            cli::handle_peers(output, &data_dir, &passphrase).await?;
+        }
+        Commands::Stats => {
+            cli::handle_stats(output, &data_dir, &passphrase).await?;
+        }
+        Commands::Audit { kind, since, limit } => {
+            cli::handle_audit(kind, since.as_deref(), limit, output, &data_dir, &passphrase).await?;
+        }
+        Commands::Connect { address } => {
+            cli::handle_connect(&address, output, &data_dir, &passphrase).await?;
+        }
+        Commands::Username { username, clear } => {
+            cli::handle_username(username.as_deref(), clear, &data_dir, &passphrase).await?;
+        }
+        Commands::Rendezvous { address, clear } => {
+            cli::handle_rendezvous(address.as_deref(), clear, &data_dir, &passphrase).await?;
+        }
+        Commands::Proxy { address, clear } => {
+            cli::handle_proxy(address.as_deref(), clear, &data_dir, &passphrase).await?;
+        }
+        Commands::Websocket { port, clear } => {
+            cli::handle_websocket(port, clear, &data_dir, &passphrase).await?;
+        }
+        Commands::Webhook { url, only, clear } => {
+            cli::handle_webhook(url.as_deref(), &only, clear, &data_dir, &passphrase).await?;
+        }
+        Commands::Mailbox(cmd) => match cmd {
+            MailboxCommands::List => {
+                cli::handle_mailbox_list(&data_dir, &passphrase).await?;
+            }
+            MailboxCommands::Add { alias } => {
+                cli::handle_mailbox_add(&alias, &data_dir, &passphrase).await?;
+            }
+            MailboxCommands::Remove { alias } => {
+                cli::handle_mailbox_remove(&alias, &data_dir, &passphrase).await?;
+            }
+            MailboxCommands::Serve { off } => {
+                cli::handle_mailbox_serve(!off, &data_dir, &passphrase).await?;
+            }
+        },
+        Commands::Queue(cmd) => match cmd {
+            QueueCommands::List => {
+                cli::handle_queue_list(&data_dir, &passphrase).await?;
+            }
+            QueueCommands::Retry { id } => {
+                cli::handle_queue_retry(id.as_deref(), &data_dir, &passphrase).await?;
+            }
+            QueueCommands::Drop { id } => {
+                cli::handle_queue_drop(&id, &data_dir, &passphrase).await?;
+            }
+        },
+        Commands::Channel(cmd) => match cmd {
+            ChannelCommands::Create { name } => {
+                cli::handle_channel_create(&name, &data_dir, &passphrase).await?;
+            }
+            ChannelCommands::Subscribe { code } => {
+                cli::handle_channel_subscribe(&code, &data_dir, &passphrase).await?;
+            }
+            ChannelCommands::Post { name, message } => {
+                cli::handle_channel_post(&name, &message, &data_dir, &passphrase).await?;
+            }
+            ChannelCommands::List => {
+                cli::handle_channel_list(&data_dir, &passphrase).await?;
+            }
+            ChannelCommands::Read { name, limit } => {
+                cli::handle_channel_read(&name, limit, &data_dir, &passphrase).await?;
+            }
+        },
+        Commands::Device(cmd) => match cmd {
+            DeviceCommands::Join { identity } => {
+                cli::handle_device_join(&identity, &data_dir, &passphrase).await?;
+            }
+            DeviceCommands::Link { code, name } => {
+                cli::handle_device_link(&code, name.as_deref(), &data_dir, &passphrase).await?;
+            }
+            DeviceCommands::List => {
+                cli::handle_device_list(&data_dir, &passphrase).await?;
+            }
+            DeviceCommands::Unlink { name } => {
+                cli::handle_device_unlink(&name, &data_dir, &passphrase).await?;
+            }
+        },
+        Commands::Presence { status } => {
+            cli::handle_presence(status.as_deref(), &data_dir, &passphrase).await?;
+        }
+        Commands::Markdown { enabled } => {
+            cli::handle_markdown(enabled, &data_dir, &passphrase).await?;
+        }
+        Commands::Stamps { difficulty } => {
+            cli::handle_stamps(difficulty, &data_dir, &passphrase).await?;
+        }
+        Commands::Policy { read_receipts, conversations, group_invites } => {
+            cli::handle_policy(
+                read_receipts.as_deref(),
+                conversations.as_deref(),
+                group_invites.as_deref(),
+                &data_dir,
+                &passphrase,
+            )
+            .await?;
         }
         Commands::Group(cmd) => {
             match cmd {
@@ -270,7 +1182,7 @@ async fn main() -> Result<()> {
                     cli::handle_group_chat(&name, &data_dir, &passphrase).await?;
                 }
                 GroupCommands::List => {
-                    cli::handle_group_list(&data_dir, &passphrase).await?;
+                    cli::handle_group_list(output, &data_dir, &passphrase).await?;
                 }
                 GroupCommands::Info { name } => {
                     cli::handle_group_info(&name, &data_dir, &passphrase).await?;
@@ -287,6 +1199,18 @@ async fn main() -> Result<()> {
                 GroupCommands::Transfer { name, alias } => {
                     cli::handle_group_transfer(&name, &alias, &data_dir, &passphrase).await?;
                 }
+                GroupCommands::Invites => {
+                    cli::handle_group_invites(&data_dir, &passphrase).await?;
+                }
+                GroupCommands::Accept { name } => {
+                    cli::handle_group_accept(&name, &data_dir, &passphrase).await?;
+                }
+                GroupCommands::Decline { name } => {
+                    cli::handle_group_decline(&name, &data_dir, &passphrase).await?;
+                }
+                GroupCommands::Leave { name } => {
+                    cli::handle_group_leave(&name, &data_dir, &passphrase).await?;
+                }
                 GroupCommands::Settings { name, rename, description } => {
                     cli::handle_group_settings(&name, rename.as_deref(), description.as_deref(), &data_dir, &passphrase).await?;
                 }
@@ -311,6 +1235,26 @@ async fn main() -> Result<()> {
                 }
             }
         }
+        Commands::Profile(cmd) => {
+            match cmd {
+                ProfileCommands::List => {
+                    cli::handle_profile_list(&base_dir).await?;
+                }
+                ProfileCommands::Create { name } => {
+                    cli::handle_profile_create(&name, &base_dir, &passphrase).await?;
+                }
+                ProfileCommands::Switch { name } => {
+                    cli::handle_profile_switch(&name, &base_dir).await?;
+                }
+            }
+        }
+        Commands::Key(KeyCommands::Backup) => {
+            cli::handle_key_backup(&data_dir, &passphrase).await?;
+        }
+        Commands::Db(DbCommands::Rekey { change_passphrase }) => {
+            let new_passphrase = if change_passphrase { Some(cli::ask_new_passphrase()?) } else { None };
+            cli::handle_db_rekey(new_passphrase.as_deref().map(String::as_str), &data_dir, &passphrase).await?;
+        }
     }
 
     Ok(())
@@ -324,21 +1268,415 @@ mod tests {
     #[test]
     fn cli_parses_init() {
         let cli = Cli::parse_from(["whisper", "init"]);
-        assert!(matches!(cli.command, Commands::Init));
+        assert!(matches!(cli.command, Commands::Init { from_seed: false, kdf_benchmark: false }));
+        let cli = Cli::parse_from(["whisper", "init", "--from-seed", "--kdf-benchmark"]);
+        assert!(matches!(cli.command, Commands::Init { from_seed: true, kdf_benchmark: true }));
+    }
+
+    #[test]
+    fn passphrase_is_asked_for_by_use() {
+        let usage = |args: &[&str]| passphrase_use(&Cli::parse_from(args).command);
+        assert_eq!(usage(&["whisper", "init", "--from-seed"]), cli::PassphraseUse::New);
+        assert_eq!(usage(&["whisper", "profile", "create", "work"]), cli::PassphraseUse::New);
+        assert_eq!(usage(&["whisper", "profile", "list"]), cli::PassphraseUse::Unused);
+        assert_eq!(usage(&["whisper", "chat", "alice"]), cli::PassphraseUse::Existing);
+    }
+
+    #[test]
+    fn cli_parses_db_rekey() {
+        let cli = Cli::parse_from(["whisper", "db", "rekey", "--change-passphrase"]);
+        assert!(matches!(cli.command, Commands::Db(DbCommands::Rekey { change_passphrase: true })));
+    }
+
+    #[test]
+    fn cli_parses_key_backup() {
+        let cli = Cli::parse_from(["whisper", "key", "backup"]);
+        assert!(matches!(cli.command, Commands::Key(KeyCommands::Backup)));
+    }
+
+    #[test]
+    fn cli_parses_chat_ephemeral() {
+        let cli = Cli::parse_from(["whisper", "chat", "alice", "--ephemeral"]);
+        assert!(matches!(cli.command, Commands::Chat { ref alias, ephemeral: true } if alias == "alice"));
     }
 
     #[test]
     fn cli_parses_send() {
         let cli = Cli::parse_from(["whisper", "send", "alice", "hello"]);
         match cli.command {
-            Commands::Send { alias, message } => {
+            Commands::Send { alias, message, file, .. } => {
+                assert_eq!(alias, "alice");
+                assert_eq!(message.as_deref(), Some("hello"));
+                assert!(file.is_none());
+            }
+            _ => panic!("Expected Send command"),
+        }
+    }
+
+    #[test]
+    fn cli_parses_send_file() {
+        let cli = Cli::parse_from(["whisper", "send", "--file", "notes.txt", "alice"]);
+        match cli.command {
+            Commands::Send { alias, message, file, .. } => {
                 assert_eq!(alias, "alice");
-                assert_eq!(message, "hello");
+                assert!(message.is_none());
+                assert_eq!(file, Some(PathBuf::from("notes.txt")));
             }
             _ => panic!("Expected Send command"),
         }
     }
 
+    #[test]
+    fn cli_send_requires_message_or_file() {
+        assert!(Cli::try_parse_from(["whisper", "send", "alice"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_send_location_and_card() {
+        let cli = Cli::parse_from(["whisper", "send", "alice", "--location", "-33.87,151.21", "--label", "Sydney"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Send { message: None, location: Some(ref l), label: Some(ref n), .. }
+                if l == "-33.87,151.21" && n == "Sydney"
+        ));
+        let cli = Cli::parse_from(["whisper", "send", "alice", "--card", "carol"]);
+        assert!(matches!(cli.command, Commands::Send { message: None, card: Some(ref c), .. } if c == "carol"));
+        assert!(Cli::try_parse_from(["whisper", "send", "alice", "hi", "--card", "carol"]).is_err());
+        assert!(Cli::try_parse_from(["whisper", "send", "alice", "hi", "--label", "Sydney"]).is_err());
+
+        let cli = Cli::parse_from(["whisper", "contact", "accept", "carol", "--as", "carol2"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Contact(ContactCommands::Accept { ref alias, save_as: Some(ref a) })
+                if alias == "carol" && a == "carol2"
+        ));
+    }
+
+    #[test]
+    fn cli_parses_profile() {
+        let cli = Cli::parse_from(["whisper", "--profile", "work", "profile", "switch", "home"]);
+        assert_eq!(cli.profile.as_deref(), Some("work"));
+        assert!(matches!(cli.command, Commands::Profile(ProfileCommands::Switch { ref name }) if name == "home"));
+    }
+
+    #[test]
+    fn cli_parses_group_remove() {
+        let cli = Cli::parse_from(["whisper", "group", "remove", "team", "bob"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Group(GroupCommands::Kick { ref name, ref alias }) if name == "team" && alias == "bob"
+        ));
+    }
+
+    #[test]
+    fn cli_parses_delete() {
+        let cli = Cli::parse_from(["whisper", "delete", "1a2b3c4d", "--local"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Delete { ref id, local: true } if id == "1a2b3c4d"
+        ));
+    }
+
+    #[test]
+    fn cli_parses_retention() {
+        let cli = Cli::parse_from(["whisper", "retention", "alice", "--days", "90"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Retention { ref conversation, days: Some(90), messages: None, keep_all: false, clear: false }
+                if conversation.as_deref() == Some("alice")
+        ));
+        let cli = Cli::parse_from(["whisper", "retention", "--messages", "10000"]);
+        assert!(matches!(cli.command, Commands::Retention { conversation: None, messages: Some(10000), .. }));
+        assert!(Cli::try_parse_from(["whisper", "retention", "alice", "--keep-all", "--days", "1"]).is_err());
+        assert!(Cli::try_parse_from(["whisper", "retention", "alice", "--clear", "--days", "1"]).is_err());
+
+        let cli = Cli::parse_from(["whisper", "prune", "--dry-run"]);
+        assert!(matches!(cli.command, Commands::Prune { dry_run: true }));
+    }
+
+    #[test]
+    fn cli_parses_pin_and_archive() {
+        let cli = Cli::parse_from(["whisper", "archive", "alice"]);
+        assert!(matches!(cli.command, Commands::Archive { ref conversation, undo: false } if conversation == "alice"));
+        let cli = Cli::parse_from(["whisper", "pin", "team", "--undo"]);
+        assert!(matches!(cli.command, Commands::Pin { ref conversation, undo: true } if conversation == "team"));
+        let cli = Cli::parse_from(["whisper", "contacts", "--archived"]);
+        assert!(matches!(cli.command, Commands::Contacts { tag: None, archived: true }));
+    }
+
+    #[test]
+    fn cli_parses_notify() {
+        let cli = Cli::parse_from(["whisper", "notify", "alice", "--level", "mentions", "--mute-hours", "8"]);
+        match cli.command {
+            Commands::Notify { conversation, level, mute, mute_hours, unmute, alert } => {
+                assert_eq!(conversation, "alice");
+                assert_eq!(level.as_deref(), Some("mentions"));
+                assert!(!mute && !unmute);
+                assert_eq!(mute_hours, Some(8));
+                assert_eq!(alert, None);
+            }
+            _ => panic!("Expected Notify command"),
+        }
+        assert!(Cli::try_parse_from(["whisper", "notify", "alice", "--mute", "--unmute"]).is_err());
+        assert!(Cli::try_parse_from(["whisper", "notify", "alice", "--alert", "siren"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_global_output() {
+        let cli = Cli::parse_from(["whisper", "contacts", "--output", "json"]);
+        assert_eq!(cli.output, OutputFormat::Json);
+        let cli = Cli::parse_from(["whisper", "--output", "json", "group", "list"]);
+        assert_eq!(cli.output, OutputFormat::Json);
+        let cli = Cli::parse_from(["whisper", "status"]);
+        assert_eq!(cli.output, OutputFormat::Text);
+        assert!(Cli::try_parse_from(["whisper", "status", "--output", "yaml"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_send_from_stdin() {
+        let cli = Cli::parse_from(["whisper", "send", "alice", "-"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Send { ref alias, message: Some(ref m), file: None, .. } if alias == "alice" && m == "-"
+        ));
+        let cli = Cli::parse_from(["whisper", "send", "--file", "-", "alice"]);
+        assert!(matches!(cli.command, Commands::Send { message: None, file: Some(ref f), .. } if f.as_os_str() == "-"));
+    }
+
+    #[test]
+    fn cli_parses_history() {
+        let cli = Cli::parse_from(["whisper", "history", "alice", "--limit", "5", "--since", "2026-01-01"]);
+        assert!(matches!(
+            cli.command,
+            Commands::History { ref alias, limit: 5, since: Some(ref s) } if alias == "alice" && s == "2026-01-01"
+        ));
+        let cli = Cli::parse_from(["whisper", "history", "alice"]);
+        assert!(matches!(cli.command, Commands::History { limit: 50, since: None, .. }));
+    }
+
+    #[test]
+    fn cli_parses_contact_notes_and_tags() {
+        let cli = Cli::parse_from(["whisper", "contact", "note", "alice", "met", "at", "FOSDEM"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Contact(ContactCommands::Note { ref text, clear: false, .. }) if text.join(" ") == "met at FOSDEM"
+        ));
+        assert!(Cli::try_parse_from(["whisper", "contact", "note", "alice", "x", "--clear"]).is_err());
+        let cli = Cli::parse_from(["whisper", "contact", "tag", "alice", "work", "oss", "--remove"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Contact(ContactCommands::Tag { ref tags, remove: true, .. }) if tags.len() == 2
+        ));
+        let cli = Cli::parse_from(["whisper", "contacts", "--tag", "work"]);
+        assert!(matches!(cli.command, Commands::Contacts { tag: Some(ref t), archived: false } if t == "work"));
+    }
+
+    #[test]
+    fn cli_parses_requests() {
+        let cli = Cli::parse_from(["whisper", "requests", "list"]);
+        assert!(matches!(cli.command, Commands::Requests(RequestCommands::List)));
+        let cli = Cli::parse_from(["whisper", "requests", "accept", "12D3KooW", "carol"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Requests(RequestCommands::Accept { ref peer_id, ref alias }) if peer_id == "12D3KooW" && alias == "carol"
+        ));
+        let cli = Cli::parse_from(["whisper", "requests", "decline", "12D3KooW"]);
+        assert!(matches!(cli.command, Commands::Requests(RequestCommands::Decline { .. })));
+        assert!(Cli::try_parse_from(["whisper", "requests", "accept", "12D3KooW"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_listen() {
+        let cli = Cli::parse_from(["whisper", "listen", "--json"]);
+        assert!(matches!(cli.command, Commands::Listen { json: true, rendezvous_server: false, metrics: None, api: None }));
+        let cli = Cli::parse_from(["whisper", "listen", "--rendezvous-server"]);
+        assert!(matches!(cli.command, Commands::Listen { json: false, rendezvous_server: true, .. }));
+
+        let cli = Cli::parse_from(["whisper", "listen", "--metrics"]);
+        let expected: SocketAddr = cli::DEFAULT_METRICS_ADDR.parse().unwrap();
+        assert!(matches!(cli.command, Commands::Listen { metrics: Some(addr), .. } if addr == expected));
+        let cli = Cli::parse_from(["whisper", "listen", "--metrics", "127.0.0.1:9000"]);
+        assert!(matches!(cli.command, Commands::Listen { metrics: Some(addr), .. } if addr.port() == 9000));
+
+        let cli = Cli::parse_from(["whisper", "listen", "--api"]);
+        let expected: SocketAddr = cli::DEFAULT_API_ADDR.parse().unwrap();
+        assert!(matches!(cli.command, Commands::Listen { api: Some(addr), .. } if addr == expected));
+    }
+
+    #[test]
+    fn cli_parses_bot() {
+        let cli = Cli::parse_from(["whisper", "bot", "--allow", "alice", "--", "python3", "bot.py", "-v"]);
+        assert!(matches!(cli.command, Commands::Bot { ref allow, ref command }
+            if allow == &["alice"] && command == &["python3", "bot.py", "-v"]));
+        assert!(Cli::try_parse_from(["whisper", "bot", "--", "./bot.sh"]).is_err());
+        assert!(Cli::try_parse_from(["whisper", "bot", "--allow", "alice"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_bridge() {
+        let cli = Cli::parse_from([
+            "whisper", "bridge", "matrix", "team", "--homeserver", "https://matrix.org", "--room", "#team:matrix.org",
+            "--token", "secret",
+        ]);
+        assert!(matches!(cli.command, Commands::Bridge(BridgeCommands::Matrix { ref group, ref room, .. })
+            if group == "team" && room == "#team:matrix.org"));
+        assert!(Cli::try_parse_from(["whisper", "bridge", "matrix", "team", "--room", "!r:x"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_mailbox() {
+        let cli = Cli::parse_from(["whisper", "mailbox", "add", "carol"]);
+        assert!(matches!(cli.command, Commands::Mailbox(MailboxCommands::Add { ref alias }) if alias == "carol"));
+        let cli = Cli::parse_from(["whisper", "mailbox", "serve", "--off"]);
+        assert!(matches!(cli.command, Commands::Mailbox(MailboxCommands::Serve { off: true })));
+        assert!(Cli::try_parse_from(["whisper", "mailbox", "add"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_queue() {
+        let cli = Cli::parse_from(["whisper", "queue", "retry"]);
+        assert!(matches!(cli.command, Commands::Queue(QueueCommands::Retry { id: None })));
+        let cli = Cli::parse_from(["whisper", "queue", "drop", "3f2a"]);
+        assert!(matches!(cli.command, Commands::Queue(QueueCommands::Drop { ref id }) if id == "3f2a"));
+        assert!(Cli::try_parse_from(["whisper", "queue", "drop"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_channel() {
+        let cli = Cli::parse_from(["whisper", "channel", "post", "news", "hello"]);
+        assert!(matches!(cli.command, Commands::Channel(ChannelCommands::Post { ref name, ref message })
+            if name == "news" && message == "hello"));
+        let cli = Cli::parse_from(["whisper", "channel", "read", "news", "--limit", "5"]);
+        assert!(matches!(cli.command, Commands::Channel(ChannelCommands::Read { limit: 5, .. })));
+        assert!(Cli::try_parse_from(["whisper", "channel", "subscribe"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_device() {
+        let cli = Cli::parse_from(["whisper", "device", "link", "CODE", "--name", "laptop"]);
+        assert!(matches!(cli.command, Commands::Device(DeviceCommands::Link { ref code, name: Some(ref name) })
+            if code == "CODE" && name == "laptop"));
+        let cli = Cli::parse_from(["whisper", "device", "unlink", "laptop"]);
+        assert!(matches!(cli.command, Commands::Device(DeviceCommands::Unlink { ref name }) if name == "laptop"));
+        assert!(Cli::try_parse_from(["whisper", "device", "join"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_connect() {
+        let cli = Cli::parse_from(["whisper", "connect", "203.0.113.5:4001"]);
+        assert!(matches!(cli.command, Commands::Connect { address } if address == "203.0.113.5:4001"));
+        assert!(Cli::try_parse_from(["whisper", "connect"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_add_by_username() {
+        let cli = Cli::parse_from(["whisper", "add", "alice", "--username", "@alice"]);
+        assert!(matches!(cli.command, Commands::Add { peer_id: None, username: Some(ref u), .. } if u == "@alice"));
+        let cli = Cli::parse_from(["whisper", "username", "--clear"]);
+        assert!(matches!(cli.command, Commands::Username { username: None, clear: true }));
+        assert!(Cli::try_parse_from(["whisper", "add", "alice"]).is_err());
+        assert!(Cli::try_parse_from(["whisper", "add", "alice", "12D3KooW", "--username", "@alice"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_invite() {
+        let cli = Cli::parse_from(["whisper", "invite", "create", "--alias", "bob"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Invite(InviteCommands::Create { alias: Some(ref a), hours: 24 }) if a == "bob"
+        ));
+        let cli = Cli::parse_from(["whisper", "invite", "accept", "whisper-invite:abc"]);
+        assert!(matches!(cli.command, Commands::Invite(InviteCommands::Accept { alias: None, .. })));
+        assert!(Cli::try_parse_from(["whisper", "invite", "create", "--hours", "0"]).is_err());
+        assert!(Cli::try_parse_from(["whisper", "invite", "accept"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_rendezvous() {
+        let cli = Cli::parse_from(["whisper", "rendezvous", "--clear"]);
+        assert!(matches!(cli.command, Commands::Rendezvous { address: None, clear: true }));
+        assert!(Cli::try_parse_from(["whisper", "rendezvous", "/ip4/1.2.3.4/tcp/1", "--clear"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_proxy() {
+        let cli = Cli::parse_from(["whisper", "proxy", "127.0.0.1:9050"]);
+        assert!(matches!(cli.command, Commands::Proxy { address: Some(ref a), clear: false } if a == "127.0.0.1:9050"));
+        let cli = Cli::parse_from(["whisper", "contact", "address", "alice", "example.onion:4001"]);
+        assert!(matches!(cli.command, Commands::Contact(ContactCommands::Address { ref alias, .. }) if alias == "alice"));
+    }
+
+    #[test]
+    fn cli_parses_webhook() {
+        let cli = Cli::parse_from(["whisper", "webhook", "https://ntfy.sh/x", "--only", "alice", "--only", "team"]);
+        assert!(matches!(cli.command, Commands::Webhook { url: Some(_), ref only, clear: false } if only.len() == 2));
+        assert!(Cli::try_parse_from(["whisper", "webhook", "--only", "alice"]).is_err());
+        assert!(Cli::try_parse_from(["whisper", "webhook", "https://ntfy.sh/x", "--clear"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_websocket() {
+        let cli = Cli::parse_from(["whisper", "websocket", "8080"]);
+        assert!(matches!(cli.command, Commands::Websocket { port: Some(8080), clear: false }));
+        assert!(Cli::try_parse_from(["whisper", "websocket", "http"]).is_err());
+        assert!(Cli::try_parse_from(["whisper", "websocket", "8080", "--clear"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_stats() {
+        let cli = Cli::parse_from(["whisper", "--output", "json", "stats"]);
+        assert!(matches!(cli.command, Commands::Stats));
+    }
+
+    #[test]
+    fn cli_parses_audit() {
+        let cli = Cli::parse_from(["whisper", "audit", "--kind", "blocked-peer", "--limit", "5"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Audit { kind: Some(AuditKind::BlockedPeer), since: None, limit: 5 }
+        ));
+        assert!(Cli::try_parse_from(["whisper", "audit", "--kind", "everything"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_presence() {
+        let cli = Cli::parse_from(["whisper", "presence", "away"]);
+        assert!(matches!(cli.command, Commands::Presence { status: Some(s) } if s == "away"));
+        let cli = Cli::parse_from(["whisper", "presence"]);
+        assert!(matches!(cli.command, Commands::Presence { status: None }));
+    }
+
+    #[test]
+    fn cli_parses_markdown() {
+        let cli = Cli::parse_from(["whisper", "markdown", "off"]);
+        assert!(matches!(cli.command, Commands::Markdown { enabled: Some(false) }));
+        let cli = Cli::parse_from(["whisper", "markdown"]);
+        assert!(matches!(cli.command, Commands::Markdown { enabled: None }));
+    }
+
+    #[test]
+    fn cli_parses_stamps() {
+        let cli = Cli::parse_from(["whisper", "stamps", "0"]);
+        assert!(matches!(cli.command, Commands::Stamps { difficulty: Some(0) }));
+        let cli = Cli::parse_from(["whisper", "stamps"]);
+        assert!(matches!(cli.command, Commands::Stamps { difficulty: None }));
+        assert!(Cli::try_parse_from(["whisper", "stamps", "25"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_policy() {
+        let cli = Cli::parse_from(["whisper", "policy", "--read-receipts", "verified", "--group-invites", "trusted"]);
+        match cli.command {
+            Commands::Policy { read_receipts, conversations, group_invites } => {
+                assert_eq!(read_receipts.as_deref(), Some("verified"));
+                assert_eq!(conversations, None);
+                assert_eq!(group_invites.as_deref(), Some("trusted"));
+            }
+            _ => panic!("Expected Policy command"),
+        }
+        assert!(Cli::try_parse_from(["whisper", "policy", "--conversations", "friends"]).is_err());
+    }
+
     #[test]
     fn cli_help_works() {
         // Just verify the command can be built
@@ -0,0 +1,126 @@
+//! Broadcast channels.
+//!
+//! A channel is a one-to-many feed: its owner posts, and subscribers only
+//! read. Every post is signed with the owner's identity key, so a
+//! subscriber can check a post against the owner's peer ID however it
+//! arrived, and nobody else can post to the channel.
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, SubsecRound, Utc};
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Domain separation for channel post signatures.
+const CHANNEL_POST_SIGNATURE_CONTEXT: &[u8] = b"whisper-channel-post-v1";
+
+/// A channel, as shared with would-be subscribers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Channel {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(with = "super::envelope::peer_id_bytes")]
+    pub owner: PeerId,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Channel {
+    /// A new channel owned by `owner`.
+    pub fn new(name: String, owner: PeerId) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            name,
+            owner,
+            // Stored to the microsecond
+            created_at: Utc::now().trunc_subsecs(6),
+        }
+    }
+
+    /// The channel as a code to hand to subscribers.
+    pub fn to_code(&self) -> Result<String> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(self, &mut buf).context("Failed to encode the channel")?;
+        Ok(BASE64.encode(&buf))
+    }
+
+    /// Read a channel code.
+    pub fn from_code(code: &str) -> Result<Self> {
+        let bytes = BASE64.decode(code.trim()).context("Not a channel code")?;
+        ciborium::from_reader(bytes.as_slice()).context("Not a channel code")
+    }
+}
+
+/// A post to a channel, signed by its owner.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelPost {
+    pub id: Uuid,
+    pub channel_id: Uuid,
+    pub text: String,
+    pub posted_at: DateTime<Utc>,
+    pub signature: Vec<u8>,
+}
+
+impl ChannelPost {
+    /// Post `text` to `channel`, as its owner.
+    pub fn sign(keypair: &Keypair, channel: &Channel, text: String) -> Result<Self> {
+        if PeerId::from(keypair.public()) != channel.owner {
+            bail!("Only the owner of '{}' can post to it", channel.name);
+        }
+        let mut post = Self {
+            id: Uuid::new_v4(),
+            channel_id: channel.id,
+            text,
+            posted_at: Utc::now().trunc_subsecs(6),
+            signature: Vec::new(),
+        };
+        post.signature = keypair
+            .sign(&post.signing_bytes())
+            .context("Failed to sign the post")?;
+        Ok(post)
+    }
+
+    /// Check the post is for `channel` and its owner signed it.
+    pub fn verify(&self, channel: &Channel) -> bool {
+        let Ok(key) = PublicKey::try_decode_protobuf(channel.owner.as_ref().digest()) else {
+            return false;
+        };
+        self.channel_id == channel.id && key.verify(&self.signing_bytes(), &self.signature)
+    }
+
+    fn signing_bytes(&self) -> Vec<u8> {
+        let mut buf = CHANNEL_POST_SIGNATURE_CONTEXT.to_vec();
+        buf.extend_from_slice(self.id.as_bytes());
+        buf.extend_from_slice(self.channel_id.as_bytes());
+        buf.extend_from_slice(&self.posted_at.timestamp_micros().to_be_bytes());
+        buf.extend_from_slice(self.text.as_bytes());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_owner_can_post() {
+        let (owner, other) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let channel = Channel::new("news".to_string(), PeerId::from(owner.public()));
+        assert_eq!(Channel::from_code(&channel.to_code().unwrap()).unwrap(), channel);
+
+        let post = ChannelPost::sign(&owner, &channel, "hello, subscribers".to_string()).unwrap();
+        assert!(post.verify(&channel));
+        assert!(ChannelPost::sign(&other, &channel, "hijacked".to_string()).is_err());
+
+        let mut edited = post.clone();
+        edited.text = "goodbye".to_string();
+        assert!(!edited.verify(&channel));
+
+        // Signed for one channel, replayed into another of the owner's
+        let mut moved = post;
+        let elsewhere = Channel::new("other".to_string(), channel.owner);
+        moved.channel_id = elsewhere.id;
+        assert!(!moved.verify(&elsewhere));
+    }
+}
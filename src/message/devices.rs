@@ -0,0 +1,195 @@
+//! Linked devices.
+//!
+//! Someone can run whisper on several devices, each with its own key. The
+//! first is the primary: its peer ID is the identity their contacts know.
+//! Another device agrees to be linked by signing the identity's peer ID
+//! (a link code), and the primary lists it in a device list signed with
+//! the identity key. Contacts holding the list send to every device on it,
+//! and take what those devices send as coming from the identity. Both
+//! signatures are needed, so nobody can list a device that didn't agree
+//! to it, and no device can attach itself to an identity on its own.
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Utc};
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+/// Domain separation for a device's consent to being linked.
+const DEVICE_LINK_SIGNATURE_CONTEXT: &[u8] = b"whisper-device-link-v1";
+
+/// Domain separation for device list signatures.
+const DEVICE_LIST_SIGNATURE_CONTEXT: &[u8] = b"whisper-device-list-v1";
+
+/// A device's consent to being linked to `identity`, signed with the
+/// device's own key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceLink {
+    #[serde(with = "super::envelope::peer_id_bytes")]
+    pub device: PeerId,
+    #[serde(with = "super::envelope::peer_id_bytes")]
+    pub identity: PeerId,
+    pub signature: Vec<u8>,
+}
+
+impl DeviceLink {
+    /// Agree, as the device with `keypair`, to be linked to `identity`.
+    pub fn new(keypair: &Keypair, identity: PeerId) -> Result<Self> {
+        let device = PeerId::from(keypair.public());
+        let signature = keypair
+            .sign(&link_signing_bytes(&device, &identity))
+            .context("Failed to sign the device link")?;
+        Ok(Self { device, identity, signature })
+    }
+
+    /// Check the device signed it.
+    pub fn verify(&self) -> bool {
+        key_of(&self.device).is_some_and(|key| key.verify(&link_signing_bytes(&self.device, &self.identity), &self.signature))
+    }
+
+    /// The link as a code to type or paste on the primary.
+    pub fn to_code(&self) -> Result<String> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(self, &mut buf).context("Failed to encode the device link")?;
+        Ok(BASE64.encode(&buf))
+    }
+
+    /// Read a link code, checking its signature.
+    pub fn from_code(code: &str) -> Result<Self> {
+        let bytes = BASE64.decode(code.trim()).context("Not a device link code")?;
+        let link: Self = ciborium::from_reader(bytes.as_slice()).context("Not a device link code")?;
+        if !link.verify() {
+            bail!("The device link code's signature doesn't match");
+        }
+        Ok(link)
+    }
+}
+
+/// A device on a device list.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkedDevice {
+    pub name: String,
+    pub link: DeviceLink,
+}
+
+impl LinkedDevice {
+    pub fn peer_id(&self) -> PeerId {
+        self.link.device
+    }
+}
+
+/// The devices linked to an identity, besides the primary, signed with
+/// the identity key. Newer lists replace older ones, so unlinking a device
+/// is announcing a list without it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceList {
+    #[serde(with = "super::envelope::peer_id_bytes")]
+    pub identity: PeerId,
+    pub devices: Vec<LinkedDevice>,
+    pub issued_at: DateTime<Utc>,
+    pub signature: Vec<u8>,
+}
+
+impl DeviceList {
+    /// Sign a list of our devices, as of now.
+    pub fn sign(keypair: &Keypair, devices: Vec<LinkedDevice>) -> Result<Self> {
+        let mut list = Self {
+            identity: PeerId::from(keypair.public()),
+            devices,
+            issued_at: Utc::now(),
+            signature: Vec::new(),
+        };
+        list.signature = keypair
+            .sign(&list.signing_bytes()?)
+            .context("Failed to sign the device list")?;
+        Ok(list)
+    }
+
+    /// Check the identity signed the list and every device agreed to be
+    /// on it.
+    pub fn verify(&self) -> bool {
+        let Some(key) = key_of(&self.identity) else {
+            return false;
+        };
+        let signed = self.signing_bytes().is_ok_and(|bytes| key.verify(&bytes, &self.signature));
+        signed
+            && self
+                .devices
+                .iter()
+                .all(|device| device.link.identity == self.identity && device.link.verify())
+    }
+
+    /// The devices' peer IDs.
+    pub fn device_ids(&self) -> Vec<PeerId> {
+        self.devices.iter().map(LinkedDevice::peer_id).collect()
+    }
+
+    fn signing_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = DEVICE_LIST_SIGNATURE_CONTEXT.to_vec();
+        buf.extend_from_slice(&self.identity.to_bytes());
+        buf.extend_from_slice(&self.issued_at.timestamp_micros().to_be_bytes());
+        ciborium::into_writer(&self.devices, &mut buf).context("Failed to encode the device list")?;
+        Ok(buf)
+    }
+}
+
+fn link_signing_bytes(device: &PeerId, identity: &PeerId) -> Vec<u8> {
+    let mut buf = DEVICE_LINK_SIGNATURE_CONTEXT.to_vec();
+    buf.extend_from_slice(&device.to_bytes());
+    buf.extend_from_slice(&identity.to_bytes());
+    buf
+}
+
+/// The public key inside a peer ID. Whisper's Ed25519 peer IDs all carry
+/// theirs.
+fn key_of(peer_id: &PeerId) -> Option<PublicKey> {
+    PublicKey::try_decode_protobuf(peer_id.as_ref().digest()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linked(device: &Keypair, identity: PeerId, name: &str) -> LinkedDevice {
+        LinkedDevice {
+            name: name.to_string(),
+            link: DeviceLink::new(device, identity).unwrap(),
+        }
+    }
+
+    #[test]
+    fn link_codes_round_trip() {
+        let (device, identity) = (Keypair::generate_ed25519(), PeerId::random());
+        let link = DeviceLink::new(&device, identity).unwrap();
+        assert_eq!(DeviceLink::from_code(&link.to_code().unwrap()).unwrap(), link);
+
+        let mut forged = link.clone();
+        forged.identity = PeerId::random();
+        assert!(DeviceLink::from_code(&forged.to_code().unwrap()).is_err());
+        assert!(DeviceLink::from_code("not a code").is_err());
+    }
+
+    #[test]
+    fn lists_need_both_signatures() {
+        let (primary, laptop) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let identity = PeerId::from(primary.public());
+
+        let list = DeviceList::sign(&primary, vec![linked(&laptop, identity, "laptop")]).unwrap();
+        assert!(list.verify());
+        assert_eq!(list.device_ids(), vec![PeerId::from(laptop.public())]);
+
+        // A device that agreed to someone else
+        let stolen = linked(&laptop, PeerId::random(), "laptop");
+        assert!(!DeviceList::sign(&primary, vec![stolen]).unwrap().verify());
+
+        // Signed by someone other than the identity
+        let mut claimed = list.clone();
+        claimed.identity = PeerId::from(laptop.public());
+        assert!(!claimed.verify());
+
+        let mut renamed = list;
+        renamed.devices[0].name = "phone".to_string();
+        assert!(!renamed.verify());
+    }
+}
@@ -0,0 +1,588 @@
+//! Versioned wire envelope for everything sent between peers.
+//!
+//! Every payload on the wire is an `Envelope` encoded as CBOR. The message
+//! kind is the `MessageContent` variant, so adding a new kind only means
+//! adding a variant.
+//!
+//! Each envelope also carries a sequence number that only ever increases
+//! for a given sender, so receivers can reject replayed copies, and is
+//! signed with the sender's Ed25519 identity key.
+//!
+//! Large envelopes can travel zstd-compressed, packed whole into a frame
+//! flagged `FLAG_ZSTD`. Only peers whose envelopes carry
+//! `FLAG_ACCEPTS_ZSTD` are sent packed frames; `decode` unpacks them.
+//!
+//! Envelopes to peers who may not know the sender carry a proof-of-work
+//! stamp over the signed part, without which a stranger's first message
+//! isn't let in.
+
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::stamp::{check_stamp, mint_stamp};
+use super::types::{Message, MessageContent, MessageStatus, Recipient};
+
+/// Current wire envelope version.
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// Flag on a packed frame: the envelope inside is zstd-compressed.
+pub const FLAG_ZSTD: u8 = 0b01;
+
+/// Flag on an envelope: its sender can decode packed frames.
+pub const FLAG_ACCEPTS_ZSTD: u8 = 0b10;
+
+/// Encoded envelopes shorter than this are sent as they are.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Most a packed frame may unpack to, so a small frame can't claim a
+/// huge envelope.
+pub const MAX_UNPACKED_LEN: usize = 16 * 1024 * 1024;
+
+/// How far behind a sender's newest sequence number an envelope may be and
+/// still be accepted, in microseconds (7 days). Allows for reordering, such
+/// as messages that sat in an offline queue.
+pub const REPLAY_WINDOW_MICROS: u64 = 7 * 24 * 60 * 60 * 1_000_000;
+
+/// How far ahead of our clock a sequence number may be, in microseconds
+/// (1 day). Anything later is rejected rather than allowed to push the
+/// sender's high-water mark out of reach.
+pub const MAX_SEQ_SKEW_MICROS: u64 = 24 * 60 * 60 * 1_000_000;
+
+/// The next sequence number for an envelope we send: the current time in
+/// microseconds, bumped past the last one handed out so it always
+/// increases, and keeps increasing across restarts.
+pub fn next_seq() -> u64 {
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    let now = seq_now();
+    let previous = LAST
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(now.max(last + 1)))
+        .expect("update always succeeds");
+    now.max(previous + 1)
+}
+
+/// The current time as a sequence number.
+pub fn seq_now() -> u64 {
+    Utc::now().timestamp_micros().max(0) as u64
+}
+
+/// A message as it travels over the wire.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u8,
+    pub id: Uuid,
+    #[serde(with = "peer_id_bytes")]
+    pub sender: PeerId,
+    pub timestamp: DateTime<Utc>,
+    pub payload: MessageContent,
+    /// Sender's sequence number for replay protection; 0 from peers that
+    /// predate it.
+    #[serde(default)]
+    pub seq: u64,
+    /// Sender's Ed25519 signature over the rest of the envelope.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Vec<u8>>,
+    /// What the sender's client supports, as `FLAG_*` bits. Not covered by
+    /// the signature, so peers that predate it still verify ours.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub flags: u8,
+    /// Proof of work for the recipient, from senders it may not know yet.
+    /// Computed over what the signature covers, so not covered by it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stamp: Option<u64>,
+}
+
+fn is_zero(flags: &u8) -> bool {
+    *flags == 0
+}
+
+/// An encoded envelope, compressed.
+#[derive(Serialize, Deserialize)]
+struct Packed {
+    version: u8,
+    flags: u8,
+    #[serde(with = "byte_vec")]
+    packed: Vec<u8>,
+}
+
+/// Whether an envelope provably came from the sender it names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Authenticity {
+    /// Signed with the sender's identity key.
+    Verified,
+    /// Not signed, as from peers that predate signing, or we have no key
+    /// to check the signature against.
+    Unverified,
+    /// Signed, but not by the sender it names.
+    Forged,
+}
+
+impl Envelope {
+    /// Wrap a payload in a new envelope from `sender`.
+    pub fn new(sender: PeerId, payload: MessageContent) -> Self {
+        Self {
+            version: ENVELOPE_VERSION,
+            id: Uuid::new_v4(),
+            sender,
+            timestamp: Utc::now(),
+            payload,
+            seq: next_seq(),
+            signature: None,
+            flags: FLAG_ACCEPTS_ZSTD,
+            stamp: None,
+        }
+    }
+
+    /// Wrap a stored message, keeping its ID and timestamp so receipts
+    /// from the recipient resolve to the sender's copy. Each call gets a
+    /// fresh sequence number.
+    pub fn from_message(msg: &Message) -> Self {
+        Self {
+            version: ENVELOPE_VERSION,
+            id: msg.id,
+            sender: msg.from,
+            timestamp: msg.timestamp,
+            payload: msg.content.clone(),
+            seq: next_seq(),
+            signature: None,
+            flags: FLAG_ACCEPTS_ZSTD,
+            stamp: None,
+        }
+    }
+
+    /// Whether the sender can decode packed frames.
+    pub fn accepts_compression(&self) -> bool {
+        self.flags & FLAG_ACCEPTS_ZSTD != 0
+    }
+
+    /// Sign the envelope with our identity key. `keypair` must be the
+    /// sender's.
+    pub fn signed(mut self, keypair: &Keypair) -> Result<Self> {
+        let signature = keypair
+            .sign(&self.signed_bytes()?)
+            .context("Failed to sign envelope")?;
+        self.signature = Some(signature);
+        Ok(self)
+    }
+
+    /// Sign with our identity key and serialize for sending.
+    pub fn encode_signed(self, keypair: &Keypair) -> Result<Vec<u8>> {
+        self.signed(keypair)?.encode()
+    }
+
+    /// Check the signature against the sender's identity key, which must
+    /// also be the key behind the sender's peer ID.
+    pub fn authenticate(&self, sender_key: Option<&PublicKey>) -> Authenticity {
+        let (Some(signature), Some(key)) = (&self.signature, sender_key) else {
+            return Authenticity::Unverified;
+        };
+        if key.to_peer_id() != self.sender {
+            return Authenticity::Forged;
+        }
+        match self.signed_bytes() {
+            Ok(bytes) if key.verify(&bytes, signature) => Authenticity::Verified,
+            _ => Authenticity::Forged,
+        }
+    }
+
+    /// What the signature covers: the envelope encoded without it, its
+    /// flags or its stamp.
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        let unsigned = Self { signature: None, flags: 0, stamp: None, ..self.clone() };
+        unsigned.encode()
+    }
+
+    /// Attach a proof-of-work stamp of `difficulty` for `recipient`.
+    pub fn stamped(mut self, recipient: &PeerId, difficulty: u8) -> Result<Self> {
+        let message = *blake3::hash(&self.signed_bytes()?).as_bytes();
+        self.stamp = Some(mint_stamp(recipient, &message, difficulty));
+        Ok(self)
+    }
+
+    /// Whether the envelope carries a stamp of at least `difficulty` for
+    /// `recipient`.
+    pub fn has_stamp(&self, recipient: &PeerId, difficulty: u8) -> bool {
+        let (Some(nonce), Ok(bytes)) = (self.stamp, self.signed_bytes()) else {
+            return false;
+        };
+        check_stamp(recipient, blake3::hash(&bytes).as_bytes(), nonce, difficulty)
+    }
+
+    /// Convert a received envelope into a message addressed to `to`.
+    pub fn into_message(self, to: Recipient) -> Message {
+        Message {
+            id: self.id,
+            from: self.sender,
+            to,
+            content: self.payload,
+            timestamp: self.timestamp,
+            status: MessageStatus::Delivered,
+        }
+    }
+
+    /// Encode the envelope for the wire.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(self, &mut buf).context("Failed to encode envelope")?;
+        Ok(buf)
+    }
+
+    /// Decode an envelope from the wire, unpacking it if it came in a
+    /// packed frame, and rejecting unknown versions.
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let envelope: Self = match ciborium::from_reader(data) {
+            Ok(envelope) => envelope,
+            Err(e) => match ciborium::from_reader::<Packed, _>(data) {
+                Ok(packed) => Self::unpack(packed)?,
+                Err(_) => return Err(e).context("Failed to decode envelope"),
+            },
+        };
+        if envelope.version != ENVELOPE_VERSION {
+            anyhow::bail!("Unsupported envelope version: {}", envelope.version);
+        }
+        Ok(envelope)
+    }
+
+    /// Compress an encoded envelope into a packed frame, if it's long
+    /// enough to be worth it and comes out shorter. Only for peers that
+    /// accept compression.
+    pub fn pack(data: Vec<u8>) -> Vec<u8> {
+        if data.len() < COMPRESSION_THRESHOLD {
+            return data;
+        }
+        let packed = Packed {
+            version: ENVELOPE_VERSION,
+            flags: FLAG_ZSTD,
+            packed: ruzstd::encoding::compress_to_vec(data.as_slice(), ruzstd::encoding::CompressionLevel::Fastest),
+        };
+        let mut buf = Vec::new();
+        match ciborium::into_writer(&packed, &mut buf) {
+            Ok(()) if buf.len() < data.len() => buf,
+            _ => data,
+        }
+    }
+
+    fn unpack(packed: Packed) -> Result<Self> {
+        if packed.version != ENVELOPE_VERSION {
+            anyhow::bail!("Unsupported envelope version: {}", packed.version);
+        }
+        if packed.flags & FLAG_ZSTD == 0 {
+            anyhow::bail!("Unknown packing: flags {:#04b}", packed.flags);
+        }
+        let mut decoder = ruzstd::decoding::StreamingDecoder::new(packed.packed.as_slice())
+            .map_err(|e| anyhow::anyhow!("Failed to unpack envelope: {}", e))?;
+        let mut data = Vec::new();
+        (&mut decoder)
+            .take(MAX_UNPACKED_LEN as u64 + 1)
+            .read_to_end(&mut data)
+            .context("Failed to unpack envelope")?;
+        if data.len() > MAX_UNPACKED_LEN {
+            anyhow::bail!("Packed envelope unpacks to more than {} bytes", MAX_UNPACKED_LEN);
+        }
+        // Packed frames don't nest
+        ciborium::from_reader(data.as_slice()).context("Failed to decode packed envelope")
+    }
+}
+
+/// Serialize a `PeerId` as its multihash bytes.
+pub(crate) mod peer_id_bytes {
+    use libp2p::PeerId;
+    use serde::{de::Error, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(peer_id: &PeerId, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&peer_id.to_bytes())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PeerId, D::Error> {
+        let bytes = super::byte_vec::deserialize(deserializer)?;
+        PeerId::from_bytes(&bytes).map_err(D::Error::custom)
+    }
+}
+
+/// Serialize a `Vec<u8>` as a CBOR byte string rather than an array.
+mod byte_vec {
+    use serde::{de::Error, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for BytesVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("peer id bytes")
+            }
+
+            fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                Ok(v.to_vec())
+            }
+
+            fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(v)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut out = Vec::new();
+                while let Some(b) = seq.next_element()? {
+                    out.push(b);
+                }
+                Ok(out)
+            }
+        }
+
+        deserializer.deserialize_bytes(BytesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{GroupInvite, GroupMemberUpdate, MemberChange, MemberRole, ReceiptType};
+    use libp2p::identity::Keypair;
+
+    fn make_peer_id() -> PeerId {
+        PeerId::from(Keypair::generate_ed25519().public())
+    }
+
+    #[test]
+    fn text_envelope_roundtrip() {
+        let sender = make_peer_id();
+        let envelope = Envelope::new(sender, MessageContent::Text("hello".to_string()));
+
+        let decoded = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+        assert_eq!(decoded.id, envelope.id);
+        assert_eq!(decoded.sender, sender);
+        assert_eq!(decoded.timestamp, envelope.timestamp);
+        assert!(matches!(decoded.payload, MessageContent::Text(ref t) if t == "hello"));
+    }
+
+    #[test]
+    fn receipt_envelope_roundtrip() {
+        let msg_id = Uuid::new_v4();
+        let envelope = Envelope::new(
+            make_peer_id(),
+            MessageContent::Receipt(msg_id, ReceiptType::Read),
+        );
+
+        let decoded = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+        match decoded.payload {
+            MessageContent::Receipt(id, ReceiptType::Read) => assert_eq!(id, msg_id),
+            other => panic!("Expected read receipt, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn group_invite_envelope_roundtrip() {
+        let invite = GroupInvite {
+            group_id: Uuid::new_v4(),
+            name: "friends".to_string(),
+            encrypted_key: vec![1, 2, 3],
+        };
+        let envelope = Envelope::new(make_peer_id(), MessageContent::GroupInvite(invite.clone()));
+
+        let decoded = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+        match decoded.payload {
+            MessageContent::GroupInvite(got) => {
+                assert_eq!(got.group_id, invite.group_id);
+                assert_eq!(got.name, "friends");
+                assert_eq!(got.encrypted_key, vec![1, 2, 3]);
+            }
+            other => panic!("Expected group invite, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn group_member_update_envelope_roundtrip() {
+        let update = GroupMemberUpdate {
+            group_id: Uuid::new_v4(),
+            peer_id: make_peer_id(),
+            change: MemberChange::Role(MemberRole::Admin),
+        };
+        let envelope = Envelope::new(make_peer_id(), MessageContent::GroupMemberUpdate(update.clone()));
+
+        let decoded = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+        match decoded.payload {
+            MessageContent::GroupMemberUpdate(got) => {
+                assert_eq!(got.group_id, update.group_id);
+                assert_eq!(got.peer_id, update.peer_id);
+                assert_eq!(got.change, MemberChange::Role(MemberRole::Admin));
+            }
+            other => panic!("Expected member update, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn typing_envelope_roundtrip() {
+        let envelope = Envelope::new(make_peer_id(), MessageContent::Typing);
+        let decoded = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+        assert!(matches!(decoded.payload, MessageContent::Typing));
+    }
+
+    #[test]
+    fn message_id_survives_roundtrip() {
+        let from = make_peer_id();
+        let to = make_peer_id();
+        let msg = Message::new_text(from, Recipient::Direct(to), "hi".to_string());
+
+        let envelope = Envelope::decode(&Envelope::from_message(&msg).encode().unwrap()).unwrap();
+        let received = envelope.into_message(Recipient::Direct(to));
+        assert_eq!(received.id, msg.id);
+        assert_eq!(received.from, from);
+        assert_eq!(received.timestamp, msg.timestamp);
+        assert!(matches!(received.status, MessageStatus::Delivered));
+    }
+
+    #[test]
+    fn decode_rejects_unknown_version() {
+        let mut envelope = Envelope::new(make_peer_id(), MessageContent::Text("hi".to_string()));
+        envelope.version = ENVELOPE_VERSION + 1;
+        let data = envelope.encode().unwrap();
+        assert!(Envelope::decode(&data).is_err());
+    }
+
+    #[test]
+    fn sequence_numbers_increase() {
+        let sender = make_peer_id();
+        let first = Envelope::new(sender, MessageContent::Typing);
+        let second = Envelope::new(sender, MessageContent::Typing);
+        assert!(second.seq > first.seq);
+        assert!(first.seq <= seq_now());
+
+        let msg = Message::new_text(sender, Recipient::Direct(make_peer_id()), "hi".to_string());
+        let resent = Envelope::from_message(&msg);
+        assert!(resent.seq > second.seq);
+        assert_eq!(Envelope::decode(&resent.encode().unwrap()).unwrap().seq, resent.seq);
+    }
+
+    #[test]
+    fn envelopes_without_seq_decode_as_zero() {
+        #[derive(Serialize)]
+        struct Unsequenced {
+            version: u8,
+            id: Uuid,
+            #[serde(with = "peer_id_bytes")]
+            sender: PeerId,
+            timestamp: DateTime<Utc>,
+            payload: MessageContent,
+        }
+        let old = Unsequenced {
+            version: ENVELOPE_VERSION,
+            id: Uuid::new_v4(),
+            sender: make_peer_id(),
+            timestamp: Utc::now(),
+            payload: MessageContent::Text("hi".to_string()),
+        };
+        let mut data = Vec::new();
+        ciborium::into_writer(&old, &mut data).unwrap();
+
+        let decoded = Envelope::decode(&data).unwrap();
+        assert_eq!(decoded.id, old.id);
+        assert_eq!(decoded.seq, 0);
+    }
+
+    #[test]
+    fn signed_envelope_verifies_after_roundtrip() {
+        let keypair = Keypair::generate_ed25519();
+        let sender = keypair.public().to_peer_id();
+        let envelope = Envelope::new(sender, MessageContent::Text("hi".to_string()))
+            .signed(&keypair)
+            .unwrap();
+
+        let decoded = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+        assert_eq!(decoded.authenticate(Some(&keypair.public())), Authenticity::Verified);
+        assert_eq!(decoded.authenticate(None), Authenticity::Unverified);
+    }
+
+    #[test]
+    fn tampered_or_misattributed_envelopes_are_forged() {
+        let keypair = Keypair::generate_ed25519();
+        let mallory = Keypair::generate_ed25519();
+        let sender = keypair.public().to_peer_id();
+
+        let mut tampered = Envelope::new(sender, MessageContent::Text("hi".to_string()))
+            .signed(&keypair)
+            .unwrap();
+        tampered.payload = MessageContent::Text("send money".to_string());
+        assert_eq!(tampered.authenticate(Some(&keypair.public())), Authenticity::Forged);
+
+        // Signed by someone else in the sender's name
+        let impostor = Envelope::new(sender, MessageContent::Typing).signed(&mallory).unwrap();
+        assert_eq!(impostor.authenticate(Some(&keypair.public())), Authenticity::Forged);
+        assert_eq!(impostor.authenticate(Some(&mallory.public())), Authenticity::Forged);
+    }
+
+    #[test]
+    fn unsigned_envelopes_are_unverified() {
+        let keypair = Keypair::generate_ed25519();
+        let envelope = Envelope::new(keypair.public().to_peer_id(), MessageContent::Typing);
+        let decoded = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+        assert!(decoded.signature.is_none());
+        assert_eq!(decoded.authenticate(Some(&keypair.public())), Authenticity::Unverified);
+    }
+
+    #[test]
+    fn large_envelopes_are_packed_and_still_verify() {
+        let keypair = Keypair::generate_ed25519();
+        let text = "all work and no play makes jack a dull boy. ".repeat(100);
+        let envelope = Envelope::new(keypair.public().to_peer_id(), MessageContent::Text(text.clone()));
+        let wire = envelope.encode_signed(&keypair).unwrap();
+
+        let packed = Envelope::pack(wire.clone());
+        assert!(packed.len() < wire.len() / 4);
+        let decoded = Envelope::decode(&packed).unwrap();
+        assert!(matches!(decoded.payload, MessageContent::Text(ref t) if *t == text));
+        assert!(decoded.accepts_compression());
+        assert_eq!(decoded.authenticate(Some(&keypair.public())), Authenticity::Verified);
+
+        // Short ones aren't worth it
+        let short = Envelope::new(keypair.public().to_peer_id(), MessageContent::Typing).encode().unwrap();
+        assert_eq!(Envelope::pack(short.clone()), short);
+    }
+
+    #[test]
+    fn flags_are_not_signed() {
+        let keypair = Keypair::generate_ed25519();
+        let mut envelope = Envelope::new(keypair.public().to_peer_id(), MessageContent::Typing)
+            .signed(&keypair)
+            .unwrap();
+        // As a peer that predates flags sees it
+        envelope.flags = 0;
+        assert_eq!(envelope.authenticate(Some(&keypair.public())), Authenticity::Verified);
+    }
+
+    #[test]
+    fn stamp_survives_the_wire_and_binds_the_envelope() {
+        let keypair = Keypair::generate_ed25519();
+        let bob = make_peer_id();
+        let envelope = Envelope::new(keypair.public().to_peer_id(), MessageContent::Text("hi".into()))
+            .signed(&keypair)
+            .unwrap()
+            .stamped(&bob, 16)
+            .unwrap();
+        let received = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+        assert!(received.has_stamp(&bob, 16));
+        assert_eq!(received.authenticate(Some(&keypair.public())), Authenticity::Verified);
+
+        // Moved to another message, it no longer counts
+        let mut moved = Envelope::new(keypair.public().to_peer_id(), MessageContent::Text("spam".into()));
+        moved.stamp = received.stamp;
+        assert!(!moved.has_stamp(&bob, 16));
+        assert!(!Envelope::new(bob, MessageContent::Typing).has_stamp(&bob, 0));
+    }
+
+    #[test]
+    fn decode_rejects_garbage() {
+        assert!(Envelope::decode(b"RCPT:D:12345").is_err());
+        assert!(Envelope::decode(b"").is_err());
+    }
+}
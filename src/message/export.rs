@@ -0,0 +1,276 @@
+//! Conversation export formats.
+
+use std::fmt::Write as _;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::types::{Message, MessageContent, MessageStatus, Recipient};
+
+/// Version of the JSON export layout.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Output format for `whisper export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Markdown,
+    Txt,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(ExportFormat::Json),
+            "markdown" | "md" => Ok(ExportFormat::Markdown),
+            "txt" | "text" => Ok(ExportFormat::Txt),
+            _ => anyhow::bail!("Unknown export format '{}' (expected json, markdown or txt)", s),
+        }
+    }
+}
+
+/// Whether we sent or received a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A message as it appears in an export.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedMessage {
+    pub id: Uuid,
+    pub from: String,
+    pub to: String,
+    pub direction: Direction,
+    pub timestamp: DateTime<Utc>,
+    pub status: MessageStatus,
+    pub content: MessageContent,
+}
+
+impl ExportedMessage {
+    /// A stored message as seen by us.
+    pub fn new(msg: &Message, our_peer_id: &PeerId) -> Self {
+        Self {
+            id: msg.id,
+            from: msg.from.to_string(),
+            to: match &msg.to {
+                Recipient::Direct(peer) => peer.to_string(),
+                Recipient::Group(id) => id.to_string(),
+            },
+            direction: if msg.from == *our_peer_id {
+                Direction::Sent
+            } else {
+                Direction::Received
+            },
+            timestamp: msg.timestamp,
+            status: msg.status.clone(),
+            content: msg.content.clone(),
+        }
+    }
+
+    /// Convert back into a stored message.
+    pub fn into_message(self) -> Result<Message> {
+        let from: PeerId = self.from.parse().context("Invalid sender peer ID")?;
+        let to = match self.to.parse::<PeerId>() {
+            Ok(peer) => Recipient::Direct(peer),
+            Err(_) => Recipient::Group(Uuid::parse_str(&self.to).context("Invalid recipient")?),
+        };
+        Ok(Message {
+            id: self.id,
+            from,
+            to,
+            content: self.content,
+            timestamp: self.timestamp,
+            status: self.status,
+        })
+    }
+}
+
+/// A conversation with one contact, as written by `whisper export`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationExport {
+    pub version: u32,
+    pub alias: String,
+    pub peer_id: String,
+    pub exported_at: DateTime<Utc>,
+    pub messages: Vec<ExportedMessage>,
+}
+
+impl ConversationExport {
+    /// Build an export of `messages` exchanged between us and a contact.
+    pub fn new(our_peer_id: &PeerId, alias: &str, peer_id: &PeerId, messages: &[Message]) -> Self {
+        let messages = messages
+            .iter()
+            .map(|msg| ExportedMessage::new(msg, our_peer_id))
+            .collect();
+
+        Self {
+            version: EXPORT_VERSION,
+            alias: alias.to_string(),
+            peer_id: peer_id.to_string(),
+            exported_at: Utc::now(),
+            messages,
+        }
+    }
+
+    /// Parse a JSON export, rejecting layouts newer than we understand.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let export: Self = serde_json::from_str(json).context("Failed to parse export")?;
+        if export.version > EXPORT_VERSION {
+            anyhow::bail!("Unsupported export version {}", export.version);
+        }
+        Ok(export)
+    }
+
+    /// Render the export in the given format.
+    pub fn render(&self, format: ExportFormat) -> Result<String> {
+        match format {
+            ExportFormat::Json => {
+                serde_json::to_string_pretty(self).context("Failed to encode export")
+            }
+            ExportFormat::Markdown => Ok(self.to_markdown()),
+            ExportFormat::Txt => Ok(self.to_txt()),
+        }
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# Conversation with {}", self.alias);
+        let _ = writeln!(out);
+        let _ = writeln!(out, "Peer ID: `{}`  ", self.peer_id);
+        let _ = writeln!(out, "Exported: {}", self.exported_at.format("%Y-%m-%d %H:%M:%S UTC"));
+        let _ = writeln!(out);
+        for msg in &self.messages {
+            let _ = writeln!(
+                out,
+                "- **{}** {} _({})_: {}",
+                msg.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                self.arrow(msg.direction),
+                status_label(&msg.status),
+                msg.content.summary(),
+            );
+        }
+        out
+    }
+
+    fn to_txt(&self) -> String {
+        let mut out = String::new();
+        for msg in &self.messages {
+            let _ = writeln!(
+                out,
+                "[{}] {} ({}): {}",
+                msg.timestamp.format("%Y-%m-%d %H:%M:%S UTC"),
+                self.arrow(msg.direction),
+                status_label(&msg.status),
+                msg.content.summary(),
+            );
+        }
+        out
+    }
+
+    fn arrow(&self, direction: Direction) -> String {
+        match direction {
+            Direction::Sent => format!("You -> {}", self.alias),
+            Direction::Received => format!("{} -> You", self.alias),
+        }
+    }
+}
+
+/// Lowercase status name, with the reason for failures.
+fn status_label(status: &MessageStatus) -> String {
+    match status {
+        MessageStatus::Pending => "pending".to_string(),
+        MessageStatus::Sent => "sent".to_string(),
+        MessageStatus::Delivered => "delivered".to_string(),
+        MessageStatus::Read => "read".to_string(),
+        MessageStatus::Failed(reason) => format!("failed: {}", reason),
+        MessageStatus::Expired => "expired".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> (PeerId, PeerId, ConversationExport) {
+        let us = PeerId::random();
+        let alice = PeerId::random();
+        let mut reply = Message::new_text(alice, Recipient::Direct(us), "hi back".to_string());
+        reply.status = MessageStatus::Read;
+        let messages = vec![
+            Message::new_text(us, Recipient::Direct(alice), "hello".to_string()),
+            reply,
+        ];
+        let export = ConversationExport::new(&us, "alice", &alice, &messages);
+        (us, alice, export)
+    }
+
+    #[test]
+    fn export_records_direction() {
+        let (_, _, export) = sample();
+        assert_eq!(export.messages[0].direction, Direction::Sent);
+        assert_eq!(export.messages[1].direction, Direction::Received);
+    }
+
+    #[test]
+    fn json_export_roundtrips() {
+        let (_, alice, export) = sample();
+        let json = export.render(ExportFormat::Json).unwrap();
+        let parsed: ConversationExport = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.version, EXPORT_VERSION);
+        assert_eq!(parsed.peer_id, alice.to_string());
+        assert_eq!(parsed.messages.len(), 2);
+        assert_eq!(parsed.messages[1].id, export.messages[1].id);
+        assert!(matches!(parsed.messages[1].status, MessageStatus::Read));
+    }
+
+    #[test]
+    fn exported_messages_convert_back() {
+        let (us, alice, export) = sample();
+        let json = export.render(ExportFormat::Json).unwrap();
+        let parsed = ConversationExport::from_json(&json).unwrap();
+
+        let msg = parsed.messages[0].clone().into_message().unwrap();
+        assert_eq!(msg.id, export.messages[0].id);
+        assert_eq!(msg.from, us);
+        assert!(matches!(msg.to, Recipient::Direct(p) if p == alice));
+        assert!(matches!(msg.content, MessageContent::Text(ref t) if t == "hello"));
+    }
+
+    #[test]
+    fn newer_export_versions_are_rejected() {
+        let (_, _, mut export) = sample();
+        export.version = EXPORT_VERSION + 1;
+        let json = export.render(ExportFormat::Json).unwrap();
+        assert!(ConversationExport::from_json(&json).is_err());
+        assert!(ConversationExport::from_json("not json").is_err());
+    }
+
+    #[test]
+    fn text_formats_include_status_and_direction() {
+        let (_, _, export) = sample();
+        let txt = export.render(ExportFormat::Txt).unwrap();
+        assert!(txt.contains("You -> alice (pending): hello"));
+        assert!(txt.contains("alice -> You (read): hi back"));
+
+        let md = export.render(ExportFormat::Markdown).unwrap();
+        assert!(md.starts_with("# Conversation with alice"));
+        assert!(md.contains("_(read)_: hi back"));
+    }
+
+    #[test]
+    fn format_parses_names() {
+        assert_eq!("json".parse::<ExportFormat>().unwrap(), ExportFormat::Json);
+        assert_eq!("Markdown".parse::<ExportFormat>().unwrap(), ExportFormat::Markdown);
+        assert_eq!("txt".parse::<ExportFormat>().unwrap(), ExportFormat::Txt);
+        assert!("pdf".parse::<ExportFormat>().is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Group metadata sync between members.
+//!
+//! When two members of a group connect, an owner or admin sends the other
+//! its signed view of the group: name, description, members and key epoch.
+//! Receivers only take metadata from someone who manages the group in their
+//! own view, and only if it's newer than what they last applied, so
+//! concurrent edits by two admins resolve last-writer-wins.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::types::{Group, MemberRole};
+
+/// Domain separation for group metadata signatures.
+const GROUP_SYNC_SIGNATURE_CONTEXT: &[u8] = b"whisper-group-sync-v1";
+
+/// A member as listed in synced metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncedMember {
+    #[serde(with = "super::envelope::peer_id_bytes")]
+    pub peer_id: PeerId,
+    pub role: MemberRole,
+    pub is_owner: bool,
+}
+
+/// One member's view of a group.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMetadata {
+    pub group_id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    /// Everyone in the group, the owner included.
+    pub members: Vec<SyncedMember>,
+    pub key_epoch: u32,
+    /// When the sender's view last changed; newer metadata wins.
+    pub updated_at: DateTime<Utc>,
+    /// The current group key sealed for the recipient, so a member who
+    /// missed a rotation catches up.
+    pub encrypted_key: Option<Vec<u8>>,
+}
+
+impl GroupMetadata {
+    /// Describe `group` as it stands locally.
+    pub fn new(group: &Group, key_epoch: u32, updated_at: DateTime<Utc>, encrypted_key: Option<Vec<u8>>) -> Self {
+        let mut members: Vec<SyncedMember> = group
+            .members
+            .iter()
+            .map(|m| SyncedMember {
+                peer_id: m.peer_id,
+                role: m.role,
+                is_owner: group.is_owner(&m.peer_id),
+            })
+            .collect();
+        // The creator's own copy doesn't list them as a member
+        if let Some(owner) = group.owner {
+            if !group.is_member(&owner) {
+                members.push(SyncedMember {
+                    peer_id: owner,
+                    role: MemberRole::Admin,
+                    is_owner: true,
+                });
+            }
+        }
+
+        Self {
+            group_id: group.id,
+            name: group.name.clone(),
+            description: group.description.clone(),
+            members,
+            key_epoch,
+            updated_at,
+            encrypted_key,
+        }
+    }
+
+    /// The owner, if the metadata names one.
+    pub fn owner(&self) -> Option<PeerId> {
+        self.members.iter().find(|m| m.is_owner).map(|m| m.peer_id)
+    }
+
+    /// Whether `peer_id` is listed.
+    pub fn has_member(&self, peer_id: &PeerId) -> bool {
+        self.members.iter().any(|m| &m.peer_id == peer_id)
+    }
+
+    fn signing_bytes(&self, sender: &PeerId) -> Result<Vec<u8>> {
+        let mut buf = GROUP_SYNC_SIGNATURE_CONTEXT.to_vec();
+        buf.extend_from_slice(&sender.to_bytes());
+        ciborium::into_writer(self, &mut buf).context("Failed to encode group metadata")?;
+        Ok(buf)
+    }
+}
+
+/// Group metadata signed by the member who sent it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupSync {
+    pub metadata: GroupMetadata,
+    pub signature: Vec<u8>,
+}
+
+impl GroupSync {
+    /// Sign `metadata` with our identity key.
+    pub fn sign(keypair: &libp2p::identity::Keypair, metadata: GroupMetadata) -> Result<Self> {
+        let bytes = metadata.signing_bytes(&PeerId::from(keypair.public()))?;
+        let signature = keypair.sign(&bytes).context("Failed to sign group metadata")?;
+        Ok(Self { metadata, signature })
+    }
+
+    /// Check the metadata was signed by `sender`, whose raw Ed25519 key is
+    /// `public_key`.
+    pub fn verify(&self, sender: &PeerId, public_key: &[u8]) -> bool {
+        let Ok(key) = libp2p::identity::ed25519::PublicKey::try_from_bytes(public_key) else {
+            return false;
+        };
+        let key = libp2p::identity::PublicKey::from(key);
+        if key.to_peer_id() != *sender {
+            return false;
+        }
+        match self.metadata.signing_bytes(sender) {
+            Ok(bytes) => key.verify(&bytes, &self.signature),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    #[test]
+    fn metadata_lists_owner_and_members() {
+        let owner = PeerId::random();
+        let member = PeerId::random();
+        let mut group = Group::new("team".to_string(), vec![0; 32], Some(owner));
+        group.add_member(member);
+
+        let metadata = GroupMetadata::new(&group, 2, Utc::now(), None);
+        assert_eq!(metadata.owner(), Some(owner));
+        assert!(metadata.has_member(&owner));
+        assert!(metadata.has_member(&member));
+        assert_eq!(metadata.key_epoch, 2);
+    }
+
+    #[test]
+    fn signed_sync_verifies() {
+        let keypair = Keypair::generate_ed25519();
+        let sender = PeerId::from(keypair.public());
+        let public_key = keypair.public().try_into_ed25519().unwrap().to_bytes();
+        let group = Group::new("team".to_string(), vec![0; 32], Some(sender));
+
+        let sync = GroupSync::sign(&keypair, GroupMetadata::new(&group, 0, Utc::now(), None)).unwrap();
+        assert!(sync.verify(&sender, &public_key));
+
+        // Tampering or a different signer fails
+        let mut renamed = sync.clone();
+        renamed.metadata.name = "other".to_string();
+        assert!(!renamed.verify(&sender, &public_key));
+        let other = Keypair::generate_ed25519();
+        let other_key = other.public().try_into_ed25519().unwrap().to_bytes();
+        assert!(!sync.verify(&PeerId::from(other.public()), &other_key));
+        assert!(!sync.verify(&sender, &other_key));
+    }
+
+    #[test]
+    fn signature_survives_the_wire() {
+        use crate::message::{Envelope, MessageContent};
+
+        let keypair = Keypair::generate_ed25519();
+        let sender = PeerId::from(keypair.public());
+        let public_key = keypair.public().try_into_ed25519().unwrap().to_bytes();
+        let mut group = Group::new("team".to_string(), vec![0; 32], Some(sender));
+        group.description = Some("weekly".to_string());
+        group.add_member(PeerId::random());
+        let metadata = GroupMetadata::new(&group, 1, Utc::now(), Some(vec![9; 48]));
+        let envelope = Envelope::new(sender, MessageContent::GroupSync(GroupSync::sign(&keypair, metadata).unwrap()));
+
+        let decoded = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+        match decoded.payload {
+            MessageContent::GroupSync(sync) => assert!(sync.verify(&sender, &public_key)),
+            other => panic!("Expected group sync, got {:?}", other),
+        }
+    }
+}
@@ -1,12 +1,31 @@
 //! Message handling - types, queue, and sync.
 
+mod channel;
+mod devices;
+mod envelope;
+mod export;
+mod group_sync;
+#[cfg(feature = "native")]
 mod queue;
+mod stamp;
 mod sync;
 mod types;
 
+pub use channel::{Channel, ChannelPost};
+pub use devices::{DeviceLink, DeviceList, LinkedDevice};
+pub use envelope::{
+    next_seq, seq_now, Authenticity, Envelope, ENVELOPE_VERSION, MAX_SEQ_SKEW_MICROS,
+    REPLAY_WINDOW_MICROS,
+};
+pub use export::{ConversationExport, Direction, ExportFormat, ExportedMessage, EXPORT_VERSION};
+pub use group_sync::{GroupMetadata, GroupSync, SyncedMember};
+#[cfg(feature = "native")]
 pub use queue::MessageQueue;
+pub use stamp::{check_stamp, mint_stamp, DEFAULT_STAMP_DIFFICULTY, MAX_STAMP_DIFFICULTY};
 pub use sync::{diff_messages, filter_history, merge_messages, needs_sync, HistoryRequest};
 pub use types::{
-    FileChunk, FileTransfer, FileTransferComplete, FileTransferStatus,
-    Group, GroupMember, MemberRole, Message, MessageContent, MessageStatus, Recipient, ReceiptType,
+    Conversation, FileChunk, FileOffer, FileTransfer, FileTransferComplete, FileTransferStatus,
+    Group, GroupInvite, GroupKeyUpdate, GroupLeave, GroupMember, GroupMemberUpdate, InviteAcceptance, MailboxDelivery,
+    MailboxDeposit, MemberChange, MemberRole, Message, MessageContent, MessageStatus, PendingContactCard,
+    PendingGroupInvite, Recipient, ReceiptType, sort_conversations,
 };
@@ -1,302 +1,182 @@
 //! Offline message queue.
-
+//!
+//! The outbox lives in the database, so whatever is queued survives a
+//! restart and is seen by every process using the same data directory.
+//! `MessageQueue` is the one way in: sends that can't go out yet are
+//! queued through it, and sessions flush it when a peer connects.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
 use libp2p::PeerId;
-use std::collections::{HashMap, VecDeque};
 use uuid::Uuid;
 
-use super::types::{Message, MessageStatus};
-use crate::storage::Database;
+use crate::storage::{Database, DeadLetter, QueueEntry};
 
-/// Message queue for pending messages.
-/// 
-/// Maintains per-peer queues and persists to database for offline handling.
-pub struct MessageQueue {
-    /// Pending messages by peer.
-    pending: HashMap<PeerId, VecDeque<Message>>,
-    /// Database for persistence (used in future for loading on startup).
-    #[allow(dead_code)]
-    db: Option<Database>,
+/// Sealed messages waiting for their recipients.
+pub struct MessageQueue<'a> {
+    db: &'a Database,
 }
 
-impl MessageQueue {
-    /// Create a new message queue.
-    pub fn new() -> Self {
-        Self {
-            pending: HashMap::new(),
-            db: None,
-        }
-    }
-
-    /// Create a queue with database persistence.
-    pub fn with_database(db: Database) -> Self {
-        Self {
-            pending: HashMap::new(),
-            db: Some(db),
-        }
+impl<'a> MessageQueue<'a> {
+    /// The queue persisted in `db`.
+    pub fn new(db: &'a Database) -> Self {
+        Self { db }
     }
 
-    /// Add a message to the queue.
-    pub fn enqueue(&mut self, message: Message) {
-        let peer_id = match &message.to {
-            super::types::Recipient::Direct(peer) => *peer,
-            super::types::Recipient::Group(_) => {
-                // For groups, we'd need to enqueue for each member
-                // For now, store under sender
-                message.from
-            }
-        };
-
-        self.pending
-            .entry(peer_id)
-            .or_default()
-            .push_back(message);
+    /// Queue sealed `data` for `to`. Queueing the same ID again replaces
+    /// the earlier copy and starts its attempts over.
+    pub fn enqueue(&self, id: &Uuid, to: &PeerId, data: &[u8]) -> Result<()> {
+        self.db.queue_pending_message(id, to, data)
     }
 
-    /// Remove and return the oldest message for a peer.
-    pub fn dequeue(&mut self, peer_id: &PeerId) -> Option<Message> {
-        self.pending
-            .get_mut(peer_id)
-            .and_then(|queue| queue.pop_front())
+    /// What's waiting for a peer, oldest first. Dead letters aren't included.
+    pub fn pending_for(&self, peer_id: &PeerId) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        self.db.get_pending_for_peer(peer_id)
     }
 
-    /// View all pending messages for a peer without removing.
-    pub fn peek_all(&self, peer_id: &PeerId) -> Vec<&Message> {
-        self.pending
-            .get(peer_id)
-            .map(|q| q.iter().collect())
-            .unwrap_or_default()
-    }
-
-    /// Get count of pending messages for a peer.
-    pub fn pending_count(&self, peer_id: &PeerId) -> usize {
-        self.pending.get(peer_id).map(|q| q.len()).unwrap_or(0)
-    }
-
-    /// Get total pending message count.
-    pub fn total_pending(&self) -> usize {
-        self.pending.values().map(|q| q.len()).sum()
-    }
-
-    /// Mark a message as sent and remove from pending.
-    pub fn mark_sent(&mut self, message_id: Uuid) -> bool {
-        for queue in self.pending.values_mut() {
-            if let Some(pos) = queue.iter().position(|m| m.id == message_id) {
-                queue.remove(pos);
-                return true;
-            }
+    /// Hand everything waiting for a connected peer to `send`, oldest
+    /// first, and take it off the queue. Returns how many were sent.
+    pub fn flush(&self, peer_id: &PeerId, mut send: impl FnMut(Vec<u8>)) -> Result<usize> {
+        let pending = self.pending_for(peer_id)?;
+        let count = pending.len();
+        for (id, data) in pending {
+            send(data);
+            self.db.remove_pending_message(&id)?;
         }
-        false
+        Ok(count)
     }
 
-    /// Mark a message as failed with reason.
-    pub fn mark_failed(&mut self, message_id: Uuid, reason: String) -> bool {
-        for queue in self.pending.values_mut() {
-            if let Some(msg) = queue.iter_mut().find(|m| m.id == message_id) {
-                msg.status = MessageStatus::Failed(reason);
-                return true;
-            }
-        }
-        false
-    }
-
-    /// Get all peers with pending messages.
-    pub fn peers_with_pending(&self) -> Vec<PeerId> {
-        self.pending
-            .iter()
-            .filter(|(_, q)| !q.is_empty())
-            .map(|(peer, _)| *peer)
-            .collect()
+    /// Take a message off the queue, once delivered or when dropped.
+    /// Returns false if it wasn't queued.
+    pub fn remove(&self, id: &Uuid) -> Result<bool> {
+        self.db.remove_pending_message(id)
     }
 
-    /// Retry all failed messages (move back to pending status).
-    pub fn retry_failed(&mut self) -> usize {
-        let mut count = 0;
-        for queue in self.pending.values_mut() {
-            for msg in queue.iter_mut() {
-                if matches!(msg.status, MessageStatus::Failed(_)) {
-                    msg.status = MessageStatus::Pending;
-                    count += 1;
-                }
+    /// How many messages are waiting for each peer, in the order their
+    /// oldest was queued.
+    pub fn counts_by_peer(&self) -> Result<Vec<(PeerId, usize)>> {
+        let mut counts: Vec<(PeerId, usize)> = Vec::new();
+        for (_, peer_id, _) in self.db.get_all_pending()? {
+            match counts.iter_mut().find(|(p, _)| *p == peer_id) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((peer_id, 1)),
             }
         }
-        count
+        Ok(counts)
     }
 
-    /// Clear all pending messages for a peer.
-    pub fn clear_peer(&mut self, peer_id: &PeerId) {
-        self.pending.remove(peer_id);
+    /// Peers with messages waiting, to look for on startup.
+    pub fn peers_with_pending(&self) -> Result<Vec<PeerId>> {
+        Ok(self.counts_by_peer()?.into_iter().map(|(peer_id, _)| peer_id).collect())
     }
 
-    /// Clear all pending messages.
-    pub fn clear_all(&mut self) {
-        self.pending.clear();
+    /// Total messages waiting.
+    pub fn total_pending(&self) -> Result<usize> {
+        Ok(self.db.get_all_pending()?.len())
     }
-}
 
-impl Default for MessageQueue {
-    fn default() -> Self {
-        Self::new()
+    /// Everything queued, dead letters included, oldest first.
+    pub fn entries(&self) -> Result<Vec<QueueEntry>> {
+        self.db.list_queue()
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::message::types::Recipient;
-    use libp2p::identity::Keypair;
 
-    fn make_peer_id() -> PeerId {
-        PeerId::from(Keypair::generate_ed25519().public())
+    /// Messages given up on.
+    pub fn dead_letters(&self) -> Result<Vec<DeadLetter>> {
+        self.db.dead_letters()
     }
 
-    fn make_message(from: PeerId, to: PeerId, text: &str) -> Message {
-        Message::new_text(from, Recipient::Direct(to), text.to_string())
+    /// Messages whose next attempt is due by `now`, with their recipients.
+    pub fn due(&self, now: DateTime<Utc>) -> Result<Vec<(Uuid, PeerId)>> {
+        self.db.pending_due(now)
     }
 
-    #[test]
-    fn enqueue_adds_to_queue() {
-        let mut queue = MessageQueue::new();
-        let from = make_peer_id();
-        let to = make_peer_id();
-        let msg = make_message(from, to, "hello");
-
-        queue.enqueue(msg);
-
-        assert_eq!(queue.pending_count(&to), 1);
+    /// Count a failed attempt at a message. Returns the attempts so far,
+    /// or 0 if it's no longer queued.
+    pub fn record_attempt(&self, id: &Uuid) -> Result<u32> {
+        self.db.increment_pending_attempts(id)
     }
 
-    #[test]
-    fn dequeue_returns_oldest_first() {
-        let mut queue = MessageQueue::new();
-        let from = make_peer_id();
-        let to = make_peer_id();
-
-        let msg1 = make_message(from, to, "first");
-        let msg2 = make_message(from, to, "second");
-        let id1 = msg1.id;
-
-        queue.enqueue(msg1);
-        queue.enqueue(msg2);
-
-        let dequeued = queue.dequeue(&to).unwrap();
-        assert_eq!(dequeued.id, id1);
+    /// Hold a message back until `at`.
+    pub fn schedule(&self, id: &Uuid, at: DateTime<Utc>) -> Result<()> {
+        self.db.schedule_pending_retry(id, at)
     }
 
-    #[test]
-    fn peer_specific_queues() {
-        let mut queue = MessageQueue::new();
-        let from = make_peer_id();
-        let to1 = make_peer_id();
-        let to2 = make_peer_id();
-
-        queue.enqueue(make_message(from, to1, "for peer 1"));
-        queue.enqueue(make_message(from, to2, "for peer 2"));
-
-        assert_eq!(queue.pending_count(&to1), 1);
-        assert_eq!(queue.pending_count(&to2), 1);
-        assert_eq!(queue.total_pending(), 2);
+    /// Give up on a message, keeping it as a dead letter.
+    pub fn give_up(&self, id: &Uuid, at: DateTime<Utc>) -> Result<()> {
+        self.db.dead_letter_pending(id, at)
     }
 
-    #[test]
-    fn peek_all_returns_without_removing() {
-        let mut queue = MessageQueue::new();
-        let from = make_peer_id();
-        let to = make_peer_id();
-
-        queue.enqueue(make_message(from, to, "msg1"));
-        queue.enqueue(make_message(from, to, "msg2"));
-
-        let peeked = queue.peek_all(&to);
-        assert_eq!(peeked.len(), 2);
-        assert_eq!(queue.pending_count(&to), 2); // Still there
+    /// Start a message's attempts over, bringing it back if it was given
+    /// up on. Returns false if it isn't queued.
+    pub fn retry(&self, id: &Uuid) -> Result<bool> {
+        self.db.requeue_pending(id)
     }
 
-    #[test]
-    fn mark_sent_removes_from_pending() {
-        let mut queue = MessageQueue::new();
-        let from = make_peer_id();
-        let to = make_peer_id();
-        let msg = make_message(from, to, "hello");
-        let msg_id = msg.id;
-
-        queue.enqueue(msg);
-        assert_eq!(queue.pending_count(&to), 1);
-
-        let removed = queue.mark_sent(msg_id);
-        assert!(removed);
-        assert_eq!(queue.pending_count(&to), 0);
+    /// Drop everything queued before `before`, dead letters included.
+    /// Returns their IDs.
+    pub fn expire(&self, before: DateTime<Utc>) -> Result<Vec<Uuid>> {
+        self.db.expire_pending(before)
     }
+}
 
-    #[test]
-    fn mark_failed_updates_status() {
-        let mut queue = MessageQueue::new();
-        let from = make_peer_id();
-        let to = make_peer_id();
-        let msg = make_message(from, to, "hello");
-        let msg_id = msg.id;
-
-        queue.enqueue(msg);
-        queue.mark_failed(msg_id, "network error".to_string());
-
-        let messages = queue.peek_all(&to);
-        assert!(matches!(messages[0].status, MessageStatus::Failed(_)));
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
-    fn retry_failed_resets_status() {
-        let mut queue = MessageQueue::new();
-        let from = make_peer_id();
-        let to = make_peer_id();
-        let msg = make_message(from, to, "hello");
-        let msg_id = msg.id;
-
-        queue.enqueue(msg);
-        queue.mark_failed(msg_id, "error".to_string());
-
-        let count = queue.retry_failed();
-        assert_eq!(count, 1);
+    fn queue_is_kept_per_peer_in_order() {
+        let db = Database::open_in_memory().unwrap();
+        let queue = MessageQueue::new(&db);
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let first = Uuid::new_v4();
 
-        let messages = queue.peek_all(&to);
-        assert!(matches!(messages[0].status, MessageStatus::Pending));
-    }
-
-    #[test]
-    fn peers_with_pending_lists_active_peers() {
-        let mut queue = MessageQueue::new();
-        let from = make_peer_id();
-        let to1 = make_peer_id();
-        let to2 = make_peer_id();
+        queue.enqueue(&first, &alice, b"first").unwrap();
+        queue.enqueue(&Uuid::new_v4(), &bob, b"for bob").unwrap();
+        queue.enqueue(&Uuid::new_v4(), &alice, b"second").unwrap();
 
-        queue.enqueue(make_message(from, to1, "msg"));
-        queue.enqueue(make_message(from, to2, "msg"));
+        assert_eq!(queue.total_pending().unwrap(), 3);
+        assert_eq!(queue.counts_by_peer().unwrap(), [(alice, 2), (bob, 1)]);
+        assert_eq!(queue.peers_with_pending().unwrap(), [alice, bob]);
+        assert_eq!(queue.pending_for(&alice).unwrap()[0], (first, b"first".to_vec()));
 
-        let peers = queue.peers_with_pending();
-        assert_eq!(peers.len(), 2);
-        assert!(peers.contains(&to1));
-        assert!(peers.contains(&to2));
+        // Another queue over the same database sees the same messages
+        assert_eq!(MessageQueue::new(&db).pending_for(&alice).unwrap().len(), 2);
     }
 
     #[test]
-    fn clear_peer_removes_only_that_peer() {
-        let mut queue = MessageQueue::new();
-        let from = make_peer_id();
-        let to1 = make_peer_id();
-        let to2 = make_peer_id();
-
-        queue.enqueue(make_message(from, to1, "msg"));
-        queue.enqueue(make_message(from, to2, "msg"));
-
-        queue.clear_peer(&to1);
-
-        assert_eq!(queue.pending_count(&to1), 0);
-        assert_eq!(queue.pending_count(&to2), 1);
+    fn flush_sends_and_removes_only_that_peer() {
+        let db = Database::open_in_memory().unwrap();
+        let queue = MessageQueue::new(&db);
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        queue.enqueue(&Uuid::new_v4(), &alice, b"one").unwrap();
+        queue.enqueue(&Uuid::new_v4(), &alice, b"two").unwrap();
+        queue.enqueue(&Uuid::new_v4(), &bob, b"other").unwrap();
+
+        let mut sent = Vec::new();
+        assert_eq!(queue.flush(&alice, |data| sent.push(data)).unwrap(), 2);
+        assert_eq!(sent, [b"one".to_vec(), b"two".to_vec()]);
+        assert!(queue.pending_for(&alice).unwrap().is_empty());
+        assert_eq!(queue.pending_for(&bob).unwrap().len(), 1);
+        assert_eq!(queue.flush(&alice, |_| panic!("nothing left")).unwrap(), 0);
     }
 
     #[test]
-    fn dequeue_empty_returns_none() {
-        let mut queue = MessageQueue::new();
-        let peer = make_peer_id();
-
-        assert!(queue.dequeue(&peer).is_none());
+    fn dead_letters_leave_the_queue_until_retried() {
+        let db = Database::open_in_memory().unwrap();
+        let queue = MessageQueue::new(&db);
+        let alice = PeerId::random();
+        let id = Uuid::new_v4();
+        queue.enqueue(&id, &alice, b"hello").unwrap();
+
+        assert_eq!(queue.record_attempt(&id).unwrap(), 1);
+        queue.give_up(&id, Utc::now()).unwrap();
+        assert!(queue.pending_for(&alice).unwrap().is_empty());
+        assert_eq!(queue.dead_letters().unwrap().len(), 1);
+        assert_eq!(queue.entries().unwrap().len(), 1);
+
+        assert!(queue.retry(&id).unwrap());
+        assert_eq!(queue.pending_for(&alice).unwrap().len(), 1);
+        assert!(queue.remove(&id).unwrap());
+        assert!(!queue.retry(&id).unwrap());
     }
 }
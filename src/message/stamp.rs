@@ -0,0 +1,77 @@
+//! Proof-of-work stamps on first contact.
+//!
+//! Anyone can send us a message request, so a stranger's first message
+//! must carry a stamp: a nonce that, hashed with our peer ID and the
+//! message, gives a hash starting with enough zero bits. Finding one takes
+//! a moment for one message and adds up for a spammer sending thousands,
+//! while checking it takes a single hash. A stamp is bound to one
+//! recipient and one message, so it can't be reused.
+
+use libp2p::PeerId;
+
+/// Zero bits a stamp needs unless the recipient says otherwise. About
+/// 65,000 hashes to find.
+pub const DEFAULT_STAMP_DIFFICULTY: u8 = 16;
+
+/// Most zero bits a recipient may ask for. About 16 million hashes, a few
+/// seconds' work.
+pub const MAX_STAMP_DIFFICULTY: u8 = 24;
+
+/// Key derivation context for stamp hashes.
+const STAMP_CONTEXT: &str = "whisper 2026 stamp";
+
+/// Find a stamp for a message to `recipient`, `message` being the hash of
+/// what the stamp vouches for.
+pub fn mint_stamp(recipient: &PeerId, message: &[u8; 32], difficulty: u8) -> u64 {
+    let base = stamp_hasher(recipient, message);
+    (0..=u64::MAX)
+        .find(|nonce| zero_bits(&base, *nonce) >= u32::from(difficulty))
+        .expect("a stamp is found long before the nonces run out")
+}
+
+/// Whether `nonce` is a stamp of at least `difficulty` for a message to
+/// `recipient`.
+pub fn check_stamp(recipient: &PeerId, message: &[u8; 32], nonce: u64, difficulty: u8) -> bool {
+    zero_bits(&stamp_hasher(recipient, message), nonce) >= u32::from(difficulty)
+}
+
+fn stamp_hasher(recipient: &PeerId, message: &[u8; 32]) -> blake3::Hasher {
+    let mut hasher = blake3::Hasher::new_derive_key(STAMP_CONTEXT);
+    hasher.update(&recipient.to_bytes());
+    hasher.update(message);
+    hasher
+}
+
+/// Leading zero bits of the hash of `nonce` added to `base`.
+fn zero_bits(base: &blake3::Hasher, nonce: u64) -> u32 {
+    let hash = base.clone().update(&nonce.to_le_bytes()).finalize();
+    let mut bits = 0;
+    for byte in hash.as_bytes() {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamps_are_bound_to_recipient_and_message() {
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let message = *blake3::hash(b"hello").as_bytes();
+        let nonce = mint_stamp(&alice, &message, 12);
+        assert!(check_stamp(&alice, &message, nonce, 12));
+        assert!(check_stamp(&alice, &message, nonce, 0));
+
+        // Not for anyone else, or any other message
+        let other = *blake3::hash(b"buy now").as_bytes();
+        let reused = [check_stamp(&bob, &message, nonce, 12), check_stamp(&alice, &other, nonce, 12)];
+        // A reused stamp passes only by a 1 in 4096 chance each
+        assert!(!reused.iter().all(|ok| *ok));
+        assert!(!check_stamp(&alice, &message, nonce, MAX_STAMP_DIFFICULTY + 32));
+    }
+}
@@ -1,13 +1,14 @@
 //! Message synchronization between peers.
 
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use uuid::Uuid;
 
 use super::types::{Message, MessageStatus};
 
 /// Request for message history.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct HistoryRequest {
     /// Request messages since this timestamp.
     pub since: DateTime<Utc>,
@@ -38,7 +39,7 @@ pub fn filter_history(messages: &[Message], since: DateTime<Utc>, limit: Option<
         .collect();
     
     // Sort by timestamp
-    filtered.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    filtered.sort_by_key(|m| m.timestamp);
     
     if let Some(limit) = limit {
         filtered.truncate(limit);
@@ -75,7 +76,7 @@ pub fn merge_messages(local: Vec<Message>, remote: Vec<Message>) -> Vec<Message>
     
     // Sort by timestamp
     let mut result: Vec<_> = by_id.into_values().collect();
-    result.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    result.sort_by_key(|m| m.timestamp);
     result
 }
 
@@ -86,7 +87,7 @@ fn status_priority(status: &MessageStatus) -> u8 {
         MessageStatus::Sent => 1,
         MessageStatus::Delivered => 2,
         MessageStatus::Read => 3,
-        MessageStatus::Failed(_) => 4, // Failed is also final
+        MessageStatus::Failed(_) | MessageStatus::Expired => 4, // Failed is also final
     }
 }
 
@@ -4,20 +4,23 @@ use chrono::{DateTime, Utc};
 use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use super::channel::ChannelPost;
+use super::devices::DeviceList;
+use super::export::ExportedMessage;
+use super::group_sync::GroupSync;
+use super::sync::HistoryRequest;
+use crate::identity::{InviteId, PresenceStatus, PublicPrekey};
 
 /// Role of a group member.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MemberRole {
+    #[default]
     Member,
     Admin,
 }
 
-impl Default for MemberRole {
-    fn default() -> Self {
-        Self::Member
-    }
-}
-
 impl std::fmt::Display for MemberRole {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -54,20 +57,20 @@ pub struct Group {
     pub description: Option<String>,
     pub owner: Option<PeerId>,
     pub members: Vec<GroupMember>,
-    pub symmetric_key: Vec<u8>,
+    pub symmetric_key: Zeroizing<Vec<u8>>,
     pub created_at: DateTime<Utc>,
 }
 
 impl Group {
     /// Create a new group with an owner.
-    pub fn new(name: String, symmetric_key: Vec<u8>, owner: Option<PeerId>) -> Self {
+    pub fn new(name: String, symmetric_key: impl Into<Zeroizing<Vec<u8>>>, owner: Option<PeerId>) -> Self {
         Self {
             id: Uuid::new_v4(),
             name,
             description: None,
             owner,
             members: Vec::new(),
-            symmetric_key,
+            symmetric_key: symmetric_key.into(),
             created_at: Utc::now(),
         }
     }
@@ -156,7 +159,7 @@ impl Group {
 }
 
 /// Message recipient.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Recipient {
     Direct(PeerId),
     Group(Uuid),
@@ -169,6 +172,278 @@ pub enum MessageContent {
     Receipt(Uuid, ReceiptType),
     FileChunk(FileChunk),
     FileComplete(FileTransferComplete),
+    GroupInvite(GroupInvite),
+    GroupKeyUpdate(GroupKeyUpdate),
+    GroupMemberUpdate(GroupMemberUpdate),
+    GroupLeave(GroupLeave),
+    GroupSync(GroupSync),
+    File(FileOffer),
+    /// Ephemeral "typing" indicator; never stored.
+    Typing,
+    /// Ask recipients to replace one of the sender's messages with a
+    /// tombstone.
+    DeleteRequest(Uuid),
+    /// What's left of a message its sender deleted.
+    Tombstone,
+    /// The sender's status, announced to trusted contacts; never stored
+    /// as a message.
+    Presence(PresenceStatus),
+    /// Mail for an offline peer, left with us as their mailbox.
+    MailboxDeposit(MailboxDeposit),
+    /// Mail a mailbox held for us while we were offline.
+    MailboxDelivery(MailboxDelivery),
+    /// The devices linked to the sender's identity.
+    DeviceList(DeviceList),
+    /// The IDs of every message the sender has, sent to another of its
+    /// identity's devices so it sends back what's missing.
+    HistoryOffer(Vec<Uuid>),
+    /// Ask a contact for our conversation's messages since the last sync.
+    HistoryRequest(HistoryRequest),
+    /// Messages the recipient was missing: from another of its identity's
+    /// devices, or a contact's answer to a `HistoryRequest`.
+    History(Vec<ExportedMessage>),
+    /// Subscribe to one of the recipient's channels.
+    ChannelSubscribe(Uuid),
+    /// A post to a channel the recipient subscribes to.
+    ChannelPost(ChannelPost),
+    /// A place, e.g. where to meet.
+    Location {
+        lat: f64,
+        lon: f64,
+        label: Option<String>,
+    },
+    /// One of the sender's contacts, introduced to the recipient.
+    ContactCard {
+        #[serde(with = "super::envelope::peer_id_bytes")]
+        peer_id: PeerId,
+        /// Ed25519 identity key, matching `peer_id`.
+        public_key: Vec<u8>,
+        /// What the sender calls them.
+        alias: String,
+    },
+    /// The sender accepted one of our invites.
+    InviteAccepted(InviteAcceptance),
+}
+
+impl MessageContent {
+    /// Whether the content can start a conversation, and so from a
+    /// stranger lands in message requests.
+    pub fn opens_request(&self) -> bool {
+        matches!(
+            self,
+            MessageContent::Text(_)
+                | MessageContent::File(_)
+                | MessageContent::Location { .. }
+                | MessageContent::ContactCard { .. }
+                | MessageContent::GroupInvite(_)
+        )
+    }
+
+    /// One-line description, e.g. for exports and previews.
+    pub fn summary(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::File(offer) => format!("[file] {} ({} bytes)", offer.filename, offer.total_size),
+            MessageContent::GroupInvite(invite) => format!("[invite] group '{}'", invite.name),
+            MessageContent::GroupKeyUpdate(_) => "[group key update]".to_string(),
+            MessageContent::GroupMemberUpdate(_) => "[group member update]".to_string(),
+            MessageContent::GroupLeave(_) => "[left group]".to_string(),
+            MessageContent::GroupSync(_) => "[group sync]".to_string(),
+            MessageContent::FileComplete(complete) => format!("[file complete] {}", complete.filename),
+            MessageContent::FileChunk(_) => "[file chunk]".to_string(),
+            MessageContent::Receipt(..) => "[receipt]".to_string(),
+            MessageContent::Typing => "[typing]".to_string(),
+            MessageContent::DeleteRequest(_) => "[delete request]".to_string(),
+            MessageContent::Tombstone => "[message deleted]".to_string(),
+            MessageContent::Presence(status) => format!("[{}]", status),
+            MessageContent::MailboxDeposit(_) => "[mailbox deposit]".to_string(),
+            MessageContent::MailboxDelivery(_) => "[mailbox delivery]".to_string(),
+            MessageContent::DeviceList(_) => "[device list]".to_string(),
+            MessageContent::HistoryOffer(_) => "[history offer]".to_string(),
+            MessageContent::HistoryRequest(_) => "[history request]".to_string(),
+            MessageContent::History(messages) => format!("[history] {} message(s)", messages.len()),
+            MessageContent::ChannelSubscribe(_) => "[channel subscription]".to_string(),
+            MessageContent::ChannelPost(post) => format!("[channel] {}", post.text),
+            MessageContent::Location { lat, lon, label: Some(label) } => {
+                format!("[location] {} geo:{:.6},{:.6}", label, lat, lon)
+            }
+            MessageContent::Location { lat, lon, label: None } => format!("[location] geo:{:.6},{:.6}", lat, lon),
+            MessageContent::ContactCard { peer_id, alias, .. } => format!("[contact] {} ({})", alias, peer_id),
+            MessageContent::InviteAccepted(_) => "[invite accepted]".to_string(),
+        }
+    }
+}
+
+/// Invitation to join a group, carrying the group key sealed for the invitee.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupInvite {
+    pub group_id: Uuid,
+    pub name: String,
+    pub encrypted_key: Vec<u8>,
+}
+
+/// A replacement group key after a membership change, sealed for one
+/// remaining member.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupKeyUpdate {
+    pub group_id: Uuid,
+    /// Key epoch; receivers ignore updates that aren't newer than theirs.
+    pub epoch: u32,
+    pub encrypted_key: Vec<u8>,
+}
+
+/// A change to a group's member list, sent by an owner or admin to the
+/// other members.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMemberUpdate {
+    pub group_id: Uuid,
+    #[serde(with = "super::envelope::peer_id_bytes")]
+    pub peer_id: PeerId,
+    pub change: MemberChange,
+}
+
+/// A message left with a mailbox peer for `to`, who wasn't online to take
+/// it. `data` is the message as it would have been sent to them, sealed
+/// for them, so the mailbox can't read it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailboxDeposit {
+    #[serde(with = "super::envelope::peer_id_bytes")]
+    pub to: PeerId,
+    pub data: Vec<u8>,
+    /// When the mailbox should give up on delivering it.
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A deposited message, forwarded by the mailbox now `from`'s recipient is
+/// online. `data` is what `from` sent, to open as if it came from them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailboxDelivery {
+    #[serde(with = "super::envelope::peer_id_bytes")]
+    pub from: PeerId,
+    pub data: Vec<u8>,
+}
+
+/// What happened to a group member.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemberChange {
+    Added,
+    Removed,
+    Role(MemberRole),
+    /// The member became the group's owner.
+    Owner,
+}
+
+impl GroupMemberUpdate {
+    /// Whether `sender` may make this change: role and ownership changes
+    /// need the owner, adding and removing members needs an owner or admin.
+    /// Nobody can remove the owner.
+    pub fn is_allowed(&self, group: &Group, sender: &PeerId) -> bool {
+        match self.change {
+            MemberChange::Added => group.can_manage(sender),
+            MemberChange::Removed => group.can_manage(sender) && !group.is_owner(&self.peer_id),
+            MemberChange::Role(_) | MemberChange::Owner => group.is_owner(sender),
+        }
+    }
+}
+
+/// Domain separation for group leave signatures.
+const GROUP_LEAVE_SIGNATURE_CONTEXT: &[u8] = b"whisper-group-leave-v1";
+
+/// Notice that the sender has left a group, signed with their identity key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupLeave {
+    pub group_id: Uuid,
+    pub left_at: DateTime<Utc>,
+    pub signature: Vec<u8>,
+}
+
+impl GroupLeave {
+    /// Sign a notice that we're leaving `group_id` now.
+    pub fn new(
+        keypair: &libp2p::identity::Keypair,
+        group_id: Uuid,
+    ) -> Result<Self, libp2p::identity::SigningError> {
+        let left_at = Utc::now();
+        let peer_id = PeerId::from(keypair.public());
+        let signature = keypair.sign(&group_leave_signing_bytes(&group_id, &peer_id, &left_at))?;
+        Ok(Self {
+            group_id,
+            left_at,
+            signature,
+        })
+    }
+
+    /// Check the notice was signed by `sender`, whose raw Ed25519 key is
+    /// `public_key`.
+    pub fn verify(&self, sender: &PeerId, public_key: &[u8]) -> bool {
+        let Ok(key) = libp2p::identity::ed25519::PublicKey::try_from_bytes(public_key) else {
+            return false;
+        };
+        let key = libp2p::identity::PublicKey::from(key);
+        key.to_peer_id() == *sender
+            && key.verify(
+                &group_leave_signing_bytes(&self.group_id, sender, &self.left_at),
+                &self.signature,
+            )
+    }
+}
+
+fn group_leave_signing_bytes(group_id: &Uuid, peer_id: &PeerId, left_at: &DateTime<Utc>) -> Vec<u8> {
+    let mut buf = GROUP_LEAVE_SIGNATURE_CONTEXT.to_vec();
+    buf.extend_from_slice(group_id.as_bytes());
+    buf.extend_from_slice(&peer_id.to_bytes());
+    buf.extend_from_slice(&left_at.timestamp_millis().to_be_bytes());
+    buf
+}
+
+/// A received group invite waiting for the user to accept or decline.
+#[derive(Debug, Clone)]
+pub struct PendingGroupInvite {
+    pub group_id: Uuid,
+    pub name: String,
+    /// Who sent the invite; becomes the group's owner on accept.
+    pub from: PeerId,
+    /// Decrypted group key.
+    pub symmetric_key: Vec<u8>,
+    pub received_at: DateTime<Utc>,
+}
+
+/// The answer to an invite, with what the inviter needs to add the
+/// sender back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InviteAcceptance {
+    /// The invite, as its code named it.
+    pub invite_id: InviteId,
+    /// The sender's signed prekey, so the inviter can start sessions with
+    /// them too.
+    pub prekey: Option<PublicPrekey>,
+    /// What the sender would like to be called, e.g. their username.
+    pub name: Option<String>,
+}
+
+/// A contact card someone sent us, waiting for the user to add the
+/// contact or decline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingContactCard {
+    pub peer_id: PeerId,
+    pub public_key: Vec<u8>,
+    /// The alias the sender gave them; the contact's alias if added.
+    pub alias: String,
+    /// Who sent the card.
+    pub from: PeerId,
+    pub received_at: DateTime<Utc>,
+}
+
+impl PendingGroupInvite {
+    /// Turn the invite into a local group with the inviter as owner.
+    pub fn into_group(self, our_peer_id: PeerId) -> Group {
+        let mut group = Group::new(self.name, self.symmetric_key, Some(self.from));
+        group.id = self.group_id;
+        group.created_at = self.received_at;
+        group.add_member(self.from);
+        group.add_member(our_peer_id);
+        group
+    }
 }
 
 /// File transfer status.
@@ -230,6 +505,16 @@ pub struct FileTransferComplete {
     pub file_checksum: [u8; 32],
 }
 
+/// Announcement of a file, sent ahead of its chunks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileOffer {
+    pub transfer_id: Uuid,
+    pub filename: String,
+    pub total_size: u64,
+    pub total_chunks: u32,
+    pub file_checksum: [u8; 32],
+}
+
 /// Metadata for a file transfer.
 #[derive(Debug, Clone)]
 pub struct FileTransfer {
@@ -294,6 +579,17 @@ impl FileTransfer {
         }
     }
 
+    /// Build the offer announcing this transfer to the recipient.
+    pub fn offer(&self) -> FileOffer {
+        FileOffer {
+            transfer_id: self.id,
+            filename: self.filename.clone(),
+            total_size: self.total_size,
+            total_chunks: self.total_chunks,
+            file_checksum: self.file_checksum,
+        }
+    }
+
     /// Check if transfer is complete.
     pub fn is_complete(&self) -> bool {
         self.chunks_received >= self.total_chunks
@@ -365,13 +661,15 @@ pub enum ReceiptType {
 }
 
 /// Message status.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MessageStatus {
     Pending,
     Sent,
     Delivered,
     Read,
     Failed(String),
+    /// Given up on after waiting in the queue too long.
+    Expired,
 }
 
 /// A message.
@@ -411,6 +709,40 @@ impl Message {
     }
 }
 
+/// A conversation with a contact or a group, as listed in the inbox.
+#[derive(Debug, Clone)]
+pub struct Conversation {
+    /// Who the conversation is with.
+    pub with: Recipient,
+    /// Contact alias or group name.
+    pub name: String,
+    /// The most recent message, if there is one.
+    pub last_message: Option<Message>,
+    /// Messages received since the conversation was last read.
+    pub unread: usize,
+    /// Listed above the rest.
+    pub pinned: bool,
+    /// Left out of the default view.
+    pub archived: bool,
+}
+
+impl Conversation {
+    /// When the last message was sent.
+    pub fn last_activity(&self) -> Option<DateTime<Utc>> {
+        self.last_message.as_ref().map(|m| m.timestamp)
+    }
+}
+
+/// Put conversations in inbox order: pinned first, then most recent first.
+pub fn sort_conversations(conversations: &mut [Conversation]) {
+    conversations.sort_by(|a, b| {
+        b.pinned
+            .cmp(&a.pinned)
+            .then_with(|| b.last_activity().cmp(&a.last_activity()))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -440,6 +772,24 @@ mod tests {
         assert!(matches!(receipt.content, MessageContent::Receipt(_, ReceiptType::Delivered)));
     }
 
+    #[test]
+    fn group_leave_signature_verifies() {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let public_key = keypair.public().try_into_ed25519().unwrap().to_bytes();
+        let leave = GroupLeave::new(&keypair, Uuid::new_v4()).unwrap();
+        assert!(leave.verify(&peer_id, &public_key));
+
+        // Another key, another sender, or an altered notice all fail
+        let other = Keypair::generate_ed25519();
+        let other_key = other.public().try_into_ed25519().unwrap().to_bytes();
+        assert!(!leave.verify(&peer_id, &other_key));
+        assert!(!leave.verify(&PeerId::from(other.public()), &other_key));
+        let mut forged = leave.clone();
+        forged.group_id = Uuid::new_v4();
+        assert!(!forged.verify(&peer_id, &public_key));
+    }
+
     #[test]
     fn message_has_unique_id() {
         let from = make_peer_id();
@@ -463,7 +813,7 @@ mod tests {
     fn create_group() {
         let group = Group::new("Test Group".to_string(), vec![1, 2, 3], None);
         assert_eq!(group.name, "Test Group");
-        assert_eq!(group.symmetric_key, vec![1, 2, 3]);
+        assert_eq!(*group.symmetric_key, vec![1, 2, 3]);
         assert!(group.members.is_empty());
         assert!(group.owner.is_none());
     }
@@ -627,6 +977,24 @@ mod tests {
         assert_eq!(transfer.total_chunks, 4);
     }
 
+    #[test]
+    fn file_transfer_offer_matches_transfer() {
+        let data = vec![7u8; 70 * 1024];
+        let transfer = FileTransfer::new_outgoing(
+            make_peer_id(),
+            Recipient::Direct(make_peer_id()),
+            "photo.jpg".to_string(),
+            &data,
+        );
+
+        let offer = transfer.offer();
+        assert_eq!(offer.transfer_id, transfer.id);
+        assert_eq!(offer.filename, "photo.jpg");
+        assert_eq!(offer.total_size, transfer.total_size);
+        assert_eq!(offer.total_chunks, 2);
+        assert_eq!(offer.file_checksum, transfer.file_checksum);
+    }
+
     #[test]
     fn file_transfer_progress() {
         let from = make_peer_id();
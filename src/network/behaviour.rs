@@ -1,38 +1,145 @@
 //! Combined libp2p network behaviour.
+//!
+//! The behaviour itself needs mDNS and so the `native` feature; the
+//! message protocol's codec is always available.
 
+use libp2p::{request_response, PeerId, StreamProtocol};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+#[cfg(feature = "native")]
 use libp2p::{
+    autonat, gossipsub, identify, identity,
     kad::{self, store::MemoryStore},
     mdns,
     relay,
-    request_response::{self, ProtocolSupport},
-    swarm::NetworkBehaviour,
-    PeerId, StreamProtocol,
+    request_response::ProtocolSupport,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
 };
+#[cfg(feature = "native")]
 use std::iter;
 
-/// Protocol name for Whisper messages.
-pub const WHISPER_PROTOCOL: &str = "/whisper/1.0.0";
+#[cfg(feature = "native")]
+use super::discovery::configure_mdns;
+#[cfg(feature = "native")]
+use super::gossip::configure_gossipsub;
+#[cfg(feature = "native")]
+use super::pex::{PexCodec, PEX_PROTOCOL};
+#[cfg(feature = "native")]
+use super::ping::{PingCodec, PING_PROTOCOL};
+#[cfg(feature = "native")]
+use super::rendezvous::{RendezvousCodec, RENDEZVOUS_PROTOCOL};
+#[cfg(feature = "native")]
+use super::transfer::{FileCodec, FILE_TRANSFER_PROTOCOL};
 
-/// Message codec for request-response.
-#[derive(Debug, Clone, Default)]
-pub struct MessageCodec;
+/// Protocol name for Whisper messages: one length-prefixed frame each way.
+pub const WHISPER_PROTOCOL: &str = "/whisper/1.1.0";
+
+/// The protocol before framing, for older peers: the request runs to the
+/// end of the stream. Held to the same size limit.
+pub const LEGACY_WHISPER_PROTOCOL: &str = "/whisper/1.0.0";
+
+/// What we tell peers we're running, over identify.
+pub const AGENT_VERSION: &str = concat!("whisper/", env!("CARGO_PKG_VERSION"));
+
+/// Whether a peer identifying with `protocol_version` can talk to us.
+/// Whisper nodes can if they share our major version; minor versions are
+/// settled per stream, falling back to the legacy protocol. Anything that
+/// isn't a whisper node, like a relay, is left alone.
+pub fn is_compatible_version(protocol_version: &str) -> bool {
+    let Some(version) = protocol_version.strip_prefix("/whisper/") else {
+        return true;
+    };
+    let major = |version: &str| version.split('.').next().and_then(|major| major.parse::<u32>().ok());
+    let ours = major(&WHISPER_PROTOCOL["/whisper/".len()..]);
+    major(version).is_some() && major(version) == ours
+}
+
+/// Largest message accepted by default, in bytes.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Bytes in a frame's length prefix.
+const LENGTH_PREFIX_LEN: usize = 4;
+
+/// Message codec for request-response. Clones share the size limit, so
+/// changing it on one changes it for every connection.
+#[derive(Debug, Clone)]
+pub struct MessageCodec {
+    max_message_size: Arc<AtomicUsize>,
+}
+
+/// A message over the size limit. Reading one gives it as the request,
+/// since libp2p drops read errors without telling the behaviour; sending
+/// one fails with it inside the `std::io::Error`.
+#[derive(Debug, thiserror::Error)]
+#[error("message of {size} bytes is over the {max} byte limit")]
+pub struct MessageTooLarge {
+    /// The message's size, or for legacy streams how much was read
+    /// before giving up.
+    pub size: u64,
+    pub max: usize,
+}
+
+impl MessageTooLarge {
+    /// The limit breach behind an I/O error, if that's what it was.
+    pub fn from_io(error: &std::io::Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    fn into_io(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, self)
+    }
+}
+
+impl MessageCodec {
+    /// A codec refusing messages over `max_message_size` bytes.
+    pub fn new(max_message_size: usize) -> Self {
+        Self { max_message_size: Arc::new(AtomicUsize::new(max_message_size)) }
+    }
+
+    /// The largest message accepted, in bytes.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size.load(Ordering::Relaxed)
+    }
+
+    /// Change the largest message accepted.
+    pub fn set_max_message_size(&self, bytes: usize) {
+        self.max_message_size.store(bytes, Ordering::Relaxed);
+    }
+
+    fn check_size(&self, size: u64) -> Result<(), MessageTooLarge> {
+        let max = self.max_message_size();
+        if size > max as u64 {
+            return Err(MessageTooLarge { size, max });
+        }
+        Ok(())
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
 
 /// Request type - encrypted message bytes.
 #[derive(Debug, Clone)]
 pub struct MessageRequest(pub Vec<u8>);
 
+/// A request as read off the wire: the message, or how it broke the limit.
+pub type MessageFrame = Result<MessageRequest, MessageTooLarge>;
+
 /// Response type - delivery receipt.
 #[derive(Debug, Clone)]
 pub struct MessageResponse(pub bool);
 
 impl request_response::Codec for MessageCodec {
     type Protocol = StreamProtocol;
-    type Request = MessageRequest;
+    type Request = MessageFrame;
     type Response = MessageResponse;
 
     fn read_request<'life0, 'life1, 'life2, 'async_trait, T>(
         &'life0 mut self,
-        _protocol: &'life1 Self::Protocol,
+        protocol: &'life1 Self::Protocol,
         io: &'life2 mut T,
     ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<Self::Request>> + Send + 'async_trait>>
     where
@@ -43,9 +150,24 @@ impl request_response::Codec for MessageCodec {
         Self: 'async_trait,
     {
         Box::pin(async move {
-            let mut buf = Vec::new();
-            futures::AsyncReadExt::read_to_end(io, &mut buf).await?;
-            Ok(MessageRequest(buf))
+            if protocol.as_ref() == LEGACY_WHISPER_PROTOCOL {
+                // Read one byte past the limit to tell if it was over
+                let mut buf = Vec::new();
+                let limit = self.max_message_size() as u64 + 1;
+                futures::AsyncReadExt::read_to_end(&mut futures::AsyncReadExt::take(io, limit), &mut buf).await?;
+                return Ok(self.check_size(buf.len() as u64).map(|()| MessageRequest(buf)));
+            }
+
+            let mut prefix = [0u8; LENGTH_PREFIX_LEN];
+            futures::AsyncReadExt::read_exact(io, &mut prefix).await?;
+            let len = u32::from_be_bytes(prefix);
+            // Checked before reading, or allocating, anything more
+            if let Err(too_large) = self.check_size(len as u64) {
+                return Ok(Err(too_large));
+            }
+            let mut buf = vec![0u8; len as usize];
+            futures::AsyncReadExt::read_exact(io, &mut buf).await?;
+            Ok(Ok(MessageRequest(buf)))
         })
     }
 
@@ -70,7 +192,7 @@ impl request_response::Codec for MessageCodec {
 
     fn write_request<'life0, 'life1, 'life2, 'async_trait, T>(
         &'life0 mut self,
-        _protocol: &'life1 Self::Protocol,
+        protocol: &'life1 Self::Protocol,
         io: &'life2 mut T,
         req: Self::Request,
     ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<()>> + Send + 'async_trait>>
@@ -82,6 +204,14 @@ impl request_response::Codec for MessageCodec {
         Self: 'async_trait,
     {
         Box::pin(async move {
+            // Don't send what the other side would refuse by default
+            let req = req.and_then(|req| self.check_size(req.0.len() as u64).map(|()| req));
+            let req = req.map_err(MessageTooLarge::into_io)?;
+            if protocol.as_ref() != LEGACY_WHISPER_PROTOCOL {
+                let len = u32::try_from(req.0.len())
+                    .map_err(|_| MessageTooLarge { size: req.0.len() as u64, max: u32::MAX as usize }.into_io())?;
+                futures::AsyncWriteExt::write_all(io, &len.to_be_bytes()).await?;
+            }
             futures::AsyncWriteExt::write_all(io, &req.0).await?;
             futures::AsyncWriteExt::close(io).await?;
             Ok(())
@@ -110,48 +240,121 @@ impl request_response::Codec for MessageCodec {
 }
 
 /// Combined network behaviour for Whisper.
+#[cfg(feature = "native")]
 #[derive(NetworkBehaviour)]
 pub struct WhisperBehaviour {
-    /// mDNS for local peer discovery.
-    pub mdns: mdns::tokio::Behaviour,
+    /// mDNS for local peer discovery; off when dialing through a proxy.
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
+    /// mDNS over IPv6, where the host has it.
+    pub mdns_v6: Toggle<mdns::tokio::Behaviour>,
     /// Kademlia DHT for peer routing.
     pub kademlia: kad::Behaviour<MemoryStore>,
     /// Request-response for message exchange.
     pub request_response: request_response::Behaviour<MessageCodec>,
+    /// Request-response for chunked file transfer.
+    pub file_transfer: request_response::Behaviour<FileCodec>,
+    /// Request-response for rendezvous points, as client and server.
+    pub rendezvous: request_response::Behaviour<RendezvousCodec>,
+    /// Request-response for measuring latency.
+    pub ping: request_response::Behaviour<PingCodec>,
+    /// Request-response for trading contact address hints.
+    pub pex: request_response::Behaviour<PexCodec>,
     /// Relay client for NAT traversal.
     pub relay_client: relay::client::Behaviour,
+    /// AutoNAT probes to learn whether peers can dial us.
+    pub autonat: autonat::Behaviour,
+    /// Identify, to swap protocol and agent versions with peers.
+    pub identify: identify::Behaviour,
+    /// Gossipsub, for posts to broadcast channels.
+    pub gossipsub: gossipsub::Behaviour,
 }
 
+#[cfg(feature = "native")]
 impl WhisperBehaviour {
-    /// Create a new WhisperBehaviour.
+    /// Create a new WhisperBehaviour. Messages are read with `codec`.
     pub fn new(
-        local_peer_id: PeerId,
+        keypair: &identity::Keypair,
         relay_client: relay::client::Behaviour,
+        codec: MessageCodec,
     ) -> Self {
-        // mDNS config
+        let local_key = keypair.public();
+        let local_peer_id = local_key.to_peer_id();
+
+        // mDNS config, one behaviour per address family
         let mdns = mdns::tokio::Behaviour::new(
-            mdns::Config::default(),
+            configure_mdns(false),
             local_peer_id,
         ).expect("mDNS should initialize");
+        let mdns_v6 = mdns::tokio::Behaviour::new(configure_mdns(true), local_peer_id).ok();
 
         // Kademlia config
         let store = MemoryStore::new(local_peer_id);
         let kademlia = kad::Behaviour::new(local_peer_id, store);
 
-        // Request-response config
-        let protocol = StreamProtocol::new(WHISPER_PROTOCOL);
-        let request_response = request_response::Behaviour::new(
-            iter::once((protocol, ProtocolSupport::Full)),
+        // Request-response config; framed requests preferred
+        let protocols = [WHISPER_PROTOCOL, LEGACY_WHISPER_PROTOCOL]
+            .map(|protocol| (StreamProtocol::new(protocol), ProtocolSupport::Full));
+        let request_response =
+            request_response::Behaviour::with_codec(codec, protocols, request_response::Config::default());
+
+        // File transfer config
+        let file_transfer = request_response::Behaviour::new(
+            iter::once((StreamProtocol::new(FILE_TRANSFER_PROTOCOL), ProtocolSupport::Full)),
+            request_response::Config::default(),
+        );
+
+        // Rendezvous config
+        let rendezvous = request_response::Behaviour::new(
+            iter::once((StreamProtocol::new(RENDEZVOUS_PROTOCOL), ProtocolSupport::Full)),
+            request_response::Config::default(),
+        );
+
+        // Ping config
+        let ping = request_response::Behaviour::new(
+            iter::once((StreamProtocol::new(PING_PROTOCOL), ProtocolSupport::Full)),
             request_response::Config::default(),
         );
 
+        // Peer exchange config
+        let pex = request_response::Behaviour::new(
+            iter::once((StreamProtocol::new(PEX_PROTOCOL), ProtocolSupport::Full)),
+            request_response::Config::default(),
+        );
+
+        // AutoNAT config
+        let autonat = autonat::Behaviour::new(local_peer_id, autonat::Config::default());
+
+        // Identify config
+        let identify = identify::Behaviour::new(
+            identify::Config::new(WHISPER_PROTOCOL.to_string(), local_key)
+                .with_agent_version(AGENT_VERSION.to_string()),
+        );
+
+        // Gossipsub config; posts are signed by whoever publishes them
+        let gossipsub = configure_gossipsub(keypair);
+
         Self {
-            mdns,
+            mdns: Some(mdns).into(),
+            mdns_v6: mdns_v6.into(),
             kademlia,
             request_response,
+            file_transfer,
+            rendezvous,
+            ping,
+            pex,
             relay_client,
+            autonat,
+            identify,
+            gossipsub,
         }
     }
+
+    /// Stop announcing ourselves and looking for peers on the local network.
+    pub fn without_mdns(mut self) -> Self {
+        self.mdns = None.into();
+        self.mdns_v6 = None.into();
+        self
+    }
 }
 
 /// Events emitted by WhisperBehaviour.
@@ -251,16 +454,79 @@ mod tests {
 
     #[test]
     fn codec_is_default() {
-        let codec = MessageCodec;
-        // Just verify it compiles and creates
-        let _ = codec;
+        let codec = MessageCodec::default();
+        assert_eq!(codec.max_message_size(), DEFAULT_MAX_MESSAGE_SIZE);
+        // Clones share the limit
+        codec.clone().set_max_message_size(10);
+        assert_eq!(codec.max_message_size(), 10);
     }
 
     #[test]
     fn protocol_name_is_valid() {
         assert!(WHISPER_PROTOCOL.starts_with('/'));
         assert!(WHISPER_PROTOCOL.contains("whisper"));
-        assert!(WHISPER_PROTOCOL.contains("1.0.0"));
+        assert!(WHISPER_PROTOCOL.contains("1.1.0"));
+        assert!(LEGACY_WHISPER_PROTOCOL.contains("1.0.0"));
+        assert!(AGENT_VERSION.starts_with("whisper/"));
+    }
+
+    #[test]
+    fn only_other_major_versions_are_incompatible() {
+        assert!(is_compatible_version(WHISPER_PROTOCOL));
+        assert!(is_compatible_version(LEGACY_WHISPER_PROTOCOL));
+        assert!(is_compatible_version("/whisper/1.7.2"));
+        assert!(!is_compatible_version("/whisper/2.0.0"));
+        assert!(!is_compatible_version("/whisper/garbage"));
+        // Relays and other non-whisper peers aren't ours to judge
+        assert!(is_compatible_version("ipfs/0.1.0"));
+    }
+
+    fn write(codec: &mut MessageCodec, protocol: &'static str, data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        use request_response::Codec;
+        let mut io = futures::io::Cursor::new(Vec::new());
+        let protocol = StreamProtocol::new(protocol);
+        futures::executor::block_on(codec.write_request(&protocol, &mut io, Ok(MessageRequest(data))))?;
+        Ok(io.into_inner())
+    }
+
+    fn read(codec: &mut MessageCodec, protocol: &'static str, wire: Vec<u8>) -> std::io::Result<MessageFrame> {
+        use request_response::Codec;
+        let mut io = futures::io::Cursor::new(wire);
+        futures::executor::block_on(codec.read_request(&StreamProtocol::new(protocol), &mut io))
+    }
+
+    #[test]
+    fn requests_are_length_prefixed() {
+        let mut codec = MessageCodec::default();
+        let wire = write(&mut codec, WHISPER_PROTOCOL, vec![7; 5]).unwrap();
+        assert_eq!(wire, [0, 0, 0, 5, 7, 7, 7, 7, 7]);
+        assert_eq!(read(&mut codec, WHISPER_PROTOCOL, wire).unwrap().unwrap().0, [7; 5]);
+
+        // Legacy peers get the bytes as they are
+        let wire = write(&mut codec, LEGACY_WHISPER_PROTOCOL, vec![7; 5]).unwrap();
+        assert_eq!(wire, [7; 5]);
+        assert_eq!(read(&mut codec, LEGACY_WHISPER_PROTOCOL, wire).unwrap().unwrap().0, [7; 5]);
+
+        // A frame cut short is an error, not a short message
+        assert!(read(&mut codec, WHISPER_PROTOCOL, vec![0, 0, 0, 5, 7]).is_err());
+    }
+
+    #[test]
+    fn oversized_messages_are_refused() {
+        let mut codec = MessageCodec::new(4);
+
+        // Refused from the prefix alone, before reading the rest
+        let too_large = read(&mut codec, WHISPER_PROTOCOL, vec![0xff, 0xff, 0xff, 0xff]).unwrap().unwrap_err();
+        assert_eq!((too_large.size, too_large.max), (u32::MAX as u64, 4));
+
+        let too_large = read(&mut codec, LEGACY_WHISPER_PROTOCOL, vec![1; 100]).unwrap().unwrap_err();
+        assert_eq!(too_large.size, 5);
+        assert_eq!(read(&mut codec, LEGACY_WHISPER_PROTOCOL, vec![1; 4]).unwrap().unwrap().0, [1; 4]);
+
+        let err = write(&mut codec, WHISPER_PROTOCOL, vec![1; 5]).unwrap_err();
+        assert_eq!(MessageTooLarge::from_io(&err).unwrap().size, 5);
+        // Other failures aren't mistaken for it
+        assert!(MessageTooLarge::from_io(&std::io::Error::other("closed")).is_none());
     }
 
     // Note: Full behaviour tests require async runtime and are in integration tests
@@ -0,0 +1,352 @@
+//! Automatic reconnection.
+//!
+//! mDNS only finds peers on the local network, and only when it happens to
+//! be looking, so the node also remembers where it last reached each peer.
+//! Peers we have messages for, and watched peers that drop their
+//! connection, are dialed again with exponential backoff until a
+//! connection is made.
+//!
+//! A peer's addresses are dialed together, so whichever of IPv4 and IPv6
+//! connects first wins and is tried first from then on. Direct addresses
+//! go ahead of relayed ones.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use libp2p::{Multiaddr, PeerId};
+use web_time::Instant;
+
+use super::relay::is_relay_address;
+
+/// Wait before the first redial by default, in seconds.
+pub const DEFAULT_RECONNECT_INITIAL_SECS: u64 = 1;
+
+/// Longest wait between redials by default, in seconds.
+pub const DEFAULT_RECONNECT_MAX_SECS: u64 = 300;
+
+/// Addresses remembered per peer; the oldest are forgotten first.
+pub const MAX_ADDRESSES_PER_PEER: usize = 8;
+
+/// How eagerly to redial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectConfig {
+    /// Wait before the first attempt after a disconnect.
+    pub initial: Duration,
+    /// The wait doubles after each failed attempt, up to this.
+    pub max: Duration,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(DEFAULT_RECONNECT_INITIAL_SECS),
+            max: Duration::from_secs(DEFAULT_RECONNECT_MAX_SECS),
+        }
+    }
+}
+
+/// An address a peer was seen at.
+#[derive(Debug, Clone)]
+struct KnownAddress {
+    addr: Multiaddr,
+    /// Whether we've connected through it.
+    reached: bool,
+}
+
+/// Redial schedule for one peer.
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    /// Attempts made since the last connection.
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Tracks peer addresses and decides when to dial whom.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionManager {
+    config: ReconnectConfig,
+    /// Last-known addresses per peer: direct ones, then relayed. Each lot
+    /// has those we've connected through, most recent first, then the
+    /// rest, most recently seen first.
+    addresses: HashMap<PeerId, Vec<KnownAddress>>,
+    /// Peers redialed whenever they disconnect.
+    watched: HashSet<PeerId>,
+    connected: HashSet<PeerId>,
+    /// Peers to dial, and when.
+    scheduled: HashMap<PeerId, Backoff>,
+}
+
+impl ConnectionManager {
+    /// Create a manager that knows no peers yet.
+    pub fn new(config: ReconnectConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// The redial settings in force.
+    pub fn config(&self) -> ReconnectConfig {
+        self.config
+    }
+
+    /// Change the redial settings. Known addresses and watched peers are
+    /// kept; dials already scheduled are dropped.
+    pub fn set_config(&mut self, config: ReconnectConfig) {
+        self.config = config;
+        self.scheduled.clear();
+    }
+
+    /// Remember that `peer` was seen at `addr`. It's tried after any
+    /// address we've already connected through, and relayed addresses
+    /// after direct ones.
+    pub fn add_address(&mut self, peer: PeerId, addr: Multiaddr) {
+        self.remember(peer, addr, false);
+    }
+
+    /// Remember that we connected to `peer` through `addr`, so it's tried
+    /// first next time.
+    pub fn address_reached(&mut self, peer: PeerId, addr: Multiaddr) {
+        self.remember(peer, addr, true);
+    }
+
+    fn remember(&mut self, peer: PeerId, addr: Multiaddr, reached: bool) {
+        let known = self.addresses.entry(peer).or_default();
+        let mut reached = reached;
+        if let Some(i) = known.iter().position(|a| a.addr == addr) {
+            reached |= known.remove(i).reached;
+        }
+        let rank = |addr: &Multiaddr, reached: bool| (is_relay_address(addr), !reached);
+        let ours = rank(&addr, reached);
+        let at = known.iter().position(|a| rank(&a.addr, a.reached) >= ours).unwrap_or(known.len());
+        known.insert(at, KnownAddress { addr, reached });
+        known.truncate(MAX_ADDRESSES_PER_PEER);
+    }
+
+    /// Where `peer` might be reached, best first: direct addresses before
+    /// relayed ones, and those we've connected through before those we've
+    /// only seen.
+    pub fn addresses(&self, peer: &PeerId) -> Vec<Multiaddr> {
+        self.addresses
+            .get(peer)
+            .map(|known| known.iter().map(|a| a.addr.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Keep a connection to `peer`: redial whenever it drops.
+    pub fn watch(&mut self, peer: PeerId) {
+        self.watched.insert(peer);
+    }
+
+    /// Stop redialing `peer` after it disconnects.
+    pub fn unwatch(&mut self, peer: &PeerId) {
+        self.watched.remove(peer);
+    }
+
+    /// Whether `peer` is redialed when it disconnects.
+    pub fn is_watched(&self, peer: &PeerId) -> bool {
+        self.watched.contains(peer)
+    }
+
+    /// Every peer redialed when it disconnects.
+    pub fn watched(&self) -> Vec<PeerId> {
+        self.watched.iter().copied().collect()
+    }
+
+    /// Every peer scheduled to be dialed.
+    pub fn scheduled(&self) -> Vec<PeerId> {
+        self.scheduled.keys().copied().collect()
+    }
+
+    /// We need a connection to `peer`, e.g. to deliver a message. Dials it
+    /// now unless it's connected or already scheduled.
+    pub fn want(&mut self, peer: PeerId, now: Instant) {
+        if self.connected.contains(&peer) {
+            return;
+        }
+        self.scheduled.entry(peer).or_insert(Backoff {
+            attempts: 0,
+            next_attempt: now,
+        });
+    }
+
+    /// A connection to `peer` is up; its backoff is reset.
+    pub fn connected(&mut self, peer: PeerId) {
+        self.connected.insert(peer);
+        self.scheduled.remove(&peer);
+    }
+
+    /// The last connection to `peer` closed. Watched peers, and peers we
+    /// still have something to send, are redialed after the initial wait.
+    pub fn disconnected(&mut self, peer: PeerId, still_needed: bool, now: Instant) {
+        self.connected.remove(&peer);
+        if still_needed || self.watched.contains(&peer) {
+            self.scheduled.entry(peer).or_insert(Backoff {
+                attempts: 0,
+                next_attempt: now + self.config.initial,
+            });
+        }
+    }
+
+    /// Peers due a dial attempt at `now`, with their known addresses. Each
+    /// is rescheduled after its backoff in case the attempt fails.
+    pub fn due(&mut self, now: Instant) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let mut due = Vec::new();
+        for (peer, backoff) in &mut self.scheduled {
+            if backoff.next_attempt > now {
+                continue;
+            }
+            let wait = self
+                .config
+                .initial
+                .saturating_mul(2u32.saturating_pow(backoff.attempts))
+                .min(self.config.max);
+            backoff.attempts = backoff.attempts.saturating_add(1);
+            backoff.next_attempt = now + wait;
+            due.push(*peer);
+        }
+        due.into_iter().map(|peer| (peer, self.addresses(&peer))).collect()
+    }
+
+    /// When the next dial attempt is due, if any are scheduled.
+    pub fn next_attempt(&self) -> Option<Instant> {
+        self.scheduled.values().map(|b| b.next_attempt).min()
+    }
+
+    /// Attempts made to reach `peer` since it was last connected, if it's
+    /// scheduled to be dialed.
+    pub fn attempts(&self, peer: &PeerId) -> Option<u32> {
+        self.scheduled.get(peer).map(|b| b.attempts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    fn manager() -> ConnectionManager {
+        ConnectionManager::new(ReconnectConfig {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+        })
+    }
+
+    #[test]
+    fn remembers_recent_addresses_first() {
+        let mut m = manager();
+        let peer = PeerId::random();
+        m.add_address(peer, addr(1));
+        m.add_address(peer, addr(2));
+        m.add_address(peer, addr(1));
+        assert_eq!(m.addresses(&peer), vec![addr(1), addr(2)]);
+
+        for port in 10..30 {
+            m.add_address(peer, addr(port));
+        }
+        assert_eq!(m.addresses(&peer).len(), MAX_ADDRESSES_PER_PEER);
+        assert_eq!(m.addresses(&peer)[0], addr(29));
+        assert!(m.addresses(&PeerId::random()).is_empty());
+    }
+
+    #[test]
+    fn addresses_we_connected_through_come_first() {
+        let mut m = manager();
+        let peer = PeerId::random();
+        m.add_address(peer, addr(1));
+        m.address_reached(peer, addr(2));
+        m.add_address(peer, addr(3));
+        // Seeing it again doesn't forget that it worked
+        m.add_address(peer, addr(2));
+        assert_eq!(m.addresses(&peer), vec![addr(2), addr(3), addr(1)]);
+
+        m.address_reached(peer, addr(1));
+        assert_eq!(m.addresses(&peer), vec![addr(1), addr(2), addr(3)]);
+
+        // Addresses never reached are forgotten first
+        for port in 10..30 {
+            m.add_address(peer, addr(port));
+        }
+        assert_eq!(&m.addresses(&peer)[..2], &[addr(1), addr(2)]);
+    }
+
+    #[test]
+    fn direct_addresses_of_either_family_beat_relays() {
+        let mut m = manager();
+        let peer = PeerId::random();
+        let relayed: Multiaddr = format!("{}/p2p/{}/p2p-circuit", addr(1), PeerId::random()).parse().unwrap();
+        let v6: Multiaddr = "/ip6/::1/tcp/2".parse().unwrap();
+        m.address_reached(peer, relayed.clone());
+        m.add_address(peer, addr(3));
+        m.add_address(peer, v6.clone());
+        assert_eq!(m.addresses(&peer), vec![v6.clone(), addr(3), relayed.clone()]);
+
+        // Whichever family connected is tried first
+        m.address_reached(peer, addr(3));
+        assert_eq!(m.addresses(&peer), vec![addr(3), v6, relayed]);
+    }
+
+    #[test]
+    fn wanted_peer_is_dialed_with_exponential_backoff() {
+        let mut m = manager();
+        let peer = PeerId::random();
+        m.add_address(peer, addr(1));
+        let start = Instant::now();
+
+        m.want(peer, start);
+        assert_eq!(m.due(start), vec![(peer, vec![addr(1)])]);
+        // Not again until the backoff passes: 1s, 2s, 4s, 8s, then capped
+        assert!(m.due(start).is_empty());
+        let mut at = start;
+        for wait in [1, 2, 4, 8, 10, 10] {
+            assert!(m.due(at + Duration::from_secs(wait) - Duration::from_millis(1)).is_empty());
+            at += Duration::from_secs(wait);
+            assert_eq!(m.due(at).len(), 1);
+        }
+        assert_eq!(m.attempts(&peer), Some(7));
+    }
+
+    #[test]
+    fn connecting_stops_dialing() {
+        let mut m = manager();
+        let peer = PeerId::random();
+        let now = Instant::now();
+        m.want(peer, now);
+        m.connected(peer);
+        assert!(m.due(now).is_empty());
+        assert_eq!(m.next_attempt(), None);
+
+        // Wanting a connected peer does nothing
+        m.want(peer, now);
+        assert_eq!(m.next_attempt(), None);
+    }
+
+    #[test]
+    fn only_watched_or_needed_peers_are_redialed() {
+        let mut m = manager();
+        let (watched, needed, other) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let now = Instant::now();
+        m.watch(watched);
+        for peer in [watched, needed, other] {
+            m.connected(peer);
+        }
+
+        m.disconnected(watched, false, now);
+        m.disconnected(needed, true, now);
+        m.disconnected(other, false, now);
+        assert_eq!(m.next_attempt(), Some(now + Duration::from_secs(1)));
+        assert!(m.due(now).is_empty());
+
+        let mut due: Vec<_> = m.due(now + Duration::from_secs(1)).into_iter().map(|(p, _)| p).collect();
+        due.sort();
+        let mut expected = vec![watched, needed];
+        expected.sort();
+        assert_eq!(due, expected);
+
+        m.unwatch(&watched);
+        assert!(!m.is_watched(&watched));
+    }
+}
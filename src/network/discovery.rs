@@ -1,12 +1,18 @@
 //! Peer discovery with mDNS and Kademlia DHT.
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use libp2p::{
-    kad::{self, QueryId},
-    mdns, Multiaddr, PeerId,
+    core::{PeerRecord, SignedEnvelope},
+    identity::Keypair,
+    kad,
+    Multiaddr, PeerId,
 };
 use std::time::Duration;
 
+#[cfg(feature = "native")]
+use libp2p::{kad::QueryId, mdns};
+
+#[cfg(feature = "native")]
 use super::node::WhisperNode;
 
 /// Default mDNS query interval in seconds.
@@ -18,12 +24,20 @@ pub const KAD_REPLICATION_FACTOR: usize = 20;
 /// Default Kademlia query timeout in seconds.
 pub const KAD_QUERY_TIMEOUT_SECS: u64 = 60;
 
-/// Configure mDNS for local peer discovery.
-pub fn configure_mdns() -> mdns::Config {
+/// How often we republish our address record, in seconds.
+pub const ADDRESS_RECORD_REFRESH_SECS: u64 = 30 * 60;
+
+/// How long DHT nodes keep an address record, in seconds.
+pub const ADDRESS_RECORD_TTL_SECS: u64 = 2 * 60 * 60;
+
+/// Configure mDNS for local peer discovery. An mDNS behaviour speaks
+/// either IPv4 or IPv6, so the node runs one of each.
+#[cfg(feature = "native")]
+pub fn configure_mdns(ipv6: bool) -> mdns::Config {
     mdns::Config {
         ttl: Duration::from_secs(6 * 60), // 6 minutes
         query_interval: Duration::from_secs(MDNS_QUERY_INTERVAL_SECS),
-        enable_ipv6: false, // Most local networks use IPv4
+        enable_ipv6: ipv6,
     }
 }
 
@@ -45,20 +59,32 @@ pub fn configure_kademlia(_peer_id: PeerId) -> kad::Config {
     config
 }
 
-/// Get bootstrap nodes for the Whisper network.
+/// Get bootstrap nodes for the Whisper network: the built-in ones, then
+/// the `user` added ones.
 /// 
 /// These are well-known nodes that help new peers join the network.
 /// In production, these would be maintained by the Whisper project.
-pub fn bootstrap_nodes() -> Vec<Multiaddr> {
+pub fn bootstrap_nodes(user: &[Multiaddr]) -> Vec<Multiaddr> {
     // Default bootstrap nodes (can be empty for local-only networks)
     // Format: /ip4/{ip}/tcp/{port}/p2p/{peer_id}
-    vec![
+    let builtin = vec![
         // Example bootstrap nodes - in production these would be real
         // "/ip4/104.131.131.82/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ"
     ]
     .into_iter()
     .filter_map(|s: &str| s.parse().ok())
-    .collect()
+    .collect();
+    merge_nodes(builtin, user)
+}
+
+/// `builtin` followed by whichever of `user` it doesn't already have.
+pub(crate) fn merge_nodes(mut builtin: Vec<Multiaddr>, user: &[Multiaddr]) -> Vec<Multiaddr> {
+    for addr in user {
+        if !builtin.contains(addr) {
+            builtin.push(addr.clone());
+        }
+    }
+    builtin
 }
 
 /// Known public bootstrap nodes for testing.
@@ -78,6 +104,7 @@ pub fn ipfs_bootstrap_nodes() -> Vec<Multiaddr> {
 /// 
 /// This initiates a DHT lookup for the given peer ID.
 /// Returns the query ID which can be used to track the result.
+#[cfg(feature = "native")]
 pub fn start_peer_discovery(node: &mut WhisperNode, peer_id: PeerId) -> QueryId {
     node.swarm_mut()
         .behaviour_mut()
@@ -85,7 +112,35 @@ pub fn start_peer_discovery(node: &mut WhisperNode, peer_id: PeerId) -> QueryId
         .get_closest_peers(peer_id)
 }
 
+/// The DHT key a peer publishes its addresses under.
+pub fn address_record_key(peer_id: &PeerId) -> kad::RecordKey {
+    let mut key = b"/whisper/addresses/".to_vec();
+    key.extend_from_slice(&peer_id.to_bytes());
+    kad::RecordKey::new(&key)
+}
+
+/// Sign our addresses for publishing in the DHT.
+pub fn encode_address_record(keypair: &Keypair, addresses: Vec<Multiaddr>) -> Result<Vec<u8>> {
+    let record = PeerRecord::new(keypair, addresses)
+        .map_err(|e| anyhow::anyhow!("Failed to sign address record: {}", e))?;
+    Ok(record.into_signed_envelope().into_protobuf_encoding())
+}
+
+/// Read an address record found in the DHT. Anyone can store a record
+/// under any key, so it's only accepted if `peer_id` signed it.
+pub fn decode_address_record(peer_id: &PeerId, bytes: &[u8]) -> Result<Vec<Multiaddr>> {
+    let envelope = SignedEnvelope::from_protobuf_encoding(bytes)
+        .map_err(|e| anyhow::anyhow!("Malformed address record: {}", e))?;
+    let record = PeerRecord::from_signed_envelope(envelope)
+        .map_err(|e| anyhow::anyhow!("Invalid address record: {}", e))?;
+    if record.peer_id() != *peer_id {
+        bail!("Address record for {} is signed by {}", peer_id, record.peer_id());
+    }
+    Ok(record.addresses().to_vec())
+}
+
 /// Add a peer address to the Kademlia routing table.
+#[cfg(feature = "native")]
 pub fn add_peer_address(node: &mut WhisperNode, peer_id: &PeerId, addr: Multiaddr) {
     node.swarm_mut()
         .behaviour_mut()
@@ -93,10 +148,11 @@ pub fn add_peer_address(node: &mut WhisperNode, peer_id: &PeerId, addr: Multiadd
         .add_address(peer_id, addr);
 }
 
-/// Bootstrap the Kademlia DHT by connecting to known nodes.
+/// Bootstrap the Kademlia DHT by connecting to the node's bootstrap nodes.
+#[cfg(feature = "native")]
 pub fn bootstrap_kademlia(node: &mut WhisperNode) -> Result<QueryId> {
     // Add bootstrap nodes to routing table
-    for addr in bootstrap_nodes() {
+    for addr in node.bootstrap_nodes().to_vec() {
         if let Some(peer_id) = extract_peer_id(&addr) {
             add_peer_address(node, &peer_id, addr);
         }
@@ -121,6 +177,16 @@ pub fn extract_peer_id(addr: &Multiaddr) -> Option<PeerId> {
     })
 }
 
+/// Split a trailing `/p2p/<peer id>` off an address, returning the
+/// transport address and the peer it names.
+pub fn split_peer_id(addr: &Multiaddr) -> (Multiaddr, Option<PeerId>) {
+    let mut transport = addr.clone();
+    match transport.pop() {
+        Some(libp2p::multiaddr::Protocol::P2p(peer_id)) => (transport, Some(peer_id)),
+        _ => (addr.clone(), None),
+    }
+}
+
 /// Check if an address is a local/private address.
 pub fn is_local_address(addr: &Multiaddr) -> bool {
     addr.iter().any(|p| match p {
@@ -128,7 +194,11 @@ pub fn is_local_address(addr: &Multiaddr) -> bool {
             ip.is_loopback() || ip.is_private() || ip.is_link_local()
         }
         libp2p::multiaddr::Protocol::Ip6(ip) => {
+            let first = ip.segments()[0];
             ip.is_loopback()
+                || ip.is_unspecified()
+                || first & 0xfe00 == 0xfc00 // unique local, fc00::/7
+                || first & 0xffc0 == 0xfe80 // link-local, fe80::/10
         }
         _ => false,
     })
@@ -139,14 +209,16 @@ mod tests {
     use super::*;
 
     #[test]
+    #[cfg(feature = "native")]
     fn mdns_config_has_valid_ttl() {
-        let config = configure_mdns();
+        let config = configure_mdns(false);
         assert!(config.ttl >= Duration::from_secs(60));
     }
 
     #[test]
+    #[cfg(feature = "native")]
     fn mdns_config_has_valid_query_interval() {
-        let config = configure_mdns();
+        let config = configure_mdns(false);
         assert!(config.query_interval <= Duration::from_secs(60));
         assert!(config.query_interval >= Duration::from_secs(1));
     }
@@ -169,7 +241,7 @@ mod tests {
 
     #[test]
     fn bootstrap_nodes_returns_valid_addrs() {
-        let nodes = bootstrap_nodes();
+        let nodes = bootstrap_nodes(&[]);
         // All returned addresses should be valid Multiaddrs
         for addr in nodes {
             // Just verify they're valid by converting to string
@@ -177,6 +249,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn user_bootstrap_nodes_follow_builtins_once() {
+        let user: Multiaddr = "/ip4/10.0.0.1/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ"
+            .parse()
+            .unwrap();
+        let nodes = bootstrap_nodes(&[user.clone(), user.clone()]);
+        assert_eq!(nodes.len(), bootstrap_nodes(&[]).len() + 1);
+        assert_eq!(nodes.last(), Some(&user));
+    }
+
     #[test]
     fn ipfs_bootstrap_nodes_are_parseable() {
         let nodes = ipfs_bootstrap_nodes();
@@ -200,6 +282,42 @@ mod tests {
         assert!(peer_id.is_none());
     }
 
+    #[test]
+    fn address_record_round_trips() {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let addrs: Vec<Multiaddr> = vec!["/ip4/203.0.113.5/tcp/4001".parse().unwrap()];
+        let bytes = encode_address_record(&keypair, addrs.clone()).unwrap();
+        assert_eq!(decode_address_record(&peer_id, &bytes).unwrap(), addrs);
+        assert_ne!(address_record_key(&peer_id), address_record_key(&PeerId::random()));
+    }
+
+    #[test]
+    fn address_record_must_be_signed_by_its_peer() {
+        let mallory = Keypair::generate_ed25519();
+        let bytes = encode_address_record(&mallory, vec!["/ip4/198.51.100.1/tcp/1".parse().unwrap()]).unwrap();
+        assert!(decode_address_record(&PeerId::random(), &bytes).is_err());
+
+        let mut tampered = bytes.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(decode_address_record(&mallory.public().to_peer_id(), &tampered).is_err());
+        assert!(decode_address_record(&mallory.public().to_peer_id(), b"junk").is_err());
+    }
+
+    #[test]
+    fn split_peer_id_only_takes_a_trailing_peer() {
+        let peer = PeerId::random();
+        let transport: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let addr = transport.clone().with(libp2p::multiaddr::Protocol::P2p(peer));
+        assert_eq!(split_peer_id(&addr), (transport.clone(), Some(peer)));
+        assert_eq!(split_peer_id(&transport), (transport.clone(), None));
+
+        // A relay's ID isn't the peer the address leads to
+        let circuit = addr.with(libp2p::multiaddr::Protocol::P2pCircuit);
+        assert_eq!(split_peer_id(&circuit), (circuit.clone(), None));
+    }
+
     #[test]
     fn is_local_address_true_for_localhost() {
         let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
@@ -211,4 +329,13 @@ mod tests {
         let addr: Multiaddr = "/ip4/8.8.8.8/tcp/4001".parse().unwrap();
         assert!(!is_local_address(&addr));
     }
+
+    #[test]
+    fn is_local_address_handles_ipv6() {
+        for local in ["/ip6/::1/tcp/4001", "/ip6/fd12:3456::1/tcp/4001", "/ip6/fe80::1/tcp/4001", "/ip6/::/tcp/0"] {
+            assert!(is_local_address(&local.parse().unwrap()), "{}", local);
+        }
+        let addr: Multiaddr = "/ip6/2001:4860:4860::8888/tcp/4001".parse().unwrap();
+        assert!(!is_local_address(&addr));
+    }
 }
@@ -0,0 +1,49 @@
+//! Channel posts over gossipsub.
+//!
+//! Each broadcast channel has a topic of its own, which its owner and
+//! subscribers join. Posts are published to the topic and relayed through
+//! the mesh of peers on it, so an owner doesn't have to reach every
+//! subscriber themselves. Gossip is only as trustworthy as whoever relayed
+//! it: subscribers check each post against the channel owner's key.
+
+#[cfg(feature = "native")]
+use libp2p::{gossipsub, identity::Keypair};
+use uuid::Uuid;
+
+/// Prefix of every channel topic; the channel's ID follows.
+pub const CHANNEL_TOPIC_PREFIX: &str = "whisper/channel/";
+
+/// The topic a channel's posts are published to.
+pub fn channel_topic(channel_id: &Uuid) -> String {
+    format!("{}{}", CHANNEL_TOPIC_PREFIX, channel_id)
+}
+
+/// The channel a topic is for, if it's a channel topic.
+pub fn topic_channel(topic: &str) -> Option<Uuid> {
+    topic.strip_prefix(CHANNEL_TOPIC_PREFIX)?.parse().ok()
+}
+
+/// Gossipsub with every message signed by its publisher, and messages
+/// without a valid signature dropped.
+#[cfg(feature = "native")]
+pub fn configure_gossipsub(keypair: &Keypair) -> gossipsub::Behaviour {
+    let config = gossipsub::ConfigBuilder::default()
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        .build()
+        .expect("gossipsub config should be valid");
+    gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Signed(keypair.clone()), config)
+        .expect("gossipsub should initialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topics_name_their_channel() {
+        let id = Uuid::new_v4();
+        assert_eq!(topic_channel(&channel_topic(&id)), Some(id));
+        assert_eq!(topic_channel("whisper/channel/not-a-uuid"), None);
+        assert_eq!(topic_channel(&id.to_string()), None);
+    }
+}
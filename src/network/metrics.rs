@@ -0,0 +1,132 @@
+//! Prometheus metrics.
+//!
+//! A node given a `NodeMetrics` keeps it up to date: connections, message
+//! counts and delivery times, its send queue, DHT health and traffic per
+//! protocol. The metrics live in a `Registry` the caller owns and can
+//! serve however it likes; `whisper listen --metrics` serves them over
+//! HTTP on localhost.
+
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+
+use super::stats::TrafficProtocol;
+
+/// Labels for per-protocol traffic.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ProtocolLabels {
+    pub protocol: &'static str,
+}
+
+/// Metrics a node reports. Cheap to clone; clones update the same metrics.
+#[derive(Debug, Clone)]
+pub struct NodeMetrics {
+    pub connected_peers: Gauge,
+    pub messages_sent: Counter,
+    pub messages_received: Counter,
+    pub messages_failed: Counter,
+    /// Time from sending a message to the peer acknowledging it.
+    pub delivery_seconds: Histogram,
+    /// Messages held in memory until their peer connects.
+    pub pending_messages: Gauge,
+    pub dht_routing_table_peers: Gauge,
+    pub dht_lookups_in_flight: Gauge,
+    pub bytes_sent: Family<ProtocolLabels, Counter>,
+    pub bytes_received: Family<ProtocolLabels, Counter>,
+}
+
+impl NodeMetrics {
+    /// Create the metrics and register them in `registry`.
+    pub fn register(registry: &mut Registry) -> Self {
+        let metrics = Self {
+            connected_peers: Gauge::default(),
+            messages_sent: Counter::default(),
+            messages_received: Counter::default(),
+            messages_failed: Counter::default(),
+            // 5ms to about 20s
+            delivery_seconds: Histogram::new(exponential_buckets(0.005, 2.0, 13)),
+            pending_messages: Gauge::default(),
+            dht_routing_table_peers: Gauge::default(),
+            dht_lookups_in_flight: Gauge::default(),
+            bytes_sent: Family::default(),
+            bytes_received: Family::default(),
+        };
+
+        let whisper = registry.sub_registry_with_prefix("whisper");
+        whisper.register("connected_peers", "Peers with an open connection", metrics.connected_peers.clone());
+        whisper.register("messages_sent", "Messages sent to peers", metrics.messages_sent.clone());
+        whisper.register("messages_received", "Messages received from peers", metrics.messages_received.clone());
+        whisper.register(
+            "messages_failed",
+            "Messages that could not be delivered over an open connection",
+            metrics.messages_failed.clone(),
+        );
+        whisper.register(
+            "message_delivery_seconds",
+            "Time from sending a message to the peer acknowledging it",
+            metrics.delivery_seconds.clone(),
+        );
+        whisper.register(
+            "pending_messages",
+            "Messages held in memory until their peer connects",
+            metrics.pending_messages.clone(),
+        );
+        whisper.register(
+            "dht_routing_table_peers",
+            "Peers in the Kademlia routing table",
+            metrics.dht_routing_table_peers.clone(),
+        );
+        whisper.register(
+            "dht_lookups_in_flight",
+            "DHT lookups for peers and address records in progress",
+            metrics.dht_lookups_in_flight.clone(),
+        );
+        whisper.register("bytes_sent", "Payload bytes sent, by protocol", metrics.bytes_sent.clone());
+        whisper.register("bytes_received", "Payload bytes received, by protocol", metrics.bytes_received.clone());
+        metrics
+    }
+
+    /// Count traffic sent over `protocol`.
+    pub fn record_sent(&self, protocol: TrafficProtocol, bytes: usize) {
+        let labels = ProtocolLabels { protocol: protocol.as_str() };
+        self.bytes_sent.get_or_create(&labels).inc_by(bytes as u64);
+    }
+
+    /// Count traffic received over `protocol`.
+    pub fn record_received(&self, protocol: TrafficProtocol, bytes: usize) {
+        let labels = ProtocolLabels { protocol: protocol.as_str() };
+        self.bytes_received.get_or_create(&labels).inc_by(bytes as u64);
+    }
+}
+
+/// Render `registry` in the Prometheus text format.
+pub fn encode_metrics(registry: &Registry) -> String {
+    let mut text = String::new();
+    // Writing to a String can't fail
+    let _ = prometheus_client::encoding::text::encode(&mut text, registry);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_encoded_with_prefix() {
+        let mut registry = Registry::default();
+        let metrics = NodeMetrics::register(&mut registry);
+        metrics.connected_peers.set(3);
+        metrics.messages_sent.inc();
+        metrics.delivery_seconds.observe(0.04);
+        metrics.record_sent(TrafficProtocol::FileTransfer, 4096);
+
+        let text = encode_metrics(&registry);
+        assert!(text.contains("whisper_connected_peers 3"));
+        assert!(text.contains("whisper_messages_sent_total 1"));
+        assert!(text.contains("whisper_message_delivery_seconds_count 1"));
+        assert!(text.contains("whisper_bytes_sent_total{protocol=\"files\"} 4096"));
+    }
+}
@@ -1,21 +1,83 @@
 //! P2P networking with libp2p.
 
 mod behaviour;
+mod connections;
 mod discovery;
+mod gossip;
+mod metrics;
+#[cfg(feature = "native")]
 mod node;
+mod pex;
+mod ping;
+#[cfg(feature = "native")]
+mod proxy;
+mod rate_limit;
 mod relay;
+mod rendezvous;
+mod stats;
+mod transfer;
+mod transport;
+mod username;
 
 pub use behaviour::{
-    MessageCodec, MessageRequest, MessageResponse, WhisperBehaviour, WhisperEvent,
-    WHISPER_PROTOCOL,
+    is_compatible_version, MessageCodec, MessageFrame, MessageRequest, MessageResponse, MessageTooLarge,
+    WhisperEvent, AGENT_VERSION, DEFAULT_MAX_MESSAGE_SIZE, LEGACY_WHISPER_PROTOCOL, WHISPER_PROTOCOL,
+};
+#[cfg(feature = "native")]
+pub use behaviour::WhisperBehaviour;
+pub use connections::{
+    ConnectionManager, ReconnectConfig, DEFAULT_RECONNECT_INITIAL_SECS, DEFAULT_RECONNECT_MAX_SECS,
+    MAX_ADDRESSES_PER_PEER,
 };
 pub use discovery::{
-    add_peer_address, bootstrap_kademlia, bootstrap_nodes, configure_kademlia, configure_mdns,
-    extract_peer_id, ipfs_bootstrap_nodes, is_local_address, start_peer_discovery,
-    KAD_QUERY_TIMEOUT_SECS, KAD_REPLICATION_FACTOR, MDNS_QUERY_INTERVAL_SECS,
+    address_record_key, bootstrap_nodes, configure_kademlia, decode_address_record,
+    encode_address_record, extract_peer_id, ipfs_bootstrap_nodes, is_local_address, split_peer_id,
+    ADDRESS_RECORD_REFRESH_SECS, ADDRESS_RECORD_TTL_SECS, KAD_QUERY_TIMEOUT_SECS,
+    KAD_REPLICATION_FACTOR, MDNS_QUERY_INTERVAL_SECS,
+};
+#[cfg(feature = "native")]
+pub use discovery::{add_peer_address, bootstrap_kademlia, configure_mdns, start_peer_discovery};
+pub use gossip::{channel_topic, topic_channel, CHANNEL_TOPIC_PREFIX};
+#[cfg(feature = "native")]
+pub use gossip::configure_gossipsub;
+pub use metrics::{encode_metrics, NodeMetrics, ProtocolLabels};
+#[cfg(feature = "native")]
+pub use node::{
+    NodeEvent, NodeHandle, WhisperNode, EVENT_CHANNEL_CAPACITY,
+    IDLE_CONNECTION_TIMEOUT_SECS,
+};
+pub use pex::{
+    decode_hints, encode_hints, hint_tag, AddressHint, HintBook, PexCodec, PexPolicy, PexRequest, PexResponse,
+    MAX_HINT_ADDRESSES, MAX_HINT_AGE_SECS, MAX_PEX_MESSAGE_SIZE, MAX_PEX_WANTED, PEX_PROTOCOL,
+};
+pub use ping::{
+    Latency, PingCodec, PingPayload, PingTracker, PING_INTERVAL_SECS, PING_PROTOCOL, PING_SIZE,
+};
+#[cfg(feature = "native")]
+pub use proxy::{
+    is_loopback_address, is_onion_address, socks_target, Socks5Transport, DEFAULT_TOR_PROXY,
+};
+pub use rate_limit::{
+    RateDecision, RateLimitConfig, RateLimiter, DEFAULT_GLOBAL_MESSAGES_PER_MINUTE,
+    DEFAULT_PEER_MESSAGES_PER_MINUTE, DEFAULT_RATE_LIMIT_BAN_SECS,
 };
-pub use node::{NodeEvent, WhisperNode};
 pub use relay::{
-    connect_to_relay, is_behind_nat, is_relay_address, make_relay_address, public_relays,
-    RELAY_CONNECT_TIMEOUT_SECS,
+    is_relay_address, make_relay_address, public_relays, Reachability, RELAY_CONNECT_TIMEOUT_SECS,
+};
+#[cfg(feature = "native")]
+pub use relay::connect_to_relay;
+pub use rendezvous::{
+    pair_namespace, RendezvousCodec, RendezvousRequest, RendezvousResponse, RendezvousStore,
+    MAX_NAMESPACE_LEN, MAX_REGISTRATIONS_PER_PEER, MAX_RENDEZVOUS_MESSAGE_SIZE,
+    RENDEZVOUS_PROTOCOL, RENDEZVOUS_TTL_SECS,
+};
+pub use stats::{NetworkStats, Traffic, TrafficProtocol, TRAFFIC_REPORT_SECS};
+pub use transfer::{
+    FileChunkAck, FileChunkRequest, FileCodec, TransferDirection, TransferTracker, FILE_TRANSFER_PROTOCOL,
+    MAX_FILE_REQUEST_SIZE, MAX_OPEN_TRANSFERS_PER_PEER, TRANSFER_IDLE_SECS,
+};
+pub use transport::{is_websocket_address, TransportConfig};
+pub use username::{
+    decode_username_record, encode_username_record, normalize_username, username_record_key, UsernameRecord,
+    MAX_USERNAME_LEN, MIN_USERNAME_LEN,
 };
@@ -2,15 +2,57 @@
 
 use anyhow::Result;
 use libp2p::{
+    autonat, gossipsub, identify,
     identity::Keypair,
-    mdns, noise, request_response,
-    swarm::SwarmEvent,
+    core::ConnectedPoint,
+    kad::{self, QueryId},
+    mdns, multiaddr::Protocol, noise, request_response, websocket,
+    relay,
+    core::transport::ListenerId,
+    swarm::{dial_opts::{DialOpts, PeerCondition}, ConnectionId, SwarmEvent},
+    core::{upgrade, Transport},
     tcp, yamux, Multiaddr, PeerId, Swarm, SwarmBuilder,
 };
-use std::collections::HashSet;
-use tokio::sync::mpsc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use uuid::Uuid;
 
-use super::behaviour::{MessageRequest, MessageResponse, WhisperBehaviour, WhisperBehaviourEvent};
+use super::behaviour::{
+    is_compatible_version, MessageCodec, MessageRequest, MessageResponse, WhisperBehaviour,
+    WhisperBehaviourEvent,
+};
+use super::connections::{ConnectionManager, ReconnectConfig};
+use super::gossip::{channel_topic, topic_channel};
+use super::discovery::{
+    address_record_key, bootstrap_kademlia, bootstrap_nodes, decode_address_record, encode_address_record,
+    is_local_address, split_peer_id, start_peer_discovery, ADDRESS_RECORD_REFRESH_SECS, ADDRESS_RECORD_TTL_SECS,
+};
+use super::metrics::NodeMetrics;
+use super::pex::{decode_hints, encode_hints, hint_tag, HintBook, PexPolicy, PexRequest, PexResponse, MAX_PEX_WANTED};
+use super::ping::{Latency, PingPayload, PingTracker, PING_INTERVAL_SECS, PING_SIZE};
+use super::proxy::{is_loopback_address, Socks5Transport};
+use super::rate_limit::{RateDecision, RateLimitConfig, RateLimiter};
+use super::relay::{connect_to_relay, is_relay_address, public_relays, Reachability};
+use super::rendezvous::{
+    pair_namespace, RendezvousRequest, RendezvousResponse, RendezvousStore, RENDEZVOUS_TTL_SECS,
+};
+use super::stats::{NetworkStats, TrafficProtocol, TRAFFIC_REPORT_SECS};
+use super::transfer::{FileChunkAck, FileChunkRequest, TransferDirection, TransferTracker};
+use super::transport::TransportConfig;
+use super::username::{
+    decode_username_record, encode_username_record, normalize_username, username_record_key, UsernameRecord,
+};
+
+/// How long an idle connection stays open, in seconds.
+pub const IDLE_CONNECTION_TIMEOUT_SECS: u64 = 60;
+
+/// Capacity of the node event broadcast channel.
+pub const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// Wait after our addresses change before publishing them, in seconds, so
+/// listeners coming up together go out in one record.
+pub const ADDRESS_PUBLISH_DELAY_SECS: u64 = 5;
 
 /// Events emitted by the network node.
 #[derive(Debug, Clone)]
@@ -25,6 +67,55 @@ pub enum NodeEvent {
     MessageSent { to: PeerId },
     /// Listening on an address.
     Listening(Multiaddr),
+    /// An encrypted file chunk was received from a peer.
+    FileChunkReceived { from: PeerId, transfer_id: Uuid, chunk_index: u32, data: Vec<u8> },
+    /// A file transfer made progress.
+    TransferProgress {
+        peer: PeerId,
+        transfer_id: Uuid,
+        chunks_done: u32,
+        total_chunks: u32,
+        direction: TransferDirection,
+    },
+    /// AutoNAT changed its view of our reachability.
+    ReachabilityChanged(Reachability),
+    /// A peer's message was dropped for going over an inbound rate limit.
+    /// Everything it sends is ignored for `ignored_for`, which is zero when
+    /// only the global limit was hit.
+    RateLimited { peer: PeerId, ignored_for: Duration },
+    /// A peer was seen at an address, found by mDNS or, with `reached`,
+    /// connected to through it. Worth storing so the peer can be dialed
+    /// there after a restart.
+    PeerAddress { peer: PeerId, address: Multiaddr, reached: bool },
+    /// A ping to a connected peer came back.
+    Latency { peer: PeerId, latency: Latency },
+    /// Traffic since the last report, reported every
+    /// `TRAFFIC_REPORT_SECS` when there was any.
+    Traffic(NetworkStats),
+    /// A peer broke the message protocol, as by sending a message over
+    /// the size limit. What it sent was refused.
+    ProtocolViolation { peer: PeerId, reason: String },
+    /// A peer told us, over identify, which versions it runs. One that
+    /// isn't `compatible` speaks another major version of the protocol and
+    /// has been disconnected.
+    PeerIdentified { peer: PeerId, protocol_version: String, agent_version: String, compatible: bool },
+    /// The startup bootstrap finished with `peers` in the DHT routing
+    /// table, so lookups and address records can go through it.
+    DhtReady { peers: usize },
+    /// A relay agreed to relay connections to us, or, with `renewal`,
+    /// extended its agreement. We're listening on a circuit through it.
+    RelayReservation { relay: PeerId, renewal: bool },
+    /// We stopped listening through a relay. It's asked again when it
+    /// reconnects.
+    RelayReservationLost { relay: PeerId },
+    /// A post to a channel we joined came in over gossip, relayed by
+    /// `from`. Anyone on the topic can relay one, so it's only the
+    /// channel's if its owner signed it.
+    ChannelPostReceived { from: PeerId, channel_id: Uuid, data: Vec<u8> },
+    /// A peer joined the topic of a channel we're on or publishing to.
+    ChannelJoined { peer: PeerId, channel_id: Uuid },
+    /// A post went out to the peers on its channel's topic.
+    PostPublished { channel_id: Uuid },
 }
 
 /// The main Whisper network node.
@@ -33,36 +124,200 @@ pub struct WhisperNode {
     swarm: Swarm<WhisperBehaviour>,
     /// Our peer ID.
     peer_id: PeerId,
+    /// Our identity, for signing the address records we publish.
+    keypair: Keypair,
     /// Connected peers.
     connected_peers: HashSet<PeerId>,
     /// Pending message sends.
     pending_sends: Vec<(PeerId, Vec<u8>)>,
+    /// Pending file chunk sends.
+    pending_chunks: Vec<(PeerId, FileChunkRequest)>,
+    /// In-flight outgoing chunks: request ID to (transfer ID, total chunks).
+    outbound_chunks: HashMap<request_response::OutboundRequestId, (Uuid, u32)>,
+    /// Chunks completed per active transfer.
+    transfer_progress: TransferTracker,
+    /// Events produced alongside another event, returned on the next poll.
+    queued_events: VecDeque<NodeEvent>,
+    /// Reachability as last reported by AutoNAT.
+    reachability: Reachability,
+    /// Limits on inbound messages.
+    rate_limiter: RateLimiter,
+    /// Known addresses and redial schedule.
+    connections: ConnectionManager,
+    /// DHT lookups in flight, and the peer each is looking for.
+    lookups: HashMap<QueryId, PeerId>,
+    /// Address record fetches in flight, and whose record each wants.
+    record_lookups: HashMap<QueryId, PeerId>,
+    /// When to next publish our address record.
+    publish_at: Option<Instant>,
+    /// The rendezvous point we register with and look peers up at.
+    rendezvous_point: Option<PeerId>,
+    /// Registrations we hold for others, when acting as a rendezvous point.
+    rendezvous_store: Option<RendezvousStore>,
+    /// Rendezvous lookups in flight, and the peer each is looking for.
+    rendezvous_discovers: HashMap<request_response::OutboundRequestId, PeerId>,
+    /// Pings waiting for their echo.
+    pings: PingTracker<request_response::OutboundRequestId>,
+    /// When to next ping connected peers.
+    ping_at: Option<Instant>,
+    /// Last round trip to each connected peer.
+    latencies: HashMap<PeerId, Latency>,
+    /// Peers speaking an incompatible protocol version, not redialed.
+    incompatible_peers: HashSet<PeerId>,
+    /// Open connections, whose peer they're to and whether they're relayed.
+    connection_paths: HashMap<ConnectionId, (PeerId, bool)>,
+    /// Traffic since the node started.
+    stats: NetworkStats,
+    /// Traffic not yet reported as `NodeEvent::Traffic`.
+    unreported: NetworkStats,
+    /// When to next report traffic.
+    report_at: Instant,
+    /// Prometheus metrics to keep up to date, if any.
+    metrics: Option<NodeMetrics>,
+    /// Messages sent and not yet acknowledged, and when each went out.
+    message_sends: HashMap<request_response::OutboundRequestId, Instant>,
+    /// Explicit dials waiting to hear who answered.
+    pending_connects: HashMap<ConnectionId, oneshot::Sender<Result<PeerId>>>,
+    /// Every event is also broadcast here for subscribers.
+    events: broadcast::Sender<NodeEvent>,
+    /// How the node reaches the network.
+    transport: TransportConfig,
+    /// The message codec, shared with the swarm, for its size limit.
+    codec: MessageCodec,
+    /// Nodes the DHT is bootstrapped from when the node starts.
+    bootstrap_nodes: Vec<Multiaddr>,
+    /// The startup bootstrap query, until it finishes.
+    bootstrap_query: Option<QueryId>,
+    /// Relays we listen through when the node starts.
+    relays: Vec<Multiaddr>,
+    /// Circuit listeners, and the relay each is through.
+    relay_listeners: HashMap<ListenerId, PeerId>,
+    /// Who we trade address hints with, and about whom.
+    pex_policy: PexPolicy,
+    /// Peers we hang up on and refuse to hear from.
+    blocked_peers: HashSet<PeerId>,
+    /// Where we've reached contacts, for hints.
+    hint_book: HintBook,
+    /// Hint requests in flight, and the peers each asked about.
+    pex_requests: HashMap<request_response::OutboundRequestId, HashSet<PeerId>>,
+    /// The username we publish, if any.
+    username: Option<String>,
+    /// Username lookups in flight.
+    username_lookups: HashMap<QueryId, UsernameLookup>,
+    /// Channel posts waiting for a peer on their channel's topic.
+    pending_posts: Vec<(Uuid, Vec<u8>)>,
+}
+
+/// A username lookup: the valid claims found so far, and who to tell.
+struct UsernameLookup {
+    username: String,
+    found: Vec<UsernameRecord>,
+    reply: oneshot::Sender<Result<Vec<UsernameRecord>>>,
 }
 
 impl WhisperNode {
     /// Create a new WhisperNode with the given keypair.
     pub async fn new(keypair: Keypair) -> Result<Self> {
+        Self::with_transport(keypair, TransportConfig::default()).await
+    }
+
+    /// Create a new WhisperNode that reaches the network as `transport` says.
+    pub async fn with_transport(keypair: Keypair, transport: TransportConfig) -> Result<Self> {
         let peer_id = PeerId::from(keypair.public());
+        let codec = MessageCodec::default();
 
         // Build the swarm
-        let swarm = SwarmBuilder::with_existing_identity(keypair)
-            .with_tokio()
-            .with_tcp(
-                tcp::Config::default(),
-                noise::Config::new,
-                yamux::Config::default,
-            )?
-            .with_relay_client(noise::Config::new, yamux::Config::default)?
-            .with_behaviour(|keypair, relay_client| {
-                WhisperBehaviour::new(PeerId::from(keypair.public()), relay_client)
-            })?
-            .build();
+        let swarm = match transport.socks_proxy {
+            None => SwarmBuilder::with_existing_identity(keypair.clone())
+                .with_tokio()
+                .with_tcp(
+                    tcp::Config::default(),
+                    noise::Config::new,
+                    yamux::Config::default,
+                )?
+                .with_websocket(noise::Config::new, yamux::Config::default)
+                .await?
+                .with_relay_client(noise::Config::new, yamux::Config::default)?
+                .with_behaviour(|keypair, relay_client| {
+                    WhisperBehaviour::new(keypair, relay_client, codec.clone())
+                })?
+                // Keep idle connections open long enough for requests to start
+                .with_swarm_config(|config| {
+                    config.with_idle_connection_timeout(Duration::from_secs(IDLE_CONNECTION_TIMEOUT_SECS))
+                })
+                .build(),
+            Some(proxy) => SwarmBuilder::with_existing_identity(keypair.clone())
+                .with_tokio()
+                .with_other_transport(|keypair| {
+                    // Dials go through the proxy; plain TCP is only for
+                    // listening, which `listen_on` keeps to loopback
+                    let stream = || {
+                        Socks5Transport::new(proxy)
+                            .or_transport(tcp::tokio::Transport::new(tcp::Config::default()))
+                            .map(|either, _| either.into_inner())
+                    };
+                    Ok::<_, Box<dyn std::error::Error + Send + Sync>>(
+                        websocket::WsConfig::new(stream())
+                            .or_transport(stream())
+                            .upgrade(upgrade::Version::V1Lazy)
+                            .authenticate(noise::Config::new(keypair)?)
+                            .multiplex(yamux::Config::default()),
+                    )
+                })?
+                .with_relay_client(noise::Config::new, yamux::Config::default)?
+                .with_behaviour(|keypair, relay_client| {
+                    WhisperBehaviour::new(keypair, relay_client, codec.clone()).without_mdns()
+                })?
+                .with_swarm_config(|config| {
+                    config.with_idle_connection_timeout(Duration::from_secs(IDLE_CONNECTION_TIMEOUT_SECS))
+                })
+                .build(),
+        };
 
         Ok(Self {
             swarm,
             peer_id,
+            keypair,
+            transport,
+            codec,
+            bootstrap_nodes: bootstrap_nodes(&[]),
+            bootstrap_query: None,
+            relays: public_relays(&[]),
+            relay_listeners: HashMap::new(),
+            pex_policy: PexPolicy::default(),
+            blocked_peers: HashSet::new(),
+            hint_book: HintBook::default(),
+            pex_requests: HashMap::new(),
+            username: None,
+            username_lookups: HashMap::new(),
+            pending_posts: Vec::new(),
             connected_peers: HashSet::new(),
             pending_sends: Vec::new(),
+            pending_chunks: Vec::new(),
+            outbound_chunks: HashMap::new(),
+            transfer_progress: TransferTracker::default(),
+            queued_events: VecDeque::new(),
+            reachability: Reachability::Unknown,
+            rate_limiter: RateLimiter::default(),
+            connections: ConnectionManager::default(),
+            lookups: HashMap::new(),
+            record_lookups: HashMap::new(),
+            publish_at: None,
+            rendezvous_point: None,
+            rendezvous_store: None,
+            rendezvous_discovers: HashMap::new(),
+            pings: PingTracker::default(),
+            ping_at: None,
+            latencies: HashMap::new(),
+            incompatible_peers: HashSet::new(),
+            connection_paths: HashMap::new(),
+            stats: NetworkStats::default(),
+            unreported: NetworkStats::default(),
+            report_at: Instant::now() + Duration::from_secs(TRAFFIC_REPORT_SECS),
+            metrics: None,
+            message_sends: HashMap::new(),
+            pending_connects: HashMap::new(),
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
         })
     }
 
@@ -81,9 +336,568 @@ impl WhisperNode {
         self.connected_peers.contains(peer_id)
     }
 
-    /// Listen on an address.
+    /// Our reachability as last reported by AutoNAT.
+    pub fn reachability(&self) -> Reachability {
+        self.reachability
+    }
+
+    /// Change the inbound rate limits. Buckets start full again.
+    pub fn set_rate_limit(&mut self, config: RateLimitConfig) {
+        self.rate_limiter = RateLimiter::new(config);
+    }
+
+    /// The inbound rate limits in force.
+    pub fn rate_limit(&self) -> RateLimitConfig {
+        self.rate_limiter.config()
+    }
+
+    /// Change the largest message we send or accept, in bytes. Peers
+    /// sending more get `NodeEvent::ProtocolViolation`.
+    pub fn set_max_message_size(&mut self, bytes: usize) {
+        self.codec.set_max_message_size(bytes);
+    }
+
+    /// The largest message we send or accept, in bytes.
+    pub fn max_message_size(&self) -> usize {
+        self.codec.max_message_size()
+    }
+
+    /// Change the nodes the DHT is bootstrapped from when the node
+    /// starts. Each must end with its peer ID.
+    pub fn set_bootstrap_nodes(&mut self, nodes: Vec<Multiaddr>) {
+        self.bootstrap_nodes = nodes;
+    }
+
+    /// The nodes the DHT is bootstrapped from when the node starts.
+    pub fn bootstrap_nodes(&self) -> &[Multiaddr] {
+        &self.bootstrap_nodes
+    }
+
+    /// Change the relays listened through when the node starts. Each must
+    /// end with its peer ID.
+    pub fn set_relays(&mut self, relays: Vec<Multiaddr>) {
+        self.relays = relays;
+    }
+
+    /// The relays listened through when the node starts.
+    pub fn relays(&self) -> &[Multiaddr] {
+        &self.relays
+    }
+
+    /// Ask each relay for a reservation and listen on a circuit through
+    /// it, so peers can reach us behind NAT. Relays are kept connected:
+    /// libp2p renews a reservation while its connection is up, and one
+    /// that's lost is asked for again when the relay reconnects.
+    pub fn listen_via_relays(&mut self) {
+        for relay in self.relays.clone() {
+            let _ = self.listen_via_relay(relay);
+        }
+    }
+
+    fn listen_via_relay(&mut self, relay: Multiaddr) -> Result<()> {
+        let (addr, relay_peer) = split_peer_id(&relay);
+        let relay_peer = relay_peer.ok_or_else(|| anyhow::anyhow!("Relay address must end with /p2p/<peer id>"))?;
+        if self.relay_listeners.values().any(|listening| *listening == relay_peer) {
+            return Ok(());
+        }
+        connect_to_relay(self, relay.clone())?;
+        self.add_address(&relay_peer, addr);
+        self.watch_peer(relay_peer);
+        let listener = self.swarm.listen_on(relay.with(Protocol::P2pCircuit))?;
+        self.relay_listeners.insert(listener, relay_peer);
+        Ok(())
+    }
+
+    /// A relay we lost our reservation with is back: ask again.
+    fn relay_connected(&mut self, peer_id: PeerId) {
+        let relay = self.relays.iter().find(|relay| split_peer_id(relay).1 == Some(peer_id)).cloned();
+        if let Some(relay) = relay {
+            let _ = self.listen_via_relay(relay);
+        }
+    }
+
+    /// Add the bootstrap nodes to the DHT and start looking for peers
+    /// through them; `NodeEvent::DhtReady` follows once it's done. Does
+    /// nothing without bootstrap nodes, as on a local-only network.
+    pub fn bootstrap(&mut self) {
+        if let Ok(query) = bootstrap_kademlia(self) {
+            self.bootstrap_query = Some(query);
+        }
+    }
+
+    fn bootstrap_progressed(&mut self, id: QueryId, last: bool) {
+        if !last || self.bootstrap_query != Some(id) {
+            return;
+        }
+        self.bootstrap_query = None;
+        let peers = self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .kbuckets()
+            .map(|bucket| bucket.num_entries())
+            .sum();
+        if peers > 0 {
+            self.publish_username();
+            self.queued_events.push_back(NodeEvent::DhtReady { peers });
+        }
+    }
+
+    /// Claim `username` in the DHT, or with `None`, stop republishing our
+    /// claim so it expires.
+    pub fn set_username(&mut self, username: Option<&str>) -> Result<()> {
+        self.username = username.map(normalize_username).transpose()?;
+        self.publish_username();
+        Ok(())
+    }
+
+    /// The username we publish, if any.
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    /// Put our signed claim to our username into the DHT. It's renewed
+    /// along with our address record.
+    fn publish_username(&mut self) {
+        let Some(username) = &self.username else {
+            return;
+        };
+        let Ok(value) = encode_username_record(&self.keypair, username, unix_now()) else {
+            return;
+        };
+        let mut record = kad::Record::new(username_record_key(username), value);
+        record.publisher = Some(self.peer_id);
+        record.expires = Some(Instant::now() + Duration::from_secs(ADDRESS_RECORD_TTL_SECS));
+        let _ = self.swarm.behaviour_mut().kademlia.put_record(record, kad::Quorum::One);
+    }
+
+    /// Find who claims `username`, and report every valid claim through
+    /// `reply` once the DHT has been searched.
+    pub fn lookup_username(&mut self, username: &str, reply: oneshot::Sender<Result<Vec<UsernameRecord>>>) {
+        let username = match normalize_username(username) {
+            Ok(username) => username,
+            Err(e) => {
+                let _ = reply.send(Err(e));
+                return;
+            }
+        };
+        let query = self.swarm.behaviour_mut().kademlia.get_record(username_record_key(&username));
+        self.username_lookups.insert(query, UsernameLookup { username, found: Vec::new(), reply });
+    }
+
+    /// A username lookup made progress. Claims that aren't signed for the
+    /// name are dropped.
+    fn username_lookup_progressed(&mut self, id: QueryId, result: kad::GetRecordResult, last: bool) {
+        let Some(lookup) = self.username_lookups.get_mut(&id) else {
+            return;
+        };
+        if let Ok(kad::GetRecordOk::FoundRecord(found)) = result {
+            if let Ok(record) = decode_username_record(&lookup.username, &found.record.value) {
+                if !lookup.found.iter().any(|r| r.peer_id == record.peer_id) {
+                    lookup.found.push(record);
+                }
+            }
+        }
+        if last {
+            if let Some(lookup) = self.username_lookups.remove(&id) {
+                let _ = lookup.reply.send(Ok(lookup.found));
+            }
+        }
+    }
+
+    /// Change how eagerly peers are redialed. Known addresses and watched
+    /// peers are kept; dials already scheduled are dropped.
+    pub fn set_reconnect(&mut self, config: ReconnectConfig) {
+        self.connections.set_config(config);
+    }
+
+    /// The redial settings in force.
+    pub fn reconnect(&self) -> ReconnectConfig {
+        self.connections.config()
+    }
+
+    /// Where a peer was last reached, most recent first.
+    pub fn known_addresses(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.connections.addresses(peer_id)
+    }
+
+    /// Keep a connection to a peer, redialing with backoff whenever it
+    /// drops. Used for contacts.
+    pub fn watch_peer(&mut self, peer_id: PeerId) {
+        self.connections.watch(peer_id);
+    }
+
+    /// Dial a peer, with backoff until it connects, unless it's connected.
+    pub fn connect_peer(&mut self, peer_id: PeerId) {
+        self.want_peer(peer_id);
+    }
+
+    /// We need a connection to `peer_id`: schedule a dial, and if we don't
+    /// know anywhere to dial, ask the DHT where they are, both for the
+    /// address record they published and for their routing table entry.
+    fn want_peer(&mut self, peer_id: PeerId) {
+        if self.connected_peers.contains(&peer_id) {
+            return;
+        }
+        self.connections.want(peer_id, Instant::now());
+        if !self.connections.addresses(&peer_id).is_empty() {
+            return;
+        }
+        self.rendezvous_discover(peer_id);
+        if !self.is_looking_up(&peer_id) {
+            let query = start_peer_discovery(self, peer_id);
+            self.lookups.insert(query, peer_id);
+            let query = self.swarm.behaviour_mut().kademlia.get_record(address_record_key(&peer_id));
+            self.record_lookups.insert(query, peer_id);
+        }
+    }
+
+    /// Register with, and look peers up at, the rendezvous point at
+    /// `addr`, which must end with the point's peer ID. The node keeps a
+    /// connection to it.
+    pub fn set_rendezvous_point(&mut self, addr: Multiaddr) -> Result<()> {
+        let (addr, point) = split_peer_id(&addr);
+        let point = point.ok_or_else(|| anyhow::anyhow!("Rendezvous address must end with /p2p/<peer id>"))?;
+        self.rendezvous_point = Some(point);
+        self.add_address(&point, addr);
+        self.watch_peer(point);
+        if self.connected_peers.contains(&point) {
+            self.rendezvous_connected();
+        } else {
+            self.want_peer(point);
+        }
+        Ok(())
+    }
+
+    /// The rendezvous point in use, if any.
+    pub fn rendezvous_point(&self) -> Option<PeerId> {
+        self.rendezvous_point
+    }
+
+    /// Trade address hints as `policy` says, starting with the trusted
+    /// contacts already connected.
+    pub fn set_pex_policy(&mut self, policy: PexPolicy) {
+        self.pex_policy = policy;
+        for peer_id in self.connected_peers() {
+            self.ask_for_hints(peer_id);
+        }
+    }
+
+    /// Who we trade address hints with, and about whom.
+    pub fn pex_policy(&self) -> &PexPolicy {
+        &self.pex_policy
+    }
+
+    /// Refuse to talk to `peers`, hanging up on any already connected.
+    pub fn set_blocked_peers(&mut self, peers: HashSet<PeerId>) {
+        for peer_id in &peers {
+            let _ = self.swarm.disconnect_peer_id(*peer_id);
+        }
+        self.blocked_peers = peers;
+    }
+
+    /// Peers we refuse to talk to.
+    pub fn blocked_peers(&self) -> &HashSet<PeerId> {
+        &self.blocked_peers
+    }
+
+    /// Join a channel's topic, to hear its posts and pass them on.
+    pub fn join_channel(&mut self, channel_id: &Uuid) {
+        let topic = gossipsub::IdentTopic::new(channel_topic(channel_id));
+        // Only filtered topics are refused, and we filter none
+        let _ = self.swarm.behaviour_mut().gossipsub.subscribe(&topic);
+    }
+
+    /// Publish a post to a channel's topic. With no peer on the topic
+    /// yet, it waits for one to join. `NodeEvent::PostPublished` follows
+    /// once it's out.
+    pub fn publish_post(&mut self, channel_id: Uuid, data: Vec<u8>) {
+        let topic = gossipsub::IdentTopic::new(channel_topic(&channel_id));
+        match self.swarm.behaviour_mut().gossipsub.publish(topic, data.clone()) {
+            Ok(_) => self.queued_events.push_back(NodeEvent::PostPublished { channel_id }),
+            Err(gossipsub::PublishError::InsufficientPeers) => self.pending_posts.push((channel_id, data)),
+            // Too large, or already out
+            Err(_) => {}
+        }
+    }
+
+    /// Get number of channel posts waiting for a peer on their topic.
+    pub fn pending_post_count(&self) -> usize {
+        self.pending_posts.len()
+    }
+
+    /// Someone joined a channel's topic: publish what was waiting for them.
+    fn publish_pending_posts(&mut self, channel_id: Uuid) {
+        let (due, waiting) = std::mem::take(&mut self.pending_posts)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(id, _)| *id == channel_id);
+        self.pending_posts = waiting;
+        for (channel_id, data) in due {
+            self.publish_post(channel_id, data);
+        }
+    }
+
+    /// Ask a trusted contact where the contacts we aren't connected to
+    /// are.
+    fn ask_for_hints(&mut self, peer_id: PeerId) {
+        if !self.pex_policy.trusted.contains(&peer_id) {
+            return;
+        }
+        let asked: HashSet<PeerId> = self
+            .connections
+            .watched()
+            .into_iter()
+            .filter(|p| *p != peer_id && !self.connected_peers.contains(p))
+            .take(MAX_PEX_WANTED)
+            .collect();
+        if asked.is_empty() {
+            return;
+        }
+        let request = PexRequest { wanted: asked.iter().map(|p| hint_tag(&self.peer_id, p)).collect() };
+        self.count_sent(peer_id, TrafficProtocol::Pex, encoded_size(&request));
+        let id = self.swarm.behaviour_mut().pex.send_request(&peer_id, request);
+        self.pex_requests.insert(id, asked);
+    }
+
+    /// Act as a rendezvous point: hold registrations for anyone who asks.
+    pub fn serve_rendezvous(&mut self) {
+        self.rendezvous_store.get_or_insert_with(RendezvousStore::default);
+    }
+
+    /// The rendezvous point, if we're connected to it.
+    fn connected_rendezvous_point(&self) -> Option<PeerId> {
+        self.rendezvous_point.filter(|point| self.connected_peers.contains(point))
+    }
+
+    /// We reached the rendezvous point: register, and ask after anyone we
+    /// are trying to dial.
+    fn rendezvous_connected(&mut self) {
+        self.register_rendezvous();
+        for peer_id in self.connections.scheduled() {
+            self.rendezvous_discover(peer_id);
+        }
+    }
+
+    /// Register our signed address record at the rendezvous point, under
+    /// the namespace we share with each watched peer.
+    fn register_rendezvous(&mut self) {
+        let Some(point) = self.connected_rendezvous_point() else {
+            return;
+        };
+        let addrs = self.public_addresses();
+        if addrs.is_empty() {
+            return;
+        }
+        let Ok(record) = encode_address_record(&self.keypair, addrs) else {
+            return;
+        };
+        for peer_id in self.connections.watched() {
+            if peer_id == point {
+                continue;
+            }
+            let request = RendezvousRequest::Register {
+                namespace: pair_namespace(&self.peer_id, &peer_id),
+                record: record.clone(),
+                ttl: RENDEZVOUS_TTL_SECS,
+            };
+            self.count_sent(point, TrafficProtocol::Rendezvous, encoded_size(&request));
+            self.swarm.behaviour_mut().rendezvous.send_request(&point, request);
+        }
+    }
+
+    /// Ask the rendezvous point where `peer_id` is, unless we're already
+    /// asking.
+    fn rendezvous_discover(&mut self, peer_id: PeerId) {
+        let Some(point) = self.connected_rendezvous_point() else {
+            return;
+        };
+        if peer_id == point || self.rendezvous_discovers.values().any(|p| *p == peer_id) {
+            return;
+        }
+        let request = RendezvousRequest::Discover { namespace: pair_namespace(&self.peer_id, &peer_id) };
+        self.count_sent(point, TrafficProtocol::Rendezvous, encoded_size(&request));
+        let id = self.swarm.behaviour_mut().rendezvous.send_request(&point, request);
+        self.rendezvous_discovers.insert(id, peer_id);
+    }
+
+    /// Whether a DHT lookup for `peer_id` is in flight.
+    pub fn is_looking_up(&self, peer_id: &PeerId) -> bool {
+        self.lookups.values().chain(self.record_lookups.values()).any(|p| p == peer_id)
+    }
+
+    /// Our addresses worth publishing: those AutoNAT confirmed, and any
+    /// listener that isn't on a private network.
+    pub fn public_addresses(&self) -> Vec<Multiaddr> {
+        let mut addrs: Vec<Multiaddr> = self.swarm.external_addresses().cloned().collect();
+        for addr in self.swarm.listeners() {
+            if !is_local_address(addr) && !addrs.contains(addr) {
+                addrs.push(addr.clone());
+            }
+        }
+        addrs
+    }
+
+    /// Our addresses changed: publish them again shortly.
+    fn addresses_changed(&mut self) {
+        let soon = Instant::now() + Duration::from_secs(ADDRESS_PUBLISH_DELAY_SECS);
+        self.publish_at = Some(self.publish_at.map_or(soon, |at| at.min(soon)));
+    }
+
+    /// Put a signed record of our public addresses into the DHT, so
+    /// contacts can find us without a rendezvous. Nothing is published
+    /// until we have an address someone else could dial.
+    fn publish_addresses(&mut self) {
+        self.publish_at = Some(Instant::now() + Duration::from_secs(ADDRESS_RECORD_REFRESH_SECS));
+        self.publish_username();
+        let addrs = self.public_addresses();
+        if addrs.is_empty() {
+            return;
+        }
+        let Ok(value) = encode_address_record(&self.keypair, addrs) else {
+            return;
+        };
+        let mut record = kad::Record::new(address_record_key(&self.peer_id), value);
+        record.publisher = Some(self.peer_id);
+        record.expires = Some(Instant::now() + Duration::from_secs(ADDRESS_RECORD_TTL_SECS));
+        // Stored locally even if no other DHT node takes it yet
+        let _ = self.swarm.behaviour_mut().kademlia.put_record(record, kad::Quorum::One);
+        self.register_rendezvous();
+    }
+
+    /// Publish our addresses if it's time.
+    fn publish_due(&mut self) {
+        if self.publish_at.is_some_and(|at| at <= Instant::now()) {
+            self.publish_addresses();
+        }
+    }
+
+    /// Traffic since the node started, per peer and protocol.
+    pub fn stats(&self) -> &NetworkStats {
+        &self.stats
+    }
+
+    fn count_sent(&mut self, peer: PeerId, protocol: TrafficProtocol, bytes: usize) {
+        self.stats.record_sent(peer, protocol, bytes);
+        self.unreported.record_sent(peer, protocol, bytes);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_sent(protocol, bytes);
+        }
+    }
+
+    fn count_received(&mut self, peer: PeerId, protocol: TrafficProtocol, bytes: usize) {
+        self.stats.record_received(peer, protocol, bytes);
+        self.unreported.record_received(peer, protocol, bytes);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_received(protocol, bytes);
+        }
+    }
+
+    /// Keep `metrics` up to date from now on.
+    pub fn set_metrics(&mut self, metrics: NodeMetrics) {
+        self.metrics = Some(metrics);
+        self.update_gauges();
+    }
+
+    /// Bring the metrics' gauges up to date.
+    fn update_gauges(&mut self) {
+        if self.metrics.is_none() {
+            return;
+        }
+        let routing_peers: usize = self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .kbuckets()
+            .map(|bucket| bucket.num_entries())
+            .sum();
+        let lookups = self.lookups.len() + self.record_lookups.len();
+        if let Some(metrics) = &self.metrics {
+            metrics.connected_peers.set(self.connected_peers.len() as i64);
+            metrics.pending_messages.set(self.pending_sends.len() as i64);
+            metrics.dht_routing_table_peers.set(routing_peers as i64);
+            metrics.dht_lookups_in_flight.set(lookups as i64);
+        }
+    }
+
+    /// Send a message request and note when it went out.
+    fn start_message_request(&mut self, peer_id: PeerId, data: Vec<u8>) {
+        self.count_sent(peer_id, TrafficProtocol::Messages, data.len());
+        let id = self
+            .swarm
+            .behaviour_mut()
+            .request_response
+            .send_request(&peer_id, Ok(MessageRequest(data)));
+        self.message_sends.insert(id, Instant::now());
+        if let Some(metrics) = &self.metrics {
+            metrics.messages_sent.inc();
+        }
+    }
+
+    /// Queue a report of new traffic if it's time.
+    fn report_due(&mut self) {
+        if self.report_at > Instant::now() {
+            return;
+        }
+        self.report_at = Instant::now() + Duration::from_secs(TRAFFIC_REPORT_SECS);
+        if !self.unreported.is_empty() {
+            let traffic = std::mem::take(&mut self.unreported);
+            self.queued_events.push_back(NodeEvent::Traffic(traffic));
+        }
+    }
+
+    /// The last measured round trip to a connected peer.
+    pub fn latency(&self, peer_id: &PeerId) -> Option<Latency> {
+        self.latencies.get(peer_id).copied()
+    }
+
+    /// Whether every open connection to `peer_id` goes through a relay.
+    fn is_relayed(&self, peer_id: &PeerId) -> bool {
+        let mut paths = self.connection_paths.values().filter(|(p, _)| p == peer_id).peekable();
+        paths.peek().is_some() && paths.all(|(_, relayed)| *relayed)
+    }
+
+    /// Ping `peer_id`, unless a ping to them is already out.
+    fn ping(&mut self, peer_id: PeerId) {
+        if self.pings.is_pinging(&peer_id) {
+            return;
+        }
+        let payload = PingPayload::random();
+        self.count_sent(peer_id, TrafficProtocol::Ping, PING_SIZE);
+        let id = self.swarm.behaviour_mut().ping.send_request(&peer_id, payload);
+        self.pings.sent(id, peer_id, payload, Instant::now());
+    }
+
+    /// Ping every connected peer if it's time.
+    fn ping_due(&mut self) {
+        if self.ping_at.is_none_or(|at| at > Instant::now()) {
+            return;
+        }
+        for peer_id in self.connected_peers() {
+            self.ping(peer_id);
+        }
+        self.ping_at = (!self.connected_peers.is_empty())
+            .then(|| Instant::now() + Duration::from_secs(PING_INTERVAL_SECS));
+    }
+
+    /// How the node reaches the network.
+    pub fn transport(&self) -> TransportConfig {
+        self.transport
+    }
+
+    /// Listen on an address. With a WebSocket port configured, listening
+    /// on a TCP address also listens for WebSocket connections on the
+    /// same IP. Behind a proxy only loopback and relay addresses are
+    /// listened on; others are skipped, so the node never accepts direct
+    /// connections from the network.
     pub fn listen_on(&mut self, addr: Multiaddr) -> Result<()> {
+        if self.transport.is_proxied() && !is_relay_address(&addr) && !is_loopback_address(&addr) {
+            return Ok(());
+        }
+        let websocket = self.transport.websocket_address(&addr);
         self.swarm.listen_on(addr)?;
+        if let Some(websocket) = websocket {
+            self.swarm.listen_on(websocket)?;
+        }
         Ok(())
     }
 
@@ -93,20 +907,89 @@ impl WhisperNode {
         Ok(())
     }
 
+    /// Dial `addr` and report, through `reply`, the peer that completed the
+    /// handshake or why none did. An address ending in `/p2p/<peer id>`
+    /// only succeeds if that peer answers.
+    fn connect(&mut self, addr: Multiaddr, reply: oneshot::Sender<Result<PeerId>>) {
+        let opts = match split_peer_id(&addr) {
+            (_, Some(peer_id)) => DialOpts::peer_id(peer_id)
+                .addresses(vec![addr])
+                .condition(PeerCondition::Always)
+                .build(),
+            (_, None) => DialOpts::unknown_peer_id().address(addr).build(),
+        };
+        let connection_id = opts.connection_id();
+        match self.swarm.dial(opts) {
+            Ok(()) => {
+                self.pending_connects.insert(connection_id, reply);
+            }
+            Err(e) => {
+                let _ = reply.send(Err(anyhow::anyhow!("Failed to dial: {}", e)));
+            }
+        }
+    }
+
     /// Queue a message to send to a peer.
     pub fn send_message(&mut self, peer_id: PeerId, data: Vec<u8>) {
         if self.connected_peers.contains(&peer_id) {
             // Send immediately using request-response
-            self.swarm
-                .behaviour_mut()
-                .request_response
-                .send_request(&peer_id, MessageRequest(data));
+            self.start_message_request(peer_id, data);
         } else {
-            // Queue for later
+            // Queue for later, and go and find them
             self.pending_sends.push((peer_id, data));
+            self.want_peer(peer_id);
+            self.update_gauges();
+        }
+    }
+
+    /// Queue a file chunk to send to a peer over the file transfer protocol.
+    pub fn send_file_chunk(&mut self, peer_id: PeerId, chunk: FileChunkRequest) {
+        if self.connected_peers.contains(&peer_id) {
+            self.start_chunk_request(peer_id, chunk);
+        } else {
+            self.pending_chunks.push((peer_id, chunk));
+            self.want_peer(peer_id);
         }
     }
 
+    /// Send a chunk request and remember which transfer it belongs to.
+    fn start_chunk_request(&mut self, peer_id: PeerId, chunk: FileChunkRequest) {
+        let transfer = (chunk.transfer_id, chunk.total_chunks);
+        self.count_sent(peer_id, TrafficProtocol::FileTransfer, chunk.data.len());
+        let request_id = self
+            .swarm
+            .behaviour_mut()
+            .file_transfer
+            .send_request(&peer_id, chunk);
+        self.outbound_chunks.insert(request_id, transfer);
+    }
+
+    /// Get number of pending file chunks.
+    pub fn pending_chunk_count(&self) -> usize {
+        self.pending_chunks.len()
+    }
+
+    /// Record a finished chunk and build the progress event for it.
+    /// Returns `None` for a chunk starting an incoming transfer past the
+    /// peer's limit.
+    fn record_progress(
+        &mut self,
+        peer: PeerId,
+        transfer_id: Uuid,
+        total_chunks: u32,
+        direction: TransferDirection,
+    ) -> Option<NodeEvent> {
+        let now = Instant::now();
+        let chunks_done = self.transfer_progress.chunk_done(peer, transfer_id, total_chunks, direction, now)?;
+        Some(NodeEvent::TransferProgress {
+            peer,
+            transfer_id,
+            chunks_done,
+            total_chunks,
+            direction,
+        })
+    }
+
     /// Flush pending messages for a newly connected peer.
     /// Called when peer connects (in event loop, not yet implemented).
     #[allow(dead_code)]
@@ -119,13 +1002,18 @@ impl WhisperNode {
             .collect();
 
         for (_, data) in to_send {
-            self.swarm
-                .behaviour_mut()
-                .request_response
-                .send_request(peer_id, MessageRequest(data));
+            self.start_message_request(*peer_id, data);
         }
 
         self.pending_sends.retain(|(p, _)| p != peer_id);
+
+        let (chunks, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending_chunks)
+            .into_iter()
+            .partition(|(p, _)| p == peer_id);
+        self.pending_chunks = rest;
+        for (_, chunk) in chunks {
+            self.start_chunk_request(*peer_id, chunk);
+        }
     }
 
     /// Get number of pending messages.
@@ -133,14 +1021,103 @@ impl WhisperNode {
         self.pending_sends.len()
     }
 
-    /// Add a peer to the Kademlia DHT.
+    /// Add a peer to the Kademlia DHT, and remember the address for redials.
     pub fn add_address(&mut self, peer_id: &PeerId, addr: Multiaddr) {
+        self.connections.add_address(*peer_id, addr.clone());
+        self.swarm
+            .behaviour_mut()
+            .kademlia
+            .add_address(peer_id, addr);
+    }
+
+    /// Add an address we've connected to a peer through before. It's
+    /// dialed ahead of addresses that haven't worked yet.
+    pub fn add_reached_address(&mut self, peer_id: &PeerId, addr: Multiaddr) {
+        self.connections.address_reached(*peer_id, addr.clone());
         self.swarm
             .behaviour_mut()
             .kademlia
             .add_address(peer_id, addr);
     }
 
+    /// Whether anything is queued for a peer.
+    fn has_pending(&self, peer_id: &PeerId) -> bool {
+        self.pending_sends.iter().any(|(p, _)| p == peer_id)
+            || self.pending_chunks.iter().any(|(p, _)| p == peer_id)
+    }
+
+    /// Dial the peers whose redial is due.
+    fn dial_due(&mut self) {
+        for (peer_id, addrs) in self.connections.due(Instant::now()) {
+            // What we know may be stale, and the point may know better
+            self.rendezvous_discover(peer_id);
+            // A failed dial is retried after the backoff
+            self.dial_peer(peer_id, addrs);
+        }
+    }
+
+    /// Dial a peer at `addrs`, and anywhere Kademlia knows of, unless
+    /// we're connected or already dialing.
+    fn dial_peer(&mut self, peer_id: PeerId, addrs: Vec<Multiaddr>) {
+        let opts = DialOpts::peer_id(peer_id)
+            .addresses(addrs)
+            .extend_addresses_through_behaviour()
+            .condition(PeerCondition::DisconnectedAndNotDialing)
+            .build();
+        let _ = self.swarm.dial(opts);
+    }
+
+    /// A fetch of someone's address record made progress. Addresses in a
+    /// record they signed are used as if the lookup had found them.
+    fn record_lookup_progressed(&mut self, id: QueryId, result: kad::GetRecordResult, last: bool) {
+        let Some(&peer_id) = self.record_lookups.get(&id) else {
+            return;
+        };
+        if last {
+            self.record_lookups.remove(&id);
+        }
+        let Ok(kad::GetRecordOk::FoundRecord(found)) = result else {
+            return;
+        };
+        // Anyone can store anything under the key; ignore forgeries
+        if let Ok(addrs) = decode_address_record(&peer_id, &found.record.value) {
+            self.found_addresses(peer_id, addrs);
+        }
+    }
+
+    /// A DHT lookup made progress. Addresses found for the peer we were
+    /// looking for are remembered, reported and dialed straight away.
+    fn lookup_progressed(&mut self, id: QueryId, result: kad::GetClosestPeersResult, last: bool) {
+        let Some(&peer_id) = self.lookups.get(&id) else {
+            return;
+        };
+        if last {
+            self.lookups.remove(&id);
+        }
+        let peers = match result {
+            Ok(ok) => ok.peers,
+            Err(kad::GetClosestPeersError::Timeout { peers, .. }) => peers,
+        };
+        if let Some(found) = peers.into_iter().find(|p| p.peer_id == peer_id) {
+            self.found_addresses(peer_id, found.addrs);
+        }
+    }
+
+    /// Remember, report and dial addresses the DHT gave for a peer.
+    fn found_addresses(&mut self, peer_id: PeerId, addrs: Vec<Multiaddr>) {
+        for addr in &addrs {
+            self.add_address(&peer_id, addr.clone());
+            self.queued_events.push_back(NodeEvent::PeerAddress {
+                peer: peer_id,
+                address: addr.clone(),
+                reached: false,
+            });
+        }
+        if !addrs.is_empty() && !self.connected_peers.contains(&peer_id) {
+            self.dial_peer(peer_id, addrs);
+        }
+    }
+
     /// Get the swarm for advanced operations.
     pub fn swarm(&self) -> &Swarm<WhisperBehaviour> {
         &self.swarm
@@ -162,24 +1139,121 @@ impl WhisperNode {
         self.connected_peers.remove(peer_id);
     }
 
-    /// Poll the swarm for events and return any node events.
-    /// This should be called in a loop from the main event handler.
+    /// Subscribe to node events. Each subscriber sees every event produced
+    /// after it subscribed, whoever is driving the node.
+    pub fn subscribe(&self) -> broadcast::Receiver<NodeEvent> {
+        self.events.subscribe()
+    }
+
+    /// Poll the swarm for the next node event, also broadcasting it to
+    /// subscribers. Prefer `spawn` unless you need to drive the node yourself.
     pub async fn poll_event(&mut self) -> Option<NodeEvent> {
+        let event = self.next_event().await?;
+        // No subscribers is fine
+        let _ = self.events.send(event.clone());
+        Some(event)
+    }
+
+    /// When a redial, a publish, a ping or a traffic report is next due.
+    fn next_deadline(&self) -> Option<Instant> {
+        [self.connections.next_attempt(), self.publish_at, self.ping_at, Some(self.report_at)]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Poll the swarm until it produces a node event.
+    async fn next_event(&mut self) -> Option<NodeEvent> {
         use futures::StreamExt;
 
+        if let Some(event) = self.queued_events.pop_front() {
+            return Some(event);
+        }
+
         loop {
-            match self.swarm.select_next_some().await {
+            let deadline = self.next_deadline();
+            let event = tokio::select! {
+                event = self.swarm.select_next_some() => event,
+                _ = wait_until(deadline) => {
+                    self.dial_due();
+                    self.publish_due();
+                    self.ping_due();
+                    self.report_due();
+                    self.update_gauges();
+                    if let Some(event) = self.queued_events.pop_front() {
+                        return Some(event);
+                    }
+                    continue;
+                }
+            };
+            match event {
                 SwarmEvent::NewListenAddr { address, .. } => {
+                    self.addresses_changed();
                     return Some(NodeEvent::Listening(address));
                 }
-                SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                SwarmEvent::ExpiredListenAddr { .. }
+                | SwarmEvent::ExternalAddrConfirmed { .. }
+                | SwarmEvent::ExternalAddrExpired { .. } => {
+                    self.addresses_changed();
+                }
+                SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, num_established, .. } => {
+                    if let Some(reply) = self.pending_connects.remove(&connection_id) {
+                        let _ = reply.send(Ok(peer_id));
+                    }
+                    if self.blocked_peers.contains(&peer_id) {
+                        let _ = self.swarm.disconnect_peer_id(peer_id);
+                        continue;
+                    }
+                    let relayed = is_relay_address(endpoint.get_remote_address());
+                    self.connection_paths.insert(connection_id, (peer_id, relayed));
+                    // Addresses we dialed out to are worth trying again
+                    if let ConnectedPoint::Dialer { address, .. } = endpoint {
+                        let (address, _) = split_peer_id(&address);
+                        self.connections.address_reached(peer_id, address.clone());
+                        self.hint_book.reached(peer_id, address.clone(), unix_now());
+                        self.queued_events.push_back(NodeEvent::PeerAddress {
+                            peer: peer_id,
+                            address,
+                            reached: true,
+                        });
+                    }
+                    self.connections.connected(peer_id);
                     self.add_connected_peer(peer_id);
+                    if num_established.get() == 1 && self.rendezvous_point == Some(peer_id) {
+                        self.rendezvous_connected();
+                    }
+                    if num_established.get() == 1 {
+                        self.ping(peer_id);
+                        self.relay_connected(peer_id);
+                        self.ask_for_hints(peer_id);
+                    }
+                    self.ping_at.get_or_insert_with(|| Instant::now() + Duration::from_secs(PING_INTERVAL_SECS));
+                    self.update_gauges();
                     return Some(NodeEvent::PeerConnected(peer_id));
                 }
-                SwarmEvent::ConnectionClosed { peer_id, .. } => {
+                SwarmEvent::ConnectionClosed { peer_id, connection_id, num_established, .. } => {
                     self.remove_connected_peer(&peer_id);
+                    self.connection_paths.remove(&connection_id);
+                    if num_established == 0 {
+                        self.latencies.remove(&peer_id);
+                        self.transfer_progress.disconnected(&peer_id);
+                        let still_needed = self.has_pending(&peer_id) && !self.incompatible_peers.contains(&peer_id);
+                        self.connections.disconnected(peer_id, still_needed, Instant::now());
+                    }
+                    self.update_gauges();
                     return Some(NodeEvent::PeerDisconnected(peer_id));
                 }
+                SwarmEvent::ListenerClosed { listener_id, .. } => {
+                    if let Some(relay) = self.relay_listeners.remove(&listener_id) {
+                        self.addresses_changed();
+                        return Some(NodeEvent::RelayReservationLost { relay });
+                    }
+                }
+                SwarmEvent::OutgoingConnectionError { connection_id, error, .. } => {
+                    if let Some(reply) = self.pending_connects.remove(&connection_id) {
+                        let _ = reply.send(Err(anyhow::anyhow!("Failed to connect: {}", error)));
+                    }
+                }
                 SwarmEvent::Behaviour(event) => {
                     if let Some(node_event) = self.handle_behaviour_event(event) {
                         return Some(node_event);
@@ -193,19 +1267,23 @@ impl WhisperNode {
     /// Handle a behaviour event and return any resulting node event.
     fn handle_behaviour_event(&mut self, event: WhisperBehaviourEvent) -> Option<NodeEvent> {
         match event {
-            WhisperBehaviourEvent::Mdns(mdns::Event::Discovered(peers)) => {
+            WhisperBehaviourEvent::Mdns(mdns::Event::Discovered(peers))
+            | WhisperBehaviourEvent::MdnsV6(mdns::Event::Discovered(peers)) => {
                 for (peer_id, addr) in peers {
                     // Add discovered peer to Kademlia
-                    self.swarm
-                        .behaviour_mut()
-                        .kademlia
-                        .add_address(&peer_id, addr.clone());
+                    self.add_address(&peer_id, addr.clone());
                     // Try to dial them
-                    let _ = self.swarm.dial(addr);
+                    let _ = self.swarm.dial(addr.clone());
+                    self.queued_events.push_back(NodeEvent::PeerAddress {
+                        peer: peer_id,
+                        address: addr,
+                        reached: false,
+                    });
                 }
-                None
+                self.queued_events.pop_front()
             }
-            WhisperBehaviourEvent::Mdns(mdns::Event::Expired(peers)) => {
+            WhisperBehaviourEvent::Mdns(mdns::Event::Expired(peers))
+            | WhisperBehaviourEvent::MdnsV6(mdns::Event::Expired(peers)) => {
                 for (peer_id, _) in peers {
                     self.remove_connected_peer(&peer_id);
                 }
@@ -216,43 +1294,532 @@ impl WhisperNode {
                 message,
             }) => {
                 match message {
-                    request_response::Message::Request { request, channel, .. } => {
-                        // Received a message - send acknowledgment
+                    request_response::Message::Request { request: Err(too_large), channel, .. } => {
+                        // Refused unread
+                        self.count_sent(peer, TrafficProtocol::Messages, 1);
                         let _ = self.swarm
                             .behaviour_mut()
                             .request_response
-                            .send_response(channel, MessageResponse(true));
-                        Some(NodeEvent::MessageReceived {
-                            from: peer,
-                            data: request.0,
-                        })
+                            .send_response(channel, MessageResponse(false));
+                        Some(NodeEvent::ProtocolViolation { peer, reason: too_large.to_string() })
                     }
-                    request_response::Message::Response { .. } => {
-                        Some(NodeEvent::MessageSent { to: peer })
+                    request_response::Message::Request { request: Ok(request), channel, .. } => {
+                        self.count_received(peer, TrafficProtocol::Messages, request.0.len());
+                        self.count_sent(peer, TrafficProtocol::Messages, 1);
+                        // Anything a blocked peer got in before we hung up is refused
+                        let decision = if self.blocked_peers.contains(&peer) {
+                            RateDecision::Drop
+                        } else {
+                            self.rate_limiter.check(&peer, Instant::now())
+                        };
+                        // Acknowledge it, refusing anything over the limit
+                        let _ = self.swarm
+                            .behaviour_mut()
+                            .request_response
+                            .send_response(channel, MessageResponse(decision == RateDecision::Allow));
+                        match decision {
+                            RateDecision::Allow => {
+                                if let Some(metrics) = &self.metrics {
+                                    metrics.messages_received.inc();
+                                }
+                                Some(NodeEvent::MessageReceived {
+                                    from: peer,
+                                    data: request.0,
+                                })
+                            }
+                            RateDecision::Limited(ignored_for) => {
+                                Some(NodeEvent::RateLimited { peer, ignored_for })
+                            }
+                            RateDecision::Drop => None,
+                        }
+                    }
+                    request_response::Message::Response { request_id, .. } => {
+                        self.count_received(peer, TrafficProtocol::Messages, 1);
+                        let sent_at = self.message_sends.remove(&request_id);
+                        if let (Some(metrics), Some(sent_at)) = (&self.metrics, sent_at) {
+                            metrics.delivery_seconds.observe(sent_at.elapsed().as_secs_f64());
+                        }
+                        Some(NodeEvent::MessageSent { to: peer })
+                    }
+                }
+            }
+            WhisperBehaviourEvent::FileTransfer(request_response::Event::Message {
+                peer,
+                message,
+            }) => {
+                match message {
+                    request_response::Message::Request { request, channel, .. } => {
+                        self.count_received(peer, TrafficProtocol::FileTransfer, request.data.len());
+                        self.count_sent(peer, TrafficProtocol::FileTransfer, 1);
+                        // Peers over their message limit, and blocked ones, are ignored entirely
+                        let progress = if self.rate_limiter.is_banned(&peer, Instant::now())
+                            || self.blocked_peers.contains(&peer)
+                        {
+                            None
+                        } else {
+                            // As are chunks past their limit on open transfers
+                            self.record_progress(
+                                peer,
+                                request.transfer_id,
+                                request.total_chunks,
+                                TransferDirection::Incoming,
+                            )
+                        };
+                        let _ = self.swarm
+                            .behaviour_mut()
+                            .file_transfer
+                            .send_response(channel, FileChunkAck(progress.is_some()));
+                        self.queued_events.push_back(progress?);
+                        Some(NodeEvent::FileChunkReceived {
+                            from: peer,
+                            transfer_id: request.transfer_id,
+                            chunk_index: request.chunk_index,
+                            data: request.data,
+                        })
+                    }
+                    request_response::Message::Response { request_id, response } => {
+                        self.count_received(peer, TrafficProtocol::FileTransfer, 1);
+                        let (transfer_id, total_chunks) = self.outbound_chunks.remove(&request_id)?;
+                        if !response.0 {
+                            return None;
+                        }
+                        self.record_progress(
+                            peer,
+                            transfer_id,
+                            total_chunks,
+                            TransferDirection::Outgoing,
+                        )
                     }
                 }
             }
+            WhisperBehaviourEvent::RequestResponse(request_response::Event::OutboundFailure {
+                request_id,
+                ..
+            }) => {
+                self.message_sends.remove(&request_id);
+                if let Some(metrics) = &self.metrics {
+                    metrics.messages_failed.inc();
+                }
+                None
+            }
+            WhisperBehaviourEvent::FileTransfer(request_response::Event::OutboundFailure {
+                request_id,
+                ..
+            }) => {
+                self.outbound_chunks.remove(&request_id);
+                None
+            }
+            WhisperBehaviourEvent::Rendezvous(request_response::Event::Message { peer, message }) => match message {
+                request_response::Message::Request { request, channel, .. } => {
+                    self.count_received(peer, TrafficProtocol::Rendezvous, encoded_size(&request));
+                    let response = match &mut self.rendezvous_store {
+                        Some(store) => store.handle(peer, request, Instant::now()),
+                        None => RendezvousResponse::Rejected("Not a rendezvous point".to_string()),
+                    };
+                    self.count_sent(peer, TrafficProtocol::Rendezvous, encoded_size(&response));
+                    let _ = self.swarm.behaviour_mut().rendezvous.send_response(channel, response);
+                    None
+                }
+                request_response::Message::Response { request_id, response } => {
+                    self.count_received(peer, TrafficProtocol::Rendezvous, encoded_size(&response));
+                    let peer_id = self.rendezvous_discovers.remove(&request_id)?;
+                    if let RendezvousResponse::Registrations(records) = response {
+                        // The point could hand back anything; only trust what the peer signed
+                        for record in records {
+                            if let Ok(addrs) = decode_address_record(&peer_id, &record) {
+                                self.found_addresses(peer_id, addrs);
+                            }
+                        }
+                    }
+                    self.queued_events.pop_front()
+                }
+            },
+            WhisperBehaviourEvent::Rendezvous(request_response::Event::OutboundFailure {
+                request_id,
+                ..
+            }) => {
+                self.rendezvous_discovers.remove(&request_id);
+                None
+            }
+            WhisperBehaviourEvent::Pex(request_response::Event::Message { peer, message }) => match message {
+                request_response::Message::Request { request, channel, .. } => {
+                    self.count_received(peer, TrafficProtocol::Pex, encoded_size(&request));
+                    let hints = self.hint_book.hints_for(&peer, &request.wanted, &self.pex_policy, unix_now());
+                    let response = PexResponse { hints: encode_hints(&self.keypair, &hints).unwrap_or_default() };
+                    self.count_sent(peer, TrafficProtocol::Pex, encoded_size(&response));
+                    let _ = self.swarm.behaviour_mut().pex.send_response(channel, response);
+                    None
+                }
+                request_response::Message::Response { request_id, response } => {
+                    self.count_received(peer, TrafficProtocol::Pex, encoded_size(&response));
+                    let asked = self.pex_requests.remove(&request_id)?;
+                    if !self.pex_policy.trusted.contains(&peer) {
+                        return None;
+                    }
+                    let now = unix_now();
+                    for hint in decode_hints(&peer, &response.hints).unwrap_or_default() {
+                        // Only for contacts we asked about, and not too old
+                        if asked.contains(&hint.peer) && hint.is_fresh(now) {
+                            self.found_addresses(hint.peer, hint.addresses);
+                        }
+                    }
+                    self.queued_events.pop_front()
+                }
+            },
+            WhisperBehaviourEvent::Pex(request_response::Event::OutboundFailure { request_id, .. }) => {
+                self.pex_requests.remove(&request_id);
+                None
+            }
+            WhisperBehaviourEvent::Ping(request_response::Event::Message { peer, message }) => match message {
+                request_response::Message::Request { request, channel, .. } => {
+                    self.count_received(peer, TrafficProtocol::Ping, PING_SIZE);
+                    // Peers over their message limit are ignored entirely
+                    if !self.rate_limiter.is_banned(&peer, Instant::now()) {
+                        self.count_sent(peer, TrafficProtocol::Ping, PING_SIZE);
+                        let _ = self.swarm.behaviour_mut().ping.send_response(channel, request);
+                    }
+                    None
+                }
+                request_response::Message::Response { request_id, response } => {
+                    self.count_received(peer, TrafficProtocol::Ping, PING_SIZE);
+                    let (peer_id, rtt) = self.pings.answered(&request_id, &response, Instant::now())?;
+                    let latency = Latency {
+                        rtt,
+                        relayed: self.is_relayed(&peer_id),
+                    };
+                    self.latencies.insert(peer_id, latency);
+                    Some(NodeEvent::Latency { peer: peer_id, latency })
+                }
+            },
+            WhisperBehaviourEvent::Ping(request_response::Event::OutboundFailure { request_id, .. }) => {
+                self.pings.failed(&request_id);
+                None
+            }
+            WhisperBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                id,
+                result: kad::QueryResult::GetClosestPeers(result),
+                step,
+                ..
+            }) => {
+                self.lookup_progressed(id, result, step.last);
+                self.queued_events.pop_front()
+            }
+            WhisperBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                id,
+                result: kad::QueryResult::GetRecord(result),
+                step,
+                ..
+            }) => {
+                self.username_lookup_progressed(id, result.clone(), step.last);
+                self.record_lookup_progressed(id, result, step.last);
+                self.queued_events.pop_front()
+            }
+            WhisperBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                id,
+                result: kad::QueryResult::Bootstrap(_),
+                step,
+                ..
+            }) => {
+                self.bootstrap_progressed(id, step.last);
+                self.queued_events.pop_front()
+            }
+            WhisperBehaviourEvent::Autonat(autonat::Event::StatusChanged { new, .. }) => {
+                let reachability = Reachability::from(&new);
+                if reachability == self.reachability {
+                    return None;
+                }
+                self.reachability = reachability;
+                self.addresses_changed();
+                Some(NodeEvent::ReachabilityChanged(reachability))
+            }
+            WhisperBehaviourEvent::RelayClient(relay::client::Event::ReservationReqAccepted {
+                relay_peer_id,
+                renewal,
+                ..
+            }) => {
+                if !renewal {
+                    // Contacts can reach us through it now
+                    self.addresses_changed();
+                }
+                Some(NodeEvent::RelayReservation { relay: relay_peer_id, renewal })
+            }
+            WhisperBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. }) => {
+                let compatible = is_compatible_version(&info.protocol_version);
+                if compatible {
+                    self.incompatible_peers.remove(&peer_id);
+                } else {
+                    // Nothing we send would be understood; don't keep trying
+                    self.incompatible_peers.insert(peer_id);
+                    let _ = self.swarm.disconnect_peer_id(peer_id);
+                }
+                Some(NodeEvent::PeerIdentified {
+                    peer: peer_id,
+                    protocol_version: info.protocol_version,
+                    agent_version: info.agent_version,
+                    compatible,
+                })
+            }
+            WhisperBehaviourEvent::Gossipsub(gossipsub::Event::Message { propagation_source, message, .. }) => {
+                let channel_id = topic_channel(message.topic.as_str())?;
+                // Blocked peers, and peers over their message limit, are ignored
+                if self.blocked_peers.contains(&propagation_source)
+                    || self.rate_limiter.is_banned(&propagation_source, Instant::now())
+                {
+                    return None;
+                }
+                Some(NodeEvent::ChannelPostReceived { from: propagation_source, channel_id, data: message.data })
+            }
+            WhisperBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic }) => {
+                let channel_id = topic_channel(topic.as_str())?;
+                self.publish_pending_posts(channel_id);
+                Some(NodeEvent::ChannelJoined { peer: peer_id, channel_id })
+            }
             _ => None,
         }
     }
 
-    /// Start listening on the default address and return the channel for events.
-    #[allow(dead_code)]
-    pub async fn start(&mut self) -> Result<mpsc::Receiver<NodeEvent>> {
-        // Listen on all interfaces
-        let addr: Multiaddr = "/ip4/0.0.0.0/tcp/0".parse()?;
-        self.listen_on(addr)?;
+    /// Drive the node in a background task, returning a handle to it.
+    /// Events reach subscribers from `NodeHandle::subscribe`.
+    pub fn spawn(mut self) -> NodeHandle {
+        self.bootstrap();
+        self.listen_via_relays();
+        let (commands, mut command_rx) = mpsc::unbounded_channel();
+        let handle = NodeHandle {
+            peer_id: self.peer_id,
+            commands,
+            events: self.events.clone(),
+        };
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    command = command_rx.recv() => match command {
+                        Some(command) => {
+                            if !self.apply_command(command) {
+                                break;
+                            }
+                        }
+                        // Every handle was dropped
+                        None => break,
+                    },
+                    event = self.poll_event() => {
+                        if event.is_none() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        handle
+    }
+
+    /// Apply a command from a handle. Returns false on shutdown.
+    fn apply_command(&mut self, command: NodeCommand) -> bool {
+        match command {
+            NodeCommand::SendMessage(peer_id, data) => self.send_message(peer_id, data),
+            NodeCommand::SendFileChunk(peer_id, chunk) => self.send_file_chunk(peer_id, chunk),
+            NodeCommand::Dial(addr, reply) => {
+                let _ = reply.send(self.dial(addr));
+            }
+            NodeCommand::Connect(addr, reply) => self.connect(addr, reply),
+            NodeCommand::AddAddress(peer_id, addr) => self.add_address(&peer_id, addr),
+            NodeCommand::AddReachedAddress(peer_id, addr) => self.add_reached_address(&peer_id, addr),
+            NodeCommand::WatchPeer(peer_id) => self.watch_peer(peer_id),
+            NodeCommand::ConnectPeer(peer_id) => self.connect_peer(peer_id),
+            NodeCommand::SetRendezvousPoint(addr) => {
+                // Addresses are checked when they're saved
+                let _ = self.set_rendezvous_point(addr);
+            }
+            NodeCommand::SetPexPolicy(policy) => self.set_pex_policy(policy),
+            NodeCommand::SetBlockedPeers(peers) => self.set_blocked_peers(peers),
+            NodeCommand::SetUsername(username) => {
+                // Usernames are checked when they're saved
+                let _ = self.set_username(username.as_deref());
+            }
+            NodeCommand::LookupUsername(username, reply) => self.lookup_username(&username, reply),
+            NodeCommand::JoinChannel(channel_id) => self.join_channel(&channel_id),
+            NodeCommand::PublishPost(channel_id, data) => self.publish_post(channel_id, data),
+            NodeCommand::Stats(reply) => {
+                let _ = reply.send(self.stats.clone());
+            }
+            NodeCommand::Shutdown => return false,
+        }
+        true
+    }
+}
 
-        // Create event channel (tx would be used in a spawned event loop)
-        let (_tx, rx) = mpsc::channel(100);
+/// Bytes a rendezvous or peer exchange message takes on the wire.
+fn encoded_size<T: serde::Serialize>(message: &T) -> usize {
+    bincode::serialized_size(message).map_or(0, |size| size as usize)
+}
+
+/// Seconds since the epoch, for dating address hints.
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
 
-        Ok(rx)
+/// Sleep until `deadline`, or forever if there isn't one.
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Commands sent from a `NodeHandle` to the node task.
+enum NodeCommand {
+    SendMessage(PeerId, Vec<u8>),
+    SendFileChunk(PeerId, FileChunkRequest),
+    Dial(Multiaddr, oneshot::Sender<Result<()>>),
+    Connect(Multiaddr, oneshot::Sender<Result<PeerId>>),
+    AddAddress(PeerId, Multiaddr),
+    AddReachedAddress(PeerId, Multiaddr),
+    WatchPeer(PeerId),
+    ConnectPeer(PeerId),
+    SetRendezvousPoint(Multiaddr),
+    SetPexPolicy(PexPolicy),
+    SetBlockedPeers(HashSet<PeerId>),
+    SetUsername(Option<String>),
+    LookupUsername(String, oneshot::Sender<Result<Vec<UsernameRecord>>>),
+    JoinChannel(Uuid),
+    PublishPost(Uuid, Vec<u8>),
+    Stats(oneshot::Sender<NetworkStats>),
+    Shutdown,
+}
+
+/// Cloneable handle to a node running in a background task.
+#[derive(Clone)]
+pub struct NodeHandle {
+    peer_id: PeerId,
+    commands: mpsc::UnboundedSender<NodeCommand>,
+    events: broadcast::Sender<NodeEvent>,
+}
+
+impl NodeHandle {
+    /// Get the node's peer ID.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Subscribe to node events.
+    pub fn subscribe(&self) -> broadcast::Receiver<NodeEvent> {
+        self.events.subscribe()
+    }
+
+    /// Queue a message to send to a peer.
+    pub fn send_message(&self, peer_id: PeerId, data: Vec<u8>) {
+        let _ = self.commands.send(NodeCommand::SendMessage(peer_id, data));
+    }
+
+    /// Queue a file chunk to send to a peer.
+    pub fn send_file_chunk(&self, peer_id: PeerId, chunk: FileChunkRequest) {
+        let _ = self.commands.send(NodeCommand::SendFileChunk(peer_id, chunk));
+    }
+
+    /// Add a peer to the Kademlia DHT.
+    pub fn add_address(&self, peer_id: PeerId, addr: Multiaddr) {
+        let _ = self.commands.send(NodeCommand::AddAddress(peer_id, addr));
+    }
+
+    /// Add an address we've connected to a peer through before.
+    pub fn add_reached_address(&self, peer_id: PeerId, addr: Multiaddr) {
+        let _ = self.commands.send(NodeCommand::AddReachedAddress(peer_id, addr));
+    }
+
+    /// Keep a connection to a peer, redialing whenever it drops.
+    pub fn watch_peer(&self, peer_id: PeerId) {
+        let _ = self.commands.send(NodeCommand::WatchPeer(peer_id));
+    }
+
+    /// Dial a peer, with backoff until it connects.
+    pub fn connect_peer(&self, peer_id: PeerId) {
+        let _ = self.commands.send(NodeCommand::ConnectPeer(peer_id));
+    }
+
+    /// Use a rendezvous point. The address must end with its peer ID.
+    pub fn set_rendezvous_point(&self, addr: Multiaddr) {
+        let _ = self.commands.send(NodeCommand::SetRendezvousPoint(addr));
+    }
+
+    /// Trade address hints as `policy` says.
+    pub fn set_pex_policy(&self, policy: PexPolicy) {
+        let _ = self.commands.send(NodeCommand::SetPexPolicy(policy));
+    }
+
+    /// Refuse to talk to `peers`.
+    pub fn set_blocked_peers(&self, peers: HashSet<PeerId>) {
+        let _ = self.commands.send(NodeCommand::SetBlockedPeers(peers));
+    }
+
+    /// Dial a peer at a specific address.
+    pub async fn dial(&self, addr: Multiaddr) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(NodeCommand::Dial(addr, reply))
+            .map_err(|_| anyhow::anyhow!("Node task stopped"))?;
+        rx.await.map_err(|_| anyhow::anyhow!("Node task stopped"))?
+    }
+
+    /// Dial an address and wait for the handshake, returning the peer that
+    /// answered. Unlike `dial`, a connection that fails is reported.
+    pub async fn connect(&self, addr: Multiaddr) -> Result<PeerId> {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(NodeCommand::Connect(addr, reply))
+            .map_err(|_| anyhow::anyhow!("Node task stopped"))?;
+        rx.await.map_err(|_| anyhow::anyhow!("Node task stopped"))?
+    }
+
+    /// Claim `username` in the DHT, or with `None`, stop claiming one.
+    pub fn set_username(&self, username: Option<String>) {
+        let _ = self.commands.send(NodeCommand::SetUsername(username));
+    }
+
+    /// Every valid claim to `username` the DHT holds.
+    pub async fn lookup_username(&self, username: &str) -> Result<Vec<UsernameRecord>> {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(NodeCommand::LookupUsername(username.to_string(), reply))
+            .map_err(|_| anyhow::anyhow!("Node task stopped"))?;
+        rx.await.map_err(|_| anyhow::anyhow!("Node task stopped"))?
+    }
+
+    /// Join a channel's topic, to hear its posts and pass them on.
+    pub fn join_channel(&self, channel_id: Uuid) {
+        let _ = self.commands.send(NodeCommand::JoinChannel(channel_id));
+    }
+
+    /// Publish a post to a channel's topic, once a peer is on it.
+    pub fn publish_post(&self, channel_id: Uuid, data: Vec<u8>) {
+        let _ = self.commands.send(NodeCommand::PublishPost(channel_id, data));
+    }
+
+    /// Traffic since the node started, per peer and protocol.
+    pub async fn stats(&self) -> Result<NetworkStats> {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(NodeCommand::Stats(reply))
+            .map_err(|_| anyhow::anyhow!("Node task stopped"))?;
+        rx.await.map_err(|_| anyhow::anyhow!("Node task stopped"))
+    }
+
+    /// Stop the node task.
+    pub fn shutdown(&self) {
+        let _ = self.commands.send(NodeCommand::Shutdown);
+    }
+
+    /// Check if the node task is still running.
+    pub fn is_running(&self) -> bool {
+        !self.commands.is_closed()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::network::is_websocket_address;
+    use futures::StreamExt;
 
     fn generate_keypair() -> Keypair {
         Keypair::generate_ed25519()
@@ -265,6 +1832,27 @@ mod tests {
         assert!(node.is_ok());
     }
 
+    #[tokio::test]
+    async fn proxied_node_only_listens_locally() {
+        let config = TransportConfig { socks_proxy: Some("127.0.0.1:9050".parse().unwrap()), ..Default::default() };
+        let mut node = WhisperNode::with_transport(generate_keypair(), config).await.unwrap();
+        assert!(node.transport().is_proxied());
+
+        node.listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap()).unwrap();
+        node.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let address = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let SwarmEvent::NewListenAddr { address, .. } = node.swarm.select_next_some().await {
+                    break address;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(is_loopback_address(&address));
+        assert_eq!(node.swarm.listeners().count(), 1);
+    }
+
     #[tokio::test]
     async fn peer_id_matches_keypair() {
         let keypair = generate_keypair();
@@ -299,6 +1887,26 @@ mod tests {
         assert_eq!(node.pending_count(), 1);
     }
 
+    #[tokio::test]
+    async fn rate_limit_is_configurable() {
+        let mut node = WhisperNode::new(generate_keypair()).await.unwrap();
+        assert_eq!(node.rate_limit(), RateLimitConfig::default());
+        let config = RateLimitConfig {
+            peer_per_minute: 10,
+            global_per_minute: 50,
+            ban: Duration::from_secs(5),
+        };
+        node.set_rate_limit(config);
+        assert_eq!(node.rate_limit(), config);
+    }
+
+    #[tokio::test]
+    async fn reachability_initially_unknown() {
+        let keypair = generate_keypair();
+        let node = WhisperNode::new(keypair).await.unwrap();
+        assert_eq!(node.reachability(), Reachability::Unknown);
+    }
+
     #[tokio::test]
     async fn pending_count_initially_zero() {
         let keypair = generate_keypair();
@@ -332,6 +1940,38 @@ mod tests {
         assert_eq!(node.pending_count(), 2);
     }
 
+    #[tokio::test]
+    async fn file_chunks_queue_when_not_connected() {
+        let keypair = generate_keypair();
+        let mut node = WhisperNode::new(keypair).await.unwrap();
+        let chunk = FileChunkRequest {
+            transfer_id: Uuid::new_v4(),
+            chunk_index: 0,
+            total_chunks: 1,
+            data: vec![1, 2, 3],
+        };
+
+        node.send_file_chunk(PeerId::random(), chunk);
+
+        assert_eq!(node.pending_chunk_count(), 1);
+        assert_eq!(node.pending_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn progress_counts_up_and_clears_when_done() {
+        let keypair = generate_keypair();
+        let mut node = WhisperNode::new(keypair).await.unwrap();
+        let peer = PeerId::random();
+        let transfer_id = Uuid::new_v4();
+
+        let first = node.record_progress(peer, transfer_id, 2, TransferDirection::Outgoing);
+        assert!(matches!(first, Some(NodeEvent::TransferProgress { chunks_done: 1, total_chunks: 2, .. })));
+
+        let second = node.record_progress(peer, transfer_id, 2, TransferDirection::Outgoing);
+        assert!(matches!(second, Some(NodeEvent::TransferProgress { chunks_done: 2, .. })));
+        assert!(node.transfer_progress.is_empty());
+    }
+
     #[tokio::test]
     async fn listen_on_valid_address() {
         let keypair = generate_keypair();
@@ -342,6 +1982,774 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[tokio::test]
+    async fn poll_event_broadcasts_to_subscribers() {
+        let keypair = generate_keypair();
+        let mut node = WhisperNode::new(keypair).await.unwrap();
+        let mut first = node.subscribe();
+        let mut second = node.subscribe();
+        node.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+
+        let event = node.poll_event().await.unwrap();
+        assert!(matches!(event, NodeEvent::Listening(_)));
+        assert!(matches!(first.try_recv(), Ok(NodeEvent::Listening(_))));
+        assert!(matches!(second.try_recv(), Ok(NodeEvent::Listening(_))));
+    }
+
+    #[tokio::test]
+    async fn spawned_nodes_exchange_messages() {
+        let alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let mut bob_events = bob.subscribe();
+        let alice = alice.spawn();
+        let bob = bob.spawn();
+
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                break addr;
+            }
+        };
+        alice.dial(bob_addr).await.unwrap();
+        alice.send_message(bob.peer_id(), vec![1, 2, 3]);
+
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(NodeEvent::MessageReceived { from, data }) = bob_events.recv().await {
+                    return (from, data);
+                }
+            }
+        })
+        .await
+        .expect("message should arrive");
+        assert_eq!(received, (alice.peer_id(), vec![1, 2, 3]));
+
+        let stats = alice.stats().await.unwrap();
+        assert!(stats.protocol(TrafficProtocol::Messages).sent >= 3);
+        assert!(stats.peer(&bob.peer_id()).sent >= 3);
+
+        alice.shutdown();
+        bob.shutdown();
+    }
+
+    #[tokio::test]
+    async fn posts_wait_for_a_peer_on_their_topic() {
+        let mut alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let (mut alice_events, mut bob_events) = (alice.subscribe(), bob.subscribe());
+        let channel_id = Uuid::new_v4();
+        alice.join_channel(&channel_id);
+        bob.join_channel(&channel_id);
+        alice.publish_post(channel_id, vec![7, 8, 9]);
+        assert_eq!(alice.pending_post_count(), 1);
+        let alice = alice.spawn();
+        let bob = bob.spawn();
+
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                break addr;
+            }
+        };
+        alice.dial(bob_addr).await.unwrap();
+
+        let joined = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(NodeEvent::ChannelJoined { peer, channel_id }) = alice_events.recv().await {
+                    return (peer, channel_id);
+                }
+            }
+        })
+        .await
+        .expect("bob should join the topic");
+        assert_eq!(joined, (bob.peer_id(), channel_id));
+
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(NodeEvent::ChannelPostReceived { from, channel_id, data }) = bob_events.recv().await {
+                    return (from, channel_id, data);
+                }
+            }
+        })
+        .await
+        .expect("post should arrive");
+        assert_eq!(received, (alice.peer_id(), channel_id, vec![7, 8, 9]));
+
+        alice.shutdown();
+        bob.shutdown();
+    }
+
+    #[tokio::test]
+    async fn nodes_connect_over_websocket() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = TransportConfig { websocket_port: Some(port), ..Default::default() };
+        let alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut bob = WhisperNode::with_transport(generate_keypair(), config).await.unwrap();
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let mut bob_events = bob.subscribe();
+        let alice = alice.spawn();
+        let bob = bob.spawn();
+
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                if is_websocket_address(&addr) {
+                    break addr;
+                }
+            }
+        };
+        assert_eq!(bob_addr, format!("/ip4/127.0.0.1/tcp/{}/ws", port).parse().unwrap());
+        assert_eq!(alice.connect(bob_addr).await.unwrap(), bob.peer_id());
+        alice.send_message(bob.peer_id(), vec![4, 5, 6]);
+
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(NodeEvent::MessageReceived { from, data }) = bob_events.recv().await {
+                    return (from, data);
+                }
+            }
+        })
+        .await
+        .expect("message should arrive");
+        assert_eq!(received, (alice.peer_id(), vec![4, 5, 6]));
+
+        alice.shutdown();
+        bob.shutdown();
+    }
+
+    #[tokio::test]
+    async fn queued_message_dials_known_address() {
+        let alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let mut bob_events = bob.subscribe();
+        let alice = alice.spawn();
+        let bob = bob.spawn();
+
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                break addr;
+            }
+        };
+        // No dial: having something to send is enough
+        alice.add_address(bob.peer_id(), bob_addr);
+        alice.send_message(bob.peer_id(), vec![4, 5, 6]);
+
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(NodeEvent::MessageReceived { data, .. }) = bob_events.recv().await {
+                    return data;
+                }
+            }
+        })
+        .await
+        .expect("message should arrive");
+        assert_eq!(received, vec![4, 5, 6]);
+
+        alice.shutdown();
+        bob.shutdown();
+    }
+
+    #[tokio::test]
+    async fn message_to_unknown_address_looks_up_the_dht() {
+        let mut alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut carol = WhisperNode::new(generate_keypair()).await.unwrap();
+        carol.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let mut carol_events = carol.subscribe();
+        let carol_id = carol.peer_id();
+        let carol = carol.spawn();
+        let carol_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = carol_events.recv().await {
+                break addr;
+            }
+        };
+
+        // Nowhere to dial Carol, so Alice asks the DHT, once
+        alice.send_message(carol_id, vec![7, 8, 9]);
+        alice.send_message(carol_id, vec![10]);
+        assert!(alice.is_looking_up(&carol_id));
+        assert_eq!(alice.lookups.len(), 1);
+        assert_eq!(alice.record_lookups.len(), 1);
+        let query = *alice.lookups.keys().next().unwrap();
+
+        // The DHT answers with where she is
+        let found = kad::GetClosestPeersOk {
+            key: carol_id.to_bytes(),
+            peers: vec![kad::PeerInfo { peer_id: carol_id, addrs: vec![carol_addr.clone()] }],
+        };
+        alice.lookup_progressed(query, Ok(found), true);
+        assert!(alice.lookups.is_empty());
+        assert_eq!(alice.known_addresses(&carol_id), vec![carol_addr.clone()]);
+        assert!(matches!(
+            alice.queued_events.front(),
+            Some(NodeEvent::PeerAddress { peer, address, reached: false }) if *peer == carol_id && *address == carol_addr
+        ));
+
+        // ...and the queued message goes once Alice dials her
+        let alice = alice.spawn();
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(NodeEvent::MessageReceived { data, .. }) = carol_events.recv().await {
+                    return data;
+                }
+            }
+        })
+        .await
+        .expect("message should arrive");
+        assert_eq!(received, vec![7, 8, 9]);
+
+        alice.shutdown();
+        carol.shutdown();
+    }
+
+    #[tokio::test]
+    async fn signed_address_record_is_published_and_found() {
+        use kad::store::RecordStore;
+
+        let mut alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let public: Multiaddr = "/ip4/203.0.113.7/tcp/4001".parse().unwrap();
+
+        // Nothing worth publishing yet
+        alice.publish_addresses();
+        let key = address_record_key(&alice.peer_id());
+        assert!(alice.swarm.behaviour_mut().kademlia.store_mut().get(&key).is_none());
+
+        alice.swarm.add_external_address(public.clone());
+        assert_eq!(alice.public_addresses(), vec![public.clone()]);
+        alice.publish_addresses();
+        let record = alice.swarm.behaviour_mut().kademlia.store_mut().get(&key).unwrap().into_owned();
+        assert_eq!(decode_address_record(&alice.peer_id(), &record.value).unwrap(), vec![public.clone()]);
+        assert!(alice.publish_at.unwrap() > Instant::now() + Duration::from_secs(60));
+
+        // Bob fetches it while looking for Alice
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        bob.send_message(alice.peer_id(), vec![1]);
+        let query = *bob.record_lookups.keys().next().unwrap();
+        let found = kad::GetRecordOk::FoundRecord(kad::PeerRecord { peer: None, record: record.clone() });
+        bob.record_lookup_progressed(query, Ok(found), true);
+        assert_eq!(bob.known_addresses(&alice.peer_id()), vec![public]);
+
+        // A record Mallory signed is ignored
+        let mallory = PeerId::random();
+        bob.send_message(mallory, vec![1]);
+        let query = *bob.record_lookups.keys().next().unwrap();
+        let forged = kad::Record::new(address_record_key(&mallory), record.value);
+        let found = kad::GetRecordOk::FoundRecord(kad::PeerRecord { peer: None, record: forged });
+        bob.record_lookup_progressed(query, Ok(found), true);
+        assert!(bob.known_addresses(&mallory).is_empty());
+    }
+
+    #[tokio::test]
+    async fn usernames_are_published_and_resolved() {
+        use kad::store::RecordStore;
+
+        let mut alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        assert!(alice.set_username(Some("no spaces")).is_err());
+        alice.set_username(Some("@Alice")).unwrap();
+        assert_eq!(alice.username(), Some("alice"));
+        let key = username_record_key("alice");
+        let record = alice.swarm.behaviour_mut().kademlia.store_mut().get(&key).unwrap().into_owned();
+
+        // Bob finds Alice's claim, once, and a claim to another name filed under hers is ignored
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        let (reply, rx) = oneshot::channel();
+        bob.lookup_username("@alice", reply);
+        let query = *bob.username_lookups.keys().next().unwrap();
+        for _ in 0..2 {
+            let found = kad::GetRecordOk::FoundRecord(kad::PeerRecord { peer: None, record: record.clone() });
+            bob.username_lookup_progressed(query, Ok(found), false);
+        }
+        let mallory = encode_username_record(&generate_keypair(), "mallory", unix_now()).unwrap();
+        let misfiled = kad::Record::new(key, mallory);
+        let found = kad::GetRecordOk::FoundRecord(kad::PeerRecord { peer: None, record: misfiled });
+        bob.username_lookup_progressed(query, Ok(found), true);
+        let claims = rx.await.unwrap().unwrap();
+        assert_eq!(claims.len(), 1);
+        assert_eq!((claims[0].peer_id, &claims[0].public_key), (alice.peer_id(), &alice.keypair.public()));
+
+        let (reply, rx) = oneshot::channel();
+        bob.lookup_username("x", reply);
+        assert!(rx.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn peers_find_each_other_at_a_rendezvous_point() {
+        async fn listening(node: &mut WhisperNode) -> (Multiaddr, broadcast::Receiver<NodeEvent>) {
+            node.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+            let mut events = node.subscribe();
+            loop {
+                if let Some(NodeEvent::Listening(addr)) = node.poll_event().await {
+                    // Stand-in for an address AutoNAT confirmed
+                    node.swarm.add_external_address(addr.clone());
+                    let _ = events.try_recv();
+                    return (addr, events);
+                }
+            }
+        }
+
+        let mut point = WhisperNode::new(generate_keypair()).await.unwrap();
+        point.serve_rendezvous();
+        let (point_addr, _) = listening(&mut point).await;
+        let point_addr = point_addr.with(libp2p::multiaddr::Protocol::P2p(point.peer_id()));
+        let point = point.spawn();
+
+        let mut alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        let (alice_id, bob_id) = (alice.peer_id(), bob.peer_id());
+        let (_, mut alice_events) = listening(&mut alice).await;
+        listening(&mut bob).await;
+        alice.watch_peer(bob_id);
+        bob.watch_peer(alice_id);
+        assert!(alice.set_rendezvous_point("/ip4/127.0.0.1/tcp/1".parse().unwrap()).is_err());
+        alice.set_rendezvous_point(point_addr.clone()).unwrap();
+        bob.set_rendezvous_point(point_addr).unwrap();
+        assert_eq!(bob.rendezvous_point(), Some(point.peer_id()));
+
+        // Bob has nowhere to dial Alice but the point; he keeps asking
+        // until her registration is there
+        bob.send_message(alice_id, vec![4, 2]);
+        let (alice, bob) = (alice.spawn(), bob.spawn());
+        let received = tokio::time::timeout(Duration::from_secs(20), async {
+            loop {
+                if let Ok(NodeEvent::MessageReceived { from, data }) = alice_events.recv().await {
+                    if from == bob_id {
+                        return data;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("message should arrive");
+        assert_eq!(received, vec![4, 2]);
+
+        alice.shutdown();
+        bob.shutdown();
+        point.shutdown();
+    }
+
+    #[tokio::test]
+    async fn trusted_contacts_swap_address_hints() {
+        let mut alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        let (alice_id, bob_id, carol_id) = (alice.peer_id(), bob.peer_id(), PeerId::random());
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let mut bob_events = bob.subscribe();
+        // Bob reached Carol somewhere Alice doesn't know of
+        let carol_addr: Multiaddr = "/ip4/203.0.113.7/tcp/4001".parse().unwrap();
+        bob.hint_book.reached(carol_id, carol_addr.clone(), unix_now());
+        bob.set_pex_policy(PexPolicy { trusted: [alice_id].into(), shared: [carol_id].into() });
+        let bob = bob.spawn();
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                break addr;
+            }
+        };
+
+        alice.watch_peer(carol_id);
+        alice.set_pex_policy(PexPolicy { trusted: [bob_id].into(), shared: HashSet::new() });
+        alice.dial(bob_addr).unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(NodeEvent::PeerAddress { peer, address, reached: false }) = alice.poll_event().await {
+                    if peer == carol_id {
+                        assert_eq!(address, carol_addr);
+                        return;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("Bob should tell Alice where Carol is");
+        assert!(alice.known_addresses(&carol_id).contains(&carol_addr));
+
+        bob.shutdown();
+    }
+
+    #[tokio::test]
+    async fn known_address_skips_the_dht() {
+        let mut alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let bob = PeerId::random();
+        alice.add_address(&bob, "/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        alice.send_message(bob, vec![1]);
+        assert!(!alice.is_looking_up(&bob));
+    }
+
+    #[tokio::test]
+    async fn dialed_addresses_are_remembered() {
+        let mut alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let mut bob_events = bob.subscribe();
+        let bob_id = bob.peer_id();
+        let bob = bob.spawn();
+
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                break addr;
+            }
+        };
+        alice.dial(bob_addr.clone()).unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !matches!(alice.poll_event().await, Some(NodeEvent::PeerConnected(peer)) if peer == bob_id) {}
+        })
+        .await
+        .expect("should connect");
+        // mDNS may have found others, but this one worked
+        assert_eq!(alice.known_addresses(&bob_id).first(), Some(&bob_addr));
+        // ...and reported so they can be stored
+        match alice.poll_event().await {
+            Some(NodeEvent::PeerAddress { peer, address, reached }) => {
+                assert_eq!((peer, address, reached), (bob_id, bob_addr, true));
+            }
+            other => panic!("expected PeerAddress, got {:?}", other),
+        }
+
+        bob.shutdown();
+    }
+
+    #[tokio::test]
+    async fn connected_peers_are_pinged() {
+        let alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut alice_events = alice.subscribe();
+        let alice = alice.spawn();
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let mut bob_events = bob.subscribe();
+        let bob_id = bob.peer_id();
+        let bob = bob.spawn();
+
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                break addr;
+            }
+        };
+        alice.connect(bob_addr).await.unwrap();
+
+        let latency = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(NodeEvent::Latency { peer, latency }) = alice_events.recv().await {
+                    if peer == bob_id {
+                        return latency;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("ping should come back");
+        assert!(!latency.relayed);
+        assert!(latency.rtt < Duration::from_secs(10));
+
+        bob.shutdown();
+        alice.shutdown();
+    }
+
+    #[tokio::test]
+    async fn connect_reports_who_answered() {
+        let alice = WhisperNode::new(generate_keypair()).await.unwrap().spawn();
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let mut bob_events = bob.subscribe();
+        let bob_id = bob.peer_id();
+        let bob = bob.spawn();
+
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                break addr;
+            }
+        };
+        let connect = |addr| tokio::time::timeout(Duration::from_secs(10), alice.connect(addr));
+        assert_eq!(connect(bob_addr.clone()).await.unwrap().unwrap(), bob_id);
+
+        // Naming the wrong peer fails the handshake
+        let wrong = bob_addr.with(libp2p::multiaddr::Protocol::P2p(PeerId::random()));
+        assert!(connect(wrong).await.unwrap().is_err());
+
+        bob.shutdown();
+        alice.shutdown();
+    }
+
+    #[tokio::test]
+    async fn connect_reports_unreachable_address() {
+        let alice = WhisperNode::new(generate_keypair()).await.unwrap().spawn();
+        // Nothing listens on port 1
+        let result = tokio::time::timeout(
+            Duration::from_secs(10),
+            alice.connect("/ip4/127.0.0.1/tcp/1".parse().unwrap()),
+        )
+        .await
+        .unwrap();
+        assert!(result.is_err());
+        alice.shutdown();
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_protocol_violations() {
+        let alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        assert_eq!(bob.max_message_size(), crate::network::DEFAULT_MAX_MESSAGE_SIZE);
+        bob.set_max_message_size(1024);
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let mut bob_events = bob.subscribe();
+        let alice = alice.spawn();
+        let bob = bob.spawn();
+
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                break addr;
+            }
+        };
+        alice.dial(bob_addr).await.unwrap();
+        alice.send_message(bob.peer_id(), vec![0; 4096]);
+        alice.send_message(bob.peer_id(), vec![1; 16]);
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            let (mut violated, mut received) = (false, false);
+            while !(violated && received) {
+                match bob_events.recv().await {
+                    Ok(NodeEvent::ProtocolViolation { peer, reason }) => {
+                        assert_eq!(peer, alice.peer_id());
+                        assert!(reason.contains("1024"), "{}", reason);
+                        violated = true;
+                    }
+                    // Messages within the limit still arrive
+                    Ok(NodeEvent::MessageReceived { data, .. }) => {
+                        assert_eq!(data, [1; 16]);
+                        received = true;
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("oversized message should be refused");
+
+        alice.shutdown();
+        bob.shutdown();
+    }
+
+    #[tokio::test]
+    async fn connected_peers_identify_their_versions() {
+        let alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let mut bob_events = bob.subscribe();
+        let alice = alice.spawn();
+        let bob = bob.spawn();
+
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                break addr;
+            }
+        };
+        alice.dial(bob_addr).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(NodeEvent::PeerIdentified { peer, protocol_version, agent_version, compatible }) =
+                    bob_events.recv().await
+                {
+                    assert_eq!(peer, alice.peer_id());
+                    assert_eq!(protocol_version, crate::network::WHISPER_PROTOCOL);
+                    assert_eq!(agent_version, crate::network::AGENT_VERSION);
+                    assert!(compatible);
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("peer should identify itself");
+
+        alice.shutdown();
+        bob.shutdown();
+    }
+
+    #[tokio::test]
+    async fn dht_is_ready_after_bootstrapping() {
+        let mut alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let mut bob_events = bob.subscribe();
+        let bob = bob.spawn();
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                break addr.with(libp2p::multiaddr::Protocol::P2p(bob.peer_id()));
+            }
+        };
+
+        alice.set_bootstrap_nodes(vec![bob_addr.clone()]);
+        assert_eq!(alice.bootstrap_nodes(), [bob_addr]);
+        let mut alice_events = alice.subscribe();
+        let alice = alice.spawn();
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(NodeEvent::DhtReady { peers }) = alice_events.recv().await {
+                    assert!(peers >= 1);
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("bootstrap should finish");
+
+        alice.shutdown();
+        bob.shutdown();
+    }
+
+    #[tokio::test]
+    async fn relays_give_us_a_circuit_address() {
+        let mut relay = SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(tcp::Config::default(), noise::Config::new, yamux::Config::default)
+            .unwrap()
+            .with_behaviour(|keypair| relay::Behaviour::new(keypair.public().to_peer_id(), Default::default()))
+            .unwrap()
+            .build();
+        relay.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let relay_addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = relay.select_next_some().await {
+                break address;
+            }
+        };
+        // Reservations tell us how the relay can be reached
+        relay.add_external_address(relay_addr.clone());
+        let relay_peer = *relay.local_peer_id();
+        let relay_task = tokio::spawn(async move {
+            loop {
+                relay.select_next_some().await;
+            }
+        });
+
+        let mut node = WhisperNode::new(generate_keypair()).await.unwrap();
+        node.set_relays(vec![relay_addr.with(Protocol::P2p(relay_peer))]);
+        let mut events = node.subscribe();
+        let node = node.spawn();
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            let (mut reserved, mut circuit) = (false, false);
+            while !(reserved && circuit) {
+                match events.recv().await {
+                    Ok(NodeEvent::RelayReservation { relay, renewal }) => {
+                        assert_eq!(relay, relay_peer);
+                        assert!(!renewal);
+                        reserved = true;
+                    }
+                    Ok(NodeEvent::Listening(addr)) if is_relay_address(&addr) => circuit = true,
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("relay should accept a reservation");
+
+        node.shutdown();
+        relay_task.abort();
+    }
+
+    #[tokio::test]
+    async fn flooding_peer_is_rate_limited() {
+        let alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        bob.set_rate_limit(RateLimitConfig {
+            peer_per_minute: 2,
+            ..RateLimitConfig::default()
+        });
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let mut bob_events = bob.subscribe();
+        let alice = alice.spawn();
+        let bob = bob.spawn();
+
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                break addr;
+            }
+        };
+        alice.dial(bob_addr).await.unwrap();
+        for i in 0..4 {
+            alice.send_message(bob.peer_id(), vec![i]);
+        }
+
+        let (received, ignored_for) = tokio::time::timeout(Duration::from_secs(10), async {
+            let mut received = 0;
+            loop {
+                match bob_events.recv().await {
+                    Ok(NodeEvent::MessageReceived { .. }) => received += 1,
+                    Ok(NodeEvent::RateLimited { peer, ignored_for }) => {
+                        assert_eq!(peer, alice.peer_id());
+                        return (received, ignored_for);
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("flood should be limited");
+        assert_eq!(received, 2);
+        assert_eq!(ignored_for, RateLimitConfig::default().ban);
+
+        alice.shutdown();
+        bob.shutdown();
+    }
+
+    #[tokio::test]
+    async fn blocked_peer_is_hung_up_on() {
+        let alice = WhisperNode::new(generate_keypair()).await.unwrap();
+        let mut bob = WhisperNode::new(generate_keypair()).await.unwrap();
+        bob.set_blocked_peers([alice.peer_id()].into());
+        bob.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let mut alice_events = alice.subscribe();
+        let mut bob_events = bob.subscribe();
+        let alice = alice.spawn();
+        let bob = bob.spawn();
+
+        let bob_addr = loop {
+            if let Ok(NodeEvent::Listening(addr)) = bob_events.recv().await {
+                break addr;
+            }
+        };
+        alice.dial(bob_addr).await.unwrap();
+        alice.send_message(bob.peer_id(), b"hello".to_vec());
+
+        // Alice is cut off, and Bob never hears from her
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(NodeEvent::PeerDisconnected(peer)) = alice_events.recv().await {
+                    assert_eq!(peer, bob.peer_id());
+                    return;
+                }
+            }
+        })
+        .await
+        .expect("bob should hang up");
+        let heard = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                match bob_events.recv().await {
+                    Ok(NodeEvent::MessageReceived { .. } | NodeEvent::PeerConnected(_)) => return,
+                    Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+                    _ => {}
+                }
+            }
+        })
+        .await;
+        assert!(heard.is_err());
+
+        alice.shutdown();
+        bob.shutdown();
+    }
+
+    #[tokio::test]
+    async fn handle_stops_on_shutdown() {
+        let handle = WhisperNode::new(generate_keypair()).await.unwrap().spawn();
+        assert!(handle.is_running());
+        handle.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while handle.is_running() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("node task should stop");
+    }
+
     #[tokio::test]
     async fn swarm_accessible() {
         let keypair = generate_keypair();
@@ -0,0 +1,323 @@
+//! Peer exchange of contact addresses.
+//!
+//! Without the DHT, a contact we've lost touch with may still be found
+//! through another: two trusted contacts who connect swap signed hints
+//! like "carol was reachable at X five minutes ago" for contacts they
+//! have in common. The asker names the contacts it wants by tags only
+//! the other side can match against its own contacts, so neither learns
+//! about contacts the other doesn't share, and only addresses we dialed
+//! out to that aren't on a private network are passed on.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Result};
+use libp2p::{core::SignedEnvelope, identity::Keypair, request_response, Multiaddr, PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+
+use super::discovery::is_local_address;
+
+/// Protocol name for Whisper peer exchange.
+pub const PEX_PROTOCOL: &str = "/whisper/pex/1.0.0";
+
+/// Largest peer exchange request or response we read.
+pub const MAX_PEX_MESSAGE_SIZE: u64 = 64 * 1024;
+
+/// Most contacts asked about in one request.
+pub const MAX_PEX_WANTED: usize = 256;
+
+/// Hints older than this, in seconds, are neither passed on nor used.
+pub const MAX_HINT_AGE_SECS: u64 = 24 * 60 * 60;
+
+/// Addresses kept per contact for hints.
+pub const MAX_HINT_ADDRESSES: usize = 4;
+
+/// Signature domain for hint bundles.
+const HINTS_DOMAIN: &str = "whisper-pex";
+
+/// Payload type of hint bundles.
+const HINTS_PAYLOAD_TYPE: &[u8] = b"/whisper/pex/hints";
+
+/// Request to a trusted contact: where are the contacts with these tags?
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexRequest {
+    pub wanted: Vec<[u8; 32]>,
+}
+
+/// Hints for the contacts asked about that the other side could share,
+/// as a bundle it signed. Empty when it has none, or won't say.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexResponse {
+    pub hints: Vec<u8>,
+}
+
+/// How `asker` names `peer` in a request. Only someone who already knows
+/// both can tell which contact it means.
+pub fn hint_tag(asker: &PeerId, peer: &PeerId) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_derive_key("whisper 2026 pex tag");
+    hasher.update(&asker.to_bytes());
+    hasher.update(&peer.to_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+/// Where a contact was reachable, and when, in seconds since the epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressHint {
+    pub peer: PeerId,
+    pub addresses: Vec<Multiaddr>,
+    pub seen_at: u64,
+}
+
+impl AddressHint {
+    /// Whether the hint is recent enough to act on at `now`.
+    pub fn is_fresh(&self, now: u64) -> bool {
+        now.saturating_sub(self.seen_at) < MAX_HINT_AGE_SECS
+    }
+}
+
+/// A hint as signed: peer ID and addresses in their byte forms.
+#[derive(Serialize, Deserialize)]
+struct WireHint {
+    peer: Vec<u8>,
+    addresses: Vec<Vec<u8>>,
+    seen_at: u64,
+}
+
+/// Sign hints for sending. No hints make an empty bundle.
+pub fn encode_hints(keypair: &Keypair, hints: &[AddressHint]) -> Result<Vec<u8>> {
+    if hints.is_empty() {
+        return Ok(Vec::new());
+    }
+    let wire: Vec<WireHint> = hints
+        .iter()
+        .map(|hint| WireHint {
+            peer: hint.peer.to_bytes(),
+            addresses: hint.addresses.iter().map(|a| a.to_vec()).collect(),
+            seen_at: hint.seen_at,
+        })
+        .collect();
+    let envelope = SignedEnvelope::new(
+        keypair,
+        HINTS_DOMAIN.to_string(),
+        HINTS_PAYLOAD_TYPE.to_vec(),
+        bincode::serialize(&wire)?,
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to sign address hints: {}", e))?;
+    Ok(envelope.into_protobuf_encoding())
+}
+
+/// Read hints `from` sent. They're only accepted if `from` signed them.
+pub fn decode_hints(from: &PeerId, bytes: &[u8]) -> Result<Vec<AddressHint>> {
+    if bytes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let envelope = SignedEnvelope::from_protobuf_encoding(bytes)
+        .map_err(|e| anyhow::anyhow!("Malformed address hints: {}", e))?;
+    let (payload, key) = envelope
+        .payload_and_signing_key(HINTS_DOMAIN.to_string(), HINTS_PAYLOAD_TYPE)
+        .map_err(|e| anyhow::anyhow!("Invalid address hints: {}", e))?;
+    if key.to_peer_id() != *from {
+        bail!("Address hints from {} are signed by {}", from, key.to_peer_id());
+    }
+    let wire: Vec<WireHint> = bincode::deserialize(payload)?;
+    wire.into_iter()
+        .map(|hint| {
+            Ok(AddressHint {
+                peer: PeerId::from_bytes(&hint.peer)?,
+                addresses: hint
+                    .addresses
+                    .into_iter()
+                    .map(Multiaddr::try_from)
+                    .collect::<Result<_, _>>()?,
+                seen_at: hint.seen_at,
+            })
+        })
+        .collect()
+}
+
+/// Who we trade hints with, and whose addresses we pass on. Nobody, by
+/// default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexPolicy {
+    /// Contacts we ask and answer.
+    pub trusted: HashSet<PeerId>,
+    /// Contacts whose addresses may be passed on.
+    pub shared: HashSet<PeerId>,
+}
+
+/// Where we've reached each contact, for passing on.
+#[derive(Debug, Clone, Default)]
+pub struct HintBook {
+    seen: HashMap<PeerId, AddressHint>,
+}
+
+impl HintBook {
+    /// We reached `peer` by dialing `addr` at `now`. Addresses on private
+    /// networks are no use elsewhere and say too much, so aren't kept.
+    pub fn reached(&mut self, peer: PeerId, addr: Multiaddr, now: u64) {
+        if is_local_address(&addr) {
+            return;
+        }
+        let hint = self.seen.entry(peer).or_insert_with(|| AddressHint { peer, addresses: Vec::new(), seen_at: now });
+        hint.addresses.retain(|a| *a != addr);
+        hint.addresses.insert(0, addr);
+        hint.addresses.truncate(MAX_HINT_ADDRESSES);
+        hint.seen_at = now;
+    }
+
+    /// Hints to give `asker` for the contacts it `wanted`, as far as
+    /// `policy` allows.
+    pub fn hints_for(&self, asker: &PeerId, wanted: &[[u8; 32]], policy: &PexPolicy, now: u64) -> Vec<AddressHint> {
+        if !policy.trusted.contains(asker) {
+            return Vec::new();
+        }
+        let wanted: HashSet<&[u8; 32]> = wanted.iter().take(MAX_PEX_WANTED).collect();
+        self.seen
+            .values()
+            .filter(|hint| hint.peer != *asker && policy.shared.contains(&hint.peer) && hint.is_fresh(now))
+            .filter(|hint| wanted.contains(&hint_tag(asker, &hint.peer)))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Peer exchange codec for request-response: bincode, size-limited.
+#[derive(Debug, Clone, Default)]
+pub struct PexCodec;
+
+/// Read a bincode message of at most `MAX_PEX_MESSAGE_SIZE` bytes.
+async fn read_message<T, M>(io: &mut T) -> std::io::Result<M>
+where
+    T: futures::AsyncRead + Unpin + Send,
+    M: serde::de::DeserializeOwned,
+{
+    let mut buf = Vec::new();
+    futures::AsyncReadExt::read_to_end(&mut futures::AsyncReadExt::take(io, MAX_PEX_MESSAGE_SIZE), &mut buf).await?;
+    bincode::deserialize(&buf).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Write a bincode message and close the stream.
+async fn write_message<T, M>(io: &mut T, message: &M) -> std::io::Result<()>
+where
+    T: futures::AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let buf = bincode::serialize(message).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    futures::AsyncWriteExt::write_all(io, &buf).await?;
+    futures::AsyncWriteExt::close(io).await
+}
+
+impl request_response::Codec for PexCodec {
+    type Protocol = StreamProtocol;
+    type Request = PexRequest;
+    type Response = PexResponse;
+
+    fn read_request<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<Self::Request>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncRead + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(read_message(io))
+    }
+
+    fn read_response<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<Self::Response>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncRead + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(read_message(io))
+    }
+
+    fn write_request<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+        req: Self::Request,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<()>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncWrite + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move { write_message(io, &req).await })
+    }
+
+    fn write_response<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+        res: Self::Response,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<()>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncWrite + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move { write_message(io, &res).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(port: u16) -> Multiaddr {
+        format!("/ip4/203.0.113.9/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn hints_must_be_signed_by_the_sender() {
+        let bob = Keypair::generate_ed25519();
+        let bob_id = bob.public().to_peer_id();
+        let hints = vec![AddressHint { peer: PeerId::random(), addresses: vec![public(1)], seen_at: 1000 }];
+        let bytes = encode_hints(&bob, &hints).unwrap();
+        assert_eq!(decode_hints(&bob_id, &bytes).unwrap(), hints);
+        assert!(decode_hints(&PeerId::random(), &bytes).is_err());
+        assert!(decode_hints(&bob_id, &encode_hints(&bob, &[]).unwrap()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_trusted_askers_get_shared_contacts_they_name() {
+        let (alice, carol, dave) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut book = HintBook::default();
+        book.reached(carol, public(1), 1000);
+        book.reached(carol, public(2), 1000);
+        book.reached(dave, public(3), 1000);
+        // Private addresses aren't passed on
+        book.reached(dave, "/ip4/192.168.1.5/tcp/4".parse().unwrap(), 1000);
+
+        let mut policy = PexPolicy { trusted: HashSet::new(), shared: [carol, dave].into() };
+        let wanted = [hint_tag(&alice, &carol), hint_tag(&alice, &dave)];
+        assert!(book.hints_for(&alice, &wanted, &policy, 1000).is_empty());
+
+        policy.trusted.insert(alice);
+        let mut hints = book.hints_for(&alice, &wanted, &policy, 1060);
+        hints.sort_by_key(|h| h.peer != carol);
+        assert_eq!(hints[0], AddressHint { peer: carol, addresses: vec![public(2), public(1)], seen_at: 1000 });
+        assert_eq!(hints[1].addresses, vec![public(3)]);
+
+        // Tags only match for the asker they were made for
+        assert!(book.hints_for(&alice, &[hint_tag(&PeerId::random(), &carol)], &policy, 1000).is_empty());
+        // Contacts kept private, and stale hints, aren't shared
+        policy.shared.remove(&dave);
+        assert_eq!(book.hints_for(&alice, &wanted, &policy, 1000).len(), 1);
+        assert!(book.hints_for(&alice, &wanted, &policy, 1000 + MAX_HINT_AGE_SECS).is_empty());
+    }
+}
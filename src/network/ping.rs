@@ -0,0 +1,224 @@
+//! Latency measurement.
+//!
+//! Each peer is pinged when it connects and every `PING_INTERVAL_SECS`
+//! after: a random payload goes out over `/whisper/ping/1.0.0` and comes
+//! back unchanged. The round trip time, and whether the connection goes
+//! through a relay, tell the user how good their path to the peer is.
+//!
+//! libp2p's ping protocol isn't part of our build, so this is a small one
+//! of our own over request-response.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Duration;
+
+use libp2p::{request_response, PeerId, StreamProtocol};
+use web_time::Instant;
+
+/// Protocol name for Whisper pings.
+pub const PING_PROTOCOL: &str = "/whisper/ping/1.0.0";
+
+/// How often connected peers are pinged, in seconds.
+pub const PING_INTERVAL_SECS: u64 = 15;
+
+/// Size of a ping payload in bytes.
+pub const PING_SIZE: usize = 32;
+
+/// Random bytes sent in a ping and echoed back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingPayload(pub [u8; PING_SIZE]);
+
+impl PingPayload {
+    /// A fresh random payload.
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+}
+
+/// The last measured round trip to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Latency {
+    pub rtt: Duration,
+    /// Whether every connection to the peer goes through a relay.
+    pub relayed: bool,
+}
+
+impl std::fmt::Display for Latency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let path = if self.relayed { "relayed" } else { "direct" };
+        write!(f, "{} ms {}", self.rtt.as_millis(), path)
+    }
+}
+
+/// Pings waiting for their echo, keyed by request ID.
+#[derive(Debug, Clone)]
+pub struct PingTracker<K> {
+    in_flight: HashMap<K, (PeerId, Instant, PingPayload)>,
+}
+
+impl<K> Default for PingTracker<K> {
+    fn default() -> Self {
+        Self {
+            in_flight: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq> PingTracker<K> {
+    /// A ping with `payload` went to `peer` at `now`.
+    pub fn sent(&mut self, id: K, peer: PeerId, payload: PingPayload, now: Instant) {
+        self.in_flight.insert(id, (peer, now, payload));
+    }
+
+    /// An echo arrived for ping `id`. Returns who it was from and the
+    /// round trip time, unless the ping is unknown or the echo doesn't
+    /// match what we sent.
+    pub fn answered(&mut self, id: &K, echo: &PingPayload, now: Instant) -> Option<(PeerId, Duration)> {
+        let (peer, sent_at, payload) = self.in_flight.remove(id)?;
+        (payload == *echo).then(|| (peer, now.saturating_duration_since(sent_at)))
+    }
+
+    /// Ping `id` failed or timed out.
+    pub fn failed(&mut self, id: &K) {
+        self.in_flight.remove(id);
+    }
+
+    /// Whether a ping to `peer` is waiting for its echo.
+    pub fn is_pinging(&self, peer: &PeerId) -> bool {
+        self.in_flight.values().any(|(p, ..)| p == peer)
+    }
+}
+
+/// Codec for pings: the payload, echoed back.
+#[derive(Debug, Clone, Default)]
+pub struct PingCodec;
+
+async fn read_payload<T>(io: &mut T) -> std::io::Result<PingPayload>
+where
+    T: futures::AsyncRead + Unpin + Send,
+{
+    let mut buf = [0u8; PING_SIZE];
+    futures::AsyncReadExt::read_exact(io, &mut buf).await?;
+    Ok(PingPayload(buf))
+}
+
+async fn write_payload<T>(io: &mut T, payload: &PingPayload) -> std::io::Result<()>
+where
+    T: futures::AsyncWrite + Unpin + Send,
+{
+    futures::AsyncWriteExt::write_all(io, &payload.0).await?;
+    futures::AsyncWriteExt::close(io).await?;
+    Ok(())
+}
+
+impl request_response::Codec for PingCodec {
+    type Protocol = StreamProtocol;
+    type Request = PingPayload;
+    type Response = PingPayload;
+
+    fn read_request<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<Self::Request>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncRead + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(read_payload(io))
+    }
+
+    fn read_response<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<Self::Response>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncRead + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(read_payload(io))
+    }
+
+    fn write_request<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+        req: Self::Request,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<()>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncWrite + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move { write_payload(io, &req).await })
+    }
+
+    fn write_response<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+        res: Self::Response,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<()>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncWrite + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move { write_payload(io, &res).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echo_gives_round_trip_time() {
+        let mut tracker = PingTracker::default();
+        let peer = PeerId::random();
+        let payload = PingPayload::random();
+        let start = Instant::now();
+
+        tracker.sent(1u32, peer, payload, start);
+        assert!(tracker.is_pinging(&peer));
+        let answer = tracker.answered(&1, &payload, start + Duration::from_millis(42));
+        assert_eq!(answer, Some((peer, Duration::from_millis(42))));
+        assert!(!tracker.is_pinging(&peer));
+
+        // Each ping is answered once
+        assert_eq!(tracker.answered(&1, &payload, start), None);
+    }
+
+    #[test]
+    fn wrong_echo_is_ignored() {
+        let mut tracker = PingTracker::default();
+        let peer = PeerId::random();
+        let start = Instant::now();
+
+        tracker.sent(1u32, peer, PingPayload([1; PING_SIZE]), start);
+        assert_eq!(tracker.answered(&1, &PingPayload([2; PING_SIZE]), start), None);
+
+        tracker.sent(2, peer, PingPayload::random(), start);
+        tracker.failed(&2);
+        assert!(!tracker.is_pinging(&peer));
+    }
+
+    #[test]
+    fn latency_displays_path() {
+        let direct = Latency { rtt: Duration::from_millis(42), relayed: false };
+        assert_eq!(direct.to_string(), "42 ms direct");
+        let relayed = Latency { rtt: Duration::from_millis(180), relayed: true };
+        assert_eq!(relayed.to_string(), "180 ms relayed");
+    }
+}
@@ -0,0 +1,208 @@
+//! Dialing through a SOCKS5 proxy, such as Tor.
+//!
+//! With a proxy set, every outbound TCP connection goes through it and
+//! host names, `.onion` ones included, are resolved by the proxy rather
+//! than locally, so nothing about who we dial leaks onto the local
+//! network. The node also stops using mDNS and only listens on loopback
+//! (where a Tor hidden service can forward to it) and relay addresses.
+
+use std::borrow::Cow;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::future::{BoxFuture, FutureExt, Pending};
+use libp2p::core::transport::{DialOpts, ListenerId, TransportError, TransportEvent};
+use libp2p::core::Transport;
+use libp2p::multiaddr::Protocol;
+use libp2p::{tcp, Multiaddr};
+use tokio_socks::tcp::Socks5Stream;
+use tokio_socks::TargetAddr;
+
+/// Where Tor listens for SOCKS connections by default.
+pub const DEFAULT_TOR_PROXY: &str = "127.0.0.1:9050";
+
+/// A dial-only transport that connects through a SOCKS5 proxy.
+#[derive(Debug, Clone)]
+pub struct Socks5Transport {
+    proxy: SocketAddr,
+}
+
+impl Socks5Transport {
+    /// Dial through the proxy at `proxy`.
+    pub fn new(proxy: SocketAddr) -> Self {
+        Self { proxy }
+    }
+}
+
+impl Transport for Socks5Transport {
+    type Output = tcp::tokio::TcpStream;
+    type Error = io::Error;
+    type ListenerUpgrade = Pending<Result<Self::Output, Self::Error>>;
+    type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn listen_on(&mut self, _id: ListenerId, addr: Multiaddr) -> Result<(), TransportError<Self::Error>> {
+        Err(TransportError::MultiaddrNotSupported(addr))
+    }
+
+    fn remove_listener(&mut self, _id: ListenerId) -> bool {
+        false
+    }
+
+    fn dial(&mut self, addr: Multiaddr, _opts: DialOpts) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let Some(target) = socks_target(&addr) else {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        };
+        let proxy = self.proxy;
+        Ok(async move {
+            let stream = Socks5Stream::connect(proxy, target).await.map_err(io::Error::other)?;
+            let stream = stream.into_inner();
+            stream.set_nodelay(true)?;
+            Ok(tcp::tokio::TcpStream(stream))
+        }
+        .boxed())
+    }
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        Poll::Pending
+    }
+}
+
+/// Where to ask the proxy to connect for `addr`: an IP or host name
+/// followed by a TCP port, or an onion address with its port, optionally
+/// ending in `/p2p/<peer id>`. Anything else can't go through SOCKS.
+pub fn socks_target(addr: &Multiaddr) -> Option<TargetAddr<'static>> {
+    let mut protocols = addr.iter();
+    let target = match (protocols.next()?, protocols.next()) {
+        (Protocol::Ip4(ip), Some(Protocol::Tcp(port))) => TargetAddr::Ip(SocketAddr::new(IpAddr::V4(ip), port)),
+        (Protocol::Ip6(ip), Some(Protocol::Tcp(port))) => TargetAddr::Ip(SocketAddr::new(IpAddr::V6(ip), port)),
+        (Protocol::Dns(host) | Protocol::Dns4(host) | Protocol::Dns6(host), Some(Protocol::Tcp(port))) => {
+            TargetAddr::Domain(Cow::Owned(host.into_owned()), port)
+        }
+        (Protocol::Onion3(onion), next) => {
+            let host = format!("{}.onion", base32_lower(onion.hash()));
+            let target = TargetAddr::Domain(Cow::Owned(host), onion.port());
+            return match next {
+                None | Some(Protocol::P2p(_)) if protocols.next().is_none() => Some(target),
+                _ => None,
+            };
+        }
+        _ => return None,
+    };
+    match protocols.next() {
+        None => Some(target),
+        Some(Protocol::P2p(_)) if protocols.next().is_none() => Some(target),
+        _ => None,
+    }
+}
+
+/// Whether `addr` is on this machine only.
+pub fn is_loopback_address(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => ip.is_loopback(),
+        Some(Protocol::Ip6(ip)) => ip.is_loopback(),
+        _ => false,
+    }
+}
+
+/// Whether `addr` is a Tor onion address.
+pub fn is_onion_address(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| matches!(p, Protocol::Onion(..) | Protocol::Onion3(_)))
+}
+
+/// Lowercase RFC 4648 base32 without padding, as onion host names use.
+fn base32_lower(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::core::transport::PortUse;
+    use libp2p::core::Endpoint;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const ONION: &str = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd";
+
+    #[test]
+    fn targets_for_tcp_and_onion_addresses() {
+        let ip: Multiaddr = "/ip4/203.0.113.5/tcp/4001/p2p/12D3KooWD3eckifWpRn9wQpMG9R9hX3sD158z7EqHWmweQAJU5SA"
+            .parse()
+            .unwrap();
+        assert_eq!(socks_target(&ip), Some(TargetAddr::Ip("203.0.113.5:4001".parse().unwrap())));
+
+        let dns: Multiaddr = "/dns4/relay.example.com/tcp/443".parse().unwrap();
+        assert_eq!(socks_target(&dns), Some(TargetAddr::Domain("relay.example.com".into(), 443)));
+
+        let onion: Multiaddr = format!("/onion3/{}:4001", ONION).parse().unwrap();
+        assert!(is_onion_address(&onion));
+        assert_eq!(
+            socks_target(&onion),
+            Some(TargetAddr::Domain(format!("{}.onion", ONION).into(), 4001))
+        );
+
+        // QUIC and relay circuits don't go through SOCKS
+        assert_eq!(socks_target(&"/ip4/1.2.3.4/udp/4001/quic-v1".parse().unwrap()), None);
+        let circuit: Multiaddr = "/ip4/1.2.3.4/tcp/4001/p2p/12D3KooWD3eckifWpRn9wQpMG9R9hX3sD158z7EqHWmweQAJU5SA/p2p-circuit"
+            .parse()
+            .unwrap();
+        assert_eq!(socks_target(&circuit), None);
+        assert!(!is_onion_address(&circuit));
+    }
+
+    #[tokio::test]
+    async fn dials_through_the_proxy() {
+        // A SOCKS5 proxy that only accepts CONNECT to a domain, then echoes
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = proxy.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[5, 0]).await.unwrap();
+            let mut head = [0u8; 5];
+            socket.read_exact(&mut head).await.unwrap();
+            assert_eq!(head[..4], [5, 1, 0, 3]);
+            let mut host = vec![0u8; head[4] as usize + 2];
+            socket.read_exact(&mut host).await.unwrap();
+            socket.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            let mut ping = [0u8; 4];
+            socket.read_exact(&mut ping).await.unwrap();
+            socket.write_all(&ping).await.unwrap();
+            String::from_utf8(host[..host.len() - 2].to_vec()).unwrap()
+        });
+
+        let mut transport = Socks5Transport::new(proxy_addr);
+        let addr: Multiaddr = format!("/onion3/{}:4001", ONION).parse().unwrap();
+        let opts = DialOpts { role: Endpoint::Dialer, port_use: PortUse::New };
+        let mut stream = transport.dial(addr, opts).unwrap().await.unwrap();
+        futures::AsyncWriteExt::write_all(&mut stream, b"ping").await.unwrap();
+        let mut echo = [0u8; 4];
+        futures::AsyncReadExt::read_exact(&mut stream, &mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+        assert_eq!(server.await.unwrap(), format!("{}.onion", ONION));
+    }
+
+    #[test]
+    fn proxy_transport_does_not_listen() {
+        let mut transport = Socks5Transport::new("127.0.0.1:9050".parse().unwrap());
+        let result = transport.listen_on(ListenerId::next(), "/ip4/0.0.0.0/tcp/0".parse().unwrap());
+        assert!(matches!(result, Err(TransportError::MultiaddrNotSupported(_))));
+    }
+}
@@ -0,0 +1,247 @@
+//! Inbound rate limiting.
+//!
+//! Anyone can open a connection and send us messages, so each peer gets a
+//! token bucket, and so does the node as a whole. A peer that empties its
+//! bucket is ignored for a while.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use libp2p::PeerId;
+use web_time::Instant;
+
+/// Messages a single peer may send per minute by default.
+pub const DEFAULT_PEER_MESSAGES_PER_MINUTE: u32 = 120;
+
+/// Messages all peers together may send per minute by default.
+pub const DEFAULT_GLOBAL_MESSAGES_PER_MINUTE: u32 = 1200;
+
+/// How long a peer that hits its limit is ignored by default, in seconds.
+pub const DEFAULT_RATE_LIMIT_BAN_SECS: u64 = 60;
+
+/// Peer buckets kept before idle ones are dropped.
+const MAX_TRACKED_PEERS: usize = 1024;
+
+/// Limits on inbound messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    /// Messages one peer may send per minute; also the burst size.
+    pub peer_per_minute: u32,
+    /// Messages all peers together may send per minute.
+    pub global_per_minute: u32,
+    /// How long to ignore a peer after it hits its limit.
+    pub ban: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            peer_per_minute: DEFAULT_PEER_MESSAGES_PER_MINUTE,
+            global_per_minute: DEFAULT_GLOBAL_MESSAGES_PER_MINUTE,
+            ban: Duration::from_secs(DEFAULT_RATE_LIMIT_BAN_SECS),
+        }
+    }
+}
+
+/// What to do with an inbound message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateDecision {
+    /// Within the limits.
+    Allow,
+    /// A limit was just hit. The peer is ignored for the given time, which
+    /// is zero if only the global limit was hit.
+    Limited(Duration),
+    /// Dropped without telling anyone: the peer is still being ignored, or
+    /// the global limit is still exhausted.
+    Drop,
+}
+
+/// A bucket holding up to `capacity` tokens, refilled continuously.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket allowing `per_minute` messages a minute.
+    fn new(per_minute: u32, now: Instant) -> Self {
+        let capacity = f64::from(per_minute.max(1));
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_per_sec: capacity / 60.0,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Take a token if there is one.
+    fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// Token-bucket limiter for inbound messages, per peer and overall.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    global: TokenBucket,
+    /// Whether the last message was dropped for the global limit.
+    global_exhausted: bool,
+    peers: HashMap<PeerId, TokenBucket>,
+    /// Peers being ignored, until when.
+    banned: HashMap<PeerId, Instant>,
+}
+
+impl RateLimiter {
+    /// Create a limiter with full buckets.
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            global: TokenBucket::new(config.global_per_minute, Instant::now()),
+            global_exhausted: false,
+            peers: HashMap::new(),
+            banned: HashMap::new(),
+        }
+    }
+
+    /// The limits in force.
+    pub fn config(&self) -> RateLimitConfig {
+        self.config
+    }
+
+    /// Whether a peer is currently being ignored.
+    pub fn is_banned(&self, peer: &PeerId, now: Instant) -> bool {
+        self.banned.get(peer).is_some_and(|until| now < *until)
+    }
+
+    /// Decide whether to accept a message from `peer` arriving at `now`.
+    pub fn check(&mut self, peer: &PeerId, now: Instant) -> RateDecision {
+        if self.is_banned(peer, now) {
+            return RateDecision::Drop;
+        }
+        self.banned.remove(peer);
+
+        if self.peers.len() >= MAX_TRACKED_PEERS {
+            self.peers.retain(|_, bucket| !bucket.is_full(now));
+        }
+        let per_minute = self.config.peer_per_minute;
+        let bucket = self
+            .peers
+            .entry(*peer)
+            .or_insert_with(|| TokenBucket::new(per_minute, now));
+        if !bucket.try_take(now) {
+            self.peers.remove(peer);
+            self.banned.insert(*peer, now + self.config.ban);
+            return RateDecision::Limited(self.config.ban);
+        }
+
+        if !self.global.try_take(now) {
+            // Report the first drop, not every one after it
+            let first = !self.global_exhausted;
+            self.global_exhausted = true;
+            return if first { RateDecision::Limited(Duration::ZERO) } else { RateDecision::Drop };
+        }
+        self.global_exhausted = false;
+        RateDecision::Allow
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimitConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(peer: u32, global: u32) -> RateLimitConfig {
+        RateLimitConfig {
+            peer_per_minute: peer,
+            global_per_minute: global,
+            ban: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn allows_bursts_up_to_the_peer_limit() {
+        let mut limiter = RateLimiter::new(config(5, 100));
+        let peer = PeerId::random();
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            assert_eq!(limiter.check(&peer, now), RateDecision::Allow);
+        }
+        assert_eq!(limiter.check(&peer, now), RateDecision::Limited(Duration::from_secs(30)));
+        assert!(limiter.is_banned(&peer, now));
+        assert_eq!(limiter.check(&peer, now), RateDecision::Drop);
+
+        // Other peers are unaffected
+        assert_eq!(limiter.check(&PeerId::random(), now), RateDecision::Allow);
+    }
+
+    #[test]
+    fn ban_expires_with_a_full_bucket() {
+        let mut limiter = RateLimiter::new(config(2, 100));
+        let peer = PeerId::random();
+        let now = Instant::now();
+
+        limiter.check(&peer, now);
+        limiter.check(&peer, now);
+        assert!(matches!(limiter.check(&peer, now), RateDecision::Limited(_)));
+        assert_eq!(limiter.check(&peer, now + Duration::from_secs(29)), RateDecision::Drop);
+
+        let later = now + Duration::from_secs(31);
+        assert!(!limiter.is_banned(&peer, later));
+        assert_eq!(limiter.check(&peer, later), RateDecision::Allow);
+        assert_eq!(limiter.check(&peer, later), RateDecision::Allow);
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let mut limiter = RateLimiter::new(config(60, 1000));
+        let peer = PeerId::random();
+        let now = Instant::now();
+
+        for _ in 0..60 {
+            assert_eq!(limiter.check(&peer, now), RateDecision::Allow);
+        }
+        // One token a second
+        assert_eq!(limiter.check(&peer, now + Duration::from_secs(1)), RateDecision::Allow);
+    }
+
+    #[test]
+    fn global_limit_drops_without_banning() {
+        let mut limiter = RateLimiter::new(config(10, 3));
+        let now = Instant::now();
+        let peers: Vec<PeerId> = (0..5).map(|_| PeerId::random()).collect();
+
+        for peer in &peers[..3] {
+            assert_eq!(limiter.check(peer, now), RateDecision::Allow);
+        }
+        assert_eq!(limiter.check(&peers[3], now), RateDecision::Limited(Duration::ZERO));
+        assert_eq!(limiter.check(&peers[4], now), RateDecision::Drop);
+        assert!(!limiter.is_banned(&peers[3], now));
+    }
+}
@@ -1,10 +1,14 @@
 //! NAT traversal with relay nodes.
 
+use libp2p::{autonat::NatStatus, Multiaddr, PeerId};
+
+#[cfg(feature = "native")]
 use anyhow::Result;
-use libp2p::{Multiaddr, PeerId};
-use std::net::UdpSocket;
 
+use super::discovery::merge_nodes;
+#[cfg(feature = "native")]
 use super::discovery::extract_peer_id;
+#[cfg(feature = "native")]
 use super::node::WhisperNode;
 
 /// Default relay connection timeout in seconds.
@@ -14,6 +18,7 @@ pub const RELAY_CONNECT_TIMEOUT_SECS: u64 = 30;
 /// 
 /// The relay address should include the peer ID of the relay.
 /// Example: /ip4/1.2.3.4/tcp/4001/p2p/12D3KooW...
+#[cfg(feature = "native")]
 pub fn connect_to_relay(node: &mut WhisperNode, relay_addr: Multiaddr) -> Result<()> {
     // Extract peer ID from the relay address
     let relay_peer_id = extract_peer_id(&relay_addr)
@@ -24,6 +29,12 @@ pub fn connect_to_relay(node: &mut WhisperNode, relay_addr: Multiaddr) -> Result
         .behaviour_mut()
         .kademlia
         .add_address(&relay_peer_id, relay_addr.clone());
+
+    // Relays are publicly reachable, so they make good AutoNAT servers
+    node.swarm_mut()
+        .behaviour_mut()
+        .autonat
+        .add_server(relay_peer_id, Some(relay_addr.clone()));
     
     // Dial the relay
     node.dial(relay_addr)?;
@@ -31,40 +42,63 @@ pub fn connect_to_relay(node: &mut WhisperNode, relay_addr: Multiaddr) -> Result
     Ok(())
 }
 
-/// Check if we're likely behind NAT.
-/// 
-/// This performs a simple check by attempting to bind to a public-facing socket.
-/// Not 100% reliable but provides a good heuristic.
-pub fn is_behind_nat() -> bool {
-    // Try to determine our external IP by connecting to a known server
-    // If our local IP differs from what external services see, we're behind NAT
-    
-    // Simple heuristic: if we only have private IPs, assume NAT
-    match get_local_ip() {
-        Some(ip) => {
-            ip.is_private() || ip.is_loopback() || ip.is_link_local()
+/// Our reachability as last reported by AutoNAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reachability {
+    /// Peers could dial us back on one of our addresses.
+    Public,
+    /// Dial-back probes failed; we need a relay to be reached.
+    BehindNat,
+    /// Not enough probes have completed yet.
+    Unknown,
+}
+
+impl Reachability {
+    /// Stable name used for storage.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reachability::Public => "public",
+            Reachability::BehindNat => "behind_nat",
+            Reachability::Unknown => "unknown",
+        }
+    }
+
+    /// Parse a name produced by `as_str`.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "public" => Some(Reachability::Public),
+            "behind_nat" => Some(Reachability::BehindNat),
+            "unknown" => Some(Reachability::Unknown),
+            _ => None,
         }
-        None => true, // Assume NAT if we can't determine
     }
 }
 
-/// Get the local IP address by creating a UDP socket.
-fn get_local_ip() -> Option<std::net::Ipv4Addr> {
-    // Create a UDP socket and connect to a public DNS server
-    // This doesn't actually send any data, just determines the route
-    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
-    socket.connect("8.8.8.8:80").ok()?;
-    
-    match socket.local_addr().ok()? {
-        std::net::SocketAddr::V4(addr) => Some(*addr.ip()),
-        std::net::SocketAddr::V6(_) => None,
+impl From<&NatStatus> for Reachability {
+    fn from(status: &NatStatus) -> Self {
+        match status {
+            NatStatus::Public(_) => Reachability::Public,
+            NatStatus::Private => Reachability::BehindNat,
+            NatStatus::Unknown => Reachability::Unknown,
+        }
+    }
+}
+
+impl std::fmt::Display for Reachability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reachability::Public => write!(f, "Public"),
+            Reachability::BehindNat => write!(f, "Behind NAT"),
+            Reachability::Unknown => write!(f, "Unknown"),
+        }
     }
 }
 
-/// Known public relay nodes for the Whisper network.
-pub fn public_relays() -> Vec<Multiaddr> {
+/// Known public relay nodes for the Whisper network, then the `user`
+/// added ones.
+pub fn public_relays(user: &[Multiaddr]) -> Vec<Multiaddr> {
     // In production, these would be maintained relay nodes
-    vec![]
+    merge_nodes(Vec::new(), user)
 }
 
 /// Create a relay listening address.
@@ -87,15 +121,25 @@ mod tests {
     use super::*;
 
     #[test]
-    fn is_behind_nat_returns_bool() {
-        // Just verify the function runs without panicking
-        let _result = is_behind_nat();
-        // Function compiles and returns a bool, that's the test
+    fn reachability_from_nat_status() {
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+        assert_eq!(Reachability::from(&NatStatus::Public(addr)), Reachability::Public);
+        assert_eq!(Reachability::from(&NatStatus::Private), Reachability::BehindNat);
+        assert_eq!(Reachability::from(&NatStatus::Unknown), Reachability::Unknown);
+    }
+
+    #[test]
+    fn reachability_roundtrips_through_str() {
+        for r in [Reachability::Public, Reachability::BehindNat, Reachability::Unknown] {
+            assert_eq!(Reachability::parse(r.as_str()), Some(r));
+        }
+        assert_eq!(Reachability::parse("bogus"), None);
+        assert_eq!(Reachability::BehindNat.to_string(), "Behind NAT");
     }
 
     #[test]
     fn public_relays_returns_vec() {
-        let relays = public_relays();
+        let relays = public_relays(&[]);
         // Should return a valid (possibly empty) vector
         for addr in relays {
             let _ = addr.to_string();
@@ -0,0 +1,327 @@
+//! Rendezvous points.
+//!
+//! Peers behind NAT may not reach the DHT, or have no address worth
+//! publishing there. A rendezvous point is a reachable Whisper node that
+//! holds signed address records for others: each side of a conversation
+//! registers under a namespace only the two of them can work out (a hash of
+//! both peer IDs) and asks the point for the other's record.
+//!
+//! This follows the shape of the libp2p rendezvous protocol (register,
+//! unregister, discover) over a request-response stream of its own, so
+//! any `whisper listen --rendezvous-server` can act as a point.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use libp2p::{request_response, PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use web_time::Instant;
+
+use super::discovery::decode_address_record;
+
+/// Protocol name for Whisper rendezvous.
+pub const RENDEZVOUS_PROTOCOL: &str = "/whisper/rendezvous/1.0.0";
+
+/// Longest a registration is kept, in seconds. Clients re-register well
+/// before this runs out.
+pub const RENDEZVOUS_TTL_SECS: u64 = 2 * 60 * 60;
+
+/// Most namespaces one peer may be registered under at a point.
+pub const MAX_REGISTRATIONS_PER_PEER: usize = 1000;
+
+/// Longest namespace a point accepts.
+pub const MAX_NAMESPACE_LEN: usize = 255;
+
+/// Largest rendezvous request or response we read.
+pub const MAX_RENDEZVOUS_MESSAGE_SIZE: u64 = 256 * 1024;
+
+/// Request sent to a rendezvous point.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RendezvousRequest {
+    /// Keep our signed address record under `namespace` for `ttl` seconds.
+    Register { namespace: String, record: Vec<u8>, ttl: u64 },
+    /// Drop our registration under `namespace`.
+    Unregister { namespace: String },
+    /// Everyone else's records under `namespace`.
+    Discover { namespace: String },
+}
+
+/// A rendezvous point's answer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RendezvousResponse {
+    /// Registered, for this many seconds.
+    Registered { ttl: u64 },
+    Unregistered,
+    /// Signed address records found.
+    Registrations(Vec<Vec<u8>>),
+    Rejected(String),
+}
+
+/// The namespace two peers meet under. It's the same whichever of them
+/// computes it, and the point can't tell who it belongs to.
+pub fn pair_namespace(a: &PeerId, b: &PeerId) -> String {
+    let (a, b) = if a.to_bytes() <= b.to_bytes() { (a, b) } else { (b, a) };
+    let mut hasher = blake3::Hasher::new_derive_key("whisper 2026 rendezvous namespace");
+    hasher.update(&a.to_bytes());
+    hasher.update(&b.to_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+/// A record held for a peer.
+#[derive(Debug, Clone)]
+struct Registration {
+    record: Vec<u8>,
+    expires: Instant,
+}
+
+/// The registrations a rendezvous point holds.
+#[derive(Debug, Clone, Default)]
+pub struct RendezvousStore {
+    namespaces: HashMap<String, HashMap<PeerId, Registration>>,
+}
+
+impl RendezvousStore {
+    /// Answer a request from `peer`.
+    pub fn handle(&mut self, peer: PeerId, request: RendezvousRequest, now: Instant) -> RendezvousResponse {
+        match request {
+            RendezvousRequest::Register { namespace, record, ttl } => self.register(peer, namespace, record, ttl, now),
+            RendezvousRequest::Unregister { namespace } => {
+                if let Some(registrations) = self.namespaces.get_mut(&namespace) {
+                    registrations.remove(&peer);
+                    if registrations.is_empty() {
+                        self.namespaces.remove(&namespace);
+                    }
+                }
+                RendezvousResponse::Unregistered
+            }
+            RendezvousRequest::Discover { namespace } => {
+                let records = self
+                    .namespaces
+                    .get(&namespace)
+                    .map(|registrations| {
+                        registrations
+                            .iter()
+                            .filter(|(p, r)| **p != peer && r.expires > now)
+                            .map(|(_, r)| r.record.clone())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                RendezvousResponse::Registrations(records)
+            }
+        }
+    }
+
+    fn register(&mut self, peer: PeerId, namespace: String, record: Vec<u8>, ttl: u64, now: Instant) -> RendezvousResponse {
+        if namespace.is_empty() || namespace.len() > MAX_NAMESPACE_LEN {
+            return RendezvousResponse::Rejected("Invalid namespace".to_string());
+        }
+        // Only the peer a record is for may register it
+        if let Err(e) = decode_address_record(&peer, &record) {
+            return RendezvousResponse::Rejected(e.to_string());
+        }
+        self.prune(now);
+        let already = self.namespaces.get(&namespace).is_some_and(|r| r.contains_key(&peer));
+        if !already && self.registrations_of(&peer) >= MAX_REGISTRATIONS_PER_PEER {
+            return RendezvousResponse::Rejected("Too many registrations".to_string());
+        }
+        let ttl = ttl.min(RENDEZVOUS_TTL_SECS);
+        let expires = now + Duration::from_secs(ttl);
+        self.namespaces.entry(namespace).or_default().insert(peer, Registration { record, expires });
+        RendezvousResponse::Registered { ttl }
+    }
+
+    /// How many namespaces `peer` is registered under.
+    pub fn registrations_of(&self, peer: &PeerId) -> usize {
+        self.namespaces.values().filter(|r| r.contains_key(peer)).count()
+    }
+
+    /// Forget registrations that have run out.
+    fn prune(&mut self, now: Instant) {
+        self.namespaces.retain(|_, registrations| {
+            registrations.retain(|_, r| r.expires > now);
+            !registrations.is_empty()
+        });
+    }
+}
+
+/// Rendezvous codec for request-response: bincode, size-limited.
+#[derive(Debug, Clone, Default)]
+pub struct RendezvousCodec;
+
+/// Read a bincode message of at most `MAX_RENDEZVOUS_MESSAGE_SIZE` bytes.
+async fn read_message<T, M>(io: &mut T) -> std::io::Result<M>
+where
+    T: futures::AsyncRead + Unpin + Send,
+    M: serde::de::DeserializeOwned,
+{
+    let mut buf = Vec::new();
+    futures::AsyncReadExt::read_to_end(
+        &mut futures::AsyncReadExt::take(io, MAX_RENDEZVOUS_MESSAGE_SIZE),
+        &mut buf,
+    )
+    .await?;
+    bincode::deserialize(&buf).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Write a bincode message and close the stream.
+async fn write_message<T, M>(io: &mut T, message: &M) -> std::io::Result<()>
+where
+    T: futures::AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let buf = bincode::serialize(message).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    futures::AsyncWriteExt::write_all(io, &buf).await?;
+    futures::AsyncWriteExt::close(io).await
+}
+
+impl request_response::Codec for RendezvousCodec {
+    type Protocol = StreamProtocol;
+    type Request = RendezvousRequest;
+    type Response = RendezvousResponse;
+
+    fn read_request<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<Self::Request>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncRead + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(read_message(io))
+    }
+
+    fn read_response<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<Self::Response>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncRead + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(read_message(io))
+    }
+
+    fn write_request<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+        req: Self::Request,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<()>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncWrite + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move { write_message(io, &req).await })
+    }
+
+    fn write_response<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+        res: Self::Response,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<()>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncWrite + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move { write_message(io, &res).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::encode_address_record;
+    use libp2p::identity::Keypair;
+
+    fn record(keypair: &Keypair) -> Vec<u8> {
+        encode_address_record(keypair, vec!["/ip4/203.0.113.9/tcp/4001".parse().unwrap()]).unwrap()
+    }
+
+    fn register(namespace: &str, keypair: &Keypair) -> RendezvousRequest {
+        RendezvousRequest::Register {
+            namespace: namespace.to_string(),
+            record: record(keypair),
+            ttl: RENDEZVOUS_TTL_SECS,
+        }
+    }
+
+    #[test]
+    fn pair_namespace_is_symmetric() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        assert_eq!(pair_namespace(&a, &b), pair_namespace(&b, &a));
+        assert_ne!(pair_namespace(&a, &b), pair_namespace(&a, &PeerId::random()));
+    }
+
+    #[test]
+    fn discover_returns_other_peers_until_they_expire() {
+        let mut store = RendezvousStore::default();
+        let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let (alice_id, bob_id) = (alice.public().to_peer_id(), bob.public().to_peer_id());
+        let now = Instant::now();
+
+        assert_eq!(
+            store.handle(alice_id, register("ns", &alice), now),
+            RendezvousResponse::Registered { ttl: RENDEZVOUS_TTL_SECS }
+        );
+        let discover = RendezvousRequest::Discover { namespace: "ns".to_string() };
+        // Alice doesn't get her own record back
+        assert_eq!(store.handle(alice_id, discover.clone(), now), RendezvousResponse::Registrations(vec![]));
+        let RendezvousResponse::Registrations(found) = store.handle(bob_id, discover.clone(), now) else {
+            panic!("expected registrations");
+        };
+        assert_eq!(found.len(), 1);
+        assert!(decode_address_record(&alice_id, &found[0]).is_ok());
+
+        let later = now + Duration::from_secs(RENDEZVOUS_TTL_SECS);
+        assert_eq!(store.handle(bob_id, discover, later), RendezvousResponse::Registrations(vec![]));
+    }
+
+    #[test]
+    fn only_the_records_owner_may_register_it() {
+        let mut store = RendezvousStore::default();
+        let alice = Keypair::generate_ed25519();
+        let response = store.handle(PeerId::random(), register("ns", &alice), Instant::now());
+        assert!(matches!(response, RendezvousResponse::Rejected(_)));
+
+        let alice_id = alice.public().to_peer_id();
+        let long = "x".repeat(MAX_NAMESPACE_LEN + 1);
+        assert!(matches!(store.handle(alice_id, register(&long, &alice), Instant::now()), RendezvousResponse::Rejected(_)));
+    }
+
+    #[test]
+    fn unregister_forgets_the_record() {
+        let mut store = RendezvousStore::default();
+        let alice = Keypair::generate_ed25519();
+        let alice_id = alice.public().to_peer_id();
+        let now = Instant::now();
+        store.handle(alice_id, register("a", &alice), now);
+        store.handle(alice_id, register("b", &alice), now);
+        assert_eq!(store.registrations_of(&alice_id), 2);
+
+        let unregister = RendezvousRequest::Unregister { namespace: "a".to_string() };
+        assert_eq!(store.handle(alice_id, unregister, now), RendezvousResponse::Unregistered);
+        assert_eq!(store.registrations_of(&alice_id), 1);
+    }
+
+    #[test]
+    fn messages_round_trip_through_bincode() {
+        let request = RendezvousRequest::Discover { namespace: "ns".to_string() };
+        let bytes = bincode::serialize(&request).unwrap();
+        assert_eq!(bincode::deserialize::<RendezvousRequest>(&bytes).unwrap(), request);
+    }
+}
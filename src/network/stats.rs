@@ -0,0 +1,181 @@
+//! Traffic statistics.
+//!
+//! The node counts the bytes each protocol carries to and from each peer:
+//! the payloads of requests and responses, not transport framing or
+//! encryption overhead. Counts since the node started come from
+//! `WhisperNode::stats`; every `TRAFFIC_REPORT_SECS` the node also reports
+//! what's new since the last report as `NodeEvent::Traffic`, for storing.
+
+use std::collections::HashMap;
+
+use libp2p::PeerId;
+
+/// How often new traffic is reported, in seconds.
+pub const TRAFFIC_REPORT_SECS: u64 = 60;
+
+/// What the bytes were for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TrafficProtocol {
+    Messages,
+    FileTransfer,
+    Rendezvous,
+    Ping,
+    /// Peer exchange of contact addresses.
+    Pex,
+}
+
+impl TrafficProtocol {
+    /// Every protocol, in display order.
+    pub const ALL: [TrafficProtocol; 5] = [
+        TrafficProtocol::Messages,
+        TrafficProtocol::FileTransfer,
+        TrafficProtocol::Rendezvous,
+        TrafficProtocol::Ping,
+        TrafficProtocol::Pex,
+    ];
+
+    /// Name used in storage and output.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrafficProtocol::Messages => "messages",
+            TrafficProtocol::FileTransfer => "files",
+            TrafficProtocol::Rendezvous => "rendezvous",
+            TrafficProtocol::Ping => "ping",
+            TrafficProtocol::Pex => "pex",
+        }
+    }
+
+    /// Parse a name from `as_str`.
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.as_str() == s)
+    }
+}
+
+impl std::fmt::Display for TrafficProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Bytes each way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl Traffic {
+    /// Both counts added to ours.
+    pub fn add(&mut self, other: Traffic) {
+        self.sent = self.sent.saturating_add(other.sent);
+        self.received = self.received.saturating_add(other.received);
+    }
+
+    /// Bytes in either direction.
+    pub fn total(&self) -> u64 {
+        self.sent.saturating_add(self.received)
+    }
+}
+
+/// Traffic per peer and protocol.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    traffic: HashMap<(PeerId, TrafficProtocol), Traffic>,
+}
+
+impl NetworkStats {
+    /// Count `bytes` sent to `peer`.
+    pub fn record_sent(&mut self, peer: PeerId, protocol: TrafficProtocol, bytes: usize) {
+        let entry = self.traffic.entry((peer, protocol)).or_default();
+        entry.sent = entry.sent.saturating_add(bytes as u64);
+    }
+
+    /// Count `bytes` received from `peer`.
+    pub fn record_received(&mut self, peer: PeerId, protocol: TrafficProtocol, bytes: usize) {
+        let entry = self.traffic.entry((peer, protocol)).or_default();
+        entry.received = entry.received.saturating_add(bytes as u64);
+    }
+
+    /// Add every count in `other` to ours.
+    pub fn merge(&mut self, other: &NetworkStats) {
+        for (key, traffic) in &other.traffic {
+            self.traffic.entry(*key).or_default().add(*traffic);
+        }
+    }
+
+    /// Every count, by peer and protocol.
+    pub fn entries(&self) -> impl Iterator<Item = (PeerId, TrafficProtocol, Traffic)> + '_ {
+        self.traffic.iter().map(|((peer, protocol), traffic)| (*peer, *protocol, *traffic))
+    }
+
+    /// Traffic with one peer, over every protocol.
+    pub fn peer(&self, peer: &PeerId) -> Traffic {
+        self.sum(|p, _| p == peer)
+    }
+
+    /// Traffic over one protocol, with every peer.
+    pub fn protocol(&self, protocol: TrafficProtocol) -> Traffic {
+        self.sum(|_, p| *p == protocol)
+    }
+
+    /// Everything sent and received.
+    pub fn totals(&self) -> Traffic {
+        self.sum(|_, _| true)
+    }
+
+    fn sum(&self, include: impl Fn(&PeerId, &TrafficProtocol) -> bool) -> Traffic {
+        let mut sum = Traffic::default();
+        for ((peer, protocol), traffic) in &self.traffic {
+            if include(peer, protocol) {
+                sum.add(*traffic);
+            }
+        }
+        sum
+    }
+
+    /// Whether nothing has been counted.
+    pub fn is_empty(&self) -> bool {
+        self.traffic.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_per_peer_and_protocol() {
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let mut stats = NetworkStats::default();
+        assert!(stats.is_empty());
+
+        stats.record_sent(alice, TrafficProtocol::Messages, 100);
+        stats.record_received(alice, TrafficProtocol::Messages, 1);
+        stats.record_sent(alice, TrafficProtocol::FileTransfer, 1000);
+        stats.record_received(bob, TrafficProtocol::Messages, 50);
+
+        assert_eq!(stats.peer(&alice), Traffic { sent: 1100, received: 1 });
+        assert_eq!(stats.protocol(TrafficProtocol::Messages), Traffic { sent: 100, received: 51 });
+        assert_eq!(stats.totals().total(), 1151);
+        assert_eq!(stats.entries().count(), 3);
+    }
+
+    #[test]
+    fn merging_adds_counts() {
+        let peer = PeerId::random();
+        let mut total = NetworkStats::default();
+        let mut delta = NetworkStats::default();
+        delta.record_sent(peer, TrafficProtocol::Ping, 32);
+        total.merge(&delta);
+        total.merge(&delta);
+        assert_eq!(total.peer(&peer), Traffic { sent: 64, received: 0 });
+    }
+
+    #[test]
+    fn protocol_names_round_trip() {
+        for protocol in TrafficProtocol::ALL {
+            assert_eq!(TrafficProtocol::parse(protocol.as_str()), Some(protocol));
+        }
+        assert_eq!(TrafficProtocol::parse("smtp"), None);
+    }
+}
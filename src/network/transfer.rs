@@ -0,0 +1,310 @@
+//! Dedicated stream protocol for chunked file transfer.
+//!
+//! File chunks travel on their own protocol so a large transfer doesn't
+//! share a stream with chat messages. Each request carries one encrypted
+//! chunk plus a small cleartext header so the node can report progress
+//! without decrypting anything.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use libp2p::{request_response, PeerId, StreamProtocol};
+use uuid::Uuid;
+use web_time::Instant;
+
+/// Protocol name for Whisper file transfers.
+pub const FILE_TRANSFER_PROTOCOL: &str = "/whisper/file/1.0.0";
+
+/// Header size: transfer ID (16) + chunk index (4) + total chunks (4).
+const HEADER_LEN: usize = 16 + 4 + 4;
+
+/// Largest chunk request we accept (64KB chunk plus encryption and envelope overhead).
+pub const MAX_FILE_REQUEST_SIZE: u64 = 256 * 1024;
+
+/// Incoming transfers a peer can have open at once. Chunks starting
+/// another are refused.
+pub const MAX_OPEN_TRANSFERS_PER_PEER: usize = 8;
+
+/// How long a transfer can go without a chunk before it's forgotten, in
+/// seconds.
+pub const TRANSFER_IDLE_SECS: u64 = 300;
+
+/// Direction of a file transfer relative to this node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransferDirection {
+    Incoming,
+    Outgoing,
+}
+
+/// Chunks done in each open transfer. Transfers are told apart by peer
+/// and direction as well as ID, since the sender picks the ID: a peer
+/// can't move another's transfer along, or ours with it.
+#[derive(Debug, Clone, Default)]
+pub struct TransferTracker {
+    open: HashMap<(PeerId, Uuid, TransferDirection), (u32, Instant)>,
+}
+
+impl TransferTracker {
+    /// A chunk of `transfer_id` with `peer` was done at `now`. Returns
+    /// the chunks done so far, or `None` if it would start an incoming
+    /// transfer past the peer's limit. A transfer is closed once all its
+    /// chunks are done.
+    pub fn chunk_done(
+        &mut self,
+        peer: PeerId,
+        transfer_id: Uuid,
+        total_chunks: u32,
+        direction: TransferDirection,
+        now: Instant,
+    ) -> Option<u32> {
+        self.expire(now);
+        let key = (peer, transfer_id, direction);
+        if direction == TransferDirection::Incoming
+            && !self.open.contains_key(&key)
+            && self.open_incoming(&peer) >= MAX_OPEN_TRANSFERS_PER_PEER
+        {
+            return None;
+        }
+        let (done, last_chunk) = self.open.entry(key).or_insert((0, now));
+        *done = done.saturating_add(1);
+        *last_chunk = now;
+        let done = *done;
+        if done >= total_chunks {
+            self.open.remove(&key);
+        }
+        Some(done)
+    }
+
+    /// Forget the transfers with a peer that's gone.
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        self.open.retain(|(p, ..), _| p != peer);
+    }
+
+    /// Incoming transfers open with `peer`.
+    pub fn open_incoming(&self, peer: &PeerId) -> usize {
+        self.open
+            .keys()
+            .filter(|(p, _, direction)| p == peer && *direction == TransferDirection::Incoming)
+            .count()
+    }
+
+    /// Whether no transfer is open.
+    pub fn is_empty(&self) -> bool {
+        self.open.is_empty()
+    }
+
+    /// Forget transfers that have gone quiet.
+    fn expire(&mut self, now: Instant) {
+        let idle = Duration::from_secs(TRANSFER_IDLE_SECS);
+        self.open.retain(|_, (_, last_chunk)| now.saturating_duration_since(*last_chunk) < idle);
+    }
+}
+
+/// File transfer codec for request-response.
+#[derive(Debug, Clone, Default)]
+pub struct FileCodec;
+
+/// Request type - one encrypted file chunk.
+#[derive(Debug, Clone)]
+pub struct FileChunkRequest {
+    pub transfer_id: Uuid,
+    pub chunk_index: u32,
+    pub total_chunks: u32,
+    /// Encrypted envelope containing the chunk.
+    pub data: Vec<u8>,
+}
+
+/// Response type - chunk acknowledgment.
+#[derive(Debug, Clone)]
+pub struct FileChunkAck(pub bool);
+
+impl FileChunkRequest {
+    /// Encode the request for the wire.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.data.len());
+        buf.extend_from_slice(self.transfer_id.as_bytes());
+        buf.extend_from_slice(&self.chunk_index.to_be_bytes());
+        buf.extend_from_slice(&self.total_chunks.to_be_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    /// Decode a request from the wire.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+        let transfer_id = Uuid::from_slice(&buf[..16]).ok()?;
+        let chunk_index = u32::from_be_bytes(buf[16..20].try_into().ok()?);
+        let total_chunks = u32::from_be_bytes(buf[20..24].try_into().ok()?);
+        Some(Self {
+            transfer_id,
+            chunk_index,
+            total_chunks,
+            data: buf[HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+impl request_response::Codec for FileCodec {
+    type Protocol = StreamProtocol;
+    type Request = FileChunkRequest;
+    type Response = FileChunkAck;
+
+    fn read_request<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<Self::Request>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncRead + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move {
+            let mut buf = Vec::new();
+            futures::AsyncReadExt::read_to_end(
+                &mut futures::AsyncReadExt::take(io, MAX_FILE_REQUEST_SIZE),
+                &mut buf,
+            )
+            .await?;
+            FileChunkRequest::from_bytes(&buf).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed file chunk header")
+            })
+        })
+    }
+
+    fn read_response<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<Self::Response>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncRead + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move {
+            let mut buf = [0u8; 1];
+            futures::AsyncReadExt::read_exact(io, &mut buf).await?;
+            Ok(FileChunkAck(buf[0] == 1))
+        })
+    }
+
+    fn write_request<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+        req: Self::Request,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<()>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncWrite + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move {
+            futures::AsyncWriteExt::write_all(io, &req.to_bytes()).await?;
+            futures::AsyncWriteExt::close(io).await?;
+            Ok(())
+        })
+    }
+
+    fn write_response<'life0, 'life1, 'life2, 'async_trait, T>(
+        &'life0 mut self,
+        _protocol: &'life1 Self::Protocol,
+        io: &'life2 mut T,
+        res: Self::Response,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<()>> + Send + 'async_trait>>
+    where
+        T: futures::AsyncWrite + Unpin + Send + 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move {
+            futures::AsyncWriteExt::write_all(io, &[if res.0 { 1 } else { 0 }]).await?;
+            futures::AsyncWriteExt::close(io).await?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_request_roundtrip() {
+        let req = FileChunkRequest {
+            transfer_id: Uuid::new_v4(),
+            chunk_index: 3,
+            total_chunks: 10,
+            data: vec![9, 8, 7],
+        };
+
+        let decoded = FileChunkRequest::from_bytes(&req.to_bytes()).unwrap();
+        assert_eq!(decoded.transfer_id, req.transfer_id);
+        assert_eq!(decoded.chunk_index, 3);
+        assert_eq!(decoded.total_chunks, 10);
+        assert_eq!(decoded.data, vec![9, 8, 7]);
+    }
+
+    #[test]
+    fn chunk_request_rejects_short_header() {
+        assert!(FileChunkRequest::from_bytes(&[0u8; HEADER_LEN - 1]).is_none());
+    }
+
+    #[test]
+    fn chunk_request_allows_empty_payload() {
+        let req = FileChunkRequest {
+            transfer_id: Uuid::new_v4(),
+            chunk_index: 0,
+            total_chunks: 1,
+            data: vec![],
+        };
+        let decoded = FileChunkRequest::from_bytes(&req.to_bytes()).unwrap();
+        assert!(decoded.data.is_empty());
+    }
+
+    #[test]
+    fn transfers_are_kept_apart_and_limited_per_peer() {
+        let mut tracker = TransferTracker::default();
+        let (alice, mallory) = (PeerId::random(), PeerId::random());
+        let (transfer, now) = (Uuid::new_v4(), Instant::now());
+        let incoming = TransferDirection::Incoming;
+
+        assert_eq!(tracker.chunk_done(alice, transfer, 3, incoming, now), Some(1));
+        // The same ID from someone else, or going the other way, is another transfer
+        assert_eq!(tracker.chunk_done(mallory, transfer, 3, incoming, now), Some(1));
+        assert_eq!(tracker.chunk_done(mallory, transfer, 3, TransferDirection::Outgoing, now), Some(1));
+        assert_eq!(tracker.chunk_done(alice, transfer, 3, incoming, now), Some(2));
+
+        // Fresh IDs only go so far
+        for _ in 1..MAX_OPEN_TRANSFERS_PER_PEER {
+            assert!(tracker.chunk_done(mallory, Uuid::new_v4(), 3, incoming, now).is_some());
+        }
+        assert_eq!(tracker.open_incoming(&mallory), MAX_OPEN_TRANSFERS_PER_PEER);
+        assert!(tracker.chunk_done(mallory, Uuid::new_v4(), 3, incoming, now).is_none());
+        assert_eq!(tracker.chunk_done(mallory, transfer, 3, incoming, now), Some(2), "already open");
+
+        // Quiet transfers are forgotten, and so are a peer's when it leaves
+        let later = now + Duration::from_secs(TRANSFER_IDLE_SECS);
+        assert_eq!(tracker.chunk_done(alice, transfer, 3, incoming, later), Some(1));
+        assert_eq!(tracker.open_incoming(&mallory), 0);
+        tracker.disconnected(&alice);
+        assert!(tracker.is_empty());
+    }
+
+    #[test]
+    fn protocol_name_is_distinct() {
+        assert!(FILE_TRANSFER_PROTOCOL.starts_with('/'));
+        assert_ne!(FILE_TRANSFER_PROTOCOL, super::super::behaviour::WHISPER_PROTOCOL);
+    }
+}
@@ -0,0 +1,73 @@
+//! How the node reaches the network and is reached.
+//!
+//! Every node speaks TCP and WebSocket (`/ws`) and dials whichever a
+//! peer's address calls for. WebSocket is for clients that can't open raw
+//! TCP connections, such as browsers, and for networks that only let HTTP
+//! through; a node only listens for it when given a port to listen on.
+
+use std::net::SocketAddr;
+
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+
+/// How the node reaches the network.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransportConfig {
+    /// Dial every peer through this SOCKS5 proxy.
+    pub socks_proxy: Option<SocketAddr>,
+    /// Also accept WebSocket connections on this port.
+    pub websocket_port: Option<u16>,
+}
+
+impl TransportConfig {
+    /// Whether connections go through a proxy.
+    pub fn is_proxied(&self) -> bool {
+        self.socks_proxy.is_some()
+    }
+
+    /// The WebSocket address to listen on alongside the TCP address
+    /// `addr`, if there's a WebSocket port and `addr` is a plain
+    /// `/ip4` or `/ip6` TCP address.
+    pub fn websocket_address(&self, addr: &Multiaddr) -> Option<Multiaddr> {
+        let port = self.websocket_port?;
+        let mut protocols = addr.iter();
+        let ip = match protocols.next()? {
+            ip @ (Protocol::Ip4(_) | Protocol::Ip6(_)) => ip,
+            _ => return None,
+        };
+        match (protocols.next(), protocols.next()) {
+            (Some(Protocol::Tcp(_)), None) => {
+                Some(Multiaddr::empty().with(ip).with(Protocol::Tcp(port)).with(Protocol::Ws("/".into())))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether `addr` is a WebSocket address.
+pub fn is_websocket_address(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| matches!(p, Protocol::Ws(_) | Protocol::Wss(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn websocket_address_follows_tcp_listeners() {
+        let tcp: Multiaddr = "/ip4/0.0.0.0/tcp/0".parse().unwrap();
+        assert_eq!(TransportConfig::default().websocket_address(&tcp), None);
+
+        let config = TransportConfig { websocket_port: Some(8080), ..Default::default() };
+        let ws = config.websocket_address(&tcp).unwrap();
+        assert_eq!(ws, "/ip4/0.0.0.0/tcp/8080/ws".parse().unwrap());
+        assert!(is_websocket_address(&ws));
+        assert!(!is_websocket_address(&tcp));
+
+        // Only plain TCP listeners get one
+        assert_eq!(config.websocket_address(&ws), None);
+        assert_eq!(config.websocket_address(&"/ip4/1.2.3.4/udp/1/quic-v1".parse().unwrap()), None);
+        let circuit = format!("/ip4/1.2.3.4/tcp/1/p2p/{}/p2p-circuit", libp2p::PeerId::random());
+        assert_eq!(config.websocket_address(&circuit.parse().unwrap()), None);
+    }
+}
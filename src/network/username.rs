@@ -0,0 +1,122 @@
+//! Usernames in the DHT.
+//!
+//! A username is a handle like `@alice` that stands in for a peer ID. Its
+//! owner publishes a record, signed with their identity key, under a key
+//! derived from the name; whoever looks it up gets the peer ID and public
+//! key from the signature. Nothing stops two peers from claiming the same
+//! name, so a lookup returns every valid claim it finds and leaves the
+//! choice to the user when there's more than one.
+
+use anyhow::{bail, Result};
+use libp2p::{core::SignedEnvelope, identity::Keypair, identity::PublicKey, kad, PeerId};
+use serde::{Deserialize, Serialize};
+
+/// Shortest username accepted.
+pub const MIN_USERNAME_LEN: usize = 3;
+
+/// Longest username accepted.
+pub const MAX_USERNAME_LEN: usize = 32;
+
+/// Signature domain for username records.
+const USERNAME_DOMAIN: &str = "whisper-username";
+
+/// Payload type of username records.
+const USERNAME_PAYLOAD_TYPE: &[u8] = b"/whisper/username";
+
+/// A claim to a username, as its owner signed it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsernameRecord {
+    pub username: String,
+    pub peer_id: PeerId,
+    pub public_key: PublicKey,
+    /// When it was signed, in seconds since the epoch.
+    pub registered_at: u64,
+}
+
+/// What's signed.
+#[derive(Serialize, Deserialize)]
+struct WireUsername {
+    username: String,
+    registered_at: u64,
+}
+
+/// A username in its canonical form: without a leading `@`, lowercase,
+/// and made of letters, digits and underscores.
+pub fn normalize_username(username: &str) -> Result<String> {
+    let name = username.strip_prefix('@').unwrap_or(username).to_ascii_lowercase();
+    if name.len() < MIN_USERNAME_LEN || name.len() > MAX_USERNAME_LEN {
+        bail!("Usernames are {} to {} characters long", MIN_USERNAME_LEN, MAX_USERNAME_LEN);
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        bail!("Usernames may only use letters, digits and underscores");
+    }
+    Ok(name)
+}
+
+/// The DHT key a username's records are stored under.
+pub fn username_record_key(username: &str) -> kad::RecordKey {
+    let mut key = b"/whisper/username/".to_vec();
+    key.extend_from_slice(username.as_bytes());
+    kad::RecordKey::new(&key)
+}
+
+/// Sign our claim to `username` for publishing in the DHT.
+pub fn encode_username_record(keypair: &Keypair, username: &str, registered_at: u64) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(&WireUsername { username: username.to_string(), registered_at })?;
+    let envelope = SignedEnvelope::new(keypair, USERNAME_DOMAIN.to_string(), USERNAME_PAYLOAD_TYPE.to_vec(), payload)
+        .map_err(|e| anyhow::anyhow!("Failed to sign username record: {}", e))?;
+    Ok(envelope.into_protobuf_encoding())
+}
+
+/// Read a record found under `username`'s key. It's only accepted if it
+/// is signed and claims that username, since anyone can store anything
+/// under any key.
+pub fn decode_username_record(username: &str, bytes: &[u8]) -> Result<UsernameRecord> {
+    let envelope = SignedEnvelope::from_protobuf_encoding(bytes)
+        .map_err(|e| anyhow::anyhow!("Malformed username record: {}", e))?;
+    let (payload, key) = envelope
+        .payload_and_signing_key(USERNAME_DOMAIN.to_string(), USERNAME_PAYLOAD_TYPE)
+        .map_err(|e| anyhow::anyhow!("Invalid username record: {}", e))?;
+    let wire: WireUsername = bincode::deserialize(payload)?;
+    if wire.username != username {
+        bail!("Record under @{} claims @{}", username, wire.username);
+    }
+    Ok(UsernameRecord {
+        username: wire.username,
+        peer_id: key.to_peer_id(),
+        public_key: key.clone(),
+        registered_at: wire.registered_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usernames_are_normalized_and_checked() {
+        assert_eq!(normalize_username("@Alice_99").unwrap(), "alice_99");
+        assert_eq!(normalize_username("bob").unwrap(), "bob");
+        for bad in ["@al", "", "a l i c e", "alice/bob", "ålice", &"x".repeat(MAX_USERNAME_LEN + 1)] {
+            assert!(normalize_username(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn username_record_is_verified() {
+        let alice = Keypair::generate_ed25519();
+        let bytes = encode_username_record(&alice, "alice", 1000).unwrap();
+        let record = decode_username_record("alice", &bytes).unwrap();
+        assert_eq!(record.peer_id, alice.public().to_peer_id());
+        assert_eq!(record.public_key, alice.public());
+        assert_eq!(record.registered_at, 1000);
+
+        // Copied under another name, it doesn't count
+        assert!(decode_username_record("bob", &bytes).is_err());
+        let mut tampered = bytes.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(decode_username_record("alice", &tampered).is_err());
+        assert_ne!(username_record_key("alice"), username_record_key("bob"));
+    }
+}
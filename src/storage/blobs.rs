@@ -0,0 +1,145 @@
+//! Content-addressed storage for large payloads.
+//!
+//! Files, images and voice notes are kept out of the database, one file per
+//! blob in `<data dir>/blobs/<hash>`. The name is a keyed BLAKE3 hash of the
+//! contents, so identical content is stored once, but someone reading the
+//! directory can't confirm that a file they already have is in it. Each blob
+//! is encrypted with secretbox. Both keys are derived from a random key kept
+//! in the encrypted database.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use sodiumoxide::crypto::secretbox;
+
+/// Length of a blob hash in hex.
+const HASH_HEX_LEN: usize = 64;
+
+/// Keys for naming and encrypting blobs.
+pub(crate) struct BlobKeys {
+    hash_key: [u8; 32],
+    box_key: secretbox::Key,
+}
+
+impl BlobKeys {
+    /// Generate a new random master key.
+    pub fn generate_master() -> [u8; 32] {
+        rand::random()
+    }
+
+    /// Derive the hashing and encryption keys from the master key.
+    pub fn derive(master: &[u8; 32]) -> Self {
+        let hash_key = blake3::derive_key("whisper 2026 blob hash key", master);
+        let box_key = blake3::derive_key("whisper 2026 blob encryption key", master);
+        Self {
+            hash_key,
+            box_key: secretbox::Key(box_key),
+        }
+    }
+
+    /// The name `data` is stored under.
+    pub fn hash(&self, data: &[u8]) -> String {
+        blake3::keyed_hash(&self.hash_key, data).to_hex().to_string()
+    }
+
+    /// Encrypt `data` for writing to disk: nonce || ciphertext.
+    pub fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+        sodiumoxide::init().map_err(|_| anyhow!("Failed to init sodiumoxide"))?;
+        let nonce = secretbox::gen_nonce();
+        let ciphertext = secretbox::seal(data, &nonce, &self.box_key);
+        let mut sealed = Vec::with_capacity(secretbox::NONCEBYTES + ciphertext.len());
+        sealed.extend_from_slice(&nonce.0);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypt a blob read from disk, checking it is the blob named `hash`.
+    pub fn open(&self, hash: &str, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < secretbox::NONCEBYTES + secretbox::MACBYTES {
+            bail!("Blob {} is corrupt: too short", hash);
+        }
+        let (nonce, ciphertext) = sealed.split_at(secretbox::NONCEBYTES);
+        let nonce = secretbox::Nonce::from_slice(nonce)
+            .ok_or_else(|| anyhow!("Blob {} is corrupt: bad nonce", hash))?;
+        let data = secretbox::open(ciphertext, &nonce, &self.box_key)
+            .map_err(|_| anyhow!("Blob {} is corrupt: decryption failed", hash))?;
+        if self.hash(&data) != hash {
+            bail!("Blob {} is corrupt: contents don't match its hash", hash);
+        }
+        Ok(data)
+    }
+}
+
+/// Check that `hash` looks like a blob hash, so it's safe to use as a filename.
+pub(crate) fn validate_hash(hash: &str) -> Result<()> {
+    if hash.len() != HASH_HEX_LEN || !hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        bail!("Invalid blob hash: {}", hash);
+    }
+    Ok(())
+}
+
+/// Where the blob named `hash` is stored.
+pub(crate) fn blob_path(dir: &Path, hash: &str) -> PathBuf {
+    dir.join(hash)
+}
+
+/// Write a sealed blob. It goes to a temporary file first, so a crash never
+/// leaves a partly written blob under its real name.
+pub(crate) fn write_blob(dir: &Path, hash: &str, sealed: &[u8]) -> Result<()> {
+    fs::create_dir_all(dir).context("Failed to create blob directory")?;
+    let path = blob_path(dir, hash);
+    let tmp = dir.join(format!("{}.tmp", hash));
+    fs::write(&tmp, sealed).with_context(|| format!("Failed to write blob {}", hash))?;
+    fs::rename(&tmp, &path).with_context(|| format!("Failed to write blob {}", hash))?;
+    Ok(())
+}
+
+/// Read a sealed blob.
+pub(crate) fn read_blob(dir: &Path, hash: &str) -> Result<Vec<u8>> {
+    fs::read(blob_path(dir, hash)).with_context(|| format!("Failed to read blob {}", hash))
+}
+
+/// Delete a blob's file. A file that's already gone is fine.
+pub(crate) fn remove_blob(dir: &Path, hash: &str) -> Result<()> {
+    match fs::remove_file(blob_path(dir, hash)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to delete blob {}", hash))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_depends_on_key() {
+        let a = BlobKeys::derive(&[1; 32]);
+        let b = BlobKeys::derive(&[2; 32]);
+        assert_eq!(a.hash(b"photo"), a.hash(b"photo"));
+        assert_ne!(a.hash(b"photo"), b.hash(b"photo"));
+        validate_hash(&a.hash(b"photo")).unwrap();
+    }
+
+    #[test]
+    fn seal_round_trips_and_detects_tampering() {
+        let keys = BlobKeys::derive(&[7; 32]);
+        let hash = keys.hash(b"voice note");
+        let mut sealed = keys.seal(b"voice note").unwrap();
+        assert!(!sealed.windows(10).any(|w| w == b"voice note"));
+        assert_eq!(keys.open(&hash, &sealed).unwrap(), b"voice note");
+
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(keys.open(&hash, &sealed).is_err());
+    }
+
+    #[test]
+    fn rejects_hashes_that_are_not_filenames() {
+        assert!(validate_hash("../whisper.db").is_err());
+        assert!(validate_hash(&"A".repeat(64)).is_err());
+        assert!(validate_hash("abc").is_err());
+    }
+}
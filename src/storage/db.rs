@@ -1,23 +1,310 @@
 //! Database operations.
 
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
-use chrono::{TimeZone, Utc};
-use libp2p::PeerId;
+use chrono::{DateTime, TimeZone, Utc};
+use libp2p::{Multiaddr, PeerId};
 use rusqlite::{params, Connection, OptionalExtension};
 use uuid::Uuid;
 
-use crate::identity::{Contact, TrustLevel};
+use crate::identity::{
+    Contact, InviteId, IssuedInvite, Presence, PresenceStatus, PublicPrekey, SignedPrekey, TrustLevel, TrustPolicy,
+    TrustRequirement,
+};
 use crate::message::{
-    FileChunk, FileTransfer, FileTransferStatus,
-    Group, GroupMember, MemberRole, Message, MessageContent, MessageStatus, Recipient,
+    seq_now, Channel, ChannelPost, DeviceList, FileChunk, FileTransfer, FileTransferStatus,
+    Conversation, Group, GroupMember, MemberRole, Message, MessageContent, MessageStatus,
+    PendingContactCard, PendingGroupInvite, Recipient, sort_conversations, DEFAULT_STAMP_DIFFICULTY,
+    MAX_SEQ_SKEW_MICROS, REPLAY_WINDOW_MICROS,
 };
+use crate::network::{Latency, NetworkStats, Reachability, TrafficProtocol, MAX_ADDRESSES_PER_PEER};
+use zeroize::Zeroizing;
+
+use super::audit::{audit_hash, AuditEvent, AuditKind, AUDIT_GENESIS};
+use super::blobs::{self, BlobKeys};
+
+/// Columns read by `row_to_contact`, with the contact's note and its tags
+/// joined by commas. Tags never contain commas.
+const CONTACT_SELECT: &str = "SELECT c.peer_id, c.alias, c.public_key, c.trust_level, c.last_seen, n.note,
+        (SELECT group_concat(t.tag, ',') FROM contact_tags t WHERE t.peer_id = c.peer_id),
+        c.presence, c.presence_at
+     FROM contacts c LEFT JOIN contact_notes n ON n.peer_id = c.peer_id";
+
+/// Where a received direct message was filed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inbox {
+    /// The sender is a contact, so it joined our conversation with them.
+    Conversation,
+    /// The sender isn't a contact, so it's held as a message request.
+    Request,
+}
+
+/// Directory next to the database file where blobs are stored.
+const BLOB_DIR: &str = "blobs";
+
+/// Key of the `node_state` row holding the blob master key.
+const BLOB_KEY: &str = "blob_key";
+
+/// Key of the `node_state` row holding the presence we announce.
+const OWN_PRESENCE: &str = "presence";
+
+/// Key of the `node_state` row holding the rendezvous point's address.
+const RENDEZVOUS_POINT: &str = "rendezvous_point";
+
+/// Key of the `node_state` row holding the username we claim in the DHT.
+const USERNAME: &str = "username";
+
+/// Key of the `node_state` row holding the SOCKS5 proxy to dial through.
+const SOCKS_PROXY: &str = "socks_proxy";
+
+/// Key of the `node_state` row holding the port to accept WebSockets on.
+const WEBSOCKET_PORT: &str = "websocket_port";
+
+/// Key of the `node_state` row holding the TCP port we listen on.
+const LISTEN_PORT: &str = "listen_port";
+
+/// Key of the `node_state` row holding the webhook for incoming messages.
+const WEBHOOK: &str = "webhook";
+
+/// Prefix of the `node_state` rows holding where each bridged Matrix room
+/// was last synced to.
+const MATRIX_SYNC_TOKEN: &str = "matrix_since:";
+
+/// Key of the `node_state` row set while we hold mail for other peers.
+const MAILBOX_SERVING: &str = "mailbox_serving";
+
+/// Key of the `node_state` row set when chats show markdown as typed.
+const RAW_MARKDOWN: &str = "raw_markdown";
+
+/// Key of the `node_state` row set when peer exchange is turned off.
+const PEX_OFF: &str = "pex_off";
+
+/// Key of the `node_state` row holding the proof-of-work difficulty asked
+/// of strangers, when it isn't the default.
+const STAMP_DIFFICULTY: &str = "stamp_difficulty";
+
+/// Prefix of the `node_state` rows holding how trusted a sender must be
+/// for each part of the trust policy, when it isn't anyone.
+const TRUST_POLICY: &str = "trust_policy:";
+
+/// Key of the `node_state` row holding the identity this device joined as
+/// a linked device.
+const LINKED_TO: &str = "linked_to";
+
+/// A message waiting in the offline queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedMessage {
+    pub id: Uuid,
+    pub to: PeerId,
+    /// The message as it will be sent, sealed for `to`.
+    pub data: Vec<u8>,
+    pub queued_at: DateTime<Utc>,
+}
+
+/// A queued message given up on after too many delivery attempts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub id: Uuid,
+    pub to: PeerId,
+    pub queued_at: DateTime<Utc>,
+    pub attempts: u32,
+    pub dead_at: DateTime<Utc>,
+}
+
+/// A message in the offline queue, as `whisper queue list` shows it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueEntry {
+    pub id: Uuid,
+    pub to: PeerId,
+    pub queued_at: DateTime<Utc>,
+    pub attempts: u32,
+    /// When it's next tried, if it's waiting out a backoff.
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    /// When it was given up on, if it's a dead letter.
+    pub dead_at: Option<DateTime<Utc>>,
+}
+
+/// Key of the default row in `retention_policies`.
+const DEFAULT_RETENTION: &str = "*";
+
+/// Where `whisper listen` posts incoming messages, set with `whisper webhook`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: String,
+    /// Only post messages in these conversations. Empty means all of them.
+    pub only: Vec<Recipient>,
+}
+
+impl Webhook {
+    /// Whether messages in `conversation` are posted.
+    pub fn wants(&self, conversation: &Recipient) -> bool {
+        self.only.is_empty() || self.only.contains(conversation)
+    }
+}
+
+/// Which messages in a conversation notify the user.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NotificationLevel {
+    #[default]
+    All,
+    /// Only messages that mention our username.
+    Mentions,
+    None,
+}
+
+impl NotificationLevel {
+    /// Stable name used for storage and the CLI.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationLevel::All => "all",
+            NotificationLevel::Mentions => "mentions",
+            NotificationLevel::None => "none",
+        }
+    }
+
+    /// Parse a name produced by `as_str`.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "all" => Some(NotificationLevel::All),
+            "mentions" => Some(NotificationLevel::Mentions),
+            "none" => Some(NotificationLevel::None),
+            _ => None,
+        }
+    }
+}
+
+/// How the chat alerts the user to a message that notifies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Alert {
+    /// Only the unread count.
+    #[default]
+    Quiet,
+    /// Ring the terminal bell.
+    Bell,
+}
+
+impl Alert {
+    /// Stable name used for storage and the CLI.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Alert::Quiet => "quiet",
+            Alert::Bell => "bell",
+        }
+    }
+
+    /// Parse a name produced by `as_str`.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "quiet" => Some(Alert::Quiet),
+            "bell" => Some(Alert::Bell),
+            _ => None,
+        }
+    }
+}
+
+/// How a conversation notifies the user, set with `whisper notify`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NotificationSettings {
+    pub level: NotificationLevel,
+    pub muted: bool,
+    /// When the mute ends. `None` mutes until it's lifted.
+    pub muted_until: Option<DateTime<Utc>>,
+    pub alert: Alert,
+}
+
+impl NotificationSettings {
+    /// Whether the conversation is muted at `now`.
+    pub fn is_muted(&self, now: DateTime<Utc>) -> bool {
+        self.muted && self.muted_until.is_none_or(|until| now < until)
+    }
+
+    /// Whether a message notifies the user at `now`, given whether it
+    /// mentions them.
+    pub fn notifies(&self, mentioned: bool, now: DateTime<Utc>) -> bool {
+        !self.is_muted(now)
+            && match self.level {
+                NotificationLevel::All => true,
+                NotificationLevel::Mentions => mentioned,
+                NotificationLevel::None => false,
+            }
+    }
+}
+
+/// How long a conversation's messages are kept. `None` means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Delete messages older than this many days.
+    pub max_age_days: Option<u32>,
+    /// Keep only this many of the newest messages.
+    pub max_messages: Option<u32>,
+}
+
+impl RetentionPolicy {
+    /// Whether nothing is ever deleted.
+    pub fn keeps_everything(&self) -> bool {
+        self.max_age_days.is_none() && self.max_messages.is_none()
+    }
+}
+
+/// Messages pruned, or that would be, from one conversation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrunedConversation {
+    /// Who the conversation is with.
+    pub with: Recipient,
+    /// Contact alias or group name.
+    pub name: String,
+    /// How many messages were deleted.
+    pub messages: usize,
+}
+
+/// An address a peer has been seen at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerAddress {
+    /// Whose address it is.
+    pub peer_id: PeerId,
+    /// Where they were seen.
+    pub address: Multiaddr,
+    /// When they were last seen there.
+    pub last_seen: DateTime<Utc>,
+    /// When we last connected to them through it, if ever.
+    pub last_success: Option<DateTime<Utc>>,
+}
+
+/// The last round trip measured to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerLatency {
+    pub latency: Latency,
+    /// When it was measured.
+    pub measured_at: DateTime<Utc>,
+}
+
+/// What a peer last told us it runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerVersion {
+    /// Its protocol version, as `/whisper/1.1.0`.
+    pub protocol_version: String,
+    /// Its agent version, as `whisper/0.5.0`.
+    pub agent_version: String,
+    /// When it told us.
+    pub seen_at: DateTime<Utc>,
+}
+
+/// Orders `peer_addresses` rows best first: addresses we've connected
+/// through, most recently first, then the rest by when they were seen.
+const PEER_ADDRESS_ORDER: &str = "last_success IS NULL, last_success DESC, last_seen DESC";
+
+/// How long to wait for another process's write before giving up.
+const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 
 /// SQLite database wrapper with SQLCipher encryption.
 pub struct Database {
     conn: Connection,
+    /// Where blobs are stored; `None` for in-memory databases.
+    blob_dir: Option<PathBuf>,
 }
 
 impl Database {
@@ -26,52 +313,125 @@ impl Database {
     /// The encryption_key should be derived using Argon2 from the user's passphrase.
     /// Use `storage::derive_database_key()` to derive the key.
     /// If the database already exists, it will be opened with the key.
-    /// If the key is wrong, an error is returned.
+    /// If the key is wrong, an error is returned. So is a missing key, a
+    /// build without SQLCipher, or a database file stored as plaintext.
     pub fn open(path: &Path, encryption_key: &str) -> Result<Self> {
+        if encryption_key.is_empty() {
+            anyhow::bail!("Database encryption key cannot be empty. Database encryption is required.");
+        }
+        // Refuse a plaintext file before SQLCipher reports it as a wrong key
+        super::encryption::ensure_encrypted_file(path)?;
+
         // Create parent directories if needed
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
 
         let conn = Connection::open(path)?;
+        super::encryption::ensure_sqlcipher(&conn)?;
         
         // Set the encryption key using SQLCipher PRAGMA
         // This must be done before any other database operations
         // The key should be in format x'hexstring' from derive_database_key()
-        if !encryption_key.is_empty() {
-            conn.pragma_update(None, "key", encryption_key)
-                .context("Failed to set encryption key - wrong passphrase?")?;
-        }
+        conn.pragma_update(None, "key", encryption_key)
+            .context("Failed to set encryption key - wrong passphrase?")?;
         
         // Verify the key is correct by trying to access the database
         // SQLCipher returns an error on first query if key is wrong
         // We use query_row instead of execute since SELECT returns results
         conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
             .context("Database authentication failed - incorrect passphrase")?;
+
+        // With write-ahead logging a crash mid-write can't damage the file,
+        // and `listen` can write while another command reads. Writers take
+        // turns, waiting up to BUSY_TIMEOUT for the other's to finish.
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))
+            .context("Failed to enable write-ahead logging")?;
         
-        let db = Self { conn };
+        let db = Self {
+            conn,
+            blob_dir: path.parent().map(|dir| dir.join(BLOB_DIR)),
+        };
         db.migrate()?;
+        // The schema has been written, so the file must not be plaintext now
+        super::encryption::ensure_encrypted_file(path)?;
         Ok(db)
     }
     
     /// Open or create encrypted database using a passphrase.
     /// 
     /// This derives the encryption key using Argon2 and then opens the database.
-    /// The data_dir is used to store/load the salt file.
+    /// The data_dir is used to store/load the salt file. A rekey that stopped
+    /// after the database took its new key is finished here.
     pub fn open_with_passphrase(path: &Path, passphrase: &str, data_dir: &Path) -> Result<Self> {
         let key = super::encryption::derive_database_key(passphrase, data_dir)?;
-        Self::open(path, &key)
+        match Self::open(path, &key) {
+            Err(e) if super::encryption::rekey_pending(data_dir) => {
+                let db = super::encryption::derive_rekeyed_key(passphrase, data_dir)
+                    .and_then(|key| Self::open(path, &key))
+                    .map_err(|_| e)?;
+                super::encryption::finish_rekey(data_dir)?;
+                Ok(db)
+            }
+            opened => opened,
+        }
     }
 
     /// Open an in-memory database (for testing).
     /// In-memory databases don't need encryption.
     pub fn open_in_memory() -> Result<Self> {
         let conn = Connection::open_in_memory()?;
-        let db = Self { conn };
+        let db = Self { conn, blob_dir: None };
         db.migrate()?;
         Ok(db)
     }
     
+    /// Open an in-memory copy of the encrypted database at `path`, for
+    /// ephemeral sessions: everything stored there can be read, but
+    /// nothing written reaches the disk. Ratchet sessions aren't copied,
+    /// so conversations start new ones whose keys are thrown away with
+    /// the copy. Blobs can't be stored.
+    pub fn open_ephemeral_copy(path: &Path, encryption_key: &str) -> Result<Self> {
+        // Checks the key and brings the schema up to date
+        drop(Self::open(path, encryption_key)?);
+
+        let db = Self::open_in_memory()?;
+        db.conn
+            .execute(
+                "ATTACH DATABASE ?1 AS disk KEY ?2",
+                params![path.to_string_lossy(), encryption_key],
+            )
+            .context("Failed to attach database")?;
+        let tables: Vec<String> = db
+            .conn
+            .prepare(
+                "SELECT name FROM disk.sqlite_master
+                 WHERE type = 'table' AND name NOT LIKE 'sqlite_%'
+                   AND name NOT LIKE 'messages_fts%' AND name != 'sessions'",
+            )?
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<_, _>>()?;
+        for table in tables {
+            db.conn.execute(
+                &format!("INSERT OR REPLACE INTO main.\"{0}\" SELECT * FROM disk.\"{0}\"", table),
+                [],
+            )?;
+        }
+        db.conn.execute("DETACH DATABASE disk", [])?;
+        Ok(db)
+    }
+
+    /// Peers we have a ratchet session with.
+    pub fn session_peers(&self) -> Result<Vec<PeerId>> {
+        let mut stmt = self.conn.prepare("SELECT peer_id FROM sessions")?;
+        let peers = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter_map(|peer| peer.ok()?.parse().ok())
+            .collect();
+        Ok(peers)
+    }
+
     /// Open an encrypted in-memory database (for testing encryption).
     #[cfg(test)]
     pub fn open_in_memory_encrypted(passphrase: &str) -> Result<Self> {
@@ -80,16 +440,72 @@ impl Database {
             conn.pragma_update(None, "key", passphrase)
                 .context("Failed to set encryption key")?;
         }
-        let db = Self { conn };
+        let db = Self { conn, blob_dir: None };
         db.migrate()?;
         Ok(db)
     }
 
+    /// Store blobs in `dir` rather than next to the database file.
+    pub fn with_blob_dir(mut self, dir: PathBuf) -> Self {
+        self.blob_dir = Some(dir);
+        self
+    }
+
+    /// Run `f` in a transaction, so either everything it writes is stored
+    /// or none of it is. Transactions nest: `f` can call methods that use
+    /// one themselves, and they become part of this one.
+    pub fn transaction<T>(&self, f: impl FnOnce(&Self) -> Result<T>) -> Result<T> {
+        // The outermost one takes the write lock up front, waiting for other
+        // writers, rather than failing when a read turns into a write
+        let (begin, commit, rollback) = if self.conn.is_autocommit() {
+            ("BEGIN IMMEDIATE", "COMMIT", "ROLLBACK")
+        } else {
+            ("SAVEPOINT nested", "RELEASE nested", "ROLLBACK TO nested; RELEASE nested")
+        };
+        self.conn.execute_batch(begin)?;
+        match f(self) {
+            Ok(value) => {
+                self.conn.execute_batch(commit)?;
+                Ok(value)
+            }
+            Err(e) => {
+                let _ = self.conn.execute_batch(rollback);
+                Err(e)
+            }
+        }
+    }
+
     /// Run migrations.
     fn migrate(&self) -> Result<()> {
+        super::schema::migrate(&self.conn)
+    }
+
+    /// The schema version of the database, i.e. the last migration applied.
+    pub fn schema_version(&self) -> Result<u32> {
+        super::schema::schema_version(&self.conn)
+    }
+
+    /// Re-encrypt the database under a new key, in the same x'hex' form
+    /// `open` takes.
+    pub fn rekey(&self, encryption_key: &str) -> Result<()> {
+        if encryption_key.is_empty() {
+            anyhow::bail!("Database encryption key cannot be empty. Database encryption is required.");
+        }
         self.conn
-            .execute_batch(include_str!("schema.sql"))
-            .context("Failed to run migrations")?;
+            .pragma_update(None, "rekey", encryption_key)
+            .context("Failed to rekey database")
+    }
+
+    /// Run SQLite's integrity check, failing with what it found if the
+    /// database is damaged.
+    pub fn integrity_check(&self) -> Result<()> {
+        let mut stmt = self.conn.prepare("PRAGMA integrity_check")?;
+        let problems: Vec<String> = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<_, _>>()?;
+        if problems != ["ok"] {
+            anyhow::bail!("Database integrity check failed: {}", problems.join("; "));
+        }
         Ok(())
     }
 
@@ -97,10 +513,7 @@ impl Database {
 
     /// Insert a message.
     pub fn insert_message(&self, msg: &Message) -> Result<()> {
-        let to_peer = match &msg.to {
-            Recipient::Direct(peer) => peer.to_string(),
-            Recipient::Group(id) => id.to_string(),
-        };
+        let to_peer = recipient_key(&msg.to);
         let content = serde_json::to_vec(&msg.content)?;
         let status = format!("{:?}", msg.status);
 
@@ -119,18 +532,149 @@ impl Database {
         Ok(())
     }
 
-    /// Get messages with a peer.
+    /// Insert many messages in one transaction, as history sync and
+    /// imports do; committing each row on its own makes thousands take
+    /// minutes. Messages whose ID is already stored are skipped. Returns
+    /// how many were inserted.
+    pub fn insert_messages_batch(&self, messages: &[Message]) -> Result<usize> {
+        self.transaction(|db| {
+            let mut stmt = db.conn.prepare_cached(
+                "INSERT OR IGNORE INTO messages (id, from_peer, to_peer, content, timestamp, status)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            let mut inserted = 0;
+            for msg in messages {
+                inserted += stmt.execute(params![
+                    msg.id.to_string(),
+                    msg.from.to_string(),
+                    recipient_key(&msg.to),
+                    serde_json::to_vec(&msg.content)?,
+                    msg.timestamp.timestamp(),
+                    format!("{:?}", msg.status),
+                ])?;
+            }
+            Ok(inserted)
+        })
+    }
+
+    /// Get the most recent messages with a peer, newest first.
     pub fn get_messages_with_peer(&self, peer_id: &PeerId, limit: usize) -> Result<Vec<Message>> {
+        self.get_messages_with_peer_page(peer_id, limit, 0)
+    }
+
+    /// Get messages with a peer, newest first, skipping the `offset` most
+    /// recent ones.
+    pub fn get_messages_with_peer_page(&self, peer_id: &PeerId, limit: usize, offset: usize) -> Result<Vec<Message>> {
         let peer_str = peer_id.to_string();
         let mut stmt = self.conn.prepare(
             "SELECT id, from_peer, to_peer, content, timestamp, status
              FROM messages
              WHERE from_peer = ?1 OR to_peer = ?1
-             ORDER BY timestamp DESC
+             ORDER BY timestamp DESC, rowid DESC
+             LIMIT ?2 OFFSET ?3",
+        )?;
+
+        let rows = stmt.query_map(params![peer_str, limit as i64, offset as i64], |row| {
+            Ok(MessageRow {
+                id: row.get(0)?,
+                from_peer: row.get(1)?,
+                to_peer: row.get(2)?,
+                content: row.get(3)?,
+                timestamp: row.get(4)?,
+                status: row.get(5)?,
+            })
+        })?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            let row = row?;
+            if let Ok(msg) = self.row_to_message(row) {
+                messages.push(msg);
+            }
+        }
+        Ok(messages)
+    }
+
+    /// All messages with a peer, oldest first, optionally only those sent
+    /// at or after `since`.
+    pub fn get_conversation(&self, peer_id: &PeerId, since: Option<DateTime<Utc>>) -> Result<Vec<Message>> {
+        let peer_str = peer_id.to_string();
+        let since = since.map_or(i64::MIN, |dt| dt.timestamp());
+        let mut stmt = self.conn.prepare(
+            "SELECT id, from_peer, to_peer, content, timestamp, status
+             FROM messages
+             WHERE (from_peer = ?1 OR to_peer = ?1) AND timestamp >= ?2
+             ORDER BY timestamp ASC",
+        )?;
+
+        let rows = stmt.query_map(params![peer_str, since], |row| {
+            Ok(MessageRow {
+                id: row.get(0)?,
+                from_peer: row.get(1)?,
+                to_peer: row.get(2)?,
+                content: row.get(3)?,
+                timestamp: row.get(4)?,
+                status: row.get(5)?,
+            })
+        })?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            let row = row?;
+            if let Ok(msg) = self.row_to_message(row) {
+                messages.push(msg);
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Every stored message, in every conversation, oldest first.
+    pub fn all_messages(&self) -> Result<Vec<Message>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, from_peer, to_peer, content, timestamp, status
+             FROM messages
+             ORDER BY timestamp ASC, rowid ASC",
+        )?;
+
+        let rows = stmt.query_map([], |row| {
+            Ok(MessageRow {
+                id: row.get(0)?,
+                from_peer: row.get(1)?,
+                to_peer: row.get(2)?,
+                content: row.get(3)?,
+                timestamp: row.get(4)?,
+                status: row.get(5)?,
+            })
+        })?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            let row = row?;
+            if let Ok(msg) = self.row_to_message(row) {
+                messages.push(msg);
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Search text messages, best matches first.
+    ///
+    /// Each word in `query` must appear in the message; FTS5 operators
+    /// are treated as plain text.
+    pub fn search_messages(&self, query: &str, limit: usize) -> Result<Vec<Message>> {
+        let Some(fts_query) = fts_query(query) else {
+            return Ok(Vec::new());
+        };
+        let mut stmt = self.conn.prepare(
+            "SELECT m.id, m.from_peer, m.to_peer, m.content, m.timestamp, m.status
+             FROM messages_fts f
+             JOIN messages m ON m.id = f.message_id
+             WHERE messages_fts MATCH ?1
+             ORDER BY f.rank, m.timestamp DESC
              LIMIT ?2",
         )?;
 
-        let rows = stmt.query_map(params![peer_str, limit as i64], |row| {
+        let rows = stmt.query_map(params![fts_query, limit as i64], |row| {
             Ok(MessageRow {
                 id: row.get(0)?,
                 from_peer: row.get(1)?,
@@ -161,6 +705,106 @@ impl Database {
         Ok(rows > 0)
     }
 
+    /// Get a message by ID.
+    pub fn get_message(&self, id: &Uuid) -> Result<Option<Message>> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT id, from_peer, to_peer, content, timestamp, status FROM messages WHERE id = ?1",
+                params![id.to_string()],
+                |row| {
+                    Ok(MessageRow {
+                        id: row.get(0)?,
+                        from_peer: row.get(1)?,
+                        to_peer: row.get(2)?,
+                        content: row.get(3)?,
+                        timestamp: row.get(4)?,
+                        status: row.get(5)?,
+                    })
+                },
+            )
+            .optional()?;
+        row.map(|row| self.row_to_message(row)).transpose()
+    }
+
+    /// IDs of messages whose ID starts with `prefix`, at most `limit`.
+    pub fn find_message_ids(&self, prefix: &str, limit: usize) -> Result<Vec<Uuid>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id FROM messages WHERE substr(id, 1, length(?1)) = ?1 LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![prefix.to_lowercase(), limit as i64], |row| row.get::<_, String>(0))?;
+
+        let mut ids = Vec::new();
+        for row in rows {
+            if let Ok(id) = Uuid::parse_str(&row?) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Delete our copy of a message, and what's recorded about it.
+    pub fn delete_message(&self, id: &Uuid) -> Result<bool> {
+        Ok(self.delete_messages(&[id.to_string()])? > 0)
+    }
+
+    /// Whether we deleted a message, so synced history shouldn't restore it.
+    pub fn was_message_deleted(&self, id: &Uuid) -> Result<bool> {
+        let found: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT 1 FROM deleted_messages WHERE message_id = ?1",
+                params![id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// IDs of every message we deleted.
+    pub fn deleted_message_ids(&self) -> Result<Vec<Uuid>> {
+        let mut stmt = self.conn.prepare("SELECT message_id FROM deleted_messages")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let mut ids = Vec::new();
+        for row in rows {
+            ids.push(Uuid::parse_str(&row?)?);
+        }
+        Ok(ids)
+    }
+
+    /// Flag a received message whose sender couldn't be verified.
+    pub fn mark_message_unverified(&self, id: &Uuid) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO unverified_messages (message_id) VALUES (?1)",
+            params![id.to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// Whether a message was flagged as coming from an unverified sender.
+    pub fn is_message_unverified(&self, id: &Uuid) -> Result<bool> {
+        let found: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT 1 FROM unverified_messages WHERE message_id = ?1",
+                params![id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// Replace a message's content with a tombstone, keeping its place in
+    /// the conversation.
+    pub fn tombstone_message(&self, id: &Uuid) -> Result<bool> {
+        let content = serde_json::to_vec(&MessageContent::Tombstone)?;
+        let rows = self.conn.execute(
+            "UPDATE messages SET content = ?1 WHERE id = ?2",
+            params![content, id.to_string()],
+        )?;
+        Ok(rows > 0)
+    }
+
     fn row_to_message(&self, row: MessageRow) -> Result<Message> {
         let id = Uuid::parse_str(&row.id)?;
         let from: PeerId = row.from_peer.parse()?;
@@ -176,6 +820,7 @@ impl Database {
             "Sent" => MessageStatus::Sent,
             "Delivered" => MessageStatus::Delivered,
             "Read" => MessageStatus::Read,
+            "Expired" => MessageStatus::Expired,
             s if s.starts_with("Failed") => MessageStatus::Failed(s.to_string()),
             _ => MessageStatus::Pending,
         };
@@ -196,16 +841,20 @@ impl Database {
     pub fn upsert_contact(&self, contact: &Contact) -> Result<()> {
         let trust = format!("{:?}", contact.trust_level);
         let last_seen = contact.last_seen.map(|dt| dt.timestamp());
+        let presence = contact.presence.map(|p| p.status.as_str());
+        let presence_at = contact.presence.map(|p| p.at.timestamp());
 
         self.conn.execute(
-            "INSERT OR REPLACE INTO contacts (peer_id, alias, public_key, trust_level, last_seen)
-             VALUES (?1, ?2, ?3, ?4, ?5)",
+            "INSERT OR REPLACE INTO contacts (peer_id, alias, public_key, trust_level, last_seen, presence, presence_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
             params![
                 contact.peer_id.to_string(),
                 contact.alias,
                 contact.public_key,
                 trust,
                 last_seen,
+                presence,
+                presence_at,
             ],
         )?;
         Ok(())
@@ -214,7 +863,7 @@ impl Database {
     /// Get a contact by peer ID.
     pub fn get_contact(&self, peer_id: &PeerId) -> Result<Option<Contact>> {
         let mut stmt = self.conn.prepare(
-            "SELECT peer_id, alias, public_key, trust_level, last_seen FROM contacts WHERE peer_id = ?1",
+            &format!("{} WHERE c.peer_id = ?1", CONTACT_SELECT),
         )?;
 
         stmt.query_row(params![peer_id.to_string()], |row| {
@@ -227,7 +876,7 @@ impl Database {
     /// Get a contact by alias.
     pub fn get_contact_by_alias(&self, alias: &str) -> Result<Option<Contact>> {
         let mut stmt = self.conn.prepare(
-            "SELECT peer_id, alias, public_key, trust_level, last_seen FROM contacts WHERE alias = ?1",
+            &format!("{} WHERE c.alias = ?1", CONTACT_SELECT),
         )?;
 
         stmt.query_row(params![alias], |row| self.row_to_contact(row))
@@ -238,7 +887,7 @@ impl Database {
     /// List all contacts.
     pub fn list_contacts(&self) -> Result<Vec<Contact>> {
         let mut stmt = self.conn.prepare(
-            "SELECT peer_id, alias, public_key, trust_level, last_seen FROM contacts ORDER BY alias",
+            &format!("{} ORDER BY c.alias", CONTACT_SELECT),
         )?;
 
         let rows = stmt.query_map([], |row| self.row_to_contact(row))?;
@@ -250,21 +899,397 @@ impl Database {
         Ok(contacts)
     }
 
-    /// Delete a contact.
+    /// Record a contact's presence announcement. Returns false if they
+    /// aren't a contact, or are blocked.
+    pub fn set_contact_presence(&self, peer_id: &PeerId, presence: Presence) -> Result<bool> {
+        let rows = self.conn.execute(
+            "UPDATE contacts SET presence = ?1, presence_at = ?2 WHERE peer_id = ?3 AND trust_level != 'Blocked'",
+            params![presence.status.as_str(), presence.at.timestamp(), peer_id.to_string()],
+        )?;
+        Ok(rows > 0)
+    }
+
+    /// Delete a contact, with their note, tags and remembered addresses.
     pub fn delete_contact(&self, peer_id: &PeerId) -> Result<bool> {
+        let peer_str = peer_id.to_string();
+        self.transaction(|db| {
+            db.conn
+                .execute("DELETE FROM contact_notes WHERE peer_id = ?1", params![peer_str])?;
+            db.conn
+                .execute("DELETE FROM contact_tags WHERE peer_id = ?1", params![peer_str])?;
+            db.conn
+                .execute("DELETE FROM peer_addresses WHERE peer_id = ?1", params![peer_str])?;
+            db.conn
+                .execute("DELETE FROM peer_latency WHERE peer_id = ?1", params![peer_str])?;
+            db.conn
+                .execute("DELETE FROM peer_versions WHERE peer_id = ?1", params![peer_str])?;
+            db.conn
+                .execute("DELETE FROM traffic WHERE peer_id = ?1", params![peer_str])?;
+            db.conn
+                .execute("DELETE FROM history_sync WHERE peer_id = ?1", params![peer_str])?;
+            db.conn
+                .execute("DELETE FROM key_changes WHERE peer_id = ?1", params![peer_str])?;
+            let rows = db
+                .conn
+                .execute("DELETE FROM contacts WHERE peer_id = ?1", params![peer_str])?;
+            Ok(rows > 0)
+        })
+    }
+
+    /// Replace a contact's identity key with one they presented, resetting
+    /// their trust to Unknown (blocked contacts stay blocked) and noting the
+    /// change until they're verified again. A change not yet verified
+    /// keeps the key from before it. Returns false if they aren't a
+    /// contact.
+    pub fn record_key_change(&self, peer_id: &PeerId, public_key: &[u8], at: DateTime<Utc>) -> Result<bool> {
+        let Some(contact) = self.get_contact(peer_id)? else {
+            return Ok(false);
+        };
+        let peer_str = peer_id.to_string();
+        self.conn.execute(
+            "INSERT INTO key_changes (peer_id, previous_key, changed_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(peer_id) DO UPDATE SET changed_at = excluded.changed_at",
+            params![peer_str, contact.public_key, at.timestamp()],
+        )?;
+        self.conn.execute(
+            "UPDATE contacts SET public_key = ?1,
+                 trust_level = CASE trust_level WHEN 'Blocked' THEN 'Blocked' ELSE 'Unknown' END
+             WHERE peer_id = ?2",
+            params![public_key, peer_str],
+        )?;
+        Ok(true)
+    }
+
+    /// When a contact's identity key last changed, if it has since they
+    /// were last verified.
+    pub fn key_change(&self, peer_id: &PeerId) -> Result<Option<DateTime<Utc>>> {
+        let changed_at: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT changed_at FROM key_changes WHERE peer_id = ?1",
+                params![peer_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(changed_at.and_then(|secs| Utc.timestamp_opt(secs, 0).single()))
+    }
+
+    /// Forget a contact's key change once they're verified again. Returns
+    /// true if there was one.
+    pub fn clear_key_change(&self, peer_id: &PeerId) -> Result<bool> {
         let rows = self
             .conn
-            .execute("DELETE FROM contacts WHERE peer_id = ?1", params![peer_id.to_string()])?;
+            .execute("DELETE FROM key_changes WHERE peer_id = ?1", params![peer_id.to_string()])?;
         Ok(rows > 0)
     }
 
-    fn row_to_contact(&self, row: &rusqlite::Row) -> rusqlite::Result<Contact> {
-        let peer_id_str: String = row.get(0)?;
-        let alias: String = row.get(1)?;
-        let public_key: Vec<u8> = row.get(2)?;
-        let trust_str: String = row.get(3)?;
-        let last_seen_ts: Option<i64> = row.get(4)?;
-
+    /// Set or, with `None`, clear the note on a contact.
+    pub fn set_contact_note(&self, peer_id: &PeerId, note: Option<&str>) -> Result<()> {
+        match note {
+            Some(note) => self.conn.execute(
+                "INSERT INTO contact_notes (peer_id, note) VALUES (?1, ?2)
+                 ON CONFLICT(peer_id) DO UPDATE SET note = excluded.note",
+                params![peer_id.to_string(), note],
+            )?,
+            None => self
+                .conn
+                .execute("DELETE FROM contact_notes WHERE peer_id = ?1", params![peer_id.to_string()])?,
+        };
+        Ok(())
+    }
+
+    /// Tag a contact. Returns false if they already had the tag.
+    pub fn add_contact_tag(&self, peer_id: &PeerId, tag: &str) -> Result<bool> {
+        let rows = self.conn.execute(
+            "INSERT OR IGNORE INTO contact_tags (peer_id, tag) VALUES (?1, ?2)",
+            params![peer_id.to_string(), tag],
+        )?;
+        Ok(rows > 0)
+    }
+
+    /// Remove a tag from a contact. Returns false if they didn't have it.
+    pub fn remove_contact_tag(&self, peer_id: &PeerId, tag: &str) -> Result<bool> {
+        let rows = self.conn.execute(
+            "DELETE FROM contact_tags WHERE peer_id = ?1 AND tag = ?2",
+            params![peer_id.to_string(), tag],
+        )?;
+        Ok(rows > 0)
+    }
+
+    /// Change a contact's alias. Fails if another contact already has it.
+    /// Returns whether the contact exists.
+    pub fn rename_contact(&self, peer_id: &PeerId, alias: &str) -> Result<bool> {
+        if let Some(existing) = self.get_contact_by_alias(alias)? {
+            if existing.peer_id != *peer_id {
+                anyhow::bail!("Contact '{}' already exists", alias);
+            }
+        }
+        let rows = self.conn.execute(
+            "UPDATE contacts SET alias = ?1 WHERE peer_id = ?2",
+            params![alias, peer_id.to_string()],
+        )?;
+        Ok(rows > 0)
+    }
+
+    /// Delete our direct conversation with a peer: the stored messages,
+    /// remembering their IDs, anything still queued for them and when we last read it. Group
+    /// messages they sent are kept. Returns (messages, pending) removed.
+    pub fn delete_conversation(&self, peer_id: &PeerId) -> Result<(usize, usize)> {
+        let peer_str = peer_id.to_string();
+        self.transaction(|db| {
+            db.conn.execute(
+                "INSERT OR IGNORE INTO deleted_messages (message_id, deleted_at)
+                 SELECT id, ?2 FROM messages
+                 WHERE to_peer = ?1 OR (from_peer = ?1 AND instr(to_peer, '-') = 0)",
+                params![peer_str, Utc::now().timestamp()],
+            )?;
+            let messages = db.conn.execute(
+                "DELETE FROM messages
+                 WHERE to_peer = ?1 OR (from_peer = ?1 AND instr(to_peer, '-') = 0)",
+                params![peer_str],
+            )?;
+            db.conn.execute(
+                "DELETE FROM mailbox_deposits WHERE message_id IN (SELECT id FROM pending_messages WHERE to_peer = ?1)",
+                params![peer_str],
+            )?;
+            let pending = db
+                .conn
+                .execute("DELETE FROM pending_messages WHERE to_peer = ?1", params![peer_str])?;
+            db.conn
+                .execute("DELETE FROM conversation_reads WHERE conversation = ?1", params![peer_str])?;
+            Ok((messages, pending))
+        })
+    }
+
+    /// Choose whether to send read receipts to a contact.
+    pub fn set_read_receipts(&self, peer_id: &PeerId, enabled: bool) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO contact_settings (peer_id, read_receipts) VALUES (?1, ?2)
+             ON CONFLICT(peer_id) DO UPDATE SET read_receipts = excluded.read_receipts",
+            params![peer_id.to_string(), enabled],
+        )?;
+        Ok(())
+    }
+
+    /// Whether we send read receipts to a contact. On unless turned off.
+    pub fn read_receipts_enabled(&self, peer_id: &PeerId) -> Result<bool> {
+        let enabled: Option<bool> = self
+            .conn
+            .query_row(
+                "SELECT read_receipts FROM contact_settings WHERE peer_id = ?1",
+                params![peer_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(enabled.unwrap_or(true))
+    }
+
+    /// Choose whether a contact takes part in peer exchange.
+    pub fn set_share_addresses(&self, peer_id: &PeerId, enabled: bool) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO contact_settings (peer_id, share_addresses) VALUES (?1, ?2)
+             ON CONFLICT(peer_id) DO UPDATE SET share_addresses = excluded.share_addresses",
+            params![peer_id.to_string(), enabled],
+        )?;
+        Ok(())
+    }
+
+    /// Whether we pass on where we've reached a contact, and trade hints
+    /// with them. On unless turned off.
+    pub fn shares_addresses(&self, peer_id: &PeerId) -> Result<bool> {
+        let enabled: Option<bool> = self
+            .conn
+            .query_row(
+                "SELECT share_addresses FROM contact_settings WHERE peer_id = ?1",
+                params![peer_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(enabled.unwrap_or(true))
+    }
+
+    /// Mark everything in a conversation so far as read.
+    pub fn mark_conversation_read(&self, with: &Recipient) -> Result<()> {
+        // A sender whose clock runs ahead would otherwise stay unread
+        self.conn.execute(
+            "INSERT INTO conversation_reads (conversation, last_read_at)
+             VALUES (?1, MAX(?2, COALESCE(
+                 (SELECT MAX(timestamp) FROM messages WHERE from_peer = ?1 OR to_peer = ?1), 0)))
+             ON CONFLICT(conversation) DO UPDATE SET last_read_at = excluded.last_read_at",
+            params![recipient_key(with), Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// Number of direct messages from each contact since their chat was last
+    /// read. Contacts with nothing unread are left out.
+    pub fn unread_counts(&self) -> Result<HashMap<PeerId, usize>> {
+        // Group messages are addressed to the group's UUID, never a peer ID
+        let mut stmt = self.conn.prepare(
+            "SELECT m.from_peer, COUNT(*)
+             FROM messages m
+             JOIN contacts c ON c.peer_id = m.from_peer
+             LEFT JOIN conversation_reads r ON r.conversation = m.from_peer
+             WHERE m.timestamp > COALESCE(r.last_read_at, -1)
+               AND instr(m.to_peer, '-') = 0
+               AND (json_extract(CAST(m.content AS TEXT), '$.Text') IS NOT NULL
+                    OR json_extract(CAST(m.content AS TEXT), '$.File') IS NOT NULL)
+             GROUP BY m.from_peer",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?;
+
+        let mut counts = HashMap::new();
+        for row in rows {
+            let (peer_id, count) = row?;
+            if let Ok(peer_id) = peer_id.parse() {
+                counts.insert(peer_id, count as usize);
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Every contact and group with its latest message and unread count,
+    /// archived ones included. Pinned conversations come first, then the
+    /// most recently active. Conversations with no messages yet come last,
+    /// by name.
+    pub fn list_conversations(&self) -> Result<Vec<Conversation>> {
+        let unread = self.unread_counts()?;
+        let places = self.conversation_places()?;
+        let place = |with: &Recipient| places.get(&recipient_key(with)).copied().unwrap_or_default();
+
+        let mut conversations = Vec::new();
+        for contact in self.list_contacts()? {
+            let with = Recipient::Direct(contact.peer_id);
+            let (pinned, archived) = place(&with);
+            conversations.push(Conversation {
+                last_message: self.last_message(&with)?,
+                unread: unread.get(&contact.peer_id).copied().unwrap_or(0),
+                name: contact.alias,
+                with,
+                pinned,
+                archived,
+            });
+        }
+        for group in self.list_groups()? {
+            let with = Recipient::Group(group.id);
+            let (pinned, archived) = place(&with);
+            conversations.push(Conversation {
+                last_message: self.last_message(&with)?,
+                unread: self.group_unread(&group.id)?,
+                name: group.name,
+                with,
+                pinned,
+                archived,
+            });
+        }
+
+        sort_conversations(&mut conversations);
+        Ok(conversations)
+    }
+
+    /// Whether each conversation with a place set is pinned and archived,
+    /// by conversation key.
+    fn conversation_places(&self) -> Result<HashMap<String, (bool, bool)>> {
+        let mut stmt = self.conn.prepare("SELECT conversation, pinned, archived FROM conversation_places")?;
+        let places = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, (row.get(1)?, row.get(2)?))))?
+            .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+        Ok(places)
+    }
+
+    /// Whether a conversation is pinned and whether it's archived.
+    pub fn conversation_place(&self, with: &Recipient) -> Result<(bool, bool)> {
+        let place = self
+            .conn
+            .query_row(
+                "SELECT pinned, archived FROM conversation_places WHERE conversation = ?1",
+                params![recipient_key(with)],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(place.unwrap_or_default())
+    }
+
+    /// Pin a conversation to the top of the inbox, or unpin it.
+    pub fn set_pinned(&self, with: &Recipient, pinned: bool) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO conversation_places (conversation, pinned) VALUES (?1, ?2)
+             ON CONFLICT(conversation) DO UPDATE SET pinned = excluded.pinned",
+            params![recipient_key(with), pinned],
+        )?;
+        Ok(())
+    }
+
+    /// Archive a conversation out of the default view, or bring it back.
+    pub fn set_archived(&self, with: &Recipient, archived: bool) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO conversation_places (conversation, archived) VALUES (?1, ?2)
+             ON CONFLICT(conversation) DO UPDATE SET archived = excluded.archived",
+            params![recipient_key(with), archived],
+        )?;
+        Ok(())
+    }
+
+    /// The latest text, file or deleted message in a conversation.
+    fn last_message(&self, with: &Recipient) -> Result<Option<Message>> {
+        let filter = conversation_filter(with);
+        let row = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT id, from_peer, to_peer, content, timestamp, status
+                     FROM messages
+                     WHERE {}
+                       AND (json_extract(CAST(content AS TEXT), '$.Text') IS NOT NULL
+                            OR json_extract(CAST(content AS TEXT), '$.File') IS NOT NULL
+                            OR CAST(content AS TEXT) = '\"Tombstone\"')
+                     ORDER BY timestamp DESC, rowid DESC
+                     LIMIT 1",
+                    filter
+                ),
+                params![recipient_key(with)],
+                |row| {
+                    Ok(MessageRow {
+                        id: row.get(0)?,
+                        from_peer: row.get(1)?,
+                        to_peer: row.get(2)?,
+                        content: row.get(3)?,
+                        timestamp: row.get(4)?,
+                        status: row.get(5)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        row.map(|row| self.row_to_message(row)).transpose()
+    }
+
+    /// Number of group messages since the group was last read.
+    fn group_unread(&self, group_id: &Uuid) -> Result<usize> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM messages
+             WHERE to_peer = ?1
+               AND timestamp > COALESCE(
+                   (SELECT last_read_at FROM conversation_reads WHERE conversation = ?1), -1)
+               AND (json_extract(CAST(content AS TEXT), '$.Text') IS NOT NULL
+                    OR json_extract(CAST(content AS TEXT), '$.File') IS NOT NULL)",
+            params![group_id.to_string()],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn row_to_contact(&self, row: &rusqlite::Row) -> rusqlite::Result<Contact> {
+        let peer_id_str: String = row.get(0)?;
+        let alias: String = row.get(1)?;
+        let public_key: Vec<u8> = row.get(2)?;
+        let trust_str: String = row.get(3)?;
+        let last_seen_ts: Option<i64> = row.get(4)?;
+        let note: Option<String> = row.get(5)?;
+        let tags: Option<String> = row.get(6)?;
+        let presence: Option<String> = row.get(7)?;
+        let presence_at: Option<i64> = row.get(8)?;
+
         let peer_id = peer_id_str
             .parse()
             .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))?;
@@ -278,12 +1303,26 @@ impl Database {
 
         let last_seen = last_seen_ts.and_then(|ts| Utc.timestamp_opt(ts, 0).single());
 
+        let mut tags: Vec<String> = tags
+            .map(|t| t.split(',').map(str::to_string).collect())
+            .unwrap_or_default();
+        tags.sort();
+
+        let presence = presence
+            .as_deref()
+            .and_then(PresenceStatus::parse)
+            .zip(presence_at.and_then(|ts| Utc.timestamp_opt(ts, 0).single()))
+            .map(|(status, at)| Presence { status, at });
+
         Ok(Contact {
             peer_id,
             alias,
             public_key,
             trust_level,
             last_seen,
+            note,
+            tags,
+            presence,
         })
     }
 
@@ -291,25 +1330,27 @@ impl Database {
 
     /// Create a new group.
     pub fn create_group(&self, group: &Group) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO groups (id, name, description, owner_peer_id, symmetric_key, created_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            params![
-                group.id.to_string(),
-                group.name,
-                group.description,
-                group.owner.map(|p| p.to_string()),
-                group.symmetric_key,
-                group.created_at.timestamp(),
-            ],
-        )?;
-
-        // Add members with roles
-        for member in &group.members {
-            self.add_group_member_with_role(&group.id, &member.peer_id, member.role)?;
-        }
+        // A group is never stored without its members
+        self.transaction(|db| {
+            db.conn.execute(
+                "INSERT INTO groups (id, name, description, owner_peer_id, symmetric_key, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    group.id.to_string(),
+                    group.name,
+                    group.description,
+                    group.owner.map(|p| p.to_string()),
+                    group.symmetric_key.as_slice(),
+                    group.created_at.timestamp(),
+                ],
+            )?;
 
-        Ok(())
+            // Add members with roles
+            for member in &group.members {
+                db.add_group_member_with_role(&group.id, &member.peer_id, member.role)?;
+            }
+            Ok(())
+        })
     }
 
     /// Get a group by ID.
@@ -344,7 +1385,7 @@ impl Database {
                     description,
                     owner,
                     members,
-                    symmetric_key,
+                    symmetric_key: Zeroizing::new(symmetric_key),
                     created_at,
                 }))
             }
@@ -401,7 +1442,7 @@ impl Database {
                 description,
                 owner,
                 members,
-                symmetric_key,
+                symmetric_key: Zeroizing::new(symmetric_key),
                 created_at,
             });
         }
@@ -455,6 +1496,18 @@ impl Database {
             "DELETE FROM group_members WHERE group_id = ?1",
             params![id.to_string()],
         )?;
+        self.conn.execute(
+            "DELETE FROM group_keys WHERE group_id = ?1",
+            params![id.to_string()],
+        )?;
+        self.conn.execute(
+            "DELETE FROM group_departures WHERE group_id = ?1",
+            params![id.to_string()],
+        )?;
+        self.conn.execute(
+            "DELETE FROM group_sync_state WHERE group_id = ?1",
+            params![id.to_string()],
+        )?;
 
         let rows = self
             .conn
@@ -473,6 +1526,11 @@ impl Database {
             "INSERT OR REPLACE INTO group_members (group_id, peer_id, role) VALUES (?1, ?2, ?3)",
             params![group_id.to_string(), peer_id.to_string(), role.to_string()],
         )?;
+        // Rejoining clears an earlier departure
+        self.conn.execute(
+            "DELETE FROM group_departures WHERE group_id = ?1 AND peer_id = ?2",
+            params![group_id.to_string(), peer_id.to_string()],
+        )?;
         Ok(())
     }
 
@@ -540,88 +1598,2067 @@ impl Database {
             .collect())
     }
 
-    // === Pending Message Queue (Persistent Offline Queue) ===
+    /// Record that a member left a group, removing them from it.
+    pub fn record_group_departure(&self, group_id: &Uuid, peer_id: &PeerId, left_at: DateTime<Utc>) -> Result<()> {
+        self.remove_group_member(group_id, peer_id)?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO group_departures (group_id, peer_id, left_at) VALUES (?1, ?2, ?3)",
+            params![group_id.to_string(), peer_id.to_string(), left_at.timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// When a peer left a group, if they have.
+    pub fn group_departure(&self, group_id: &Uuid, peer_id: &PeerId) -> Result<Option<DateTime<Utc>>> {
+        let left_at: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT left_at FROM group_departures WHERE group_id = ?1 AND peer_id = ?2",
+                params![group_id.to_string(), peer_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(left_at.and_then(|ts| Utc.timestamp_opt(ts, 0).single()))
+    }
+
+    // === Group Keys ===
+
+    /// Current key epoch of a group; 0 until the key is first rotated.
+    pub fn group_key_epoch(&self, group_id: &Uuid) -> Result<u32> {
+        let epoch: u32 = self.conn.query_row(
+            "SELECT COALESCE(MAX(epoch), 0) FROM group_keys WHERE group_id = ?1",
+            params![group_id.to_string()],
+            |row| row.get(0),
+        )?;
+        Ok(epoch)
+    }
+
+    /// Replace a group's key with a newer epoch.
+    ///
+    /// The old key is kept in the key history and the group's stored
+    /// messages are marked as sent under it. Returns false, changing
+    /// nothing, if `epoch` isn't newer than the current one.
+    pub fn set_group_key(&self, group_id: &Uuid, epoch: u32, symmetric_key: &[u8]) -> Result<bool> {
+        let current = self.group_key_epoch(group_id)?;
+        if epoch <= current {
+            return Ok(false);
+        }
+        let id = group_id.to_string();
+        let now = Utc::now().timestamp();
+
+        // Keep the outgoing key (a no-op after the first rotation)
+        let rows = self.conn.execute(
+            "INSERT OR IGNORE INTO group_keys (group_id, epoch, symmetric_key, created_at)
+             SELECT id, ?2, symmetric_key, created_at FROM groups WHERE id = ?1",
+            params![id, current],
+        )?;
+        if rows == 0 && current == 0 {
+            // No such group
+            return Ok(false);
+        }
 
-    /// Queue an encrypted message for later delivery.
-    pub fn queue_pending_message(&self, id: &Uuid, to_peer: &PeerId, encrypted_data: &[u8]) -> Result<()> {
         self.conn.execute(
-            "INSERT OR REPLACE INTO pending_messages (id, to_peer, encrypted_data, created_at, attempts)
-             VALUES (?1, ?2, ?3, ?4, 0)",
+            "INSERT OR IGNORE INTO group_message_epochs (message_id, group_id, epoch)
+             SELECT id, to_peer, ?2 FROM messages WHERE to_peer = ?1",
+            params![id, current],
+        )?;
+        self.conn.execute(
+            "INSERT INTO group_keys (group_id, epoch, symmetric_key, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![id, epoch, symmetric_key, now],
+        )?;
+        self.conn.execute(
+            "UPDATE groups SET symmetric_key = ?1 WHERE id = ?2",
+            params![symmetric_key, id],
+        )?;
+        Ok(true)
+    }
+
+    /// Key epoch a group message was sent under, if that key has been
+    /// rotated out. `None` means the message used the current key.
+    pub fn retired_key_epoch(&self, message_id: &Uuid) -> Result<Option<u32>> {
+        let epoch = self
+            .conn
+            .query_row(
+                "SELECT epoch FROM group_message_epochs WHERE message_id = ?1",
+                params![message_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(epoch)
+    }
+
+    // === Group Sync ===
+
+    /// When our view of a group's metadata last changed, if it has since
+    /// we created or joined it.
+    pub fn group_updated_at(&self, group_id: &Uuid) -> Result<Option<DateTime<Utc>>> {
+        let updated_at: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT updated_at FROM group_sync_state WHERE group_id = ?1",
+                params![group_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(updated_at.and_then(|ms| Utc.timestamp_millis_opt(ms).single()))
+    }
+
+    /// Record when our view of a group's metadata changed.
+    pub fn set_group_updated_at(&self, group_id: &Uuid, updated_at: DateTime<Utc>) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO group_sync_state (group_id, updated_at) VALUES (?1, ?2)",
+            params![group_id.to_string(), updated_at.timestamp_millis()],
+        )?;
+        Ok(())
+    }
+
+    // === Message Requests ===
+
+    /// Store a direct message someone sent us. Messages from anyone who
+    /// isn't a contact, or isn't as trusted as the trust policy asks, are
+    /// held as message requests until accepted. Returns where it went, or
+    /// `None` if we already had it.
+    pub fn receive_message(&self, msg: &Message) -> Result<Option<Inbox>> {
+        let policy = self.trust_policy()?;
+        let contact = self.get_contact(&msg.from)?;
+        if contact.is_some_and(|contact| policy.conversations.admits(contact.trust_level)) {
+            // A failed insert means we already have it (redelivery)
+            return Ok(self.insert_message(msg).is_ok().then_some(Inbox::Conversation));
+        }
+
+        let content = serde_json::to_vec(&msg.content)?;
+        let rows = self.conn.execute(
+            "INSERT OR IGNORE INTO message_requests (id, from_peer, to_peer, content, timestamp, status)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
             params![
-                id.to_string(),
-                to_peer.to_string(),
-                encrypted_data,
-                Utc::now().timestamp(),
+                msg.id.to_string(),
+                msg.from.to_string(),
+                recipient_key(&msg.to),
+                content,
+                msg.timestamp.timestamp(),
+                format!("{:?}", msg.status),
+            ],
+        )?;
+        Ok((rows > 0).then_some(Inbox::Request))
+    }
+
+    /// Messages held as requests, oldest first.
+    pub fn list_message_requests(&self) -> Result<Vec<Message>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, from_peer, to_peer, content, timestamp, status
+             FROM message_requests
+             ORDER BY timestamp, rowid",
+        )?;
+
+        let rows = stmt.query_map([], |row| {
+            Ok(MessageRow {
+                id: row.get(0)?,
+                from_peer: row.get(1)?,
+                to_peer: row.get(2)?,
+                content: row.get(3)?,
+                timestamp: row.get(4)?,
+                status: row.get(5)?,
+            })
+        })?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            if let Ok(msg) = self.row_to_message(row?) {
+                messages.push(msg);
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Whether `from` has messages waiting as requests.
+    pub fn has_message_requests(&self, from: &PeerId) -> Result<bool> {
+        Ok(self.conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM message_requests WHERE from_peer = ?1)",
+            params![from.to_string()],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Whether `from` has ever sent us a message we kept in a conversation.
+    pub fn has_messages_from(&self, from: &PeerId) -> Result<bool> {
+        Ok(self.conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM messages WHERE from_peer = ?1)",
+            params![from.to_string()],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// How many different peers have messages waiting as requests.
+    pub fn count_message_request_senders(&self) -> Result<usize> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(DISTINCT from_peer) FROM message_requests",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Move a peer's message requests into our conversation with them.
+    /// Returns how many messages were moved.
+    pub fn accept_message_requests(&self, from: &PeerId) -> Result<usize> {
+        let from = from.to_string();
+        let moved = self.conn.execute(
+            "INSERT OR IGNORE INTO messages (id, from_peer, to_peer, content, timestamp, status)
+             SELECT id, from_peer, to_peer, content, timestamp, status
+             FROM message_requests WHERE from_peer = ?1",
+            params![from],
+        )?;
+        self.conn
+            .execute("DELETE FROM message_requests WHERE from_peer = ?1", params![from])?;
+        Ok(moved)
+    }
+
+    /// Throw away a peer's message requests. Returns how many were deleted.
+    pub fn delete_message_requests(&self, from: &PeerId) -> Result<usize> {
+        let rows = self.conn.execute(
+            "DELETE FROM message_requests WHERE from_peer = ?1",
+            params![from.to_string()],
+        )?;
+        Ok(rows)
+    }
+
+    // === Group Invites ===
+
+    /// Save a received group invite until the user accepts or declines it.
+    /// A newer invite for the same group replaces the old one.
+    pub fn save_group_invite(&self, invite: &PendingGroupInvite) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO group_invites (group_id, name, from_peer, symmetric_key, received_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                invite.group_id.to_string(),
+                invite.name,
+                invite.from.to_string(),
+                invite.symmetric_key,
+                invite.received_at.timestamp(),
             ],
         )?;
         Ok(())
     }
 
-    /// Get all pending messages for a peer.
-    pub fn get_pending_for_peer(&self, peer_id: &PeerId) -> Result<Vec<(Uuid, Vec<u8>)>> {
+    /// List pending group invites, oldest first.
+    pub fn list_group_invites(&self) -> Result<Vec<PendingGroupInvite>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, encrypted_data FROM pending_messages WHERE to_peer = ?1 ORDER BY created_at",
+            "SELECT group_id, name, from_peer, symmetric_key, received_at FROM group_invites ORDER BY received_at",
         )?;
 
-        let rows = stmt.query_map(params![peer_id.to_string()], |row| {
+        let rows = stmt.query_map([], |row| {
             let id_str: String = row.get(0)?;
-            let data: Vec<u8> = row.get(1)?;
-            Ok((id_str, data))
+            let name: String = row.get(1)?;
+            let from_str: String = row.get(2)?;
+            let symmetric_key: Vec<u8> = row.get(3)?;
+            let received_at_ts: i64 = row.get(4)?;
+            Ok((id_str, name, from_str, symmetric_key, received_at_ts))
         })?;
 
-        let mut pending = Vec::new();
+        let mut invites = Vec::new();
+        for row in rows {
+            let (id_str, name, from_str, symmetric_key, received_at_ts) = row?;
+            let from = match from_str.parse() {
+                Ok(peer) => peer,
+                Err(_) => continue,
+            };
+            invites.push(PendingGroupInvite {
+                group_id: Uuid::parse_str(&id_str)?,
+                name,
+                from,
+                symmetric_key,
+                received_at: Utc.timestamp_opt(received_at_ts, 0).single().unwrap_or_else(Utc::now),
+            });
+        }
+
+        Ok(invites)
+    }
+
+    /// Delete a pending group invite.
+    pub fn delete_group_invite(&self, group_id: &Uuid) -> Result<bool> {
+        let rows = self.conn.execute(
+            "DELETE FROM group_invites WHERE group_id = ?1",
+            params![group_id.to_string()],
+        )?;
+        Ok(rows > 0)
+    }
+
+    // === Contact Cards ===
+
+    /// Save a received contact card until the user adds the contact or
+    /// declines. A newer card for the same peer replaces the old one.
+    pub fn save_contact_card(&self, card: &PendingContactCard) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO contact_cards (peer_id, alias, public_key, from_peer, received_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                card.peer_id.to_string(),
+                card.alias,
+                card.public_key,
+                card.from.to_string(),
+                card.received_at.timestamp(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// List pending contact cards, oldest first.
+    pub fn list_contact_cards(&self) -> Result<Vec<PendingContactCard>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT peer_id, alias, public_key, from_peer, received_at FROM contact_cards ORDER BY received_at",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Vec<u8>>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, i64>(4)?,
+            ))
+        })?;
+
+        let mut cards = Vec::new();
+        for row in rows {
+            let (peer_id, alias, public_key, from, received_at) = row?;
+            let (Ok(peer_id), Ok(from)) = (peer_id.parse(), from.parse()) else {
+                continue;
+            };
+            cards.push(PendingContactCard {
+                peer_id,
+                alias,
+                public_key,
+                from,
+                received_at: Utc.timestamp_opt(received_at, 0).single().unwrap_or_else(Utc::now),
+            });
+        }
+        Ok(cards)
+    }
+
+    /// Delete a pending contact card.
+    pub fn delete_contact_card(&self, peer_id: &PeerId) -> Result<bool> {
+        let rows = self.conn.execute(
+            "DELETE FROM contact_cards WHERE peer_id = ?1",
+            params![peer_id.to_string()],
+        )?;
+        Ok(rows > 0)
+    }
+
+    // === Invites ===
+
+    /// Keep an invite we handed out, so we know it when it's accepted.
+    pub fn save_invite(&self, invite: &IssuedInvite) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO invites (id, alias, created_at, expires_at, used_by) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                invite.id.as_slice(),
+                invite.alias,
+                invite.created_at.timestamp(),
+                invite.expires_at.timestamp(),
+                invite.used_by.map(|peer_id| peer_id.to_string()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Use up an invite on `peer_id`'s behalf. Returns `None` for invites
+    /// that are unknown, expired at `now`, or already used.
+    pub fn redeem_invite(&self, id: &InviteId, peer_id: &PeerId, now: DateTime<Utc>) -> Result<Option<IssuedInvite>> {
+        let rows = self.conn.execute(
+            "UPDATE invites SET used_by = ?2 WHERE id = ?1 AND used_by IS NULL AND expires_at > ?3",
+            params![id.as_slice(), peer_id.to_string(), now.timestamp()],
+        )?;
+        if rows == 0 {
+            return Ok(None);
+        }
+        Ok(self.list_invites()?.into_iter().find(|invite| invite.id == *id))
+    }
+
+    /// List the invites we handed out, newest first.
+    pub fn list_invites(&self) -> Result<Vec<IssuedInvite>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, alias, created_at, expires_at, used_by FROM invites ORDER BY created_at DESC",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, Vec<u8>>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, Option<String>>(4)?,
+            ))
+        })?;
+
+        let mut invites = Vec::new();
+        for row in rows {
+            let (id, alias, created_at, expires_at, used_by) = row?;
+            let Ok(id) = InviteId::try_from(id.as_slice()) else {
+                continue;
+            };
+            invites.push(IssuedInvite {
+                id,
+                alias,
+                created_at: Utc.timestamp_opt(created_at, 0).single().unwrap_or_else(Utc::now),
+                expires_at: Utc.timestamp_opt(expires_at, 0).single().unwrap_or_else(Utc::now),
+                used_by: used_by.and_then(|peer_id| peer_id.parse().ok()),
+            });
+        }
+        Ok(invites)
+    }
+
+    // === Pending Message Queue (Persistent Offline Queue) ===
+
+    /// Queue an encrypted message for later delivery.
+    pub fn queue_pending_message(&self, id: &Uuid, to_peer: &PeerId, encrypted_data: &[u8]) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO pending_messages (id, to_peer, encrypted_data, created_at, attempts)
+             VALUES (?1, ?2, ?3, ?4, 0)",
+            params![
+                id.to_string(),
+                to_peer.to_string(),
+                encrypted_data,
+                Utc::now().timestamp(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Get all pending messages for a peer, other than dead letters.
+    pub fn get_pending_for_peer(&self, peer_id: &PeerId) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, encrypted_data FROM pending_messages
+             WHERE to_peer = ?1 AND dead_at IS NULL ORDER BY created_at",
+        )?;
+
+        let rows = stmt.query_map(params![peer_id.to_string()], |row| {
+            let id_str: String = row.get(0)?;
+            let data: Vec<u8> = row.get(1)?;
+            Ok((id_str, data))
+        })?;
+
+        let mut pending = Vec::new();
+        for row in rows {
+            let (id_str, data) = row?;
+            if let Ok(id) = Uuid::parse_str(&id_str) {
+                pending.push((id, data));
+            }
+        }
+
+        Ok(pending)
+    }
+
+    /// Remove a pending message after successful delivery.
+    pub fn remove_pending_message(&self, id: &Uuid) -> Result<bool> {
+        let rows = self.conn.execute(
+            "DELETE FROM pending_messages WHERE id = ?1",
+            params![id.to_string()],
+        )?;
+        self.conn
+            .execute("DELETE FROM mailbox_deposits WHERE message_id = ?1", params![id.to_string()])?;
+        Ok(rows > 0)
+    }
+
+    /// Get all pending messages (for loading queue on startup). Dead
+    /// letters aren't included.
+    pub fn get_all_pending(&self) -> Result<Vec<(Uuid, PeerId, Vec<u8>)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, to_peer, encrypted_data FROM pending_messages WHERE dead_at IS NULL ORDER BY created_at",
+        )?;
+
+        let rows = stmt.query_map([], |row| {
+            let id_str: String = row.get(0)?;
+            let peer_str: String = row.get(1)?;
+            let data: Vec<u8> = row.get(2)?;
+            Ok((id_str, peer_str, data))
+        })?;
+
+        let mut pending = Vec::new();
+        for row in rows {
+            let (id_str, peer_str, data) = row?;
+            if let (Ok(id), Ok(peer_id)) = (Uuid::parse_str(&id_str), peer_str.parse()) {
+                pending.push((id, peer_id, data));
+            }
+        }
+
+        Ok(pending)
+    }
+
+    /// Increment attempt count for a pending message. Returns the new
+    /// count, or 0 if it's no longer queued.
+    pub fn increment_pending_attempts(&self, id: &Uuid) -> Result<u32> {
+        let attempts = self
+            .conn
+            .query_row(
+                "UPDATE pending_messages SET attempts = attempts + 1 WHERE id = ?1 RETURNING attempts",
+                params![id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(attempts.unwrap_or(0))
+    }
+
+    /// Queued messages due a delivery attempt at `now`, oldest first, with
+    /// their recipients.
+    pub fn pending_due(&self, now: DateTime<Utc>) -> Result<Vec<(Uuid, PeerId)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, to_peer FROM pending_messages
+             WHERE dead_at IS NULL AND (next_attempt_at IS NULL OR next_attempt_at <= ?1)
+             ORDER BY created_at",
+        )?;
+        let rows = stmt.query_map(params![now.timestamp()], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut due = Vec::new();
+        for row in rows {
+            let (id, to_peer) = row?;
+            if let (Ok(id), Ok(to)) = (Uuid::parse_str(&id), to_peer.parse()) {
+                due.push((id, to));
+            }
+        }
+        Ok(due)
+    }
+
+    /// Put off the next delivery attempt for a queued message until `at`.
+    pub fn schedule_pending_retry(&self, id: &Uuid, at: DateTime<Utc>) -> Result<()> {
+        self.conn.execute(
+            "UPDATE pending_messages SET next_attempt_at = ?2 WHERE id = ?1",
+            params![id.to_string(), at.timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// Give up on delivering a queued message. It's kept, but no longer
+    /// sent.
+    pub fn dead_letter_pending(&self, id: &Uuid, at: DateTime<Utc>) -> Result<()> {
+        self.conn.execute(
+            "UPDATE pending_messages SET dead_at = ?2 WHERE id = ?1",
+            params![id.to_string(), at.timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// Queued messages given up on, oldest first.
+    pub fn dead_letters(&self) -> Result<Vec<DeadLetter>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, to_peer, created_at, attempts, dead_at FROM pending_messages
+             WHERE dead_at IS NOT NULL ORDER BY created_at",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, u32>(3)?,
+                row.get::<_, i64>(4)?,
+            ))
+        })?;
+
+        let mut dead = Vec::new();
+        for row in rows {
+            let (id, to_peer, created_at, attempts, dead_at) = row?;
+            if let (Ok(id), Ok(to)) = (Uuid::parse_str(&id), to_peer.parse()) {
+                dead.push(DeadLetter {
+                    id,
+                    to,
+                    queued_at: Utc.timestamp_opt(created_at, 0).single().unwrap_or_else(Utc::now),
+                    attempts,
+                    dead_at: Utc.timestamp_opt(dead_at, 0).single().unwrap_or_else(Utc::now),
+                });
+            }
+        }
+        Ok(dead)
+    }
+
+    /// Everything in the offline queue, dead letters included, oldest
+    /// first.
+    pub fn list_queue(&self) -> Result<Vec<QueueEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, to_peer, created_at, attempts, next_attempt_at, dead_at FROM pending_messages
+             ORDER BY created_at",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, u32>(3)?,
+                row.get::<_, Option<i64>>(4)?,
+                row.get::<_, Option<i64>>(5)?,
+            ))
+        })?;
+
+        let mut queue = Vec::new();
+        for row in rows {
+            let (id, to_peer, created_at, attempts, next_attempt_at, dead_at) = row?;
+            if let (Ok(id), Ok(to)) = (Uuid::parse_str(&id), to_peer.parse()) {
+                queue.push(QueueEntry {
+                    id,
+                    to,
+                    queued_at: Utc.timestamp_opt(created_at, 0).single().unwrap_or_else(Utc::now),
+                    attempts,
+                    next_attempt_at: next_attempt_at.and_then(|at| Utc.timestamp_opt(at, 0).single()),
+                    dead_at: dead_at.and_then(|at| Utc.timestamp_opt(at, 0).single()),
+                });
+            }
+        }
+        Ok(queue)
+    }
+
+    /// Start a queued message's attempts over, bringing it back if it was
+    /// given up on. Returns false if it isn't queued.
+    pub fn requeue_pending(&self, id: &Uuid) -> Result<bool> {
+        let rows = self.conn.execute(
+            "UPDATE pending_messages SET attempts = 0, next_attempt_at = NULL, dead_at = NULL WHERE id = ?1",
+            params![id.to_string()],
+        )?;
+        Ok(rows > 0)
+    }
+
+    /// Drop queued messages, dead letters included, queued before
+    /// `before`. Returns their IDs.
+    pub fn expire_pending(&self, before: DateTime<Utc>) -> Result<Vec<Uuid>> {
+        let mut stmt = self
+            .conn
+            .prepare("DELETE FROM pending_messages WHERE created_at < ?1 RETURNING id")?;
+        let ids: Vec<String> = stmt
+            .query_map(params![before.timestamp()], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        if !ids.is_empty() {
+            self.conn.execute(
+                "DELETE FROM mailbox_deposits WHERE message_id NOT IN (SELECT id FROM pending_messages)",
+                [],
+            )?;
+        }
+        Ok(ids.iter().filter_map(|id| Uuid::parse_str(id).ok()).collect())
+    }
+
+    // === Mailboxes ===
+
+    /// Leave messages for offline peers with `peer_id`. Returns false if
+    /// they already were a mailbox.
+    pub fn add_mailbox_peer(&self, peer_id: &PeerId) -> Result<bool> {
+        let rows = self.conn.execute(
+            "INSERT OR IGNORE INTO mailbox_peers (peer_id, added_at) VALUES (?1, ?2)",
+            params![peer_id.to_string(), Utc::now().timestamp()],
+        )?;
+        Ok(rows > 0)
+    }
+
+    /// Stop leaving messages with `peer_id`.
+    pub fn remove_mailbox_peer(&self, peer_id: &PeerId) -> Result<bool> {
+        let peer_str = peer_id.to_string();
+        let rows = self
+            .conn
+            .execute("DELETE FROM mailbox_peers WHERE peer_id = ?1", params![peer_str])?;
+        self.conn
+            .execute("DELETE FROM mailbox_deposits WHERE mailbox = ?1", params![peer_str])?;
+        Ok(rows > 0)
+    }
+
+    /// The peers we leave messages with, in the order they were added.
+    pub fn mailbox_peers(&self) -> Result<Vec<PeerId>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT peer_id FROM mailbox_peers ORDER BY added_at, rowid")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let mut peers = Vec::new();
+        for row in rows {
+            if let Ok(peer_id) = row?.parse() {
+                peers.push(peer_id);
+            }
+        }
+        Ok(peers)
+    }
+
+    /// Queued messages not yet left with `mailbox`, other than those for
+    /// the mailbox itself, oldest first.
+    pub fn pending_for_mailbox(&self, mailbox: &PeerId) -> Result<Vec<QueuedMessage>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, to_peer, encrypted_data, created_at FROM pending_messages
+             WHERE to_peer != ?1 AND dead_at IS NULL
+               AND id NOT IN (SELECT message_id FROM mailbox_deposits WHERE mailbox = ?1)
+             ORDER BY created_at",
+        )?;
+        let rows = stmt.query_map(params![mailbox.to_string()], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Vec<u8>>(2)?,
+                row.get::<_, i64>(3)?,
+            ))
+        })?;
+
+        let mut pending = Vec::new();
+        for row in rows {
+            let (id, to_peer, data, created_at) = row?;
+            if let (Ok(id), Ok(to)) = (Uuid::parse_str(&id), to_peer.parse()) {
+                let queued_at = Utc.timestamp_opt(created_at, 0).single().unwrap_or_else(Utc::now);
+                pending.push(QueuedMessage { id, to, data, queued_at });
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Record that a queued message was left with `mailbox`.
+    pub fn record_mailbox_deposit(&self, message_id: &Uuid, mailbox: &PeerId) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO mailbox_deposits (message_id, mailbox) VALUES (?1, ?2)",
+            params![message_id.to_string(), mailbox.to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// Choose whether we hold mail for other peers.
+    pub fn set_mailbox_serving(&self, serving: bool) -> Result<()> {
+        if serving {
+            self.conn.execute(
+                "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, '1', ?2)",
+                params![MAILBOX_SERVING, Utc::now().timestamp()],
+            )?;
+        } else {
+            self.conn
+                .execute("DELETE FROM node_state WHERE key = ?1", params![MAILBOX_SERVING])?;
+        }
+        Ok(())
+    }
+
+    /// Whether we hold mail for other peers. Off unless turned on.
+    pub fn mailbox_serving(&self) -> Result<bool> {
+        let value: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM node_state WHERE key = ?1", params![MAILBOX_SERVING], |row| row.get(0))
+            .optional()?;
+        Ok(value.is_some())
+    }
+
+    /// Show markdown in chats as typed rather than rendering it.
+    pub fn set_raw_markdown(&self, raw: bool) -> Result<()> {
+        if raw {
+            self.conn.execute(
+                "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, '1', ?2)",
+                params![RAW_MARKDOWN, Utc::now().timestamp()],
+            )?;
+        } else {
+            self.conn
+                .execute("DELETE FROM node_state WHERE key = ?1", params![RAW_MARKDOWN])?;
+        }
+        Ok(())
+    }
+
+    /// Whether chats show markdown as typed. Rendered unless turned off.
+    pub fn raw_markdown(&self) -> Result<bool> {
+        let value: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM node_state WHERE key = ?1", params![RAW_MARKDOWN], |row| row.get(0))
+            .optional()?;
+        Ok(value.is_some())
+    }
+
+    /// Turn peer exchange on or off for every contact.
+    pub fn set_pex_enabled(&self, enabled: bool) -> Result<()> {
+        if enabled {
+            self.conn.execute("DELETE FROM node_state WHERE key = ?1", params![PEX_OFF])?;
+        } else {
+            self.conn.execute(
+                "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, '1', ?2)",
+                params![PEX_OFF, Utc::now().timestamp()],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Whether we trade address hints with trusted contacts. On unless
+    /// turned off.
+    pub fn pex_enabled(&self) -> Result<bool> {
+        let value: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM node_state WHERE key = ?1", params![PEX_OFF], |row| row.get(0))
+            .optional()?;
+        Ok(value.is_none())
+    }
+
+    /// Set how many zero bits the stamp on a stranger's first message
+    /// must have; 0 lets them in without one.
+    pub fn set_stamp_difficulty(&self, difficulty: u8) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+            params![STAMP_DIFFICULTY, difficulty.to_string(), Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// How many zero bits the stamp on a stranger's first message must
+    /// have, `DEFAULT_STAMP_DIFFICULTY` unless set.
+    pub fn stamp_difficulty(&self) -> Result<u8> {
+        let value: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM node_state WHERE key = ?1", params![STAMP_DIFFICULTY], |row| row.get(0))
+            .optional()?;
+        value
+            .map(|v| v.parse().context("Stored stamp difficulty is not a number"))
+            .transpose()
+            .map(|difficulty| difficulty.unwrap_or(DEFAULT_STAMP_DIFFICULTY))
+    }
+
+    /// Save how trusted senders must be for each thing we do for them.
+    pub fn set_trust_policy(&self, policy: &TrustPolicy) -> Result<()> {
+        let mut policy = *policy;
+        self.transaction(|db| {
+            for (part, requirement) in trust_policy_parts(&mut policy) {
+                let key = format!("{}{}", TRUST_POLICY, part);
+                if *requirement == TrustRequirement::Anyone {
+                    db.conn.execute("DELETE FROM node_state WHERE key = ?1", params![key])?;
+                } else {
+                    db.conn.execute(
+                        "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+                        params![key, requirement.as_str(), Utc::now().timestamp()],
+                    )?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// How trusted senders must be for each thing we do for them. Anyone
+    /// not blocked gets everything unless set.
+    pub fn trust_policy(&self) -> Result<TrustPolicy> {
+        let mut policy = TrustPolicy::default();
+        for (part, requirement) in trust_policy_parts(&mut policy) {
+            let value: Option<String> = self
+                .conn
+                .query_row(
+                    "SELECT value FROM node_state WHERE key = ?1",
+                    params![format!("{}{}", TRUST_POLICY, part)],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if let Some(value) = value {
+                *requirement = TrustRequirement::parse(&value)
+                    .ok_or_else(|| anyhow::anyhow!("Stored trust policy for {} is not a requirement", part))?;
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Hold mail from `from` for `to` until `expires_at`. Returns false if
+    /// we already have it.
+    pub fn store_mail(
+        &self,
+        id: &Uuid,
+        from: &PeerId,
+        to: &PeerId,
+        data: &[u8],
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool> {
+        let rows = self.conn.execute(
+            "INSERT OR IGNORE INTO mailbox (id, from_peer, to_peer, data, received_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                id.to_string(),
+                from.to_string(),
+                to.to_string(),
+                data,
+                Utc::now().timestamp(),
+                expires_at.timestamp(),
+            ],
+        )?;
+        Ok(rows > 0)
+    }
+
+    /// How many messages we hold from `from`.
+    pub fn count_mail_from(&self, from: &PeerId) -> Result<usize> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM mailbox WHERE from_peer = ?1",
+            params![from.to_string()],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    /// How many messages we hold for others.
+    pub fn count_mail(&self) -> Result<usize> {
+        let count: i64 = self.conn.query_row("SELECT COUNT(*) FROM mailbox", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Hand over the mail held for `to` as (sender, data), oldest first.
+    /// It's no longer held afterwards; mail that expired before `now` is
+    /// dropped, not returned.
+    pub fn take_mail_for(&self, to: &PeerId, now: DateTime<Utc>) -> Result<Vec<(PeerId, Vec<u8>)>> {
+        let to_str = to.to_string();
+        let mut stmt = self.conn.prepare(
+            "SELECT from_peer, data FROM mailbox WHERE to_peer = ?1 AND expires_at >= ?2 ORDER BY received_at, rowid",
+        )?;
+        let rows = stmt.query_map(params![to_str, now.timestamp()], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?;
+        let mut mail = Vec::new();
+        for row in rows {
+            let (from, data) = row?;
+            if let Ok(from) = from.parse() {
+                mail.push((from, data));
+            }
+        }
+        self.conn.execute("DELETE FROM mailbox WHERE to_peer = ?1", params![to_str])?;
+        Ok(mail)
+    }
+
+    /// Drop held mail that expired before `now`. Returns how many went.
+    pub fn expire_mail(&self, now: DateTime<Utc>) -> Result<usize> {
+        let rows = self
+            .conn
+            .execute("DELETE FROM mailbox WHERE expires_at < ?1", params![now.timestamp()])?;
+        Ok(rows)
+    }
+
+    // === Linked Devices ===
+
+    /// Store an identity's device list, unless we have one issued at the
+    /// same time or later. Returns whether it was stored. Check the list's
+    /// signatures first.
+    pub fn save_device_list(&self, list: &DeviceList) -> Result<bool> {
+        let identity = list.identity.to_string();
+        let issued_at = list.issued_at.timestamp_micros();
+        let newest: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT issued_at FROM device_lists WHERE identity = ?1",
+                params![identity],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if newest.is_some_and(|newest| newest >= issued_at) {
+            return Ok(false);
+        }
+
+        self.transaction(|db| {
+            db.conn.execute(
+                "INSERT OR REPLACE INTO device_lists (identity, list, issued_at) VALUES (?1, ?2, ?3)",
+                params![identity, serde_json::to_vec(list)?, issued_at],
+            )?;
+            db.conn.execute("DELETE FROM devices WHERE identity = ?1", params![identity])?;
+            for device in &list.devices {
+                db.conn.execute(
+                    "INSERT OR REPLACE INTO devices (device, identity, name) VALUES (?1, ?2, ?3)",
+                    params![device.peer_id().to_string(), identity, device.name],
+                )?;
+            }
+            Ok(())
+        })?;
+        Ok(true)
+    }
+
+    /// The newest device list we have for an identity.
+    pub fn device_list(&self, identity: &PeerId) -> Result<Option<DeviceList>> {
+        let list: Option<Vec<u8>> = self
+            .conn
+            .query_row(
+                "SELECT list FROM device_lists WHERE identity = ?1",
+                params![identity.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        list.map(|list| serde_json::from_slice(&list).context("Stored device list is corrupt"))
+            .transpose()
+    }
+
+    /// The devices linked to an identity, besides the identity itself.
+    pub fn linked_devices(&self, identity: &PeerId) -> Result<Vec<PeerId>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT device FROM devices WHERE identity = ?1 ORDER BY rowid")?;
+        let rows = stmt.query_map(params![identity.to_string()], |row| row.get::<_, String>(0))?;
+        let mut devices = Vec::new();
+        for row in rows {
+            if let Ok(device) = row?.parse() {
+                devices.push(device);
+            }
+        }
+        Ok(devices)
+    }
+
+    /// The identity `device` is linked to, if it's on anyone's list.
+    pub fn device_identity(&self, device: &PeerId) -> Result<Option<PeerId>> {
+        let identity: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT identity FROM devices WHERE device = ?1",
+                params![device.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(identity.and_then(|identity| identity.parse().ok()))
+    }
+
+    /// Record, or with `None` forget, the identity this device joined.
+    pub fn set_linked_to(&self, identity: Option<&PeerId>) -> Result<()> {
+        match identity {
+            Some(identity) => self.conn.execute(
+                "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+                params![LINKED_TO, identity.to_string(), Utc::now().timestamp()],
+            )?,
+            None => self.conn.execute("DELETE FROM node_state WHERE key = ?1", params![LINKED_TO])?,
+        };
+        Ok(())
+    }
+
+    /// The identity this device joined as a linked device, if any.
+    pub fn linked_to(&self) -> Result<Option<PeerId>> {
+        let value: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM node_state WHERE key = ?1", params![LINKED_TO], |row| row.get(0))
+            .optional()?;
+        value
+            .map(|v| v.parse().context("Stored linked identity is not a valid peer ID"))
+            .transpose()
+    }
+
+    // === Channels ===
+
+    /// Store a channel we created or subscribed to. Returns false if we
+    /// already have it.
+    pub fn add_channel(&self, channel: &Channel) -> Result<bool> {
+        let rows = self.conn.execute(
+            "INSERT OR IGNORE INTO channels (id, name, owner, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                channel.id.to_string(),
+                channel.name,
+                channel.owner.to_string(),
+                channel.created_at.timestamp_micros(),
+            ],
+        )?;
+        Ok(rows > 0)
+    }
+
+    /// A channel by ID.
+    pub fn get_channel(&self, id: &Uuid) -> Result<Option<Channel>> {
+        let mut channels = self.query_channels("WHERE id = ?1", params![id.to_string()])?;
+        Ok(channels.pop())
+    }
+
+    /// A channel by name, preferring one of ours.
+    pub fn get_channel_by_name(&self, name: &str, our_peer_id: &PeerId) -> Result<Option<Channel>> {
+        let mut channels = self.query_channels("WHERE name = ?1", params![name])?;
+        let first = channels.iter().position(|channel| channel.owner == *our_peer_id).unwrap_or(0);
+        Ok((first < channels.len()).then(|| channels.swap_remove(first)))
+    }
+
+    /// Every channel we own or subscribe to, oldest first.
+    pub fn list_channels(&self) -> Result<Vec<Channel>> {
+        self.query_channels("", [])
+    }
+
+    fn query_channels(&self, filter: &str, params: impl rusqlite::Params) -> Result<Vec<Channel>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, name, owner, created_at FROM channels {} ORDER BY created_at, rowid",
+            filter
+        ))?;
+        let rows = stmt.query_map(params, |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+            ))
+        })?;
+        let mut channels = Vec::new();
+        for row in rows {
+            let (id, name, owner, created_at) = row?;
+            let (Ok(id), Ok(owner)) = (Uuid::parse_str(&id), owner.parse()) else {
+                continue;
+            };
+            let created_at = Utc.timestamp_micros(created_at).single().unwrap_or_else(Utc::now);
+            channels.push(Channel { id, name, owner, created_at });
+        }
+        Ok(channels)
+    }
+
+    /// Add a subscriber to one of our channels. Returns false if they
+    /// already subscribe.
+    pub fn add_channel_subscriber(&self, channel_id: &Uuid, peer_id: &PeerId) -> Result<bool> {
+        let rows = self.conn.execute(
+            "INSERT OR IGNORE INTO channel_subscribers (channel_id, peer_id, subscribed_at) VALUES (?1, ?2, ?3)",
+            params![channel_id.to_string(), peer_id.to_string(), Utc::now().timestamp()],
+        )?;
+        Ok(rows > 0)
+    }
+
+    /// The subscribers to one of our channels.
+    pub fn channel_subscribers(&self, channel_id: &Uuid) -> Result<Vec<PeerId>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT peer_id FROM channel_subscribers WHERE channel_id = ?1 ORDER BY subscribed_at, rowid",
+        )?;
+        let rows = stmt.query_map(params![channel_id.to_string()], |row| row.get::<_, String>(0))?;
+        let mut peers = Vec::new();
+        for row in rows {
+            if let Ok(peer_id) = row?.parse() {
+                peers.push(peer_id);
+            }
+        }
+        Ok(peers)
+    }
+
+    /// Store a channel post. Returns false if we already have it. Check
+    /// its signature first.
+    pub fn store_channel_post(&self, post: &ChannelPost) -> Result<bool> {
+        let rows = self.conn.execute(
+            "INSERT OR IGNORE INTO channel_posts (id, channel_id, text, posted_at, signature)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                post.id.to_string(),
+                post.channel_id.to_string(),
+                post.text,
+                post.posted_at.timestamp_micros(),
+                post.signature,
+            ],
+        )?;
+        Ok(rows > 0)
+    }
+
+    /// The most recent posts to a channel, oldest first.
+    pub fn channel_posts(&self, channel_id: &Uuid, limit: usize) -> Result<Vec<ChannelPost>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, text, posted_at, signature FROM channel_posts
+             WHERE channel_id = ?1 ORDER BY posted_at DESC, rowid DESC LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![channel_id.to_string(), limit as i64], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, Vec<u8>>(3)?,
+            ))
+        })?;
+        let mut posts = Vec::new();
+        for row in rows {
+            let (id, text, posted_at, signature) = row?;
+            let (Ok(id), Some(posted_at)) = (Uuid::parse_str(&id), Utc.timestamp_micros(posted_at).single()) else {
+                continue;
+            };
+            posts.push(ChannelPost { id, channel_id: *channel_id, text, posted_at, signature });
+        }
+        posts.reverse();
+        Ok(posts)
+    }
+
+    // === History Sync ===
+
+    /// When `peer` last answered one of our history requests.
+    pub fn history_synced_at(&self, peer_id: &PeerId) -> Result<Option<DateTime<Utc>>> {
+        let synced_at: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT synced_at FROM history_sync WHERE peer_id = ?1",
+                params![peer_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(synced_at.and_then(|ts| Utc.timestamp_opt(ts, 0).single()))
+    }
+
+    /// Record that `peer` answered a history request at `at`.
+    pub fn set_history_synced_at(&self, peer_id: &PeerId, at: DateTime<Utc>) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO history_sync (peer_id, synced_at) VALUES (?1, ?2)",
+            params![peer_id.to_string(), at.timestamp()],
+        )?;
+        Ok(())
+    }
+
+    // === Ratchet Sessions ===
+
+    /// Save the serialized ratchet session for a peer.
+    pub fn save_session(&self, peer_id: &PeerId, state: &[u8]) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO sessions (peer_id, state, updated_at) VALUES (?1, ?2, ?3)",
+            params![peer_id.to_string(), state, Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// Get the serialized ratchet session for a peer.
+    pub fn get_session(&self, peer_id: &PeerId) -> Result<Option<Vec<u8>>> {
+        let state = self
+            .conn
+            .query_row(
+                "SELECT state FROM sessions WHERE peer_id = ?1",
+                params![peer_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(state)
+    }
+
+    /// Delete the ratchet session for a peer.
+    pub fn delete_session(&self, peer_id: &PeerId) -> Result<bool> {
+        let rows = self.conn.execute(
+            "DELETE FROM sessions WHERE peer_id = ?1",
+            params![peer_id.to_string()],
+        )?;
+        Ok(rows > 0)
+    }
+
+    // === Replay Protection ===
+
+    /// Record an envelope sequence number from `sender`. Returns false if
+    /// the envelope should be rejected as a replay: the number was seen
+    /// before, is too far behind the sender's high-water mark or too far
+    /// ahead of our clock, or is 0 from a sender who has sent numbered
+    /// envelopes before.
+    pub fn check_envelope_seq(&self, sender: &PeerId, seq: u64) -> Result<bool> {
+        let peer = sender.to_string();
+        let high_water: Option<i64> = self.conn.query_row(
+            "SELECT MAX(seq) FROM envelope_seqs WHERE peer_id = ?1",
+            params![peer],
+            |row| row.get(0),
+        )?;
+        let high_water = high_water.map(|hw| hw as u64);
+
+        // Peers that predate sequence numbers send 0
+        if seq == 0 {
+            return Ok(high_water.is_none());
+        }
+        if seq > seq_now().saturating_add(MAX_SEQ_SKEW_MICROS) {
+            return Ok(false);
+        }
+        if high_water.is_some_and(|hw| seq.saturating_add(REPLAY_WINDOW_MICROS) < hw) {
+            return Ok(false);
+        }
+
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO envelope_seqs (peer_id, seq) VALUES (?1, ?2)",
+            params![peer, seq as i64],
+        )?;
+        if inserted == 0 {
+            return Ok(false);
+        }
+
+        // Forget numbers that have fallen out of the window
+        let newest = high_water.map_or(seq, |hw| hw.max(seq));
+        self.conn.execute(
+            "DELETE FROM envelope_seqs WHERE peer_id = ?1 AND seq < ?2",
+            params![peer, newest.saturating_sub(REPLAY_WINDOW_MICROS) as i64],
+        )?;
+        Ok(true)
+    }
+
+    /// Record whether a peer can decode packed envelopes.
+    pub fn set_accepts_compression(&self, peer_id: &PeerId, accepts: bool) -> Result<()> {
+        if accepts {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO compression_peers (peer_id) VALUES (?1)",
+                params![peer_id.to_string()],
+            )?;
+        } else {
+            self.conn
+                .execute("DELETE FROM compression_peers WHERE peer_id = ?1", params![peer_id.to_string()])?;
+        }
+        Ok(())
+    }
+
+    /// Whether a peer has said it can decode packed envelopes.
+    pub fn accepts_compression(&self, peer_id: &PeerId) -> Result<bool> {
+        let found = self
+            .conn
+            .query_row(
+                "SELECT 1 FROM compression_peers WHERE peer_id = ?1",
+                params![peer_id.to_string()],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    // === Retention ===
+
+    /// Set a conversation's retention policy, or with `None` the default
+    /// for conversations without their own.
+    pub fn set_retention_policy(&self, with: Option<&Recipient>, policy: &RetentionPolicy) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO retention_policies (conversation, max_age_days, max_messages)
+             VALUES (?1, ?2, ?3)
+             ON CONFLICT(conversation) DO UPDATE SET
+                 max_age_days = excluded.max_age_days,
+                 max_messages = excluded.max_messages",
+            params![retention_key(with), policy.max_age_days, policy.max_messages],
+        )?;
+        Ok(())
+    }
+
+    /// A conversation's own retention policy, or with `None` the default.
+    pub fn retention_policy(&self, with: Option<&Recipient>) -> Result<Option<RetentionPolicy>> {
+        let policy = self
+            .conn
+            .query_row(
+                "SELECT max_age_days, max_messages FROM retention_policies WHERE conversation = ?1",
+                params![retention_key(with)],
+                |row| {
+                    Ok(RetentionPolicy {
+                        max_age_days: row.get(0)?,
+                        max_messages: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(policy)
+    }
+
+    /// Remove a conversation's own retention policy, or with `None` the
+    /// default. Returns true if there was one.
+    pub fn clear_retention_policy(&self, with: Option<&Recipient>) -> Result<bool> {
+        let removed = self.conn.execute(
+            "DELETE FROM retention_policies WHERE conversation = ?1",
+            params![retention_key(with)],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// The policy that applies to a conversation: its own, else the
+    /// default, else keep everything.
+    pub fn effective_retention_policy(&self, with: &Recipient) -> Result<RetentionPolicy> {
+        match self.retention_policy(Some(with))? {
+            Some(policy) => Ok(policy),
+            None => Ok(self.retention_policy(None)?.unwrap_or_default()),
+        }
+    }
+
+    /// Save how a conversation notifies the user.
+    pub fn set_notification_settings(&self, with: &Recipient, settings: &NotificationSettings) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO notification_settings (conversation, level, muted, muted_until, alert)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                recipient_key(with),
+                settings.level.as_str(),
+                settings.muted,
+                settings.muted_until.map(|until| until.timestamp()),
+                settings.alert.as_str(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// How a conversation notifies the user: every message, quietly,
+    /// unless set.
+    pub fn notification_settings(&self, with: &Recipient) -> Result<NotificationSettings> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT level, muted, muted_until, alert FROM notification_settings WHERE conversation = ?1",
+                params![recipient_key(with)],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, bool>(1)?,
+                        row.get::<_, Option<i64>>(2)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let Some((level, muted, muted_until, alert)) = row else {
+            return Ok(NotificationSettings::default());
+        };
+        Ok(NotificationSettings {
+            level: NotificationLevel::parse(&level).context("Stored notification level is not valid")?,
+            muted,
+            muted_until: muted_until.and_then(|secs| Utc.timestamp_opt(secs, 0).single()),
+            alert: Alert::parse(&alert).context("Stored alert is not valid")?,
+        })
+    }
+
+    /// Delete the messages each conversation's retention policy no longer
+    /// keeps, as of `now`. With `dry_run` nothing is deleted. Returns the
+    /// conversations that lost messages.
+    pub fn prune_messages(&self, now: DateTime<Utc>, dry_run: bool) -> Result<Vec<PrunedConversation>> {
+        let mut pruned = Vec::new();
+        for conversation in self.list_conversations()? {
+            let policy = self.effective_retention_policy(&conversation.with)?;
+            if policy.keeps_everything() {
+                continue;
+            }
+            let ids = self.messages_outside_policy(&conversation.with, &policy, now)?;
+            if ids.is_empty() {
+                continue;
+            }
+            if !dry_run {
+                self.delete_messages(&ids)?;
+            }
+            pruned.push(PrunedConversation {
+                with: conversation.with,
+                name: conversation.name,
+                messages: ids.len(),
+            });
+        }
+        Ok(pruned)
+    }
+
+    /// IDs of a conversation's messages that `policy` doesn't keep.
+    fn messages_outside_policy(
+        &self,
+        with: &Recipient,
+        policy: &RetentionPolicy,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<String>> {
+        let filter = conversation_filter(with);
+        let key = recipient_key(with);
+        let mut ids = BTreeSet::new();
+
+        if let Some(days) = policy.max_age_days {
+            let cutoff = (now - chrono::Duration::days(i64::from(days))).timestamp();
+            let mut stmt = self
+                .conn
+                .prepare(&format!("SELECT id FROM messages WHERE {} AND timestamp < ?2", filter))?;
+            for id in stmt.query_map(params![key, cutoff], |row| row.get::<_, String>(0))? {
+                ids.insert(id?);
+            }
+        }
+        if let Some(max) = policy.max_messages {
+            let mut stmt = self.conn.prepare(&format!(
+                "SELECT id FROM messages WHERE {}
+                 ORDER BY timestamp DESC, rowid DESC LIMIT -1 OFFSET ?2",
+                filter
+            ))?;
+            for id in stmt.query_map(params![key, max], |row| row.get::<_, String>(0))? {
+                ids.insert(id?);
+            }
+        }
+        Ok(ids.into_iter().collect())
+    }
+
+    /// Delete messages and what's recorded about them, all or nothing,
+    /// remembering their IDs. Returns how many messages there were.
+    fn delete_messages(&self, ids: &[String]) -> Result<usize> {
+        let now = Utc::now().timestamp();
+        self.transaction(|db| {
+            let mut deleted = 0;
+            for id in ids {
+                let removed = db.conn.execute("DELETE FROM messages WHERE id = ?1", params![id])?;
+                if removed > 0 {
+                    db.conn.execute(
+                        "INSERT OR IGNORE INTO deleted_messages (message_id, deleted_at) VALUES (?1, ?2)",
+                        params![id, now],
+                    )?;
+                }
+                deleted += removed;
+                db.conn.execute("DELETE FROM unverified_messages WHERE message_id = ?1", params![id])?;
+                db.conn.execute("DELETE FROM group_message_epochs WHERE message_id = ?1", params![id])?;
+            }
+            Ok(deleted)
+        })
+    }
+
+    // === Node State ===
+
+    /// Remember the reachability AutoNAT last reported.
+    pub fn save_reachability(&self, reachability: Reachability) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES ('reachability', ?1, ?2)",
+            params![reachability.as_str(), Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// Get the last reported reachability and when it was observed.
+    pub fn last_reachability(&self) -> Result<Option<(Reachability, DateTime<Utc>)>> {
+        let row: Option<(String, i64)> = self
+            .conn
+            .query_row(
+                "SELECT value, updated_at FROM node_state WHERE key = 'reachability'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(row.and_then(|(value, ts)| {
+            let reachability = Reachability::parse(&value)?;
+            let observed_at = Utc.timestamp_opt(ts, 0).single()?;
+            Some((reachability, observed_at))
+        }))
+    }
+
+    /// Set the presence we announce to contacts.
+    pub fn set_own_presence(&self, status: PresenceStatus) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+            params![OWN_PRESENCE, status.as_str(), Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// The presence we announce to contacts; online unless set.
+    pub fn own_presence(&self) -> Result<PresenceStatus> {
+        let value: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM node_state WHERE key = ?1", params![OWN_PRESENCE], |row| row.get(0))
+            .optional()?;
+        Ok(value.as_deref().and_then(PresenceStatus::parse).unwrap_or(PresenceStatus::Online))
+    }
+
+    /// Set or, with `None`, clear the username we claim.
+    pub fn set_username(&self, username: Option<&str>) -> Result<()> {
+        match username {
+            Some(username) => self.conn.execute(
+                "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+                params![USERNAME, username, Utc::now().timestamp()],
+            )?,
+            None => self.conn.execute("DELETE FROM node_state WHERE key = ?1", params![USERNAME])?,
+        };
+        Ok(())
+    }
+
+    /// The username we claim, if any.
+    pub fn username(&self) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row("SELECT value FROM node_state WHERE key = ?1", params![USERNAME], |row| row.get(0))
+            .optional()?)
+    }
+
+    /// Set or, with `None`, clear the rendezvous point to use.
+    pub fn set_rendezvous_point(&self, addr: Option<&Multiaddr>) -> Result<()> {
+        match addr {
+            Some(addr) => self.conn.execute(
+                "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+                params![RENDEZVOUS_POINT, addr.to_string(), Utc::now().timestamp()],
+            )?,
+            None => self.conn.execute("DELETE FROM node_state WHERE key = ?1", params![RENDEZVOUS_POINT])?,
+        };
+        Ok(())
+    }
+
+    /// The rendezvous point to use, if one is set.
+    pub fn rendezvous_point(&self) -> Result<Option<Multiaddr>> {
+        let value: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT value FROM node_state WHERE key = ?1",
+                params![RENDEZVOUS_POINT],
+                |row| row.get(0),
+            )
+            .optional()?;
+        value
+            .map(|v| v.parse().context("Stored rendezvous point is not a valid address"))
+            .transpose()
+    }
+
+    /// Set or, with `None`, clear the SOCKS5 proxy to dial through.
+    pub fn set_socks_proxy(&self, proxy: Option<SocketAddr>) -> Result<()> {
+        match proxy {
+            Some(proxy) => self.conn.execute(
+                "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+                params![SOCKS_PROXY, proxy.to_string(), Utc::now().timestamp()],
+            )?,
+            None => self.conn.execute("DELETE FROM node_state WHERE key = ?1", params![SOCKS_PROXY])?,
+        };
+        Ok(())
+    }
+
+    /// The SOCKS5 proxy to dial through, if one is set.
+    pub fn socks_proxy(&self) -> Result<Option<SocketAddr>> {
+        let value: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM node_state WHERE key = ?1", params![SOCKS_PROXY], |row| row.get(0))
+            .optional()?;
+        value
+            .map(|v| v.parse().context("Stored SOCKS proxy is not a valid address"))
+            .transpose()
+    }
+
+    /// Set or, with `None`, clear the port to accept WebSocket connections on.
+    pub fn set_websocket_port(&self, port: Option<u16>) -> Result<()> {
+        match port {
+            Some(port) => self.conn.execute(
+                "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+                params![WEBSOCKET_PORT, port.to_string(), Utc::now().timestamp()],
+            )?,
+            None => self.conn.execute("DELETE FROM node_state WHERE key = ?1", params![WEBSOCKET_PORT])?,
+        };
+        Ok(())
+    }
+
+    /// The port to accept WebSocket connections on, if one is set.
+    pub fn websocket_port(&self) -> Result<Option<u16>> {
+        let value: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM node_state WHERE key = ?1", params![WEBSOCKET_PORT], |row| row.get(0))
+            .optional()?;
+        value
+            .map(|v| v.parse().context("Stored WebSocket port is not a valid port"))
+            .transpose()
+    }
+
+    /// Set or, with `None`, clear the TCP port to listen on.
+    pub fn set_listen_port(&self, port: Option<u16>) -> Result<()> {
+        match port {
+            Some(port) => self.conn.execute(
+                "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+                params![LISTEN_PORT, port.to_string(), Utc::now().timestamp()],
+            )?,
+            None => self.conn.execute("DELETE FROM node_state WHERE key = ?1", params![LISTEN_PORT])?,
+        };
+        Ok(())
+    }
+
+    /// The TCP port to listen on, if one has been chosen.
+    pub fn listen_port(&self) -> Result<Option<u16>> {
+        let value: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM node_state WHERE key = ?1", params![LISTEN_PORT], |row| row.get(0))
+            .optional()?;
+        value
+            .map(|v| v.parse().context("Stored listen port is not a valid port"))
+            .transpose()
+    }
+
+    /// Set or, with `None`, clear the webhook for incoming messages.
+    pub fn set_webhook(&self, webhook: Option<&Webhook>) -> Result<()> {
+        match webhook {
+            Some(webhook) => {
+                // The URL, then one conversation per line
+                let value = std::iter::once(webhook.url.clone())
+                    .chain(webhook.only.iter().map(recipient_key))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                self.conn.execute(
+                    "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+                    params![WEBHOOK, value, Utc::now().timestamp()],
+                )?
+            }
+            None => self.conn.execute("DELETE FROM node_state WHERE key = ?1", params![WEBHOOK])?,
+        };
+        Ok(())
+    }
+
+    /// The webhook for incoming messages, if one is set.
+    pub fn webhook(&self) -> Result<Option<Webhook>> {
+        let value: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM node_state WHERE key = ?1", params![WEBHOOK], |row| row.get(0))
+            .optional()?;
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        let mut lines = value.lines();
+        let url = lines.next().unwrap_or_default().to_string();
+        let only = lines
+            .map(|key| match Uuid::parse_str(key) {
+                Ok(id) => Ok(Recipient::Group(id)),
+                Err(_) => key.parse().map(Recipient::Direct).context("Stored webhook filter is not valid"),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Some(Webhook { url, only }))
+    }
+
+    /// Where a bridged Matrix room was last synced to, if it has been.
+    pub fn matrix_sync_token(&self, room: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT value FROM node_state WHERE key = ?1",
+                params![format!("{}{}", MATRIX_SYNC_TOKEN, room)],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Remember where a bridged Matrix room was synced to.
+    pub fn set_matrix_sync_token(&self, room: &str, token: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO node_state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+            params![format!("{}{}", MATRIX_SYNC_TOKEN, room), token, Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    // === Peer Addresses ===
+
+    /// Remember that a peer was seen at `address`, and with `reached` that
+    /// we connected to them through it. Only the best
+    /// `MAX_ADDRESSES_PER_PEER` addresses are kept per peer.
+    pub fn record_peer_address(&self, peer_id: &PeerId, address: &Multiaddr, reached: bool) -> Result<()> {
+        let peer_str = peer_id.to_string();
+        let now = Utc::now().timestamp();
+        self.transaction(|db| {
+            db.conn.execute(
+                "INSERT INTO peer_addresses (peer_id, address, last_seen, last_success) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (peer_id, address) DO UPDATE SET
+                     last_seen = excluded.last_seen,
+                     last_success = COALESCE(excluded.last_success, last_success)",
+                params![peer_str, address.to_string(), now, reached.then_some(now)],
+            )?;
+            db.conn.execute(
+                &format!(
+                    "DELETE FROM peer_addresses WHERE peer_id = ?1 AND address NOT IN (
+                         SELECT address FROM peer_addresses WHERE peer_id = ?1 ORDER BY {} LIMIT ?2
+                     )",
+                    PEER_ADDRESS_ORDER
+                ),
+                params![peer_str, MAX_ADDRESSES_PER_PEER as i64],
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Addresses a peer has been seen at, best first.
+    pub fn peer_addresses(&self, peer_id: &PeerId) -> Result<Vec<PeerAddress>> {
+        self.query_peer_addresses(
+            &format!(
+                "SELECT peer_id, address, last_seen, last_success FROM peer_addresses
+                 WHERE peer_id = ?1 ORDER BY {}",
+                PEER_ADDRESS_ORDER
+            ),
+            params![peer_id.to_string()],
+        )
+    }
+
+    /// Every remembered address (for loading into the node on startup),
+    /// grouped by peer and best first.
+    pub fn all_peer_addresses(&self) -> Result<Vec<PeerAddress>> {
+        self.query_peer_addresses(
+            &format!(
+                "SELECT peer_id, address, last_seen, last_success FROM peer_addresses
+                 ORDER BY peer_id, {}",
+                PEER_ADDRESS_ORDER
+            ),
+            [],
+        )
+    }
+
+    fn query_peer_addresses(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<PeerAddress>> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            let peer_str: String = row.get(0)?;
+            let address: String = row.get(1)?;
+            let last_seen: i64 = row.get(2)?;
+            let last_success: Option<i64> = row.get(3)?;
+            Ok((peer_str, address, last_seen, last_success))
+        })?;
+
+        let mut addresses = Vec::new();
+        for row in rows {
+            let (peer_str, address, last_seen, last_success) = row?;
+            let (Ok(peer_id), Ok(address)) = (peer_str.parse(), address.parse()) else {
+                continue;
+            };
+            let Some(last_seen) = Utc.timestamp_opt(last_seen, 0).single() else {
+                continue;
+            };
+            addresses.push(PeerAddress {
+                peer_id,
+                address,
+                last_seen,
+                last_success: last_success.and_then(|ts| Utc.timestamp_opt(ts, 0).single()),
+            });
+        }
+        Ok(addresses)
+    }
+
+    // === Latency ===
+
+    /// Remember the latest round trip measured to a peer.
+    pub fn record_latency(&self, peer_id: &PeerId, latency: Latency) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO peer_latency (peer_id, rtt_ms, relayed, measured_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                peer_id.to_string(),
+                latency.rtt.as_millis() as i64,
+                latency.relayed,
+                Utc::now().timestamp()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The last round trip measured to a peer, if any.
+    pub fn peer_latency(&self, peer_id: &PeerId) -> Result<Option<PeerLatency>> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT rtt_ms, relayed, measured_at FROM peer_latency WHERE peer_id = ?1",
+                params![peer_id.to_string()],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, bool>(1)?, row.get::<_, i64>(2)?)),
+            )
+            .optional()?;
+        Ok(row.and_then(|(rtt_ms, relayed, measured_at)| {
+            Some(PeerLatency {
+                latency: Latency {
+                    rtt: std::time::Duration::from_millis(rtt_ms.max(0) as u64),
+                    relayed,
+                },
+                measured_at: Utc.timestamp_opt(measured_at, 0).single()?,
+            })
+        }))
+    }
+
+    // === Versions ===
+
+    /// Remember the versions a peer identified itself with.
+    pub fn record_peer_version(&self, peer_id: &PeerId, protocol_version: &str, agent_version: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO peer_versions (peer_id, protocol_version, agent_version, seen_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![peer_id.to_string(), protocol_version, agent_version, Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// The versions a peer last identified itself with, if it has.
+    pub fn peer_version(&self, peer_id: &PeerId) -> Result<Option<PeerVersion>> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT protocol_version, agent_version, seen_at FROM peer_versions WHERE peer_id = ?1",
+                params![peer_id.to_string()],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?)),
+            )
+            .optional()?;
+        Ok(row.and_then(|(protocol_version, agent_version, seen_at)| {
+            Some(PeerVersion {
+                protocol_version,
+                agent_version,
+                seen_at: Utc.timestamp_opt(seen_at, 0).single()?,
+            })
+        }))
+    }
+
+    // === Traffic ===
+
+    /// Add a node's traffic report to the running totals.
+    pub fn record_traffic(&self, traffic: &NetworkStats) -> Result<()> {
+        self.transaction(|db| {
+            for (peer_id, protocol, bytes) in traffic.entries() {
+                db.conn.execute(
+                    "INSERT INTO traffic (peer_id, protocol, bytes_sent, bytes_received) VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (peer_id, protocol) DO UPDATE SET
+                         bytes_sent = bytes_sent + excluded.bytes_sent,
+                         bytes_received = bytes_received + excluded.bytes_received",
+                    params![
+                        peer_id.to_string(),
+                        protocol.as_str(),
+                        bytes.sent.min(i64::MAX as u64) as i64,
+                        bytes.received.min(i64::MAX as u64) as i64
+                    ],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Traffic with every peer across all sessions.
+    pub fn traffic(&self) -> Result<NetworkStats> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT peer_id, protocol, bytes_sent, bytes_received FROM traffic")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?,
+            ))
+        })?;
+
+        let mut stats = NetworkStats::default();
         for row in rows {
-            let (id_str, data) = row?;
-            if let Ok(id) = Uuid::parse_str(&id_str) {
-                pending.push((id, data));
-            }
+            let (peer_str, protocol, sent, received) = row?;
+            let (Ok(peer_id), Some(protocol)) = (peer_str.parse(), TrafficProtocol::parse(&protocol)) else {
+                continue;
+            };
+            stats.record_sent(peer_id, protocol, sent.max(0) as usize);
+            stats.record_received(peer_id, protocol, received.max(0) as usize);
         }
-
-        Ok(pending)
+        Ok(stats)
     }
 
-    /// Remove a pending message after successful delivery.
-    pub fn remove_pending_message(&self, id: &Uuid) -> Result<bool> {
-        let rows = self.conn.execute(
-            "DELETE FROM pending_messages WHERE id = ?1",
-            params![id.to_string()],
-        )?;
-        Ok(rows > 0)
+    // === Audit log ===
+
+    /// Append an event to the audit log.
+    pub fn record_audit(&self, kind: AuditKind, peer_id: Option<&PeerId>, detail: &str) -> Result<()> {
+        self.transaction(|db| {
+            let previous: Option<Vec<u8>> = db
+                .conn
+                .query_row("SELECT hash FROM audit_log ORDER BY id DESC LIMIT 1", [], |row| row.get(0))
+                .optional()?;
+            let previous = match previous {
+                Some(bytes) => bytes.try_into().map_err(|_| anyhow::anyhow!("Corrupt audit log hash"))?,
+                None => AUDIT_GENESIS,
+            };
+            let at = Utc::now().timestamp();
+            let peer = peer_id.map(|p| p.to_string());
+            let hash = audit_hash(&previous, at, kind, peer.as_deref(), detail);
+            db.conn.execute(
+                "INSERT INTO audit_log (at, kind, peer_id, detail, hash) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![at, kind.as_str(), peer, detail, hash.as_slice()],
+            )?;
+            Ok(())
+        })
     }
 
-    /// Get all pending messages (for loading queue on startup).
-    pub fn get_all_pending(&self) -> Result<Vec<(Uuid, PeerId, Vec<u8>)>> {
+    /// The latest `limit` audit events, oldest first, optionally only of
+    /// one kind or from `since` on.
+    pub fn audit_log(
+        &self,
+        kind: Option<AuditKind>,
+        since: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, to_peer, encrypted_data FROM pending_messages ORDER BY created_at",
+            "SELECT id, at, kind, peer_id, detail FROM audit_log
+             WHERE (?1 IS NULL OR kind = ?1) AND (?2 IS NULL OR at >= ?2)
+             ORDER BY id DESC LIMIT ?3",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                kind.map(|k| k.as_str()),
+                since.map(|s| s.timestamp()),
+                limit.min(i64::MAX as usize) as i64
+            ],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, String>(4)?,
+                ))
+            },
         )?;
 
-        let rows = stmt.query_map([], |row| {
-            let id_str: String = row.get(0)?;
-            let peer_str: String = row.get(1)?;
-            let data: Vec<u8> = row.get(2)?;
-            Ok((id_str, peer_str, data))
-        })?;
-
-        let mut pending = Vec::new();
+        let mut events = Vec::new();
         for row in rows {
-            let (id_str, peer_str, data) = row?;
-            if let (Ok(id), Ok(peer_id)) = (Uuid::parse_str(&id_str), peer_str.parse()) {
-                pending.push((id, peer_id, data));
+            let (id, at, kind, peer, detail) = row?;
+            let (Some(kind), Some(at)) = (AuditKind::parse(&kind), Utc.timestamp_opt(at, 0).single()) else {
+                continue;
+            };
+            events.push(AuditEvent {
+                id,
+                at,
+                kind,
+                peer_id: peer.and_then(|p| p.parse().ok()),
+                detail,
+            });
+        }
+        events.reverse();
+        Ok(events)
+    }
+
+    /// Check the audit log's hash chain. Returns the ID of the first entry
+    /// that doesn't match what came before it, if any.
+    pub fn verify_audit_log(&self) -> Result<Option<i64>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, at, kind, peer_id, detail, hash FROM audit_log ORDER BY id")?;
+        let mut rows = stmt.query([])?;
+        let mut previous = AUDIT_GENESIS;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            let kind = AuditKind::parse(&row.get::<_, String>(2)?);
+            let peer: Option<String> = row.get(3)?;
+            let detail: String = row.get(4)?;
+            let stored: Vec<u8> = row.get(5)?;
+            let Some(kind) = kind else {
+                return Ok(Some(id));
+            };
+            let expected = audit_hash(&previous, row.get(1)?, kind, peer.as_deref(), &detail);
+            if stored != expected {
+                return Ok(Some(id));
             }
+            previous = expected;
         }
+        Ok(None)
+    }
 
-        Ok(pending)
+    // === Prekeys ===
+
+    /// Save one of our signed prekeys.
+    pub fn save_prekey(&self, prekey: &SignedPrekey) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO prekeys (id, public_key, secret_key, signature, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                prekey.id,
+                prekey.public_key.as_slice(),
+                prekey.secret_key.as_slice(),
+                prekey.signature,
+                Utc::now().timestamp(),
+            ],
+        )?;
+        Ok(())
     }
 
-    /// Increment attempt count for a pending message.
-    pub fn increment_pending_attempts(&self, id: &Uuid) -> Result<()> {
+    /// Get one of our signed prekeys by ID.
+    pub fn get_prekey(&self, id: u32) -> Result<Option<SignedPrekey>> {
+        let prekey = self
+            .conn
+            .query_row(
+                "SELECT id, public_key, secret_key, signature FROM prekeys WHERE id = ?1",
+                params![id],
+                Self::row_to_prekey,
+            )
+            .optional()?;
+        Ok(prekey)
+    }
+
+    /// Get our most recent signed prekey.
+    pub fn latest_prekey(&self) -> Result<Option<SignedPrekey>> {
+        let prekey = self
+            .conn
+            .query_row(
+                "SELECT id, public_key, secret_key, signature FROM prekeys ORDER BY id DESC LIMIT 1",
+                [],
+                Self::row_to_prekey,
+            )
+            .optional()?;
+        Ok(prekey)
+    }
+
+    fn row_to_prekey(row: &rusqlite::Row) -> rusqlite::Result<SignedPrekey> {
+        let public_key: Vec<u8> = row.get(1)?;
+        let secret_key: Vec<u8> = row.get(2)?;
+        Ok(SignedPrekey {
+            id: row.get(0)?,
+            public_key: public_key.try_into().unwrap_or([0u8; 32]),
+            secret_key: secret_key.try_into().unwrap_or([0u8; 32]),
+            signature: row.get(3)?,
+        })
+    }
+
+    /// Save a contact's published prekey, replacing any previous one.
+    pub fn save_contact_prekey(&self, peer_id: &PeerId, prekey: &PublicPrekey) -> Result<()> {
         self.conn.execute(
-            "UPDATE pending_messages SET attempts = attempts + 1 WHERE id = ?1",
-            params![id.to_string()],
+            "INSERT OR REPLACE INTO contact_prekeys (peer_id, prekey_id, public_key, signature)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                peer_id.to_string(),
+                prekey.id,
+                prekey.public_key.as_slice(),
+                prekey.signature,
+            ],
         )?;
         Ok(())
     }
 
+    /// Get a contact's published prekey.
+    pub fn get_contact_prekey(&self, peer_id: &PeerId) -> Result<Option<PublicPrekey>> {
+        let prekey = self
+            .conn
+            .query_row(
+                "SELECT prekey_id, public_key, signature FROM contact_prekeys WHERE peer_id = ?1",
+                params![peer_id.to_string()],
+                |row| {
+                    let public_key: Vec<u8> = row.get(1)?;
+                    Ok(PublicPrekey {
+                        id: row.get(0)?,
+                        public_key: public_key.try_into().unwrap_or([0u8; 32]),
+                        signature: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(prekey)
+    }
+
     // === File Transfer Operations ===
 
     /// Insert a new file transfer.
@@ -853,18 +3890,29 @@ impl Database {
         Ok(chunks)
     }
 
-    /// Delete a file transfer and its chunks.
+    /// Delete a file transfer, its chunks and its reference to its blob.
     pub fn delete_file_transfer(&self, id: &Uuid) -> Result<bool> {
-        // Foreign key cascade will delete chunks
+        let blob = self.file_blob(id)?;
         let rows = self.conn.execute(
             "DELETE FROM file_transfers WHERE id = ?1",
             params![id.to_string()],
         )?;
+        self.conn.execute("DELETE FROM file_chunks WHERE transfer_id = ?1", params![id.to_string()])?;
+        if let Some(hash) = blob {
+            self.conn.execute("DELETE FROM file_blobs WHERE transfer_id = ?1", params![id.to_string()])?;
+            self.release_blob(&hash)?;
+        }
         Ok(rows > 0)
     }
 
-    /// Reassemble file from chunks.
+    /// A file's contents: its blob once it has been stored as one,
+    /// otherwise its chunks joined in order.
     pub fn reassemble_file(&self, transfer_id: &Uuid) -> Result<Vec<u8>> {
+        if let Some(hash) = self.file_blob(transfer_id)? {
+            return self
+                .get_blob(&hash)?
+                .with_context(|| format!("Blob {} for transfer {} is missing", hash, transfer_id));
+        }
         let chunks = self.get_file_chunks(transfer_id)?;
         let mut data = Vec::new();
         for chunk in chunks {
@@ -873,6 +3921,134 @@ impl Database {
         Ok(data)
     }
 
+    /// Store a large payload as a blob and return its hash. Storing content
+    /// that's already there adds a reference instead of a second copy;
+    /// each call should be matched by a `release_blob`.
+    pub fn put_blob(&self, data: &[u8]) -> Result<String> {
+        let dir = self.blob_dir()?;
+        let keys = self.blob_keys()?;
+        let hash = keys.hash(data);
+
+        let added = self.conn.execute(
+            "UPDATE blobs SET ref_count = ref_count + 1 WHERE hash = ?1",
+            params![hash],
+        )?;
+        // Rewrite the file even for known content, in case it went missing
+        if added == 0 || !blobs::blob_path(dir, &hash).exists() {
+            blobs::write_blob(dir, &hash, &keys.seal(data)?)?;
+        }
+        if added == 0 {
+            self.conn.execute(
+                "INSERT INTO blobs (hash, size, ref_count, created_at) VALUES (?1, ?2, 1, ?3)",
+                params![hash, data.len() as i64, Utc::now().timestamp()],
+            )?;
+        }
+        Ok(hash)
+    }
+
+    /// A blob's contents, or `None` if no blob has that hash. Fails if the
+    /// file is missing or doesn't match its hash.
+    pub fn get_blob(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        blobs::validate_hash(hash)?;
+        let known: bool = self.conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM blobs WHERE hash = ?1)",
+            params![hash],
+            |row| row.get(0),
+        )?;
+        if !known {
+            return Ok(None);
+        }
+        let sealed = blobs::read_blob(self.blob_dir()?, hash)?;
+        self.blob_keys()?.open(hash, &sealed).map(Some)
+    }
+
+    /// Drop one reference to a blob, deleting it when none are left.
+    /// Returns whether the blob was deleted.
+    pub fn release_blob(&self, hash: &str) -> Result<bool> {
+        blobs::validate_hash(hash)?;
+        self.conn.execute(
+            "UPDATE blobs SET ref_count = ref_count - 1 WHERE hash = ?1",
+            params![hash],
+        )?;
+        let removed = self.conn.execute(
+            "DELETE FROM blobs WHERE hash = ?1 AND ref_count <= 0",
+            params![hash],
+        )?;
+        if removed == 0 {
+            return Ok(false);
+        }
+        if let Some(dir) = &self.blob_dir {
+            blobs::remove_blob(dir, hash)?;
+        }
+        Ok(true)
+    }
+
+    /// Move a completed transfer's contents out of its chunks and into a
+    /// blob. Returns the blob's hash.
+    pub fn store_file_blob(&self, transfer_id: &Uuid, data: &[u8]) -> Result<String> {
+        if let Some(hash) = self.file_blob(transfer_id)? {
+            return Ok(hash);
+        }
+        let hash = self.put_blob(data)?;
+        self.transaction(|db| {
+            db.conn.execute(
+                "INSERT INTO file_blobs (transfer_id, hash) VALUES (?1, ?2)",
+                params![transfer_id.to_string(), hash],
+            )?;
+            db.conn.execute(
+                "DELETE FROM file_chunks WHERE transfer_id = ?1",
+                params![transfer_id.to_string()],
+            )?;
+            Ok(())
+        })?;
+        Ok(hash)
+    }
+
+    /// The hash of the blob holding a transfer's contents, if it has one.
+    pub fn file_blob(&self, transfer_id: &Uuid) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT hash FROM file_blobs WHERE transfer_id = ?1",
+                params![transfer_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn blob_dir(&self) -> Result<&Path> {
+        self.blob_dir
+            .as_deref()
+            .context("Blob storage is not available for in-memory databases")
+    }
+
+    /// The blob keys, generating the master key on first use.
+    fn blob_keys(&self) -> Result<BlobKeys> {
+        let stored: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT value FROM node_state WHERE key = ?1",
+                params![BLOB_KEY],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let master = match stored {
+            Some(hex_key) => hex::decode(&hex_key)
+                .ok()
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                .context("Stored blob key is corrupt")?,
+            None => {
+                let master = BlobKeys::generate_master();
+                self.conn.execute(
+                    "INSERT INTO node_state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+                    params![BLOB_KEY, hex::encode(master), Utc::now().timestamp()],
+                )?;
+                master
+            }
+        };
+        Ok(BlobKeys::derive(&master))
+    }
+
     fn row_to_file_transfer(&self, row: FileTransferRow) -> Result<FileTransfer> {
         use crate::message::{FileTransfer, FileTransferStatus};
         
@@ -913,6 +4089,52 @@ impl Database {
     }
 }
 
+/// Quote each word of a user query as an FTS5 string, so punctuation
+/// and keywords like `OR` match literally. `None` for a blank query.
+fn fts_query(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect();
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+/// Each part of a trust policy, with the name it's stored under.
+fn trust_policy_parts(policy: &mut TrustPolicy) -> [(&'static str, &mut TrustRequirement); 3] {
+    [
+        ("read_receipts", &mut policy.read_receipts),
+        ("conversations", &mut policy.conversations),
+        ("group_invites", &mut policy.group_invites),
+    ]
+}
+
+/// How a recipient is stored in `messages.to_peer`.
+fn recipient_key(recipient: &Recipient) -> String {
+    match recipient {
+        Recipient::Direct(peer) => peer.to_string(),
+        Recipient::Group(id) => id.to_string(),
+    }
+}
+
+/// SQL matching the messages in a conversation, with its key as `?1`.
+/// Direct chats leave out the contact's group messages, which are
+/// addressed to a group ID.
+fn conversation_filter(with: &Recipient) -> &'static str {
+    match with {
+        Recipient::Direct(_) => "(from_peer = ?1 OR to_peer = ?1) AND instr(to_peer, '-') = 0",
+        Recipient::Group(_) => "to_peer = ?1",
+    }
+}
+
+/// Key of a conversation's row in `retention_policies`; `None` is the default.
+fn retention_key(with: Option<&Recipient>) -> String {
+    with.map_or_else(|| DEFAULT_RETENTION.to_string(), recipient_key)
+}
+
 struct MessageRow {
     id: String,
     from_peer: String,
@@ -950,6 +4172,143 @@ mod tests {
         let db = Database::open_in_memory().unwrap();
         // Should not panic - tables exist
         db.list_contacts().unwrap();
+        assert_eq!(db.schema_version().unwrap(), crate::storage::schema::latest_version());
+    }
+
+    #[test]
+    fn batches_of_messages_skip_ones_already_stored() {
+        let db = Database::open_in_memory().unwrap();
+        let (alice, bob) = (make_peer_id(), make_peer_id());
+        let messages: Vec<Message> = (0..1000)
+            .map(|i| Message::new_text(alice, Recipient::Direct(bob), format!("message {}", i)))
+            .collect();
+        db.insert_message(&messages[0]).unwrap();
+
+        assert_eq!(db.insert_messages_batch(&messages).unwrap(), 999);
+        assert_eq!(db.get_conversation(&alice, None).unwrap().len(), 1000);
+        assert_eq!(db.insert_messages_batch(&messages[..10]).unwrap(), 0);
+        assert_eq!(db.insert_messages_batch(&[]).unwrap(), 0);
+    }
+
+    #[test]
+    fn transactions_roll_back_on_error_and_nest() {
+        let db = Database::open_in_memory().unwrap();
+        let (alice, bob) = (make_peer_id(), make_peer_id());
+
+        let failed: Result<()> = db.transaction(|db| {
+            db.upsert_contact(&Contact::new(alice, "alice".to_string(), vec![1]))?;
+            anyhow::bail!("changed my mind")
+        });
+        assert!(failed.is_err());
+        assert!(db.get_contact(&alice).unwrap().is_none());
+
+        // A failed inner transaction only undoes its own writes
+        db.transaction(|db| {
+            db.upsert_contact(&Contact::new(alice, "alice".to_string(), vec![1]))?;
+            let inner: Result<()> = db.transaction(|db| {
+                db.upsert_contact(&Contact::new(bob, "bob".to_string(), vec![2]))?;
+                anyhow::bail!("not bob")
+            });
+            assert!(inner.is_err());
+            Ok(())
+        })
+        .unwrap();
+        assert!(db.get_contact(&alice).unwrap().is_some());
+        assert!(db.get_contact(&bob).unwrap().is_none());
+    }
+
+    #[test]
+    fn processes_can_write_to_one_file_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let db = Database::open(&path, "x'0101'").unwrap();
+        let mode: String = db.conn.query_row("PRAGMA journal_mode", [], |row| row.get(0)).unwrap();
+        assert_eq!(mode, "wal");
+
+        let writers: Vec<_> = (0..2)
+            .map(|writer| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let db = Database::open(&path, "x'0101'").unwrap();
+                    for i in 0..20 {
+                        let contact = Contact::new(make_peer_id(), format!("{}-{}", writer, i), vec![1]);
+                        db.transaction(|db| db.upsert_contact(&contact)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(db.list_contacts().unwrap().len(), 40);
+    }
+
+    #[test]
+    fn rekeyed_databases_only_open_with_the_new_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let (old_key, new_key) = ("x'0101'", "x'0202'");
+        {
+            let db = Database::open(&path, old_key).unwrap();
+            db.upsert_contact(&Contact::new(make_peer_id(), "alice".to_string(), vec![1])).unwrap();
+            db.rekey(new_key).unwrap();
+        }
+        assert!(Database::open(&path, old_key).is_err());
+        let db = Database::open(&path, new_key).unwrap();
+        db.integrity_check().unwrap();
+        assert!(db.get_contact_by_alias("alice").unwrap().is_some());
+    }
+
+    #[test]
+    fn ephemeral_copies_leave_the_file_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let key = "x'0101'";
+        let (alice, bob) = (make_peer_id(), make_peer_id());
+        {
+            let db = Database::open(&path, key).unwrap();
+            db.upsert_contact(&Contact::new(alice, "alice".to_string(), vec![1])).unwrap();
+            let msg = Message::new_text(alice, Recipient::Direct(alice), "hello there".to_string());
+            db.insert_message(&msg).unwrap();
+            db.save_session(&alice, &[1, 2, 3]).unwrap();
+        }
+
+        let copy = Database::open_ephemeral_copy(&path, key).unwrap();
+        assert!(copy.get_contact_by_alias("alice").unwrap().is_some());
+        assert_eq!(copy.search_messages("hello", 10).unwrap().len(), 1);
+        assert!(copy.session_peers().unwrap().is_empty());
+        copy.upsert_contact(&Contact::new(bob, "bob".to_string(), vec![2])).unwrap();
+        copy.save_session(&bob, &[4]).unwrap();
+        assert_eq!(copy.session_peers().unwrap(), vec![bob]);
+        drop(copy);
+
+        let db = Database::open(&path, key).unwrap();
+        assert!(db.get_contact_by_alias("bob").unwrap().is_none());
+        assert_eq!(db.session_peers().unwrap(), vec![alice]);
+    }
+
+    #[test]
+    fn unversioned_databases_are_migrated() {
+        use tempfile::tempdir;
+
+        // A database from before migrations: tables, but no version
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.pragma_update(None, "key", "test-key").unwrap();
+            conn.execute_batch(crate::storage::schema::MIGRATIONS[0].sql).unwrap();
+            // Only the columns that schema had
+            conn.execute(
+                "INSERT INTO contacts (peer_id, alias, public_key, trust_level) VALUES (?1, 'alice', x'', 'Unknown')",
+                params![make_peer_id().to_string()],
+            )
+            .unwrap();
+        }
+
+        let db = Database::open(&path, "test-key").unwrap();
+        assert_eq!(db.schema_version().unwrap(), crate::storage::schema::latest_version());
+        assert_eq!(db.list_contacts().unwrap().len(), 1);
     }
 
     #[test]
@@ -997,6 +4356,78 @@ mod tests {
         assert!(db.get_contact(&peer_id).unwrap().is_none());
     }
 
+    #[test]
+    fn contact_notes_and_tags_round_trip() {
+        let db = Database::open_in_memory().unwrap();
+        let alice = make_peer_id();
+        db.upsert_contact(&Contact::new(alice, "alice".to_string(), vec![])).unwrap();
+
+        db.set_contact_note(&alice, Some("met at FOSDEM")).unwrap();
+        assert!(db.add_contact_tag(&alice, "work").unwrap());
+        assert!(db.add_contact_tag(&alice, "family").unwrap());
+        assert!(!db.add_contact_tag(&alice, "work").unwrap());
+
+        // Upserting the contact itself leaves them alone
+        let mut contact = db.get_contact_by_alias("alice").unwrap().unwrap();
+        assert_eq!(contact.note.as_deref(), Some("met at FOSDEM"));
+        assert_eq!(contact.tags, vec!["family".to_string(), "work".to_string()]);
+        contact.last_seen = Some(Utc::now());
+        db.upsert_contact(&contact).unwrap();
+        let listed = db.list_contacts().unwrap();
+        assert_eq!(listed[0].tags.len(), 2);
+
+        assert!(db.remove_contact_tag(&alice, "family").unwrap());
+        db.set_contact_note(&alice, None).unwrap();
+        let contact = db.get_contact(&alice).unwrap().unwrap();
+        assert!(contact.note.is_none());
+        assert_eq!(contact.tags, vec!["work".to_string()]);
+
+        // Removing the contact takes them too
+        db.delete_contact(&alice).unwrap();
+        db.upsert_contact(&Contact::new(alice, "alice".to_string(), vec![])).unwrap();
+        assert!(db.get_contact(&alice).unwrap().unwrap().tags.is_empty());
+    }
+
+    #[test]
+    fn rename_contact_rejects_taken_alias() {
+        let db = Database::open_in_memory().unwrap();
+        let (alice, bob) = (make_peer_id(), make_peer_id());
+        db.upsert_contact(&Contact::new(alice, "alice".to_string(), vec![])).unwrap();
+        db.upsert_contact(&Contact::new(bob, "bob".to_string(), vec![])).unwrap();
+
+        assert!(db.rename_contact(&alice, "ally").unwrap());
+        assert_eq!(db.get_contact(&alice).unwrap().unwrap().alias, "ally");
+        assert!(db.get_contact_by_alias("alice").unwrap().is_none());
+
+        assert!(db.rename_contact(&alice, "bob").is_err());
+        assert_eq!(db.get_contact(&alice).unwrap().unwrap().alias, "ally");
+        // Renaming to the same alias is fine; unknown peers aren't
+        assert!(db.rename_contact(&alice, "ally").unwrap());
+        assert!(!db.rename_contact(&make_peer_id(), "carol").unwrap());
+    }
+
+    #[test]
+    fn delete_conversation_keeps_other_chats() {
+        let db = Database::open_in_memory().unwrap();
+        let (me, alice, bob) = (make_peer_id(), make_peer_id(), make_peer_id());
+        let group = Uuid::new_v4();
+
+        db.insert_message(&Message::new_text(me, Recipient::Direct(alice), "hi".to_string())).unwrap();
+        db.insert_message(&Message::new_text(alice, Recipient::Direct(me), "hey".to_string())).unwrap();
+        let in_group = Message::new_text(alice, Recipient::Group(group), "all".to_string());
+        db.insert_message(&in_group).unwrap();
+        db.insert_message(&Message::new_text(bob, Recipient::Direct(me), "yo".to_string())).unwrap();
+        db.queue_pending_message(&Uuid::new_v4(), &alice, b"queued").unwrap();
+        db.queue_pending_message(&Uuid::new_v4(), &bob, b"queued").unwrap();
+
+        assert_eq!(db.delete_conversation(&alice).unwrap(), (2, 1));
+        let left = db.get_messages_with_peer(&alice, 10).unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].id, in_group.id);
+        assert_eq!(db.get_messages_with_peer(&bob, 10).unwrap().len(), 1);
+        assert_eq!(db.get_all_pending().unwrap().len(), 1);
+    }
+
     #[test]
     fn insert_message() {
         let db = Database::open_in_memory().unwrap();
@@ -1023,6 +4454,27 @@ mod tests {
         assert_eq!(messages.len(), 2);
     }
 
+    #[test]
+    fn get_messages_with_peer_pages_back_in_time() {
+        let db = Database::open_in_memory().unwrap();
+        let (me, them) = (make_peer_id(), make_peer_id());
+        let start = Utc::now() - chrono::Duration::hours(1);
+        let ids: Vec<Uuid> = (0..5)
+            .map(|i| {
+                let mut msg = Message::new_text(me, Recipient::Direct(them), i.to_string());
+                msg.timestamp = start + chrono::Duration::minutes(i);
+                db.insert_message(&msg).unwrap();
+                msg.id
+            })
+            .collect();
+
+        let newest: Vec<Uuid> = db.get_messages_with_peer(&them, 2).unwrap().iter().map(|m| m.id).collect();
+        assert_eq!(newest, vec![ids[4], ids[3]]);
+        let older: Vec<Uuid> = db.get_messages_with_peer_page(&them, 2, 2).unwrap().iter().map(|m| m.id).collect();
+        assert_eq!(older, vec![ids[2], ids[1]]);
+        assert_eq!(db.get_messages_with_peer_page(&them, 2, 4).unwrap().len(), 1);
+    }
+
     #[test]
     fn update_message_status() {
         let db = Database::open_in_memory().unwrap();
@@ -1071,9 +4523,314 @@ mod tests {
         let mut contact = Contact::new(peer_id, "alice".to_string(), vec![]);
         contact.last_seen = Some(Utc::now());
 
-        db.upsert_contact(&contact).unwrap();
-        let loaded = db.get_contact(&peer_id).unwrap().unwrap();
-        assert!(loaded.last_seen.is_some());
+        db.upsert_contact(&contact).unwrap();
+        let loaded = db.get_contact(&peer_id).unwrap().unwrap();
+        assert!(loaded.last_seen.is_some());
+    }
+
+    #[test]
+    fn conversation_is_oldest_first_and_filtered() {
+        let db = Database::open_in_memory().unwrap();
+        let (alice, bob) = (make_peer_id(), make_peer_id());
+        let mut old = Message::new_text(alice, Recipient::Direct(bob), "old".to_string());
+        old.timestamp = Utc::now() - chrono::Duration::days(10);
+        let new = Message::new_text(bob, Recipient::Direct(alice), "new".to_string());
+        db.insert_message(&new).unwrap();
+        db.insert_message(&old).unwrap();
+
+        let all = db.get_conversation(&alice, None).unwrap();
+        assert_eq!(all.iter().map(|m| m.id).collect::<Vec<_>>(), vec![old.id, new.id]);
+
+        let recent = db.get_conversation(&alice, Some(Utc::now() - chrono::Duration::days(1))).unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].id, new.id);
+    }
+
+    #[test]
+    fn search_messages_finds_text() {
+        let db = Database::open_in_memory().unwrap();
+        let (alice, bob) = (make_peer_id(), make_peer_id());
+        let lunch = Message::new_text(alice, Recipient::Direct(bob), "Lunch on Friday?".to_string());
+        let other = Message::new_text(bob, Recipient::Direct(alice), "See you then".to_string());
+        db.insert_message(&lunch).unwrap();
+        db.insert_message(&other).unwrap();
+
+        let found = db.search_messages("friday", 10).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, lunch.id);
+
+        // Every word has to match
+        assert!(db.search_messages("lunch then", 10).unwrap().is_empty());
+        assert_eq!(db.search_messages("you then", 10).unwrap()[0].id, other.id);
+    }
+
+    #[test]
+    fn search_messages_treats_syntax_as_text() {
+        let db = Database::open_in_memory().unwrap();
+        let msg = Message::new_text(make_peer_id(), Recipient::Direct(make_peer_id()), "a \"quoted\" OR b-c".to_string());
+        db.insert_message(&msg).unwrap();
+
+        assert_eq!(db.search_messages("\"quoted\"", 10).unwrap().len(), 1);
+        assert_eq!(db.search_messages("b-c OR", 10).unwrap().len(), 1);
+        assert!(db.search_messages("   ", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn tombstone_and_delete_messages() {
+        let db = Database::open_in_memory().unwrap();
+        let (alice, bob) = (make_peer_id(), make_peer_id());
+        let first = Message::new_text(alice, Recipient::Direct(bob), "secret plans".to_string());
+        let second = Message::new_text(alice, Recipient::Direct(bob), "more plans".to_string());
+        db.insert_message(&first).unwrap();
+        db.insert_message(&second).unwrap();
+
+        let prefix = &first.id.to_string()[..8];
+        assert_eq!(db.find_message_ids(prefix, 2).unwrap(), vec![first.id]);
+
+        // A tombstone keeps the message's place but drops its text
+        assert!(db.tombstone_message(&first.id).unwrap());
+        let stored = db.get_message(&first.id).unwrap().unwrap();
+        assert!(matches!(stored.content, MessageContent::Tombstone));
+        assert_eq!(db.get_messages_with_peer(&bob, 10).unwrap().len(), 2);
+        assert!(db.search_messages("secret", 10).unwrap().is_empty());
+
+        assert!(db.delete_message(&second.id).unwrap());
+        assert!(db.get_message(&second.id).unwrap().is_none());
+        assert!(db.search_messages("plans", 10).unwrap().is_empty());
+        assert!(!db.delete_message(&second.id).unwrap());
+        // Deleted messages are remembered; tombstoned ones are still here
+        assert!(db.was_message_deleted(&second.id).unwrap());
+        assert!(!db.was_message_deleted(&first.id).unwrap());
+        assert_eq!(db.deleted_message_ids().unwrap(), vec![second.id]);
+
+        // What's recorded about a message goes with it
+        let group = Group::new("team".to_string(), vec![1; 32], Some(alice));
+        db.create_group(&group).unwrap();
+        let third = Message::new_text(alice, Recipient::Group(group.id), "group plans".to_string());
+        db.insert_message(&third).unwrap();
+        db.mark_message_unverified(&third.id).unwrap();
+        db.set_group_key(&group.id, 1, &[2; 32]).unwrap();
+        assert_eq!(db.retired_key_epoch(&third.id).unwrap(), Some(0));
+
+        assert!(db.delete_message(&third.id).unwrap());
+        assert!(!db.is_message_unverified(&third.id).unwrap());
+        assert_eq!(db.retired_key_epoch(&third.id).unwrap(), None);
+    }
+
+    #[test]
+    fn unverified_messages_are_flagged() {
+        let db = Database::open_in_memory().unwrap();
+        let msg = Message::new_text(make_peer_id(), Recipient::Direct(make_peer_id()), "hi".to_string());
+        db.insert_message(&msg).unwrap();
+
+        assert!(!db.is_message_unverified(&msg.id).unwrap());
+        db.mark_message_unverified(&msg.id).unwrap();
+        db.mark_message_unverified(&msg.id).unwrap();
+        assert!(db.is_message_unverified(&msg.id).unwrap());
+    }
+
+    #[test]
+    fn unread_counts_reset_when_read() {
+        let db = Database::open_in_memory().unwrap();
+        let (us, alice, bob) = (make_peer_id(), make_peer_id(), make_peer_id());
+        db.upsert_contact(&Contact::new(alice, "alice".to_string(), Vec::new())).unwrap();
+        db.upsert_contact(&Contact::new(bob, "bob".to_string(), Vec::new())).unwrap();
+
+        for text in ["one", "two"] {
+            db.insert_message(&Message::new_text(alice, Recipient::Direct(us), text.to_string())).unwrap();
+        }
+        db.insert_message(&Message::new_text(bob, Recipient::Direct(us), "hi".to_string())).unwrap();
+        // Our own messages and group messages don't count
+        db.insert_message(&Message::new_text(us, Recipient::Direct(alice), "hey".to_string())).unwrap();
+        db.insert_message(&Message::new_text(alice, Recipient::Group(Uuid::new_v4()), "all".to_string())).unwrap();
+
+        let counts = db.unread_counts().unwrap();
+        assert_eq!(counts.get(&alice), Some(&2));
+        assert_eq!(counts.get(&bob), Some(&1));
+        assert_eq!(counts.len(), 2);
+
+        db.mark_conversation_read(&Recipient::Direct(alice)).unwrap();
+        let counts = db.unread_counts().unwrap();
+        assert!(!counts.contains_key(&alice));
+        assert_eq!(counts.get(&bob), Some(&1));
+
+        // Anything newer is unread again
+        let mut later = Message::new_text(alice, Recipient::Direct(us), "three".to_string());
+        later.timestamp = Utc::now() + chrono::Duration::seconds(5);
+        db.insert_message(&later).unwrap();
+        assert_eq!(db.unread_counts().unwrap().get(&alice), Some(&1));
+    }
+
+    #[test]
+    fn conversations_sorted_by_recency() {
+        let db = Database::open_in_memory().unwrap();
+        let (us, alice, bob) = (make_peer_id(), make_peer_id(), make_peer_id());
+        db.upsert_contact(&Contact::new(alice, "alice".to_string(), Vec::new())).unwrap();
+        db.upsert_contact(&Contact::new(bob, "bob".to_string(), Vec::new())).unwrap();
+        db.upsert_contact(&Contact::new(make_peer_id(), "carol".to_string(), Vec::new())).unwrap();
+        let group = Group::new("team".to_string(), vec![0; 32], Some(us));
+        db.create_group(&group).unwrap();
+
+        let start = Utc::now() - chrono::Duration::hours(1);
+        let insert = |from: PeerId, to: Recipient, text: &str, minutes: i64| {
+            let mut msg = Message::new_text(from, to, text.to_string());
+            msg.timestamp = start + chrono::Duration::minutes(minutes);
+            db.insert_message(&msg).unwrap();
+        };
+        insert(alice, Recipient::Direct(us), "morning", 0);
+        insert(us, Recipient::Direct(bob), "hi bob", 1);
+        insert(alice, Recipient::Group(group.id), "standup?", 2);
+        insert(bob, Recipient::Direct(us), "hey", 3);
+
+        let conversations = db.list_conversations().unwrap();
+        let names: Vec<&str> = conversations.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["bob", "team", "alice", "carol"]);
+
+        // Alice's group message doesn't count towards her direct chat
+        let alice_chat = &conversations[2];
+        assert!(matches!(
+            alice_chat.last_message.as_ref().map(|m| &m.content),
+            Some(MessageContent::Text(text)) if text == "morning"
+        ));
+        assert_eq!(alice_chat.unread, 1);
+        assert_eq!(conversations[1].unread, 1);
+        assert!(conversations[3].last_message.is_none());
+
+        db.mark_conversation_read(&Recipient::Group(group.id)).unwrap();
+        let team = db
+            .list_conversations()
+            .unwrap()
+            .into_iter()
+            .find(|c| c.with == Recipient::Group(group.id))
+            .unwrap();
+        assert_eq!(team.unread, 0);
+    }
+
+    #[test]
+    fn pinned_conversations_come_first_and_archiving_sticks() {
+        let db = Database::open_in_memory().unwrap();
+        let (us, alice, bob) = (make_peer_id(), make_peer_id(), make_peer_id());
+        db.upsert_contact(&Contact::new(alice, "alice".to_string(), Vec::new())).unwrap();
+        db.upsert_contact(&Contact::new(bob, "bob".to_string(), Vec::new())).unwrap();
+        db.insert_message(&Message::new_text(bob, Recipient::Direct(us), "hey".to_string())).unwrap();
+
+        let names = |db: &Database| -> Vec<String> {
+            db.list_conversations().unwrap().into_iter().map(|c| c.name).collect()
+        };
+        assert_eq!(names(&db), vec!["bob", "alice"]);
+
+        db.set_pinned(&Recipient::Direct(alice), true).unwrap();
+        db.set_archived(&Recipient::Direct(alice), true).unwrap();
+        assert_eq!(names(&db), vec!["alice", "bob"]);
+        let alice_chat = &db.list_conversations().unwrap()[0];
+        assert!(alice_chat.pinned && alice_chat.archived);
+        assert_eq!(db.conversation_place(&Recipient::Direct(alice)).unwrap(), (true, true));
+        assert_eq!(db.conversation_place(&Recipient::Direct(bob)).unwrap(), (false, false));
+
+        // Unpinning leaves the archive alone
+        db.set_pinned(&Recipient::Direct(alice), false).unwrap();
+        let alice_chat = &db.list_conversations().unwrap()[1];
+        assert!(!alice_chat.pinned && alice_chat.archived);
+        assert_eq!(names(&db), vec!["bob", "alice"]);
+    }
+
+    #[test]
+    fn key_changes_reset_trust_until_cleared() {
+        let db = Database::open_in_memory().unwrap();
+        let (alice, bob) = (make_peer_id(), make_peer_id());
+        let mut contact = Contact::new(alice, "alice".to_string(), vec![1; 32]);
+        contact.trust_level = TrustLevel::Verified;
+        db.upsert_contact(&contact).unwrap();
+        let mut blocked = Contact::new(bob, "bob".to_string(), vec![3; 32]);
+        blocked.trust_level = TrustLevel::Blocked;
+        db.upsert_contact(&blocked).unwrap();
+        assert_eq!(db.key_change(&alice).unwrap(), None);
+
+        let now = Utc::now();
+        assert!(db.record_key_change(&alice, &[2; 32], now).unwrap());
+        assert!(db.record_key_change(&bob, &[4; 32], now).unwrap());
+        assert!(!db.record_key_change(&make_peer_id(), &[2; 32], now).unwrap());
+        let contact = db.get_contact(&alice).unwrap().unwrap();
+        assert_eq!(contact.public_key, vec![2; 32]);
+        assert_eq!(contact.trust_level, TrustLevel::Unknown);
+        assert_eq!(db.key_change(&alice).unwrap().map(|at| at.timestamp()), Some(now.timestamp()));
+        assert_eq!(db.get_contact(&bob).unwrap().unwrap().trust_level, TrustLevel::Blocked);
+
+        assert!(db.clear_key_change(&alice).unwrap());
+        assert!(!db.clear_key_change(&alice).unwrap());
+        assert_eq!(db.key_change(&alice).unwrap(), None);
+    }
+
+    #[test]
+    fn stamp_difficulty_defaults_and_is_set() {
+        let db = Database::open_in_memory().unwrap();
+        assert_eq!(db.stamp_difficulty().unwrap(), DEFAULT_STAMP_DIFFICULTY);
+        db.set_stamp_difficulty(0).unwrap();
+        assert_eq!(db.stamp_difficulty().unwrap(), 0);
+        db.set_stamp_difficulty(20).unwrap();
+        assert_eq!(db.stamp_difficulty().unwrap(), 20);
+    }
+
+    #[test]
+    fn trust_policy_holds_untrusted_contacts_as_requests() {
+        let db = Database::open_in_memory().unwrap();
+        assert_eq!(db.trust_policy().unwrap(), TrustPolicy::default());
+
+        let policy = TrustPolicy {
+            conversations: TrustRequirement::Trusted,
+            group_invites: TrustRequirement::Verified,
+            ..TrustPolicy::default()
+        };
+        db.set_trust_policy(&policy).unwrap();
+        assert_eq!(db.trust_policy().unwrap(), policy);
+
+        let me = make_peer_id();
+        let mut contact = Contact::new(make_peer_id(), "bob".to_string(), vec![]);
+        db.upsert_contact(&contact).unwrap();
+        let first = Message::new_text(contact.peer_id, Recipient::Direct(me), "hi".to_string());
+        assert_eq!(db.receive_message(&first).unwrap(), Some(Inbox::Request));
+
+        contact.trust_level = TrustLevel::Trusted;
+        db.upsert_contact(&contact).unwrap();
+        let second = Message::new_text(contact.peer_id, Recipient::Direct(me), "hi again".to_string());
+        assert_eq!(db.receive_message(&second).unwrap(), Some(Inbox::Conversation));
+
+        // Back to anyone clears it
+        db.set_trust_policy(&TrustPolicy::default()).unwrap();
+        assert_eq!(db.trust_policy().unwrap(), TrustPolicy::default());
+    }
+
+    #[test]
+    fn address_sharing_defaults_on_and_toggles() {
+        let db = Database::open_in_memory().unwrap();
+        let peer = PeerId::random();
+        assert!(db.shares_addresses(&peer).unwrap());
+        db.set_read_receipts(&peer, false).unwrap();
+        db.set_share_addresses(&peer, false).unwrap();
+        assert!(!db.shares_addresses(&peer).unwrap());
+        // Settings are kept apart
+        assert!(!db.read_receipts_enabled(&peer).unwrap());
+        db.set_share_addresses(&peer, true).unwrap();
+        assert!(db.shares_addresses(&peer).unwrap());
+
+        assert!(db.pex_enabled().unwrap());
+        db.set_pex_enabled(false).unwrap();
+        assert!(!db.pex_enabled().unwrap());
+        db.set_pex_enabled(true).unwrap();
+        assert!(db.pex_enabled().unwrap());
+    }
+
+    #[test]
+    fn read_receipts_default_on_and_toggle() {
+        let db = Database::open_in_memory().unwrap();
+        let peer = make_peer_id();
+        assert!(db.read_receipts_enabled(&peer).unwrap());
+
+        db.set_read_receipts(&peer, false).unwrap();
+        assert!(!db.read_receipts_enabled(&peer).unwrap());
+
+        db.set_read_receipts(&peer, true).unwrap();
+        assert!(db.read_receipts_enabled(&peer).unwrap());
     }
 
     // === Group Tests ===
@@ -1087,7 +4844,7 @@ mod tests {
         let loaded = db.get_group(&group.id).unwrap().unwrap();
 
         assert_eq!(loaded.name, "Test Group");
-        assert_eq!(loaded.symmetric_key, vec![1, 2, 3]);
+        assert_eq!(*loaded.symmetric_key, vec![1, 2, 3]);
     }
 
     #[test]
@@ -1123,6 +4880,118 @@ mod tests {
         assert!(db.get_group(&group.id).unwrap().is_none());
     }
 
+    #[test]
+    fn group_key_rotation_marks_old_messages() {
+        let db = Database::open_in_memory().unwrap();
+        let group = Group::new("team".to_string(), vec![1; 32], Some(make_peer_id()));
+        db.create_group(&group).unwrap();
+        assert_eq!(db.group_key_epoch(&group.id).unwrap(), 0);
+
+        let before = Message::new_text(make_peer_id(), Recipient::Group(group.id), "old".to_string());
+        db.insert_message(&before).unwrap();
+
+        assert!(db.set_group_key(&group.id, 1, &[2; 32]).unwrap());
+        let after = Message::new_text(make_peer_id(), Recipient::Group(group.id), "new".to_string());
+        db.insert_message(&after).unwrap();
+
+        assert_eq!(db.group_key_epoch(&group.id).unwrap(), 1);
+        assert_eq!(*db.get_group(&group.id).unwrap().unwrap().symmetric_key, vec![2; 32]);
+        assert_eq!(db.retired_key_epoch(&before.id).unwrap(), Some(0));
+        assert_eq!(db.retired_key_epoch(&after.id).unwrap(), None);
+
+        // Stale or replayed epochs are ignored
+        assert!(!db.set_group_key(&group.id, 1, &[3; 32]).unwrap());
+        assert_eq!(*db.get_group(&group.id).unwrap().unwrap().symmetric_key, vec![2; 32]);
+
+        assert!(db.set_group_key(&group.id, 2, &[4; 32]).unwrap());
+        assert_eq!(db.retired_key_epoch(&before.id).unwrap(), Some(0));
+        assert_eq!(db.retired_key_epoch(&after.id).unwrap(), Some(1));
+
+        // Unknown groups have nothing to rotate
+        assert!(!db.set_group_key(&Uuid::new_v4(), 1, &[5; 32]).unwrap());
+    }
+
+    #[test]
+    fn group_departure_recorded_until_rejoin() {
+        let db = Database::open_in_memory().unwrap();
+        let peer = make_peer_id();
+        let mut group = Group::new("team".to_string(), vec![1; 32], None);
+        group.add_member(peer);
+        db.create_group(&group).unwrap();
+        assert!(db.group_departure(&group.id, &peer).unwrap().is_none());
+
+        let left_at = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        db.record_group_departure(&group.id, &peer, left_at).unwrap();
+        assert_eq!(db.group_departure(&group.id, &peer).unwrap(), Some(left_at));
+        assert!(!db.get_group(&group.id).unwrap().unwrap().is_member(&peer));
+
+        db.add_group_member(&group.id, &peer).unwrap();
+        assert!(db.group_departure(&group.id, &peer).unwrap().is_none());
+    }
+
+    #[test]
+    fn group_invite_save_list_delete() {
+        let db = Database::open_in_memory().unwrap();
+        let invite = PendingGroupInvite {
+            group_id: Uuid::new_v4(),
+            name: "friends".to_string(),
+            from: make_peer_id(),
+            symmetric_key: vec![7; 32],
+            received_at: Utc::now(),
+        };
+
+        db.save_group_invite(&invite).unwrap();
+        db.save_group_invite(&invite).unwrap();
+        let invites = db.list_group_invites().unwrap();
+        assert_eq!(invites.len(), 1);
+        assert_eq!(invites[0].group_id, invite.group_id);
+        assert_eq!(invites[0].from, invite.from);
+        assert_eq!(invites[0].symmetric_key, vec![7; 32]);
+
+        assert!(db.delete_group_invite(&invite.group_id).unwrap());
+        assert!(db.list_group_invites().unwrap().is_empty());
+    }
+
+    #[test]
+    fn strangers_land_in_message_requests() {
+        let db = Database::open_in_memory().unwrap();
+        let (me, alice, stranger) = (make_peer_id(), make_peer_id(), make_peer_id());
+        db.upsert_contact(&Contact::new(alice, "alice".to_string(), vec![])).unwrap();
+
+        let from_alice = Message::new_text(alice, Recipient::Direct(me), "hi".to_string());
+        let from_stranger = Message::new_text(stranger, Recipient::Direct(me), "hello?".to_string());
+        assert_eq!(db.receive_message(&from_alice).unwrap(), Some(Inbox::Conversation));
+        assert_eq!(db.receive_message(&from_stranger).unwrap(), Some(Inbox::Request));
+        // Redelivery is ignored either way
+        assert_eq!(db.receive_message(&from_alice).unwrap(), None);
+        assert_eq!(db.receive_message(&from_stranger).unwrap(), None);
+
+        assert!(db.get_messages_with_peer(&stranger, 10).unwrap().is_empty());
+        let requests = db.list_message_requests().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].id, from_stranger.id);
+        assert_eq!(db.count_message_request_senders().unwrap(), 1);
+    }
+
+    #[test]
+    fn accept_and_decline_message_requests() {
+        let db = Database::open_in_memory().unwrap();
+        let (me, carol, dave) = (make_peer_id(), make_peer_id(), make_peer_id());
+        for (from, text) in [(carol, "one"), (carol, "two"), (dave, "spam")] {
+            let msg = Message::new_text(from, Recipient::Direct(me), text.to_string());
+            db.receive_message(&msg).unwrap();
+        }
+        assert_eq!(db.count_message_request_senders().unwrap(), 2);
+
+        assert_eq!(db.accept_message_requests(&carol).unwrap(), 2);
+        assert_eq!(db.get_messages_with_peer(&carol, 10).unwrap().len(), 2);
+        assert_eq!(db.search_messages("two", 10).unwrap().len(), 1);
+
+        assert_eq!(db.delete_message_requests(&dave).unwrap(), 1);
+        assert!(db.get_messages_with_peer(&dave, 10).unwrap().is_empty());
+        assert!(db.list_message_requests().unwrap().is_empty());
+    }
+
     #[test]
     fn add_group_member() {
         let db = Database::open_in_memory().unwrap();
@@ -1235,6 +5104,620 @@ mod tests {
         assert!(loaded.description.is_none());
     }
 
+    // === Session Tests ===
+
+    #[test]
+    fn session_save_and_load() {
+        let db = Database::open_in_memory().unwrap();
+        let peer = make_peer_id();
+
+        assert!(db.get_session(&peer).unwrap().is_none());
+        db.save_session(&peer, &[1, 2, 3]).unwrap();
+        assert_eq!(db.get_session(&peer).unwrap(), Some(vec![1, 2, 3]));
+
+        db.save_session(&peer, &[4, 5]).unwrap();
+        assert_eq!(db.get_session(&peer).unwrap(), Some(vec![4, 5]));
+    }
+
+    #[test]
+    fn session_delete() {
+        let db = Database::open_in_memory().unwrap();
+        let peer = make_peer_id();
+
+        db.save_session(&peer, &[1]).unwrap();
+        assert!(db.delete_session(&peer).unwrap());
+        assert!(!db.delete_session(&peer).unwrap());
+        assert!(db.get_session(&peer).unwrap().is_none());
+    }
+
+    #[test]
+    fn reachability_save_and_load() {
+        let db = Database::open_in_memory().unwrap();
+        assert!(db.last_reachability().unwrap().is_none());
+
+        db.save_reachability(Reachability::BehindNat).unwrap();
+        db.save_reachability(Reachability::Public).unwrap();
+        let (reachability, _) = db.last_reachability().unwrap().unwrap();
+        assert_eq!(reachability, Reachability::Public);
+    }
+
+    #[test]
+    fn presence_is_stored_on_contacts() {
+        let db = Database::open_in_memory().unwrap();
+        assert_eq!(db.own_presence().unwrap(), PresenceStatus::Online);
+        db.set_own_presence(PresenceStatus::Away).unwrap();
+        assert_eq!(db.own_presence().unwrap(), PresenceStatus::Away);
+
+        let peer = PeerId::random();
+        let presence = Presence { status: PresenceStatus::Away, at: Utc.timestamp_opt(1_700_000_000, 0).unwrap() };
+        assert!(!db.set_contact_presence(&peer, presence).unwrap());
+        db.upsert_contact(&Contact::new(peer, "alice".to_string(), vec![])).unwrap();
+        assert!(db.set_contact_presence(&peer, presence).unwrap());
+        let mut contact = db.get_contact(&peer).unwrap().unwrap();
+        assert_eq!(contact.presence, Some(presence));
+
+        // Saving the contact keeps it
+        contact.last_seen = Some(Utc::now());
+        db.upsert_contact(&contact).unwrap();
+        assert_eq!(db.get_contact(&peer).unwrap().unwrap().presence, Some(presence));
+
+        contact.trust_level = TrustLevel::Blocked;
+        db.upsert_contact(&contact).unwrap();
+        assert!(!db.set_contact_presence(&peer, presence).unwrap());
+    }
+
+    #[test]
+    fn rendezvous_point_set_and_clear() {
+        let db = Database::open_in_memory().unwrap();
+        assert!(db.rendezvous_point().unwrap().is_none());
+
+        let addr: Multiaddr = format!("/ip4/203.0.113.1/tcp/4001/p2p/{}", PeerId::random()).parse().unwrap();
+        db.set_rendezvous_point(Some(&addr)).unwrap();
+        assert_eq!(db.rendezvous_point().unwrap(), Some(addr));
+        db.set_rendezvous_point(None).unwrap();
+        assert!(db.rendezvous_point().unwrap().is_none());
+    }
+
+    #[test]
+    fn username_set_and_clear() {
+        let db = Database::open_in_memory().unwrap();
+        assert!(db.username().unwrap().is_none());
+        db.set_username(Some("alice")).unwrap();
+        assert_eq!(db.username().unwrap().as_deref(), Some("alice"));
+        db.set_username(None).unwrap();
+        assert!(db.username().unwrap().is_none());
+    }
+
+    #[test]
+    fn invites_are_redeemed_once_before_expiring() {
+        let db = Database::open_in_memory().unwrap();
+        let now = Utc::now();
+        let invite = IssuedInvite {
+            id: [7; 8],
+            alias: Some("bob".to_string()),
+            created_at: now,
+            expires_at: now + chrono::Duration::hours(1),
+            used_by: None,
+        };
+        db.save_invite(&invite).unwrap();
+        let (bob, mallory) = (PeerId::random(), PeerId::random());
+
+        // Not after it expires, and not an invite we never made
+        assert!(db.redeem_invite(&invite.id, &bob, invite.expires_at).unwrap().is_none());
+        assert!(db.redeem_invite(&[8; 8], &bob, now).unwrap().is_none());
+
+        let redeemed = db.redeem_invite(&invite.id, &bob, now).unwrap().unwrap();
+        assert_eq!(redeemed.alias.as_deref(), Some("bob"));
+        assert_eq!(redeemed.used_by, Some(bob));
+        assert!(db.redeem_invite(&invite.id, &mallory, now).unwrap().is_none());
+        assert_eq!(db.list_invites().unwrap()[0].used_by, Some(bob));
+    }
+
+    #[test]
+    fn socks_proxy_set_and_clear() {
+        let db = Database::open_in_memory().unwrap();
+        assert!(db.socks_proxy().unwrap().is_none());
+
+        let proxy: SocketAddr = "127.0.0.1:9050".parse().unwrap();
+        db.set_socks_proxy(Some(proxy)).unwrap();
+        assert_eq!(db.socks_proxy().unwrap(), Some(proxy));
+        db.set_socks_proxy(None).unwrap();
+        assert!(db.socks_proxy().unwrap().is_none());
+
+        db.set_websocket_port(Some(8080)).unwrap();
+        assert_eq!(db.websocket_port().unwrap(), Some(8080));
+        db.set_websocket_port(None).unwrap();
+        assert!(db.websocket_port().unwrap().is_none());
+
+        db.set_listen_port(Some(7777)).unwrap();
+        assert_eq!(db.listen_port().unwrap(), Some(7777));
+        db.set_listen_port(None).unwrap();
+        assert!(db.listen_port().unwrap().is_none());
+    }
+
+    #[test]
+    fn webhook_set_and_clear() {
+        let db = Database::open_in_memory().unwrap();
+        assert!(db.webhook().unwrap().is_none());
+
+        let webhook = Webhook {
+            url: "http://127.0.0.1:8123/hook".to_string(),
+            only: vec![Recipient::Direct(make_peer_id()), Recipient::Group(Uuid::new_v4())],
+        };
+        db.set_webhook(Some(&webhook)).unwrap();
+        assert_eq!(db.webhook().unwrap(), Some(webhook.clone()));
+        assert!(webhook.wants(&webhook.only[1]));
+        assert!(!webhook.wants(&Recipient::Direct(make_peer_id())));
+
+        let everything = Webhook { url: webhook.url.clone(), only: Vec::new() };
+        db.set_webhook(Some(&everything)).unwrap();
+        assert!(db.webhook().unwrap().unwrap().wants(&Recipient::Direct(make_peer_id())));
+        db.set_webhook(None).unwrap();
+        assert!(db.webhook().unwrap().is_none());
+    }
+
+    #[test]
+    fn matrix_sync_tokens_are_per_room() {
+        let db = Database::open_in_memory().unwrap();
+        assert!(db.matrix_sync_token("!a:x").unwrap().is_none());
+        db.set_matrix_sync_token("!a:x", "s1").unwrap();
+        db.set_matrix_sync_token("!a:x", "s2").unwrap();
+        db.set_matrix_sync_token("!b:x", "t1").unwrap();
+        assert_eq!(db.matrix_sync_token("!a:x").unwrap().as_deref(), Some("s2"));
+        assert_eq!(db.matrix_sync_token("!b:x").unwrap().as_deref(), Some("t1"));
+    }
+
+    #[test]
+    fn mailbox_deposits_are_tracked_per_mailbox() {
+        let db = Database::open_in_memory().unwrap();
+        let (alice, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+        assert!(db.add_mailbox_peer(&carol).unwrap());
+        assert!(!db.add_mailbox_peer(&carol).unwrap());
+        assert_eq!(db.mailbox_peers().unwrap(), vec![carol]);
+
+        let (for_alice, for_carol) = (Uuid::new_v4(), Uuid::new_v4());
+        db.queue_pending_message(&for_alice, &alice, b"to alice").unwrap();
+        db.queue_pending_message(&for_carol, &carol, b"to carol").unwrap();
+
+        // Carol gets her own messages directly
+        let pending = db.pending_for_mailbox(&carol).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!((pending[0].id, pending[0].to), (for_alice, alice));
+
+        db.record_mailbox_deposit(&for_alice, &carol).unwrap();
+        assert!(db.pending_for_mailbox(&carol).unwrap().is_empty());
+        assert_eq!(db.pending_for_mailbox(&bob).unwrap().len(), 2);
+
+        db.remove_pending_message(&for_alice).unwrap();
+        db.remove_mailbox_peer(&carol).unwrap();
+        assert!(db.mailbox_peers().unwrap().is_empty());
+    }
+
+    #[test]
+    fn mail_is_handed_over_once_and_expires() {
+        let db = Database::open_in_memory().unwrap();
+        assert!(!db.mailbox_serving().unwrap());
+        db.set_mailbox_serving(true).unwrap();
+        assert!(db.mailbox_serving().unwrap());
+        // Settings in node_state are independent of each other
+        assert!(!db.raw_markdown().unwrap());
+        db.set_raw_markdown(true).unwrap();
+        assert!(db.raw_markdown().unwrap() && db.mailbox_serving().unwrap());
+
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let now = Utc::now();
+        let id = Uuid::new_v4();
+        assert!(db.store_mail(&id, &alice, &bob, b"sealed", now + chrono::Duration::days(1)).unwrap());
+        assert!(!db.store_mail(&id, &alice, &bob, b"sealed", now + chrono::Duration::days(1)).unwrap());
+        db.store_mail(&Uuid::new_v4(), &alice, &bob, b"stale", now - chrono::Duration::days(1))
+            .unwrap();
+        db.store_mail(&Uuid::new_v4(), &bob, &alice, b"later", now + chrono::Duration::days(1))
+            .unwrap();
+        assert_eq!(db.count_mail_from(&alice).unwrap(), 2);
+
+        assert_eq!(db.take_mail_for(&bob, now).unwrap(), vec![(alice, b"sealed".to_vec())]);
+        assert!(db.take_mail_for(&bob, now).unwrap().is_empty());
+        assert_eq!(db.expire_mail(now + chrono::Duration::days(2)).unwrap(), 1);
+        assert_eq!(db.count_mail().unwrap(), 0);
+    }
+
+    #[test]
+    fn newer_device_lists_replace_older() {
+        use crate::message::{DeviceLink, LinkedDevice};
+        use libp2p::identity::Keypair;
+
+        let db = Database::open_in_memory().unwrap();
+        let (primary, laptop, tablet) = (
+            Keypair::generate_ed25519(),
+            Keypair::generate_ed25519(),
+            Keypair::generate_ed25519(),
+        );
+        let identity = PeerId::from(primary.public());
+        let device = |keypair: &Keypair, name: &str| LinkedDevice {
+            name: name.to_string(),
+            link: DeviceLink::new(keypair, identity).unwrap(),
+        };
+
+        let both = DeviceList::sign(&primary, vec![device(&laptop, "laptop"), device(&tablet, "tablet")]).unwrap();
+        let mut tablet_only = DeviceList::sign(&primary, vec![device(&tablet, "tablet")]).unwrap();
+        tablet_only.issued_at = both.issued_at + chrono::Duration::seconds(1);
+
+        assert!(db.save_device_list(&both).unwrap());
+        assert_eq!(db.linked_devices(&identity).unwrap().len(), 2);
+        assert_eq!(db.device_identity(&PeerId::from(laptop.public())).unwrap(), Some(identity));
+
+        assert!(db.save_device_list(&tablet_only).unwrap());
+        assert!(!db.save_device_list(&both).unwrap(), "older list");
+        assert_eq!(db.linked_devices(&identity).unwrap(), vec![PeerId::from(tablet.public())]);
+        assert_eq!(db.device_identity(&PeerId::from(laptop.public())).unwrap(), None);
+        assert_eq!(db.device_list(&identity).unwrap(), Some(tablet_only));
+
+        assert_eq!(db.linked_to().unwrap(), None);
+        db.set_linked_to(Some(&identity)).unwrap();
+        assert_eq!(db.linked_to().unwrap(), Some(identity));
+    }
+
+    #[test]
+    fn channels_keep_subscribers_and_posts() {
+        use libp2p::identity::Keypair;
+
+        let db = Database::open_in_memory().unwrap();
+        let owner = Keypair::generate_ed25519();
+        let us = PeerId::from(owner.public());
+        let theirs = Channel::new("news".to_string(), PeerId::random());
+        let ours = Channel::new("news".to_string(), us);
+
+        assert!(db.add_channel(&theirs).unwrap());
+        assert!(db.add_channel(&ours).unwrap());
+        assert!(!db.add_channel(&ours).unwrap());
+        assert_eq!(db.get_channel_by_name("news", &us).unwrap(), Some(ours.clone()));
+        assert_eq!(db.get_channel(&theirs.id).unwrap(), Some(theirs));
+        assert_eq!(db.list_channels().unwrap().len(), 2);
+
+        let subscriber = PeerId::random();
+        assert!(db.add_channel_subscriber(&ours.id, &subscriber).unwrap());
+        assert!(!db.add_channel_subscriber(&ours.id, &subscriber).unwrap());
+        assert_eq!(db.channel_subscribers(&ours.id).unwrap(), vec![subscriber]);
+
+        let first = ChannelPost::sign(&owner, &ours, "first".to_string()).unwrap();
+        let second = ChannelPost::sign(&owner, &ours, "second".to_string()).unwrap();
+        assert!(db.store_channel_post(&first).unwrap());
+        assert!(!db.store_channel_post(&first).unwrap());
+        assert!(db.store_channel_post(&second).unwrap());
+        assert_eq!(db.channel_posts(&ours.id, 10).unwrap(), vec![first, second.clone()]);
+        assert_eq!(db.channel_posts(&ours.id, 1).unwrap(), vec![second]);
+    }
+
+    // === Peer Address Tests ===
+
+    fn tcp_addr(port: u16) -> Multiaddr {
+        format!("/ip4/10.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn peer_addresses_prefer_successful_ones() {
+        let db = Database::open_in_memory().unwrap();
+        let peer = make_peer_id();
+        db.record_peer_address(&peer, &tcp_addr(1), false).unwrap();
+        db.record_peer_address(&peer, &tcp_addr(2), true).unwrap();
+        // Seeing it again doesn't forget that it worked
+        db.record_peer_address(&peer, &tcp_addr(2), false).unwrap();
+
+        let addresses = db.peer_addresses(&peer).unwrap();
+        assert_eq!(addresses.len(), 2);
+        assert_eq!(addresses[0].address, tcp_addr(2));
+        assert!(addresses[0].last_success.is_some());
+        assert_eq!(addresses[1].address, tcp_addr(1));
+        assert!(addresses[1].last_success.is_none());
+
+        assert!(db.peer_addresses(&make_peer_id()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn peer_addresses_are_capped_keeping_successful_ones() {
+        let db = Database::open_in_memory().unwrap();
+        let (peer, other) = (make_peer_id(), make_peer_id());
+        db.record_peer_address(&peer, &tcp_addr(1), true).unwrap();
+        for port in 100..120 {
+            db.record_peer_address(&peer, &tcp_addr(port), false).unwrap();
+        }
+        db.record_peer_address(&other, &tcp_addr(1), false).unwrap();
+
+        let addresses = db.peer_addresses(&peer).unwrap();
+        assert_eq!(addresses.len(), MAX_ADDRESSES_PER_PEER);
+        assert_eq!(addresses[0].address, tcp_addr(1));
+        assert_eq!(db.all_peer_addresses().unwrap().len(), MAX_ADDRESSES_PER_PEER + 1);
+    }
+
+    #[test]
+    fn deleting_contact_forgets_addresses() {
+        let db = Database::open_in_memory().unwrap();
+        let peer = make_peer_id();
+        db.upsert_contact(&Contact::new(peer, "alice".to_string(), vec![1; 32])).unwrap();
+        db.record_peer_address(&peer, &tcp_addr(1), true).unwrap();
+        db.delete_contact(&peer).unwrap();
+        assert!(db.peer_addresses(&peer).unwrap().is_empty());
+    }
+
+    #[test]
+    fn traffic_adds_up_across_reports() {
+        let db = Database::open_in_memory().unwrap();
+        let (alice, bob) = (make_peer_id(), make_peer_id());
+        let mut report = NetworkStats::default();
+        report.record_sent(alice, TrafficProtocol::Messages, 100);
+        report.record_received(alice, TrafficProtocol::FileTransfer, 4096);
+        report.record_sent(bob, TrafficProtocol::Ping, 32);
+
+        db.record_traffic(&report).unwrap();
+        db.record_traffic(&report).unwrap();
+        let traffic = db.traffic().unwrap();
+        assert_eq!(traffic.peer(&alice).sent, 200);
+        assert_eq!(traffic.peer(&alice).received, 8192);
+        assert_eq!(traffic.totals().sent, 264);
+    }
+
+    #[test]
+    fn audit_log_is_append_only() {
+        let db = Database::open_in_memory().unwrap();
+        let peer = make_peer_id();
+        db.record_audit(AuditKind::TrustChanged, Some(&peer), "Unknown → Trusted").unwrap();
+        db.record_audit(AuditKind::DecryptionFailed, Some(&peer), "No session with peer").unwrap();
+        db.record_audit(AuditKind::KeyChanged, None, "rotated key for group 'team'").unwrap();
+
+        let events = db.audit_log(None, None, 10).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].kind, AuditKind::TrustChanged);
+        assert_eq!(events[0].peer_id, Some(peer));
+        assert_eq!(events[2].peer_id, None);
+        let failures = db.audit_log(Some(AuditKind::DecryptionFailed), None, 10).unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(db.audit_log(None, None, 1).unwrap()[0].kind, AuditKind::KeyChanged);
+
+        // Entries can't be changed or removed, and deleting the contact leaves them
+        assert!(db.conn.execute("UPDATE audit_log SET detail = 'nothing'", []).is_err());
+        assert!(db.conn.execute("DELETE FROM audit_log", []).is_err());
+        db.delete_contact(&peer).unwrap();
+        assert_eq!(db.audit_log(None, None, 10).unwrap().len(), 3);
+        assert_eq!(db.verify_audit_log().unwrap(), None);
+    }
+
+    #[test]
+    fn audit_log_edits_break_the_chain() {
+        let db = Database::open_in_memory().unwrap();
+        for detail in ["first", "second", "third"] {
+            db.record_audit(AuditKind::BlockedPeer, None, detail).unwrap();
+        }
+        db.conn.execute("DROP TRIGGER audit_log_no_update", []).unwrap();
+        db.conn
+            .execute("UPDATE audit_log SET detail = 'edited' WHERE detail = 'second'", [])
+            .unwrap();
+        let second = db.audit_log(None, None, 10).unwrap()[1].id;
+        assert_eq!(db.verify_audit_log().unwrap(), Some(second));
+    }
+
+    #[test]
+    fn latest_latency_is_kept() {
+        let db = Database::open_in_memory().unwrap();
+        let peer = make_peer_id();
+        assert_eq!(db.peer_latency(&peer).unwrap(), None);
+
+        let slow = Latency { rtt: std::time::Duration::from_millis(180), relayed: true };
+        let fast = Latency { rtt: std::time::Duration::from_millis(12), relayed: false };
+        db.record_latency(&peer, slow).unwrap();
+        db.record_latency(&peer, fast).unwrap();
+        assert_eq!(db.peer_latency(&peer).unwrap().unwrap().latency, fast);
+
+        db.upsert_contact(&Contact::new(peer, "alice".to_string(), Vec::new())).unwrap();
+        db.delete_contact(&peer).unwrap();
+        assert_eq!(db.peer_latency(&peer).unwrap(), None);
+    }
+
+    #[test]
+    fn latest_peer_version_is_kept() {
+        let db = Database::open_in_memory().unwrap();
+        let peer = make_peer_id();
+        assert_eq!(db.peer_version(&peer).unwrap(), None);
+
+        db.record_peer_version(&peer, "/whisper/1.0.0", "whisper/0.4.0").unwrap();
+        db.record_peer_version(&peer, "/whisper/1.1.0", "whisper/0.5.0").unwrap();
+        let version = db.peer_version(&peer).unwrap().unwrap();
+        assert_eq!(version.protocol_version, "/whisper/1.1.0");
+        assert_eq!(version.agent_version, "whisper/0.5.0");
+
+        db.upsert_contact(&Contact::new(peer, "alice".to_string(), Vec::new())).unwrap();
+        db.delete_contact(&peer).unwrap();
+        assert_eq!(db.peer_version(&peer).unwrap(), None);
+    }
+
+    // === Replay Tests ===
+
+    #[test]
+    fn envelope_seq_rejects_replays() {
+        let db = Database::open_in_memory().unwrap();
+        let (alice, bob) = (make_peer_id(), make_peer_id());
+        let now = seq_now();
+
+        assert!(db.check_envelope_seq(&alice, now).unwrap());
+        assert!(!db.check_envelope_seq(&alice, now).unwrap());
+        // Out of order within the window is fine, once
+        assert!(db.check_envelope_seq(&alice, now - 1_000).unwrap());
+        assert!(!db.check_envelope_seq(&alice, now - 1_000).unwrap());
+        assert!(db.check_envelope_seq(&alice, now + 1_000).unwrap());
+        // Senders are tracked separately
+        assert!(db.check_envelope_seq(&bob, now).unwrap());
+    }
+
+    #[test]
+    fn envelope_seq_window_and_skew() {
+        let db = Database::open_in_memory().unwrap();
+        let alice = make_peer_id();
+        let now = seq_now();
+
+        assert!(!db.check_envelope_seq(&alice, now + 2 * MAX_SEQ_SKEW_MICROS).unwrap());
+        assert!(db.check_envelope_seq(&alice, now).unwrap());
+        assert!(!db.check_envelope_seq(&alice, now - REPLAY_WINDOW_MICROS - 1).unwrap());
+        assert!(db.check_envelope_seq(&alice, now - REPLAY_WINDOW_MICROS + 1).unwrap());
+    }
+
+    #[test]
+    fn unsequenced_envelopes_only_from_legacy_senders() {
+        let db = Database::open_in_memory().unwrap();
+        let alice = make_peer_id();
+
+        assert!(db.check_envelope_seq(&alice, 0).unwrap());
+        assert!(db.check_envelope_seq(&alice, 0).unwrap());
+        assert!(db.check_envelope_seq(&alice, seq_now()).unwrap());
+        assert!(!db.check_envelope_seq(&alice, 0).unwrap());
+    }
+
+    #[test]
+    fn compression_support_is_remembered_per_peer() {
+        let db = Database::open_in_memory().unwrap();
+        let (alice, bob) = (make_peer_id(), make_peer_id());
+
+        assert!(!db.accepts_compression(&alice).unwrap());
+        db.set_accepts_compression(&alice, true).unwrap();
+        db.set_accepts_compression(&alice, true).unwrap();
+        assert!(db.accepts_compression(&alice).unwrap());
+        assert!(!db.accepts_compression(&bob).unwrap());
+        db.set_accepts_compression(&alice, false).unwrap();
+        assert!(!db.accepts_compression(&alice).unwrap());
+    }
+
+    // === Retention Tests ===
+
+    #[test]
+    fn retention_policies_fall_back_to_default() {
+        let db = Database::open_in_memory().unwrap();
+        let alice = Recipient::Direct(make_peer_id());
+        assert!(db.effective_retention_policy(&alice).unwrap().keeps_everything());
+
+        let default = RetentionPolicy { max_age_days: Some(90), max_messages: None };
+        db.set_retention_policy(None, &default).unwrap();
+        assert_eq!(db.effective_retention_policy(&alice).unwrap(), default);
+
+        // An explicit keep-everything overrides the default
+        db.set_retention_policy(Some(&alice), &RetentionPolicy::default()).unwrap();
+        assert!(db.effective_retention_policy(&alice).unwrap().keeps_everything());
+
+        assert!(db.clear_retention_policy(Some(&alice)).unwrap());
+        assert!(!db.clear_retention_policy(Some(&alice)).unwrap());
+        assert_eq!(db.effective_retention_policy(&alice).unwrap(), default);
+    }
+
+    #[test]
+    fn notification_settings_default_and_mutes_expire() {
+        let db = Database::open_in_memory().unwrap();
+        let alice = Recipient::Direct(make_peer_id());
+        let now = Utc::now();
+        let settings = db.notification_settings(&alice).unwrap();
+        assert_eq!(settings, NotificationSettings::default());
+        assert!(settings.notifies(false, now));
+
+        let muted = NotificationSettings {
+            level: NotificationLevel::Mentions,
+            muted: true,
+            muted_until: Some(now + chrono::Duration::hours(8)),
+            alert: Alert::Bell,
+        };
+        db.set_notification_settings(&alice, &muted).unwrap();
+        let stored = db.notification_settings(&alice).unwrap();
+        assert_eq!(stored.muted_until.map(|until| until.timestamp()), Some(muted.muted_until.unwrap().timestamp()));
+        assert!(!stored.notifies(true, now));
+
+        // Once the mute is over, only mentions notify
+        let later = now + chrono::Duration::hours(9);
+        assert!(stored.notifies(true, later));
+        assert!(!stored.notifies(false, later));
+
+        // Muting for good lasts
+        let forever = NotificationSettings { muted_until: None, ..muted };
+        assert!(forever.is_muted(later + chrono::Duration::days(365)));
+    }
+
+    #[test]
+    fn prune_messages_by_age_and_count() {
+        let db = Database::open_in_memory().unwrap();
+        let (me, alice, bob) = (make_peer_id(), make_peer_id(), make_peer_id());
+        db.upsert_contact(&Contact::new(alice, "alice".to_string(), Vec::new())).unwrap();
+        db.upsert_contact(&Contact::new(bob, "bob".to_string(), Vec::new())).unwrap();
+        let now = Utc::now();
+
+        let mut old = Message::new_text(alice, Recipient::Direct(me), "old".to_string());
+        old.timestamp = now - chrono::Duration::days(100);
+        db.insert_message(&old).unwrap();
+        db.mark_message_unverified(&old.id).unwrap();
+        db.insert_message(&Message::new_text(me, Recipient::Direct(alice), "new".to_string())).unwrap();
+        for i in 0..4 {
+            let mut msg = Message::new_text(bob, Recipient::Direct(me), i.to_string());
+            msg.timestamp = now - chrono::Duration::minutes(10 - i);
+            db.insert_message(&msg).unwrap();
+        }
+
+        db.set_retention_policy(None, &RetentionPolicy { max_age_days: Some(90), max_messages: None })
+            .unwrap();
+        db.set_retention_policy(
+            Some(&Recipient::Direct(bob)),
+            &RetentionPolicy { max_age_days: None, max_messages: Some(2) },
+        )
+        .unwrap();
+
+        // A dry run deletes nothing
+        let planned = db.prune_messages(now, true).unwrap();
+        assert_eq!(planned.len(), 2);
+        assert_eq!(db.get_messages_with_peer(&alice, 10).unwrap().len(), 2);
+        assert!(db.deleted_message_ids().unwrap().is_empty());
+
+        let pruned = db.prune_messages(now, false).unwrap();
+        assert_eq!(pruned, planned);
+        let with_alice = db.get_messages_with_peer(&alice, 10).unwrap();
+        assert_eq!(with_alice.len(), 1);
+        assert!(matches!(with_alice[0].content, MessageContent::Text(ref t) if t == "new"));
+        assert!(!db.is_message_unverified(&old.id).unwrap());
+        assert!(db.was_message_deleted(&old.id).unwrap());
+
+        // Bob's newest two are kept
+        let with_bob = db.get_messages_with_peer(&bob, 10).unwrap();
+        let kept: Vec<_> = with_bob.iter().map(|m| m.content.clone()).collect();
+        assert_eq!(kept.len(), 2);
+        assert!(kept.iter().all(|c| matches!(c, MessageContent::Text(t) if t == "2" || t == "3")));
+        assert!(db.prune_messages(now, false).unwrap().is_empty());
+    }
+
+    // === Prekey Tests ===
+
+    #[test]
+    fn prekey_save_and_load() {
+        let db = Database::open_in_memory().unwrap();
+        let kp = libp2p::identity::Keypair::generate_ed25519();
+        assert!(db.latest_prekey().unwrap().is_none());
+
+        let first = crate::identity::generate_signed_prekey(&kp, 1).unwrap();
+        let second = crate::identity::generate_signed_prekey(&kp, 2).unwrap();
+        db.save_prekey(&first).unwrap();
+        db.save_prekey(&second).unwrap();
+
+        let loaded = db.get_prekey(1).unwrap().unwrap();
+        assert_eq!(loaded.secret_key, first.secret_key);
+        assert_eq!(loaded.public(), first.public());
+        assert_eq!(db.latest_prekey().unwrap().unwrap().id, 2);
+        assert!(db.get_prekey(3).unwrap().is_none());
+    }
+
+    #[test]
+    fn contact_prekey_replaced() {
+        let db = Database::open_in_memory().unwrap();
+        let peer = make_peer_id();
+        let kp = libp2p::identity::Keypair::generate_ed25519();
+        assert!(db.get_contact_prekey(&peer).unwrap().is_none());
+
+        let old = crate::identity::generate_signed_prekey(&kp, 1).unwrap().public();
+        let new = crate::identity::generate_signed_prekey(&kp, 2).unwrap().public();
+        db.save_contact_prekey(&peer, &old).unwrap();
+        db.save_contact_prekey(&peer, &new).unwrap();
+        assert_eq!(db.get_contact_prekey(&peer).unwrap(), Some(new));
+    }
+
     // === Pending Queue Tests ===
 
     #[test]
@@ -1290,19 +5773,39 @@ mod tests {
 
         // Queue and close
         {
-            let db = Database::open(&path, "").unwrap();
+            let db = Database::open(&path, "test-key").unwrap();
             db.queue_pending_message(&id, &peer, b"persist me").unwrap();
         }
 
         // Reopen and verify
         {
-            let db = Database::open(&path, "").unwrap();
+            let db = Database::open(&path, "test-key").unwrap();
             let pending = db.get_all_pending().unwrap();
             assert_eq!(pending.len(), 1);
             assert_eq!(pending[0].2, b"persist me");
         }
     }
 
+    #[test]
+    fn open_refuses_unencrypted_databases() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        assert!(Database::open(&path, "").is_err());
+
+        // What we write is encrypted
+        Database::open(&path, "test-key").unwrap();
+        let header = fs::read(&path).unwrap();
+        assert!(!header.starts_with(b"SQLite format 3"));
+
+        // A plaintext file is refused with instructions, not a bad passphrase
+        let plain = dir.path().join("plain.db");
+        Connection::open(&plain).unwrap().execute_batch("CREATE TABLE t (x)").unwrap();
+        let err = Database::open(&plain, "test-key").err().unwrap();
+        assert!(err.to_string().contains("not encrypted"));
+    }
+
     // File transfer tests
 
     #[test]
@@ -1364,7 +5867,7 @@ mod tests {
                 from,
                 Recipient::Direct(to),
                 format!("file{}.txt", i),
-                &vec![0u8; 100],
+                &[0u8; 100],
             );
             db.insert_file_transfer(&transfer).unwrap();
         }
@@ -1384,7 +5887,7 @@ mod tests {
             from,
             Recipient::Direct(to),
             "pending.txt".to_string(),
-            &vec![0u8; 100],
+            &[0u8; 100],
         );
         db.insert_file_transfer(&pending).unwrap();
 
@@ -1393,7 +5896,7 @@ mod tests {
             from,
             Recipient::Direct(to),
             "complete.txt".to_string(),
-            &vec![0u8; 100],
+            &[0u8; 100],
         );
         db.insert_file_transfer(&complete).unwrap();
         db.update_file_transfer(&complete.id, 1, &FileTransferStatus::Complete).unwrap();
@@ -1420,7 +5923,7 @@ mod tests {
             from,
             Recipient::Direct(to),
             "chunk_test.txt".to_string(),
-            &vec![1, 2, 3, 4, 5],
+            &[1, 2, 3, 4, 5],
         );
         db.insert_file_transfer(&transfer).unwrap();
 
@@ -1444,7 +5947,7 @@ mod tests {
             from,
             Recipient::Direct(to),
             "multi_chunk.txt".to_string(),
-            &vec![0u8; 30],
+            &[0u8; 30],
         );
         db.insert_file_transfer(&transfer).unwrap();
 
@@ -1500,7 +6003,7 @@ mod tests {
             from,
             Recipient::Direct(to),
             "delete_me.txt".to_string(),
-            &vec![0u8; 100],
+            &[0u8; 100],
         );
         db.insert_file_transfer(&transfer).unwrap();
 
@@ -1514,4 +6017,78 @@ mod tests {
         // Verify gone
         assert!(db.get_file_transfer(&transfer.id).unwrap().is_none());
     }
+
+    fn blob_db() -> (Database, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open_in_memory().unwrap().with_blob_dir(dir.path().join("blobs"));
+        (db, dir)
+    }
+
+    #[test]
+    fn blobs_round_trip_and_deduplicate() {
+        let (db, dir) = blob_db();
+        let data = vec![42u8; 100_000];
+        let hash = db.put_blob(&data).unwrap();
+        assert_eq!(db.put_blob(&data).unwrap(), hash);
+        assert_eq!(db.get_blob(&hash).unwrap().unwrap(), data);
+        assert_eq!(fs::read_dir(dir.path().join("blobs")).unwrap().count(), 1);
+
+        // Stored encrypted, not as the raw bytes
+        let on_disk = fs::read(dir.path().join("blobs").join(&hash)).unwrap();
+        assert!(!on_disk.windows(64).any(|w| w == &data[..64]));
+
+        // Kept until both references are released
+        assert!(!db.release_blob(&hash).unwrap());
+        assert!(db.get_blob(&hash).unwrap().is_some());
+        assert!(db.release_blob(&hash).unwrap());
+        assert!(db.get_blob(&hash).unwrap().is_none());
+        assert!(!dir.path().join("blobs").join(&hash).exists());
+    }
+
+    #[test]
+    fn blob_hashes_are_keyed_per_database() {
+        let (a, _dir_a) = blob_db();
+        let (b, _dir_b) = blob_db();
+        assert_ne!(a.put_blob(b"same").unwrap(), b.put_blob(b"same").unwrap());
+    }
+
+    #[test]
+    fn corrupted_blob_is_an_error() {
+        let (db, dir) = blob_db();
+        let hash = db.put_blob(b"voice note").unwrap();
+        let path = dir.path().join("blobs").join(&hash);
+        let mut sealed = fs::read(&path).unwrap();
+        sealed[30] ^= 1;
+        fs::write(&path, sealed).unwrap();
+        assert!(db.get_blob(&hash).is_err());
+    }
+
+    #[test]
+    fn in_memory_database_has_no_blob_store() {
+        let db = Database::open_in_memory().unwrap();
+        assert!(db.put_blob(b"data").is_err());
+        assert!(db.get_blob("../whisper.db").is_err());
+    }
+
+    #[test]
+    fn completed_file_moves_into_blob() {
+        let (db, _dir) = blob_db();
+        let original = b"Hello, World! This is a test file.";
+        let transfer = FileTransfer::new_outgoing(
+            make_peer_id(),
+            Recipient::Direct(make_peer_id()),
+            "blob.txt".to_string(),
+            original,
+        );
+        db.insert_file_transfer(&transfer).unwrap();
+        db.insert_file_chunk(&FileChunk::new(transfer.id, 0, 1, original.to_vec())).unwrap();
+
+        let hash = db.store_file_blob(&transfer.id, original).unwrap();
+        assert!(db.get_file_chunks(&transfer.id).unwrap().is_empty());
+        assert_eq!(db.file_blob(&transfer.id).unwrap(), Some(hash.clone()));
+        assert_eq!(db.reassemble_file(&transfer.id).unwrap(), original);
+
+        db.delete_file_transfer(&transfer.id).unwrap();
+        assert!(db.get_blob(&hash).unwrap().is_none());
+    }
 }
@@ -1,48 +1,243 @@
 //! Database encryption with Argon2 key derivation.
+//!
+//! The salt file records the Argon2 parameters the key was derived with,
+//! as `m=<KiB>,t=<iterations>,p=<lanes>$<salt>`, so a database made on a
+//! fast machine opens with the same work on a slow one. Salt files from
+//! before that hold the bare salt, and use the default parameters.
 
 use std::fs;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
-    Argon2,
+    Algorithm, Argon2, Params, Version,
 };
+use rusqlite::Connection;
+use zeroize::Zeroizing;
 
 const SALT_FILE: &str = ".whisper.salt";
 
+/// Where a rekey keeps the new salt, and the files to replace along with
+/// it, until the database has taken its new key.
+const REKEY_DIR: &str = ".whisper.rekey";
+
+/// Most memory calibration will ask for, in KiB.
+const MAX_CALIBRATED_MEMORY_KIB: u32 = 256 * 1024;
+
+/// How long `whisper init --kdf-benchmark` aims for a derivation to take.
+pub const KDF_TARGET: Duration = Duration::from_millis(500);
+
+/// Argon2id cost parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory in KiB.
+    pub memory_kib: u32,
+    /// Passes over the memory.
+    pub iterations: u32,
+    /// Lanes.
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// The argon2 crate's defaults, which every database used before the
+    /// parameters were recorded.
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl std::fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "m={},t={},p={}", self.memory_kib, self.iterations, self.parallelism)
+    }
+}
+
+impl std::str::FromStr for KdfParams {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut params = Self::default();
+        for field in s.split(',') {
+            let (name, value) = field
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Invalid Argon2 parameter '{}'", field))?;
+            let value: u32 = value
+                .parse()
+                .with_context(|| format!("Invalid Argon2 parameter '{}'", field))?;
+            match name {
+                "m" => params.memory_kib = value,
+                "t" => params.iterations = value,
+                "p" => params.parallelism = value,
+                _ => bail!("Unknown Argon2 parameter '{}'", name),
+            }
+        }
+        params.argon2()?;
+        Ok(params)
+    }
+}
+
+impl KdfParams {
+    /// An Argon2id hasher with these parameters, if they're in range.
+    fn argon2(&self) -> Result<Argon2<'static>> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters {}: {}", self, e))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+/// Read the salt file: the salt and the parameters to use with it.
+fn read_salt_file(salt_path: &Path) -> Result<(SaltString, KdfParams)> {
+    let contents = fs::read_to_string(salt_path).context("Failed to read salt file")?;
+    let (params, salt) = match contents.trim().split_once('$') {
+        Some((params, salt)) => (params.parse().context("Invalid salt file")?, salt),
+        None => (KdfParams::default(), contents.trim()),
+    };
+    let salt = SaltString::from_b64(salt).map_err(|e| anyhow::anyhow!("Invalid salt file: {}", e))?;
+    Ok((salt, params))
+}
+
+/// Start a data directory's key derivation with a new salt and `params`.
+/// Fails if it already has a salt, since replacing it would lock the
+/// database.
+pub fn create_salt_file(data_dir: &Path, params: &KdfParams) -> Result<()> {
+    params.argon2()?;
+    let salt_path = data_dir.join(SALT_FILE);
+    if salt_path.exists() {
+        bail!("Salt file {} already exists", salt_path.display());
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    fs::create_dir_all(data_dir)?;
+    fs::write(&salt_path, format!("{}${}", params, salt.as_str())).context("Failed to write salt file")
+}
+
+/// The parameters a data directory's database key is derived with, if it
+/// has one yet.
+pub fn kdf_params(data_dir: &Path) -> Result<Option<KdfParams>> {
+    let salt_path = data_dir.join(SALT_FILE);
+    if !salt_path.exists() {
+        return Ok(None);
+    }
+    read_salt_file(&salt_path).map(|(_, params)| Some(params))
+}
+
+/// Find parameters that take about `target` to derive a key with here:
+/// double the memory while that stays under half the target, then set the
+/// iterations to make up the rest. Never less than the defaults' memory.
+pub fn calibrate_kdf(target: Duration) -> Result<KdfParams> {
+    let time = |params: &KdfParams| -> Result<Duration> {
+        let salt = SaltString::generate(&mut OsRng);
+        let start = Instant::now();
+        params
+            .argon2()?
+            .hash_password(b"calibration", &salt)
+            .map_err(|e| anyhow::anyhow!("Failed to derive key: {}", e))?;
+        Ok(start.elapsed())
+    };
+    let mut params = KdfParams { iterations: 1, ..KdfParams::default() };
+    let mut elapsed = time(&params)?;
+    while elapsed * 2 <= target && params.memory_kib * 2 <= MAX_CALIBRATED_MEMORY_KIB {
+        params.memory_kib *= 2;
+        elapsed = time(&params)?;
+    }
+    let passes = target.as_secs_f64() / elapsed.as_secs_f64().max(f64::EPSILON);
+    params.iterations = (passes.round() as u32).max(1);
+    Ok(params)
+}
+
+/// The first bytes of every unencrypted SQLite file. SQLCipher files start
+/// with random salt instead.
+const PLAINTEXT_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
 /// Derive a database encryption key from a passphrase using Argon2.
 /// 
-/// If a salt file exists in the data directory, uses that salt.
-/// If not, creates a new salt file (for first-run).
-pub fn derive_database_key(passphrase: &str, data_dir: &Path) -> Result<String> {
+/// If a salt file exists in the data directory, uses that salt and the
+/// parameters recorded with it. If not, creates a new salt file with the
+/// default parameters (for first-run).
+pub fn derive_database_key(passphrase: &str, data_dir: &Path) -> Result<Zeroizing<String>> {
     if passphrase.is_empty() {
         bail!("Passphrase cannot be empty. Database encryption is required.");
     }
 
     let salt_path = data_dir.join(SALT_FILE);
     
-    let salt = if salt_path.exists() {
-        // Load existing salt
-        let salt_str = fs::read_to_string(&salt_path)
-            .context("Failed to read salt file")?;
-        SaltString::from_b64(&salt_str)
-            .map_err(|e| anyhow::anyhow!("Invalid salt file: {}", e))?
-    } else {
+    if !salt_path.exists() {
         // Generate new salt for first-run
-        let salt = SaltString::generate(&mut OsRng);
-        fs::create_dir_all(data_dir)?;
-        fs::write(&salt_path, salt.as_str())
-            .context("Failed to write salt file")?;
-        salt
-    };
+        create_salt_file(data_dir, &KdfParams::default())?;
+    }
+    let (salt, params) = read_salt_file(&salt_path)?;
+    derive_key_with(passphrase, &salt, &params)
+}
 
-    // Use Argon2id with recommended parameters
-    let argon2 = Argon2::default();
+/// Derive a database key for the rekey `prepare_rekey` started, with its
+/// new salt.
+pub fn derive_rekeyed_key(passphrase: &str, data_dir: &Path) -> Result<Zeroizing<String>> {
+    if passphrase.is_empty() {
+        bail!("Passphrase cannot be empty. Database encryption is required.");
+    }
+    let (salt, params) = read_salt_file(&data_dir.join(REKEY_DIR).join(SALT_FILE))?;
+    derive_key_with(passphrase, &salt, &params)
+}
+
+/// Start rekeying a data directory's database: make a new salt for
+/// `params`, kept aside until `finish_rekey` puts it in place. Anything
+/// left from a rekey that never reached the database is dropped.
+pub fn prepare_rekey(data_dir: &Path, params: &KdfParams) -> Result<()> {
+    params.argon2()?;
+    abandon_rekey(data_dir);
+    let dir = data_dir.join(REKEY_DIR);
+    fs::create_dir_all(&dir).context("Failed to create rekey directory")?;
+    let salt = SaltString::generate(&mut OsRng);
+    fs::write(dir.join(SALT_FILE), format!("{}${}", params, salt.as_str())).context("Failed to write salt file")
+}
+
+/// Where to write a new version of the data directory's file `name` that
+/// only works with the new key, so `finish_rekey` puts it in place with
+/// the salt.
+pub fn staged_for_rekey(data_dir: &Path, name: &str) -> PathBuf {
+    data_dir.join(REKEY_DIR).join(name)
+}
+
+/// Whether a rekey has been started and not finished or abandoned. If the
+/// database only opens with the new salt, it got as far as rekeying it.
+pub fn rekey_pending(data_dir: &Path) -> bool {
+    data_dir.join(REKEY_DIR).join(SALT_FILE).exists()
+}
+
+/// Put the files `prepare_rekey` and `staged_for_rekey` set aside in
+/// place, once the database has been rekeyed. The salt goes last, so a
+/// rekey cut off part way is still pending and can be finished again.
+pub fn finish_rekey(data_dir: &Path) -> Result<()> {
+    let dir = data_dir.join(REKEY_DIR);
+    for entry in fs::read_dir(&dir).context("Failed to read rekey directory")? {
+        let name = entry?.file_name();
+        if name != SALT_FILE {
+            fs::rename(dir.join(&name), data_dir.join(&name))
+                .with_context(|| format!("Failed to replace {}", name.to_string_lossy()))?;
+        }
+    }
+    fs::rename(dir.join(SALT_FILE), data_dir.join(SALT_FILE)).context("Failed to replace salt file")?;
+    let _ = fs::remove_dir(&dir);
+    Ok(())
+}
+
+/// Drop what was set aside for a rekey that didn't happen.
+pub fn abandon_rekey(data_dir: &Path) {
+    let _ = fs::remove_dir_all(data_dir.join(REKEY_DIR));
+}
+
+fn derive_key_with(passphrase: &str, salt: &SaltString, params: &KdfParams) -> Result<Zeroizing<String>> {
+    let argon2 = params.argon2()?;
     
     // Hash the passphrase with the salt
     let password_hash = argon2
-        .hash_password(passphrase.as_bytes(), &salt)
+        .hash_password(passphrase.as_bytes(), salt)
         .map_err(|e| anyhow::anyhow!("Failed to derive key: {}", e))?;
     
     // Extract the raw hash output for use as the database key
@@ -51,10 +246,46 @@ pub fn derive_database_key(passphrase: &str, data_dir: &Path) -> Result<String>
     
     // Convert to hex string for SQLCipher (it expects a string key)
     let key_bytes = hash_output.as_bytes();
-    let hex_key = hex::encode(key_bytes);
+    let hex_key = Zeroizing::new(hex::encode(key_bytes));
     
     // SQLCipher wants the key prefixed with x'' for hex input
-    Ok(format!("x'{}'", hex_key))
+    Ok(Zeroizing::new(format!("x'{}'", hex_key.as_str())))
+}
+
+/// Check that the connection is backed by SQLCipher. Plain SQLite accepts
+/// `PRAGMA key` and silently ignores it, so this is the only way to tell.
+pub fn ensure_sqlcipher(conn: &Connection) -> Result<()> {
+    let version: Option<String> = conn
+        .query_row("PRAGMA cipher_version", [], |row| row.get(0))
+        .ok();
+    match version {
+        Some(version) if !version.is_empty() => Ok(()),
+        _ => bail!(
+            "Database encryption is not available: this build of whisper is linked \
+             against plain SQLite, not SQLCipher. Rebuild with the default \
+             `bundled-sqlcipher` feature (cargo build --release)."
+        ),
+    }
+}
+
+/// Check that the database file at `path` isn't readable as plain SQLite.
+/// A file that doesn't exist yet or is still empty passes.
+pub fn ensure_encrypted_file(path: &Path) -> Result<()> {
+    let mut header = [0u8; 16];
+    let read = match fs::File::open(path) {
+        Ok(mut file) => file.read(&mut header).context("Failed to read database file")?,
+        Err(_) => return Ok(()),
+    };
+    if read == header.len() && &header == PLAINTEXT_HEADER {
+        bail!(
+            "Database {} is not encrypted: it can be read without your passphrase. \
+             It was created by a build without SQLCipher. Move the file aside (the \
+             sqlite3 tool can still read it) and run whisper again to create an \
+             encrypted database, then securely delete the old file.",
+            path.display()
+        );
+    }
+    Ok(())
 }
 
 /// Check if a database exists and is encrypted.
@@ -108,6 +339,73 @@ mod tests {
         assert_eq!(key1, key2);
     }
 
+    #[test]
+    fn salt_file_records_the_parameters() {
+        let temp = TempDir::new().unwrap();
+        let params = KdfParams { memory_kib: 8 * 1024, iterations: 3, parallelism: 1 };
+        create_salt_file(temp.path(), &params).unwrap();
+        assert_eq!(kdf_params(temp.path()).unwrap(), Some(params));
+        assert!(create_salt_file(temp.path(), &params).is_err());
+
+        // The same salt with other parameters gives another key
+        let key = derive_database_key("test_passphrase", temp.path()).unwrap();
+        let (salt, _) = read_salt_file(&temp.path().join(SALT_FILE)).unwrap();
+        fs::write(temp.path().join(SALT_FILE), salt.as_str()).unwrap();
+        assert_eq!(kdf_params(temp.path()).unwrap(), Some(KdfParams::default()));
+        assert_ne!(derive_database_key("test_passphrase", temp.path()).unwrap(), key);
+
+        assert!("m=1,t=1,p=1".parse::<KdfParams>().is_err());
+        assert!("m=8192,x=1".parse::<KdfParams>().is_err());
+    }
+
+    #[test]
+    fn rekey_salt_replaces_the_old_one_when_finished() {
+        let temp = TempDir::new().unwrap();
+        let old = derive_database_key("test_passphrase", temp.path()).unwrap();
+
+        prepare_rekey(temp.path(), &KdfParams::default()).unwrap();
+        let new = derive_rekeyed_key("test_passphrase", temp.path()).unwrap();
+        assert_ne!(new, old);
+        assert_eq!(derive_database_key("test_passphrase", temp.path()).unwrap(), old);
+
+        finish_rekey(temp.path()).unwrap();
+        assert_eq!(derive_database_key("test_passphrase", temp.path()).unwrap(), new);
+        assert!(derive_rekeyed_key("test_passphrase", temp.path()).is_err());
+        assert!(!rekey_pending(temp.path()));
+    }
+
+    #[test]
+    fn staged_files_are_put_in_place_before_the_salt() {
+        let temp = TempDir::new().unwrap();
+        derive_database_key("test_passphrase", temp.path()).unwrap();
+        fs::write(temp.path().join("identity.key"), "old").unwrap();
+
+        // What an earlier rekey left is dropped
+        prepare_rekey(temp.path(), &KdfParams::default()).unwrap();
+        fs::write(staged_for_rekey(temp.path(), "stale"), "stale").unwrap();
+        prepare_rekey(temp.path(), &KdfParams::default()).unwrap();
+        let new = derive_rekeyed_key("test_passphrase", temp.path()).unwrap();
+        fs::write(staged_for_rekey(temp.path(), "identity.key"), "new").unwrap();
+        assert!(rekey_pending(temp.path()));
+
+        // Cut off after the staged file moved: the salt is still pending
+        fs::rename(staged_for_rekey(temp.path(), "identity.key"), temp.path().join("identity.key")).unwrap();
+        assert!(rekey_pending(temp.path()));
+        finish_rekey(temp.path()).unwrap();
+
+        assert!(!rekey_pending(temp.path()));
+        assert_eq!(fs::read_to_string(temp.path().join("identity.key")).unwrap(), "new");
+        assert!(!temp.path().join("stale").exists());
+        assert_eq!(derive_database_key("test_passphrase", temp.path()).unwrap(), new);
+    }
+
+    #[test]
+    fn calibration_never_goes_below_the_default_memory() {
+        let params = calibrate_kdf(Duration::from_millis(1)).unwrap();
+        assert_eq!(params.memory_kib, KdfParams::default().memory_kib);
+        assert!(params.iterations >= 1);
+    }
+
     #[test]
     fn derive_key_different_passphrases_different_keys() {
         let temp = TempDir::new().unwrap();
@@ -129,6 +427,34 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn sqlcipher_is_linked() {
+        let conn = Connection::open_in_memory().unwrap();
+        ensure_sqlcipher(&conn).unwrap();
+    }
+
+    #[test]
+    fn plaintext_database_files_are_rejected() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("plain.db");
+        ensure_encrypted_file(&path).unwrap();
+
+        // An unkeyed connection writes a plain SQLite file
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        drop(conn);
+        let err = ensure_encrypted_file(&path).unwrap_err();
+        assert!(err.to_string().contains("not encrypted"));
+
+        // A keyed one doesn't
+        let path = temp.path().join("encrypted.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.pragma_update(None, "key", "secret").unwrap();
+        conn.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        drop(conn);
+        ensure_encrypted_file(&path).unwrap();
+    }
+
     #[test]
     fn is_first_run_detects_salt_file() {
         let temp = TempDir::new().unwrap();
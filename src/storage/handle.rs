@@ -0,0 +1,142 @@
+//! Storage on its own thread.
+//!
+//! rusqlite is synchronous, so calling `Database` from async code blocks the
+//! task, and the runtime thread under it, for as long as the query takes.
+//! `DatabaseHandle` moves the database onto a dedicated thread and sends it
+//! work; callers await the result instead. Handles are cheap to clone and
+//! can be shared between tasks. The thread exits when the last one is
+//! dropped.
+
+use std::sync::mpsc;
+use std::thread;
+
+use anyhow::{anyhow, Context, Result};
+use libp2p::PeerId;
+use tokio::sync::oneshot;
+
+use super::Database;
+use crate::identity::Contact;
+use crate::message::Message;
+
+/// Work queued for the storage thread.
+type Job = Box<dyn FnOnce(&Database) + Send>;
+
+/// Async, cloneable access to a `Database` running on its own thread.
+///
+/// Jobs run one at a time in the order they were sent, so a closure passed
+/// to `call` sees a consistent database for its whole run.
+#[derive(Clone)]
+pub struct DatabaseHandle {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl DatabaseHandle {
+    /// Move `db` onto a new storage thread.
+    pub fn spawn(db: Database) -> Result<Self> {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        thread::Builder::new()
+            .name("whisper-storage".to_string())
+            .spawn(move || {
+                for job in queue {
+                    job(&db);
+                }
+            })
+            .context("Failed to start storage thread")?;
+        Ok(Self { jobs })
+    }
+
+    /// Run `f` against the database on the storage thread.
+    pub async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Database) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |db| {
+                // The caller may have stopped waiting
+                let _ = reply.send(f(db));
+            }))
+            .map_err(|_| anyhow!("Storage thread has stopped"))?;
+        result.await.map_err(|_| anyhow!("Storage thread has stopped"))?
+    }
+
+    /// Wait for the work sent so far to finish, so nothing queued is lost
+    /// when the process exits.
+    pub async fn flush(&self) -> Result<()> {
+        self.call(|_| Ok(())).await
+    }
+
+    /// List all contacts.
+    pub async fn list_contacts(&self) -> Result<Vec<Contact>> {
+        self.call(|db| db.list_contacts()).await
+    }
+
+    /// Get a contact by peer ID.
+    pub async fn get_contact(&self, peer_id: PeerId) -> Result<Option<Contact>> {
+        self.call(move |db| db.get_contact(&peer_id)).await
+    }
+
+    /// Add or update a contact.
+    pub async fn upsert_contact(&self, contact: Contact) -> Result<()> {
+        self.call(move |db| db.upsert_contact(&contact)).await
+    }
+
+    /// Recent messages exchanged with a peer, newest first.
+    pub async fn get_messages_with_peer(&self, peer_id: PeerId, limit: usize) -> Result<Vec<Message>> {
+        self.call(move |db| db.get_messages_with_peer(&peer_id, limit)).await
+    }
+
+    /// Store a message.
+    pub async fn insert_message(&self, msg: Message) -> Result<()> {
+        self.call(move |db| db.insert_message(&msg)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Recipient;
+
+    fn handle() -> DatabaseHandle {
+        DatabaseHandle::spawn(Database::open_in_memory().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn calls_run_against_the_database() {
+        let db = handle();
+        let peer = PeerId::random();
+        db.upsert_contact(Contact::new(peer, "alice".to_string(), Vec::new())).await.unwrap();
+        assert_eq!(db.get_contact(peer).await.unwrap().unwrap().alias, "alice");
+
+        // Errors from the database come back to the caller
+        db.upsert_contact(Contact::new(PeerId::random(), "bob".to_string(), Vec::new())).await.unwrap();
+        assert!(db.call(move |db| db.rename_contact(&peer, "bob")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn clones_share_one_database_across_tasks() {
+        let db = handle();
+        let peer = PeerId::random();
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    let msg = Message::new_text(peer, Recipient::Direct(peer), format!("message {}", i));
+                    db.insert_message(msg).await.unwrap();
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(db.get_messages_with_peer(peer, 100).await.unwrap().len(), 8);
+    }
+
+    #[tokio::test]
+    async fn panicking_job_stops_the_thread() {
+        let db = handle();
+        assert!(db.call(|_| -> Result<()> { panic!("boom") }).await.is_err());
+        assert!(db.list_contacts().await.is_err());
+    }
+}
@@ -0,0 +1,225 @@
+-- Migration 1: the schema as it stood before numbered migrations.
+-- Every statement is idempotent, so it also applies to databases that
+-- were created from the old single schema file.
+
+CREATE TABLE IF NOT EXISTS messages (
+    id TEXT PRIMARY KEY,
+    from_peer TEXT NOT NULL,
+    to_peer TEXT NOT NULL,
+    content BLOB NOT NULL,
+    timestamp INTEGER NOT NULL,
+    status TEXT NOT NULL
+);
+
+-- Full-text index over text message bodies, kept in sync by triggers.
+-- Message content is stored as JSON, e.g. {"Text":"hello"}.
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+    message_id UNINDEXED,
+    body
+);
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages
+WHEN json_extract(CAST(new.content AS TEXT), '$.Text') IS NOT NULL
+BEGIN
+    INSERT INTO messages_fts (message_id, body)
+    VALUES (new.id, json_extract(CAST(new.content AS TEXT), '$.Text'));
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages
+BEGIN
+    DELETE FROM messages_fts WHERE message_id = old.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages
+BEGIN
+    DELETE FROM messages_fts WHERE message_id = old.id;
+    INSERT INTO messages_fts (message_id, body)
+    SELECT new.id, json_extract(CAST(new.content AS TEXT), '$.Text')
+    WHERE json_extract(CAST(new.content AS TEXT), '$.Text') IS NOT NULL;
+END;
+
+-- Index messages stored before the FTS table existed
+INSERT INTO messages_fts (message_id, body)
+SELECT id, json_extract(CAST(content AS TEXT), '$.Text') FROM messages
+WHERE json_extract(CAST(content AS TEXT), '$.Text') IS NOT NULL
+  AND id NOT IN (SELECT message_id FROM messages_fts);
+
+-- Received messages whose sender couldn't be verified from a signature.
+CREATE TABLE IF NOT EXISTS unverified_messages (
+    message_id TEXT PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS contacts (
+    peer_id TEXT PRIMARY KEY,
+    alias TEXT UNIQUE NOT NULL,
+    public_key BLOB NOT NULL,
+    trust_level TEXT NOT NULL,
+    last_seen INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS contact_notes (
+    peer_id TEXT PRIMARY KEY,
+    note TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS contact_tags (
+    peer_id TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (peer_id, tag)
+);
+
+CREATE TABLE IF NOT EXISTS contact_settings (
+    peer_id TEXT PRIMARY KEY,
+    read_receipts INTEGER NOT NULL DEFAULT 1
+);
+
+-- When each conversation was last viewed, in seconds, keyed by the
+-- contact's peer ID or the group's ID. Later messages are unread.
+CREATE TABLE IF NOT EXISTS conversation_reads (
+    conversation TEXT PRIMARY KEY,
+    last_read_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS groups (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    description TEXT,
+    owner_peer_id TEXT,
+    symmetric_key BLOB NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS group_members (
+    group_id TEXT NOT NULL,
+    peer_id TEXT NOT NULL,
+    role TEXT NOT NULL DEFAULT 'member',
+    PRIMARY KEY (group_id, peer_id)
+);
+
+-- Keys a group has used; groups.symmetric_key holds the newest.
+-- Epoch 0 is the key the group was created with.
+CREATE TABLE IF NOT EXISTS group_keys (
+    group_id TEXT NOT NULL,
+    epoch INTEGER NOT NULL,
+    symmetric_key BLOB NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (group_id, epoch)
+);
+
+-- Group messages sent under a key that has since been rotated out.
+CREATE TABLE IF NOT EXISTS group_message_epochs (
+    message_id TEXT PRIMARY KEY,
+    group_id TEXT NOT NULL,
+    epoch INTEGER NOT NULL
+);
+
+-- Members who left a group; their later messages are dropped.
+CREATE TABLE IF NOT EXISTS group_departures (
+    group_id TEXT NOT NULL,
+    peer_id TEXT NOT NULL,
+    left_at INTEGER NOT NULL,
+    PRIMARY KEY (group_id, peer_id)
+);
+
+-- When our view of a group's metadata last changed, in milliseconds.
+-- Synced metadata older than this is ignored.
+CREATE TABLE IF NOT EXISTS group_sync_state (
+    group_id TEXT PRIMARY KEY,
+    updated_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS group_invites (
+    group_id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    from_peer TEXT NOT NULL,
+    symmetric_key BLOB NOT NULL,
+    received_at INTEGER NOT NULL
+);
+
+-- Direct messages from peers who aren't contacts, held until the user
+-- accepts or declines them. Same columns as messages.
+CREATE TABLE IF NOT EXISTS message_requests (
+    id TEXT PRIMARY KEY,
+    from_peer TEXT NOT NULL,
+    to_peer TEXT NOT NULL,
+    content BLOB NOT NULL,
+    timestamp INTEGER NOT NULL,
+    status TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS pending_messages (
+    id TEXT PRIMARY KEY,
+    to_peer TEXT NOT NULL,
+    encrypted_data BLOB NOT NULL,
+    created_at INTEGER NOT NULL,
+    attempts INTEGER DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    peer_id TEXT PRIMARY KEY,
+    state BLOB NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+-- Envelope sequence numbers seen from each sender within the replay
+-- window. The largest is the sender's high-water mark and is never pruned.
+CREATE TABLE IF NOT EXISTS envelope_seqs (
+    peer_id TEXT NOT NULL,
+    seq INTEGER NOT NULL,
+    PRIMARY KEY (peer_id, seq)
+);
+
+CREATE TABLE IF NOT EXISTS node_state (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS prekeys (
+    id INTEGER PRIMARY KEY,
+    public_key BLOB NOT NULL,
+    secret_key BLOB NOT NULL,
+    signature BLOB NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS contact_prekeys (
+    peer_id TEXT PRIMARY KEY,
+    prekey_id INTEGER NOT NULL,
+    public_key BLOB NOT NULL,
+    signature BLOB NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_messages_from ON messages(from_peer);
+CREATE INDEX IF NOT EXISTS idx_messages_to ON messages(to_peer);
+CREATE INDEX IF NOT EXISTS idx_messages_timestamp ON messages(timestamp);
+CREATE INDEX IF NOT EXISTS idx_pending_to ON pending_messages(to_peer);
+
+-- File transfer tables
+
+CREATE TABLE IF NOT EXISTS file_transfers (
+    id TEXT PRIMARY KEY,
+    from_peer TEXT NOT NULL,
+    to_peer TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    total_size INTEGER NOT NULL,
+    total_chunks INTEGER NOT NULL,
+    chunks_received INTEGER DEFAULT 0,
+    status TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    file_checksum BLOB NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS file_chunks (
+    transfer_id TEXT NOT NULL,
+    chunk_index INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    checksum BLOB NOT NULL,
+    received_at INTEGER NOT NULL,
+    PRIMARY KEY (transfer_id, chunk_index),
+    FOREIGN KEY (transfer_id) REFERENCES file_transfers(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_file_transfers_status ON file_transfers(status);
+CREATE INDEX IF NOT EXISTS idx_file_transfers_from ON file_transfers(from_peer);
+CREATE INDEX IF NOT EXISTS idx_file_transfers_to ON file_transfers(to_peer);
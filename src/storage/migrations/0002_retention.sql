@@ -0,0 +1,10 @@
+-- Migration 2: message retention.
+
+-- How long messages are kept, keyed by a contact's peer ID or a group's
+-- ID. The row keyed '*' is the default for conversations without their
+-- own. A NULL limit means no limit.
+CREATE TABLE retention_policies (
+    conversation TEXT PRIMARY KEY,
+    max_age_days INTEGER,
+    max_messages INTEGER
+);
@@ -0,0 +1,17 @@
+-- Migration 3: blob store.
+
+-- Large payloads kept on disk as blobs/<hash> rather than in the
+-- database. ref_count is how many things use the blob, so identical
+-- content is stored once and removed when nothing needs it.
+CREATE TABLE blobs (
+    hash TEXT PRIMARY KEY,
+    size INTEGER NOT NULL,
+    ref_count INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+-- The blob holding each completed file transfer's contents.
+CREATE TABLE file_blobs (
+    transfer_id TEXT PRIMARY KEY,
+    hash TEXT NOT NULL
+);
@@ -0,0 +1,12 @@
+-- Migration 4: remembered peer addresses.
+
+-- Where each peer has been seen, so they can be dialed again after a
+-- restart. last_success is when we last connected through the address,
+-- NULL if we never have; those addresses are tried first.
+CREATE TABLE peer_addresses (
+    peer_id TEXT NOT NULL,
+    address TEXT NOT NULL,
+    last_seen INTEGER NOT NULL,
+    last_success INTEGER,
+    PRIMARY KEY (peer_id, address)
+);
@@ -0,0 +1,6 @@
+-- Migration 5: contact presence.
+
+-- The status a contact last announced (online, away or offline) and
+-- when. NULL until they announce one.
+ALTER TABLE contacts ADD COLUMN presence TEXT;
+ALTER TABLE contacts ADD COLUMN presence_at INTEGER;
@@ -0,0 +1,10 @@
+-- Migration 6: peer latency.
+
+-- The last round trip measured to each peer, and whether it went through
+-- a relay, so `whisper peers` can show it after the session ends.
+CREATE TABLE peer_latency (
+    peer_id TEXT PRIMARY KEY,
+    rtt_ms INTEGER NOT NULL,
+    relayed INTEGER NOT NULL,
+    measured_at INTEGER NOT NULL
+);
@@ -0,0 +1,11 @@
+-- Migration 7: traffic statistics.
+
+-- Bytes exchanged with each peer over each protocol (messages, files,
+-- rendezvous, ping), added up across sessions.
+CREATE TABLE traffic (
+    peer_id TEXT NOT NULL,
+    protocol TEXT NOT NULL,
+    bytes_sent INTEGER NOT NULL,
+    bytes_received INTEGER NOT NULL,
+    PRIMARY KEY (peer_id, protocol)
+);
@@ -0,0 +1,24 @@
+-- Migration 8: audit log.
+
+-- Security-relevant events: key changes, failed decryptions, messages from
+-- blocked peers and trust level changes. Entries are never changed or
+-- removed; each one's hash covers the previous entry's, so `whisper audit`
+-- can tell if rows were altered by something that dropped the triggers.
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    at INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    peer_id TEXT,
+    detail TEXT NOT NULL,
+    hash BLOB NOT NULL
+);
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit log is append-only');
+END;
+
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit log is append-only');
+END;
@@ -0,0 +1,28 @@
+-- Migration 9: mailboxes.
+
+-- Contacts we leave messages with while the peer they're for is offline.
+CREATE TABLE mailbox_peers (
+    peer_id TEXT PRIMARY KEY,
+    added_at INTEGER NOT NULL
+);
+
+-- Which mailboxes each queued message has been left with, so it's left
+-- with each only once.
+CREATE TABLE mailbox_deposits (
+    message_id TEXT NOT NULL,
+    mailbox TEXT NOT NULL,
+    PRIMARY KEY (message_id, mailbox)
+);
+
+-- Messages we hold for others as a mailbox, still sealed for the peer
+-- they're addressed to.
+CREATE TABLE mailbox (
+    id TEXT PRIMARY KEY,
+    from_peer TEXT NOT NULL,
+    to_peer TEXT NOT NULL,
+    data BLOB NOT NULL,
+    received_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+
+CREATE INDEX idx_mailbox_to ON mailbox(to_peer);
@@ -0,0 +1,18 @@
+-- Migration 10: linked devices.
+
+-- The newest signed device list each identity has announced, ours
+-- included, as JSON.
+CREATE TABLE device_lists (
+    identity TEXT PRIMARY KEY,
+    list BLOB NOT NULL,
+    issued_at INTEGER NOT NULL
+);
+
+-- The devices on those lists, to tell whose device a peer is.
+CREATE TABLE devices (
+    device TEXT PRIMARY KEY,
+    identity TEXT NOT NULL,
+    name TEXT NOT NULL
+);
+
+CREATE INDEX idx_devices_identity ON devices(identity);
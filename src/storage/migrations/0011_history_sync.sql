@@ -0,0 +1,8 @@
+-- Migration 11: history sync with contacts.
+
+-- When each contact last answered a history request, so the next one
+-- asks only for what's newer.
+CREATE TABLE history_sync (
+    peer_id TEXT PRIMARY KEY,
+    synced_at INTEGER NOT NULL
+);
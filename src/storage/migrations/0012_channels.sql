@@ -0,0 +1,28 @@
+-- Migration 12: broadcast channels.
+
+-- Channels we own or subscribe to.
+CREATE TABLE channels (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+-- Who subscribes to the channels we own.
+CREATE TABLE channel_subscribers (
+    channel_id TEXT NOT NULL,
+    peer_id TEXT NOT NULL,
+    subscribed_at INTEGER NOT NULL,
+    PRIMARY KEY (channel_id, peer_id)
+);
+
+-- Posts, with the owner's signature so they can be checked again.
+CREATE TABLE channel_posts (
+    id TEXT PRIMARY KEY,
+    channel_id TEXT NOT NULL,
+    text TEXT NOT NULL,
+    posted_at INTEGER NOT NULL,
+    signature BLOB NOT NULL
+);
+
+CREATE INDEX idx_channel_posts_channel ON channel_posts(channel_id, posted_at);
@@ -0,0 +1,10 @@
+-- Migration 13: contact cards.
+
+-- Contacts introduced to us by someone else, waiting to be added.
+CREATE TABLE contact_cards (
+    peer_id TEXT PRIMARY KEY,
+    alias TEXT NOT NULL,
+    public_key BLOB NOT NULL,
+    from_peer TEXT NOT NULL,
+    received_at INTEGER NOT NULL
+);
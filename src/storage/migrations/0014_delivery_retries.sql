@@ -0,0 +1,7 @@
+-- Migration 14: delivery retries.
+
+-- When to next try to reach a queued message's recipient, NULL to try
+-- straight away, and when it was given up on after too many attempts.
+-- Messages given up on stay queued but are no longer sent.
+ALTER TABLE pending_messages ADD COLUMN next_attempt_at INTEGER;
+ALTER TABLE pending_messages ADD COLUMN dead_at INTEGER;
@@ -0,0 +1,6 @@
+-- Migration 15: compression.
+
+-- Peers whose envelopes say they can decode zstd-packed envelopes.
+CREATE TABLE compression_peers (
+    peer_id TEXT PRIMARY KEY
+);
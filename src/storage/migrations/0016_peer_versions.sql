@@ -0,0 +1,9 @@
+-- Migration 16: peer versions.
+
+-- What each peer last told us it runs over identify, for `whisper peers`.
+CREATE TABLE peer_versions (
+    peer_id TEXT PRIMARY KEY,
+    protocol_version TEXT NOT NULL,
+    agent_version TEXT NOT NULL,
+    seen_at INTEGER NOT NULL
+);
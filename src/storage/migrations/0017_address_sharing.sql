@@ -0,0 +1,5 @@
+-- Migration 17: address sharing.
+
+-- Whether a contact takes part in peer exchange: we pass on where we've
+-- reached them, and trade address hints with them if they're trusted.
+ALTER TABLE contact_settings ADD COLUMN share_addresses INTEGER NOT NULL DEFAULT 1;
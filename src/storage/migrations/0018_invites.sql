@@ -0,0 +1,10 @@
+-- Migration 18: invites.
+
+-- Invite codes we handed out. Each adds one contact, before it expires.
+CREATE TABLE invites (
+    id BLOB PRIMARY KEY,
+    alias TEXT,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    used_by TEXT
+);
@@ -0,0 +1,12 @@
+-- Migration 19: notification settings.
+
+-- How a conversation notifies the user, keyed by a contact's peer ID or a
+-- group's ID. Conversations without a row notify for every message and
+-- ring no bell. A mute with a NULL muted_until lasts until lifted.
+CREATE TABLE notification_settings (
+    conversation TEXT PRIMARY KEY,
+    level TEXT NOT NULL DEFAULT 'all',
+    muted INTEGER NOT NULL DEFAULT 0,
+    muted_until INTEGER,
+    alert TEXT NOT NULL DEFAULT 'quiet'
+);
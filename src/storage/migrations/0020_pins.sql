@@ -0,0 +1,10 @@
+-- Migration 20: pinned and archived conversations.
+
+-- Where a conversation sits in the inbox, keyed by a contact's peer ID or
+-- a group's ID. Pinned conversations are listed first; archived ones are
+-- left out of the default view. Conversations without a row are neither.
+CREATE TABLE conversation_places (
+    conversation TEXT PRIMARY KEY,
+    pinned INTEGER NOT NULL DEFAULT 0,
+    archived INTEGER NOT NULL DEFAULT 0
+);
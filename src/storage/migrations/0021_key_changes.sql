@@ -0,0 +1,10 @@
+-- Migration 21: identity key changes.
+
+-- Contacts whose identity key changed since we stored it, with the key we
+-- had before. Sending to them waits until they're verified again, which
+-- clears the row.
+CREATE TABLE key_changes (
+    peer_id TEXT PRIMARY KEY,
+    previous_key BLOB NOT NULL,
+    changed_at INTEGER NOT NULL
+);
@@ -0,0 +1,9 @@
+-- Migration 22: deleted messages.
+
+-- IDs of messages deleted here, by hand or by a retention policy, so
+-- history synced from our other devices or contacts doesn't bring them
+-- back.
+CREATE TABLE deleted_messages (
+    message_id TEXT PRIMARY KEY,
+    deleted_at INTEGER NOT NULL
+);
@@ -1,8 +1,26 @@
 //! SQLite storage.
 
+#[cfg(not(feature = "bundled-sqlcipher"))]
+compile_error!(
+    "whisper encrypts its database with SQLCipher; build with the default \
+     `bundled-sqlcipher` feature (drop --no-default-features or add --features bundled-sqlcipher)"
+);
+
+mod audit;
+mod blobs;
 mod db;
 pub mod encryption;
-mod schema;
+mod handle;
+pub mod schema;
 
-pub use db::Database;
-pub use encryption::{derive_database_key, is_first_run};
+pub use audit::{AuditEvent, AuditKind};
+pub use db::{
+    Alert, Database, DeadLetter, Inbox, NotificationLevel, NotificationSettings, PeerAddress, PeerLatency, PeerVersion,
+    PrunedConversation, QueueEntry, QueuedMessage, RetentionPolicy, Webhook,
+};
+pub use handle::DatabaseHandle;
+pub use encryption::{
+    abandon_rekey, calibrate_kdf, create_salt_file, derive_database_key, derive_rekeyed_key, ensure_encrypted_file,
+    ensure_sqlcipher, finish_rekey, is_first_run, kdf_params, prepare_rekey, rekey_pending, staged_for_rekey,
+    KdfParams, KDF_TARGET,
+};
@@ -1 +1,257 @@
 //! Database schema definitions.
+//!
+//! The schema is built by numbered migrations, applied in order. The
+//! database's `user_version` pragma records the last one applied, so each
+//! runs exactly once. To change the schema, add a new file under
+//! `migrations/` and list it in `MIGRATIONS`; never edit one that has
+//! shipped.
+
+use anyhow::{bail, Context, Result};
+use rusqlite::Connection;
+
+/// One step in building the schema.
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    /// Schema version after this migration; the first is 1.
+    pub version: u32,
+    /// Short description, for error messages.
+    pub name: &'static str,
+    /// SQL to run.
+    pub sql: &'static str,
+}
+
+/// Every migration, oldest first.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "retention",
+        sql: include_str!("migrations/0002_retention.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "blobs",
+        sql: include_str!("migrations/0003_blobs.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "peer addresses",
+        sql: include_str!("migrations/0004_peer_addresses.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "presence",
+        sql: include_str!("migrations/0005_presence.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "latency",
+        sql: include_str!("migrations/0006_latency.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "traffic",
+        sql: include_str!("migrations/0007_traffic.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "audit log",
+        sql: include_str!("migrations/0008_audit.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "mailbox",
+        sql: include_str!("migrations/0009_mailbox.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "devices",
+        sql: include_str!("migrations/0010_devices.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "history sync",
+        sql: include_str!("migrations/0011_history_sync.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "channels",
+        sql: include_str!("migrations/0012_channels.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "contact cards",
+        sql: include_str!("migrations/0013_contact_cards.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "delivery retries",
+        sql: include_str!("migrations/0014_delivery_retries.sql"),
+    },
+    Migration {
+        version: 15,
+        name: "compression",
+        sql: include_str!("migrations/0015_compression.sql"),
+    },
+    Migration {
+        version: 16,
+        name: "peer versions",
+        sql: include_str!("migrations/0016_peer_versions.sql"),
+    },
+    Migration {
+        version: 17,
+        name: "address sharing",
+        sql: include_str!("migrations/0017_address_sharing.sql"),
+    },
+    Migration {
+        version: 18,
+        name: "invites",
+        sql: include_str!("migrations/0018_invites.sql"),
+    },
+    Migration {
+        version: 19,
+        name: "notifications",
+        sql: include_str!("migrations/0019_notifications.sql"),
+    },
+    Migration {
+        version: 20,
+        name: "pins",
+        sql: include_str!("migrations/0020_pins.sql"),
+    },
+    Migration {
+        version: 21,
+        name: "key_changes",
+        sql: include_str!("migrations/0021_key_changes.sql"),
+    },
+    Migration {
+        version: 22,
+        name: "deleted messages",
+        sql: include_str!("migrations/0022_deleted_messages.sql"),
+    },
+];
+
+/// The schema version this build creates.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// The schema version recorded in the database; 0 if none is.
+pub fn schema_version(conn: &Connection) -> Result<u32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+        .context("Failed to read schema version")
+}
+
+/// Check that migrations are numbered 1, 2, 3... with no gaps and none empty.
+pub fn validate(migrations: &[Migration]) -> Result<()> {
+    for (i, migration) in migrations.iter().enumerate() {
+        let expected = i as u32 + 1;
+        if migration.version != expected {
+            bail!(
+                "Migration '{}' has version {}, expected {}",
+                migration.name,
+                migration.version,
+                expected
+            );
+        }
+        if migration.sql.trim().is_empty() {
+            bail!("Migration {} ('{}') is empty", migration.version, migration.name);
+        }
+    }
+    Ok(())
+}
+
+/// Bring the database up to date with `MIGRATIONS`.
+pub fn migrate(conn: &Connection) -> Result<()> {
+    apply(conn, MIGRATIONS)
+}
+
+/// Apply the migrations the database hasn't seen yet. Each runs in its own
+/// transaction along with the version bump, so a failure leaves the
+/// database at the last version that succeeded.
+pub fn apply(conn: &Connection, migrations: &[Migration]) -> Result<()> {
+    validate(migrations)?;
+
+    let current = schema_version(conn)?;
+    let latest = migrations.last().map_or(0, |m| m.version);
+    if current > latest {
+        bail!(
+            "Database schema version {} is newer than this version of whisper supports ({}). \
+             Upgrade whisper to open it.",
+            current,
+            latest
+        );
+    }
+
+    for migration in migrations.iter().filter(|m| m.version > current) {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration.sql)
+            .and_then(|_| tx.pragma_update(None, "user_version", migration.version))
+            .with_context(|| {
+                format!("Failed to run migration {} ('{}')", migration.version, migration.name)
+            })?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEPS: &[Migration] = &[
+        Migration { version: 1, name: "one", sql: "CREATE TABLE a (x INTEGER);" },
+        Migration { version: 2, name: "two", sql: "CREATE TABLE b (y INTEGER);" },
+    ];
+
+    #[test]
+    fn built_in_migrations_are_valid() {
+        validate(MIGRATIONS).unwrap();
+        assert_eq!(latest_version(), MIGRATIONS.len() as u32);
+    }
+
+    #[test]
+    fn applies_pending_migrations_once() {
+        let conn = Connection::open_in_memory().unwrap();
+        apply(&conn, &STEPS[..1]).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 1);
+
+        // Only the new one runs; rerunning the first would fail
+        apply(&conn, STEPS).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 2);
+        apply(&conn, STEPS).unwrap();
+        conn.execute("INSERT INTO b (y) VALUES (1)", []).unwrap();
+    }
+
+    #[test]
+    fn rejects_gaps_and_empty_migrations() {
+        let gap = [STEPS[0], Migration { version: 3, ..STEPS[1] }];
+        assert!(validate(&gap).is_err());
+        let empty = [Migration { sql: "  ", ..STEPS[0] }];
+        assert!(validate(&empty).is_err());
+    }
+
+    #[test]
+    fn refuses_databases_from_newer_versions() {
+        let conn = Connection::open_in_memory().unwrap();
+        apply(&conn, STEPS).unwrap();
+        let err = apply(&conn, &STEPS[..1]).unwrap_err();
+        assert!(err.to_string().contains("newer"));
+    }
+
+    #[test]
+    fn failed_migration_leaves_earlier_version() {
+        let conn = Connection::open_in_memory().unwrap();
+        let broken = [
+            STEPS[0],
+            Migration { version: 2, name: "broken", sql: "CREATE TABLE c (z); NOT SQL;" },
+        ];
+        assert!(apply(&conn, &broken).is_err());
+        assert_eq!(schema_version(&conn).unwrap(), 1);
+        // The part that ran was rolled back
+        assert!(conn.execute("INSERT INTO c (z) VALUES (1)", []).is_err());
+    }
+}
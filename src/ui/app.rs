@@ -1,10 +1,33 @@
 //! TUI application state.
 
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
 use chrono::{DateTime, Utc};
-use crossterm::event::{KeyCode, KeyEvent};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 use libp2p::PeerId;
+use uuid::Uuid;
+
+use crate::identity::{Contact, Presence};
+use crate::message::{sort_conversations, Conversation, Message, Recipient};
+
+use super::images::{ImageProtocol, Preview};
+use super::views::short_peer_id;
+
+/// Minimum gap between outgoing typing indicators.
+pub const TYPING_SEND_INTERVAL: Duration = Duration::from_secs(3);
+
+/// How long a received typing indicator stays visible.
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// Shown in place of a deleted message.
+pub const DELETED_MESSAGE: &str = "[message deleted]";
 
-use crate::identity::Contact;
+/// Messages loaded from the database at a time.
+pub const HISTORY_PAGE: usize = 100;
+
+/// Messages moved by PageUp/PageDown.
+const SCROLL_PAGE: usize = 10;
 
 /// Application mode.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -15,6 +38,12 @@ pub enum AppMode {
     Contacts,
     /// Entering text input.
     Input,
+    /// Searching message history.
+    Search,
+    /// Typing a new alias for the selected contact.
+    Rename,
+    /// Typing an address to dial.
+    Connect,
 }
 
 /// A message formatted for display.
@@ -28,6 +57,14 @@ pub struct DisplayMessage {
     pub timestamp: DateTime<Utc>,
     /// Whether this message is from us.
     pub is_ours: bool,
+    /// Stored message ID, if the message can be deleted.
+    pub id: Option<Uuid>,
+    /// Whether the sender couldn't be verified from a signature.
+    pub unverified: bool,
+    /// Transfer ID of the image the message carries.
+    pub attachment: Option<Uuid>,
+    /// Rows kept free under the message for the image's preview.
+    pub preview_rows: u16,
 }
 
 impl DisplayMessage {
@@ -38,8 +75,55 @@ impl DisplayMessage {
             content,
             timestamp,
             is_ours,
+            id: None,
+            unverified: false,
+            attachment: None,
+            preview_rows: 0,
         }
     }
+
+    /// Attach the stored message's ID.
+    pub fn with_id(mut self, id: Uuid) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Mark the message as coming from an unverified sender.
+    pub fn with_unverified(mut self, unverified: bool) -> Self {
+        self.unverified = unverified;
+        self
+    }
+
+    /// Attach the image transfer the message announces, if any.
+    pub fn with_attachment(mut self, transfer_id: Option<Uuid>) -> Self {
+        self.attachment = transfer_id;
+        self
+    }
+}
+
+/// A file transfer in progress, as shown in the TUI.
+#[derive(Debug, Clone)]
+pub struct TransferView {
+    /// Transfer ID.
+    pub transfer_id: Uuid,
+    /// File name, if known.
+    pub filename: String,
+    /// Chunks sent or received so far.
+    pub chunks_done: u32,
+    /// Total chunks in the file.
+    pub total_chunks: u32,
+    /// Whether we are the sender.
+    pub outgoing: bool,
+}
+
+impl TransferView {
+    /// Fraction complete, between 0.0 and 1.0.
+    pub fn ratio(&self) -> f64 {
+        if self.total_chunks == 0 {
+            return 1.0;
+        }
+        (self.chunks_done as f64 / self.total_chunks as f64).min(1.0)
+    }
 }
 
 /// Input action result.
@@ -51,6 +135,95 @@ pub enum InputAction {
     Send(String),
     /// Cancel input mode.
     Cancel,
+    /// Search message history.
+    Search(String),
+    /// Delete one of our messages for everyone.
+    Delete(Uuid),
+    /// Load the page of history before the oldest loaded message.
+    LoadOlder,
+    /// Give a contact a new alias.
+    Rename(PeerId, String),
+    /// Dial an address typed by the user.
+    Connect(String),
+    /// Open an image in the system's viewer.
+    Open(Uuid),
+    /// Pin a conversation to the top of the inbox, or unpin it.
+    Pin(Recipient, bool),
+    /// Archive a conversation, or bring it back from the archive.
+    Archive(Recipient, bool),
+}
+
+/// Messages and scroll position for one conversation.
+#[derive(Debug, Clone, Default)]
+pub struct ChatBuffer {
+    /// Messages, oldest first.
+    pub messages: Vec<DisplayMessage>,
+    /// How many messages the chat is scrolled up from the newest.
+    pub scroll: usize,
+    /// Stored messages fetched so far.
+    history_loaded: usize,
+    /// Whether every stored message is loaded.
+    history_complete: bool,
+}
+
+impl ChatBuffer {
+    /// Stored messages fetched so far, i.e. where the next page of history
+    /// starts.
+    pub fn history_loaded(&self) -> usize {
+        self.history_loaded
+    }
+
+    /// Whether no history has been loaded yet.
+    fn is_fresh(&self) -> bool {
+        self.history_loaded == 0 && !self.history_complete
+    }
+
+    /// Add a page of older messages, oldest first, above those already
+    /// loaded. `fetched` is how many stored messages the page came from;
+    /// a short page means there's nothing older left.
+    pub fn prepend_history(&mut self, older: Vec<DisplayMessage>, fetched: usize) {
+        let mut older: Vec<DisplayMessage> = older
+            .into_iter()
+            .filter(|m| m.id.is_none() || !self.messages.iter().any(|loaded| loaded.id == m.id))
+            .collect();
+        older.append(&mut self.messages);
+        self.messages = older;
+        self.history_loaded += fetched;
+        self.history_complete = fetched < HISTORY_PAGE;
+    }
+
+    /// Scroll towards older messages, asking for more history once the
+    /// loaded messages are about to run out.
+    fn scroll_up(&mut self, by: usize) -> InputAction {
+        let loaded = self.messages.len();
+        self.scroll = (self.scroll + by).min(loaded.saturating_sub(1));
+        if !self.history_complete && self.scroll + SCROLL_PAGE >= loaded {
+            InputAction::LoadOlder
+        } else {
+            InputAction::None
+        }
+    }
+
+    /// Our most recent message that isn't deleted yet.
+    fn last_own_message(&self) -> Option<Uuid> {
+        self.messages
+            .iter()
+            .rev()
+            .filter(|m| m.is_ours && m.content != DELETED_MESSAGE)
+            .find_map(|m| m.id)
+    }
+
+    /// The newest image in the chat.
+    fn last_attachment(&self) -> Option<Uuid> {
+        self.messages.iter().rev().find_map(|m| m.attachment)
+    }
+
+    /// Show a message as deleted.
+    fn mark_deleted(&mut self, id: &Uuid) {
+        for message in self.messages.iter_mut().filter(|m| m.id.as_ref() == Some(id)) {
+            message.content = DELETED_MESSAGE.to_string();
+        }
+    }
 }
 
 /// TUI application.
@@ -59,18 +232,66 @@ pub struct App {
     pub mode: AppMode,
     /// Currently selected chat (peer).
     pub current_chat: Option<PeerId>,
-    /// Messages in current chat.
-    pub messages: Vec<DisplayMessage>,
+    /// The open chat's messages.
+    pub chat: ChatBuffer,
+    /// Chats opened earlier in this session, kept for switching back.
+    buffers: HashMap<PeerId, ChatBuffer>,
+    /// Whether the conversation sidebar is shown next to the chat.
+    pub show_sidebar: bool,
+    /// Whether messages are shown as typed instead of rendering markdown.
+    pub raw_markdown: bool,
     /// Current input buffer.
     pub input: String,
+    /// Byte offset of the cursor in `input`.
+    pub cursor: usize,
     /// Contact list.
     pub contacts: Vec<Contact>,
-    /// Selected contact index.
-    pub selected_contact: usize,
+    /// Conversations shown in the inbox, pinned first, then most recent.
+    pub conversations: Vec<Conversation>,
+    /// Conversations not in the current view: the archived ones, or in the
+    /// archive the rest.
+    set_aside: Vec<Conversation>,
+    /// Whether the inbox shows archived conversations instead of the rest.
+    pub show_archived: bool,
+    /// Selected conversation index.
+    pub selected_conversation: usize,
     /// Whether the app should quit.
     pub should_quit: bool,
     /// Our peer ID.
     pub our_peer_id: Option<PeerId>,
+    /// Active file transfers.
+    pub transfers: Vec<TransferView>,
+    /// When each peer last told us they were typing.
+    pub typing: HashMap<PeerId, Instant>,
+    /// When we last sent a typing indicator.
+    last_typing_sent: Option<Instant>,
+    /// Received messages the user hasn't seen yet.
+    unread: Vec<(PeerId, Uuid)>,
+    /// Search query buffer.
+    pub search: String,
+    /// Results of the last search.
+    pub search_results: Vec<DisplayMessage>,
+    /// How many strangers have messages waiting in message requests.
+    pub message_requests: usize,
+    /// Contacts whose identity key changed since they were last verified.
+    /// Nothing is sent to them until they are.
+    pub key_changed: HashSet<PeerId>,
+    /// New alias being typed in rename mode.
+    pub rename: String,
+    /// Why the last rename was refused.
+    pub rename_error: Option<String>,
+    /// The contact being renamed.
+    renaming: Option<PeerId>,
+    /// Address being typed in connect mode.
+    pub connect: String,
+    /// How the last connect went: who answered, or why it failed.
+    pub connect_result: Option<Result<String, String>>,
+    /// How the terminal draws images, if it can.
+    pub image_protocol: Option<ImageProtocol>,
+    /// Image previews by transfer ID.
+    pub previews: HashMap<Uuid, Preview>,
+    /// Images with no preview to show: not all here yet, or unreadable.
+    no_preview: HashSet<Uuid>,
 }
 
 impl App {
@@ -79,15 +300,67 @@ impl App {
         Self {
             mode: AppMode::Contacts,
             current_chat: None,
-            messages: Vec::new(),
+            chat: ChatBuffer::default(),
+            buffers: HashMap::new(),
+            show_sidebar: true,
+            raw_markdown: false,
             input: String::new(),
+            cursor: 0,
             contacts: Vec::new(),
-            selected_contact: 0,
+            conversations: Vec::new(),
+            set_aside: Vec::new(),
+            show_archived: false,
+            selected_conversation: 0,
             should_quit: false,
             our_peer_id: None,
+            transfers: Vec::new(),
+            typing: HashMap::new(),
+            last_typing_sent: None,
+            unread: Vec::new(),
+            search: String::new(),
+            search_results: Vec::new(),
+            rename: String::new(),
+            rename_error: None,
+            renaming: None,
+            connect: String::new(),
+            connect_result: None,
+            message_requests: 0,
+            key_changed: HashSet::new(),
+            image_protocol: None,
+            previews: HashMap::new(),
+            no_preview: HashSet::new(),
+        }
+    }
+
+    /// Show these conversations, archived ones only in the archive view.
+    pub fn set_conversations(&mut self, conversations: Vec<Conversation>) {
+        let selected = self.conversations.get(self.selected_conversation).map(|c| c.with);
+        let (mut shown, mut set_aside): (Vec<_>, Vec<_>) =
+            conversations.into_iter().partition(|c| c.archived == self.show_archived);
+        sort_conversations(&mut shown);
+        sort_conversations(&mut set_aside);
+        self.conversations = shown;
+        self.set_aside = set_aside;
+        self.reselect(selected);
+    }
+
+    /// Keep `selected` selected after the list changed, or stay in range
+    /// if it's gone.
+    fn reselect(&mut self, selected: Option<Recipient>) {
+        match self.conversations.iter().position(|c| Some(c.with) == selected) {
+            Some(index) => self.selected_conversation = index,
+            None => {
+                self.selected_conversation = self.selected_conversation.min(self.conversations.len().saturating_sub(1))
+            }
         }
     }
 
+    /// How many conversations are archived.
+    pub fn archived_count(&self) -> usize {
+        let set_aside = if self.show_archived { &self.conversations } else { &self.set_aside };
+        set_aside.len()
+    }
+
     /// Set our peer ID.
     pub fn set_peer_id(&mut self, peer_id: PeerId) {
         self.our_peer_id = Some(peer_id);
@@ -95,10 +368,18 @@ impl App {
 
     /// Handle a key event.
     pub fn handle_key(&mut self, key: KeyEvent) -> InputAction {
+        // Raw mode turns Ctrl+C into a key, so it quits from anywhere
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            self.should_quit = true;
+            return InputAction::None;
+        }
         match self.mode {
             AppMode::Chat => self.handle_chat_key(key),
             AppMode::Contacts => self.handle_contacts_key(key),
             AppMode::Input => self.handle_input_key(key),
+            AppMode::Search => self.handle_search_key(key),
+            AppMode::Rename => self.handle_rename_key(key),
+            AppMode::Connect => self.handle_connect_key(key),
         }
     }
 
@@ -114,37 +395,159 @@ impl App {
             KeyCode::Char('i') => {
                 self.mode = AppMode::Input;
             }
+            KeyCode::Char('/') => {
+                self.search.clear();
+                self.search_results.clear();
+                self.mode = AppMode::Search;
+            }
+            KeyCode::Char('d') => {
+                if let Some(id) = self.chat.last_own_message() {
+                    return InputAction::Delete(id);
+                }
+            }
+            KeyCode::Char('s') => {
+                self.show_sidebar = !self.show_sidebar;
+            }
+            KeyCode::Char('m') => {
+                self.raw_markdown = !self.raw_markdown;
+            }
+            KeyCode::Char('o') => {
+                if let Some(id) = self.chat.last_attachment() {
+                    return InputAction::Open(id);
+                }
+            }
+            KeyCode::Tab => return self.switch_chat(true),
+            KeyCode::BackTab => return self.switch_chat(false),
+            KeyCode::PageUp => return self.chat.scroll_up(SCROLL_PAGE),
+            KeyCode::Up | KeyCode::Char('k') => return self.chat.scroll_up(1),
+            KeyCode::PageDown => self.chat.scroll = self.chat.scroll.saturating_sub(SCROLL_PAGE),
+            KeyCode::Down | KeyCode::Char('j') => self.chat.scroll = self.chat.scroll.saturating_sub(1),
             KeyCode::Esc => {
                 self.mode = AppMode::Contacts;
-                self.current_chat = None;
+                self.close_chat();
             }
             _ => {}
         }
         InputAction::None
     }
 
+    /// Open the chat with `peer_id`, keeping the current one's buffer for
+    /// later. Asks for history if this chat hasn't been opened before.
+    pub fn open_chat(&mut self, peer_id: PeerId) -> InputAction {
+        if self.current_chat != Some(peer_id) {
+            self.close_chat();
+            self.chat = self.buffers.remove(&peer_id).unwrap_or_default();
+            self.current_chat = Some(peer_id);
+        }
+        self.mode = AppMode::Chat;
+        let with = Recipient::Direct(peer_id);
+        if let Some(index) = self.conversations.iter().position(|c| c.with == with) {
+            self.selected_conversation = index;
+        }
+
+        if self.chat.is_fresh() {
+            InputAction::LoadOlder
+        } else {
+            InputAction::None
+        }
+    }
+
+    /// Put the open chat's buffer away.
+    fn close_chat(&mut self) {
+        if let Some(peer_id) = self.current_chat.take() {
+            self.buffers.insert(peer_id, std::mem::take(&mut self.chat));
+        }
+    }
+
+    /// Open the next (or previous) direct conversation in the inbox.
+    fn switch_chat(&mut self, forward: bool) -> InputAction {
+        let chats: Vec<PeerId> = self
+            .conversations
+            .iter()
+            .filter_map(|c| match c.with {
+                Recipient::Direct(peer_id) => Some(peer_id),
+                Recipient::Group(_) => None,
+            })
+            .collect();
+        if chats.is_empty() {
+            return InputAction::None;
+        }
+
+        let next = match chats.iter().position(|p| Some(*p) == self.current_chat) {
+            Some(i) if forward => (i + 1) % chats.len(),
+            Some(i) => (i + chats.len() - 1) % chats.len(),
+            None => 0,
+        };
+        self.open_chat(chats[next])
+    }
+
+    /// Show a message as deleted wherever it's loaded.
+    pub fn mark_deleted(&mut self, id: &Uuid) {
+        self.chat.mark_deleted(id);
+        for buffer in self.buffers.values_mut() {
+            buffer.mark_deleted(id);
+        }
+    }
+
     /// Handle key in contacts mode.
     fn handle_contacts_key(&mut self, key: KeyEvent) -> InputAction {
         match key.code {
             KeyCode::Char('q') => {
                 self.should_quit = true;
             }
-            KeyCode::Up | KeyCode::Char('k') => {
-                if self.selected_contact > 0 {
-                    self.selected_contact -= 1;
+            KeyCode::Up | KeyCode::Char('k') if self.selected_conversation > 0 => {
+                self.selected_conversation -= 1;
+            }
+            KeyCode::Down | KeyCode::Char('j') if self.selected_conversation + 1 < self.conversations.len() => {
+                self.selected_conversation += 1;
+            }
+            KeyCode::Enter => {
+                // Group chats run in `whisper group chat`
+                let selected = self.conversations.get(self.selected_conversation).map(|c| c.with);
+                if let Some(Recipient::Direct(peer_id)) = selected {
+                    return self.open_chat(peer_id);
                 }
             }
-            KeyCode::Down | KeyCode::Char('j') => {
-                if self.selected_contact + 1 < self.contacts.len() {
-                    self.selected_contact += 1;
+            KeyCode::Char('r') => {
+                if let Some(conversation) = self.conversations.get(self.selected_conversation) {
+                    if let Recipient::Direct(peer_id) = conversation.with {
+                        self.rename = conversation.name.clone();
+                        self.rename_error = None;
+                        self.renaming = Some(peer_id);
+                        self.mode = AppMode::Rename;
+                    }
                 }
             }
-            KeyCode::Enter => {
-                if let Some(contact) = self.contacts.get(self.selected_contact) {
-                    self.current_chat = Some(contact.peer_id);
-                    self.mode = AppMode::Chat;
+            KeyCode::Char('c') => {
+                self.connect.clear();
+                self.connect_result = None;
+                self.mode = AppMode::Connect;
+            }
+            KeyCode::Char('p') => {
+                if let Some(conversation) = self.conversations.get_mut(self.selected_conversation) {
+                    conversation.pinned = !conversation.pinned;
+                    let (with, pinned) = (conversation.with, conversation.pinned);
+                    sort_conversations(&mut self.conversations);
+                    self.reselect(Some(with));
+                    return InputAction::Pin(with, pinned);
                 }
             }
+            KeyCode::Char('a') if self.selected_conversation < self.conversations.len() => {
+                let mut conversation = self.conversations.remove(self.selected_conversation);
+                conversation.archived = !conversation.archived;
+                let (with, archived) = (conversation.with, conversation.archived);
+                self.set_aside.push(conversation);
+                sort_conversations(&mut self.set_aside);
+                self.reselect(None);
+                return InputAction::Archive(with, archived);
+            }
+            KeyCode::Char('A') => {
+                self.show_archived = !self.show_archived;
+                let mut all = std::mem::take(&mut self.conversations);
+                all.append(&mut self.set_aside);
+                self.selected_conversation = 0;
+                self.set_conversations(all);
+            }
             _ => {}
         }
         InputAction::None
@@ -154,40 +557,182 @@ impl App {
     fn handle_input_key(&mut self, key: KeyEvent) -> InputAction {
         match key.code {
             KeyCode::Esc => {
-                self.input.clear();
+                self.set_input(String::new());
                 self.mode = AppMode::Chat;
                 InputAction::Cancel
             }
+            // Not every terminal reports Shift+Enter, so Alt+Enter works too
+            KeyCode::Enter if key.modifiers.intersects(KeyModifiers::SHIFT | KeyModifiers::ALT) => {
+                self.insert_char('\n');
+                InputAction::None
+            }
             KeyCode::Enter => {
-                if !self.input.is_empty() {
+                // Held back until the new key is verified
+                if !self.input.is_empty() && !self.current_chat.is_some_and(|p| self.key_changed.contains(&p)) {
                     let text = std::mem::take(&mut self.input);
+                    self.cursor = 0;
                     self.mode = AppMode::Chat;
+                    // Jump back down to see what we sent
+                    self.chat.scroll = 0;
+                    self.last_typing_sent = None;
                     InputAction::Send(text)
                 } else {
                     InputAction::None
                 }
             }
             KeyCode::Backspace => {
-                self.input.pop();
+                let cursor = self.cursor.min(self.input.len());
+                let start = prev_boundary(&self.input, cursor);
+                self.input.replace_range(start..cursor, "");
+                self.cursor = start;
+                InputAction::None
+            }
+            KeyCode::Delete => {
+                let cursor = self.cursor.min(self.input.len());
+                let end = next_boundary(&self.input, cursor);
+                self.input.replace_range(cursor..end, "");
+                InputAction::None
+            }
+            KeyCode::Left | KeyCode::Right => {
+                let cursor = self.cursor.min(self.input.len());
+                let by_word = key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT);
+                self.cursor = match (key.code == KeyCode::Left, by_word) {
+                    (true, false) => prev_boundary(&self.input, cursor),
+                    (false, false) => next_boundary(&self.input, cursor),
+                    (true, true) => word_start(&self.input, cursor),
+                    (false, true) => word_end(&self.input, cursor),
+                };
+                InputAction::None
+            }
+            // Home and End stay on the cursor's line
+            KeyCode::Home => {
+                let cursor = self.cursor.min(self.input.len());
+                self.cursor = self.input[..cursor].rfind('\n').map_or(0, |i| i + 1);
+                InputAction::None
+            }
+            KeyCode::End => {
+                let cursor = self.cursor.min(self.input.len());
+                self.cursor = self.input[cursor..].find('\n').map_or(self.input.len(), |i| cursor + i);
                 InputAction::None
             }
             KeyCode::Char(c) => {
-                self.input.push(c);
+                self.insert_char(c);
                 InputAction::None
             }
             _ => InputAction::None,
         }
     }
 
-    /// Handle an incoming message.
-    pub fn handle_message(&mut self, msg: DisplayMessage) {
-        // Add to messages if it's for the current chat
-        if let Some(current) = &self.current_chat {
-            // Message is relevant if it's from/to the current peer
-            let is_relevant = *current == msg.from;
-            if is_relevant {
-                self.messages.push(msg);
+    /// Replace the input buffer, leaving the cursor at its end.
+    pub fn set_input(&mut self, text: String) {
+        self.cursor = text.len();
+        self.input = text;
+    }
+
+    /// Insert `c` at the cursor and move past it.
+    fn insert_char(&mut self, c: char) {
+        let cursor = self.cursor.min(self.input.len());
+        self.input.insert(cursor, c);
+        self.cursor = cursor + c.len_utf8();
+    }
+
+    /// Handle key in search mode.
+    fn handle_search_key(&mut self, key: KeyEvent) -> InputAction {
+        match key.code {
+            KeyCode::Esc => {
+                self.search.clear();
+                self.search_results.clear();
+                self.mode = AppMode::Chat;
+                InputAction::Cancel
+            }
+            KeyCode::Enter if !self.search.trim().is_empty() => {
+                InputAction::Search(self.search.clone())
             }
+            KeyCode::Backspace => {
+                self.search.pop();
+                InputAction::None
+            }
+            KeyCode::Char(c) => {
+                self.search.push(c);
+                InputAction::None
+            }
+            _ => InputAction::None,
+        }
+    }
+
+    /// Handle key in rename mode.
+    fn handle_rename_key(&mut self, key: KeyEvent) -> InputAction {
+        match key.code {
+            KeyCode::Esc => {
+                self.rename.clear();
+                self.renaming = None;
+                self.mode = AppMode::Contacts;
+                InputAction::Cancel
+            }
+            KeyCode::Enter if !self.rename.trim().is_empty() => match self.renaming {
+                Some(peer_id) => InputAction::Rename(peer_id, self.rename.trim().to_string()),
+                None => InputAction::None,
+            },
+            KeyCode::Backspace => {
+                self.rename.pop();
+                InputAction::None
+            }
+            KeyCode::Char(c) => {
+                self.rename.push(c);
+                InputAction::None
+            }
+            _ => InputAction::None,
+        }
+    }
+
+    /// Handle key in connect mode.
+    fn handle_connect_key(&mut self, key: KeyEvent) -> InputAction {
+        match key.code {
+            KeyCode::Esc => {
+                self.connect.clear();
+                self.connect_result = None;
+                self.mode = AppMode::Contacts;
+                InputAction::Cancel
+            }
+            KeyCode::Enter if !self.connect.trim().is_empty() => {
+                InputAction::Connect(self.connect.trim().to_string())
+            }
+            KeyCode::Backspace => {
+                self.connect.pop();
+                InputAction::None
+            }
+            KeyCode::Char(c) => {
+                self.connect.push(c);
+                InputAction::None
+            }
+            _ => InputAction::None,
+        }
+    }
+
+    /// A rename was saved: show the new alias and leave rename mode.
+    pub fn contact_renamed(&mut self, peer_id: PeerId, alias: &str) {
+        for contact in self.contacts.iter_mut().filter(|c| c.peer_id == peer_id) {
+            contact.alias = alias.to_string();
+        }
+        for conversation in self.conversations.iter_mut().chain(self.set_aside.iter_mut()) {
+            if conversation.with == Recipient::Direct(peer_id) {
+                conversation.name = alias.to_string();
+            }
+        }
+        self.rename.clear();
+        self.rename_error = None;
+        self.renaming = None;
+        self.mode = AppMode::Contacts;
+    }
+
+    /// Handle an incoming message: it goes into its sender's chat if that
+    /// chat is open or was opened earlier. Other chats load it from the
+    /// database when opened.
+    pub fn handle_message(&mut self, msg: DisplayMessage) {
+        if self.current_chat == Some(msg.from) {
+            self.chat.messages.push(msg);
+        } else if let Some(buffer) = self.buffers.get_mut(&msg.from) {
+            buffer.messages.push(msg);
         }
     }
 
@@ -196,9 +741,204 @@ impl App {
         self.contacts.push(contact);
     }
 
+    /// Start showing a transfer.
+    pub fn track_transfer(&mut self, transfer_id: Uuid, filename: String, total_chunks: u32, outgoing: bool) {
+        if self.transfers.iter().any(|t| t.transfer_id == transfer_id) {
+            return;
+        }
+        self.transfers.push(TransferView {
+            transfer_id,
+            filename,
+            chunks_done: 0,
+            total_chunks,
+            outgoing,
+        });
+    }
+
+    /// Update a transfer's progress, dropping it once finished.
+    pub fn update_transfer(&mut self, transfer_id: Uuid, chunks_done: u32, total_chunks: u32, outgoing: bool) {
+        match self.transfers.iter_mut().find(|t| t.transfer_id == transfer_id) {
+            Some(view) => {
+                view.chunks_done = chunks_done;
+                view.total_chunks = total_chunks;
+            }
+            None => self.transfers.push(TransferView {
+                transfer_id,
+                filename: "file".to_string(),
+                chunks_done,
+                total_chunks,
+                outgoing,
+            }),
+        }
+        if chunks_done >= total_chunks {
+            self.transfers.retain(|t| t.transfer_id != transfer_id);
+            // All of it is here now, so it may have a preview after all
+            self.no_preview.remove(&transfer_id);
+        }
+    }
+
+    /// Make room for the open chat's loaded previews, and return the
+    /// images in it still to be loaded. Empty if the terminal can't draw
+    /// images.
+    pub fn sync_previews(&mut self) -> Vec<Uuid> {
+        if self.image_protocol.is_none() {
+            return Vec::new();
+        }
+        let mut wanted = Vec::new();
+        for msg in self.chat.messages.iter_mut() {
+            let Some(id) = msg.attachment else { continue };
+            match self.previews.get(&id) {
+                Some(preview) => msg.preview_rows = preview.rows,
+                None if !self.no_preview.contains(&id) && !wanted.contains(&id) => wanted.push(id),
+                None => {}
+            }
+        }
+        wanted
+    }
+
+    /// Keep an image's preview, or note that it has none for now.
+    pub fn set_preview(&mut self, transfer_id: Uuid, preview: Option<Preview>) {
+        match preview {
+            Some(preview) => {
+                for msg in self.chat.messages.iter_mut().filter(|m| m.attachment == Some(transfer_id)) {
+                    msg.preview_rows = preview.rows;
+                }
+                self.previews.insert(transfer_id, preview);
+            }
+            None => {
+                self.no_preview.insert(transfer_id);
+            }
+        }
+    }
+
+    /// Whether to send a typing indicator now. True at most once per
+    /// `TYPING_SEND_INTERVAL` while composing a message to the current chat.
+    pub fn should_send_typing(&mut self, now: Instant) -> bool {
+        if self.mode != AppMode::Input || self.input.is_empty() || self.current_chat.is_none() {
+            return false;
+        }
+        if let Some(last) = self.last_typing_sent {
+            if now.duration_since(last) < TYPING_SEND_INTERVAL {
+                return false;
+            }
+        }
+        self.last_typing_sent = Some(now);
+        true
+    }
+
+    /// Record that a peer is typing.
+    pub fn set_typing(&mut self, peer_id: PeerId, now: Instant) {
+        self.typing.insert(peer_id, now);
+    }
+
+    /// Record a contact's latest presence announcement.
+    pub fn set_presence(&mut self, peer_id: PeerId, presence: Presence) {
+        if let Some(contact) = self.contacts.iter_mut().find(|c| c.peer_id == peer_id) {
+            contact.presence = Some(presence);
+        }
+    }
+
+    /// Forget a peer's typing state, e.g. once their message arrives.
+    pub fn clear_typing(&mut self, peer_id: &PeerId) {
+        self.typing.remove(peer_id);
+    }
+
+    /// "alice is typing…" if the current chat peer is typing.
+    pub fn typing_label(&self, now: Instant) -> Option<String> {
+        let peer_id = self.current_chat?;
+        let since = self.typing.get(&peer_id)?;
+        if now.duration_since(*since) >= TYPING_TIMEOUT {
+            return None;
+        }
+        Some(format!("{} is typing…", self.name_of(&peer_id)))
+    }
+
+    /// The warning over the open chat if its contact's identity key
+    /// changed since they were last verified.
+    pub fn key_warning(&self) -> Option<String> {
+        let peer_id = self.current_chat.filter(|peer_id| self.key_changed.contains(peer_id))?;
+        let name = self.name_of(&peer_id);
+        Some(format!(
+            "⚠ {}'s identity key changed. Nothing is sent until you check it with: whisper verify {}",
+            name, name
+        ))
+    }
+
+    /// A contact's alias, or their short peer ID if they aren't one.
+    fn name_of(&self, peer_id: &PeerId) -> String {
+        self.contacts
+            .iter()
+            .find(|c| c.peer_id == *peer_id)
+            .map(|c| c.alias.clone())
+            .unwrap_or_else(|| short_peer_id(peer_id))
+    }
+
+    /// Remember a received message until the user views it.
+    pub fn mark_unread(&mut self, peer_id: PeerId, message_id: Uuid) {
+        if !self.unread.contains(&(peer_id, message_id)) {
+            self.unread.push((peer_id, message_id));
+        }
+    }
+
+    /// Show a new message in the inbox: it becomes the conversation's
+    /// preview, counts as unread unless it's ours or on screen, and moves
+    /// the conversation to the top, below any pinned ones.
+    pub fn record_message(&mut self, with: Recipient, msg: &Message) {
+        let on_screen = matches!(with, Recipient::Direct(peer_id) if self.viewing() == Some(peer_id));
+        let ours = Some(msg.from) == self.our_peer_id;
+        let Some(conversation) =
+            self.conversations.iter_mut().chain(self.set_aside.iter_mut()).find(|c| c.with == with)
+        else {
+            return;
+        };
+        if !ours && !on_screen {
+            conversation.unread += 1;
+        }
+        conversation.last_message = Some(msg.clone());
+
+        // Keep the same conversation selected as the list reorders
+        let selected = self.conversations.get(self.selected_conversation).map(|c| c.with);
+        sort_conversations(&mut self.conversations);
+        sort_conversations(&mut self.set_aside);
+        self.reselect(selected);
+    }
+
+    /// Clear a conversation's unread count. Returns true if it had any.
+    pub fn clear_unread(&mut self, with: &Recipient) -> bool {
+        match self.conversations.iter_mut().chain(self.set_aside.iter_mut()).find(|c| &c.with == with) {
+            Some(conversation) if conversation.unread > 0 => {
+                conversation.unread = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The peer whose chat is on screen, if any.
+    pub fn viewing(&self) -> Option<PeerId> {
+        if matches!(self.mode, AppMode::Chat | AppMode::Input) {
+            self.current_chat
+        } else {
+            None
+        }
+    }
+
+    /// Unread messages that are now on screen, removed from the unread
+    /// list. Empty unless the sender's chat is open.
+    pub fn take_viewed(&mut self) -> Vec<(PeerId, Uuid)> {
+        let Some(current) = self.viewing() else {
+            return Vec::new();
+        };
+        let (viewed, unread) = std::mem::take(&mut self.unread)
+            .into_iter()
+            .partition(|(peer_id, _)| *peer_id == current);
+        self.unread = unread;
+        viewed
+    }
+
     /// Clear messages.
     pub fn clear_messages(&mut self) {
-        self.messages.clear();
+        self.chat.messages.clear();
     }
 
     /// Get the current chat peer.
@@ -213,6 +953,33 @@ impl Default for App {
     }
 }
 
+/// Byte offset of the character before `at`.
+fn prev_boundary(text: &str, at: usize) -> usize {
+    text[..at].char_indices().next_back().map_or(0, |(i, _)| i)
+}
+
+/// Byte offset just past the character at `at`.
+fn next_boundary(text: &str, at: usize) -> usize {
+    text[at..].chars().next().map_or(at, |c| at + c.len_utf8())
+}
+
+/// Start of the word before `at`, skipping whitespace first.
+fn word_start(text: &str, at: usize) -> usize {
+    text[..at]
+        .trim_end()
+        .char_indices()
+        .rfind(|(_, c)| c.is_whitespace())
+        .map_or(0, |(i, c)| i + c.len_utf8())
+}
+
+/// End of the word after `at`, skipping whitespace first.
+fn word_end(text: &str, at: usize) -> usize {
+    let after = &text[at..];
+    let skipped = after.len() - after.trim_start().len();
+    let rest = &after[skipped..];
+    at + skipped + rest.find(char::is_whitespace).unwrap_or(rest.len())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -229,6 +996,17 @@ mod tests {
         assert!(!app.should_quit);
     }
 
+    #[test]
+    fn ctrl_c_quits_from_any_mode() {
+        for mode in [AppMode::Chat, AppMode::Contacts, AppMode::Input, AppMode::Search] {
+            let mut app = App::new();
+            app.mode = mode;
+            app.handle_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL));
+            assert!(app.should_quit);
+            assert!(app.input.is_empty());
+        }
+    }
+
     #[test]
     fn q_key_sets_should_quit() {
         let mut app = App::new();
@@ -252,13 +1030,373 @@ mod tests {
     fn backspace_removes_char() {
         let mut app = App::new();
         app.mode = AppMode::Input;
-        app.input = "hello".to_string();
+        app.set_input("hello".to_string());
         
         app.handle_key(KeyEvent::from(KeyCode::Backspace));
         
         assert_eq!(app.input, "hell");
     }
 
+    #[test]
+    fn typing_indicator_is_rate_limited() {
+        let mut app = App::new();
+        app.current_chat = Some(PeerId::random());
+        app.mode = AppMode::Input;
+        let start = Instant::now();
+
+        // Nothing to send until there's text
+        assert!(!app.should_send_typing(start));
+        app.input = "h".to_string();
+        assert!(app.should_send_typing(start));
+        assert!(!app.should_send_typing(start + Duration::from_secs(1)));
+        assert!(app.should_send_typing(start + TYPING_SEND_INTERVAL));
+    }
+
+    #[test]
+    fn typing_label_shows_alias_then_expires() {
+        let mut app = App::new();
+        let peer = PeerId::random();
+        app.add_contact(Contact::new(peer, "alice".to_string(), vec![]));
+        app.current_chat = Some(peer);
+        let now = Instant::now();
+
+        assert!(app.typing_label(now).is_none());
+        app.set_typing(peer, now);
+        assert_eq!(app.typing_label(now).as_deref(), Some("alice is typing…"));
+        assert!(app.typing_label(now + TYPING_TIMEOUT).is_none());
+
+        app.clear_typing(&peer);
+        assert!(app.typing_label(now).is_none());
+    }
+
+    #[test]
+    fn unread_messages_are_viewed_only_in_their_chat() {
+        let mut app = App::new();
+        let alice = PeerId::random();
+        let bob = PeerId::random();
+        let (from_alice, from_bob) = (Uuid::new_v4(), Uuid::new_v4());
+        app.mark_unread(alice, from_alice);
+        app.mark_unread(bob, from_bob);
+
+        // Nothing is on screen from the contact list
+        app.current_chat = Some(alice);
+        assert!(app.take_viewed().is_empty());
+
+        app.mode = AppMode::Chat;
+        assert_eq!(app.take_viewed(), vec![(alice, from_alice)]);
+        assert!(app.take_viewed().is_empty());
+
+        app.current_chat = Some(bob);
+        app.mode = AppMode::Input;
+        assert_eq!(app.take_viewed(), vec![(bob, from_bob)]);
+    }
+
+    fn conversation(with: Recipient, name: &str) -> Conversation {
+        Conversation {
+            with,
+            name: name.to_string(),
+            last_message: None,
+            unread: 0,
+            pinned: false,
+            archived: false,
+        }
+    }
+
+    #[test]
+    fn new_messages_reorder_inbox() {
+        let mut app = App::new();
+        let (me, alice, bob) = (PeerId::random(), PeerId::random(), PeerId::random());
+        app.set_peer_id(me);
+        app.conversations = vec![
+            conversation(Recipient::Direct(alice), "alice"),
+            conversation(Recipient::Direct(bob), "bob"),
+        ];
+        app.selected_conversation = 0;
+
+        let msg = Message::new_text(bob, Recipient::Direct(me), "hi".to_string());
+        app.record_message(Recipient::Direct(bob), &msg);
+        assert_eq!(app.conversations[0].name, "bob");
+        assert_eq!(app.conversations[0].unread, 1);
+        // Alice stays selected
+        assert_eq!(app.selected_conversation, 1);
+
+        // Our own messages aren't unread
+        let reply = Message::new_text(me, Recipient::Direct(bob), "hey".to_string());
+        app.record_message(Recipient::Direct(bob), &reply);
+        assert_eq!(app.conversations[0].unread, 1);
+
+        assert!(app.clear_unread(&Recipient::Direct(bob)));
+        assert!(!app.clear_unread(&Recipient::Direct(bob)));
+    }
+
+    #[test]
+    fn enter_opens_direct_conversations_only() {
+        let mut app = App::new();
+        let alice = PeerId::random();
+        app.conversations = vec![
+            conversation(Recipient::Group(Uuid::new_v4()), "team"),
+            conversation(Recipient::Direct(alice), "alice"),
+        ];
+
+        app.handle_key(KeyEvent::from(KeyCode::Enter));
+        assert_eq!(app.mode, AppMode::Contacts);
+
+        app.handle_key(KeyEvent::from(KeyCode::Char('j')));
+        app.handle_key(KeyEvent::from(KeyCode::Enter));
+        assert_eq!(app.mode, AppMode::Chat);
+        assert_eq!(app.current_chat, Some(alice));
+    }
+
+    #[test]
+    fn tab_switches_chats_and_keeps_their_buffers() {
+        let mut app = App::new();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        app.conversations = vec![
+            conversation(Recipient::Direct(alice), "alice"),
+            conversation(Recipient::Group(Uuid::new_v4()), "team"),
+            conversation(Recipient::Direct(bob), "bob"),
+        ];
+
+        // A chat opened for the first time asks for its history
+        assert_eq!(app.open_chat(alice), InputAction::LoadOlder);
+        app.chat.prepend_history(Vec::new(), 0);
+        app.handle_message(DisplayMessage::new(alice, "hi".to_string(), Utc::now(), false));
+        app.handle_message(DisplayMessage::new(bob, "not loaded".to_string(), Utc::now(), false));
+        assert_eq!(app.chat.messages.len(), 1);
+
+        // Tab skips the group
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::Tab)), InputAction::LoadOlder);
+        assert_eq!(app.current_chat, Some(bob));
+        assert_eq!(app.selected_conversation, 2);
+        assert!(app.chat.messages.is_empty());
+        app.chat.prepend_history(Vec::new(), 0);
+
+        // Messages for a chat in the background land in its buffer
+        app.handle_message(DisplayMessage::new(alice, "still there?".to_string(), Utc::now(), false));
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::Tab)), InputAction::None);
+        assert_eq!(app.current_chat, Some(alice));
+        assert_eq!(app.chat.messages.len(), 2);
+
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::BackTab)), InputAction::None);
+        assert_eq!(app.current_chat, Some(bob));
+
+        app.handle_key(KeyEvent::from(KeyCode::Char('s')));
+        assert!(!app.show_sidebar);
+        app.handle_key(KeyEvent::from(KeyCode::Char('m')));
+        assert!(app.raw_markdown);
+    }
+
+    #[test]
+    fn viewing_only_while_chat_is_open() {
+        let mut app = App::new();
+        let alice = PeerId::random();
+
+        app.current_chat = Some(alice);
+        assert_eq!(app.viewing(), None);
+        app.mode = AppMode::Chat;
+        assert_eq!(app.viewing(), Some(alice));
+        app.mode = AppMode::Search;
+        assert_eq!(app.viewing(), None);
+    }
+
+    #[test]
+    fn scrolling_up_loads_older_history() {
+        let mut app = App::new();
+        app.mode = AppMode::Chat;
+        let me = PeerId::random();
+        let page = |n: usize| -> Vec<DisplayMessage> {
+            (0..n)
+                .map(|i| DisplayMessage::new(me, i.to_string(), Utc::now(), true).with_id(Uuid::new_v4()))
+                .collect()
+        };
+        app.chat.prepend_history(page(15), HISTORY_PAGE);
+
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::Char('k'))), InputAction::None);
+        assert_eq!(app.chat.scroll, 1);
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::PageUp)), InputAction::LoadOlder);
+        assert_eq!(app.chat.scroll, 11);
+
+        // Older messages go above, and already loaded ones aren't repeated
+        let mut older = page(3);
+        older.push(app.chat.messages[0].clone());
+        let newest = app.chat.messages[14].id;
+        app.chat.prepend_history(older, 4);
+        assert_eq!(app.chat.history_loaded(), HISTORY_PAGE + 4);
+        assert_eq!(app.chat.messages.len(), 18);
+        assert_eq!(app.chat.messages[17].id, newest);
+
+        // Scrolling stops at the oldest message, with nothing more to load
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::PageUp)), InputAction::None);
+        assert_eq!(app.chat.scroll, 17);
+        app.handle_key(KeyEvent::from(KeyCode::PageDown));
+        app.handle_key(KeyEvent::from(KeyCode::Char('j')));
+        assert_eq!(app.chat.scroll, 6);
+    }
+
+    #[test]
+    fn slash_opens_search_and_enter_submits() {
+        let mut app = App::new();
+        app.mode = AppMode::Chat;
+
+        app.handle_key(KeyEvent::from(KeyCode::Char('/')));
+        assert_eq!(app.mode, AppMode::Search);
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::Enter)), InputAction::None);
+
+        for c in "lunch".chars() {
+            app.handle_key(KeyEvent::from(KeyCode::Char(c)));
+        }
+        assert_eq!(
+            app.handle_key(KeyEvent::from(KeyCode::Enter)),
+            InputAction::Search("lunch".to_string())
+        );
+        assert_eq!(app.mode, AppMode::Search);
+
+        app.handle_key(KeyEvent::from(KeyCode::Esc));
+        assert_eq!(app.mode, AppMode::Chat);
+        assert!(app.search.is_empty());
+    }
+
+    #[test]
+    fn r_renames_the_selected_contact() {
+        let mut app = App::new();
+        let alice = PeerId::random();
+        app.add_contact(Contact::new(alice, "alice".to_string(), vec![]));
+        app.conversations = vec![
+            conversation(Recipient::Direct(alice), "alice"),
+            conversation(Recipient::Group(Uuid::new_v4()), "team"),
+        ];
+
+        app.handle_key(KeyEvent::from(KeyCode::Char('r')));
+        assert_eq!(app.mode, AppMode::Rename);
+        assert_eq!(app.rename, "alice");
+        for _ in 0..3 {
+            app.handle_key(KeyEvent::from(KeyCode::Backspace));
+        }
+        app.handle_key(KeyEvent::from(KeyCode::Char('y')));
+        let action = app.handle_key(KeyEvent::from(KeyCode::Enter));
+        assert_eq!(action, InputAction::Rename(alice, "aly".to_string()));
+
+        app.contact_renamed(alice, "aly");
+        assert_eq!(app.mode, AppMode::Contacts);
+        assert_eq!(app.contacts[0].alias, "aly");
+        assert_eq!(app.conversations[0].name, "aly");
+
+        // Groups aren't renamed here
+        app.handle_key(KeyEvent::from(KeyCode::Char('j')));
+        app.handle_key(KeyEvent::from(KeyCode::Char('r')));
+        assert_eq!(app.mode, AppMode::Contacts);
+    }
+
+    #[test]
+    fn pinning_and_archiving_move_conversations() {
+        let mut app = App::new();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let mut archived = conversation(Recipient::Direct(bob), "bob");
+        archived.archived = true;
+        app.set_conversations(vec![
+            conversation(Recipient::Direct(alice), "alice"),
+            conversation(Recipient::Group(Uuid::new_v4()), "team"),
+            archived,
+        ]);
+        assert_eq!(app.conversations.len(), 2);
+        assert_eq!(app.archived_count(), 1);
+
+        // Pinning the team moves it to the top and keeps it selected
+        app.handle_key(KeyEvent::from(KeyCode::Char('j')));
+        let team = app.conversations[1].with;
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::Char('p'))), InputAction::Pin(team, true));
+        assert_eq!(app.conversations[0].name, "team");
+        assert_eq!(app.selected_conversation, 0);
+
+        // Archiving it leaves alice
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::Char('a'))), InputAction::Archive(team, true));
+        assert_eq!(app.conversations.len(), 1);
+        assert_eq!(app.conversations[0].name, "alice");
+        assert_eq!(app.archived_count(), 2);
+
+        // The archive view shows the rest, and messages still reach them
+        app.handle_key(KeyEvent::from(KeyCode::Char('A')));
+        let names: Vec<&str> = app.conversations.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["team", "bob"]);
+        let msg = Message::new_text(alice, Recipient::Direct(PeerId::random()), "hi".to_string());
+        app.record_message(Recipient::Direct(alice), &msg);
+        app.handle_key(KeyEvent::from(KeyCode::Char('A')));
+        assert_eq!(app.conversations[0].unread, 1);
+    }
+
+    #[test]
+    fn nothing_is_sent_after_a_key_change() {
+        let mut app = App::new();
+        let alice = PeerId::random();
+        app.open_chat(alice);
+        assert_eq!(app.key_warning(), None);
+        app.key_changed.insert(alice);
+        assert!(app.key_warning().unwrap().contains("whisper verify"));
+        app.handle_key(KeyEvent::from(KeyCode::Char('i')));
+        app.handle_key(KeyEvent::from(KeyCode::Char('h')));
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::Enter)), InputAction::None);
+        assert_eq!(app.input, "h");
+
+        app.key_changed.remove(&alice);
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::Enter)), InputAction::Send("h".to_string()));
+    }
+
+    #[test]
+    fn c_prompts_for_an_address_to_connect_to() {
+        let mut app = App::new();
+        app.handle_key(KeyEvent::from(KeyCode::Char('c')));
+        assert_eq!(app.mode, AppMode::Connect);
+        // Nothing typed yet
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::Enter)), InputAction::None);
+
+        for c in "10.0.0.2:4001".chars() {
+            app.handle_key(KeyEvent::from(KeyCode::Char(c)));
+        }
+        let action = app.handle_key(KeyEvent::from(KeyCode::Enter));
+        assert_eq!(action, InputAction::Connect("10.0.0.2:4001".to_string()));
+
+        // The result stays up until Esc
+        app.connect_result = Some(Ok("alice".to_string()));
+        assert_eq!(app.mode, AppMode::Connect);
+        app.handle_key(KeyEvent::from(KeyCode::Esc));
+        assert_eq!(app.mode, AppMode::Contacts);
+        assert!(app.connect.is_empty());
+        assert!(app.connect_result.is_none());
+    }
+
+    #[test]
+    fn d_deletes_our_last_message() {
+        let mut app = App::new();
+        app.mode = AppMode::Chat;
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let me = PeerId::random();
+        app.chat.messages.push(DisplayMessage::new(me, "one".to_string(), Utc::now(), true).with_id(first));
+        app.chat.messages.push(DisplayMessage::new(me, "two".to_string(), Utc::now(), true).with_id(second));
+        app.chat.messages.push(DisplayMessage::new(PeerId::random(), "three".to_string(), Utc::now(), false));
+
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::Char('d'))), InputAction::Delete(second));
+        app.mark_deleted(&second);
+        assert_eq!(app.chat.messages[1].content, DELETED_MESSAGE);
+
+        // The next press reaches further back
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::Char('d'))), InputAction::Delete(first));
+    }
+
+    #[test]
+    fn transfer_progress_updates_and_finishes() {
+        let mut app = App::new();
+        let id = Uuid::new_v4();
+        app.track_transfer(id, "notes.txt".to_string(), 4, false);
+
+        app.update_transfer(id, 2, 4, false);
+        assert_eq!(app.transfers.len(), 1);
+        assert_eq!(app.transfers[0].filename, "notes.txt");
+        assert!((app.transfers[0].ratio() - 0.5).abs() < f64::EPSILON);
+
+        app.update_transfer(id, 4, 4, false);
+        assert!(app.transfers.is_empty());
+    }
+
     #[test]
     fn enter_in_input_mode_sends() {
         let mut app = App::new();
@@ -271,4 +1409,88 @@ mod tests {
         assert!(app.input.is_empty());
         assert_eq!(app.mode, AppMode::Chat);
     }
+
+    #[test]
+    fn modified_enter_inserts_newline() {
+        let mut app = App::new();
+        app.mode = AppMode::Input;
+        app.set_input("first".to_string());
+
+        app.handle_key(KeyEvent::new(KeyCode::Enter, KeyModifiers::ALT));
+        app.handle_key(KeyEvent::from(KeyCode::Char('x')));
+        app.handle_key(KeyEvent::new(KeyCode::Enter, KeyModifiers::SHIFT));
+        assert_eq!(app.input, "first\nx\n");
+        assert_eq!(app.mode, AppMode::Input);
+
+        let action = app.handle_key(KeyEvent::from(KeyCode::Enter));
+        assert_eq!(action, InputAction::Send("first\nx\n".to_string()));
+    }
+
+    #[test]
+    fn cursor_moves_and_edits_mid_string() {
+        let mut app = App::new();
+        app.mode = AppMode::Input;
+        app.set_input("héllo world".to_string());
+
+        app.handle_key(KeyEvent::from(KeyCode::Home));
+        app.handle_key(KeyEvent::from(KeyCode::Right));
+        app.handle_key(KeyEvent::from(KeyCode::Right));
+        app.handle_key(KeyEvent::from(KeyCode::Backspace));
+        assert_eq!(app.input, "hllo world");
+        app.handle_key(KeyEvent::from(KeyCode::Char('e')));
+        assert_eq!(app.input, "hello world");
+        app.handle_key(KeyEvent::from(KeyCode::Delete));
+        assert_eq!(app.input, "helo world");
+
+        app.handle_key(KeyEvent::new(KeyCode::Right, KeyModifiers::CONTROL));
+        app.handle_key(KeyEvent::from(KeyCode::Char(',')));
+        assert_eq!(app.input, "helo, world");
+        app.handle_key(KeyEvent::new(KeyCode::Left, KeyModifiers::CONTROL));
+        assert_eq!(app.cursor, 0);
+        app.handle_key(KeyEvent::from(KeyCode::End));
+        app.handle_key(KeyEvent::from(KeyCode::Delete));
+        assert_eq!(app.input, "helo, world");
+        assert_eq!(app.cursor, app.input.len());
+    }
+
+    #[test]
+    fn home_and_end_stay_on_the_line() {
+        let mut app = App::new();
+        app.mode = AppMode::Input;
+        app.set_input("one\ntwo\nthree".to_string());
+        app.cursor = 5;
+
+        app.handle_key(KeyEvent::from(KeyCode::Home));
+        assert_eq!(app.cursor, 4);
+        app.handle_key(KeyEvent::from(KeyCode::End));
+        assert_eq!(app.cursor, 7);
+    }
+
+    #[test]
+    fn image_previews_load_once_the_file_is_here() {
+        let mut app = App::new();
+        app.mode = AppMode::Chat;
+        let transfer_id = Uuid::new_v4();
+        let text = "[image] cat.png (100 bytes) - press o to open".to_string();
+        app.chat.messages.push(
+            DisplayMessage::new(PeerId::random(), text, Utc::now(), false).with_attachment(Some(transfer_id)),
+        );
+        assert_eq!(app.handle_key(KeyEvent::from(KeyCode::Char('o'))), InputAction::Open(transfer_id));
+        assert!(app.sync_previews().is_empty(), "the terminal can't draw images");
+
+        app.image_protocol = Some(ImageProtocol::Kitty);
+        assert_eq!(app.sync_previews(), vec![transfer_id]);
+        // Still arriving
+        app.set_preview(transfer_id, None);
+        assert!(app.sync_previews().is_empty());
+        app.update_transfer(transfer_id, 3, 3, false);
+        assert_eq!(app.sync_previews(), vec![transfer_id]);
+
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::new_rgb8(16, 32).write_to(&mut png, image::ImageFormat::Png).unwrap();
+        let preview = Preview::encode(ImageProtocol::Kitty, png.get_ref(), (8, 16)).unwrap();
+        app.set_preview(transfer_id, Some(preview));
+        assert_eq!(app.chat.messages[0].preview_rows, 2);
+        assert!(app.sync_previews().is_empty());
+    }
 }
@@ -0,0 +1,279 @@
+//! Image previews in the chat view.
+//!
+//! Terminals that can draw images each have their own escape sequences:
+//! kitty's graphics protocol, iTerm2's inline images, or sixel. Which one
+//! to use is worked out from the environment when the TUI starts. The chat
+//! view leaves blank rows under an image message and the previews are
+//! drawn into them once ratatui has drawn the frame. Other terminals get a
+//! placeholder line, and `o` opens the image in the system's viewer.
+
+use std::collections::HashMap;
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use crossterm::{cursor::MoveTo, queue};
+use image::{DynamicImage, ImageFormat};
+use ratatui::layout::Rect;
+use uuid::Uuid;
+
+/// Tallest a preview is drawn, in rows.
+pub const PREVIEW_ROWS: u16 = 8;
+
+/// Widest a preview is drawn, in columns.
+pub const PREVIEW_COLUMNS: u16 = 32;
+
+/// Cell size in pixels when the terminal doesn't report one.
+const DEFAULT_CELL_SIZE: (u32, u32) = (8, 16);
+
+/// Largest piece of a kitty image sent in one escape sequence.
+const KITTY_CHUNK: usize = 4096;
+
+/// Extensions of files shown as image previews.
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif"];
+
+/// A way of drawing images in the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageProtocol {
+    /// kitty's graphics protocol, also spoken by Ghostty.
+    Kitty,
+    /// iTerm2's inline images, also spoken by WezTerm.
+    Iterm,
+    /// Sixel graphics.
+    Sixel,
+}
+
+impl ImageProtocol {
+    /// The protocol the terminal we're running in understands, if any.
+    /// `WHISPER_IMAGES` overrides the guess: `kitty`, `iterm`, `sixel`, or
+    /// `off`.
+    pub fn detect() -> Option<Self> {
+        Self::from_env(|key| std::env::var(key).ok())
+    }
+
+    fn from_env(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        if let Some(choice) = var("WHISPER_IMAGES") {
+            return match choice.to_ascii_lowercase().as_str() {
+                "kitty" => Some(Self::Kitty),
+                "iterm" => Some(Self::Iterm),
+                "sixel" => Some(Self::Sixel),
+                _ => None,
+            };
+        }
+        let term = var("TERM").unwrap_or_default();
+        // Multiplexers don't pass the sequences through unless wrapped
+        if var("TMUX").is_some() || term.starts_with("screen") || term.starts_with("tmux") {
+            return None;
+        }
+        let program = var("TERM_PROGRAM").unwrap_or_default();
+        if var("KITTY_WINDOW_ID").is_some() || term.contains("kitty") || term.contains("ghostty") {
+            Some(Self::Kitty)
+        } else if program == "iTerm.app" || program == "WezTerm" || var("LC_TERMINAL").as_deref() == Some("iTerm2") {
+            Some(Self::Iterm)
+        } else if term.contains("sixel") || ["foot", "mlterm", "contour"].iter().any(|t| term.starts_with(t)) {
+            Some(Self::Sixel)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether a file is shown as an image, going by its name.
+pub fn is_image(filename: &str) -> bool {
+    Path::new(filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// An image scaled down and encoded for the terminal.
+#[derive(Debug, Clone)]
+pub struct Preview {
+    /// Width in columns.
+    pub columns: u16,
+    /// Height in rows.
+    pub rows: u16,
+    /// The escape sequence that draws it at the cursor.
+    escape: Vec<u8>,
+}
+
+impl Preview {
+    /// Scale `data` down to fit in `PREVIEW_COLUMNS` by `PREVIEW_ROWS`
+    /// cells of `cell` pixels, and encode it for `protocol`.
+    pub fn encode(protocol: ImageProtocol, data: &[u8], cell: (u32, u32)) -> Result<Self> {
+        let mut image = image::load_from_memory(data).context("Not an image we can show")?;
+        let (width, height) = (u32::from(PREVIEW_COLUMNS) * cell.0, u32::from(PREVIEW_ROWS) * cell.1);
+        // Small images are shown as they are
+        if image.width() > width || image.height() > height {
+            image = image.thumbnail(width, height);
+        }
+        let escape = match protocol {
+            ImageProtocol::Kitty => kitty_escape(&png_bytes(&image)?),
+            ImageProtocol::Iterm => iterm_escape(&png_bytes(&image)?),
+            ImageProtocol::Sixel => sixel_escape(&image)?,
+        };
+        Ok(Self {
+            columns: image.width().div_ceil(cell.0).max(1) as u16,
+            rows: image.height().div_ceil(cell.1).max(1) as u16,
+            escape,
+        })
+    }
+}
+
+/// The size of a terminal cell in pixels.
+pub fn cell_size() -> (u32, u32) {
+    match crossterm::terminal::window_size() {
+        Ok(size) if size.width > 0 && size.height > 0 && size.columns > 0 && size.rows > 0 => (
+            u32::from(size.width / size.columns).max(1),
+            u32::from(size.height / size.rows).max(1),
+        ),
+        _ => DEFAULT_CELL_SIZE,
+    }
+}
+
+/// Draw previews at their places on screen, skipping any not loaded or
+/// too wide for their place.
+pub fn draw_previews(
+    out: &mut impl Write,
+    previews: &HashMap<Uuid, Preview>,
+    placements: &[(Uuid, Rect)],
+) -> io::Result<()> {
+    for (id, area) in placements {
+        if let Some(preview) = previews.get(id).filter(|preview| preview.columns <= area.width) {
+            queue!(out, MoveTo(area.x, area.y))?;
+            out.write_all(&preview.escape)?;
+        }
+    }
+    out.flush()
+}
+
+/// Remove drawn previews. Sixel and iTerm images are part of the cells
+/// they cover and go when those are redrawn; kitty's float above them.
+pub fn clear_previews(out: &mut impl Write, protocol: ImageProtocol) -> io::Result<()> {
+    if protocol == ImageProtocol::Kitty {
+        out.write_all(b"\x1b_Ga=d,q=2\x1b\\")?;
+    }
+    out.flush()
+}
+
+/// Write a file to the temporary directory and open it with the system's
+/// default application. Returns where it was written.
+pub fn open_externally(filename: &str, data: &[u8]) -> Result<PathBuf> {
+    // The name came from the sender, so keep only its last component
+    let name = Path::new(filename)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "attachment".to_string());
+    let dir = std::env::temp_dir().join("whisper");
+    std::fs::create_dir_all(&dir).context("Failed to create a temporary directory")?;
+    let path = dir.join(name);
+    std::fs::write(&path, data).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else if cfg!(windows) {
+        "explorer"
+    } else {
+        "xdg-open"
+    };
+    Command::new(opener)
+        .arg(&path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", opener))?;
+    Ok(path)
+}
+
+fn png_bytes(image: &DynamicImage) -> Result<Vec<u8>> {
+    let mut buf = Cursor::new(Vec::new());
+    image
+        .write_to(&mut buf, ImageFormat::Png)
+        .context("Failed to encode the preview")?;
+    Ok(buf.into_inner())
+}
+
+/// A PNG for kitty, split into chunks it reassembles.
+fn kitty_escape(png: &[u8]) -> Vec<u8> {
+    let encoded = BASE64.encode(png);
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(KITTY_CHUNK).collect();
+    let mut escape = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(i + 1 < chunks.len());
+        if i == 0 {
+            // Transmit and display, without moving the cursor or replying
+            escape.extend_from_slice(format!("\x1b_Ga=T,f=100,C=1,q=2,m={};", more).as_bytes());
+        } else {
+            escape.extend_from_slice(format!("\x1b_Gm={};", more).as_bytes());
+        }
+        escape.extend_from_slice(chunk);
+        escape.extend_from_slice(b"\x1b\\");
+    }
+    escape
+}
+
+/// A PNG as an iTerm2 inline image.
+fn iterm_escape(png: &[u8]) -> Vec<u8> {
+    format!(
+        "\x1b]1337;File=inline=1;size={};preserveAspectRatio=1:{}\x07",
+        png.len(),
+        BASE64.encode(png)
+    )
+    .into_bytes()
+}
+
+fn sixel_escape(image: &DynamicImage) -> Result<Vec<u8>> {
+    let rgb = image.to_rgb8();
+    icy_sixel::sixel_string(
+        rgb.as_raw(),
+        rgb.width() as i32,
+        rgb.height() as i32,
+        icy_sixel::PixelFormat::RGB888,
+        icy_sixel::DiffusionMethod::Stucki,
+        icy_sixel::MethodForLargest::Auto,
+        icy_sixel::MethodForRep::Auto,
+        icy_sixel::Quality::HIGH,
+    )
+    .map(String::into_bytes)
+    .map_err(|e| anyhow!("Failed to encode the preview as sixel: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    #[test]
+    fn protocol_is_detected_from_the_environment() {
+        let detect = |vars: &[(&str, &str)]| ImageProtocol::from_env(env(vars));
+        assert_eq!(detect(&[("TERM", "xterm-kitty")]), Some(ImageProtocol::Kitty));
+        assert_eq!(detect(&[("TERM_PROGRAM", "iTerm.app")]), Some(ImageProtocol::Iterm));
+        assert_eq!(detect(&[("TERM", "foot")]), Some(ImageProtocol::Sixel));
+        assert_eq!(detect(&[("TERM", "xterm-256color")]), None);
+        assert_eq!(detect(&[("TERM", "xterm-kitty"), ("TMUX", "/tmp/tmux")]), None);
+        assert_eq!(detect(&[("TERM", "xterm-kitty"), ("WHISPER_IMAGES", "off")]), None);
+        assert_eq!(detect(&[("TERM", "xterm"), ("WHISPER_IMAGES", "sixel")]), Some(ImageProtocol::Sixel));
+    }
+
+    #[test]
+    fn previews_fit_the_box_in_every_protocol() {
+        let image = DynamicImage::new_rgb8(400, 100);
+        let png = png_bytes(&image).unwrap();
+        assert!(is_image("Cat.PNG") && !is_image("notes.txt"));
+
+        for protocol in [ImageProtocol::Kitty, ImageProtocol::Iterm, ImageProtocol::Sixel] {
+            let preview = Preview::encode(protocol, &png, (8, 16)).unwrap();
+            // Four times as wide as it's tall, so the width is what limits it
+            assert_eq!((preview.columns, preview.rows), (PREVIEW_COLUMNS, 4));
+            assert!(preview.escape.starts_with(b"\x1b"));
+        }
+        assert!(Preview::encode(ImageProtocol::Kitty, b"not an image", (8, 16)).is_err());
+    }
+}
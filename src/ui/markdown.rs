@@ -0,0 +1,186 @@
+//! Markdown in chat messages.
+//!
+//! Only the bits people type in chat are understood: `**bold**`,
+//! `*italics*` or `_italics_`, `` `inline code` ``, fenced code blocks
+//! and `[links](https://example.com)`. Markers that aren't closed are
+//! shown as they are. Every line of the message stays one line on screen,
+//! fences included, so a message takes the same rows either way.
+
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+
+/// Marks the start or end of a code block.
+const FENCE: &str = "```";
+
+/// A message's lines styled from its markdown, on top of `style`.
+pub fn markdown_lines(text: &str, style: Style) -> Vec<Line<'static>> {
+    let mut in_code = false;
+    text.split('\n')
+        .map(|line| {
+            if line.trim_start().starts_with(FENCE) {
+                in_code = !in_code;
+                Line::from(Span::styled(line.to_string(), style.fg(Color::DarkGray)))
+            } else if in_code {
+                Line::from(Span::styled(line.to_string(), code_style(style)))
+            } else {
+                let mut spans = Vec::new();
+                inline(line, style, &mut spans);
+                Line::from(spans)
+            }
+        })
+        .collect()
+}
+
+fn code_style(style: Style) -> Style {
+    style.fg(Color::LightYellow)
+}
+
+/// Style one line's inline markup into `spans`.
+fn inline(text: &str, style: Style, spans: &mut Vec<Span<'static>>) {
+    let mut plain = String::new();
+    let mut rest = text;
+    let mut previous = None;
+    while let Some(c) = rest.chars().next() {
+        if let Some((markup, after)) = markup(rest, previous) {
+            if !plain.is_empty() {
+                spans.push(Span::styled(std::mem::take(&mut plain), style));
+            }
+            match markup {
+                Markup::Bold(inner) => inline(inner, style.add_modifier(Modifier::BOLD), spans),
+                Markup::Italic(inner) => inline(inner, style.add_modifier(Modifier::ITALIC), spans),
+                Markup::Code(inner) => spans.push(Span::styled(inner.to_string(), code_style(style))),
+                Markup::Link { text, url } => {
+                    inline(text, style.add_modifier(Modifier::UNDERLINED), spans);
+                    if text != url {
+                        spans.push(Span::styled(format!(" ({})", url), style.fg(Color::DarkGray)));
+                    }
+                }
+            }
+            previous = rest[..rest.len() - after.len()].chars().last();
+            rest = after;
+        } else {
+            plain.push(c);
+            previous = Some(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    if !plain.is_empty() {
+        spans.push(Span::styled(plain, style));
+    }
+}
+
+enum Markup<'a> {
+    Bold(&'a str),
+    Italic(&'a str),
+    Code(&'a str),
+    Link { text: &'a str, url: &'a str },
+}
+
+/// The markup `text` starts with, if it's closed, and what follows it.
+/// `previous` is the character before, so `snake_case` isn't italics.
+fn markup(text: &str, previous: Option<char>) -> Option<(Markup<'_>, &str)> {
+    if let Some(rest) = text.strip_prefix('`') {
+        let end = rest.find('`').filter(|&end| end > 0)?;
+        return Some((Markup::Code(&rest[..end]), &rest[end + 1..]));
+    }
+    if let Some(rest) = text.strip_prefix("**") {
+        let end = rest.find("**").filter(|&end| hugs(&rest[..end]))?;
+        return Some((Markup::Bold(&rest[..end]), &rest[end + 2..]));
+    }
+    if let Some(rest) = text.strip_prefix('[') {
+        let (label, rest) = rest.split_once("](")?;
+        let (url, after) = rest.split_once(')')?;
+        if label.is_empty() || url.is_empty() || url.contains(char::is_whitespace) {
+            return None;
+        }
+        return Some((Markup::Link { text: label, url }, after));
+    }
+    let marker = text.chars().next().filter(|&c| c == '*' || c == '_')?;
+    if previous.is_some_and(char::is_alphanumeric) {
+        return None;
+    }
+    let rest = &text[1..];
+    // The closing marker can't be followed by a letter either
+    let end = rest.match_indices(marker).map(|(i, _)| i).find(|&i| {
+        !rest[i + 1..].starts_with(|c: char| c.is_alphanumeric() || c == marker)
+    })?;
+    hugs(&rest[..end]).then(|| (Markup::Italic(&rest[..end]), &rest[end + 1..]))
+}
+
+/// Whether emphasised text is non-empty and doesn't start or end with a
+/// space, as in `**this**` but not `2 ** 3 ** 4`.
+fn hugs(inner: &str) -> bool {
+    !inner.is_empty() && !inner.starts_with(char::is_whitespace) && !inner.ends_with(char::is_whitespace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each span's text and whether it's bold, italic, code or underlined.
+    fn styled(line: &Line) -> Vec<(String, &'static str)> {
+        line.spans
+            .iter()
+            .map(|span| {
+                let kind = if span.style.add_modifier.contains(Modifier::BOLD) {
+                    "bold"
+                } else if span.style.add_modifier.contains(Modifier::ITALIC) {
+                    "italic"
+                } else if span.style.add_modifier.contains(Modifier::UNDERLINED) {
+                    "link"
+                } else if span.style.fg == Some(Color::LightYellow) {
+                    "code"
+                } else if span.style.fg == Some(Color::DarkGray) {
+                    "dim"
+                } else {
+                    "plain"
+                };
+                (span.content.to_string(), kind)
+            })
+            .collect()
+    }
+
+    fn one_line(text: &str) -> Vec<(String, &'static str)> {
+        styled(&markdown_lines(text, Style::default())[0])
+    }
+
+    #[test]
+    fn inline_markup_is_styled() {
+        assert_eq!(
+            one_line("a **bold** and *italic* `code`"),
+            [
+                ("a ".into(), "plain"),
+                ("bold".into(), "bold"),
+                (" and ".into(), "plain"),
+                ("italic".into(), "italic"),
+                (" ".into(), "plain"),
+                ("code".into(), "code"),
+            ]
+        );
+        assert_eq!(
+            one_line("see [the docs](https://example.com)."),
+            [
+                ("see ".into(), "plain"),
+                ("the docs".into(), "link"),
+                (" (https://example.com)".into(), "dim"),
+                (".".into(), "plain"),
+            ]
+        );
+    }
+
+    #[test]
+    fn stray_markers_are_left_alone() {
+        for text in ["snake_case_name", "2 * 3 * 4", "**not closed", "a ` b", "[x] (y)", "_"] {
+            assert_eq!(one_line(text), [(text.to_string(), "plain")], "{}", text);
+        }
+    }
+
+    #[test]
+    fn code_blocks_keep_their_lines() {
+        let lines = markdown_lines("run:\n```\nlet **x** = 1;\n```\ndone", Style::default());
+        assert_eq!(lines.len(), 5);
+        assert_eq!(styled(&lines[1]), [("```".into(), "dim")]);
+        assert_eq!(styled(&lines[2]), [("let **x** = 1;".into(), "code")]);
+        assert_eq!(styled(&lines[4]), [("done".into(), "plain")]);
+    }
+}
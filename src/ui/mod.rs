@@ -1,12 +1,23 @@
 //! Terminal UI.
 
 mod app;
+mod images;
 mod input;
+mod markdown;
+mod terminal;
 mod views;
 
-pub use app::{App, AppMode, DisplayMessage, InputAction};
+pub use app::{
+    App, AppMode, ChatBuffer, DisplayMessage, InputAction, TransferView, DELETED_MESSAGE, HISTORY_PAGE,
+    TYPING_SEND_INTERVAL, TYPING_TIMEOUT,
+};
+pub use images::{cell_size, clear_previews, draw_previews, is_image, open_externally, ImageProtocol, Preview};
 pub use input::{
     handle_chat_mode, handle_contacts_mode, handle_input_mode, ChatAction, ContactAction,
     InputResult,
 };
-pub use views::{render_chat, render_contacts, render_empty, render_status, short_peer_id};
+pub use terminal::TerminalGuard;
+pub use views::{
+    render_chat, render_connect, render_conversations, render_empty, render_rename, render_search,
+    render_sidebar, render_status, render_transfers, render_warning, short_peer_id, transfers_height,
+};
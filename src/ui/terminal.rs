@@ -0,0 +1,94 @@
+//! Taking over the terminal for the TUI and always handing it back.
+//!
+//! `TerminalGuard` switches to raw mode and the alternate screen, and
+//! switches back when it's dropped, so an early `?` can't leave the shell
+//! unusable. A panic hook does the same before the panic message prints,
+//! but only for panics on the thread running the TUI: a panicking
+//! background task doesn't end the TUI, so it shouldn't tear it down.
+
+use std::io::{self, Stdout};
+use std::ops::{Deref, DerefMut};
+use std::panic;
+use std::sync::{Mutex, Once};
+use std::thread::{self, ThreadId};
+
+use anyhow::Result;
+use crossterm::{
+    cursor::Show,
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{backend::CrosstermBackend, Terminal};
+
+/// The thread that has the terminal in TUI mode, if any.
+static OWNER: Mutex<Option<ThreadId>> = Mutex::new(None);
+
+/// The terminal, in TUI mode until dropped.
+pub struct TerminalGuard {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+}
+
+impl TerminalGuard {
+    /// Put the terminal into TUI mode.
+    pub fn enter() -> Result<Self> {
+        install_panic_hook();
+        enable_raw_mode()?;
+        *OWNER.lock().unwrap_or_else(|e| e.into_inner()) = Some(thread::current().id());
+
+        let terminal = execute!(io::stdout(), EnterAlternateScreen)
+            .and_then(|_| Terminal::new(CrosstermBackend::new(io::stdout())));
+        match terminal {
+            Ok(terminal) => Ok(Self { terminal }),
+            Err(e) => {
+                restore();
+                Err(e.into())
+            }
+        }
+    }
+}
+
+impl Deref for TerminalGuard {
+    type Target = Terminal<CrosstermBackend<Stdout>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.terminal
+    }
+}
+
+impl DerefMut for TerminalGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.terminal
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+/// Leave TUI mode, if we're in it. Errors are ignored: this runs while
+/// something else has already gone wrong.
+fn restore() {
+    if OWNER.lock().unwrap_or_else(|e| e.into_inner()).take().is_none() {
+        return;
+    }
+    let _ = disable_raw_mode();
+    let _ = execute!(io::stdout(), LeaveAlternateScreen, Show);
+}
+
+/// Restore the terminal before a panic on the TUI thread is reported, so
+/// the message is readable and the shell works afterwards.
+fn install_panic_hook() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        let report = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let owner = *OWNER.lock().unwrap_or_else(|e| e.into_inner());
+            if owner == Some(thread::current().id()) {
+                restore();
+            }
+            report(info);
+        }));
+    });
+}
@@ -1,52 +1,127 @@
 //! Render views for the TUI.
 
+use chrono::{DateTime, Utc};
 use libp2p::PeerId;
 use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    widgets::{Block, Borders, LineGauge, List, ListItem, Paragraph},
     Frame,
 };
+use uuid::Uuid;
 
-use crate::identity::Contact;
+use crate::crypto::emoji_fingerprint;
+use crate::identity::{Contact, PresenceStatus, TrustLevel};
+use crate::message::{Conversation, Recipient};
+use crate::network::Latency;
 
-use super::app::DisplayMessage;
+use super::app::{ChatBuffer, DisplayMessage, TransferView};
+use super::markdown::markdown_lines;
 
-/// Render the chat view with messages and input.
+/// Who a chat message is shown as from.
+fn sender_label(msg: &DisplayMessage) -> &'static str {
+    match (msg.is_ours, msg.unverified) {
+        (true, _) => "You",
+        (false, true) => "Them (unverified)",
+        (false, false) => "Them",
+    }
+}
+
+/// Render the chat view with messages and input. `cursor` is the cursor's
+/// byte offset in `input` while typing, and `raw` shows markdown as typed.
+/// Returns where image previews go, for those that fit on screen.
 pub fn render_chat(
     frame: &mut Frame,
     area: Rect,
-    messages: &[DisplayMessage],
+    chat: &ChatBuffer,
     input: &str,
-    is_input_mode: bool,
-) {
-    // Split into messages area and input area
+    cursor: Option<usize>,
+    typing: Option<&str>,
+    raw: bool,
+) -> Vec<(Uuid, Rect)> {
+    let messages = &chat.messages;
+    let is_input_mode = cursor.is_some();
+    // Split into messages area and an input area that grows with its lines
+    let input_lines = input.split('\n').count().min(MAX_INPUT_LINES);
     let chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Min(3), Constraint::Length(3)])
+        .constraints([Constraint::Min(3), Constraint::Length(input_lines as u16 + 2)])
         .split(area);
 
-    // Render messages
-    let message_items: Vec<ListItem> = messages
+    // Render the messages that fit, newest at the bottom
+    let height = chunks[0].height.saturating_sub(2) as usize;
+    let heights: Vec<usize> = messages
+        .iter()
+        .map(|m| m.content.split('\n').count() + m.preview_rows as usize)
+        .collect();
+    let (start, end) = visible_range(&heights, height, chat.scroll);
+    let mut placements = Vec::new();
+    let mut top = 0;
+    let message_items: Vec<ListItem> = messages[start..end]
         .iter()
         .map(|msg| {
             let style = if msg.is_ours {
                 Style::default().fg(Color::Cyan)
+            } else if msg.unverified {
+                Style::default().fg(Color::Yellow)
             } else {
                 Style::default().fg(Color::White)
             };
 
             let time = msg.timestamp.format("%H:%M");
-            let prefix = if msg.is_ours { "You" } else { "Them" };
-            let text = format!("[{}] {}: {}", time, prefix, msg.content);
-            ListItem::new(Line::from(Span::styled(text, style)))
+            let header = format!("[{}] {}: ", time, sender_label(msg));
+            // Continuation lines line up under the first
+            let indent = " ".repeat(header.chars().count());
+            let body = if raw {
+                msg.content.split('\n').map(|line| Line::from(Span::styled(line.to_string(), style))).collect()
+            } else {
+                markdown_lines(&msg.content, style)
+            };
+            let mut lines: Vec<Line> = body
+                .into_iter()
+                .enumerate()
+                .map(|(i, mut line)| {
+                    let lead = if i == 0 { header.clone() } else { indent.clone() };
+                    line.spans.insert(0, Span::styled(lead, style));
+                    line
+                })
+                .collect();
+
+            // Blank rows for the image preview to be drawn over
+            let preview_top = top + lines.len();
+            if let (Some(id), rows @ 1..) = (msg.attachment, msg.preview_rows) {
+                let left = indent.chars().count() as u16;
+                if preview_top + rows as usize <= height && left < chunks[0].width.saturating_sub(2) {
+                    placements.push((
+                        id,
+                        Rect::new(
+                            chunks[0].x + 1 + left,
+                            chunks[0].y + 1 + preview_top as u16,
+                            chunks[0].width - 2 - left,
+                            rows,
+                        ),
+                    ));
+                }
+                lines.extend((0..rows).map(|_| Line::default()));
+            }
+            top += lines.len();
+            ListItem::new(lines)
         })
         .collect();
 
-    let messages_block = Block::default()
-        .title("Messages")
+    let title = if end < messages.len() {
+        format!("Messages (↓ {} newer)", messages.len() - end)
+    } else {
+        "Messages".to_string()
+    };
+    let mut messages_block = Block::default()
+        .title(title)
         .borders(Borders::ALL);
+    if let Some(typing) = typing {
+        let style = Style::default().fg(Color::DarkGray).add_modifier(Modifier::ITALIC);
+        messages_block = messages_block.title_bottom(Line::from(Span::styled(typing, style)));
+    }
 
     let messages_list = List::new(message_items).block(messages_block);
     frame.render_widget(messages_list, chunks[0]);
@@ -59,51 +134,287 @@ pub fn render_chat(
     };
 
     let input_block = Block::default()
-        .title(if is_input_mode { "Input (typing...)" } else { "Input (press i)" })
+        .title(if is_input_mode { "Input (Alt+Enter for a new line)" } else { "Input (press i)" })
         .borders(Borders::ALL)
         .style(input_style);
 
-    let input_widget = Paragraph::new(input).block(input_block);
+    // Keep the cursor's line in view once the box stops growing
+    let last_line = input.split('\n').count() - 1;
+    let (line, column) = cursor.map_or((last_line, 0), |c| cursor_position(input, c));
+    let hidden = (line + 1).saturating_sub(input_lines);
+    let input_widget = Paragraph::new(input)
+        .block(input_block)
+        .scroll((hidden as u16, 0));
     frame.render_widget(input_widget, chunks[1]);
+
+    if is_input_mode {
+        let inner = chunks[1];
+        let x = inner.x + 1 + (column as u16).min(inner.width.saturating_sub(3));
+        let y = inner.y + 1 + (line - hidden) as u16;
+        frame.set_cursor_position((x, y));
+    }
+    placements
+}
+
+/// Line and column, in characters, of byte offset `cursor` in `input`.
+fn cursor_position(input: &str, cursor: usize) -> (usize, usize) {
+    let before = &input[..cursor.min(input.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (before.matches('\n').count(), before[line_start..].chars().count())
+}
+
+/// Render the prompt for a contact's new alias, or why it was refused.
+pub fn render_rename(frame: &mut Frame, area: Rect, alias: &str, error: Option<&str>) {
+    let (title, color) = match error {
+        Some(error) => (error.to_string(), Color::Red),
+        None => ("Rename to (Enter to save, Esc to cancel)".to_string(), Color::Yellow),
+    };
+    let block = Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .style(Style::default().fg(color));
+    frame.render_widget(Paragraph::new(alias).block(block), area);
+}
+
+/// Render the prompt for an address to connect to, or how the last
+/// attempt went.
+pub fn render_connect(frame: &mut Frame, area: Rect, address: &str, result: Option<&Result<String, String>>) {
+    let (title, color) = match result {
+        Some(Ok(peer)) => (format!("Connected to {} (Esc to close)", peer), Color::Green),
+        Some(Err(error)) => (error.clone(), Color::Red),
+        None => ("Connect to multiaddr or ip:port (Enter to dial, Esc to cancel)".to_string(), Color::Yellow),
+    };
+    let block = Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .style(Style::default().fg(color));
+    frame.render_widget(Paragraph::new(address).block(block), area);
+}
+
+/// Tallest the input box grows, in lines of text.
+const MAX_INPUT_LINES: usize = 6;
+
+/// The slice of messages, with the given heights in rows, to show in
+/// `height` rows when scrolled `scroll` messages up from the newest.
+/// Short chats don't scroll.
+fn visible_range(heights: &[usize], height: usize, scroll: usize) -> (usize, usize) {
+    let mut end = heights.len().saturating_sub(scroll);
+    let mut start = end;
+    let mut used = 0;
+    while start > 0 && used + heights[start - 1] <= height {
+        start -= 1;
+        used += heights[start];
+    }
+    // Fill any space left below with newer messages
+    while end < heights.len() && used + heights[end] <= height {
+        used += heights[end];
+        end += 1;
+    }
+    // A message taller than the view is shown cut off
+    if start == end && end > 0 {
+        start = end - 1;
+    }
+    (start, end)
+}
+
+/// Render search results with the query box below them.
+pub fn render_search(
+    frame: &mut Frame,
+    area: Rect,
+    results: &[DisplayMessage],
+    query: &str,
+    contacts: &[Contact],
+) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(3)])
+        .split(area);
+
+    let items: Vec<ListItem> = results
+        .iter()
+        .map(|msg| {
+            let name = if msg.is_ours {
+                "You".to_string()
+            } else {
+                contacts
+                    .iter()
+                    .find(|c| c.peer_id == msg.from)
+                    .map(|c| c.alias.clone())
+                    .unwrap_or_else(|| short_peer_id(&msg.from))
+            };
+            let time = msg.timestamp.format("%Y-%m-%d %H:%M");
+            ListItem::new(Line::from(format!("[{}] {}: {}", time, name, msg.content)))
+        })
+        .collect();
+
+    let results_block = Block::default()
+        .title(format!("Results ({})", results.len()))
+        .borders(Borders::ALL);
+    frame.render_widget(List::new(items).block(results_block), chunks[0]);
+
+    let query_block = Block::default()
+        .title("Search (Enter to search, Esc to close)")
+        .borders(Borders::ALL)
+        .style(Style::default().fg(Color::Yellow));
+    frame.render_widget(Paragraph::new(query).block(query_block), chunks[1]);
 }
 
-/// Render the contact list.
-pub fn render_contacts(
+/// Render the inbox: conversations with their latest message, most
+/// recent first.
+pub fn render_conversations(
     frame: &mut Frame,
     area: Rect,
+    conversations: &[Conversation],
     contacts: &[Contact],
     selected: usize,
+    our_peer_id: Option<PeerId>,
+    archived: Option<usize>,
 ) {
-    let items: Vec<ListItem> = contacts
+    let now = Utc::now();
+    let items: Vec<ListItem> = conversations
         .iter()
         .enumerate()
-        .map(|(i, contact)| {
-            let style = if i == selected {
+        .map(|(i, conversation)| {
+            let mut style = if i == selected {
                 Style::default()
                     .fg(Color::Yellow)
                     .add_modifier(Modifier::BOLD)
             } else {
                 Style::default()
             };
+            if conversation.unread > 0 {
+                style = style.add_modifier(Modifier::BOLD);
+            }
 
-            let status = match contact.trust_level {
-                crate::identity::TrustLevel::Trusted => "✓",
-                crate::identity::TrustLevel::Verified => "◆",
-                crate::identity::TrustLevel::Blocked => "✗",
-                crate::identity::TrustLevel::Unknown => "?",
+            let contact = match conversation.with {
+                Recipient::Direct(peer_id) => contacts.iter().find(|c| c.peer_id == peer_id),
+                Recipient::Group(_) => None,
+            };
+            let marker = match conversation.with {
+                Recipient::Direct(_) => contact.map_or("?", |c| trust_symbol(&c.trust_level)),
+                Recipient::Group(_) => "#",
             };
+            let presence = contact.and_then(|c| c.current_presence(now));
+            let fingerprint = contact
+                .and_then(|c| c.identity_key())
+                .map(|key| emoji_fingerprint(&key));
+            let text = conversation_label(
+                conversation,
+                marker,
+                fingerprint.as_deref(),
+                presence,
+                our_peer_id,
+                now,
+            );
+            ListItem::new(Line::from(Span::styled(text, style)))
+        })
+        .collect();
+
+    let title = match archived {
+        None => "Archived (Enter to chat, a to unarchive, A for the rest)".to_string(),
+        Some(0) => "Conversations (Enter to chat, r to rename, c to connect, p to pin, a to archive)".to_string(),
+        Some(count) => format!(
+            "Conversations (Enter to chat, r to rename, c to connect, p to pin, a to archive, A for {} archived)",
+            count
+        ),
+    };
+    let block = Block::default().title(title).borders(Borders::ALL);
+
+    let list = List::new(items).block(block);
+    frame.render_widget(list, area);
+}
 
-            let text = format!("{} {} ({})", status, contact.alias, short_peer_id(&contact.peer_id));
+/// Render the conversation sidebar shown beside a chat. The open chat is
+/// highlighted; group chats are listed but open in `whisper group chat`.
+pub fn render_sidebar(
+    frame: &mut Frame,
+    area: Rect,
+    conversations: &[Conversation],
+    current: Option<PeerId>,
+) {
+    let items: Vec<ListItem> = conversations
+        .iter()
+        .map(|conversation| {
+            let (name, style) = match conversation.with {
+                Recipient::Direct(peer_id) if Some(peer_id) == current => (
+                    conversation.name.clone(),
+                    Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+                ),
+                Recipient::Direct(_) => (conversation.name.clone(), Style::default()),
+                Recipient::Group(_) => (
+                    format!("#{}", conversation.name),
+                    Style::default().fg(Color::DarkGray),
+                ),
+            };
+            let text = if conversation.unread > 0 {
+                format!("{} ({})", name, conversation.unread)
+            } else {
+                name
+            };
             ListItem::new(Line::from(Span::styled(text, style)))
         })
         .collect();
 
     let block = Block::default()
-        .title("Contacts")
+        .title("Chats (Tab)")
         .borders(Borders::ALL);
+    frame.render_widget(List::new(items).block(block), area);
+}
 
-    let list = List::new(items).block(block);
-    frame.render_widget(list, area);
+/// Longest message preview shown in the inbox, in characters.
+const PREVIEW_CHARS: usize = 40;
+
+fn trust_symbol(trust_level: &TrustLevel) -> &'static str {
+    match trust_level {
+        TrustLevel::Trusted => "✓",
+        TrustLevel::Verified => "◆",
+        TrustLevel::Blocked => "✗",
+        TrustLevel::Unknown => "?",
+    }
+}
+
+/// A conversation's line in the inbox, e.g.
+/// "✓ alice 🦊🐙🍋🐢🦉 · online (3)  14:02  You: see you". Pinned
+/// conversations are starred.
+fn conversation_label(
+    conversation: &Conversation,
+    marker: &str,
+    fingerprint: Option<&str>,
+    presence: Option<PresenceStatus>,
+    our_peer_id: Option<PeerId>,
+    now: DateTime<Utc>,
+) -> String {
+    let mut label = format!("{} {}", marker, conversation.name);
+    if let Some(fingerprint) = fingerprint {
+        label.push_str(&format!(" {}", fingerprint));
+    }
+    if conversation.pinned {
+        label.push_str(" ★");
+    }
+    if let Some(status @ (PresenceStatus::Online | PresenceStatus::Away)) = presence {
+        label.push_str(&format!(" · {}", status));
+    }
+    if conversation.unread > 0 {
+        label.push_str(&format!(" ({})", conversation.unread));
+    }
+
+    if let Some(msg) = &conversation.last_message {
+        let time = if msg.timestamp.date_naive() == now.date_naive() {
+            msg.timestamp.format("%H:%M").to_string()
+        } else {
+            msg.timestamp.format("%b %d").to_string()
+        };
+        // Previews stay on one line
+        let summary = msg.content.summary().replace('\n', " ");
+        let mut preview: String = summary.chars().take(PREVIEW_CHARS).collect();
+        if summary.chars().count() > PREVIEW_CHARS {
+            preview.push('…');
+        }
+        let sender = if Some(msg.from) == our_peer_id { "You: " } else { "" };
+        label.push_str(&format!("  {}  {}{}", time, sender, preview));
+    }
+
+    label
 }
 
 /// Render the status bar.
@@ -112,19 +423,77 @@ pub fn render_status(
     area: Rect,
     peer_id: &PeerId,
     connected_count: usize,
+    latency: Option<Latency>,
+    message_requests: usize,
 ) {
-    let text = format!(
+    let text = status_line(peer_id, connected_count, latency, message_requests);
+
+    let block = Block::default()
+        .title("Status")
+        .borders(Borders::ALL);
+
+    let paragraph = Paragraph::new(text).block(block);
+    frame.render_widget(paragraph, area);
+}
+
+/// The status bar text, with the round trip to the open chat's peer and
+/// pointing at any waiting message requests.
+fn status_line(
+    peer_id: &PeerId,
+    connected_count: usize,
+    latency: Option<Latency>,
+    message_requests: usize,
+) -> String {
+    let mut text = format!(
         "ID: {} | Connected: {} peers",
         short_peer_id(peer_id),
         connected_count
     );
+    if let Some(latency) = latency {
+        text.push_str(&format!(" | {}", latency));
+    }
+    if message_requests > 0 {
+        text.push_str(&format!(
+            " | {} message request(s): whisper requests list",
+            message_requests
+        ));
+    }
+    text
+}
 
+/// Render a progress bar for each active file transfer.
+pub fn render_transfers(frame: &mut Frame, area: Rect, transfers: &[TransferView]) {
     let block = Block::default()
-        .title("Status")
+        .title("Transfers")
         .borders(Borders::ALL);
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
 
-    let paragraph = Paragraph::new(text).block(block);
-    frame.render_widget(paragraph, area);
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(transfers.iter().map(|_| Constraint::Length(1)).collect::<Vec<_>>())
+        .split(inner);
+
+    for (transfer, row) in transfers.iter().zip(rows.iter()) {
+        let arrow = if transfer.outgoing { "↑" } else { "↓" };
+        let gauge = LineGauge::default()
+            .label(format!(
+                "{} {} {}/{}",
+                arrow, transfer.filename, transfer.chunks_done, transfer.total_chunks
+            ))
+            .filled_style(Style::default().fg(Color::Green))
+            .ratio(transfer.ratio());
+        frame.render_widget(gauge, *row);
+    }
+}
+
+/// Height needed to render the given transfers (zero when there are none).
+pub fn transfers_height(transfers: &[TransferView]) -> u16 {
+    if transfers.is_empty() {
+        0
+    } else {
+        transfers.len() as u16 + 2
+    }
 }
 
 /// Shorten a peer ID for display.
@@ -137,6 +506,15 @@ pub fn short_peer_id(peer_id: &PeerId) -> String {
     }
 }
 
+/// Render a warning the user shouldn't miss, in red.
+pub fn render_warning(frame: &mut Frame, area: Rect, message: &str) {
+    let block = Block::default().borders(Borders::ALL).border_style(Style::default().fg(Color::Red));
+    let paragraph = Paragraph::new(message)
+        .style(Style::default().fg(Color::Red).add_modifier(Modifier::BOLD))
+        .block(block);
+    frame.render_widget(paragraph, area);
+}
+
 /// Render an empty state message.
 pub fn render_empty(frame: &mut Frame, area: Rect, message: &str) {
     let block = Block::default().borders(Borders::ALL);
@@ -150,6 +528,33 @@ pub fn render_empty(frame: &mut Frame, area: Rect, message: &str) {
 mod tests {
     use super::*;
 
+    #[test]
+    fn status_line_mentions_message_requests() {
+        let peer_id = PeerId::random();
+        assert!(!status_line(&peer_id, 2, None, 0).contains("request"));
+        let text = status_line(&peer_id, 2, None, 3);
+        assert!(text.contains("Connected: 2 peers"));
+        assert!(text.contains("3 message request(s): whisper requests list"));
+    }
+
+    #[test]
+    fn status_line_shows_latency() {
+        let peer_id = PeerId::random();
+        let latency = Latency { rtt: std::time::Duration::from_millis(180), relayed: true };
+        let text = status_line(&peer_id, 1, Some(latency), 0);
+        assert!(text.ends_with("| 180 ms relayed"));
+    }
+
+    #[test]
+    fn unverified_senders_are_labelled() {
+        let msg = DisplayMessage::new(PeerId::random(), "hi".to_string(), chrono::Utc::now(), false);
+        assert_eq!(sender_label(&msg), "Them");
+        assert_eq!(sender_label(&msg.clone().with_unverified(true)), "Them (unverified)");
+
+        let ours = DisplayMessage::new(PeerId::random(), "hi".to_string(), chrono::Utc::now(), true);
+        assert_eq!(sender_label(&ours), "You");
+    }
+
     #[test]
     fn short_peer_id_truncates_long_id() {
         let peer_id = PeerId::random();
@@ -176,13 +581,16 @@ mod tests {
     fn contact_list_creates_items() {
         use crate::identity::TrustLevel;
         
-        let contacts = vec![
+        let contacts = [
             Contact {
                 peer_id: PeerId::random(),
                 alias: "Alice".to_string(),
                 public_key: vec![],
                 trust_level: TrustLevel::Trusted,
                 last_seen: None,
+                presence: None,
+                note: None,
+                tags: Vec::new(),
             },
             Contact {
                 peer_id: PeerId::random(),
@@ -190,6 +598,9 @@ mod tests {
                 public_key: vec![],
                 trust_level: TrustLevel::Unknown,
                 last_seen: None,
+                presence: None,
+                note: None,
+                tags: Vec::new(),
             },
         ];
         
@@ -198,6 +609,77 @@ mod tests {
         assert_eq!(contacts[0].alias, "Alice");
     }
 
+    #[test]
+    fn visible_range_follows_scroll() {
+        let single = [1; 50];
+        // Newest messages at the bottom by default
+        assert_eq!(visible_range(&single, 10, 0), (40, 50));
+        assert_eq!(visible_range(&single, 10, 5), (35, 45));
+        // Can't scroll past the oldest
+        assert_eq!(visible_range(&single, 10, 45), (0, 10));
+        assert_eq!(visible_range(&single, 10, 100), (0, 10));
+        // Everything fits
+        assert_eq!(visible_range(&single[..4], 10, 2), (0, 4));
+        assert_eq!(visible_range(&[], 10, 0), (0, 0));
+    }
+
+    #[test]
+    fn cursor_position_counts_lines_and_chars() {
+        assert_eq!(cursor_position("", 0), (0, 0));
+        assert_eq!(cursor_position("héllo", 3), (0, 2));
+        assert_eq!(cursor_position("one\ntwo", 5), (1, 1));
+        assert_eq!(cursor_position("one\n", 4), (1, 0));
+    }
+
+    #[test]
+    fn visible_range_counts_message_lines() {
+        // Two three-line messages fill most of the view
+        assert_eq!(visible_range(&[1, 1, 1, 3, 3], 7, 0), (2, 5));
+        assert_eq!(visible_range(&[1, 1, 1, 3, 3], 7, 1), (0, 4));
+        // One message taller than the view
+        assert_eq!(visible_range(&[1, 20], 10, 0), (1, 2));
+    }
+
+    #[test]
+    fn conversation_label_shows_unread_and_preview() {
+        use crate::message::Message;
+
+        let (me, alice) = (PeerId::random(), PeerId::random());
+        let now = Utc::now();
+        let mut conversation = Conversation {
+            with: Recipient::Direct(alice),
+            name: "alice".to_string(),
+            last_message: None,
+            unread: 0,
+            pinned: false,
+            archived: false,
+        };
+        assert_eq!(conversation_label(&conversation, "✓", None, None, Some(me), now), "✓ alice");
+        assert_eq!(
+            conversation_label(&conversation, "✓", None, Some(PresenceStatus::Away), Some(me), now),
+            "✓ alice · away"
+        );
+        assert_eq!(
+            conversation_label(&conversation, "✓", None, Some(PresenceStatus::Offline), Some(me), now),
+            "✓ alice"
+        );
+        assert_eq!(
+            conversation_label(&conversation, "✓", Some("🦊🐙🍋🐢🦉"), None, Some(me), now),
+            "✓ alice 🦊🐙🍋🐢🦉"
+        );
+
+        let mut msg = Message::new_text(me, Recipient::Direct(alice), "x".repeat(50));
+        msg.timestamp = now;
+        conversation.last_message = Some(msg);
+        conversation.unread = 3;
+        let label = conversation_label(&conversation, "✓", None, Some(PresenceStatus::Online), Some(me), now);
+        let time = now.format("%H:%M");
+        assert_eq!(label, format!("✓ alice · online (3)  {}  You: {}…", time, "x".repeat(PREVIEW_CHARS)));
+        conversation.pinned = true;
+        conversation.last_message = None;
+        assert_eq!(conversation_label(&conversation, "✓", None, None, Some(me), now), "✓ alice ★ (3)");
+    }
+
     #[test]
     fn display_message_formats() {
         use chrono::Utc;
@@ -225,6 +707,19 @@ mod tests {
         assert!(matches!(blocked, TrustLevel::Blocked));
     }
 
+    #[test]
+    fn transfers_height_includes_border() {
+        assert_eq!(transfers_height(&[]), 0);
+        let view = TransferView {
+            transfer_id: uuid::Uuid::new_v4(),
+            filename: "a.txt".to_string(),
+            chunks_done: 1,
+            total_chunks: 2,
+            outgoing: true,
+        };
+        assert_eq!(transfers_height(&[view.clone(), view]), 4);
+    }
+
     #[test]
     fn empty_contacts_handled() {
         let contacts: Vec<Contact> = vec![];
@@ -101,19 +101,20 @@ async fn store_message_in_db() {
 /// Test: Queue message for offline peer.
 #[tokio::test]
 async fn queue_message_for_offline_peer() {
-    let from = PeerId::random();
+    let temp = TempDir::new().unwrap();
     let to = PeerId::random();
+    let msg = Message::new_text(PeerId::random(), Recipient::Direct(to), "Offline message".to_string());
 
-    let mut queue = MessageQueue::new();
-
-    // Queue message
-    let msg = Message::new_text(from, Recipient::Direct(to), "Offline message".to_string());
-    queue.enqueue(msg.clone());
+    {
+        let db = open_test_db(temp.path(), "test");
+        MessageQueue::new(&db).enqueue(&msg.id, &to, b"sealed").unwrap();
+    }
 
-    // Verify it's queued
-    let pending = queue.peek_all(&to);
+    // Still queued after reopening
+    let db = open_test_db(temp.path(), "test");
+    let pending = MessageQueue::new(&db).pending_for(&to).unwrap();
     assert_eq!(pending.len(), 1);
-    assert_eq!(pending[0].id, msg.id);
+    assert_eq!(pending[0].0, msg.id);
 }
 
 /// Test: Multiple contacts with different trust levels.
@@ -172,32 +173,20 @@ async fn group_encryption_flow() {
 /// Test: Message queue multiple peers.
 #[tokio::test]
 async fn message_queue_multiple_peers() {
-    let from = PeerId::random();
+    let temp = TempDir::new().unwrap();
+    let db = open_test_db(temp.path(), "test");
+    let queue = MessageQueue::new(&db);
     let peer1 = PeerId::random();
     let peer2 = PeerId::random();
 
-    let mut queue = MessageQueue::new();
-
     // Queue messages for different peers
-    queue.enqueue(Message::new_text(
-        from,
-        Recipient::Direct(peer1),
-        "Message 1".to_string(),
-    ));
-    queue.enqueue(Message::new_text(
-        from,
-        Recipient::Direct(peer2),
-        "Message 2".to_string(),
-    ));
-    queue.enqueue(Message::new_text(
-        from,
-        Recipient::Direct(peer1),
-        "Message 3".to_string(),
-    ));
+    queue.enqueue(&uuid::Uuid::new_v4(), &peer1, b"Message 1").unwrap();
+    queue.enqueue(&uuid::Uuid::new_v4(), &peer2, b"Message 2").unwrap();
+    queue.enqueue(&uuid::Uuid::new_v4(), &peer1, b"Message 3").unwrap();
 
     // Check peer-specific queues
-    assert_eq!(queue.peek_all(&peer1).len(), 2);
-    assert_eq!(queue.peek_all(&peer2).len(), 1);
+    assert_eq!(queue.pending_for(&peer1).unwrap().len(), 2);
+    assert_eq!(queue.pending_for(&peer2).unwrap().len(), 1);
 }
 
 /// Test: Full identity workflow.
@@ -240,5 +229,5 @@ async fn status_with_identity() {
         .unwrap();
 
     // Status should work without error
-    cli::handle_status(data_dir, "test").await.unwrap();
+    cli::handle_status(cli::OutputFormat::Text, data_dir, "test").await.unwrap();
 }